@@ -0,0 +1,13 @@
+use std::{env, path::PathBuf};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // For `descriptor`, to compare with what the service was built from.
+    let descriptor_path = PathBuf::from(env::var("OUT_DIR")?).join("authentication_descriptor.bin");
+
+    // Only the client is needed, from the same proto the service is built from.
+    tonic_build::configure()
+        .build_server(false)
+        .file_descriptor_set_path(descriptor_path)
+        .compile(&["../proto/authentication/v1/authentication.proto"], &["../proto"])?;
+    Ok(())
+}
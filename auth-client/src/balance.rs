@@ -0,0 +1,151 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use tokio::{
+    net::{lookup_host, TcpStream},
+    sync::mpsc::Sender,
+    time::{sleep, timeout},
+};
+use tonic::transport::{Channel, Endpoint, Error};
+use tower::discover::Change;
+use tracing::warn;
+
+// A channel spread over a fixed set of replicas, e.g. `["http://auth-1:50051", "http://auth-2:50051"]`.
+pub fn static_channel(uris: &[&str]) -> Result<Channel, Error> {
+    let endpoints = uris
+        .iter()
+        .map(|uri| Endpoint::from_shared(uri.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Channel::balance_list(endpoints.into_iter()))
+}
+
+// A channel spread over every address a host name resolves to, e.g. a headless Kubernetes
+// service. The name is resolved again every `refresh_every`, and only addresses that accept a
+// connection are kept, so replicas coming and going are picked up without restarting the caller:
+//
+//   let channel = DnsDiscovery::new("auth", 50051).with_refresh_every(Duration::from_secs(10)).channel();
+//   let client = AuthClient::new(channel);
+pub struct DnsDiscovery {
+    host: String,
+    port: u16,
+    refresh_every: Duration,
+    probe_timeout: Duration,
+}
+
+impl DnsDiscovery {
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: host.to_owned(),
+            port,
+            refresh_every: Duration::from_secs(30),
+            probe_timeout: Duration::from_secs(1),
+        }
+    }
+
+    pub fn with_refresh_every(mut self, refresh_every: Duration) -> Self {
+        self.refresh_every = refresh_every;
+        self
+    }
+
+    pub fn with_probe_timeout(mut self, probe_timeout: Duration) -> Self {
+        self.probe_timeout = probe_timeout;
+        self
+    }
+
+    // Has to be called from within a Tokio runtime; the re-resolution runs on it until the
+    // channel is dropped.
+    pub fn channel(self) -> Channel {
+        let (channel, changes) = Channel::balance_channel(16);
+        tokio::spawn(self.discover(changes));
+        channel
+    }
+
+    async fn discover(self, changes: Sender<Change<SocketAddr, Endpoint>>) {
+        let mut current = HashSet::new();
+
+        while !changes.is_closed() {
+            match lookup_host((self.host.as_str(), self.port)).await {
+                Ok(resolved) => {
+                    let healthy = reachable(resolved, self.probe_timeout).await;
+
+                    // With none reachable, the ones we have are as good a bet as any.
+                    if !healthy.is_empty() {
+                        let (inserted, removed) = diff(&current, &healthy);
+
+                        for address in inserted {
+                            let Ok(endpoint) = Endpoint::from_shared(format!("http://{address}")) else { continue };
+                            if changes.send(Change::Insert(address, endpoint)).await.is_err() {
+                                return;
+                            }
+                        }
+                        for address in removed {
+                            if changes.send(Change::Remove(address)).await.is_err() {
+                                return;
+                            }
+                        }
+
+                        current = healthy;
+                    }
+                }
+                // Keep what we have; DNS hiccups shouldn't empty the channel.
+                Err(e) => warn!("Failed to resolve {}.\n{e:?}", self.host),
+            }
+
+            sleep(self.refresh_every).await;
+        }
+    }
+}
+
+async fn reachable(addresses: impl Iterator<Item = SocketAddr>, probe_timeout: Duration) -> HashSet<SocketAddr> {
+    let mut healthy = HashSet::new();
+
+    for address in addresses {
+        if let Ok(Ok(_)) = timeout(probe_timeout, TcpStream::connect(address)).await {
+            healthy.insert(address);
+        }
+    }
+
+    healthy
+}
+
+// What to insert into and remove from the balancer to get from `current` to `next`.
+fn diff(current: &HashSet<SocketAddr>, next: &HashSet<SocketAddr>) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+    (
+        next.difference(current).copied().collect(),
+        current.difference(next).copied().collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn should_diff_address_sets() {
+        let a: SocketAddr = "10.0.0.1:50051".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:50051".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:50051".parse().unwrap();
+
+        let (inserted, removed) = diff(&HashSet::from([a, b]), &HashSet::from([b, c]));
+
+        assert_eq!(inserted, vec![c]);
+        assert_eq!(removed, vec![a]);
+    }
+
+    #[tokio::test]
+    async fn should_only_keep_reachable_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listening = listener.local_addr().unwrap();
+        // Nothing listens on the discard port.
+        let closed: SocketAddr = "127.0.0.1:9".parse().unwrap();
+
+        let healthy = reachable([listening, closed].into_iter(), Duration::from_secs(1)).await;
+
+        assert_eq!(healthy, HashSet::from([listening]));
+    }
+}
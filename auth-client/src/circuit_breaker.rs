@@ -0,0 +1,296 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tonic::{transport::Channel, Code, Response, Status};
+
+use crate::authentication::auth_client::AuthClient;
+
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerConfig {
+    // How many of the latest calls the failure rate is taken over.
+    pub window_size: usize,
+    // No tripping before this many calls, so a single early failure doesn't open the circuit.
+    pub minimum_calls: usize,
+    // Share of failed calls in the window that opens the circuit.
+    pub failure_rate_threshold: f64,
+    // How long calls fail fast before a few are let through again.
+    pub open_for: Duration,
+    // Calls let through while half-open; the circuit closes once all of them succeeded.
+    pub half_open_calls: usize,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            window_size: 20,
+            minimum_calls: 10,
+            failure_rate_threshold: 0.5,
+            open_for: Duration::from_secs(30),
+            half_open_calls: 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+// Returned instead of calling the service while the circuit is open.
+#[derive(Clone, Copy, Debug)]
+pub struct CircuitOpen {
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit open, retry after {:?}", self.retry_after)
+    }
+}
+
+impl Error for CircuitOpen {}
+
+#[derive(Debug)]
+pub enum ClientError {
+    CircuitOpen(CircuitOpen),
+    Status(Status),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::CircuitOpen(circuit_open) => circuit_open.fmt(f),
+            ClientError::Status(status) => status.fmt(f),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+impl From<CircuitOpen> for ClientError {
+    fn from(circuit_open: CircuitOpen) -> Self {
+        ClientError::CircuitOpen(circuit_open)
+    }
+}
+
+// Only errors saying the service is unwell count. A rejected password or a bad argument is the
+// service working as it should.
+fn is_failure(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::DeadlineExceeded | Code::Internal | Code::Unknown | Code::ResourceExhausted
+    )
+}
+
+enum State {
+    // The latest outcomes, `true` for failures.
+    Closed { outcomes: VecDeque<bool> },
+    Open { until: Instant },
+    HalfOpen { let_through: usize, succeeded: usize },
+}
+
+// Shared by every clone of a `GuardedAuthClient`, so they all see the same service health.
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::Closed { outcomes: VecDeque::new() }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match *self.state.lock().expect("circuit breaker lock seems broken!") {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { until } if Instant::now() < until => CircuitState::Open,
+            State::Open { .. } | State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    // Whether a call may go through now.
+    fn permit(&self) -> Result<(), CircuitOpen> {
+        let mut state = self.state.lock().expect("circuit breaker lock seems broken!");
+
+        if let State::Open { until } = *state {
+            let now = Instant::now();
+            if now < until {
+                return Err(CircuitOpen { retry_after: until - now });
+            }
+            *state = State::HalfOpen { let_through: 0, succeeded: 0 };
+        }
+
+        if let State::HalfOpen { let_through, .. } = &mut *state {
+            if *let_through >= self.config.half_open_calls {
+                // The trial calls are still out; wait for their verdict.
+                return Err(CircuitOpen { retry_after: Duration::ZERO });
+            }
+            *let_through += 1;
+        }
+
+        Ok(())
+    }
+
+    fn record(&self, failed: bool) {
+        let mut state = self.state.lock().expect("circuit breaker lock seems broken!");
+        let open = State::Open { until: Instant::now() + self.config.open_for };
+
+        match &mut *state {
+            State::Closed { outcomes } => {
+                outcomes.push_back(failed);
+                if outcomes.len() > self.config.window_size {
+                    outcomes.pop_front();
+                }
+
+                let failures = outcomes.iter().filter(|failed| **failed).count();
+                if outcomes.len() >= self.config.minimum_calls
+                    && failures as f64 / outcomes.len() as f64 >= self.config.failure_rate_threshold
+                {
+                    *state = open;
+                }
+            }
+            // A call let through before the circuit opened.
+            State::Open { .. } => {}
+            State::HalfOpen { .. } if failed => *state = open,
+            State::HalfOpen { succeeded, .. } => {
+                *succeeded += 1;
+                if *succeeded >= self.config.half_open_calls {
+                    *state = State::Closed { outcomes: VecDeque::new() };
+                }
+            }
+        }
+    }
+
+    // Runs the call unless the circuit is open, and learns from its outcome.
+    pub async fn call<T>(&self, call: impl Future<Output = Result<T, Status>>) -> Result<T, ClientError> {
+        self.permit()?;
+
+        let result = call.await;
+        self.record(result.as_ref().is_err_and(is_failure));
+
+        result.map_err(ClientError::Status)
+    }
+}
+
+// `AuthClient` behind a circuit breaker:
+//
+//   let mut client = GuardedAuthClient::new(AuthClient::new(channel), CircuitBreakerConfig::default());
+//   let response = client.call(|auth| auth.sign_in(request)).await?;
+#[derive(Clone)]
+pub struct GuardedAuthClient {
+    client: AuthClient<Channel>,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl GuardedAuthClient {
+    pub fn new(client: AuthClient<Channel>, config: CircuitBreakerConfig) -> Self {
+        Self {
+            client,
+            breaker: Arc::new(CircuitBreaker::new(config)),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.breaker.state()
+    }
+
+    pub async fn call<'a, T, F>(
+        &'a mut self,
+        rpc: impl FnOnce(&'a mut AuthClient<Channel>) -> F,
+    ) -> Result<Response<T>, ClientError>
+    where
+        F: Future<Output = Result<Response<T>, Status>> + 'a,
+    {
+        self.breaker.call(rpc(&mut self.client)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::SignOutRequest;
+
+    fn config(open_for: Duration) -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            window_size: 4,
+            minimum_calls: 4,
+            failure_rate_threshold: 0.5,
+            open_for,
+            half_open_calls: 2,
+        }
+    }
+
+    #[test]
+    fn should_open_once_failure_rate_is_reached() {
+        let breaker = CircuitBreaker::new(config(Duration::from_secs(60)));
+
+        for failed in [true, false, true] {
+            breaker.permit().unwrap();
+            breaker.record(failed);
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        breaker.permit().unwrap();
+        breaker.record(false);
+
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.permit().is_err());
+    }
+
+    #[test]
+    fn should_close_after_half_open_calls_succeed_and_reopen_on_failure() {
+        let breaker = CircuitBreaker::new(config(Duration::ZERO));
+
+        for _ in 0..4 {
+            breaker.record(true);
+        }
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+        breaker.permit().unwrap();
+        breaker.permit().unwrap();
+        assert!(breaker.permit().is_err());
+
+        breaker.record(false);
+        breaker.record(false);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        for _ in 0..4 {
+            breaker.record(true);
+        }
+        breaker.permit().unwrap();
+        breaker.record(true);
+        assert!(matches!(*breaker.state.lock().unwrap(), State::Open { .. }));
+    }
+
+    #[tokio::test]
+    async fn should_fail_fast_once_service_is_unreachable() {
+        // Nothing listens on the discard port.
+        let channel = tonic::transport::Endpoint::from_static("http://127.0.0.1:9").connect_lazy();
+        let mut client = GuardedAuthClient::new(AuthClient::new(channel), config(Duration::from_secs(60)));
+
+        for _ in 0..4 {
+            let result = client.call(|auth| auth.sign_out(SignOutRequest::default())).await;
+            assert!(matches!(result, Err(ClientError::Status(_))));
+        }
+
+        let result = client.call(|auth| auth.sign_out(SignOutRequest::default())).await;
+        assert!(matches!(result, Err(ClientError::CircuitOpen(_))));
+    }
+
+    #[test]
+    fn should_not_count_rejections_as_failures() {
+        assert!(!is_failure(&Status::unauthenticated("wrong password")));
+        assert!(is_failure(&Status::unavailable("connection refused")));
+    }
+}
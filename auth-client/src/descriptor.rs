@@ -0,0 +1,85 @@
+// What the services' protos look like on the wire, to tell whether two builds of them can talk:
+// the service's compat test checks it against a golden file, and clients against what the service
+// says it was built with (see `preflight`).
+
+use std::{collections::BTreeMap, sync::OnceLock};
+
+use prost::{DecodeError, Message};
+use prost_types::{DescriptorProto, FileDescriptorSet};
+use sha2::{Digest, Sha256};
+
+// The proto this crate was compiled from.
+pub const DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/authentication_descriptor.bin"));
+
+// Keyed by what the wire format cares about (numbers, not names); the value is what must not
+// change.
+fn add_message(entries: &mut BTreeMap<String, String>, scope: &str, message: &DescriptorProto) {
+    let name = format!("{scope}.{}", message.name());
+
+    for field in &message.field {
+        entries.insert(
+            format!("field {name} {}", field.number()),
+            format!("{} {:?} {:?} {}", field.name(), field.label(), field.r#type(), field.type_name())
+                .trim_end()
+                .to_owned(),
+        );
+    }
+    for range in &message.reserved_range {
+        for number in range.start()..range.end() {
+            entries.insert(format!("field {name} {number}"), String::from("reserved"));
+        }
+    }
+    for nested in &message.nested_type {
+        add_message(entries, &name, nested);
+    }
+}
+
+// What the proto passes on the wire, from its encoded `FileDescriptorSet`: "field <message> <number>"
+// = "<name> <label> <type> <type name>", and likewise for enum values and RPCs. Comments and the
+// like are left out.
+pub fn wire_entries(descriptor_set: &[u8]) -> Result<BTreeMap<String, String>, DecodeError> {
+    let descriptor_set = FileDescriptorSet::decode(descriptor_set)?;
+    let mut entries = BTreeMap::new();
+
+    for file in &descriptor_set.file {
+        let package = file.package();
+
+        for message in &file.message_type {
+            add_message(&mut entries, package, message);
+        }
+        for enumeration in &file.enum_type {
+            for value in &enumeration.value {
+                entries.insert(
+                    format!("enum {package}.{} {}", enumeration.name(), value.number()),
+                    value.name().to_owned(),
+                );
+            }
+        }
+        for service in &file.service {
+            for method in &service.method {
+                entries.insert(
+                    format!("rpc {package}.{}/{}", service.name(), method.name()),
+                    format!("{} {}", method.input_type(), method.output_type()),
+                );
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+// Hex SHA-256 of the wire entries: the same for two builds of the proto as long as neither has a
+// field, enum value or RPC the other hasn't.
+pub fn fingerprint(descriptor_set: &[u8]) -> Result<String, DecodeError> {
+    let mut digest = Sha256::new();
+    for (key, value) in wire_entries(descriptor_set)? {
+        digest.update(format!("{key} = {value}\n"));
+    }
+    Ok(digest.finalize().iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+// The fingerprint of `DESCRIPTOR_SET`.
+pub fn compiled_fingerprint() -> &'static str {
+    static FINGERPRINT: OnceLock<String> = OnceLock::new();
+    FINGERPRINT.get_or_init(|| fingerprint(DESCRIPTOR_SET).expect("the build writes a valid descriptor set"))
+}
@@ -0,0 +1,25 @@
+// The auth-service client for other services: the generated `AuthClient`, and what keeps calling
+// it well-behaved when the service isn't.
+
+// Errors are `tonic::Status`, as they are for the calls themselves.
+#![allow(clippy::result_large_err)]
+
+pub mod balance;
+pub mod circuit_breaker;
+pub mod descriptor;
+pub mod middleware;
+pub mod preflight;
+pub mod propagation;
+pub mod quota;
+pub mod retry;
+pub mod server_timing;
+pub mod status;
+
+pub mod authentication {
+    pub mod v1 {
+        tonic::include_proto!("authentication.v1");
+    }
+
+    // The version in use
+    pub use v1::*;
+}
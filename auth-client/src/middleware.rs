@@ -0,0 +1,530 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::request::Parts,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Service},
+    server::NamedService,
+    transport::Channel,
+    Code, Status,
+};
+use tokio::time::sleep;
+use tower::Layer;
+use tracing::warn;
+
+use crate::authentication::{auth_client::AuthClient, ListRevokedSessionsRequest, StatusCode, ValidateSessionRequest};
+use crate::propagation::ContextSigner;
+use crate::status;
+
+// Who a request comes from, as its bearer token says, or the context passed on with it (see
+// `propagation`). `AuthLayer` puts it in the request's extensions for tonic handlers; axum
+// handlers take it as an extractor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthContext {
+    pub user_uuid: String,
+    pub scopes: Vec<String>,
+    pub expires_at: SystemTime,
+    // The tenant the session is pinned to, when the validator can tell.
+    pub tenant: Option<String>,
+    // Hex SHA-256 of the session token, as ListRevokedSessions names sessions.
+    pub session_id: String,
+}
+
+impl AuthContext {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|held| held == scope)
+    }
+
+    pub fn require_scope(&self, scope: &str) -> Result<(), Status> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(Status::permission_denied(format!("missing scope {scope}")))
+        }
+    }
+
+    // For tonic handlers behind `AuthLayer`.
+    pub fn from_request<T>(request: &tonic::Request<T>) -> Result<&Self, Status> {
+        request.extensions().get::<Self>().ok_or_else(|| Status::unauthenticated("not authenticated"))
+    }
+}
+
+// The token of an `authorization: Bearer <token>` header.
+pub fn bearer_token(headers: &http::HeaderMap) -> Option<&str> {
+    let value = headers.get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+// Turns a bearer token into who it was issued to, or the status to turn the request away with:
+// UNAUTHENTICATED for a token that isn't (or no longer is) valid, anything else when the answer
+// couldn't be had.
+#[tonic::async_trait]
+pub trait TokenValidator: Send + Sync {
+    async fn validate(&self, token: &str) -> Result<AuthContext, Status>;
+}
+
+pub type SharedValidator = Arc<dyn TokenValidator>;
+
+#[derive(Deserialize)]
+struct SessionClaims {
+    sub: String,
+    exp: u64,
+    #[serde(default)]
+    scope: String,
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+// Checks the session JWTs the service issues with AUTH_SESSION_JWT_SECRET, without a call.
+// Only the signature, issuer and `exp` are looked at: a session signed out, revoked or of a
+// suspended user stays valid here until it expires. Where that matters, use `RemoteValidator`.
+pub struct JwtValidator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtValidator {
+    pub fn new(issuer: &str, secret: &[u8]) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[issuer]);
+        Self { key: DecodingKey::from_secret(secret), validation }
+    }
+
+    // How long past `exp` a token still holds, for this host's clock and the service's being
+    // apart; a minute unless set.
+    pub fn with_leeway(mut self, leeway: Duration) -> Self {
+        self.validation.leeway = leeway.as_secs();
+        self
+    }
+}
+
+#[tonic::async_trait]
+impl TokenValidator for JwtValidator {
+    async fn validate(&self, token: &str) -> Result<AuthContext, Status> {
+        let claims = jsonwebtoken::decode::<SessionClaims>(token, &self.key, &self.validation)
+            .map_err(|_| Status::unauthenticated("invalid session token"))?
+            .claims;
+
+        Ok(AuthContext {
+            user_uuid: claims.sub,
+            scopes: claims.scope.split_whitespace().map(str::to_owned).collect(),
+            expires_at: UNIX_EPOCH + Duration::from_secs(claims.exp),
+            tenant: claims.tenant,
+            session_id: token_digest(token),
+        })
+    }
+}
+
+// No more tokens than this are cached; past it, the cache starts over.
+const MAX_CACHED_TOKENS: usize = 10_000;
+
+// How the service names a session in ListRevokedSessions: hex SHA-256 of its token.
+pub(crate) fn token_digest(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+// Asks the service with ValidateSession, so revocations are seen, and remembers the answer for
+// `cache_ttl` (never past the session's expiry) to spare it a call per request. Only valid
+// sessions are cached, and a call that failed is never taken as an answer. With
+// `watch_revocations`, sessions revoked meanwhile are dropped from the cache within a poll, which
+// makes a longer `cache_ttl` safe:
+//
+//   let validator = Arc::new(RemoteValidator::new(client).with_cache_ttl(Duration::from_secs(300)));
+//   validator.watch_revocations(Duration::from_secs(5));
+//   let layer = AuthLayer::shared(validator);
+pub struct RemoteValidator {
+    client: AuthClient<Channel>,
+    cache_ttl: Duration,
+    // By token digest, as revocations come.
+    cache: Mutex<HashMap<String, (AuthContext, Instant)>>,
+    revocations_cursor: AtomicU64,
+}
+
+impl RemoteValidator {
+    pub fn new(client: AuthClient<Channel>) -> Self {
+        Self { client, cache_ttl: Duration::from_secs(30), cache: Mutex::default(), revocations_cursor: AtomicU64::new(0) }
+    }
+
+    // How long a revoked session may still be let through, without `watch_revocations`. Zero
+    // turns caching off.
+    pub fn with_cache_ttl(mut self, cache_ttl: Duration) -> Self {
+        self.cache_ttl = cache_ttl;
+        self
+    }
+
+    fn cached(&self, token: &str) -> Option<AuthContext> {
+        let digest = token_digest(token);
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        match cache.get(&digest) {
+            Some((context, until)) if *until > Instant::now() && context.expires_at > SystemTime::now() => {
+                Some(context.clone())
+            }
+            Some(_) => {
+                cache.remove(&digest);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn remember(&self, token: &str, context: &AuthContext) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if cache.len() >= MAX_CACHED_TOKENS {
+            cache.clear();
+        }
+        cache.insert(token_digest(token), (context.clone(), Instant::now() + self.cache_ttl));
+    }
+
+    // Drops the sessions revoked since the last poll from the cache, or all of it when the service
+    // can't tell which (see ListRevokedSessionsResponse.reset). Returns how many were dropped.
+    pub async fn poll_revocations(&self) -> Result<usize, Status> {
+        let request = ListRevokedSessionsRequest { cursor: self.revocations_cursor.load(Ordering::Relaxed) };
+        let reply = self.client.clone().list_revoked_sessions(request).await?.into_inner();
+        if reply.status_code() != StatusCode::Success {
+            return Err(Status::unavailable(reply.error_message));
+        }
+
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        let dropped = if reply.reset {
+            cache.drain().count()
+        } else {
+            reply.revoked_token_digests.iter().filter(|digest| cache.remove(*digest).is_some()).count()
+        };
+        self.revocations_cursor.store(reply.cursor, Ordering::Relaxed);
+        Ok(dropped)
+    }
+
+    // Has to be called from within a Tokio runtime; polls every `every` until the validator is
+    // dropped. A failed poll is tried again next time; meanwhile `cache_ttl` still bounds the cache.
+    pub fn watch_revocations(self: &Arc<Self>, every: Duration) {
+        let weak: Weak<Self> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            while let Some(validator) = weak.upgrade() {
+                if let Err(e) = validator.poll_revocations().await {
+                    warn!("Failed to poll revoked sessions.\n{e:?}");
+                }
+                drop(validator);
+                sleep(every).await;
+            }
+        });
+    }
+}
+
+#[tonic::async_trait]
+impl TokenValidator for RemoteValidator {
+    async fn validate(&self, token: &str) -> Result<AuthContext, Status> {
+        if let Some(context) = self.cached(token) {
+            return Ok(context);
+        }
+
+        let request = ValidateSessionRequest { session_token: token.to_owned() };
+        let reply = self.client.clone().validate_session(request).await?.into_inner();
+        status::check(reply.status_code, &reply.error_message)?;
+
+        let context = AuthContext {
+            user_uuid: reply.user_uuid,
+            scopes: reply.scopes,
+            expires_at: UNIX_EPOCH + Duration::from_secs(reply.expires_at.max(0) as u64),
+            tenant: Some(reply.tenant).filter(|tenant| !tenant.is_empty()),
+            session_id: token_digest(token),
+        };
+        self.remember(token, &context);
+        Ok(context)
+    }
+}
+
+// Authenticates every request to a tonic server (or a single service) by its bearer token, and
+// turns away those without a valid one before they reach a handler:
+//
+//   Server::builder().layer(AuthLayer::new(JwtValidator::new(issuer, secret)))
+#[derive(Clone)]
+pub struct AuthLayer {
+    validator: SharedValidator,
+    signer: Option<Arc<ContextSigner>>,
+}
+
+impl AuthLayer {
+    pub fn new(validator: impl TokenValidator + 'static) -> Self {
+        Self { validator: Arc::new(validator), signer: None }
+    }
+
+    pub fn shared(validator: SharedValidator) -> Self {
+        Self { validator, signer: None }
+    }
+
+    // Also takes the context another service passed on with the request, signed by `signer` (see
+    // `propagation`), instead of validating a token: that service did. A request with a context
+    // that isn't valid is turned away, whatever its token.
+    pub fn with_propagated_contexts(mut self, signer: ContextSigner) -> Self {
+        self.signer = Some(Arc::new(signer));
+        self
+    }
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = RequireAuth<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireAuth { inner, validator: self.validator.clone(), signer: self.signer.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireAuth<S> {
+    inner: S,
+    validator: SharedValidator,
+    signer: Option<Arc<ContextSigner>>,
+}
+
+impl<S, B> Service<http::Request<B>> for RequireAuth<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        // The service that was polled ready is the one to call; the clone stays for the next.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let validator = self.validator.clone();
+        let propagated = self.signer.as_ref().and_then(|signer| signer.received(request.headers()));
+
+        Box::pin(async move {
+            let token = bearer_token(request.headers()).map(str::to_owned);
+            let context = match (propagated, token) {
+                (Some(context), _) => context,
+                (None, Some(token)) => validator.validate(&token).await,
+                (None, None) => Err(Status::unauthenticated("missing bearer token")),
+            };
+
+            match context {
+                Ok(context) => {
+                    request.extensions_mut().insert(context);
+                    inner.call(request).await
+                }
+                Err(status) => Ok(status.to_http()),
+            }
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for RequireAuth<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+// For axum handlers, with the validator in the router's state (`SharedValidator`, or a state
+// it can be taken from). Answers 401 without a valid token, 503 when it couldn't be checked.
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for AuthContext
+where
+    SharedValidator: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = (http::StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(context) = parts.extensions.get::<AuthContext>() {
+            return Ok(context.clone());
+        }
+
+        let token = bearer_token(&parts.headers)
+            .ok_or_else(|| (http::StatusCode::UNAUTHORIZED, String::from("missing bearer token")))?;
+        let context = SharedValidator::from_ref(state).validate(token).await.map_err(|status| {
+            let code = match status.code() {
+                Code::Unauthenticated => http::StatusCode::UNAUTHORIZED,
+                Code::PermissionDenied => http::StatusCode::FORBIDDEN,
+                _ => http::StatusCode::SERVICE_UNAVAILABLE,
+            };
+            (code, status.message().to_owned())
+        })?;
+
+        parts.extensions.insert(context.clone());
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde::Serialize;
+    use tonic::transport::Endpoint;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    fn session_jwt(issuer: &str, secret: &[u8], exp: SystemTime) -> String {
+        #[derive(Serialize)]
+        struct Claims<'a> {
+            iss: &'a str,
+            sub: &'a str,
+            exp: u64,
+            scope: &'a str,
+        }
+
+        let exp = exp.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let claims = Claims { iss: issuer, sub: "1234", exp, scope: "profile:read admin" };
+        jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    struct Accepts(&'static str);
+
+    #[tonic::async_trait]
+    impl TokenValidator for Accepts {
+        async fn validate(&self, token: &str) -> Result<AuthContext, Status> {
+            if token != self.0 {
+                return Err(Status::unauthenticated("invalid session token"));
+            }
+            Ok(AuthContext {
+                user_uuid: String::from("1234"),
+                scopes: vec![],
+                expires_at: SystemTime::now(),
+                tenant: None,
+                session_id: token_digest(token),
+            })
+        }
+    }
+
+    #[test]
+    fn should_only_take_bearer_tokens() {
+        let headers = |value: &'static str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert(http::header::AUTHORIZATION, http::HeaderValue::from_static(value));
+            headers
+        };
+
+        assert_eq!(bearer_token(&headers("Bearer abc")), Some("abc"));
+        assert_eq!(bearer_token(&headers("bearer  abc ")), Some("abc"));
+        assert_eq!(bearer_token(&headers("Basic abc")), None);
+        assert_eq!(bearer_token(&headers("Bearer ")), None);
+        assert_eq!(bearer_token(&http::HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn should_validate_session_jwts_locally() {
+        let validator = JwtValidator::new("auth", b"secret");
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+
+        let context = validator.validate(&session_jwt("auth", b"secret", expires_at)).await.unwrap();
+        assert_eq!(context.user_uuid, "1234");
+        assert!(context.has_scope("admin") && !context.has_scope("profile:write"));
+        assert_eq!(context.require_scope("profile:write").unwrap_err().code(), Code::PermissionDenied);
+
+        let refused = [
+            session_jwt("auth", b"other secret", expires_at),
+            session_jwt("someone else", b"secret", expires_at),
+            session_jwt("auth", b"secret", SystemTime::now() - Duration::from_secs(3600)),
+        ];
+        for token in refused {
+            assert_eq!(validator.validate(&token).await.unwrap_err().code(), Code::Unauthenticated);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_allow_for_clock_skew_as_far_as_the_leeway() {
+        let expired = session_jwt("auth", b"secret", SystemTime::now() - Duration::from_secs(30));
+
+        assert!(JwtValidator::new("auth", b"secret").validate(&expired).await.is_ok());
+        let strict = JwtValidator::new("auth", b"secret").with_leeway(Duration::ZERO);
+        assert_eq!(strict.validate(&expired).await.unwrap_err().code(), Code::Unauthenticated);
+    }
+
+    #[test]
+    fn should_digest_tokens_as_the_service_does() {
+        assert_eq!(token_digest("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[tokio::test]
+    async fn should_answer_from_the_cache_until_it_expires() {
+        // Nothing listens there: a call that isn't answered from the cache fails.
+        let channel = Endpoint::from_static("http://127.0.0.1:9").connect_lazy();
+        let validator = RemoteValidator::new(AuthClient::new(channel));
+        let context = AuthContext {
+            user_uuid: String::from("1234"),
+            scopes: vec![],
+            expires_at: SystemTime::now() + Duration::from_secs(60),
+            tenant: None,
+            session_id: token_digest("token"),
+        };
+
+        validator.remember("token", &context);
+        assert_eq!(validator.validate("token").await.unwrap(), context);
+
+        let expired = AuthContext { expires_at: SystemTime::now() - Duration::from_secs(1), ..context };
+        validator.remember("token", &expired);
+        assert_ne!(validator.validate("token").await.unwrap_err().code(), Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn should_only_let_authenticated_requests_through() {
+        let handler = service_fn(|request: http::Request<()>| async move {
+            let user_uuid = request.extensions().get::<AuthContext>().unwrap().user_uuid.clone();
+            Ok::<_, Infallible>(Status::ok(user_uuid).to_http())
+        });
+        let service = AuthLayer::new(Accepts("valid")).layer(handler);
+        let call = |authorization: Option<&'static str>| {
+            let mut request = http::Request::new(());
+            if let Some(authorization) = authorization {
+                request.headers_mut().insert(http::header::AUTHORIZATION, http::HeaderValue::from_static(authorization));
+            }
+            service.clone().oneshot(request)
+        };
+        let grpc_status = |response: &http::Response<BoxBody>| response.headers().get("grpc-status").cloned();
+
+        let response = call(Some("Bearer valid")).await.unwrap();
+        assert_eq!(grpc_status(&response).unwrap(), "0");
+        assert_eq!(response.headers().get("grpc-message").unwrap(), "1234");
+
+        for authorization in [None, Some("Bearer invalid")] {
+            let response = call(authorization).await.unwrap();
+            assert_eq!(grpc_status(&response).unwrap(), "16");
+        }
+    }
+
+    #[tokio::test]
+    async fn should_extract_the_context_in_axum_handlers() {
+        let validator: SharedValidator = Arc::new(Accepts("valid"));
+        let parts = |authorization: &'static str| {
+            http::Request::get("/").header(http::header::AUTHORIZATION, authorization).body(()).unwrap().into_parts().0
+        };
+
+        let context = AuthContext::from_request_parts(&mut parts("Bearer valid"), &validator).await.unwrap();
+        assert_eq!(context.user_uuid, "1234");
+
+        let (code, _) = AuthContext::from_request_parts(&mut parts("Bearer invalid"), &validator).await.unwrap_err();
+        assert_eq!(code, http::StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,49 @@
+// Whether the service was built from the same proto as this crate, to check before relying on it.
+// Each binary compiles the proto into its own copy of the messages, so they drift apart as soon as
+// one is rebuilt without the others; and a field only one side knows of is silently dropped rather
+// than failing the call, which turns the skew into wrong answers.
+
+use std::fmt;
+
+use tonic::{transport::Channel, Status};
+
+use crate::authentication::{auth_client::AuthClient, GetServerInfoRequest};
+use crate::descriptor::compiled_fingerprint;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProtoDrift {
+    pub ours: String,
+    pub theirs: String,
+}
+
+impl fmt::Display for ProtoDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "built from another proto than ours (fingerprint {}, ours {})", self.theirs, self.ours)
+    }
+}
+
+// None when the protos match, or the service is too old to tell.
+pub async fn proto_drift(client: &mut AuthClient<Channel>) -> Result<Option<ProtoDrift>, Status> {
+    let info = client.get_server_info(GetServerInfoRequest {}).await?.into_inner();
+    Ok(drift_from(compiled_fingerprint(), info.proto_fingerprint))
+}
+
+fn drift_from(ours: &str, theirs: String) -> Option<ProtoDrift> {
+    (!theirs.is_empty() && theirs != ours).then(|| ProtoDrift { ours: ours.to_owned(), theirs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_only_report_fingerprints_that_differ() {
+        let ours = compiled_fingerprint();
+        assert_eq!(ours.len(), 64);
+
+        assert_eq!(drift_from(ours, ours.to_owned()), None);
+        // A service from before GetServerInfo said.
+        assert_eq!(drift_from(ours, String::new()), None);
+        assert_eq!(drift_from(ours, String::from("0123")).unwrap().theirs, "0123");
+    }
+}
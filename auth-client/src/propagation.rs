@@ -0,0 +1,196 @@
+// Who a call is made for, passed on from service to service so that only the first one has to
+// validate the session: it authenticates the caller (`AuthLayer`), and its own calls carry the
+// `AuthContext` along, signed with a key the services share (not one of the auth service's). The
+// services further down take it instead of a token:
+//
+//   let signer = ContextSigner::new(&key);
+//   let context = AuthContext::from_request(&request)?;
+//   let mut downstream = tonic::Request::new(message);
+//   signer.attach(context, &mut downstream);
+//
+// and, downstream:
+//
+//   Server::builder().layer(AuthLayer::new(validator).with_propagated_contexts(ContextSigner::new(&key)))
+//
+// A context is as good as a session token until the session's expiry, revoked or not: only pass it
+// to services trusted with the key.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use prost::Message;
+use sha2::Sha256;
+use tonic::{
+    codegen::http,
+    metadata::{MetadataMap, MetadataValue},
+    Request, Status,
+};
+
+use crate::authentication::{AuthContext as ProtoAuthContext, SignedAuthContext};
+use crate::middleware::AuthContext;
+
+// A `SignedAuthContext`, base64 encoded as binary metadata is.
+pub const AUTH_CONTEXT_HEADER: &str = "x-auth-context-bin";
+
+impl From<&AuthContext> for ProtoAuthContext {
+    fn from(context: &AuthContext) -> Self {
+        ProtoAuthContext {
+            user_uuid: context.user_uuid.clone(),
+            roles: context.scopes.clone(),
+            tenant: context.tenant.clone().unwrap_or_default(),
+            session_id: context.session_id.clone(),
+            expires_at: context.expires_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        }
+    }
+}
+
+impl From<ProtoAuthContext> for AuthContext {
+    fn from(context: ProtoAuthContext) -> Self {
+        AuthContext {
+            user_uuid: context.user_uuid,
+            scopes: context.roles,
+            expires_at: UNIX_EPOCH + Duration::from_secs(context.expires_at),
+            tenant: Some(context.tenant).filter(|tenant| !tenant.is_empty()),
+            session_id: context.session_id,
+        }
+    }
+}
+
+// Signs the contexts passed on, and checks the ones received, with HMAC-SHA256.
+#[derive(Clone)]
+pub struct ContextSigner {
+    key: Vec<u8>,
+    leeway: Duration,
+}
+
+impl ContextSigner {
+    pub fn new(key: &[u8]) -> Self {
+        Self { key: key.to_vec(), leeway: Duration::from_secs(60) }
+    }
+
+    // How long past its expiry a context still holds, for the services' clocks being apart; a
+    // minute unless set, as for `JwtValidator`.
+    pub fn with_leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::new_from_slice(&self.key).expect("HMAC takes keys of any length")
+    }
+
+    // An encoded `SignedAuthContext`.
+    pub fn sign(&self, context: &AuthContext) -> Vec<u8> {
+        let context = ProtoAuthContext::from(context).encode_to_vec();
+        let mut mac = self.mac();
+        mac.update(&context);
+        SignedAuthContext { signature: mac.finalize().into_bytes().to_vec(), context }.encode_to_vec()
+    }
+
+    // UNAUTHENTICATED unless signed with our key, and not expired.
+    pub fn verify(&self, signed: &[u8]) -> Result<AuthContext, Status> {
+        let invalid = || Status::unauthenticated("invalid auth context");
+        let signed = SignedAuthContext::decode(signed).map_err(|_| invalid())?;
+
+        let mut mac = self.mac();
+        mac.update(&signed.context);
+        mac.verify_slice(&signed.signature).map_err(|_| invalid())?;
+
+        let context = AuthContext::from(ProtoAuthContext::decode(signed.context.as_slice()).map_err(|_| invalid())?);
+        if context.expires_at + self.leeway <= SystemTime::now() {
+            return Err(Status::unauthenticated("expired auth context"));
+        }
+        Ok(context)
+    }
+
+    // Passes `context` on with a call to another service.
+    pub fn attach<T>(&self, context: &AuthContext, request: &mut Request<T>) {
+        request.metadata_mut().insert_bin(AUTH_CONTEXT_HEADER, MetadataValue::from_bytes(&self.sign(context)));
+    }
+
+    // The context passed on with a request, when there is one.
+    pub fn received(&self, headers: &http::HeaderMap) -> Option<Result<AuthContext, Status>> {
+        if !headers.contains_key(AUTH_CONTEXT_HEADER) {
+            return None;
+        }
+        let metadata = MetadataMap::from_headers(headers.clone());
+        let signed = metadata.get_bin(AUTH_CONTEXT_HEADER)?.to_bytes();
+        Some(signed.map_err(|_| Status::unauthenticated("invalid auth context")).and_then(|signed| self.verify(&signed)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use tonic::{body::BoxBody, Code};
+    use tower::{service_fn, Layer, ServiceExt};
+
+    use super::*;
+    use crate::middleware::{AuthLayer, TokenValidator};
+
+    fn context(expires_at: SystemTime) -> AuthContext {
+        AuthContext {
+            user_uuid: String::from("1234"),
+            scopes: vec![String::from("admin")],
+            expires_at,
+            tenant: Some(String::from("acme")),
+            session_id: String::from("ba7816bf"),
+        }
+    }
+
+    #[test]
+    fn should_only_take_contexts_signed_with_the_key() {
+        let signer = ContextSigner::new(b"shared key");
+        // Whole seconds, as they go over the wire.
+        let expires_at = UNIX_EPOCH + Duration::from_secs(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 60);
+
+        let signed = signer.sign(&context(expires_at));
+        assert_eq!(signer.verify(&signed).unwrap(), context(expires_at));
+        assert_eq!(ContextSigner::new(b"other key").verify(&signed).unwrap_err().code(), Code::Unauthenticated);
+
+        let mut tampered = SignedAuthContext::decode(signed.as_slice()).unwrap();
+        let mut forged = ProtoAuthContext::decode(tampered.context.as_slice()).unwrap();
+        forged.user_uuid = String::from("5678");
+        tampered.context = forged.encode_to_vec();
+        assert!(signer.verify(&tampered.encode_to_vec()).is_err());
+
+        let expired = signer.sign(&context(SystemTime::now() - Duration::from_secs(120)));
+        assert!(signer.verify(&expired).is_err());
+        assert!(signer.clone().with_leeway(Duration::from_secs(600)).verify(&expired).is_ok());
+    }
+
+    struct Refuses;
+
+    #[tonic::async_trait]
+    impl TokenValidator for Refuses {
+        async fn validate(&self, _: &str) -> Result<AuthContext, Status> {
+            Err(Status::unauthenticated("invalid session token"))
+        }
+    }
+
+    #[tokio::test]
+    async fn should_let_through_requests_with_a_propagated_context() {
+        let signer = ContextSigner::new(b"shared key");
+        let handler = service_fn(|request: http::Request<()>| async move {
+            let context = request.extensions().get::<AuthContext>().unwrap();
+            Ok::<_, Infallible>(Status::ok(context.tenant.clone().unwrap()).to_http())
+        });
+        let service = AuthLayer::new(Refuses).with_propagated_contexts(signer.clone()).layer(handler);
+        let call = |signer: &ContextSigner| {
+            let mut request = Request::new(());
+            signer.attach(&context(SystemTime::now() + Duration::from_secs(60)), &mut request);
+            let mut http_request = http::Request::new(());
+            *http_request.headers_mut() = request.metadata().clone().into_headers();
+            service.clone().oneshot(http_request)
+        };
+        let grpc_status = |response: &http::Response<BoxBody>| response.headers()["grpc-status"].clone();
+
+        let response = call(&signer).await.unwrap();
+        assert_eq!(grpc_status(&response), "0");
+        assert_eq!(response.headers()["grpc-message"], "acme");
+
+        let response = call(&ContextSigner::new(b"other key")).await.unwrap();
+        assert_eq!(grpc_status(&response), "16");
+    }
+}
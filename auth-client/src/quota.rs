@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use tonic::metadata::MetadataMap;
+
+// What the service says of the quota of a caller's `x-api-key`, with every response to it (see
+// AUTH_API_KEY_QUOTA_PER_MINUTE): the requests a minute, the ones left, and when nearly none are,
+// a warning and the seconds until all are back.
+pub const LIMIT_HEADER: &str = "x-ratelimit-limit";
+pub const REMAINING_HEADER: &str = "x-ratelimit-remaining";
+pub const RESET_HEADER: &str = "x-ratelimit-reset";
+pub const WARNING_HEADER: &str = "x-ratelimit-warning";
+
+// Where the caller's quota stands, as a response says. A caller going by it slows down before
+// being refused rather than after:
+//
+//   let response = client.validate_session(request).await?;
+//   if let Some(quota) = Quota::from_metadata(response.metadata()) {
+//       tokio::time::sleep(quota.pace()).await;
+//   }
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub limit: u32,
+    pub remaining: u32,
+    // Until the quota is whole again; only said along with a warning.
+    pub reset_after: Option<Duration>,
+    // Whether the service warned that the quota is nearly used up.
+    pub warning: bool,
+}
+
+impl Quota {
+    // None when the response says nothing of a quota: the call had no key, or the key no quota.
+    pub fn from_metadata(metadata: &MetadataMap) -> Option<Self> {
+        let number = |header| metadata.get(header)?.to_str().ok()?.parse::<u64>().ok();
+
+        Some(Self {
+            limit: u32::try_from(number(LIMIT_HEADER)?).ok()?,
+            remaining: u32::try_from(number(REMAINING_HEADER)?).ok()?,
+            reset_after: number(RESET_HEADER).map(Duration::from_secs),
+            warning: metadata.contains_key(WARNING_HEADER),
+        })
+    }
+
+    // How long to wait before the next call to spread what is left until the reset: nothing
+    // until the service warns.
+    pub fn pace(&self) -> Duration {
+        match self.reset_after {
+            Some(reset_after) if self.warning => reset_after / (self.remaining + 1),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_read_what_the_service_sends() {
+        let mut metadata = MetadataMap::new();
+        assert_eq!(Quota::from_metadata(&metadata), None);
+
+        metadata.insert(LIMIT_HEADER, 60.into());
+        metadata.insert(REMAINING_HEADER, 40.into());
+        let quota = Quota::from_metadata(&metadata).unwrap();
+        assert_eq!(quota, Quota { limit: 60, remaining: 40, reset_after: None, warning: false });
+        assert_eq!(quota.pace(), Duration::ZERO);
+
+        metadata.insert(REMAINING_HEADER, 3.into());
+        metadata.insert(RESET_HEADER, 20.into());
+        metadata.insert(WARNING_HEADER, "quota nearly used up".parse().unwrap());
+        let quota = Quota::from_metadata(&metadata).unwrap();
+        assert!(quota.warning);
+        assert_eq!(quota.pace(), Duration::from_secs(5));
+    }
+}
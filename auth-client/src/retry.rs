@@ -0,0 +1,229 @@
+use std::{future::Future, time::Duration};
+
+use tokio::time::sleep;
+use tonic::{metadata::AsciiMetadataValue, Code, Request, Response, Status};
+
+use crate::authentication::{
+    AcceptTermsRequest, ExportMyDataRequest, ExportUserDataRequest, GetStatsRequest, GetUserMetadataRequest, IntrospectTokenRequest,
+    ListRevokedSessionsRequest, ListUsersRequest, RevokeLongLivedSessionsRequest, RevokeSessionsRequest, SetUserMetadataRequest, SignOutRequest, SuspendUserRequest, UnsuspendUserRequest,
+    SetUserScopesRequest, SoftDeleteUserRequest, RestoreUserRequest, ClearLockoutRequest, ValidateSessionRequest,
+};
+
+// Requests that may safely be sent again when the answer to the first attempt got lost: doing
+// them twice leaves the service as doing them once. Sign up, sign in and the like create something
+// new every time, so they only get retried with an idempotency key.
+pub trait Idempotent: Clone {}
+
+impl Idempotent for SignOutRequest {}
+impl Idempotent for IntrospectTokenRequest {}
+impl Idempotent for ValidateSessionRequest {}
+impl Idempotent for ListRevokedSessionsRequest {}
+impl Idempotent for SetUserMetadataRequest {}
+impl Idempotent for GetUserMetadataRequest {}
+impl Idempotent for ExportMyDataRequest {}
+impl Idempotent for AcceptTermsRequest {}
+impl Idempotent for SuspendUserRequest {}
+impl Idempotent for UnsuspendUserRequest {}
+impl Idempotent for SoftDeleteUserRequest {}
+impl Idempotent for RestoreUserRequest {}
+impl Idempotent for ClearLockoutRequest {}
+impl Idempotent for SetUserScopesRequest {}
+impl Idempotent for RevokeLongLivedSessionsRequest {}
+impl Idempotent for RevokeSessionsRequest {}
+impl Idempotent for ExportUserDataRequest {}
+impl Idempotent for ListUsersRequest {}
+impl Idempotent for GetStatsRequest {}
+
+// Sent with every attempt of a retried non-idempotent call, so the service can tell a retry from
+// a second request.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+// When and how often to try a call again:
+//
+//   let policy = RetryPolicy::default().with_max_attempts(5);
+//   let response = policy
+//       .call(&client, ValidateSessionRequest { session_token }, |mut auth, request| async move {
+//           auth.validate_session(request).await
+//       })
+//       .await?;
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retry_on: Vec<Code>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            retry_on: vec![Code::Unavailable, Code::DeadlineExceeded],
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Including the first one.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    // The wait doubles after every attempt, up to `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    pub fn with_retry_on(mut self, codes: &[Code]) -> Self {
+        self.retry_on = codes.to_vec();
+        self
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_backoff)
+    }
+
+    // `rpc` gets a clone of the client and the request for every attempt.
+    pub async fn call<C, R, T, F, Fut>(&self, client: &C, request: R, rpc: F) -> Result<Response<T>, Status>
+    where
+        C: Clone,
+        R: Idempotent,
+        F: Fn(C, Request<R>) -> Fut,
+        Fut: Future<Output = Result<Response<T>, Status>>,
+    {
+        self.retry(client, request, None, rpc).await
+    }
+
+    // For everything else, e.g. sign_up: the same key goes with every attempt.
+    pub async fn call_with_idempotency_key<C, R, T, F, Fut>(
+        &self,
+        client: &C,
+        request: R,
+        idempotency_key: &str,
+        rpc: F,
+    ) -> Result<Response<T>, Status>
+    where
+        C: Clone,
+        R: Clone,
+        F: Fn(C, Request<R>) -> Fut,
+        Fut: Future<Output = Result<Response<T>, Status>>,
+    {
+        let idempotency_key = AsciiMetadataValue::try_from(idempotency_key)
+            .map_err(|_| Status::invalid_argument("idempotency key isn't a valid header value"))?;
+
+        self.retry(client, request, Some(idempotency_key), rpc).await
+    }
+
+    async fn retry<C, R, T, F, Fut>(
+        &self,
+        client: &C,
+        request: R,
+        idempotency_key: Option<AsciiMetadataValue>,
+        rpc: F,
+    ) -> Result<Response<T>, Status>
+    where
+        C: Clone,
+        R: Clone,
+        F: Fn(C, Request<R>) -> Fut,
+        Fut: Future<Output = Result<Response<T>, Status>>,
+    {
+        let mut attempt = 1;
+
+        loop {
+            let mut attempt_request = Request::new(request.clone());
+            if let Some(idempotency_key) = &idempotency_key {
+                attempt_request
+                    .metadata_mut()
+                    .insert(IDEMPOTENCY_KEY_HEADER, idempotency_key.clone());
+            }
+
+            match rpc(client.clone(), attempt_request).await {
+                Err(status) if attempt < self.max_attempts && self.retry_on.contains(&status.code()) => {
+                    sleep(self.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::authentication::{SignUpRequest, ValidateSessionResponse};
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::default().with_backoff(Duration::from_millis(1), Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn should_retry_idempotent_call_until_it_succeeds() {
+        let attempts = Arc::new(AtomicU32::new(0));
+
+        let result = policy()
+            .call(&attempts, ValidateSessionRequest::default(), |attempts, _| async move {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(Status::unavailable("restarting")),
+                    _ => Ok(Response::new(ValidateSessionResponse::default())),
+                }
+            })
+            .await;
+
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn should_give_up_after_max_attempts_or_on_other_codes() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let result = policy()
+            .call(&attempts, ValidateSessionRequest::default(), |attempts, _| async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err::<Response<ValidateSessionResponse>, _>(Status::unavailable("down"))
+            })
+            .await;
+        assert_eq!(result.unwrap_err().code(), Code::Unavailable);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let attempts = Arc::new(AtomicU32::new(0));
+        let result = policy()
+            .call(&attempts, ValidateSessionRequest::default(), |attempts, _| async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err::<Response<ValidateSessionResponse>, _>(Status::invalid_argument("bad"))
+            })
+            .await;
+        assert_eq!(result.unwrap_err().code(), Code::InvalidArgument);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn should_send_idempotency_key_with_every_attempt() {
+        let attempts = Arc::new(AtomicU32::new(0));
+
+        let result = policy()
+            .call_with_idempotency_key(&attempts, SignUpRequest::default(), "key-1", |attempts, request| async move {
+                assert_eq!(request.metadata().get(IDEMPOTENCY_KEY_HEADER).unwrap(), "key-1");
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(Status::unavailable("restarting")),
+                    _ => Ok(Response::new(())),
+                }
+            })
+            .await;
+
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
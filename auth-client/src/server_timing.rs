@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use tonic::metadata::MetadataMap;
+
+// Where the service says how long it took, when it runs with AUTH_SERVER_TIMING.
+pub const SERVER_TIMING_HEADER: &str = "server-timing";
+
+// How long the service spent on a call, as its response says: `handler` from the request to the
+// response, `store` of that in its stores. Whatever the caller measured beyond `handler` went to
+// the network, or to waiting for the service:
+//
+//   let response = client.sign_in(request).await?;
+//   if let Some(timing) = ServerTiming::from_metadata(response.metadata()) {
+//       println!("network: {:?}", timing.network(started_at.elapsed()));
+//   }
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerTiming {
+    pub handler: Duration,
+    pub store: Duration,
+}
+
+impl ServerTiming {
+    // None when the service didn't say, or not so this can read it.
+    pub fn from_metadata(metadata: &MetadataMap) -> Option<Self> {
+        metadata.get(SERVER_TIMING_HEADER)?.to_str().ok().and_then(Self::parse)
+    }
+
+    // `handler;dur=12.345, store;dur=3.210`, in milliseconds; metrics this doesn't know are
+    // skipped. Without `handler`, there's nothing to go by.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut handler = None;
+        let mut store = Duration::ZERO;
+
+        for metric in value.split(',') {
+            let mut params = metric.split(';').map(str::trim);
+            let name = params.next()?;
+            let millis: f64 = params.find_map(|param| param.strip_prefix("dur="))?.parse().ok()?;
+            let duration = Duration::try_from_secs_f64(millis / 1000.0).ok()?;
+
+            match name {
+                "handler" => handler = Some(duration),
+                "store" => store = duration,
+                _ => {}
+            }
+        }
+
+        Some(Self { handler: handler?, store })
+    }
+
+    // The part of `latency`, as measured by the caller, spent outside the service.
+    pub fn network(&self, latency: Duration) -> Duration {
+        latency.saturating_sub(self.handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_what_the_service_sends() {
+        assert_eq!(
+            ServerTiming::parse("handler;dur=12.5, store;dur=0.25"),
+            Some(ServerTiming { handler: Duration::from_micros(12_500), store: Duration::from_micros(250) })
+        );
+        assert_eq!(
+            ServerTiming::parse("cache;desc=\"hit\";dur=1, handler;dur=2"),
+            Some(ServerTiming { handler: Duration::from_millis(2), store: Duration::ZERO })
+        );
+        assert_eq!(ServerTiming::parse("store;dur=1"), None);
+        assert_eq!(ServerTiming::parse("handler;dur=soon"), None);
+
+        let timing = ServerTiming { handler: Duration::from_millis(3), store: Duration::ZERO };
+        assert_eq!(timing.network(Duration::from_millis(10)), Duration::from_millis(7));
+        assert_eq!(timing.network(Duration::from_millis(1)), Duration::ZERO);
+    }
+}
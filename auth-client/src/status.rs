@@ -0,0 +1,142 @@
+// What the StatusCode of a reply means, in one table: as a gRPC code and as a Rust error. The
+// service and this crate both go by it, the service for its handlers' replies and the clients for
+// what they get back, so the three never tell a status apart differently. A StatusCode added to
+// the proto doesn't compile until it has a row here.
+//
+// The service builds the proto into types of its own, so status codes cross over as the `i32`s
+// the replies carry.
+
+use std::fmt;
+
+use tonic::{Code, Status};
+
+use crate::authentication::StatusCode;
+
+macro_rules! status_codes {
+    ($($status_code:ident => $code:ident, $description:literal;)*) => {
+        // A reply with a StatusCode other than SUCCESS.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum AuthError {
+            $($status_code,)*
+        }
+
+        impl AuthError {
+            // None for SUCCESS.
+            pub fn from_status_code(status_code: StatusCode) -> Option<Self> {
+                match status_code {
+                    StatusCode::Success => None,
+                    $(StatusCode::$status_code => Some(Self::$status_code),)*
+                }
+            }
+
+            pub fn status_code(self) -> StatusCode {
+                match self {
+                    $(Self::$status_code => StatusCode::$status_code,)*
+                }
+            }
+
+            pub fn grpc_code(self) -> Code {
+                match self {
+                    $(Self::$status_code => Code::$code,)*
+                }
+            }
+
+            // For when the reply has no error message.
+            fn description(self) -> &'static str {
+                match self {
+                    $(Self::$status_code => $description,)*
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    Failure => Unauthenticated, "refused";
+    PasswordBreached => InvalidArgument, "password found in a data breach";
+    AccountSuspended => PermissionDenied, "account suspended";
+    AccountDeleted => PermissionDenied, "account deleted";
+    AccountPendingVerification => FailedPrecondition, "account not verified yet";
+    UsernameTaken => AlreadyExists, "username taken";
+    InvalidInvite => InvalidArgument, "invalid invite";
+    TermsUpdateRequired => FailedPrecondition, "the current terms have to be accepted first";
+    SourceBlocked => ResourceExhausted, "too many failed sign-ins, retry later";
+    ChallengeRequired => FailedPrecondition, "a solved challenge is required";
+    PasswordTooShort => InvalidArgument, "password too short";
+    MfaRequired => FailedPrecondition, "sign-in to be finished through a magic link";
+    AccountPendingApproval => FailedPrecondition, "account waiting for approval";
+    RequestReplayed => InvalidArgument, "request replayed";
+    PasswordNotPrehashed => InvalidArgument, "password not pre-hashed";
+    TenantMismatch => PermissionDenied, "session of another tenant";
+    SessionNotFound => Unauthenticated, "no such session";
+    UsernameNotAllowed => InvalidArgument, "username not allowed";
+    TooManyRequests => ResourceExhausted, "too many requests, retry later";
+}
+
+impl AuthError {
+    // The status code of a reply, as sent: None for SUCCESS, and FAILURE for values this build
+    // doesn't know.
+    pub fn from_i32(status_code: i32) -> Option<Self> {
+        Self::from_status_code(StatusCode::from_i32(status_code).unwrap_or(StatusCode::Failure))
+    }
+
+    // As an error of the call, with the reply's error message when it has one.
+    pub fn to_status(self, error_message: &str) -> Status {
+        let message = if error_message.is_empty() { self.description() } else { error_message };
+        Status::new(self.grpc_code(), message)
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.description(), self.status_code().as_str_name())
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+// OK for SUCCESS.
+pub fn grpc_code(status_code: i32) -> Code {
+    AuthError::from_i32(status_code).map_or(Code::Ok, AuthError::grpc_code)
+}
+
+// A reply as the result of its call: Ok for SUCCESS, the error and its message as a `Status`
+// otherwise.
+pub fn check(status_code: i32, error_message: &str) -> Result<(), Status> {
+    AuthError::from_i32(status_code).map_or(Ok(()), |error| Err(error.to_status(error_message)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_map_every_status_code_both_ways() {
+        for status_code in (0..).map_while(StatusCode::from_i32) {
+            match AuthError::from_status_code(status_code) {
+                Some(error) => {
+                    assert_eq!(error.status_code(), status_code);
+                    assert_ne!(error.grpc_code(), Code::Ok, "{status_code:?}");
+                }
+                None => assert_eq!(status_code, StatusCode::Success),
+            }
+        }
+    }
+
+    #[test]
+    fn should_turn_replies_into_results() {
+        assert!(check(StatusCode::Success as i32, "").is_ok());
+
+        let status = check(StatusCode::TenantMismatch as i32, "").unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert_eq!(status.message(), "session of another tenant");
+
+        let status = check(StatusCode::SessionNotFound as i32, "Unbekannte Sitzung.").unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        assert_eq!(status.message(), "Unbekannte Sitzung.");
+
+        // From a newer proto.
+        assert_eq!(AuthError::from_i32(1_000), Some(AuthError::Failure));
+        assert_eq!(grpc_code(StatusCode::UsernameTaken as i32), Code::AlreadyExists);
+    }
+}
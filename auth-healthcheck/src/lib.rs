@@ -0,0 +1,322 @@
+// The health check's cycle as a library: sign up a throwaway user, sign in, sign out, delete the
+// user again, and report how each step went. CI jobs and synthetic monitors can run it against
+// their own client:
+//
+//   let mut client = AuthClient::connect("http://[::1]:50051").await?;
+//   let report = run_cycle(&mut client).await?;
+//   assert!(report.passed());
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use opentelemetry::Context;
+use tonic::{transport::Channel, Request, Response, Status};
+
+use crate::authentication::auth_client::AuthClient;
+use crate::authentication::{
+    DeleteAccountRequest, SignInRequest, SignOutRequest, SignUpRequest, StatusCode, ValidateSessionRequest,
+};
+use crate::trace::{end, start_cycle, start_step, traced, Exemplar};
+
+pub use auth_client::authentication;
+pub use auth_client::preflight;
+pub use auth_client::server_timing::ServerTiming;
+pub use auth_ids::Ids;
+
+pub mod trace;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    // The raw status code from the response, as the service may know codes this crate doesn't.
+    Answered(i32),
+    // No answer within the timeout: the service may be hung, rather than failing.
+    TimedOut,
+}
+
+#[derive(Clone, Debug)]
+pub struct StepReport {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub latency: Duration,
+    // What the service said it took of `latency`, when it says (AUTH_SERVER_TIMING).
+    pub server_timing: Option<ServerTiming>,
+    // The step's span, for the latency to link to the server's side of the call.
+    pub exemplar: Option<Exemplar>,
+    // Whether the step should have succeeded; some check that a call is turned down instead.
+    pub expect_success: bool,
+}
+
+impl StepReport {
+    pub fn status(&self) -> Option<StatusCode> {
+        match self.outcome {
+            Outcome::Answered(status_code) => StatusCode::from_i32(status_code),
+            Outcome::TimedOut => None,
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        match self.outcome {
+            Outcome::Answered(status_code) => (status_code == StatusCode::Success as i32) == self.expect_success,
+            Outcome::TimedOut => false,
+        }
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.outcome == Outcome::TimedOut
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CycleReport {
+    pub username: String,
+    pub steps: Vec<StepReport>,
+}
+
+impl CycleReport {
+    // Whether every step was answered as it should have been.
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(StepReport::passed)
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.steps.iter().any(StepReport::timed_out)
+    }
+
+    // The validations that went against what sign_in and sign_out said: the session wasn't valid
+    // right after signing in, or still was after signing out. Unlike other failures, never down to
+    // the service being slow or unreachable.
+    pub fn session_violations(&self) -> Vec<&StepReport> {
+        self.steps
+            .iter()
+            .filter(|step| SESSION_CHECKS.contains(&step.name) && !step.passed() && !step.timed_out())
+            .collect()
+    }
+
+    pub fn latency(&self) -> Duration {
+        self.steps.iter().map(|step| step.latency).sum()
+    }
+
+    // The cycle's trace, when there was a tracer provider to make one.
+    pub fn trace_id(&self) -> Option<&str> {
+        self.steps.iter().find_map(|step| step.exemplar.as_ref()).map(|exemplar| exemplar.trace_id.as_str())
+    }
+}
+
+// The steps validating the session signed in with, before and after signing out.
+pub const SESSION_CHECKS: [&str; 2] = ["validate_session", "validate_after_sign_out"];
+
+// How long a single call may take, unless told otherwise.
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Who the cycle signs in as.
+#[derive(Clone, Debug, Default)]
+pub enum TestAccount {
+    // A new user every cycle, deleted at its end.
+    #[default]
+    Throwaway,
+    // An existing user, only signed in and out, so the store doesn't churn at all.
+    Fixed { username: String, password: String },
+}
+
+// Runs the cycle as a throwaway user.
+pub async fn run_cycle(client: &mut AuthClient<Channel>) -> Result<CycleReport, Status> {
+    run_cycle_as(client, &TestAccount::Throwaway, DEFAULT_RPC_TIMEOUT).await
+}
+
+// Times one call, made in a span of its own within `cycle`, into the report. Returns the response,
+// or `None` when it timed out.
+#[allow(clippy::too_many_arguments)]
+async fn step<M, T, F>(
+    report: &mut CycleReport,
+    cycle: &Context,
+    name: &'static str,
+    expect_success: bool,
+    timeout: Duration,
+    message: M,
+    call: impl FnOnce(Request<M>) -> F,
+    status_code: impl FnOnce(&T) -> i32,
+) -> Result<Option<T>, Status>
+where
+    F: Future<Output = Result<Response<T>, Status>>,
+{
+    let cx = start_step(cycle, name);
+    let exemplar = Exemplar::of(&cx);
+
+    let started_at = Instant::now();
+    let answer = tokio::time::timeout(timeout, call(traced(message, &cx))).await;
+    let latency = started_at.elapsed();
+
+    let Ok(response) = answer else {
+        end(&cx, false);
+        report.steps.push(StepReport { name, outcome: Outcome::TimedOut, latency, server_timing: None, exemplar, expect_success });
+        return Ok(None);
+    };
+
+    let (server_timing, response) = match response {
+        Ok(response) => (ServerTiming::from_metadata(response.metadata()), response.into_inner()),
+        Err(status) => {
+            end(&cx, false);
+            return Err(status);
+        }
+    };
+    let outcome = Outcome::Answered(status_code(&response));
+    let step = StepReport { name, outcome, latency, server_timing, exemplar, expect_success };
+    end(&cx, step.passed());
+    report.steps.push(step);
+    Ok(Some(response))
+}
+
+// Runs every step even when an earlier one failed, so the report shows all of them, but stops at
+// the first one that timed out. An error is only returned when a call didn't get an answer at
+// all.
+pub async fn run_cycle_as(
+    client: &mut AuthClient<Channel>,
+    account: &TestAccount,
+    timeout: Duration,
+) -> Result<CycleReport, Status> {
+    run_cycle_with(client, account, timeout, &Ids::default()).await
+}
+
+// Like `run_cycle_as`, with the throwaway users' names and passwords made up by `ids`. The cycle is
+// a trace of its own (see `trace`).
+pub async fn run_cycle_with(
+    client: &mut AuthClient<Channel>,
+    account: &TestAccount,
+    timeout: Duration,
+    ids: &Ids,
+) -> Result<CycleReport, Status> {
+    let cycle = start_cycle();
+    let report = run_cycle_in(&cycle, client, account, timeout, ids).await;
+    end(&cycle, report.as_ref().is_ok_and(CycleReport::passed));
+    report
+}
+
+async fn run_cycle_in(
+    cycle: &Context,
+    client: &mut AuthClient<Channel>,
+    account: &TestAccount,
+    timeout: Duration,
+    ids: &Ids,
+) -> Result<CycleReport, Status> {
+    let (username, password) = match account {
+        TestAccount::Throwaway => (format!("User-{}", ids.new_id()), ids.new_id().to_string()),
+        TestAccount::Fixed { username, password } => (username.clone(), password.clone()),
+    };
+    let mut report = CycleReport { username: username.clone(), steps: Vec::new() };
+    let throwaway = matches!(account, TestAccount::Throwaway);
+    let sign_in = || SignInRequest { username: username.clone(), password: password.clone(), remember_me: false };
+
+    if throwaway {
+        let request = SignUpRequest {
+            username: username.clone(),
+            password: password.clone(),
+            ..SignUpRequest::default()
+        };
+        if step(&mut report, cycle, "sign_up", true, timeout, request, |r| client.sign_up(r), |r| r.status_code).await?.is_none() {
+            return Ok(report);
+        }
+    }
+
+    let Some(signed_in) = step(&mut report, cycle, "sign_in", true, timeout, sign_in(), |r| client.sign_in(r), |r| r.status_code).await? else {
+        return Ok(report);
+    };
+
+    // The session is good until signed out of, and no longer afterwards.
+    let session_token = signed_in.session_token;
+    let validate = || ValidateSessionRequest { session_token: session_token.clone() };
+    if step(&mut report, cycle, "validate_session", true, timeout, validate(), |r| client.validate_session(r), |r| r.status_code).await?.is_none() {
+        return Ok(report);
+    }
+
+    let request = SignOutRequest { session_token: session_token.clone() };
+    if step(&mut report, cycle, "sign_out", true, timeout, request, |r| client.sign_out(r), |r| r.status_code).await?.is_none() {
+        return Ok(report);
+    }
+
+    if step(&mut report, cycle, "validate_after_sign_out", false, timeout, validate(), |r| client.validate_session(r), |r| r.status_code).await?.is_none() {
+        return Ok(report);
+    }
+
+    if throwaway {
+        // Deleting takes a session, so sign in once more for it. The account is purged once the
+        // service's deletion grace period is over.
+        let Some(signed_in) = step(&mut report, cycle, "sign_in_again", true, timeout, sign_in(), |r| client.sign_in(r), |r| r.status_code).await? else {
+            return Ok(report);
+        };
+
+        if signed_in.status_code == StatusCode::Success as i32 {
+            let request = DeleteAccountRequest { session_token: signed_in.session_token };
+            step(&mut report, cycle, "delete_account", true, timeout, request, |r| client.delete_account(r), |r| r.status_code).await?;
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(name: &'static str, status_code: StatusCode, millis: u64) -> StepReport {
+        StepReport {
+            name,
+            outcome: Outcome::Answered(status_code as i32),
+            latency: Duration::from_millis(millis),
+            server_timing: None,
+            exemplar: None,
+            expect_success: true,
+        }
+    }
+
+    #[test]
+    fn should_pass_only_when_every_step_succeeded() {
+        let mut report = CycleReport {
+            username: String::from("User-1"),
+            steps: vec![step("sign_up", StatusCode::Success, 5), step("sign_in", StatusCode::Success, 7)],
+        };
+        assert!(report.passed());
+        assert_eq!(report.latency(), Duration::from_millis(12));
+
+        report.steps.push(step("sign_out", StatusCode::Failure, 1));
+        assert!(!report.passed());
+        assert!(!report.timed_out());
+    }
+
+    #[test]
+    fn should_tell_timeouts_from_failures() {
+        let report = CycleReport {
+            username: String::from("User-1"),
+            steps: vec![StepReport { name: "sign_up", outcome: Outcome::TimedOut, latency: DEFAULT_RPC_TIMEOUT, server_timing: None, exemplar: None, expect_success: true }],
+        };
+
+        assert!(!report.passed());
+        assert!(report.timed_out());
+        assert_eq!(report.steps[0].status(), None);
+    }
+
+    #[test]
+    fn should_flag_sessions_contradicting_sign_in_and_sign_out() {
+        let mut still_valid = step("validate_after_sign_out", StatusCode::Success, 1);
+        still_valid.expect_success = false;
+        let mut report = CycleReport {
+            username: String::from("User-1"),
+            steps: vec![step("sign_in", StatusCode::Success, 5), step("validate_session", StatusCode::Success, 1)],
+        };
+        assert!(report.session_violations().is_empty());
+
+        report.steps.push(step("sign_out", StatusCode::Success, 1));
+        report.steps.push(still_valid.clone());
+        assert!(!report.passed());
+        assert_eq!(report.session_violations().len(), 1);
+
+        // Turned down, as it should be.
+        report.steps[3].outcome = Outcome::Answered(StatusCode::Failure as i32);
+        assert!(report.passed());
+
+        // Slow is not wrong.
+        report.steps[3] = StepReport { outcome: Outcome::TimedOut, ..still_valid };
+        assert!(report.session_violations().is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+// Every cycle is an OpenTelemetry trace, with a span per step. The steps' requests carry it in a
+// W3C `traceparent` header, so the auth-service's spans (and logs) join the same trace, and every
+// latency in the report points to it.
+
+use opentelemetry::{
+    global,
+    propagation::{Injector, TextMapPropagator},
+    sdk::propagation::TraceContextPropagator,
+    trace::{Status, TraceContextExt, Tracer},
+    Context,
+};
+use tonic::{
+    metadata::{MetadataKey, MetadataMap, MetadataValue},
+    Request,
+};
+
+// The trace and span a latency was measured in, as OpenMetrics exemplars expect them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+impl Exemplar {
+    // None while no tracer provider is installed: the spans then have no ids to point to.
+    pub fn of(cx: &Context) -> Option<Self> {
+        let span = cx.span();
+        let span_context = span.span_context();
+
+        span_context.is_valid().then(|| Self {
+            trace_id: span_context.trace_id().to_string(),
+            span_id: span_context.span_id().to_string(),
+        })
+    }
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (MetadataKey::from_bytes(key.as_bytes()), value.parse::<MetadataValue<_>>()) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+// The request for a call made within `cx`'s span.
+pub fn traced<T>(message: T, cx: &Context) -> Request<T> {
+    let mut request = Request::new(message);
+    TraceContextPropagator::new().inject_context(cx, &mut MetadataInjector(request.metadata_mut()));
+    request
+}
+
+// The root span of a cycle.
+pub fn start_cycle() -> Context {
+    Context::new().with_span(global::tracer("auth-healthcheck").start("health_check_cycle"))
+}
+
+// A step's span, within the cycle's.
+pub fn start_step(cycle: &Context, name: &'static str) -> Context {
+    cycle.with_span(global::tracer("auth-healthcheck").start_with_context(name, cycle))
+}
+
+pub fn end(cx: &Context, passed: bool) {
+    let span = cx.span();
+    if !passed {
+        span.set_status(Status::error("not answered as expected"));
+    }
+    span.end();
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::{sdk::trace::TracerProvider, trace::TracerProvider as _};
+
+    use super::*;
+
+    #[test]
+    fn should_carry_the_step_span_in_the_metadata() {
+        // Its tracers only make spans while it is around.
+        let provider = TracerProvider::default();
+        let tracer = provider.tracer("test");
+        let cycle = Context::new().with_span(tracer.start("health_check_cycle"));
+        let step = cycle.with_span(tracer.start_with_context("sign_in", &cycle));
+
+        let request = traced((), &step);
+        let exemplar = Exemplar::of(&step).unwrap();
+        assert_eq!(exemplar.trace_id, Exemplar::of(&cycle).unwrap().trace_id);
+
+        let traceparent = request.metadata().get("traceparent").unwrap().to_str().unwrap();
+        assert_eq!(traceparent, format!("00-{}-{}-01", exemplar.trace_id, exemplar.span_id));
+    }
+
+    #[test]
+    fn should_have_no_exemplar_without_a_tracer_provider() {
+        let cycle = Context::new().with_span(opentelemetry::trace::noop::NoopTracer::new().start("health_check_cycle"));
+
+        assert_eq!(Exemplar::of(&cycle), None);
+        assert!(traced((), &cycle).metadata().get("traceparent").is_none());
+    }
+}
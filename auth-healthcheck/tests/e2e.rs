@@ -0,0 +1,109 @@
+// The cycle against the auth service as it ships: built into its image and run by docker compose
+// (docker-compose.e2e.yaml), with its event logs on a volume, over the network. This catches what
+// the in-process tests can't: the Dockerfile, the environment, the listeners, the store outliving a
+// restart. It takes docker and a few minutes to build, so it only runs when asked for:
+//
+//   cargo test -p auth-healthcheck --test e2e -- --ignored
+
+use std::{
+    path::PathBuf,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use auth_healthcheck::authentication::{
+    auth_admin_client::AuthAdminClient, auth_client::AuthClient, GetStatsRequest, GetStatsResponse, SignUpRequest,
+    StatusCode,
+};
+use auth_healthcheck::{run_cycle, run_cycle_as, TestAccount, DEFAULT_RPC_TIMEOUT};
+use tonic::{transport::Channel, Request};
+
+// As in docker-compose.e2e.yaml.
+const ADDRESS: &str = "http://127.0.0.1:50061";
+const ADMIN_KEY: &str = "e2e-admin-key";
+
+// How long the service may take to listen once its container is up.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The compose project, taken down with its volume however the test ends.
+struct Compose {
+    root: PathBuf,
+}
+
+impl Compose {
+    fn up() -> Self {
+        let compose = Self { root: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("..") };
+        compose.run(&["up", "--detach", "--build", "--wait"]);
+        compose
+    }
+
+    fn run(&self, args: &[&str]) {
+        let status = Command::new("docker")
+            .current_dir(&self.root)
+            .args(["compose", "--file", "docker-compose.e2e.yaml", "--project-name", "auth-e2e"])
+            .args(args)
+            .status()
+            .expect("docker should be installed");
+        assert!(status.success(), "docker compose {} failed", args.join(" "));
+    }
+}
+
+impl Drop for Compose {
+    fn drop(&mut self) {
+        self.run(&["down", "--volumes"]);
+    }
+}
+
+// The service once it listens.
+async fn connect() -> Channel {
+    let started_at = Instant::now();
+    loop {
+        match Channel::from_static(ADDRESS).connect().await {
+            Ok(channel) => return channel,
+            Err(e) if started_at.elapsed() > STARTUP_TIMEOUT => panic!("The service didn't come up.\n{e:?}"),
+            Err(_) => tokio::time::sleep(Duration::from_millis(500)).await,
+        }
+    }
+}
+
+async fn get_stats(channel: &Channel) -> GetStatsResponse {
+    let mut request = Request::new(GetStatsRequest {});
+    request.metadata_mut().insert("x-api-key", ADMIN_KEY.parse().unwrap());
+    AuthAdminClient::new(channel.clone()).get_stats(request).await.unwrap().into_inner()
+}
+
+#[tokio::test]
+#[ignore = "builds and runs the service with docker compose"]
+async fn should_pass_the_cycle_against_the_service_in_its_container() {
+    let compose = Compose::up();
+    let channel = connect().await;
+    let mut client = AuthClient::new(channel.clone());
+
+    let report = run_cycle(&mut client).await.unwrap();
+    assert!(report.passed(), "{report:?}");
+
+    let stats = get_stats(&channel).await;
+    assert_eq!(stats.status_code, StatusCode::Success as i32);
+    // sign_in and sign_in_again.
+    assert!(stats.sign_ins >= 2, "{stats:?}");
+    assert_eq!(stats.refused_sign_ins, 0);
+    assert_eq!(stats.store_down_secs, 0);
+    assert_eq!(stats.internal_errors, 0);
+    assert!(!stats.store_operations.is_empty());
+    assert!(stats.store_operations.iter().all(|operation| operation.errors == 0), "{stats:?}");
+
+    // A user signed up before a restart can still sign in after it: the store is where the
+    // service looks for it.
+    let (username, password) = (String::from("User-e2e"), String::from("e2e-password"));
+    let request = SignUpRequest { username: username.clone(), password: password.clone(), ..SignUpRequest::default() };
+    assert_eq!(client.sign_up(request).await.unwrap().into_inner().status_code, StatusCode::Success as i32);
+
+    compose.run(&["restart", "auth"]);
+    let channel = connect().await;
+    let mut client = AuthClient::new(channel.clone());
+
+    let account = TestAccount::Fixed { username, password };
+    let report = run_cycle_as(&mut client, &account, DEFAULT_RPC_TIMEOUT).await.unwrap();
+    assert!(report.passed(), "{report:?}");
+    assert_eq!(get_stats(&channel).await.store_down_secs, 0);
+}
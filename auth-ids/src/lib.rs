@@ -0,0 +1,217 @@
+use std::{
+    env, fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(any(test, feature = "deterministic"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use uuid::Uuid;
+
+// Makes up the ids of users, guests, magic links, invites, test accounts, ... Everything that
+// used to call `Uuid::new_v4()` asks one of these instead, so tests can swap in `SeededIds`.
+pub trait IdGenerator {
+    fn new_id(&self) -> Uuid;
+}
+
+// Random v4 UUIDs, for production.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomIds;
+
+impl IdGenerator for RandomIds {
+    fn new_id(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+// The same v4-looking UUIDs, in the same order, for the same seed. Not random at all, so only
+// built for tests and with the `deterministic` feature.
+#[cfg(any(test, feature = "deterministic"))]
+#[derive(Debug)]
+pub struct SeededIds {
+    state: AtomicU64,
+}
+
+#[cfg(any(test, feature = "deterministic"))]
+impl SeededIds {
+    pub fn new(seed: u64) -> Self {
+        Self { state: AtomicU64::new(seed) }
+    }
+
+    // SplitMix64
+    fn next_u64(&self) -> u64 {
+        let mut z = self
+            .state
+            .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+            .wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(any(test, feature = "deterministic"))]
+impl IdGenerator for SeededIds {
+    fn new_id(&self) -> Uuid {
+        let bytes = (u128::from(self.next_u64()) << 64 | u128::from(self.next_u64())).to_be_bytes();
+        uuid::Builder::from_random_bytes(bytes).into_uuid()
+    }
+}
+
+// A generator to hand around: cheap to clone, and random by default, so the stores can keep
+// deriving `Default`. Clones share the generator, and so a seeded sequence.
+#[derive(Clone)]
+pub struct Ids(Arc<dyn IdGenerator + Send + Sync>);
+
+impl Ids {
+    pub fn new(generator: impl IdGenerator + Send + Sync + 'static) -> Self {
+        Self(Arc::new(generator))
+    }
+
+    pub fn new_id(&self) -> Uuid {
+        self.0.new_id()
+    }
+}
+
+impl Default for Ids {
+    fn default() -> Self {
+        Self::new(RandomIds)
+    }
+}
+
+impl fmt::Debug for Ids {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Ids")
+    }
+}
+
+// What user ids look like. A store keeps the scheme it was created with, so every id in it is of
+// the same kind: v7 UUIDs and ULIDs start with the time they were made at, and so sort, and are
+// indexed, in the order their users were created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdScheme {
+    #[default]
+    UuidV4,
+    UuidV7,
+    // 26 characters of Crockford's base32, e.g. "01HGW2N7EHJM5NJYPTA3Z1XW7B".
+    Ulid,
+}
+
+impl IdScheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::UuidV4 => "uuid-v4",
+            Self::UuidV7 => "uuid-v7",
+            Self::Ulid => "ulid",
+        }
+    }
+
+    // The randomness comes from `ids`, so seeded ids stay seeded.
+    pub fn new_id(self, ids: &Ids) -> String {
+        self.new_id_at(ids, SystemTime::now())
+    }
+
+    pub fn new_id_at(self, ids: &Ids, at: SystemTime) -> String {
+        let random = ids.new_id().into_bytes();
+        let millis = at.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64);
+        match self {
+            Self::UuidV4 => Uuid::from_bytes(random).to_string(),
+            Self::UuidV7 => {
+                let random: &[u8; 10] = random.last_chunk().unwrap_or(&[0; 10]);
+                uuid::Builder::from_unix_timestamp_millis(millis, random).into_uuid().to_string()
+            }
+            Self::Ulid => {
+                // 48 bits of milliseconds, then 80 random ones.
+                let mut bits = u128::from(millis & 0xFFFF_FFFF_FFFF) << 80;
+                bits |= u128::from_be_bytes(random) & ((1 << 80) - 1);
+                (0..26).rev().map(|digit| CROCKFORD[(bits >> (digit * 5)) as usize & 0x1F] as char).collect()
+            }
+        }
+    }
+}
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+impl fmt::Display for IdScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for IdScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uuid-v4" => Ok(Self::UuidV4),
+            "uuid-v7" => Ok(Self::UuidV7),
+            "ulid" => Ok(Self::Ulid),
+            _ => Err(format!("Error::UnknownIdScheme: {s}")),
+        }
+    }
+}
+
+// Random ids, unless `seed_var` holds a seed. Seeds are only honoured with the `deterministic`
+// feature; a release build refuses them rather than quietly ignoring them.
+pub fn ids_from_env(seed_var: &str) -> Result<Ids, String> {
+    let Ok(seed) = env::var(seed_var) else { return Ok(Ids::default()) };
+
+    #[cfg(feature = "deterministic")]
+    {
+        let seed = seed.parse().map_err(|_| format!("Error::InvalidConfig: {seed_var}={seed}"))?;
+        Ok(Ids::new(SeededIds::new(seed)))
+    }
+
+    #[cfg(not(feature = "deterministic"))]
+    Err(format!("Error::DeterministicIdsNotBuiltIn: {seed_var}={seed}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_ids_should_repeat_for_the_same_seed() {
+        let first: Vec<Uuid> = (0..3).map(|_| SeededIds::new(7)).map(|ids| ids.new_id()).collect();
+        assert!(first.iter().all(|id| *id == first[0]));
+
+        let ids = SeededIds::new(7);
+        let (a, b) = (ids.new_id(), ids.new_id());
+        assert_ne!(a, b);
+        assert_eq!(a, first[0]);
+        assert_eq!(a.get_version_num(), 4);
+        assert_ne!(SeededIds::new(8).new_id(), a);
+
+        // Clones draw from the same sequence.
+        let ids = Ids::new(SeededIds::new(7));
+        assert_eq!(ids.clone().new_id(), a);
+        assert_eq!(ids.new_id(), b);
+    }
+
+    #[test]
+    fn ids_should_follow_their_scheme() {
+        let ids = Ids::new(SeededIds::new(7));
+        let at = UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_123);
+
+        let v4 = IdScheme::UuidV4.new_id_at(&ids, at);
+        assert_eq!(Uuid::parse_str(&v4).unwrap().get_version_num(), 4);
+
+        let v7 = Uuid::parse_str(&IdScheme::UuidV7.new_id_at(&ids, at)).unwrap();
+        assert_eq!(v7.get_version_num(), 7);
+        let later = IdScheme::UuidV7.new_id_at(&ids, at + std::time::Duration::from_millis(1));
+        assert!(v7.to_string() < later);
+
+        let ulid = IdScheme::Ulid.new_id_at(&ids, at);
+        assert_eq!(ulid.len(), 26);
+        // 1_700_000_000_123 ms in base32, 10 digits.
+        assert_eq!(&ulid[..10], "01HF7YAT3V");
+        assert!(ulid < IdScheme::Ulid.new_id_at(&ids, at + std::time::Duration::from_millis(1)));
+
+        for scheme in [IdScheme::UuidV4, IdScheme::UuidV7, IdScheme::Ulid] {
+            assert_eq!(scheme.as_str().parse::<IdScheme>(), Ok(scheme));
+        }
+        assert!("uuid-v1".parse::<IdScheme>().is_err());
+    }
+}
@@ -1,4 +1,14 @@
+use std::{env, path::PathBuf};
+
+// One entry per version of the API; a new version is added next to the ones still served.
+const PROTOS: &[&str] = &["proto/authentication/v1/authentication.proto"];
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/authentication.proto")?;
+    // The descriptors are checked against the committed golden file by the proto compat test.
+    let descriptor_path = PathBuf::from(env::var("OUT_DIR")?).join("authentication_descriptor.bin");
+
+    tonic_build::configure()
+        .file_descriptor_set_path(descriptor_path)
+        .compile(PROTOS, &["proto"])?;
     Ok(())
 }
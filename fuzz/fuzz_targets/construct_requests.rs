@@ -0,0 +1,208 @@
+// Sequences of calls with arbitrary strings, built directly rather than decoded, so they get past
+// prost and deep into the handlers. Calls taking a session pick one handed out earlier in the
+// sequence (or an arbitrary token), so sign-outs, renames, deletions, ... act on real accounts.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use tonic::Request;
+
+use auth::auth::authentication::auth_admin_server::AuthAdmin;
+use auth::auth::authentication::auth_server::Auth;
+use auth::auth::authentication::*;
+use auth_microservice_fuzz::{admin_request, Harness};
+
+#[derive(Arbitrary, Debug)]
+enum Session {
+    Issued(u8),
+    Arbitrary(String),
+}
+
+#[derive(Arbitrary, Debug)]
+enum Call {
+    SignUp { username: String, password: String, invite_code: String, accepted_terms_version: String },
+    SignIn { username: String, password: String, remember_me: bool },
+    SignOut { session: Session },
+    ValidateSession { session: Session },
+    IntrospectToken { session: Session },
+    CreateGuestSession,
+    UpgradeGuestSession { session: Session, username: String, password: String },
+    ChangeUsername { session: Session, new_username: String },
+    SetUserMetadata { session: Session, key: String, value: String },
+    GetUserMetadata { session: Session },
+    ExportMyData { session: Session },
+    DeleteAccount { session: Session },
+    AcceptTerms { username: String, password: String, version: String },
+    CreateDelegationToken { session: Session, scopes: Vec<String>, ttl_secs: u64 },
+    ListRevokedSessions { cursor: u64 },
+    RequestMagicLink { username: String },
+    ConsumeMagicLink { magic_link_token: String },
+    SuspendUser { user: Session },
+    UnsuspendUser { user: Session },
+    SetUserScopes { user: Session, scopes: Vec<String> },
+    ExportUserData { user: Session },
+    ListUsers { page_size: u8, page_token: String, status: i32, created_after: i64, order_by: i32, descending: bool },
+    RevokeLongLivedSessions,
+    RevokeSessions { sessions: Vec<Session>, user: Option<Session>, created_before: i64 },
+}
+
+// Session tokens and user uuids handed out so far.
+#[derive(Default)]
+struct Issued {
+    session_tokens: Vec<String>,
+    user_uuids: Vec<String>,
+}
+
+impl Issued {
+    fn session_token(&self, session: Session) -> String {
+        pick(&self.session_tokens, session)
+    }
+
+    fn user_uuid(&self, user: Session) -> String {
+        pick(&self.user_uuids, user)
+    }
+
+    fn record(&mut self, user_uuid: String, session_token: String) {
+        self.user_uuids.push(user_uuid);
+        self.session_tokens.push(session_token);
+    }
+}
+
+fn pick(issued: &[String], session: Session) -> String {
+    match session {
+        Session::Issued(index) if !issued.is_empty() => issued[usize::from(index) % issued.len()].clone(),
+        Session::Issued(_) => String::new(),
+        Session::Arbitrary(value) => value,
+    }
+}
+
+fuzz_target!(|calls: Vec<Call>| {
+    let harness = Harness::get();
+    let auth_service = &harness.auth_service;
+    let mut issued = Issued::default();
+
+    harness.block_on(async {
+        // Errors are answers too; only a panic is a finding.
+        for call in calls {
+            match call {
+                Call::SignUp { username, password, invite_code, accepted_terms_version } => {
+                    let request = SignUpRequest { username, password, invite_code, accepted_terms_version, ..Default::default() };
+                    let _ = auth_service.sign_up(Request::new(request)).await;
+                }
+                Call::SignIn { username, password, remember_me } => {
+                    let request = SignInRequest { username, password, remember_me };
+                    if let Ok(response) = auth_service.sign_in(Request::new(request)).await {
+                        let response = response.into_inner();
+                        issued.record(response.user_uuid, response.session_token);
+                    }
+                }
+                Call::SignOut { session } => {
+                    let request = SignOutRequest { session_token: issued.session_token(session) };
+                    let _ = auth_service.sign_out(Request::new(request)).await;
+                }
+                Call::ValidateSession { session } => {
+                    let request = ValidateSessionRequest { session_token: issued.session_token(session) };
+                    let _ = auth_service.validate_session(Request::new(request)).await;
+                }
+                Call::IntrospectToken { session } => {
+                    let request = IntrospectTokenRequest { token: issued.session_token(session) };
+                    let _ = auth_service.introspect_token(Request::new(request)).await;
+                }
+                Call::CreateGuestSession => {
+                    if let Ok(response) = auth_service.create_guest_session(Request::new(Default::default())).await {
+                        let response = response.into_inner();
+                        issued.record(response.guest_uuid, response.session_token);
+                    }
+                }
+                Call::UpgradeGuestSession { session, username, password } => {
+                    let request = UpgradeGuestSessionRequest { session_token: issued.session_token(session), username, password };
+                    if let Ok(response) = auth_service.upgrade_guest_session(Request::new(request)).await {
+                        let response = response.into_inner();
+                        issued.record(response.user_uuid, response.session_token);
+                    }
+                }
+                Call::ChangeUsername { session, new_username } => {
+                    let request = ChangeUsernameRequest { session_token: issued.session_token(session), new_username };
+                    let _ = auth_service.change_username(Request::new(request)).await;
+                }
+                Call::SetUserMetadata { session, key, value } => {
+                    let request = SetUserMetadataRequest { session_token: issued.session_token(session), key, value };
+                    let _ = auth_service.set_user_metadata(Request::new(request)).await;
+                }
+                Call::GetUserMetadata { session } => {
+                    let request = GetUserMetadataRequest { session_token: issued.session_token(session) };
+                    let _ = auth_service.get_user_metadata(Request::new(request)).await;
+                }
+                Call::ExportMyData { session } => {
+                    let request = ExportMyDataRequest { session_token: issued.session_token(session) };
+                    let _ = auth_service.export_my_data(Request::new(request)).await;
+                }
+                Call::DeleteAccount { session } => {
+                    let request = DeleteAccountRequest { session_token: issued.session_token(session) };
+                    let _ = auth_service.delete_account(Request::new(request)).await;
+                }
+                Call::AcceptTerms { username, password, version } => {
+                    let request = AcceptTermsRequest { username, password, version };
+                    let _ = auth_service.accept_terms(Request::new(request)).await;
+                }
+                Call::CreateDelegationToken { session, scopes, ttl_secs } => {
+                    let request = CreateDelegationTokenRequest { session_token: issued.session_token(session), scopes, ttl_secs };
+                    let _ = auth_service.create_delegation_token(Request::new(request)).await;
+                }
+                Call::ListRevokedSessions { cursor } => {
+                    let request = ListRevokedSessionsRequest { cursor };
+                    let _ = auth_service.list_revoked_sessions(Request::new(request)).await;
+                }
+                Call::RequestMagicLink { username } => {
+                    let request = RequestMagicLinkRequest { username };
+                    let _ = auth_service.request_magic_link(Request::new(request)).await;
+                }
+                Call::ConsumeMagicLink { magic_link_token } => {
+                    let request = ConsumeMagicLinkRequest { magic_link_token, ..Default::default() };
+                    if let Ok(response) = auth_service.consume_magic_link(Request::new(request)).await {
+                        let response = response.into_inner();
+                        issued.record(response.user_uuid, response.session_token);
+                    }
+                }
+                Call::SuspendUser { user } => {
+                    let request = SuspendUserRequest { user_uuid: issued.user_uuid(user) };
+                    let _ = auth_service.suspend_user(admin_request(request)).await;
+                }
+                Call::UnsuspendUser { user } => {
+                    let request = UnsuspendUserRequest { user_uuid: issued.user_uuid(user) };
+                    let _ = auth_service.unsuspend_user(admin_request(request)).await;
+                }
+                Call::SetUserScopes { user, scopes } => {
+                    let request = SetUserScopesRequest { user_uuid: issued.user_uuid(user), scopes };
+                    let _ = auth_service.set_user_scopes(admin_request(request)).await;
+                }
+                Call::ExportUserData { user } => {
+                    let request = ExportUserDataRequest { user_uuid: issued.user_uuid(user) };
+                    let _ = auth_service.export_user_data(admin_request(request)).await;
+                }
+                Call::ListUsers { page_size, page_token, status, created_after, order_by, descending } => {
+                    let request = ListUsersRequest {
+                        page_size: page_size.into(),
+                        page_token,
+                        status,
+                        created_after,
+                        order_by,
+                        descending,
+                    };
+                    let _ = auth_service.list_users(admin_request(request)).await;
+                }
+                Call::RevokeLongLivedSessions => {
+                    let _ = auth_service.revoke_long_lived_sessions(admin_request(Default::default())).await;
+                }
+                Call::RevokeSessions { sessions, user, created_before } => {
+                    let request = RevokeSessionsRequest {
+                        session_tokens: sessions.into_iter().map(|session| issued.session_token(session)).collect(),
+                        user_uuid: user.map(|user| issued.user_uuid(user)).unwrap_or_default(),
+                        created_before,
+                    };
+                    let _ = auth_service.revoke_sessions(admin_request(request)).await;
+                }
+            }
+        }
+    });
+});
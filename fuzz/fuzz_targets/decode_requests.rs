@@ -0,0 +1,65 @@
+// Arbitrary bytes, as they could arrive on the wire: the first byte picks the RPC, the rest is
+// decoded as its request and, if prost takes it, handed to the handler.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use prost::Message;
+use tonic::Request;
+
+use auth::auth::authentication::auth_admin_server::AuthAdmin;
+use auth::auth::authentication::auth_server::Auth;
+use auth::auth::authentication::session_replication_server::SessionReplication;
+use auth::auth::authentication::*;
+use auth_microservice_fuzz::{admin_request, Harness};
+
+macro_rules! drive {
+    ($bytes:expr, $request:ty, $call:expr) => {
+        if let Ok(message) = <$request>::decode($bytes) {
+            // Errors are answers too; only a panic is a finding.
+            let _ = $call(message).await;
+        }
+    };
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&rpc, bytes)) = data.split_first() else { return };
+    let harness = Harness::get();
+    let auth_service = &harness.auth_service;
+
+    harness.block_on(async {
+        match rpc % 32 {
+            0 => drive!(bytes, SignUpRequest, |m| auth_service.sign_up(Request::new(m))),
+            1 => drive!(bytes, SignInRequest, |m| auth_service.sign_in(Request::new(m))),
+            2 => drive!(bytes, SignOutRequest, |m| auth_service.sign_out(Request::new(m))),
+            3 => drive!(bytes, ExchangeExternalTokenRequest, |m| auth_service.exchange_external_token(Request::new(m))),
+            4 => drive!(bytes, IntrospectTokenRequest, |m| auth_service.introspect_token(Request::new(m))),
+            5 => drive!(bytes, RequestMagicLinkRequest, |m| auth_service.request_magic_link(Request::new(m))),
+            6 => drive!(bytes, ConsumeMagicLinkRequest, |m| auth_service.consume_magic_link(Request::new(m))),
+            7 => drive!(bytes, ValidateSessionRequest, |m| auth_service.validate_session(Request::new(m))),
+            8 => drive!(bytes, CreateGuestSessionRequest, |m| auth_service.create_guest_session(Request::new(m))),
+            9 => drive!(bytes, UpgradeGuestSessionRequest, |m| auth_service.upgrade_guest_session(Request::new(m))),
+            10 => drive!(bytes, ChangeUsernameRequest, |m| auth_service.change_username(Request::new(m))),
+            11 => drive!(bytes, SetUserMetadataRequest, |m| auth_service.set_user_metadata(Request::new(m))),
+            12 => drive!(bytes, GetUserMetadataRequest, |m| auth_service.get_user_metadata(Request::new(m))),
+            13 => drive!(bytes, ExportMyDataRequest, |m| auth_service.export_my_data(Request::new(m))),
+            14 => drive!(bytes, DeleteAccountRequest, |m| auth_service.delete_account(Request::new(m))),
+            15 => drive!(bytes, AcceptTermsRequest, |m| auth_service.accept_terms(Request::new(m))),
+            16 => drive!(bytes, SuspendUserRequest, |m| auth_service.suspend_user(admin_request(m))),
+            17 => drive!(bytes, UnsuspendUserRequest, |m| auth_service.unsuspend_user(admin_request(m))),
+            18 => drive!(bytes, RevokeLongLivedSessionsRequest, |m| auth_service.revoke_long_lived_sessions(admin_request(m))),
+            19 => drive!(bytes, ExportUserDataRequest, |m| auth_service.export_user_data(admin_request(m))),
+            20 => drive!(bytes, CreateInviteRequest, |m| auth_service.create_invite(admin_request(m))),
+            21 => drive!(bytes, ListUsersRequest, |m| auth_service.list_users(admin_request(m))),
+            22 => drive!(bytes, RevokeSessionsRequest, |m| auth_service.revoke_sessions(admin_request(m))),
+            23 => drive!(bytes, SetMaintenanceModeRequest, |m| auth_service.set_maintenance_mode(admin_request(m))),
+            24 => drive!(bytes, SetLogLevelRequest, |m| auth_service.set_log_level(admin_request(m))),
+            25 => drive!(bytes, GetStatsRequest, |m| auth_service.get_stats(admin_request(m))),
+            26 => drive!(bytes, ReplayProjectionRequest, |m| auth_service.replay_projection(admin_request(m))),
+            27 => drive!(bytes, SetUserScopesRequest, |m| auth_service.set_user_scopes(admin_request(m))),
+            28 => drive!(bytes, CreateDelegationTokenRequest, |m| auth_service.create_delegation_token(Request::new(m))),
+            29 => drive!(bytes, ListRevokedSessionsRequest, |m| auth_service.list_revoked_sessions(Request::new(m))),
+            30 => drive!(bytes, SetFeatureFlagRequest, |m| auth_service.set_feature_flag(admin_request(m))),
+            _ => drive!(bytes, ReplicateSessionEventsRequest, |m| auth_service.replicate_session_events(admin_request(m))),
+        }
+    });
+});
@@ -0,0 +1,49 @@
+use std::{
+    future::Future,
+    sync::{Mutex, OnceLock},
+};
+
+use tokio::runtime::{Builder, Runtime};
+use tonic::Request;
+
+use auth::api_keys::ApiKeys;
+use auth::auth::AuthService;
+use auth::sessions::SessionsImpl;
+use auth::users::UsersImpl;
+
+pub const ADMIN_API_KEY: &str = "fuzz-admin-key";
+
+// One service for the whole run, so inputs also hit the state left behind by earlier ones. Any
+// panic in a handler is a finding, even though the service would recover the store it poisoned.
+pub struct Harness {
+    runtime: Runtime,
+    pub auth_service: AuthService,
+}
+
+impl Harness {
+    pub fn get() -> &'static Harness {
+        static HARNESS: OnceLock<Harness> = OnceLock::new();
+
+        HARNESS.get_or_init(|| Harness {
+            runtime: Builder::new_current_thread().enable_all().build().expect("tokio runtime seems broken!"),
+            auth_service: AuthService::new(
+                Box::new(Mutex::new(UsersImpl::default())),
+                Box::new(Mutex::new(SessionsImpl::default())),
+            )
+            .with_admin_api_keys(ApiKeys::new([ADMIN_API_KEY.to_owned()]))
+            // So `admin_request` gets replication requests in too.
+            .with_replication_keys(ApiKeys::new([ADMIN_API_KEY.to_owned()])),
+        })
+    }
+
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+}
+
+// Admin RPCs turn away requests without a key before looking at them.
+pub fn admin_request<T>(message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    request.metadata_mut().insert("x-api-key", ADMIN_API_KEY.parse().unwrap());
+    request
+}
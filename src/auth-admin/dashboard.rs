@@ -0,0 +1,221 @@
+use std::time::{Duration, Instant};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Style, Stylize},
+    text::Line,
+    widgets::{Block, Paragraph, Row, Table},
+    DefaultTerminal, Frame,
+};
+use tonic::{metadata::AsciiMetadataValue, transport::Channel, Request};
+
+use auth::{
+    auth::authentication::{
+        auth_admin_client::AuthAdminClient, AuditRecord, GetStatsResponse, ListRecentAuditEventsRequest,
+        ListThrottledSourcesRequest, StreamStatsRequest, ThrottledSource,
+    },
+    error::Error,
+};
+
+// How many audit events and throttled sources are shown.
+const ROWS: u32 = 50;
+// How often the keyboard is checked.
+const INPUT_POLL: Duration = Duration::from_millis(100);
+
+// What the dashboard shows: the stats StreamStats pushes, and the audit events and throttled
+// sources, polled as often.
+#[derive(Default)]
+struct Dashboard {
+    stats: Option<GetStatsResponse>,
+    // When the previous stats came, for the rates.
+    previous: Option<(Instant, GetStatsResponse)>,
+    // Per second, since the previous stats.
+    sign_in_rate: f64,
+    refused_sign_in_rate: f64,
+    events: Vec<AuditRecord>,
+    sources: Vec<ThrottledSource>,
+    // The last call that failed; the dashboard keeps showing what it had.
+    error: Option<String>,
+}
+
+impl Dashboard {
+    fn update_stats(&mut self, stats: GetStatsResponse, now: Instant) {
+        if let Some((then, previous)) = &self.previous {
+            let secs = now.duration_since(*then).as_secs_f64();
+            if secs > 0.0 {
+                // A restarted service counts from 0 again.
+                self.sign_in_rate = stats.sign_ins.saturating_sub(previous.sign_ins) as f64 / secs;
+                self.refused_sign_in_rate = stats.refused_sign_ins.saturating_sub(previous.refused_sign_ins) as f64 / secs;
+            }
+        }
+        self.previous = Some((now, stats.clone()));
+        self.stats = Some(stats);
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [stats_area, tables_area, status_area] =
+            Layout::vertical([Constraint::Length(6), Constraint::Fill(1), Constraint::Length(1)]).areas(frame.area());
+        let [events_area, sources_area] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(tables_area);
+
+        let stats = match &self.stats {
+            Some(stats) => vec![
+                Line::from(format!(
+                    "users {}   sessions {}   internal errors {}",
+                    stats.users, stats.sessions, stats.internal_errors
+                )),
+                Line::from(format!(
+                    "sign-ins {:.1}/s   refused {:.1}/s   tarpitted {}   blocked {}",
+                    self.sign_in_rate, self.refused_sign_in_rate, stats.tarpitted_requests, stats.blocked_requests
+                )),
+                Line::from(format!(
+                    "stores down {}s   refused changes {}   stale validations {}",
+                    stats.store_down_secs, stats.refused_changes, stats.stale_validations
+                )),
+                Line::from(format!(
+                    "jobs {}   with panics {}",
+                    stats.jobs.len(),
+                    stats.jobs.iter().filter(|job| job.panics > 0).count()
+                )),
+            ],
+            None => vec![Line::from("waiting for stats...")],
+        };
+        frame.render_widget(Paragraph::new(stats).block(Block::bordered().title(" Stats ")), stats_area);
+
+        let events = self.events.iter().map(|event| {
+            let mut details: Vec<String> = event.details.iter().map(|(key, value)| format!("{key}={value}")).collect();
+            details.sort();
+            Row::new([time_of_day(event.recorded_at), event.kind.clone(), event.user_uuid.clone(), details.join(" ")])
+        });
+        let events = Table::new(events, [Constraint::Length(8), Constraint::Length(24), Constraint::Length(36), Constraint::Fill(1)])
+            .header(Row::new(["time", "event", "user", "details"]).bold())
+            .block(Block::bordered().title(" Recent audit events "));
+        frame.render_widget(events, events_area);
+
+        let sources = self.sources.iter().map(|source| {
+            let row = Row::new([
+                source.source.clone(),
+                source.failures.to_string(),
+                match source.blocked_for_secs {
+                    0 => String::new(),
+                    secs => format!("{secs}s"),
+                },
+            ]);
+            if source.blocked_for_secs > 0 { row.style(Style::default().fg(Color::Red)) } else { row }
+        });
+        let sources = Table::new(sources, [Constraint::Fill(1), Constraint::Length(8), Constraint::Length(10)])
+            .header(Row::new(["source", "failures", "blocked"]).bold())
+            .block(Block::bordered().title(" Throttled sources "));
+        frame.render_widget(sources, sources_area);
+
+        let status = match &self.error {
+            Some(error) => Line::from(error.as_str()).red(),
+            None => Line::from("q to quit").dim(),
+        };
+        frame.render_widget(status, status_area);
+    }
+}
+
+// "HH:MM:SS", UTC, of seconds since the epoch.
+fn time_of_day(epoch_secs: i64) -> String {
+    let secs = epoch_secs.rem_euclid(24 * 60 * 60);
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn admin_request<T>(admin_key: &AsciiMetadataValue, message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    request.metadata_mut().insert("x-api-key", admin_key.clone());
+    request
+}
+
+// Watches the service at `url` until q (or Esc) is pressed: its stats as StreamStats pushes them
+// every `interval`, and the recent audit events and throttled sources, polled as often.
+pub async fn run(url: String, admin_key: String, interval: Duration) -> Result<(), Error> {
+    let admin_key: AsciiMetadataValue = admin_key.parse()?;
+    let channel = Channel::from_shared(url).map_err(|e| Error::Config(e.to_string()))?.connect().await?;
+    let mut client = AuthAdminClient::new(channel);
+
+    let interval_secs = interval.as_secs().try_into().unwrap_or(u32::MAX);
+    let mut stats = client.stream_stats(admin_request(&admin_key, StreamStatsRequest { interval_secs })).await?.into_inner();
+
+    let mut terminal = ratatui::init();
+    let result = watch(&mut terminal, &mut client, &admin_key, &mut stats, interval).await;
+    ratatui::restore();
+    result
+}
+
+async fn watch(
+    terminal: &mut DefaultTerminal,
+    client: &mut AuthAdminClient<Channel>,
+    admin_key: &AsciiMetadataValue,
+    stats: &mut tonic::Streaming<GetStatsResponse>,
+    interval: Duration,
+) -> Result<(), Error> {
+    let mut dashboard = Dashboard::default();
+    let mut polls = tokio::time::interval(interval);
+    let mut input = tokio::time::interval(INPUT_POLL);
+
+    loop {
+        tokio::select! {
+            message = stats.message() => match message {
+                Ok(Some(message)) => dashboard.update_stats(message, Instant::now()),
+                Ok(None) => return Err(Error::Transport(String::from("the service ended the stats stream"))),
+                Err(status) => return Err(status.into()),
+            },
+            _ = polls.tick() => {
+                dashboard.error = None;
+                let events = client.list_recent_audit_events(admin_request(admin_key, ListRecentAuditEventsRequest { limit: ROWS }));
+                match events.await {
+                    Ok(response) => dashboard.events = response.into_inner().events,
+                    Err(status) => dashboard.error = Some(format!("audit events: {}", status.message())),
+                }
+                let sources = client.list_throttled_sources(admin_request(admin_key, ListThrottledSourcesRequest { limit: ROWS }));
+                match sources.await {
+                    Ok(response) => dashboard.sources = response.into_inner().sources,
+                    Err(status) => dashboard.error = Some(format!("throttled sources: {}", status.message())),
+                }
+            },
+            _ = input.tick() => {
+                while event::poll(Duration::ZERO)? {
+                    let Event::Key(key) = event::read()? else { continue };
+                    let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                    if key.kind == KeyEventKind::Press && (matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) || ctrl_c) {
+                        return Ok(());
+                    }
+                }
+            },
+        }
+
+        terminal.draw(|frame| dashboard.draw(frame))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_compute_sign_in_rates_between_stats() {
+        let mut dashboard = Dashboard::default();
+        let start = Instant::now();
+
+        dashboard.update_stats(GetStatsResponse { sign_ins: 10, refused_sign_ins: 4, ..GetStatsResponse::default() }, start);
+        assert_eq!(dashboard.sign_in_rate, 0.0);
+
+        let later = start + Duration::from_secs(2);
+        dashboard.update_stats(GetStatsResponse { sign_ins: 20, refused_sign_ins: 5, ..GetStatsResponse::default() }, later);
+        assert_eq!((dashboard.sign_in_rate, dashboard.refused_sign_in_rate), (5.0, 0.5));
+
+        // Restarted in between.
+        let restarted = later + Duration::from_secs(2);
+        dashboard.update_stats(GetStatsResponse { sign_ins: 2, ..GetStatsResponse::default() }, restarted);
+        assert_eq!(dashboard.sign_in_rate, 0.0);
+    }
+
+    #[test]
+    fn should_format_time_of_day() {
+        assert_eq!(time_of_day(0), "00:00:00");
+        assert_eq!(time_of_day(86_400 + 3_723), "01:02:03");
+    }
+}
@@ -0,0 +1,91 @@
+use std::{process::ExitCode, time::Duration};
+
+use clap::{Parser, Subcommand};
+
+use auth::error::Error;
+use auth::migrate::{migrate, Backend};
+
+mod dashboard;
+
+// Offline maintenance of the auth service's stores, and a dashboard of a running one. Run
+// `migrate` while the service is stopped: the stores are opened here, not through the service.
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct AdminCommandlineContents {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    // Copies every user and live session into an empty backend, then checks the copy, e.g.
+    // `auth-admin migrate --from event-log:/var/lib/auth --to event-log:/var/lib/auth-new`.
+    Migrate {
+        // `memory` or `event-log:<dir>`
+        #[arg(long)]
+        from: Backend,
+        #[arg(long)]
+        to: Backend,
+        #[arg(long, default_value_t = 1000)]
+        batch_size: usize,
+    },
+    // Live stats, recent audit events and throttled sources of a running service, in the terminal;
+    // q to quit.
+    Dashboard {
+        #[arg(long, default_value = "http://[::1]:50051")]
+        url: String,
+        #[arg(short, long)]
+        admin_key: String,
+        #[arg(short, long, default_value_t = 2)]
+        interval_secs: u64,
+    },
+}
+
+// A migration that went through but whose copy doesn't match exits with 1; one that couldn't go
+// through, or a dashboard that couldn't start, with the code of what stopped it (see
+// `Error::exit_code`).
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            eprintln!("{e}");
+            e.exit_code()
+        }
+    }
+}
+
+async fn run() -> Result<ExitCode, Error> {
+    let cli = AdminCommandlineContents::parse();
+
+    match cli.command {
+        Commands::Migrate { from, to, batch_size } => {
+            let (from_users, from_sessions) = from.open()?;
+            let (mut to_users, mut to_sessions) = to.open()?;
+
+            let report = migrate(&from_users, &from_sessions, &mut to_users, &mut to_sessions, batch_size, |report| {
+                println!("migrated {} users, {} sessions", report.users, report.sessions);
+            })?;
+
+            for user_uuid in &report.skipped_users {
+                println!("SKIPPED user {user_uuid}: the source doesn't export it");
+            }
+            for mismatch in &report.mismatches {
+                println!("MISMATCH {mismatch}");
+            }
+            println!(
+                "done: {} users, {} sessions, {} skipped, {} mismatches",
+                report.users,
+                report.sessions,
+                report.skipped_users.len(),
+                report.mismatches.len()
+            );
+
+            Ok(if report.mismatches.is_empty() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+        }
+        Commands::Dashboard { url, admin_key, interval_secs } => {
+            dashboard::run(url, admin_key, Duration::from_secs(interval_secs.max(1))).await?;
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
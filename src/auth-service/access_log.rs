@@ -0,0 +1,300 @@
+use std::{
+    convert::Infallible,
+    fs::OpenOptions,
+    future::Future,
+    io::{self, Write},
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use rand_core::{OsRng, RngCore};
+use serde_json::json;
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Service},
+    metadata::MetadataMap,
+    server::NamedService,
+    transport::{
+        server::{TcpConnectInfo, TlsConnectInfo},
+        Body,
+    },
+    Code, Status,
+};
+
+use crate::config::Config;
+use crate::debug_capture::grpc_status;
+use crate::ip_rules::Cidr;
+use crate::peer::PeerInfo;
+
+// Sent back with every logged call, so a caller can quote it; taken from the request when it
+// comes with one, e.g. from a gateway.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// One JSON line per call (time, method, peer, grpcStatus, durationMs, requestId), written to
+// AUTH_ACCESS_LOG apart from the application's logs: it doesn't depend on the log level, and it is
+// all there is about the traffic, whatever the handlers log.
+pub struct AccessLog {
+    sink: Mutex<Box<dyn Write + Send>>,
+    sample_rate: f64,
+    trusted_proxies: Vec<Cidr>,
+}
+
+impl AccessLog {
+    pub fn new(sink: Box<dyn Write + Send>, sample_rate: f64, trusted_proxies: Vec<Cidr>) -> Self {
+        Self { sink: Mutex::new(sink), sample_rate, trusted_proxies }
+    }
+
+    // None without AUTH_ACCESS_LOG.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        let sink: Box<dyn Write + Send> = match config.access_log.as_deref() {
+            None => return Ok(None),
+            Some("-") => Box::new(io::stdout()),
+            Some(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("Failed to open the access log {path}.\n{e:?}"))?,
+            ),
+        };
+        Ok(Some(Self::new(sink, config.access_log_rate, config.trusted_proxies.clone())))
+    }
+
+    // Failed calls are always logged.
+    fn sampled(&self, grpc_status: Code) -> bool {
+        match self.sample_rate {
+            _ if grpc_status != Code::Ok => true,
+            rate if rate <= 0.0 => false,
+            rate if rate >= 1.0 => true,
+            rate => (OsRng.next_u64() as f64) < rate * u64::MAX as f64,
+        }
+    }
+
+    fn write(&self, call: &PendingCall, grpc_status: Code) {
+        if !self.sampled(grpc_status) {
+            return;
+        }
+        let line = json!({
+            "time": call.started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            "method": call.method,
+            "peer": call.peer.map(|ip| ip.to_string()),
+            "grpcStatus": grpc_status as i32,
+            "durationMs": call.timer.elapsed().as_secs_f64() * 1000.0,
+            "requestId": call.request_id,
+        });
+        let mut sink = self.sink.lock().unwrap_or_else(PoisonError::into_inner);
+        // Nowhere to report it to: the log is what's failing.
+        let _ = writeln!(sink, "{line}").and_then(|_| sink.flush());
+    }
+}
+
+// Logs the calls to a service to an `AccessLog`, when there is one. A call is logged once its
+// reply is done with, so a stream is timed to its end.
+#[derive(Clone)]
+pub struct AccessLogService<S> {
+    inner: S,
+    log: Option<Arc<AccessLog>>,
+}
+
+impl<S> AccessLogService<S> {
+    pub fn new(inner: S, log: Option<Arc<AccessLog>>) -> Self {
+        Self { inner, log }
+    }
+}
+
+impl<S> Service<http::Request<Body>> for AccessLogService<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let Some(log) = self.log.clone() else {
+            return Box::pin(self.inner.call(request));
+        };
+
+        let pending = PendingCall {
+            method: request.uri().path().to_owned(),
+            peer: peer(&request, &log.trusted_proxies),
+            request_id: request
+                .headers()
+                .get(REQUEST_ID_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map_or_else(|| format!("{:016x}", OsRng.next_u64()), str::to_owned),
+            started_at: SystemTime::now(),
+            timer: Instant::now(),
+            log,
+        };
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let (mut parts, body) = response.await?.into_parts();
+            if let Ok(value) = pending.request_id.parse() {
+                parts.headers.insert(REQUEST_ID_HEADER, value);
+            }
+            let body = LoggingBody {
+                inner: body,
+                // Errors come as headers only.
+                grpc_status: grpc_status(&parts.headers),
+                pending: Some(pending),
+            };
+            Ok(http::Response::from_parts(parts, body.boxed_unsync()))
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for AccessLogService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+// The client, as `PeerInfo` tells it; None over a unix socket.
+fn peer(request: &http::Request<Body>, trusted_proxies: &[Cidr]) -> Option<IpAddr> {
+    let extensions = request.extensions();
+    let remote_addr = extensions
+        .get::<TcpConnectInfo>()
+        .and_then(TcpConnectInfo::remote_addr)
+        .or_else(|| extensions.get::<TlsConnectInfo<TcpConnectInfo>>().and_then(|info| info.get_ref().remote_addr()));
+    let metadata = MetadataMap::from_headers(request.headers().clone());
+    PeerInfo::new(remote_addr.map(|addr| addr.ip()), &metadata, trusted_proxies).client_ip
+}
+
+struct PendingCall {
+    log: Arc<AccessLog>,
+    method: String,
+    peer: Option<IpAddr>,
+    request_id: String,
+    started_at: SystemTime,
+    timer: Instant,
+}
+
+// A reply, passed on as it is; the call is logged once the reply is done with, sent or not.
+struct LoggingBody {
+    inner: BoxBody,
+    grpc_status: Option<Code>,
+    pending: Option<PendingCall>,
+}
+
+impl HttpBody for LoggingBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Status>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<http::HeaderMap>, Status>> {
+        let polled = Pin::new(&mut self.inner).poll_trailers(cx);
+        if let Poll::Ready(Ok(Some(trailers))) = &polled {
+            self.grpc_status = grpc_status(trailers).or(self.grpc_status);
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggingBody {
+    fn drop(&mut self) {
+        let Some(pending) = self.pending.take() else { return };
+        // No status: the caller went away before the reply was done.
+        pending.log.write(&pending, self.grpc_status.unwrap_or(Code::Cancelled));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Lines {
+        fn take(&self) -> Vec<Value> {
+            let lines = std::mem::take(&mut *self.0.lock().unwrap());
+            String::from_utf8(lines).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+        }
+    }
+
+    async fn call(log: &Arc<AccessLog>, request: http::Request<Body>) -> http::Response<BoxBody> {
+        let handler = service_fn(|request: http::Request<Body>| async move {
+            Ok::<_, Infallible>(match request.uri().path() {
+                "/authentication.v1.Auth/SignIn" => Status::unauthenticated("Error::InvalidCredentials").to_http(),
+                _ => http::Response::new(tonic::body::empty_body()),
+            })
+        });
+        let mut response = AccessLogService::new(handler, Some(log.clone())).oneshot(request).await.unwrap();
+        while response.body_mut().data().await.is_some() {}
+        response
+    }
+
+    #[tokio::test]
+    async fn should_log_a_line_per_call() {
+        let lines = Lines::default();
+        let log = Arc::new(AccessLog::new(Box::new(lines.clone()), 1.0, Vec::new()));
+
+        let request = http::Request::post("/authentication.v1.Auth/ValidateSession")
+            .header(REQUEST_ID_HEADER, "req-1")
+            .body(Body::empty())
+            .unwrap();
+        let response = call(&log, request).await;
+        drop(response);
+
+        let line = &lines.take()[0];
+        assert_eq!(line["method"], "/authentication.v1.Auth/ValidateSession");
+        assert_eq!(line["requestId"], "req-1");
+        // Neither trailers nor a status in the headers: the reply was dropped unfinished.
+        assert_eq!(line["grpcStatus"], Code::Cancelled as i32);
+        assert!(line["durationMs"].as_f64().unwrap() >= 0.0);
+        assert_eq!(line["peer"], Value::Null);
+
+        let response = call(&log, http::Request::post("/authentication.v1.Auth/SignIn").body(Body::empty()).unwrap()).await;
+        let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_owned();
+        drop(response);
+
+        let line = &lines.take()[0];
+        assert_eq!(line["grpcStatus"], Code::Unauthenticated as i32);
+        assert_eq!(line["requestId"], request_id.as_str());
+    }
+
+    #[tokio::test]
+    async fn should_always_log_failed_calls() {
+        let lines = Lines::default();
+        let log = Arc::new(AccessLog::new(Box::new(lines.clone()), 0.0, Vec::new()));
+
+        assert!(log.sampled(Code::Unauthenticated));
+        assert!(!log.sampled(Code::Ok));
+
+        drop(call(&log, http::Request::post("/authentication.v1.Auth/SignIn").body(Body::empty()).unwrap()).await);
+        assert_eq!(lines.take().len(), 1);
+    }
+}
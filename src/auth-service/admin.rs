@@ -0,0 +1,1782 @@
+use std::{
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+use crate::auth::authentication::auth_admin_server::AuthAdmin;
+use crate::auth::authentication::{
+    ApproveUserRequest, ApproveUserResponse, RejectUserRequest, RejectUserResponse, ListPendingUsersRequest, ListPendingUsersResponse,
+    CreateInviteRequest, CreateInviteResponse, ReserveUsernameRequest, ReserveUsernameResponse, SetHoneypotUsernameRequest, SetHoneypotUsernameResponse, ExportUserDataRequest, ExportUserDataResponse, ListUsersRequest, ListUsersResponse, ListSessionsRequest, ListSessionsResponse, SessionSummary as ProtoSessionSummary, RevokeLongLivedSessionsRequest,
+    RevokeSessionsRequest, RevokeSessionsResponse, RevokeLongLivedSessionsResponse, StatusCode, SuspendUserRequest,
+    SuspendUserResponse, UnsuspendUserRequest, UnsuspendUserResponse, SoftDeleteUserRequest, SoftDeleteUserResponse, RestoreUserRequest, RestoreUserResponse, SetUserScopesRequest, SetUserScopesResponse, SetMaintenanceModeRequest, SetMaintenanceModeResponse,
+    SetFeatureFlagRequest, SetFeatureFlagResponse,
+    SetLogLevelRequest, SetLogLevelResponse, GetStatsRequest, GetStatsResponse, StreamStatsRequest, GetEffectiveConfigRequest, GetEffectiveConfigResponse, ConfigSetting, ListRecentAuditEventsRequest, ListRecentAuditEventsResponse, ListThrottledSourcesRequest, ListThrottledSourcesResponse, ThrottledSource as ProtoThrottledSource, GetRateLimitStateRequest, GetRateLimitStateResponse, get_rate_limit_state_request::Subject, ClearLockoutRequest, ClearLockoutResponse, AuditRecord, SetDebugCaptureRequest, SetDebugCaptureResponse, ListDebugCapturesRequest, ListDebugCapturesResponse, CapturedCall as ProtoCapturedCall, JobStats, StoreOperationStats, RpcStats, ReplayProjectionRequest, ReplayProjectionResponse, UserOrder as ProtoUserOrder, UserStatus, UserSummary as ProtoUserSummary,
+};
+use crate::audit::AuditEvent;
+use crate::auth::{epoch_secs, AuthService};
+use crate::debug_capture::CapturedCall;
+use crate::events::Event;
+use crate::flags::Flag;
+use crate::sessions::{Session, SessionClass, SessionQuery};
+use crate::throttle::ThrottledSource;
+use crate::users::{AccountStatus, UserOrder, UserQuery, UserSummary};
+
+// Re-exporting
+pub use crate::auth::authentication::auth_admin_server::AuthAdminServer;
+
+// Page sizes of ListUsers.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+// How often StreamStats pushes, and how often the stats it pushes are refreshed.
+pub const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(5);
+pub const MAX_STATS_INTERVAL: Duration = Duration::from_secs(60 * 60);
+pub const STATS_FEED_REFRESH: Duration = Duration::from_secs(1);
+
+impl From<AccountStatus> for UserStatus {
+    fn from(status: AccountStatus) -> Self {
+        match status {
+            AccountStatus::Active => UserStatus::Active,
+            AccountStatus::Suspended => UserStatus::Suspended,
+            AccountStatus::Deleted => UserStatus::Deleted,
+            AccountStatus::PendingVerification => UserStatus::PendingVerification,
+            AccountStatus::PendingApproval => UserStatus::PendingApproval,
+            AccountStatus::SoftDeleted => UserStatus::SoftDeleted,
+        }
+    }
+}
+
+impl From<(String, Session)> for ProtoSessionSummary {
+    fn from((session_token, session): (String, Session)) -> Self {
+        ProtoSessionSummary {
+            session_token,
+            user_uuid: session.user_uuid,
+            class: format!("{:?}", session.class),
+            created_at: epoch_secs(session.created_at),
+            expires_at: epoch_secs(session.expires_at),
+            last_used_at: epoch_secs(session.last_used_at),
+            ip: session.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<UserSummary> for ProtoUserSummary {
+    fn from(user: UserSummary) -> Self {
+        ProtoUserSummary {
+            user_uuid: user.user_uuid,
+            username: user.username,
+            status: UserStatus::from(user.status).into(),
+            created_at: epoch_secs(user.created_at),
+        }
+    }
+}
+
+impl From<ThrottledSource> for ProtoThrottledSource {
+    fn from(source: ThrottledSource) -> Self {
+        ProtoThrottledSource {
+            source: source.source,
+            failures: source.failures,
+            blocked_for_secs: source.blocked_for.map_or(0, |blocked_for| blocked_for.as_secs()),
+        }
+    }
+}
+
+impl From<CapturedCall> for ProtoCapturedCall {
+    fn from(call: CapturedCall) -> Self {
+        ProtoCapturedCall {
+            captured_at: epoch_secs(call.captured_at),
+            method: call.method,
+            request: call.request,
+            response: call.response,
+            grpc_status: call.grpc_status as i32,
+            duration_ms: call.duration.as_millis() as u64,
+        }
+    }
+}
+
+fn page_size(page_size: u32) -> usize {
+    match page_size as usize {
+        0 => DEFAULT_PAGE_SIZE,
+        page_size => page_size.min(MAX_PAGE_SIZE),
+    }
+}
+
+// The status to filter ListUsers by; None for all.
+fn account_status(status: UserStatus) -> Option<AccountStatus> {
+    match status {
+        UserStatus::Unspecified => None,
+        UserStatus::Active => Some(AccountStatus::Active),
+        UserStatus::Suspended => Some(AccountStatus::Suspended),
+        UserStatus::Deleted => Some(AccountStatus::Deleted),
+        UserStatus::PendingVerification => Some(AccountStatus::PendingVerification),
+        UserStatus::PendingApproval => Some(AccountStatus::PendingApproval),
+        UserStatus::SoftDeleted => Some(AccountStatus::SoftDeleted),
+    }
+}
+
+impl AuthService {
+    // Moves an account from `from` to `to`, leaving accounts in any other state alone.
+    fn transition_account_status(
+        &self,
+        user_uuid: &str,
+        from: AccountStatus,
+        to: AccountStatus,
+    ) -> Result<(), StatusCode> {
+        let mut users_service = self.users();
+
+        match users_service.get_account_status(user_uuid) {
+            Some(status) if status == from => {
+                users_service.set_account_status(user_uuid, to).map_err(|_| StatusCode::Failure)
+            }
+            _ => Err(StatusCode::Failure),
+        }
+    }
+
+    // Refreshes the stats StreamStats pushes, while anybody is watching, until the service goes.
+    pub fn start_stats_feed(self: &Arc<Self>) {
+        let auth_service = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(STATS_FEED_REFRESH);
+            loop {
+                ticks.tick().await;
+                let Some(auth_service) = auth_service.upgrade() else { break };
+                if auth_service.stats_feed.receiver_count() > 0 {
+                    auth_service.stats_feed.send_replace(auth_service.stats());
+                }
+            }
+        });
+    }
+
+    // What GetStats answers, and StreamStats pushes.
+    pub(crate) fn stats(&self) -> GetStatsResponse {
+        let throttle = self.throttle.metrics();
+
+        let jobs = self
+            .job_statuses
+            .snapshot()
+            .into_iter()
+            .map(|(name, status)| JobStats {
+                name: name.to_owned(),
+                interval_secs: status.interval.as_secs(),
+                runs: status.runs,
+                panics: status.panics,
+                last_run_at: status.last_run_at.map(epoch_secs).unwrap_or_default(),
+                last_duration_ms: status.last_duration.as_millis() as u64,
+                last_panic: status.last_panic.unwrap_or_default(),
+                skipped_runs: status.skipped_runs,
+            })
+            .collect();
+
+        let outdated_password_hashes = self.users().outdated_password_hashes() as u64;
+        let shadow_refusals =
+            self.shadow_refusals.snapshot().into_iter().map(|(policy, count)| (policy.to_owned(), count)).collect();
+
+        let store_operations = self
+            .store_metrics
+            .snapshot()
+            .into_iter()
+            .map(|((store, backend, operation), stats)| StoreOperationStats {
+                store: store.to_owned(),
+                backend: backend.to_owned(),
+                operation: operation.to_owned(),
+                calls: stats.calls,
+                errors: stats.errors,
+                total_micros: stats.total.as_micros() as u64,
+                duration_buckets: stats.buckets.to_vec(),
+            })
+            .collect();
+
+        let rpcs = self
+            .rpc_metrics
+            .snapshot()
+            .into_iter()
+            .map(|(rpc, stats)| RpcStats {
+                rpc: rpc.to_owned(),
+                calls: stats.calls,
+                failures: stats.errors,
+                total_micros: stats.total.as_micros() as u64,
+                duration_buckets: stats.buckets.to_vec(),
+            })
+            .collect();
+
+        let (users, sessions) = self.counts();
+        let outage = self.outage_metrics();
+        let (sign_ins, refused_sign_ins) = self.sign_ins();
+        let (purged_audit_entries, purged_discarded_journals) = self.purged_logs();
+
+        GetStatsResponse {
+            store_operations,
+            rpcs,
+            users: users as u64,
+            sessions: sessions as u64,
+            store_down_secs: outage.down_for.unwrap_or_default().as_secs(),
+            refused_changes: outage.refused_changes,
+            stale_validations: outage.stale_validations,
+            sign_ins,
+            refused_sign_ins,
+            purged_audit_entries,
+            purged_discarded_journals,
+            ..GetStatsResponse::success(
+                jobs,
+                self.internal_errors(),
+                throttle.tarpitted_requests,
+                throttle.blocked_requests,
+                self.passwords_rehashed(),
+                outdated_password_hashes,
+                shadow_refusals,
+            )
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl AuthAdmin for AuthService {
+    type StreamStatsStream = ReceiverStream<Result<GetStatsResponse, Status>>;
+
+    async fn suspend_user(
+        &self,
+        request: Request<SuspendUserRequest>,
+    ) -> Result<Response<SuspendUserResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        let reply: SuspendUserResponse = SuspendUserResponse::from_result(self.transition_account_status(
+            &req.user_uuid,
+            AccountStatus::Active,
+            AccountStatus::Suspended,
+        ));
+
+        call.finish(reply)
+    }
+
+    async fn unsuspend_user(
+        &self,
+        request: Request<UnsuspendUserRequest>,
+    ) -> Result<Response<UnsuspendUserResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        let reply: UnsuspendUserResponse = UnsuspendUserResponse::from_result(self.transition_account_status(
+            &req.user_uuid,
+            AccountStatus::Suspended,
+            AccountStatus::Active,
+        ));
+
+        call.finish(reply)
+    }
+
+    async fn soft_delete_user(
+        &self,
+        request: Request<SoftDeleteUserRequest>,
+    ) -> Result<Response<SoftDeleteUserResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        // Accounts their users deleted are on their way out already.
+        let result = {
+            let mut users_service = self.users();
+            match users_service.get_account_status(&req.user_uuid) {
+                Some(AccountStatus::Deleted | AccountStatus::SoftDeleted) | None => Err(StatusCode::Failure),
+                Some(_) => users_service.soft_delete(&req.user_uuid).map_err(|_| StatusCode::Failure),
+            }
+        };
+        if result.is_ok() {
+            self.sessions().delete_sessions_of_user(&req.user_uuid);
+            self.audit_log.record(AuditEvent::AccountSoftDeleted { user_uuid: req.user_uuid, reason: req.reason });
+        }
+
+        let reply: SoftDeleteUserResponse = SoftDeleteUserResponse::from_result(result);
+
+        call.finish(reply)
+    }
+
+    async fn restore_user(
+        &self,
+        request: Request<RestoreUserRequest>,
+    ) -> Result<Response<RestoreUserResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        let result = self.transition_account_status(&req.user_uuid, AccountStatus::SoftDeleted, AccountStatus::Active);
+        if result.is_ok() {
+            self.audit_log.record(AuditEvent::SoftDeletedAccountRestored { user_uuid: req.user_uuid });
+        }
+
+        let reply: RestoreUserResponse = RestoreUserResponse::from_result(result);
+
+        call.finish(reply)
+    }
+
+    async fn approve_user(
+        &self,
+        request: Request<ApproveUserRequest>,
+    ) -> Result<Response<ApproveUserResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        let result = self.transition_account_status(&req.user_uuid, AccountStatus::PendingApproval, AccountStatus::Active);
+        if result.is_ok() {
+            self.event_sink.publish(Event::UserApproved { user_uuid: req.user_uuid });
+        }
+
+        let reply: ApproveUserResponse = ApproveUserResponse::from_result(result);
+
+        call.finish(reply)
+    }
+
+    async fn reject_user(
+        &self,
+        request: Request<RejectUserRequest>,
+    ) -> Result<Response<RejectUserResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        // Only users still waiting: approved ones are deleted the usual way.
+        let result = {
+            let mut users_service = self.users();
+            match users_service.get_account_status(&req.user_uuid) {
+                Some(AccountStatus::PendingApproval) => {
+                    users_service.delete_user(req.user_uuid.clone());
+                    Ok(())
+                }
+                _ => Err(StatusCode::Failure),
+            }
+        };
+        if result.is_ok() {
+            self.event_sink.publish(Event::UserRejected { user_uuid: req.user_uuid });
+        }
+
+        let reply: RejectUserResponse = RejectUserResponse::from_result(result);
+
+        call.finish(reply)
+    }
+
+    async fn list_pending_users(
+        &self,
+        request: Request<ListPendingUsersRequest>,
+    ) -> Result<Response<ListPendingUsersResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        let query = UserQuery {
+            status: Some(AccountStatus::PendingApproval),
+            cursor: (!req.page_token.is_empty()).then_some(req.page_token),
+            limit: page_size(req.page_size),
+            ..UserQuery::default()
+        };
+
+        let reply: ListPendingUsersResponse = match self.users().list_users(&query) {
+            Ok(page) => ListPendingUsersResponse::success(
+                page.users.into_iter().map(ProtoUserSummary::from).collect(),
+                page.next_cursor.unwrap_or_default(),
+            ),
+            Err(e) => {
+                warn!("failed to list pending users: {}", e);
+                ListPendingUsersResponse::failure(StatusCode::Failure)
+            }
+        };
+
+        call.finish(reply)
+    }
+
+    async fn set_user_scopes(
+        &self,
+        request: Request<SetUserScopesRequest>,
+    ) -> Result<Response<SetUserScopesResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        let result = self.users().set_scopes(&req.user_uuid, &req.scopes).map_err(|e| {
+            warn!("failed to set scopes of {}: {}", req.user_uuid, e);
+            StatusCode::Failure
+        });
+
+        let reply: SetUserScopesResponse = SetUserScopesResponse::from_result(result);
+
+        call.finish(reply)
+    }
+
+    async fn revoke_long_lived_sessions(
+        &self,
+        request: Request<RevokeLongLivedSessionsRequest>,
+    ) -> Result<Response<RevokeLongLivedSessionsResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let revoked_count = self.sessions().delete_sessions_of_class(SessionClass::LongLived);
+
+        let reply: RevokeLongLivedSessionsResponse = RevokeLongLivedSessionsResponse::success(revoked_count as u64);
+
+        call.finish(reply)
+    }
+
+    async fn revoke_sessions(
+        &self,
+        request: Request<RevokeSessionsRequest>,
+    ) -> Result<Response<RevokeSessionsResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        let reply: RevokeSessionsResponse = if req.session_tokens.is_empty() && req.user_uuid.is_empty() {
+            RevokeSessionsResponse::failure(StatusCode::Failure)
+        } else {
+            // One lock for the lot, so nobody gets to use a session that's about to go.
+            let mut sessions_service = self.sessions();
+
+            let mut revoked_count = sessions_service.delete_sessions(&req.session_tokens);
+            if !req.user_uuid.is_empty() {
+                revoked_count += match req.created_before {
+                    0 => sessions_service.delete_sessions_of_user(&req.user_uuid),
+                    created_before => sessions_service.delete_sessions_of_user_created_before(
+                        &req.user_uuid,
+                        UNIX_EPOCH + Duration::from_secs(created_before.max(0) as u64),
+                    ),
+                };
+            }
+            info!("revoked {} sessions", revoked_count);
+
+            RevokeSessionsResponse::success(revoked_count as u64)
+        };
+
+        call.finish(reply)
+    }
+
+    async fn export_user_data(
+        &self,
+        request: Request<ExportUserDataRequest>,
+    ) -> Result<Response<ExportUserDataResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        let reply: ExportUserDataResponse = AuthService::export_user_data(self, &req.user_uuid)
+            .map_or_else(|| ExportUserDataResponse::failure(StatusCode::Failure), ExportUserDataResponse::success);
+
+        call.finish(reply)
+    }
+
+    async fn list_users(
+        &self,
+        request: Request<ListUsersRequest>,
+    ) -> Result<Response<ListUsersResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        let query = UserQuery {
+            status: account_status(req.status()),
+            created_after: (req.created_after > 0).then(|| UNIX_EPOCH + Duration::from_secs(req.created_after as u64)),
+            order: match req.order_by() {
+                ProtoUserOrder::CreatedAt => UserOrder::CreatedAt,
+                ProtoUserOrder::Username => UserOrder::Username,
+            },
+            descending: req.descending,
+            cursor: (!req.page_token.is_empty()).then_some(req.page_token),
+            limit: page_size(req.page_size),
+        };
+
+        let reply: ListUsersResponse = match self.users().list_users(&query) {
+            Ok(page) => ListUsersResponse::success(
+                page.users.into_iter().map(ProtoUserSummary::from).collect(),
+                page.next_cursor.unwrap_or_default(),
+            ),
+            Err(e) => {
+                warn!("failed to list users: {}", e);
+                ListUsersResponse::failure(StatusCode::Failure)
+            }
+        };
+
+        call.finish(reply)
+    }
+
+    async fn list_sessions(
+        &self,
+        request: Request<ListSessionsRequest>,
+    ) -> Result<Response<ListSessionsResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        let query = (!req.ip_prefix.is_empty())
+            .then(|| req.ip_prefix.parse())
+            .transpose()
+            .map(|ip_prefix| SessionQuery {
+                user_uuid: (!req.user_uuid.is_empty()).then_some(req.user_uuid),
+                created_before: (req.created_before > 0).then(|| UNIX_EPOCH + Duration::from_secs(req.created_before as u64)),
+                idle_for: (req.idle_longer_than_secs > 0).then(|| Duration::from_secs(req.idle_longer_than_secs)),
+                ip_prefix,
+                cursor: (!req.page_token.is_empty()).then_some(req.page_token),
+                limit: page_size(req.page_size),
+            });
+
+        let reply: ListSessionsResponse = match query.and_then(|query| self.sessions().list_sessions(&query).map_err(|e| e.to_string())) {
+            Ok(page) => ListSessionsResponse::success(
+                page.sessions.into_iter().map(ProtoSessionSummary::from).collect(),
+                page.next_cursor.unwrap_or_default(),
+            ),
+            Err(e) => {
+                warn!("failed to list sessions: {}", e);
+                ListSessionsResponse::failure(StatusCode::Failure)
+            }
+        };
+
+        call.finish(reply)
+    }
+
+    async fn create_invite(
+        &self,
+        request: Request<CreateInviteRequest>,
+    ) -> Result<Response<CreateInviteResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let invite = self.invites().create_invite(self.config.invite_ttl);
+
+        let reply: CreateInviteResponse = CreateInviteResponse::success(invite.code, epoch_secs(invite.expires_at));
+
+        call.finish(reply)
+    }
+
+    async fn reserve_username(
+        &self,
+        request: Request<ReserveUsernameRequest>,
+    ) -> Result<Response<ReserveUsernameResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+        let username = req.username.trim();
+
+        let result = if username.is_empty() {
+            Err(StatusCode::Failure)
+        } else if req.release {
+            self.reserved_usernames.release(username).then_some(()).ok_or(StatusCode::Failure)
+        } else if self.users().find_user_uuid(username).is_some() {
+            Err(StatusCode::UsernameTaken)
+        } else {
+            self.reserved_usernames.reserve(username);
+            Ok(())
+        };
+        if result.is_ok() {
+            info!("username {:?} {}", username, if req.release { "released" } else { "reserved" });
+        }
+
+        let reply: ReserveUsernameResponse = ReserveUsernameResponse::from_result(result);
+
+        call.finish(reply)
+    }
+
+    async fn set_honeypot_username(
+        &self,
+        request: Request<SetHoneypotUsernameRequest>,
+    ) -> Result<Response<SetHoneypotUsernameResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+
+        let req = request.into_inner();
+        let username = req.username.trim();
+
+        // A user's username would lock them out.
+        let result = if username.is_empty() {
+            Err(StatusCode::Failure)
+        } else if req.remove {
+            self.honeypot_usernames.remove(username).then_some(()).ok_or(StatusCode::Failure)
+        } else if self.users().find_user_uuid(username).is_some() {
+            Err(StatusCode::UsernameTaken)
+        } else {
+            self.honeypot_usernames.add(username);
+            Ok(())
+        };
+        if result.is_ok() {
+            info!("honeypot username {}", if req.remove { "removed" } else { "set" });
+        }
+
+        let reply: SetHoneypotUsernameResponse = SetHoneypotUsernameResponse::from_result(result);
+
+        call.finish(reply)
+    }
+
+    async fn set_maintenance_mode(
+        &self,
+        request: Request<SetMaintenanceModeRequest>,
+    ) -> Result<Response<SetMaintenanceModeResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+
+        let req = request.into_inner();
+
+        self.flags.set(Flag::MaintenanceMode, req.enabled);
+        info!("maintenance mode {}", if req.enabled { "on" } else { "off" });
+
+        let reply: SetMaintenanceModeResponse = SetMaintenanceModeResponse::success();
+
+        call.finish(reply)
+    }
+
+    async fn set_feature_flag(
+        &self,
+        request: Request<SetFeatureFlagRequest>,
+    ) -> Result<Response<SetFeatureFlagResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+
+        let req = request.into_inner();
+
+        let flipped = match req.flag.as_str() {
+            "" => Ok(()),
+            flag => flag.parse::<Flag>().map(|flag| {
+                let was_enabled = self.flags.set(flag, req.enabled);
+                info!("feature flag {} {} (was {})", flag.name(), req.enabled, was_enabled);
+            }),
+        };
+
+        // The flags in effect either way, so a failed change shows what's still in place.
+        let flags = self.flags.snapshot().into_iter().map(|(flag, enabled)| (flag.to_owned(), enabled)).collect();
+        let reply: SetFeatureFlagResponse = match flipped {
+            Ok(()) => SetFeatureFlagResponse::success(flags),
+            Err(e) => {
+                warn!("failed to set feature flag: {}", e);
+                SetFeatureFlagResponse { flags, ..SetFeatureFlagResponse::failure(StatusCode::Failure) }
+            }
+        };
+
+        call.finish(reply)
+    }
+
+    async fn set_log_level(
+        &self,
+        request: Request<SetLogLevelRequest>,
+    ) -> Result<Response<SetLogLevelResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+
+        let req = request.into_inner();
+
+        // The filter in effect either way, so a failed change shows what's still in place.
+        let reply: SetLogLevelResponse = match self.log_filter.set(&req.filter) {
+            Ok(()) => {
+                info!("log filter set to {}", req.filter);
+                SetLogLevelResponse::success(self.log_filter.current())
+            }
+            Err(e) => {
+                warn!("failed to set log filter to {}: {}", req.filter, e);
+                SetLogLevelResponse {
+                    filter: self.log_filter.current(),
+                    ..SetLogLevelResponse::failure(StatusCode::Failure)
+                }
+            }
+        };
+
+        call.finish(reply)
+    }
+
+    async fn replay_projection(
+        &self,
+        request: Request<ReplayProjectionRequest>,
+    ) -> Result<Response<ReplayProjectionResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+        self.check_stores_are_sound()?;
+
+        let req = request.into_inner();
+
+        let until = (req.until > 0).then(|| UNIX_EPOCH + Duration::from_secs(req.until as u64));
+        // The users may come back with other metadata.
+        self.metadata_cache.clear();
+        let replayed = self
+            .users()
+            .replay(until)
+            .and_then(|user_events| Ok((user_events, self.sessions().replay(until)?)));
+
+        let reply: ReplayProjectionResponse = match replayed {
+            Ok((user_events, session_events)) => {
+                info!("replayed {} user and {} session events", user_events, session_events);
+                ReplayProjectionResponse::success(user_events as u64, session_events as u64)
+            }
+            Err(e) => {
+                warn!("failed to replay projection: {}", e);
+                ReplayProjectionResponse::failure(StatusCode::Failure)
+            }
+        };
+
+        call.finish(reply)
+    }
+
+    async fn get_stats(
+        &self,
+        request: Request<GetStatsRequest>,
+    ) -> Result<Response<GetStatsResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+
+
+        let reply: GetStatsResponse = self.stats();
+
+        call.finish(reply)
+    }
+
+    async fn stream_stats(
+        &self,
+        request: Request<StreamStatsRequest>,
+    ) -> Result<Response<Self::StreamStatsStream>, Status> {
+        self.log_request("StreamStats", &request);
+
+        self.authorize_admin(&request, "StreamStats")?;
+
+        let interval = match request.into_inner().interval_secs {
+            0 => DEFAULT_STATS_INTERVAL,
+            secs => Duration::from_secs(secs.into()).min(MAX_STATS_INTERVAL),
+        };
+
+        // The first ones straight away, as the feed may not have been refreshed for a while; then
+        // the feed's latest, or the same again when it hasn't been since.
+        let mut stats = self.stats();
+        let mut stats_feed = self.stats_feed.subscribe();
+        let (sender, receiver) = mpsc::channel(1);
+
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            loop {
+                // Gone when the caller is.
+                if sender.send(Ok(stats.clone())).await.is_err() {
+                    break;
+                }
+                ticks.tick().await;
+                match stats_feed.has_changed() {
+                    Ok(true) => stats = stats_feed.borrow_and_update().clone(),
+                    Ok(false) => {}
+                    // And the feed when the service is.
+                    Err(_) => break,
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn get_effective_config(
+        &self,
+        request: Request<GetEffectiveConfigRequest>,
+    ) -> Result<Response<GetEffectiveConfigResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+
+        let settings = self
+            .effective_config
+            .settings
+            .iter()
+            .map(|(name, value)| ConfigSetting { name: name.clone(), value: value.clone() })
+            .collect();
+        let reply: GetEffectiveConfigResponse = GetEffectiveConfigResponse::success(settings);
+
+        call.finish(reply)
+    }
+
+    async fn list_recent_audit_events(
+        &self,
+        request: Request<ListRecentAuditEventsRequest>,
+    ) -> Result<Response<ListRecentAuditEventsResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+
+        let limit = page_size(request.into_inner().limit);
+
+        let events = self.audit_log.recent(limit).into_iter().map(AuditRecord::from).collect();
+        let reply: ListRecentAuditEventsResponse = ListRecentAuditEventsResponse::success(events);
+
+        call.finish(reply)
+    }
+
+    async fn list_throttled_sources(
+        &self,
+        request: Request<ListThrottledSourcesRequest>,
+    ) -> Result<Response<ListThrottledSourcesResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+
+        let limit = page_size(request.into_inner().limit);
+
+        let sources = self.throttle.top_sources(limit, &self.config).into_iter().map(ProtoThrottledSource::from).collect();
+        let reply: ListThrottledSourcesResponse = ListThrottledSourcesResponse::success(sources);
+
+        call.finish(reply)
+    }
+
+    async fn get_rate_limit_state(
+        &self,
+        request: Request<GetRateLimitStateRequest>,
+    ) -> Result<Response<GetRateLimitStateResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+
+        let reply: GetRateLimitStateResponse = match request.into_inner().subject {
+            Some(Subject::Ip(ip)) => match ip.parse() {
+                Ok(ip) => {
+                    let sources = self.throttle.state_of_ip(ip, &self.config).into_iter().map(ProtoThrottledSource::from).collect();
+                    GetRateLimitStateResponse::success(0, sources)
+                }
+                Err(_) => GetRateLimitStateResponse::failure(StatusCode::Failure),
+            },
+            Some(Subject::Username(username)) => {
+                let state = self.throttle.state_of_username(&username, &self.config);
+                GetRateLimitStateResponse::success(state.failures, state.sources.into_iter().map(ProtoThrottledSource::from).collect())
+            }
+            None => GetRateLimitStateResponse::failure(StatusCode::Failure),
+        };
+
+        call.finish(reply)
+    }
+
+    async fn clear_lockout(
+        &self,
+        request: Request<ClearLockoutRequest>,
+    ) -> Result<Response<ClearLockoutResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+
+        let cleared = self.throttle.clear_lockout(&request.into_inner().username);
+        for source in &cleared {
+            self.audit_log.record(AuditEvent::SourceCleared { source: source.clone() });
+        }
+        let reply: ClearLockoutResponse = ClearLockoutResponse::success(cleared);
+
+        call.finish(reply)
+    }
+
+    async fn set_debug_capture(
+        &self,
+        request: Request<SetDebugCaptureRequest>,
+    ) -> Result<Response<SetDebugCaptureResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+
+        let req = request.into_inner();
+
+        let result = self.debug_capture.set_sample_rate(req.sample_rate).map_err(|e| {
+            warn!("failed to set debug capture sample rate to {}: {}", req.sample_rate, e);
+            StatusCode::Failure
+        });
+        if result.is_ok() {
+            info!("debug capture sample rate set to {}", req.sample_rate);
+            if req.clear {
+                self.debug_capture.clear();
+            }
+        }
+
+        let reply: SetDebugCaptureResponse = SetDebugCaptureResponse::from_result(result);
+
+        call.finish(reply)
+    }
+
+    async fn list_debug_captures(
+        &self,
+        request: Request<ListDebugCapturesRequest>,
+    ) -> Result<Response<ListDebugCapturesResponse>, Status> {
+        let call = self.start_admin_rpc(&request)?;
+
+        let req = request.into_inner();
+
+        let method = (!req.method.is_empty()).then_some(req.method.as_str());
+        let calls = self.debug_capture.calls(page_size(req.limit), method).into_iter().map(ProtoCapturedCall::from).collect();
+        let reply: ListDebugCapturesResponse = ListDebugCapturesResponse::success(calls, self.debug_capture.sample_rate());
+
+        call.finish(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api_keys::ApiKeys;
+    use crate::config::Config;
+    use crate::{users::{UsersImpl, UsersOps}, sessions::{SessionsImpl, SessionsOps}};
+
+    use super::*;
+
+    fn admin_request<T>(message: T) -> Request<T> {
+        let mut request = tonic::Request::new(message);
+        request.metadata_mut().insert("x-api-key", "admin-key".parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn suspend_and_unsuspend_user_should_succeed() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+        let user_uuid = users_service.find_user_uuid("123456").unwrap();
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let suspend = || admin_request(SuspendUserRequest { user_uuid: user_uuid.clone() });
+
+        let result = auth_service.suspend_user(suspend()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        // Already suspended
+        let result = auth_service.suspend_user(suspend()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+
+        let result = auth_service
+            .unsuspend_user(admin_request(UnsuspendUserRequest { user_uuid: user_uuid.clone() }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+    }
+
+    #[tokio::test]
+    async fn soft_deleted_user_should_be_hidden_until_restored() {
+        use crate::auth::authentication::{auth_server::Auth, SignInRequest};
+
+        let mut users_service = UsersImpl::default();
+        let _ = users_service.create_user("alice".to_owned(), "password".to_owned());
+        let _ = users_service.create_user("bob".to_owned(), "password".to_owned());
+        let user_uuid = users_service.find_user_uuid("alice").unwrap();
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .config(Config { soft_delete_retention: Duration::ZERO, ..Config::default() })
+            .build();
+
+        let sign_in = || async {
+            let request = Request::new(SignInRequest { username: "alice".to_owned(), password: "password".to_owned(), remember_me: false });
+            auth_service.sign_in(request).await.unwrap().into_inner().status_code()
+        };
+        let auth_service = &auth_service;
+        let list_users = |status: UserStatus| async move {
+            let request = admin_request(ListUsersRequest { status: status.into(), ..Default::default() });
+            let users = auth_service.list_users(request).await.unwrap().into_inner().users;
+            users.into_iter().map(|user| user.username).collect::<Vec<_>>()
+        };
+        let soft_delete = || admin_request(SoftDeleteUserRequest { user_uuid: user_uuid.clone(), reason: "spam".to_owned() });
+        let restore = || admin_request(RestoreUserRequest { user_uuid: user_uuid.clone() });
+
+        assert_eq!(auth_service.soft_delete_user(soft_delete()).await.unwrap().into_inner().status_code(), StatusCode::Success);
+        assert_eq!(auth_service.soft_delete_user(soft_delete()).await.unwrap().into_inner().status_code(), StatusCode::Failure);
+        assert_eq!(sign_in().await, StatusCode::Failure);
+        assert_eq!(list_users(UserStatus::Unspecified).await, ["bob"]);
+        assert_eq!(list_users(UserStatus::SoftDeleted).await, ["alice"]);
+        assert_eq!(
+            auth_service.audit_log.entries_for(&user_uuid)[0].event,
+            AuditEvent::AccountSoftDeleted { user_uuid: user_uuid.clone(), reason: "spam".to_owned() }
+        );
+
+        assert_eq!(auth_service.restore_user(restore()).await.unwrap().into_inner().status_code(), StatusCode::Success);
+        assert_eq!(auth_service.restore_user(restore()).await.unwrap().into_inner().status_code(), StatusCode::Failure);
+        assert_eq!(sign_in().await, StatusCode::Success);
+        assert_eq!(auth_service.purge_soft_deleted_accounts(), 0);
+
+        // Purged once kept for the retention.
+        auth_service.soft_delete_user(soft_delete()).await.unwrap();
+        assert_eq!(auth_service.purge_soft_deleted_accounts(), 1);
+        assert_eq!(auth_service.restore_user(restore()).await.unwrap().into_inner().status_code(), StatusCode::Failure);
+    }
+
+    #[tokio::test]
+    async fn reserve_username_should_keep_the_name_until_released() {
+        use crate::auth::authentication::{auth_server::Auth, SignUpRequest};
+
+        let mut users_service = UsersImpl::default();
+        let _ = users_service.create_user("bob".to_owned(), "password".to_owned());
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let reserve = |username: &str, release: bool| {
+            admin_request(ReserveUsernameRequest { username: username.to_owned(), release })
+        };
+        let sign_up = || {
+            tonic::Request::new(SignUpRequest {
+                username: "billing".to_owned(),
+                password: "password".to_owned(),
+                ..SignUpRequest::default()
+            })
+        };
+
+        let result = auth_service.reserve_username(reserve("bob", false)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::UsernameTaken as i32);
+
+        let result = auth_service.reserve_username(reserve("Billing", false)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        let result = auth_service.sign_up(sign_up()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::UsernameNotAllowed as i32);
+
+        let result = auth_service.reserve_username(reserve("billing", true)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        // Not reserved anymore.
+        let result = auth_service.reserve_username(reserve("billing", true)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+
+        let result = auth_service.sign_up(sign_up()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+    }
+
+    #[tokio::test]
+    async fn honeypot_sign_ins_should_fail_and_raise_an_alert() {
+        use crate::auth::authentication::{auth_server::Auth, SignInRequest, SignUpRequest};
+        use crate::events::tests::RecordingEventSink;
+
+        let mut users_service = UsersImpl::default();
+        let _ = users_service.create_user("bob".to_owned(), "password".to_owned());
+        let event_sink = RecordingEventSink::default();
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .event_sink(Box::new(event_sink.clone()))
+            .build();
+
+        let set = |username: &str, remove: bool| {
+            admin_request(SetHoneypotUsernameRequest { username: username.to_owned(), remove })
+        };
+        let sign_in = || {
+            let mut request = tonic::Request::new(SignInRequest {
+                username: "J.Smith".to_owned(),
+                password: "Summer2024!".to_owned(),
+                remember_me: false,
+            });
+            request.metadata_mut().insert("user-agent", "python-requests/2.31".parse().unwrap());
+            request.metadata_mut().insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+            request
+        };
+
+        let result = auth_service.set_honeypot_username(set("bob", false)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::UsernameTaken as i32);
+        let result = auth_service.set_honeypot_username(set("j.smith", false)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        let result = auth_service.sign_in(sign_in()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+        assert!(result.session_token.is_empty());
+
+        let alert = auth_service.audit_log.recent(1).remove(0).event;
+        assert!(alert.is_alert());
+        assert_eq!(alert.details()["userAgent"], "python-requests/2.31");
+        assert_eq!(
+            *event_sink.events.lock().unwrap(),
+            vec![Event::HoneypotSignIn {
+                username: "J.Smith".to_owned(),
+                ip: String::new(),
+                remote_ip: String::new(),
+                forwarded_for: "203.0.113.7".to_owned(),
+                user_agent: "python-requests/2.31".to_owned(),
+                tenant: String::new(),
+            }]
+        );
+
+        // Looks like somebody's.
+        let sign_up = tonic::Request::new(SignUpRequest {
+            username: "j.smith".to_owned(),
+            password: "password".to_owned(),
+            ..SignUpRequest::default()
+        });
+        let result = auth_service.sign_up(sign_up).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::UsernameTaken as i32);
+
+        let result = auth_service.set_honeypot_username(set("j.smith", true)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        let result = auth_service.set_honeypot_username(set("j.smith", true)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+
+        auth_service.sign_in(sign_in()).await.unwrap();
+        assert_eq!(event_sink.events.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn approval_required_should_hold_sign_ups_until_approved() {
+        use crate::auth::authentication::{auth_server::Auth, SignInRequest, SignUpRequest};
+        use crate::events::tests::RecordingEventSink;
+
+        let mut users_service = UsersImpl::default();
+        let _ = users_service.create_user("bob".to_owned(), "password".to_owned());
+        let bob = users_service.find_user_uuid("bob").unwrap();
+        let _ = users_service.set_account_status(&bob, AccountStatus::PendingApproval);
+
+        let event_sink = RecordingEventSink::default();
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .event_sink(Box::new(event_sink.clone()))
+            .build();
+        auth_service.flags.set(Flag::ApprovalRequired, true);
+
+        let result = auth_service
+            .sign_up(tonic::Request::new(SignUpRequest {
+                username: "alice".to_owned(),
+                password: "password".to_owned(),
+                ..SignUpRequest::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(result.status_code, StatusCode::AccountPendingApproval as i32);
+        let alice = auth_service.users().find_user_uuid("alice").unwrap();
+
+        let sign_in = || {
+            tonic::Request::new(SignInRequest {
+                username: "alice".to_owned(),
+                password: "password".to_owned(),
+                remember_me: false,
+            })
+        };
+        let result = auth_service.sign_in(sign_in()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::AccountPendingApproval as i32);
+
+        let result = auth_service
+            .list_pending_users(admin_request(ListPendingUsersRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        let usernames: Vec<_> = result.users.iter().map(|user| user.username.as_str()).collect();
+        assert_eq!(usernames, ["bob", "alice"]);
+
+        let approve = || admin_request(ApproveUserRequest { user_uuid: alice.clone() });
+        let result = auth_service.approve_user(approve()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        let result = auth_service.sign_in(sign_in()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        // Neither approved twice, nor rejected once approved.
+        let result = auth_service.approve_user(approve()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+        let reject = |user_uuid: &str| admin_request(RejectUserRequest { user_uuid: user_uuid.to_owned() });
+        let result = auth_service.reject_user(reject(&alice)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+
+        let result = auth_service.reject_user(reject(&bob)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(auth_service.users().find_user_uuid("bob"), None);
+
+        assert_eq!(
+            *event_sink.events.lock().unwrap(),
+            [
+                Event::SignUpPendingApproval { user_uuid: alice.clone(), username: "alice".to_owned() },
+                Event::UserApproved { user_uuid: alice },
+                Event::UserRejected { user_uuid: bob },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn revoke_sessions_should_revoke_tokens_and_sessions_of_user() {
+        let mut sessions_service = SessionsImpl::default();
+
+        let leaked = sessions_service.create_session("123456", SessionClass::Standard);
+        let kept = sessions_service.create_session("123456", SessionClass::Standard);
+        let other_user = sessions_service.create_session("654321", SessionClass::LongLived);
+
+        let auth_service = AuthService::builder()
+            .sessions(sessions_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let result = auth_service
+            .revoke_sessions(admin_request(RevokeSessionsRequest {
+                session_tokens: vec![leaked.clone(), "unknown".to_owned()],
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.revoked_count, 1);
+        assert!(auth_service.sessions().get_session(&kept).is_some());
+
+        let result = auth_service
+            .revoke_sessions(admin_request(RevokeSessionsRequest { user_uuid: "654321".to_owned(), ..Default::default() }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(result.revoked_count, 1);
+        assert!(auth_service.sessions().get_session(&other_user).is_none());
+
+        // Sessions created after the cutoff stay.
+        let result = auth_service
+            .revoke_sessions(admin_request(RevokeSessionsRequest {
+                user_uuid: "123456".to_owned(),
+                created_before: 1,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(result.revoked_count, 0);
+
+        let result = auth_service.revoke_sessions(admin_request(RevokeSessionsRequest::default())).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+    }
+
+    #[tokio::test]
+    async fn list_users_should_page_and_filter() {
+        let mut users_service = UsersImpl::default();
+        for username in ["alice", "bob", "carol"] {
+            let _ = users_service.create_user(username.to_owned(), "password".to_owned());
+        }
+        let bob = users_service.find_user_uuid("bob").unwrap();
+        let _ = users_service.set_account_status(&bob, AccountStatus::Suspended);
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let list_users = |request: ListUsersRequest| async {
+            auth_service.list_users(admin_request(request)).await.unwrap().into_inner()
+        };
+        let usernames = |result: &ListUsersResponse| result.users.iter().map(|user| user.username.clone()).collect::<Vec<_>>();
+
+        let request = ListUsersRequest { page_size: 2, order_by: ProtoUserOrder::Username.into(), ..Default::default() };
+        let result = list_users(request.clone()).await;
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(usernames(&result), ["alice", "bob"]);
+        assert_eq!(result.users[1].status, UserStatus::Suspended as i32);
+
+        let result = list_users(ListUsersRequest { page_token: result.next_page_token, ..request.clone() }).await;
+        assert_eq!(usernames(&result), ["carol"]);
+        assert!(result.next_page_token.is_empty());
+
+        let result = list_users(ListUsersRequest { status: UserStatus::Active.into(), ..request.clone() }).await;
+        assert_eq!(usernames(&result), ["alice", "carol"]);
+
+        let result = list_users(ListUsersRequest { page_token: "garbage".to_owned(), ..request }).await;
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+    }
+
+    #[tokio::test]
+    async fn list_sessions_should_page_and_filter() {
+        let mut sessions_service = SessionsImpl::default();
+        let alice = sessions_service.create_session("alice", SessionClass::Standard);
+        let bob = sessions_service.create_session("bob", SessionClass::LongLived);
+        sessions_service.set_session_ip(&bob, "203.0.113.7".parse().unwrap());
+
+        let auth_service = AuthService::builder()
+            .sessions(sessions_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let list_sessions = |request: ListSessionsRequest| async {
+            auth_service.list_sessions(admin_request(request)).await.unwrap().into_inner()
+        };
+
+        let result = list_sessions(ListSessionsRequest { page_size: 1, ..Default::default() }).await;
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.sessions.len(), 1);
+        let result = list_sessions(ListSessionsRequest { page_size: 1, page_token: result.next_page_token, ..Default::default() }).await;
+        assert_eq!(result.sessions.len(), 1);
+        assert!(result.next_page_token.is_empty());
+
+        let result = list_sessions(ListSessionsRequest { user_uuid: "alice".to_owned(), ..Default::default() }).await;
+        assert_eq!(result.sessions.len(), 1);
+        assert_eq!(result.sessions[0].session_token, alice);
+        assert_eq!(result.sessions[0].class, "Standard");
+        assert!(result.sessions[0].ip.is_empty());
+
+        let result = list_sessions(ListSessionsRequest { ip_prefix: "203.0.113.0/24".to_owned(), ..Default::default() }).await;
+        assert_eq!(result.sessions.len(), 1);
+        assert_eq!(result.sessions[0].session_token, bob);
+        assert_eq!(result.sessions[0].ip, "203.0.113.7");
+
+        let result = list_sessions(ListSessionsRequest { idle_longer_than_secs: 60, ..Default::default() }).await;
+        assert!(result.sessions.is_empty());
+
+        let result = list_sessions(ListSessionsRequest { ip_prefix: "not an address".to_owned(), ..Default::default() }).await;
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+    }
+
+    #[tokio::test]
+    async fn maintenance_mode_should_refuse_sign_up_but_not_sign_in() {
+        use crate::auth::authentication::{auth_server::Auth, SignInRequest, SignUpRequest};
+
+        let mut users_service = UsersImpl::default();
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let set_maintenance_mode = |enabled| admin_request(SetMaintenanceModeRequest { enabled });
+        auth_service.set_maintenance_mode(set_maintenance_mode(true)).await.unwrap();
+
+        let sign_up = || {
+            tonic::Request::new(SignUpRequest {
+                username: "abcdef".to_owned(),
+                password: "fedcba".to_owned(),
+                ..SignUpRequest::default()
+            })
+        };
+        let status = auth_service.sign_up(sign_up()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(status.metadata().get("retry-after").unwrap(), "60");
+
+        let result = auth_service
+            .sign_in(tonic::Request::new(SignInRequest {
+                username: "123456".to_owned(),
+                password: "654321".to_owned(),
+                remember_me: false,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        auth_service.set_maintenance_mode(set_maintenance_mode(false)).await.unwrap();
+        let result = auth_service.sign_up(sign_up()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+    }
+
+    #[tokio::test]
+    async fn set_feature_flag_should_flip_invite_only_at_runtime() {
+        use crate::auth::authentication::{auth_server::Auth, SignUpRequest};
+
+        let auth_service = AuthService::builder()
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let set_feature_flag = |flag: &str, enabled| {
+            admin_request(SetFeatureFlagRequest { flag: flag.to_owned(), enabled })
+        };
+        let result = auth_service.set_feature_flag(set_feature_flag("invite_only", true)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert!(result.flags["invite_only"]);
+
+        let sign_up = |username: &str| {
+            tonic::Request::new(SignUpRequest {
+                username: username.to_owned(),
+                password: "fedcba".to_owned(),
+                ..SignUpRequest::default()
+            })
+        };
+        let result = auth_service.sign_up(sign_up("abcdef")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::InvalidInvite as i32);
+
+        let result = auth_service.set_feature_flag(set_feature_flag("mfa_required", true)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+        assert_eq!(result.flags.len(), 5);
+
+        auth_service.set_feature_flag(set_feature_flag("invite_only", false)).await.unwrap();
+        let result = auth_service.sign_up(sign_up("abcdef")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+    }
+
+    #[tokio::test]
+    async fn set_log_level_should_fail_without_reloadable_filter() {
+        let auth_service = AuthService::builder()
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let result = auth_service
+            .set_log_level(admin_request(SetLogLevelRequest { filter: "debug".to_owned() }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+    }
+
+    #[tokio::test]
+    async fn set_debug_capture_should_change_the_sample_rate() {
+        let auth_service = AuthService::builder()
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let set = |sample_rate| admin_request(SetDebugCaptureRequest { sample_rate, clear: true });
+        let result = auth_service.set_debug_capture(set(1.5)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+
+        let result = auth_service.set_debug_capture(set(0.25)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        let result = auth_service
+            .list_debug_captures(admin_request(ListDebugCapturesRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(result.sample_rate, 0.25);
+        assert!(result.calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn get_stats_should_report_jobs() {
+        use crate::jobs::Scheduler;
+
+        let scheduler = Scheduler::new(Duration::from_secs(60), vec![], Duration::ZERO)
+            .with_job("sweep-sessions", || {});
+
+        let auth_service = AuthService::builder()
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .job_statuses(scheduler.statuses())
+            .build();
+
+        let result = auth_service.get_stats(admin_request(GetStatsRequest {})).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.jobs.len(), 1);
+        assert_eq!(result.jobs[0].name, "sweep-sessions");
+        assert_eq!(result.jobs[0].interval_secs, 60);
+        assert_eq!(result.jobs[0].runs, 0);
+    }
+
+    #[tokio::test]
+    async fn get_effective_config_should_list_the_settings_redacted() {
+        use crate::effective_config::EffectiveConfig;
+        use crate::quotas::KeyQuota;
+
+        let config = Config {
+            invite_only: true,
+            api_key_quotas: vec![KeyQuota { key: "partner-key".to_owned(), per_minute: 60 }],
+            ..Config::default()
+        };
+        let auth_service = AuthService::builder()
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .effective_config(EffectiveConfig::from_config(&config).with("users_backend", "ldap"))
+            .config(config)
+            .build();
+
+        assert!(auth_service.get_effective_config(tonic::Request::new(GetEffectiveConfigRequest {})).await.is_err());
+
+        let result = auth_service.get_effective_config(admin_request(GetEffectiveConfigRequest {})).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        let value = |name: &str| result.settings.iter().find(|setting| setting.name == name).map(|setting| setting.value.as_str());
+        assert_eq!(value("invite_only"), Some("true"));
+        assert_eq!(value("users_backend"), Some("ldap"));
+        assert!(!value("api_key_quotas").unwrap().contains("partner-key"));
+    }
+
+    #[tokio::test]
+    async fn get_stats_should_report_rpcs() {
+        use crate::auth::authentication::{auth_server::Auth, SignOutRequest};
+
+        let auth_service = AuthService::builder()
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        // No such session: answered, but as a failure.
+        auth_service.sign_out(tonic::Request::new(SignOutRequest { session_token: "unknown".to_owned() })).await.unwrap();
+        // Not an admin: refused, but counted all the same.
+        assert!(auth_service.get_stats(tonic::Request::new(GetStatsRequest {})).await.is_err());
+
+        let result = auth_service.get_stats(admin_request(GetStatsRequest {})).await.unwrap().into_inner();
+
+        let rpcs: Vec<_> = result.rpcs.iter().map(|rpc| (rpc.rpc.as_str(), rpc.calls, rpc.failures)).collect();
+        assert_eq!(rpcs, [("GetStats", 1, 1), ("SignOut", 1, 1)]);
+        assert_eq!(result.rpcs[0].duration_buckets.iter().sum::<u64>(), 1);
+    }
+
+    #[tokio::test]
+    async fn stream_stats_should_push_the_latest_stats() {
+        use crate::auth::authentication::{auth_server::Auth, SignInRequest};
+
+        let auth_service = Arc::new(
+            AuthService::builder()
+                .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+                .build(),
+        );
+        auth_service.start_stats_feed();
+
+        let stream = auth_service
+            .stream_stats(admin_request(StreamStatsRequest { interval_secs: 1 }))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut stats = stream.into_inner();
+
+        let first = stats.recv().await.unwrap().unwrap();
+        assert_eq!(first.status_code, StatusCode::Success as i32);
+        assert_eq!(first.refused_sign_ins, 0);
+
+        let request = SignInRequest { username: "nobody".to_owned(), password: "wrong".to_owned(), remember_me: false };
+        auth_service.sign_in(tonic::Request::new(request)).await.unwrap();
+
+        // Within a refresh of the feed and a push.
+        let mut refused_sign_ins = 0;
+        for _ in 0..3 {
+            refused_sign_ins = stats.recv().await.unwrap().unwrap().refused_sign_ins;
+            if refused_sign_ins == 1 {
+                break;
+            }
+        }
+        assert_eq!(refused_sign_ins, 1);
+    }
+
+    #[tokio::test]
+    async fn should_list_recent_audit_events_and_throttled_sources() {
+        let auth_service = AuthService::builder()
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let ip = "192.0.2.1".parse().unwrap();
+        for _ in 0..3 {
+            auth_service.throttle.record_failure(ip, "alice", &auth_service.config);
+        }
+        auth_service.audit_log.record(AuditEvent::AccountPurged { user_uuid: "123456".to_owned() });
+
+        let result = auth_service
+            .list_recent_audit_events(admin_request(ListRecentAuditEventsRequest { limit: 0 }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.events[0].kind, "AccountPurged");
+        assert_eq!(result.events[0].user_uuid, "123456");
+
+        let result = auth_service
+            .list_throttled_sources(admin_request(ListThrottledSourcesRequest { limit: 1 }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.sources.len(), 1);
+        assert_eq!(result.sources[0].failures, 3);
+    }
+
+    #[tokio::test]
+    async fn should_show_and_clear_the_lockout_of_a_username() {
+        use crate::throttle::Verdict;
+
+        let auth_service = AuthService::builder()
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let ip = "192.0.2.1".parse().unwrap();
+        for _ in 0..auth_service.config.throttle_ip_block_after {
+            auth_service.throttle.record_failure(ip, "alice", &auth_service.config);
+        }
+        assert_eq!(auth_service.throttle.check(ip, &auth_service.config), Verdict::Block);
+
+        let request = GetRateLimitStateRequest { subject: Some(Subject::Username("alice".to_owned())) };
+        let result = auth_service.get_rate_limit_state(admin_request(request)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.username_failures, auth_service.config.throttle_ip_block_after);
+        assert_eq!(result.sources[0].source, "192.0.2.1");
+        assert!(result.sources[0].blocked_for_secs > 0);
+
+        let request = GetRateLimitStateRequest { subject: Some(Subject::Ip("192.0.2.1".to_owned())) };
+        let result = auth_service.get_rate_limit_state(admin_request(request)).await.unwrap().into_inner();
+        let sources: Vec<_> = result.sources.iter().map(|source| source.source.as_str()).collect();
+        assert_eq!(sources, ["192.0.2.1", "192.0.2.0/24"]);
+
+        let request = GetRateLimitStateRequest { subject: Some(Subject::Ip("not an address".to_owned())) };
+        let result = auth_service.get_rate_limit_state(admin_request(request)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+
+        let result = auth_service
+            .clear_lockout(admin_request(ClearLockoutRequest { username: "alice".to_owned() }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.cleared_sources, ["192.0.2.1", "192.0.2.0/24"]);
+        assert_eq!(auth_service.throttle.check(ip, &auth_service.config), Verdict::Allow);
+        assert_eq!(
+            auth_service.audit_log.recent(10)[0].event,
+            AuditEvent::SourceCleared { source: "192.0.2.0/24".to_owned() }
+        );
+
+        let request = GetRateLimitStateRequest { subject: Some(Subject::Username("alice".to_owned())) };
+        let result = auth_service.get_rate_limit_state(admin_request(request)).await.unwrap().into_inner();
+        assert_eq!((result.username_failures, result.sources.len()), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn replay_projection_should_fail_without_journal() {
+        let auth_service = AuthService::builder()
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let result = auth_service
+            .replay_projection(admin_request(ReplayProjectionRequest { until: 0 }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+    }
+
+    #[tokio::test]
+    async fn suspend_user_should_require_admin_key() {
+        let auth_service = AuthService::builder()
+            .api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let result = auth_service
+            .suspend_user(admin_request(SuspendUserRequest { user_uuid: "123456".to_owned() }))
+            .await;
+
+        assert_eq!(result.unwrap_err().code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn admin_rpcs_should_be_refused_when_the_policy_does_not_allow_them() {
+        use crate::admin_policy::{AdminPrincipals, ExpressionPolicy};
+
+        let mut sessions_service = SessionsImpl::default();
+        let session_token = sessions_service.create_session("123456", SessionClass::Standard);
+        sessions_service.pin_session(&session_token, "acme");
+
+        let policy: ExpressionPolicy = "allow support SuspendUser when principal.tenant == request.tenant".parse().unwrap();
+        let auth_service = AuthService::builder()
+            .sessions(sessions_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .admin_principals(AdminPrincipals::new([("admin-key".to_owned(), "jo:support@acme".parse().unwrap())]))
+            .admin_policy(Box::new(policy))
+            .build();
+        let suspend = |tenant: &str| {
+            let mut request = admin_request(SuspendUserRequest { user_uuid: "123456".to_owned() });
+            request.metadata_mut().insert("x-tenant-id", tenant.parse().unwrap());
+            request
+        };
+
+        assert!(auth_service.suspend_user(suspend("acme")).await.is_ok());
+
+        let result = auth_service.suspend_user(suspend("globex")).await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::PermissionDenied);
+
+        let result = auth_service.revoke_long_lived_sessions(admin_request(RevokeLongLivedSessionsRequest {})).await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn admin_rpcs_should_be_refused_for_targets_of_another_tenant() {
+        use crate::admin_policy::{AdminPrincipals, ExpressionPolicy};
+
+        let mut sessions_service = SessionsImpl::default();
+        let acme_session = sessions_service.create_session("acme-user", SessionClass::Standard);
+        sessions_service.pin_session(&acme_session, "acme");
+        let globex_session = sessions_service.create_session("globex-user", SessionClass::Standard);
+        sessions_service.pin_session(&globex_session, "globex");
+
+        let policy: ExpressionPolicy =
+            "allow support SuspendUser,RevokeSessions when principal.tenant == request.tenant".parse().unwrap();
+        let auth_service = AuthService::builder()
+            .sessions(sessions_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .admin_principals(AdminPrincipals::new([("admin-key".to_owned(), "jo:support@acme".parse().unwrap())]))
+            .admin_policy(Box::new(policy))
+            .build();
+        fn for_tenant<T>(mut request: tonic::Request<T>, tenant: Option<&str>) -> tonic::Request<T> {
+            if let Some(tenant) = tenant {
+                request.metadata_mut().insert("x-tenant-id", tenant.parse().unwrap());
+            }
+            request
+        }
+        let suspend = |user_uuid: &str, tenant| {
+            for_tenant(admin_request(SuspendUserRequest { user_uuid: user_uuid.to_owned() }), tenant)
+        };
+
+        assert!(auth_service.suspend_user(suspend("acme-user", None)).await.is_ok());
+        assert!(auth_service.suspend_user(suspend("acme-user", Some("acme"))).await.is_ok());
+
+        // Claiming their own tenant doesn't make another tenant's user theirs.
+        let result = auth_service.suspend_user(suspend("globex-user", Some("acme"))).await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::PermissionDenied);
+        let result = auth_service.suspend_user(suspend("globex-user", None)).await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::PermissionDenied);
+
+        let revoke = for_tenant(
+            admin_request(RevokeSessionsRequest { session_tokens: vec![globex_session.clone()], ..Default::default() }),
+            Some("acme"),
+        );
+        let result = auth_service.revoke_sessions(revoke).await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::PermissionDenied);
+        assert!(auth_service.sessions().get_session(&globex_session).is_some());
+    }
+
+    #[tokio::test]
+    async fn admin_rpcs_should_go_by_the_tenants_on_record_of_signed_out_users() {
+        use crate::admin_policy::{AdminPrincipals, ExpressionPolicy};
+        use crate::auth::authentication::{auth_server::Auth, SignInRequest};
+
+        let mut users_service = UsersImpl::default();
+        for username in ["globex-user", "unseen-user"] {
+            users_service.create_user(username.to_owned(), "654321".to_owned()).unwrap();
+        }
+        let globex_user = users_service.find_user_uuid("globex-user").unwrap();
+        let unseen_user = users_service.find_user_uuid("unseen-user").unwrap();
+
+        let policy: ExpressionPolicy = "allow support SuspendUser when principal.tenant == request.tenant".parse().unwrap();
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .admin_principals(AdminPrincipals::new([("admin-key".to_owned(), "jo:support@acme".parse().unwrap())]))
+            .admin_policy(Box::new(policy))
+            .config(Config { tenants: vec!["acme".to_owned(), "globex".to_owned()], ..Config::default() })
+            .build();
+
+        let mut sign_in = tonic::Request::new(SignInRequest {
+            username: "globex-user".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+        sign_in.metadata_mut().insert("x-tenant-id", "globex".parse().unwrap());
+        let session_token = auth_service.sign_in(sign_in).await.unwrap().into_inner().session_token;
+        auth_service.sessions().delete_session(&session_token);
+        assert!(auth_service.sessions().get_sessions_of_user(&globex_user).is_empty());
+
+        let suspend = |user_uuid: &str| {
+            let mut request = admin_request(SuspendUserRequest { user_uuid: user_uuid.to_owned() });
+            request.metadata_mut().insert("x-tenant-id", "acme".parse().unwrap());
+            request
+        };
+
+        let result = auth_service.suspend_user(suspend(&globex_user)).await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::PermissionDenied);
+        let result = auth_service.suspend_user(suspend(&unseen_user)).await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::PermissionDenied);
+        assert_eq!(auth_service.users().get_account_status(&globex_user), Some(AccountStatus::Active));
+        assert_eq!(auth_service.users().get_account_status(&unseen_user), Some(AccountStatus::Active));
+    }
+
+    #[tokio::test]
+    async fn revoke_long_lived_sessions_should_keep_standard_sessions() {
+        let mut sessions_service = SessionsImpl::default();
+
+        let standard = sessions_service.create_session("123456", SessionClass::Standard);
+        let long_lived = sessions_service.create_session("123456", SessionClass::LongLived);
+
+        let auth_service = AuthService::builder()
+            .sessions(sessions_service)
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()]))
+            .build();
+
+        let result = auth_service
+            .revoke_long_lived_sessions(admin_request(RevokeLongLivedSessionsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.revoked_count, 1);
+
+        let sessions_service = auth_service.sessions_service.lock().unwrap();
+        assert!(sessions_service.get_session(&standard).is_some());
+        assert!(sessions_service.get_session(&long_lived).is_none());
+    }
+}
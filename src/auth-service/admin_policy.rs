@@ -0,0 +1,460 @@
+use std::{collections::HashMap, env, fs, str::FromStr};
+
+use tonic::metadata::MetadataMap;
+
+use crate::auth::authentication::{
+    get_rate_limit_state_request::Subject, ApproveUserRequest, ClearLockoutRequest, CreateInviteRequest,
+    ExportUserDataRequest, GetEffectiveConfigRequest, GetRateLimitStateRequest, GetStatsRequest, ListDebugCapturesRequest,
+    ListPendingUsersRequest, ListRecentAuditEventsRequest, ListSessionsRequest, ListThrottledSourcesRequest,
+    ListUsersRequest, RejectUserRequest, ReplayProjectionRequest, ReserveUsernameRequest, RestoreUserRequest,
+    RevokeLongLivedSessionsRequest, RevokeSessionsRequest, SetDebugCaptureRequest, SetFeatureFlagRequest,
+    SetHoneypotUsernameRequest, SetLogLevelRequest, SetMaintenanceModeRequest, SetUserScopesRequest, SoftDeleteUserRequest,
+    StreamStatsRequest, SuspendUserRequest, UnsuspendUserRequest,
+};
+
+// Who is calling an admin RPC: the operator behind the admin API key, as AUTH_ADMIN_PRINCIPALS
+// describes them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Principal {
+    pub name: String,
+    pub role: String,
+    // The tenant they look after; None for all of them.
+    pub tenant: Option<String>,
+}
+
+impl Principal {
+    // Who is behind a key AUTH_ADMIN_PRINCIPALS doesn't describe: an admin, as every key was before
+    // there were roles.
+    pub fn operator() -> Self {
+        Self { name: String::from("operator"), role: String::from(ADMIN_ROLE), tenant: None }
+    }
+}
+
+// The role allowed every admin RPC by default.
+pub const ADMIN_ROLE: &str = "admin";
+
+// "key=name:role" or "key=name:role@tenant".
+impl FromStr for Principal {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, role) = value.split_once(':').ok_or_else(|| String::from("Error::InvalidPrincipal"))?;
+        let (role, tenant) = match role.split_once('@') {
+            Some((role, tenant)) => (role, Some(tenant.trim().to_owned())),
+            None => (role, None),
+        };
+        if name.trim().is_empty() || role.trim().is_empty() {
+            return Err(String::from("Error::InvalidPrincipal"));
+        }
+        Ok(Self { name: name.trim().to_owned(), role: role.trim().to_owned(), tenant })
+    }
+}
+
+// The principals behind the admin API keys. Which keys are accepted is still up to
+// AUTH_ADMIN_API_KEYS; this only says who they are.
+#[derive(Debug, Default)]
+pub struct AdminPrincipals {
+    by_key: HashMap<String, Principal>,
+}
+
+impl AdminPrincipals {
+    pub fn new<I: IntoIterator<Item = (String, Principal)>>(principals: I) -> Self {
+        Self { by_key: principals.into_iter().collect() }
+    }
+
+    // `var` (e.g. AUTH_ADMIN_PRINCIPALS) holds a comma separated list of "key=name:role[@tenant]".
+    pub fn from_env(var: &str) -> Result<Self, String> {
+        let value = env::var(var).unwrap_or_default();
+        let principals = value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (key, principal) = entry.split_once('=').ok_or_else(|| format!("Error::InvalidConfig: {var}"))?;
+                let principal = principal.parse().map_err(|_| format!("Error::InvalidConfig: {var}"))?;
+                Ok((key.trim().to_owned(), principal))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self::new(principals))
+    }
+
+    // Who is behind the request's x-api-key, once authenticated.
+    pub fn principal(&self, metadata: &MetadataMap) -> Principal {
+        metadata
+            .get("x-api-key")
+            .and_then(|key| key.to_str().ok())
+            .and_then(|key| self.by_key.get(key))
+            .cloned()
+            .unwrap_or_else(Principal::operator)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_key.is_empty()
+    }
+}
+
+// An admin RPC about to be handled, with what a policy decides on.
+#[derive(Clone, Copy, Debug)]
+pub struct AdminCall<'a> {
+    pub principal: &'a Principal,
+    // The RPC, e.g. "SuspendUser".
+    pub action: &'a str,
+    // The tenant the call is for: that of its target (see `AdminTarget`), or for RPCs without one
+    // (or a target of no tenant yet) x-tenant-id; None without either, or for a target spanning
+    // several tenants.
+    pub tenant: Option<&'a str>,
+}
+
+// What an admin RPC acts on, so that the call is checked against the tenant of its target rather
+// than the one it claims (see `AuthService::authorize_admin`). RPCs about no one in particular
+// have no target.
+pub trait AdminTarget {
+    fn target_user(&self) -> Option<&str> {
+        None
+    }
+
+    fn target_username(&self) -> Option<&str> {
+        None
+    }
+
+    fn target_sessions(&self) -> &[String] {
+        &[]
+    }
+}
+
+fn non_empty(value: &str) -> Option<&str> {
+    Some(value).filter(|value| !value.is_empty())
+}
+
+macro_rules! admin_targets {
+    (users: [$($user:ty),* $(,)?], none: [$($none:ty),* $(,)?] $(,)?) => {
+        $(impl AdminTarget for $user {
+            fn target_user(&self) -> Option<&str> {
+                non_empty(&self.user_uuid)
+            }
+        })*
+        $(impl AdminTarget for $none {})*
+    };
+}
+
+admin_targets! {
+    users: [
+        SuspendUserRequest,
+        UnsuspendUserRequest,
+        SoftDeleteUserRequest,
+        RestoreUserRequest,
+        ApproveUserRequest,
+        RejectUserRequest,
+        SetUserScopesRequest,
+        ListSessionsRequest,
+        ExportUserDataRequest,
+    ],
+    none: [
+        ListPendingUsersRequest,
+        RevokeLongLivedSessionsRequest,
+        ListUsersRequest,
+        CreateInviteRequest,
+        ReserveUsernameRequest,
+        SetHoneypotUsernameRequest,
+        SetMaintenanceModeRequest,
+        SetFeatureFlagRequest,
+        SetLogLevelRequest,
+        GetStatsRequest,
+        StreamStatsRequest,
+        GetEffectiveConfigRequest,
+        ListRecentAuditEventsRequest,
+        ListThrottledSourcesRequest,
+        SetDebugCaptureRequest,
+        ListDebugCapturesRequest,
+        ReplayProjectionRequest,
+    ],
+}
+
+impl AdminTarget for RevokeSessionsRequest {
+    fn target_user(&self) -> Option<&str> {
+        non_empty(&self.user_uuid)
+    }
+
+    fn target_sessions(&self) -> &[String] {
+        &self.session_tokens
+    }
+}
+
+impl AdminTarget for ClearLockoutRequest {
+    fn target_username(&self) -> Option<&str> {
+        non_empty(&self.username)
+    }
+}
+
+impl AdminTarget for GetRateLimitStateRequest {
+    fn target_username(&self) -> Option<&str> {
+        match &self.subject {
+            Some(Subject::Username(username)) => non_empty(username),
+            _ => None,
+        }
+    }
+}
+
+// Decides who may call which admin RPC, consulted after the API key is authenticated and before
+// the call is handled.
+pub trait Policy {
+    fn allows(&self, call: &AdminCall) -> bool;
+}
+
+// The RPCs of each role (AUTH_ADMIN_ROLE_GRANTS), "*" standing for all of them. Admins may call all
+// of them, unless granted otherwise.
+#[derive(Debug)]
+pub struct AllowList {
+    grants: HashMap<String, Vec<String>>,
+}
+
+impl Default for AllowList {
+    fn default() -> Self {
+        Self { grants: HashMap::from([(ADMIN_ROLE.to_owned(), vec![String::from("*")])]) }
+    }
+}
+
+impl AllowList {
+    pub fn from_grants(grants: &[RoleGrant]) -> Self {
+        grants.iter().fold(Self::default(), |allow_list, grant| allow_list.with_grant(&grant.role, &grant.actions))
+    }
+
+    pub fn with_grant(mut self, role: &str, actions: &[String]) -> Self {
+        self.grants.insert(role.to_owned(), actions.to_vec());
+        self
+    }
+}
+
+impl Policy for AllowList {
+    fn allows(&self, call: &AdminCall) -> bool {
+        self.grants
+            .get(&call.principal.role)
+            .is_some_and(|actions| actions.iter().any(|action| action == "*" || action == call.action))
+    }
+}
+
+// A role and the RPCs it may call: "support=SuspendUser|UnsuspendUser".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoleGrant {
+    pub role: String,
+    pub actions: Vec<String>,
+}
+
+impl FromStr for RoleGrant {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (role, actions) = value.split_once('=').ok_or_else(|| String::from("Error::InvalidRoleGrant"))?;
+        let actions: Vec<String> = actions.split('|').map(str::trim).filter(|action| !action.is_empty()).map(String::from).collect();
+        if role.trim().is_empty() || actions.is_empty() {
+            return Err(String::from("Error::InvalidRoleGrant"));
+        }
+        Ok(Self { role: role.trim().to_owned(), actions })
+    }
+}
+
+// Rules read from AUTH_ADMIN_POLICY_FILE, one a line; blank lines and those starting with `#` are
+// skipped:
+//
+//     allow admin *
+//     allow support SuspendUser,UnsuspendUser when principal.tenant == request.tenant
+//     deny * DeleteUser when request.tenant == "production"
+//
+// A call is allowed when an `allow` rule matches it and no `deny` rule does. Conditions compare
+// principal.name, principal.role, principal.tenant, request.tenant, action or a quoted string, with
+// == or !=, joined by `and`. A comparison with a tenant that isn't there never holds.
+#[derive(Debug, Default)]
+pub struct ExpressionPolicy {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    allow: bool,
+    // None for any.
+    role: Option<String>,
+    actions: Option<Vec<String>>,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug)]
+struct Condition {
+    left: Operand,
+    equal: bool,
+    right: Operand,
+}
+
+#[derive(Debug)]
+enum Operand {
+    PrincipalName,
+    PrincipalRole,
+    PrincipalTenant,
+    RequestTenant,
+    Action,
+    Literal(String),
+}
+
+impl ExpressionPolicy {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let rules = fs::read_to_string(path).map_err(|e| format!("Failed to read {path}.\n{e:?}"))?;
+        rules.parse()
+    }
+}
+
+impl FromStr for ExpressionPolicy {
+    type Err = String;
+
+    fn from_str(rules: &str) -> Result<Self, Self::Err> {
+        let rules = rules
+            .lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(n, line)| Rule::parse(line).ok_or_else(|| format!("Error::InvalidAdminPolicy: line {n}")))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+}
+
+impl Policy for ExpressionPolicy {
+    fn allows(&self, call: &AdminCall) -> bool {
+        let matching = |allow: bool| self.rules.iter().any(|rule| rule.allow == allow && rule.matches(call));
+        matching(true) && !matching(false)
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let (head, conditions) = match line.split_once(" when ") {
+            Some((head, conditions)) => (head, Some(conditions)),
+            None => (line, None),
+        };
+        let any = |value: &str| (value != "*").then(|| value.to_owned());
+
+        let mut words = head.split_whitespace();
+        let allow = match words.next()? {
+            "allow" => true,
+            "deny" => false,
+            _ => return None,
+        };
+        let role = any(words.next()?);
+        let actions = any(words.next()?).map(|actions| actions.split(',').map(String::from).collect());
+        if words.next().is_some() {
+            return None;
+        }
+        let conditions = match conditions {
+            Some(conditions) => conditions.split(" and ").map(Condition::parse).collect::<Option<_>>()?,
+            None => Vec::new(),
+        };
+
+        Some(Self { allow, role, actions, conditions })
+    }
+
+    fn matches(&self, call: &AdminCall) -> bool {
+        self.role.as_ref().is_none_or(|role| *role == call.principal.role)
+            && self.actions.as_ref().is_none_or(|actions| actions.iter().any(|action| action == call.action))
+            && self.conditions.iter().all(|condition| condition.holds(call))
+    }
+}
+
+impl Condition {
+    fn parse(condition: &str) -> Option<Self> {
+        let (left, equal, right) = match condition.split_once("!=") {
+            Some((left, right)) => (left, false, right),
+            None => condition.split_once("==").map(|(left, right)| (left, true, right))?,
+        };
+        Some(Self { left: Operand::parse(left.trim())?, equal, right: Operand::parse(right.trim())? })
+    }
+
+    fn holds(&self, call: &AdminCall) -> bool {
+        match (self.left.value(call), self.right.value(call)) {
+            (Some(left), Some(right)) => (left == right) == self.equal,
+            _ => false,
+        }
+    }
+}
+
+impl Operand {
+    fn parse(operand: &str) -> Option<Self> {
+        Some(match operand {
+            "principal.name" => Self::PrincipalName,
+            "principal.role" => Self::PrincipalRole,
+            "principal.tenant" => Self::PrincipalTenant,
+            "request.tenant" => Self::RequestTenant,
+            "action" => Self::Action,
+            _ => Self::Literal(operand.strip_prefix('"')?.strip_suffix('"')?.to_owned()),
+        })
+    }
+
+    fn value<'a>(&'a self, call: &AdminCall<'a>) -> Option<&'a str> {
+        match self {
+            Self::PrincipalName => Some(&call.principal.name),
+            Self::PrincipalRole => Some(&call.principal.role),
+            Self::PrincipalTenant => call.principal.tenant.as_deref(),
+            Self::RequestTenant => call.tenant,
+            Self::Action => Some(call.action),
+            Self::Literal(value) => Some(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn support(tenant: &str) -> Principal {
+        format!("jo:support@{tenant}").parse().unwrap()
+    }
+
+    fn call<'a>(principal: &'a Principal, action: &'a str, tenant: Option<&'a str>) -> AdminCall<'a> {
+        AdminCall { principal, action, tenant }
+    }
+
+    #[test]
+    fn should_allow_admins_everything_and_other_roles_their_grants() {
+        let allow_list = AllowList::from_grants(&["support=SuspendUser|UnsuspendUser".parse().unwrap()]);
+        let support = support("acme");
+
+        assert!(allow_list.allows(&call(&Principal::operator(), "DeleteUser", None)));
+        assert!(allow_list.allows(&call(&support, "SuspendUser", None)));
+        assert!(!allow_list.allows(&call(&support, "DeleteUser", None)));
+        assert!("support=".parse::<RoleGrant>().is_err());
+    }
+
+    #[test]
+    fn should_evaluate_rules_with_conditions() {
+        let policy: ExpressionPolicy = r#"
+            # Support looks after the users of their tenant only.
+            allow admin *
+            allow support SuspendUser,UnsuspendUser when principal.tenant == request.tenant
+            deny * DeleteUser when request.tenant == "production" and principal.name != "ops"
+        "#
+        .parse()
+        .unwrap();
+        let support = support("acme");
+        let admin = Principal::operator();
+
+        assert!(policy.allows(&call(&support, "SuspendUser", Some("acme"))));
+        assert!(!policy.allows(&call(&support, "SuspendUser", Some("globex"))));
+        assert!(!policy.allows(&call(&support, "SuspendUser", None)));
+        assert!(!policy.allows(&call(&support, "DeleteUser", Some("acme"))));
+
+        assert!(policy.allows(&call(&admin, "DeleteUser", Some("acme"))));
+        assert!(!policy.allows(&call(&admin, "DeleteUser", Some("production"))));
+        assert!(policy.allows(&call(&"ops:admin".parse().unwrap(), "DeleteUser", Some("production"))));
+    }
+
+    #[test]
+    fn should_refuse_rules_it_cannot_read() {
+        assert_eq!(
+            "allow admin *\npermit support *".parse::<ExpressionPolicy>().unwrap_err(),
+            "Error::InvalidAdminPolicy: line 2"
+        );
+        assert!("allow support * when principal.tenant == tenant".parse::<ExpressionPolicy>().is_err());
+        assert!("allow support".parse::<ExpressionPolicy>().is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::{collections::HashSet, env};
+
+use tonic::{metadata::MetadataMap, Status};
+
+// Keys identifying the services (resource servers, gateways, ...) that may call the
+// service-to-service RPCs, such as `IntrospectToken`, or the operators allowed to use the admin
+// RPCs. They are passed in the `x-api-key` metadata.
+#[derive(Default)]
+pub struct ApiKeys {
+    keys: HashSet<String>,
+}
+
+impl ApiKeys {
+    pub fn new<I: IntoIterator<Item = String>>(keys: I) -> Self {
+        Self {
+            keys: keys.into_iter().filter(|key| !key.is_empty()).collect(),
+        }
+    }
+
+    // `var` (e.g. AUTH_API_KEYS) holds a comma separated list of accepted keys.
+    pub fn from_env(var: &str) -> Self {
+        Self::new(
+            env::var(var)
+                .unwrap_or_default()
+                .split(',')
+                .map(|key| key.trim().to_owned()),
+        )
+    }
+
+    // With no keys configured, nobody is allowed in.
+    pub fn authenticate(&self, metadata: &MetadataMap) -> Result<(), Status> {
+        metadata
+            .get("x-api-key")
+            .and_then(|key| key.to_str().ok())
+            .filter(|key| self.keys.contains(*key))
+            .map(|_| ())
+            .ok_or_else(|| Status::unauthenticated("a valid x-api-key is required"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_authenticate_known_key() {
+        let api_keys = ApiKeys::new(vec!["key-1".to_owned()]);
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert("x-api-key", "key-1".parse().unwrap());
+
+        assert!(api_keys.authenticate(&metadata).is_ok());
+    }
+
+    #[test]
+    fn should_reject_unknown_or_missing_key() {
+        let api_keys = ApiKeys::new(vec!["key-1".to_owned()]);
+
+        let mut metadata = MetadataMap::new();
+        assert!(api_keys.authenticate(&metadata).is_err());
+
+        metadata.insert("x-api-key", "key-2".parse().unwrap());
+        assert!(api_keys.authenticate(&metadata).is_err());
+    }
+
+    #[test]
+    fn should_reject_everything_without_keys() {
+        let api_keys = ApiKeys::new(vec!["".to_owned()]);
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert("x-api-key", "".parse().unwrap());
+
+        assert!(api_keys.authenticate(&metadata).is_err());
+    }
+}
@@ -0,0 +1,312 @@
+use std::{collections::HashMap, sync::{Mutex, PoisonError}, time::SystemTime};
+
+use tokio::sync::broadcast;
+use tracing::info;
+
+// Security relevant things that happened to an account, kept so they can be reviewed later.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuditEvent {
+    UsernameChanged {
+        user_uuid: String,
+        old_username: String,
+        new_username: String,
+    },
+    // Every session of the user was signed out with it, on every replica.
+    PasswordChanged {
+        user_uuid: String,
+        sessions_revoked: usize,
+    },
+    AccountDeletionRequested {
+        user_uuid: String,
+    },
+    // Signed in again during the deletion grace period.
+    AccountRestored {
+        user_uuid: String,
+    },
+    AccountPurged {
+        user_uuid: String,
+    },
+    // Taken down by an operator (SoftDeleteUser), and brought back (RestoreUser).
+    AccountSoftDeleted {
+        user_uuid: String,
+        reason: String,
+    },
+    SoftDeletedAccountRestored {
+        user_uuid: String,
+    },
+    // Too many failed sign-ins from an address or network (see `SourceThrottle`).
+    SourceTarpitted {
+        source: String,
+    },
+    SourceBlocked {
+        source: String,
+    },
+    // Forgotten by an operator (ClearLockout), to let a user sign in again.
+    SourceCleared {
+        source: String,
+    },
+    // Signed in from an unusual place (see `SignInLocations`).
+    SignInAnomaly {
+        user_uuid: String,
+        ip: String,
+        reason: String,
+    },
+    // Signed out to make room for a new session (see `SessionsImpl::with_max_sessions`).
+    SessionEvicted {
+        user_uuid: String,
+        class: String,
+    },
+    // A session token presented by another client than the one it is bound to (see
+    // `client_fingerprint`): most likely stolen, and refused.
+    SessionBindingMismatch {
+        user_uuid: String,
+    },
+    // A session token presented for another tenant than its own (see `request_tenant`), and
+    // refused; `tenant` is the one it was presented for.
+    TenantMismatch {
+        user_uuid: String,
+        tenant: String,
+    },
+    // A sign-in as a honeypot (see `HoneypotUsernames`), which nobody has a reason to try but a
+    // credential stuffing campaign; `ip` as `PeerInfo::client_ip`, `remote_ip` the connection's.
+    HoneypotSignIn {
+        username: String,
+        ip: String,
+        remote_ip: String,
+        user_agent: String,
+    },
+    // What a sign_up or RequestMagicLink really came to, answered as something else so as not to
+    // tell whether the username is somebody's (AUTH_ENUMERATION_PROTECTION).
+    OutcomeMasked {
+        rpc: String,
+        username: String,
+        outcome: String,
+    },
+}
+
+impl AuditEvent {
+    // None for events that are not about a particular account.
+    pub fn user_uuid(&self) -> Option<&str> {
+        match self {
+            AuditEvent::UsernameChanged { user_uuid, .. }
+            | AuditEvent::PasswordChanged { user_uuid, .. }
+            | AuditEvent::AccountDeletionRequested { user_uuid }
+            | AuditEvent::AccountRestored { user_uuid }
+            | AuditEvent::AccountPurged { user_uuid }
+            | AuditEvent::AccountSoftDeleted { user_uuid, .. }
+            | AuditEvent::SoftDeletedAccountRestored { user_uuid }
+            | AuditEvent::SignInAnomaly { user_uuid, .. }
+            | AuditEvent::SessionEvicted { user_uuid, .. }
+            | AuditEvent::SessionBindingMismatch { user_uuid }
+            | AuditEvent::TenantMismatch { user_uuid, .. } => Some(user_uuid),
+            AuditEvent::SourceTarpitted { .. }
+            | AuditEvent::SourceBlocked { .. }
+            | AuditEvent::SourceCleared { .. }
+            | AuditEvent::HoneypotSignIn { .. }
+            | AuditEvent::OutcomeMasked { .. } => None,
+        }
+    }
+
+    // Events somebody should look at now rather than in the next review, exported as such (see
+    // `to_json` and `SyslogSink`).
+    pub fn is_alert(&self) -> bool {
+        matches!(self, AuditEvent::HoneypotSignIn { .. })
+    }
+
+    // A name and flat fields, for exports.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AuditEvent::UsernameChanged { .. } => "UsernameChanged",
+            AuditEvent::PasswordChanged { .. } => "PasswordChanged",
+            AuditEvent::AccountDeletionRequested { .. } => "AccountDeletionRequested",
+            AuditEvent::AccountRestored { .. } => "AccountRestored",
+            AuditEvent::AccountPurged { .. } => "AccountPurged",
+            AuditEvent::AccountSoftDeleted { .. } => "AccountSoftDeleted",
+            AuditEvent::SoftDeletedAccountRestored { .. } => "SoftDeletedAccountRestored",
+            AuditEvent::SourceTarpitted { .. } => "SourceTarpitted",
+            AuditEvent::SourceBlocked { .. } => "SourceBlocked",
+            AuditEvent::SourceCleared { .. } => "SourceCleared",
+            AuditEvent::SignInAnomaly { .. } => "SignInAnomaly",
+            AuditEvent::SessionEvicted { .. } => "SessionEvicted",
+            AuditEvent::SessionBindingMismatch { .. } => "SessionBindingMismatch",
+            AuditEvent::TenantMismatch { .. } => "TenantMismatch",
+            AuditEvent::HoneypotSignIn { .. } => "HoneypotSignIn",
+            AuditEvent::OutcomeMasked { .. } => "OutcomeMasked",
+        }
+    }
+
+    pub fn details(&self) -> HashMap<String, String> {
+        match self {
+            AuditEvent::UsernameChanged { old_username, new_username, .. } => HashMap::from([
+                ("oldUsername".to_owned(), old_username.clone()),
+                ("newUsername".to_owned(), new_username.clone()),
+            ]),
+            AuditEvent::PasswordChanged { sessions_revoked, .. } => {
+                HashMap::from([("sessionsRevoked".to_owned(), sessions_revoked.to_string())])
+            }
+            AuditEvent::SourceTarpitted { source } | AuditEvent::SourceBlocked { source } | AuditEvent::SourceCleared { source } => {
+                HashMap::from([("source".to_owned(), source.clone())])
+            }
+            AuditEvent::SignInAnomaly { ip, reason, .. } => {
+                HashMap::from([("ip".to_owned(), ip.clone()), ("reason".to_owned(), reason.clone())])
+            }
+            AuditEvent::SessionEvicted { class, .. } => HashMap::from([("class".to_owned(), class.clone())]),
+            AuditEvent::TenantMismatch { tenant, .. } => HashMap::from([("tenant".to_owned(), tenant.clone())]),
+            AuditEvent::AccountSoftDeleted { reason, .. } => HashMap::from([("reason".to_owned(), reason.clone())]),
+            AuditEvent::HoneypotSignIn { username, ip, remote_ip, user_agent } => HashMap::from([
+                ("username".to_owned(), username.clone()),
+                ("ip".to_owned(), ip.clone()),
+                ("remoteIp".to_owned(), remote_ip.clone()),
+                ("userAgent".to_owned(), user_agent.clone()),
+            ]),
+            AuditEvent::OutcomeMasked { rpc, username, outcome } => HashMap::from([
+                ("rpc".to_owned(), rpc.clone()),
+                ("username".to_owned(), username.clone()),
+                ("outcome".to_owned(), outcome.clone()),
+            ]),
+            AuditEvent::AccountDeletionRequested { .. }
+            | AuditEvent::AccountRestored { .. }
+            | AuditEvent::AccountPurged { .. }
+            | AuditEvent::SoftDeletedAccountRestored { .. }
+            | AuditEvent::SessionBindingMismatch { .. } => HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    pub recorded_at: SystemTime,
+    pub event: AuditEvent,
+}
+
+pub trait AuditLog {
+    fn record(&self, event: AuditEvent);
+    fn entries_for(&self, user_uuid: &str) -> Vec<AuditEntry>;
+    // The last `limit` entries of every account, the newest first.
+    fn recent(&self, limit: usize) -> Vec<AuditEntry>;
+    // Forgets the entries recorded before `cutoff`, and the oldest past `max_entries`; returns how
+    // many went.
+    fn purge(&self, cutoff: Option<SystemTime>, max_entries: Option<usize>) -> usize;
+}
+
+// Keeps the entries in memory (so users can export theirs), and prints them for whatever collects
+// the container's output.
+#[derive(Default)]
+pub struct InMemoryAuditLog {
+    entries: Mutex<Vec<AuditEntry>>,
+    export: Option<broadcast::Sender<AuditEntry>>,
+}
+
+impl InMemoryAuditLog {
+    // Every entry is also sent to `export`, for the sinks outside the service (see `AuditExporter`).
+    pub fn with_export(mut self, export: Option<broadcast::Sender<AuditEntry>>) -> Self {
+        self.export = export;
+        self
+    }
+}
+
+impl AuditLog for InMemoryAuditLog {
+    fn record(&self, event: AuditEvent) {
+        info!("audit: {:?}", event);
+
+        let entry = AuditEntry {
+            recorded_at: SystemTime::now(),
+            event,
+        };
+        // Never waits: a sink that is behind misses the entry instead.
+        if let Some(export) = &self.export {
+            let _ = export.send(entry.clone());
+        }
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).push(entry);
+    }
+
+    fn entries_for(&self, user_uuid: &str) -> Vec<AuditEntry> {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|entry| entry.event.user_uuid() == Some(user_uuid))
+            .cloned()
+            .collect()
+    }
+
+    fn recent(&self, limit: usize) -> Vec<AuditEntry> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).iter().rev().take(limit).cloned().collect()
+    }
+
+    // Entries are in the order they were recorded, so the oldest come first.
+    fn purge(&self, cutoff: Option<SystemTime>, max_entries: Option<usize>) -> usize {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = entries.len();
+
+        if let Some(cutoff) = cutoff {
+            entries.retain(|entry| entry.recorded_at >= cutoff);
+        }
+        if let Some(max_entries) = max_entries {
+            let over = entries.len().saturating_sub(max_entries);
+            entries.drain(..over);
+        }
+        before - entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_return_entries_of_user_only() {
+        let audit_log = InMemoryAuditLog::default();
+
+        for user_uuid in ["123456", "654321", "123456"] {
+            audit_log.record(AuditEvent::UsernameChanged {
+                user_uuid: user_uuid.to_owned(),
+                old_username: "a".to_owned(),
+                new_username: "b".to_owned(),
+            });
+        }
+
+        assert_eq!(audit_log.entries_for("123456").len(), 2);
+        assert_eq!(audit_log.entries_for("unknown").len(), 0);
+    }
+
+    #[test]
+    fn should_return_recent_entries_newest_first() {
+        let audit_log = InMemoryAuditLog::default();
+
+        for source in ["192.0.2.1", "192.0.2.2", "192.0.2.3"] {
+            audit_log.record(AuditEvent::SourceBlocked { source: source.to_owned() });
+        }
+
+        let recent: Vec<AuditEvent> = audit_log.recent(2).into_iter().map(|entry| entry.event).collect();
+        assert_eq!(
+            recent,
+            vec![
+                AuditEvent::SourceBlocked { source: "192.0.2.3".to_owned() },
+                AuditEvent::SourceBlocked { source: "192.0.2.2".to_owned() },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_purge_old_entries_and_those_past_the_limit() {
+        let audit_log = InMemoryAuditLog::default();
+
+        audit_log.record(AuditEvent::SourceBlocked { source: "192.0.2.1".to_owned() });
+        let cutoff = SystemTime::now();
+        for source in ["192.0.2.2", "192.0.2.3", "192.0.2.4"] {
+            audit_log.record(AuditEvent::SourceBlocked { source: source.to_owned() });
+        }
+
+        assert_eq!(audit_log.purge(None, None), 0);
+        assert_eq!(audit_log.purge(Some(cutoff), Some(2)), 2);
+        let sources: Vec<AuditEvent> = audit_log.recent(10).into_iter().map(|entry| entry.event).collect();
+        assert_eq!(
+            sources,
+            vec![
+                AuditEvent::SourceBlocked { source: "192.0.2.4".to_owned() },
+                AuditEvent::SourceBlocked { source: "192.0.2.3".to_owned() },
+            ]
+        );
+    }
+}
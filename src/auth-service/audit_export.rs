@@ -0,0 +1,430 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::{SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde_json::{json, Map, Value};
+use tokio::{
+    sync::broadcast::{self, error::RecvError, error::TryRecvError},
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+
+use crate::audit::AuditEntry;
+use crate::auth::epoch_secs;
+use crate::config::Config;
+
+// Entries not yet exported, per sink. A sink that falls further behind misses some.
+const BUFFERED_ENTRIES: usize = 10_000;
+// Entries exported in one go, at most.
+const MAX_BATCH: usize = 500;
+// A batch a sink keeps failing is tried this many times, waiting twice as long each time, then
+// dropped.
+const MAX_ATTEMPTS: u32 = 5;
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// A sink failing to take a batch, or to be set up, naming the sink.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("Error::AuditExportFailed: {0}")]
+pub struct ExportError(pub String);
+
+// An entry as exported: its kind, the account (if any), when, and the event's fields; alerts
+// (see `AuditEvent::is_alert`) with `"priority": "high"`.
+pub fn to_json(entry: &AuditEntry) -> Value {
+    let mut fields = Map::new();
+    fields.insert("type".to_owned(), entry.event.kind().into());
+    if entry.event.is_alert() {
+        fields.insert("priority".to_owned(), "high".into());
+    }
+    if let Some(user_uuid) = entry.event.user_uuid() {
+        fields.insert("userUuid".to_owned(), user_uuid.into());
+    }
+    fields.insert("recordedAt".to_owned(), epoch_secs(entry.recorded_at).into());
+    for (key, value) in entry.event.details() {
+        fields.insert(key, value.into());
+    }
+    Value::Object(fields)
+}
+
+// Somewhere audit entries are kept outside the service, for retention and alerting. Each sink is
+// fed by a task of its own (see `AuditExporter`), so exporting never holds up the RPC that
+// recorded the entry.
+#[tonic::async_trait]
+pub trait AuditSink: Send {
+    fn name(&self) -> String;
+    async fn export(&mut self, entries: &[AuditEntry]) -> Result<(), ExportError>;
+}
+
+// One JSON entry per line. Past `max_bytes`, the file is moved to `<file>.1` (and `<file>.1` to
+// `<file>.2`, ...) and a new one started; only the `keep` most recent moved files are kept.
+pub struct JsonlFileSink {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: Option<File>,
+    written: u64,
+}
+
+impl JsonlFileSink {
+    pub fn new(path: impl AsRef<Path>, max_bytes: u64, keep: usize) -> Self {
+        Self { path: path.as_ref().to_owned(), max_bytes, keep, file: None, written: 0 }
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> Result<(), ExportError> {
+        self.file = None;
+        let _ = fs::remove_file(self.rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+        }
+        let moved = if self.keep > 0 { fs::rename(&self.path, self.rotated(1)) } else { fs::remove_file(&self.path) };
+        moved.map_err(|e| ExportError(format!("{}: {e}", self.path.display())))
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), ExportError> {
+        if self.written > 0 && self.written + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        let path = &self.path;
+        let error = |e: std::io::Error| ExportError(format!("{}: {e}", path.display()));
+        if self.file.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(path).map_err(error)?;
+            self.written = file.metadata().map_err(error)?.len();
+            self.file = Some(file);
+        }
+
+        let file = self.file.as_mut().expect("opened above");
+        file.write_all(line.as_bytes()).map_err(error)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl AuditSink for JsonlFileSink {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    // A few small writes to a local file; not worth a blocking thread.
+    async fn export(&mut self, entries: &[AuditEntry]) -> Result<(), ExportError> {
+        for entry in entries {
+            self.write_line(&format!("{}\n", to_json(entry)))?;
+        }
+        match &self.file {
+            Some(file) => file.sync_data().map_err(|e| ExportError(format!("{}: {e}", self.path.display()))),
+            None => Ok(()),
+        }
+    }
+}
+
+// RFC 5424 messages over UDP, facility authpriv and severity notice (alert for alerts), with the
+// entry as JSON for the message. The timestamp is left to the collector; the entry has its own.
+pub struct SyslogSink {
+    socket: UdpSocket,
+    addr: SocketAddr,
+}
+
+// authpriv (10) * 8 + notice (5), and alert (1)
+const SYSLOG_PRIORITY: u8 = 85;
+const SYSLOG_ALERT_PRIORITY: u8 = 81;
+
+impl SyslogSink {
+    pub fn new(addr: SocketAddr) -> Result<Self, ExportError> {
+        let local: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(local).map_err(|e| ExportError(format!("{addr}: {e}")))?;
+
+        Ok(Self { socket, addr })
+    }
+
+    pub fn message(entry: &AuditEntry) -> String {
+        let priority = if entry.event.is_alert() { SYSLOG_ALERT_PRIORITY } else { SYSLOG_PRIORITY };
+        format!("<{priority}>1 - - auth {} {} - {}", std::process::id(), entry.event.kind(), to_json(entry))
+    }
+}
+
+#[tonic::async_trait]
+impl AuditSink for SyslogSink {
+    fn name(&self) -> String {
+        format!("syslog {}", self.addr)
+    }
+
+    async fn export(&mut self, entries: &[AuditEntry]) -> Result<(), ExportError> {
+        for entry in entries {
+            self.socket
+                .send_to(Self::message(entry).as_bytes(), self.addr)
+                .map_err(|e| ExportError(format!("{}: {e}", self.addr)))?;
+        }
+        Ok(())
+    }
+}
+
+// POSTs batches in the `_bulk` format of Elasticsearch and OpenSearch, e.g. to
+// `http://elasticsearch:9200/auth-audit/_bulk`: an `index` action before each entry.
+pub struct HttpBulkSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpBulkSink {
+    pub fn new(url: String) -> Self {
+        Self { client: reqwest::Client::new(), url }
+    }
+
+    pub fn body(entries: &[AuditEntry]) -> String {
+        entries.iter().map(|entry| format!("{}\n{}\n", json!({ "index": {} }), to_json(entry))).collect()
+    }
+}
+
+#[tonic::async_trait]
+impl AuditSink for HttpBulkSink {
+    fn name(&self) -> String {
+        self.url.clone()
+    }
+
+    async fn export(&mut self, entries: &[AuditEntry]) -> Result<(), ExportError> {
+        let error = |e: reqwest::Error| ExportError(format!("{}: {e}", self.url));
+
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/x-ndjson")
+            .body(Self::body(entries))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(error)?;
+
+        // A bulk request succeeds as a whole even when some of its items were refused.
+        let reply: Value = serde_json::from_slice(&response.bytes().await.map_err(error)?).unwrap_or_default();
+        match reply.get("errors").and_then(Value::as_bool) {
+            Some(true) => Err(ExportError(format!("{}: some entries were refused", self.url))),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Hands every audit entry recorded here to the configured sinks (AUTH_AUDIT_FILE,
+// AUTH_AUDIT_SYSLOG_ADDR, AUTH_AUDIT_HTTP_URL), each from a task of its own, so a slow or broken
+// sink holds up neither the RPCs nor the other sinks. Recording only ever queues the entry; what
+// a sink can't keep up with, or keeps failing to take, is dropped and counted.
+pub struct AuditExporter {
+    sinks: Vec<Box<dyn AuditSink + Sync>>,
+    entries: broadcast::Sender<AuditEntry>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Default for AuditExporter {
+    fn default() -> Self {
+        Self { sinks: Vec::new(), entries: broadcast::channel(BUFFERED_ENTRIES).0, dropped: Arc::default() }
+    }
+}
+
+impl AuditExporter {
+    pub fn from_config(config: &Config) -> Result<Self, ExportError> {
+        let mut exporter = Self::default();
+        if let Some(path) = &config.audit_file {
+            exporter = exporter.with_sink(Box::new(JsonlFileSink::new(path, config.audit_file_max_bytes, config.audit_file_keep)));
+        }
+        if let Some(addr) = config.audit_syslog_addr {
+            exporter = exporter.with_sink(Box::new(SyslogSink::new(addr)?));
+        }
+        if let Some(url) = &config.audit_http_url {
+            exporter = exporter.with_sink(Box::new(HttpBulkSink::new(url.clone())));
+        }
+        Ok(exporter)
+    }
+
+    pub fn with_sink(mut self, sink: Box<dyn AuditSink + Sync>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    // What the audit log sends its entries to (see `InMemoryAuditLog::with_export`); None without
+    // any sink, so nothing gets queued for nobody.
+    pub fn sender(&self) -> Option<broadcast::Sender<AuditEntry>> {
+        (!self.sinks.is_empty()).then(|| self.entries.clone())
+    }
+
+    // Entries some sink never got, so far.
+    pub fn dropped(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+
+    pub fn start(self) -> Vec<JoinHandle<()>> {
+        self.sinks
+            .into_iter()
+            .map(|sink| tokio::spawn(export_to(sink, self.entries.subscribe(), self.dropped.clone())))
+            .collect()
+    }
+}
+
+async fn export_to(
+    mut sink: Box<dyn AuditSink + Sync>,
+    mut entries: broadcast::Receiver<AuditEntry>,
+    dropped: Arc<AtomicU64>,
+) {
+    let name = sink.name();
+    let lagged = |missed: u64| {
+        warn!("{} audit entries were not exported to {}", missed, name);
+        dropped.fetch_add(missed, Ordering::Relaxed);
+    };
+
+    loop {
+        let mut batch = match entries.recv().await {
+            Ok(entry) => vec![entry],
+            Err(RecvError::Lagged(missed)) => {
+                lagged(missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        while batch.len() < MAX_BATCH {
+            match entries.try_recv() {
+                Ok(entry) => batch.push(entry),
+                Err(TryRecvError::Lagged(missed)) => lagged(missed),
+                Err(_) => break,
+            }
+        }
+
+        let mut delay = FIRST_RETRY_DELAY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match sink.export(&batch).await {
+                Ok(()) => {
+                    debug!("exported {} audit entries to {}", batch.len(), name);
+                    break;
+                }
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    warn!("failed to export {} audit entries, retrying: {}", batch.len(), e);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    warn!("gave up exporting {} audit entries: {}", batch.len(), e);
+                    dropped.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::SystemTime};
+
+    use super::*;
+    use crate::audit::{AuditEvent, AuditLog, InMemoryAuditLog};
+
+    fn entry(user_uuid: &str) -> AuditEntry {
+        AuditEntry {
+            recorded_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            event: AuditEvent::UsernameChanged {
+                user_uuid: user_uuid.to_owned(),
+                old_username: "a".to_owned(),
+                new_username: "b".to_owned(),
+            },
+        }
+    }
+
+    // Keeps exported entries, failing the first `failures` exports.
+    struct RecordingSink {
+        exported: Arc<Mutex<Vec<AuditEntry>>>,
+        failures: u32,
+    }
+
+    #[tonic::async_trait]
+    impl AuditSink for RecordingSink {
+        fn name(&self) -> String {
+            "recording".to_owned()
+        }
+
+        async fn export(&mut self, entries: &[AuditEntry]) -> Result<(), ExportError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(ExportError("recording".to_owned()));
+            }
+            self.exported.lock().unwrap().extend_from_slice(entries);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_export_flat_json() {
+        assert_eq!(
+            to_json(&entry("123456")),
+            json!({
+                "type": "UsernameChanged",
+                "userUuid": "123456",
+                "recordedAt": 1_700_000_000,
+                "oldUsername": "a",
+                "newUsername": "b",
+            })
+        );
+        assert!(SyslogSink::message(&entry("123456")).starts_with("<85>1 - - auth "));
+
+        let alert = AuditEntry {
+            event: AuditEvent::HoneypotSignIn {
+                username: "j.smith".to_owned(),
+                ip: "203.0.113.7".to_owned(),
+                remote_ip: "10.0.0.1".to_owned(),
+                user_agent: "python-requests/2.31".to_owned(),
+            },
+            ..entry("123456")
+        };
+        assert_eq!(to_json(&alert)["priority"], "high");
+        assert_eq!(to_json(&alert)["remoteIp"], "10.0.0.1");
+        assert!(SyslogSink::message(&alert).starts_with("<81>1 - - auth "));
+        assert_eq!(HttpBulkSink::body(&[entry("123456")]).lines().next(), Some(r#"{"index":{}}"#));
+    }
+
+    #[tokio::test]
+    async fn file_sink_should_rotate_and_keep_the_most_recent_files() {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", std::process::id()));
+        let line_length = format!("{}\n", to_json(&entry("1"))).len() as u64;
+
+        // Two entries per file, two files kept besides the current one.
+        let mut sink = JsonlFileSink::new(&path, 2 * line_length, 2);
+        for user_uuid in ["1", "2", "3", "4", "5", "6", "7"] {
+            sink.export(&[entry(user_uuid)]).await.unwrap();
+        }
+
+        let read = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(read(&path), 1);
+        assert_eq!(read(&sink.rotated(1)), 2);
+        assert_eq!(read(&sink.rotated(2)), 2);
+        assert!(!sink.rotated(3).exists());
+        assert!(fs::read_to_string(sink.rotated(2)).unwrap().contains(r#""userUuid":"3""#));
+
+        for n in 0..=2 {
+            let _ = fs::remove_file(if n == 0 { path.clone() } else { sink.rotated(n) });
+        }
+    }
+
+    #[tokio::test]
+    async fn should_retry_failed_exports_in_the_background() {
+        let exported = Arc::new(Mutex::new(Vec::new()));
+        let exporter = AuditExporter::default().with_sink(Box::new(RecordingSink { exported: exported.clone(), failures: 1 }));
+        let audit_log = InMemoryAuditLog::default().with_export(exporter.sender());
+        let dropped = exporter.dropped();
+        exporter.start();
+
+        audit_log.record(entry("123456").event);
+
+        tokio::time::sleep(FIRST_RETRY_DELAY * 2).await;
+        assert_eq!(exported.lock().unwrap().len(), 1);
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+    }
+}
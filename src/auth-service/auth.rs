@@ -1,284 +1,4933 @@
-use std::sync::Mutex;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock, PoisonError,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use auth_client::descriptor::fingerprint;
+
+use crate::{
+    audit::{AuditEntry, AuditEvent, AuditLog, InMemoryAuditLog},
+    invites::{InvitesImpl, InvitesOps},
+    jobs::JobStatuses,
+    journal::{purge_discarded, JournalError},
+    admin_policy::{AdminCall, AdminPrincipals, AdminTarget, AllowList, Policy},
+    api_keys::ApiKeys,
+    breached::{BreachedPasswordChecker, BreachedPasswordMode, BreachedPasswords},
+    challenge::{ChallengeMode, ChallengeVerifier, ProofOfWork},
+    claims::{Claims, ClaimsProvider},
+    config::Config,
+    debug_capture::{describe_request, DebugCapture},
+    effective_config::EffectiveConfig,
+    error::{Error, StoreError},
+    federation::ExternalProviders,
+    flags::{FeatureFlags, Flag},
+    geo::{GeoLookup, SignInLocations},
+    i18n::{ErrorMessage, Locale},
+    mailer::{Mailer, LoggingMailer},
+    nonces::{RequestNonce, SeenNonces},
+    events::{Event, EventSink, LoggingEventSink},
+    logging::{FixedLogFilter, LogFilter},
+    outage::{OutageMetrics, StoreOutage},
+    peer::PeerInfo,
+    policy::{PolicyMode, ShadowRefusals},
+    prehash::is_derived_key,
+    response_cache::{with_cache_control, CacheScope, ResponseCache},
+    rpc::{RpcCall, RpcMetrics},
+    store_metrics::StoreMetrics,
+    sessions::{client_fingerprint, Device, Session, SessionClass, SessionsImpl, SessionsOps, UnknownSessionReply},
+    tenants::{request_tenant, TENANT_CLAIM},
+    throttle::{SourceThrottle, Verdict},
+    usage_stats::UsageStats,
+    usernames::{HoneypotUsernames, ReservedUsernames},
+    users::{AccountStatus, UserQuery, UserSummary, UsersImpl, UsersOps},
+    validation::{FieldLimits, Validate},
+    webauthn::{challenge_of, RelyingParty, WebAuthnCeremony},
+};
+
+use prost::Message;
+use tonic::{metadata::MetadataMap, Request, Response, Status};
+use tokio::sync::watch;
+use tracing::{debug, error, info, warn};
+
+use auth_ids::Ids;
+
+use authentication::auth_server::Auth;
+use authentication::{
+    ConsumeMagicLinkRequest, ConsumeMagicLinkResponse, ExchangeExternalTokenRequest,
+    ExchangeExternalTokenResponse, IntrospectTokenRequest, IntrospectTokenResponse,
+    RequestMagicLinkRequest, RequestMagicLinkResponse, SignInRequest, SignInResponse,
+    SignOutRequest, SignOutResponse, SignUpRequest, SignUpResponse, StatusCode, ValidateSessionRequest,
+    ValidateSessionResponse, CreateGuestSessionRequest, CreateGuestSessionResponse,
+    UpgradeGuestSessionRequest, UpgradeGuestSessionResponse, ChangeUsernameRequest, ChangeUsernameResponse,
+    ChangePasswordRequest, ChangePasswordResponse,
+    SetUserMetadataRequest, SetUserMetadataResponse, GetUserMetadataRequest, GetUserMetadataResponse,
+    ExportMyDataRequest, ExportMyDataResponse, UserDataExport, SessionRecord, AuditRecord,
+    DeleteAccountRequest, DeleteAccountResponse, AcceptTermsRequest, AcceptTermsResponse,
+    CreateDelegationTokenRequest, CreateDelegationTokenResponse, ListRevokedSessionsRequest,
+    ListRevokedSessionsResponse, GetServerInfoRequest, GetServerInfoResponse, GetStatsResponse,
+    StartWebAuthnRegistrationRequest, StartWebAuthnRegistrationResponse, FinishWebAuthnRegistrationRequest,
+    FinishWebAuthnRegistrationResponse, StartWebAuthnAssertionRequest, StartWebAuthnAssertionResponse,
+    FinishWebAuthnAssertionRequest, FinishWebAuthnAssertionResponse,
+};
+
+pub mod authentication {
+    pub mod v1 {
+        tonic::include_proto!("authentication.v1");
+
+        // The compiled descriptors, for GetServerInfo and the proto compat test.
+        pub const DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/authentication_descriptor.bin"));
+    }
+
+    // The version in use
+    pub use v1::*;
+}
+
+// Re-exporting
+pub use authentication::auth_server::AuthServer;
+pub use tonic::transport::Server;
+
+// Logged at debug level only (see `AuthService::start_rpc`): revocations are polled every few
+// seconds by every caching service, and the server info asked for before every sign in.
+const QUIET_RPCS: &[&str] = &["ListRevokedSessions", "GetServerInfo"];
+
+// Who presents a session, as far as the session cares (see `AuthService::caller_of`).
+struct Caller {
+    fingerprint: Option<String>,
+    tenant: Option<String>,
+}
+
+pub struct AuthService {
+    pub(crate) users_service: Box<Mutex<dyn UsersOps + Send + Sync>>,
+    pub(crate) sessions_service: Box<Mutex<dyn SessionsOps + Send + Sync>>,
+    pub(crate) invites_service: Box<Mutex<dyn InvitesOps + Send + Sync>>,
+    external_providers: ExternalProviders,
+    api_keys: ApiKeys,
+    pub(crate) admin_api_keys: ApiKeys,
+    // Who is behind each admin API key, and which admin RPCs they may call.
+    admin_principals: AdminPrincipals,
+    admin_policy: Box<dyn Policy + Send + Sync>,
+    // The other replicas', pushing their session changes (see `SessionReplicator`).
+    pub(crate) replication_keys: ApiKeys,
+    mailer: Box<dyn Mailer + Send + Sync>,
+    breached_passwords: Box<dyn BreachedPasswords + Send + Sync>,
+    pub(crate) audit_log: Box<dyn AuditLog + Send + Sync>,
+    pub(crate) event_sink: Box<dyn EventSink + Send + Sync>,
+    pub throttle: SourceThrottle,
+    // For AUTH_REPLAY_PROTECTION.
+    pub seen_nonces: SeenNonces,
+    challenge_verifier: Box<dyn ChallengeVerifier + Send + Sync>,
+    // Places sign-ins, to flag the unusual ones; none without AUTH_GEOIP_DB.
+    geo_lookup: Option<Box<dyn GeoLookup + Send + Sync>>,
+    sign_in_locations: SignInLocations,
+    // Adds the deployment's own claims to session tokens; none by default.
+    claims_provider: Option<Box<dyn ClaimsProvider + Send + Sync>>,
+    pub config: Config,
+    // Flipped at runtime by `SetFeatureFlag` and `SetMaintenanceMode`.
+    pub(crate) flags: FeatureFlags,
+    pub(crate) log_filter: Box<dyn LogFilter + Send + Sync>,
+    // Shared with the `DebugCaptureService` in front of the service.
+    pub(crate) debug_capture: Arc<DebugCapture>,
+    // Requests answered with an internal error, so far.
+    internal_errors: AtomicU64,
+    // Passwords moved to the current hashing policy at sign in, so far.
+    passwords_rehashed: AtomicU64,
+    // Sign-ins that got a session, and those refused, so far.
+    sign_ins: AtomicU64,
+    refused_sign_ins: AtomicU64,
+    // Audit entries and discarded journal files past their retention, removed so far.
+    purged_audit_entries: AtomicU64,
+    purged_discarded_journals: AtomicU64,
+    // The latest stats, for StreamStats; refreshed while somebody watches (see `start_stats_feed`).
+    pub(crate) stats_feed: watch::Sender<GetStatsResponse>,
+    // What the policies in shadow mode would have refused, so far.
+    pub(crate) shadow_refusals: ShadowRefusals,
+    // The background jobs', for GetStats.
+    pub(crate) job_statuses: JobStatuses,
+    // Filled in by the stores wrapped in `InstrumentedUsers` and `InstrumentedSessions`, for GetStats.
+    pub(crate) store_metrics: StoreMetrics,
+    // Filled in by every handler (see `start_rpc`), for GetStats.
+    pub(crate) rpc_metrics: RpcMetrics,
+    // Kept by `check_stores`: while the stores can't persist, accounts don't change.
+    pub(crate) store_outage: StoreOutage,
+    // Checked on sign up and username changes; names are reserved at runtime by `ReserveUsername`.
+    pub(crate) reserved_usernames: ReservedUsernames,
+    // Checked on sign in; set at runtime by `SetHoneypotUsername`.
+    pub(crate) honeypot_usernames: HoneypotUsernames,
+    // What GetEffectiveConfig answers.
+    pub(crate) effective_config: EffectiveConfig,
+    // GetUserMetadata's, per user; invalidated by SetUserMetadata and ReplayProjection.
+    pub(crate) metadata_cache: ResponseCache<String, HashMap<String, String>>,
+    // Sign-ins per day, for the `export-usage-stats` job; none counted unless it runs.
+    pub(crate) usage_stats: UsageStats,
+    // For guests' made up uuids.
+    ids: Ids,
+}
+
+// Puts an `AuthService` together. Whatever isn't given has a default: in-memory users and
+// sessions, no API keys (so no introspection and no admin RPCs), the logging mailer and event
+// sink, a proof of work as the challenge, `Config::default()`, ...
+pub struct AuthServiceBuilder {
+    users: Box<Mutex<dyn UsersOps + Send + Sync>>,
+    sessions: Box<Mutex<dyn SessionsOps + Send + Sync>>,
+    external_providers: ExternalProviders,
+    api_keys: ApiKeys,
+    admin_api_keys: ApiKeys,
+    admin_principals: AdminPrincipals,
+    admin_policy: Option<Box<dyn Policy + Send + Sync>>,
+    replication_keys: ApiKeys,
+    mailer: Box<dyn Mailer + Send + Sync>,
+    breached_passwords: Box<dyn BreachedPasswords + Send + Sync>,
+    audit_log: Box<dyn AuditLog + Send + Sync>,
+    event_sink: Box<dyn EventSink + Send + Sync>,
+    challenge_verifier: Box<dyn ChallengeVerifier + Send + Sync>,
+    geo_lookup: Option<Box<dyn GeoLookup + Send + Sync>>,
+    claims_provider: Option<Box<dyn ClaimsProvider + Send + Sync>>,
+    config: Config,
+    log_filter: Box<dyn LogFilter + Send + Sync>,
+    debug_capture: Arc<DebugCapture>,
+    job_statuses: JobStatuses,
+    store_metrics: StoreMetrics,
+    reserved_usernames: Option<ReservedUsernames>,
+    effective_config: Option<EffectiveConfig>,
+    ids: Ids,
+}
+
+impl Default for AuthServiceBuilder {
+    fn default() -> Self {
+        Self {
+            users: Box::new(Mutex::new(UsersImpl::default())),
+            sessions: Box::new(Mutex::new(SessionsImpl::default())),
+            external_providers: ExternalProviders::default(),
+            api_keys: ApiKeys::default(),
+            admin_api_keys: ApiKeys::default(),
+            admin_principals: AdminPrincipals::default(),
+            admin_policy: None,
+            replication_keys: ApiKeys::default(),
+            mailer: Box::new(LoggingMailer),
+            breached_passwords: Box::new(BreachedPasswordChecker::default()),
+            audit_log: Box::new(InMemoryAuditLog::default()),
+            event_sink: Box::new(LoggingEventSink),
+            challenge_verifier: Box::new(ProofOfWork::new(20)),
+            geo_lookup: None,
+            claims_provider: None,
+            config: Config::default(),
+            log_filter: Box::new(FixedLogFilter),
+            debug_capture: Arc::new(DebugCapture::default()),
+            job_statuses: JobStatuses::default(),
+            store_metrics: StoreMetrics::default(),
+            reserved_usernames: None,
+            effective_config: None,
+            ids: Ids::default(),
+        }
+    }
+}
+
+impl AuthServiceBuilder {
+    pub fn users(mut self, users: impl UsersOps + Send + Sync + 'static) -> Self {
+        self.users = Box::new(Mutex::new(users));
+        self
+    }
+
+    pub fn sessions(mut self, sessions: impl SessionsOps + Send + Sync + 'static) -> Self {
+        self.sessions = Box::new(Mutex::new(sessions));
+        self
+    }
+
+    pub fn external_providers(mut self, external_providers: ExternalProviders) -> Self {
+        self.external_providers = external_providers;
+        self
+    }
+
+    pub fn api_keys(mut self, api_keys: ApiKeys) -> Self {
+        self.api_keys = api_keys;
+        self
+    }
+
+    pub fn admin_api_keys(mut self, admin_api_keys: ApiKeys) -> Self {
+        self.admin_api_keys = admin_api_keys;
+        self
+    }
+
+    pub fn admin_principals(mut self, admin_principals: AdminPrincipals) -> Self {
+        self.admin_principals = admin_principals;
+        self
+    }
+
+    // Without, the allow list of AUTH_ADMIN_ROLE_GRANTS.
+    pub fn admin_policy(mut self, admin_policy: Box<dyn Policy + Send + Sync>) -> Self {
+        self.admin_policy = Some(admin_policy);
+        self
+    }
+
+    pub fn replication_keys(mut self, replication_keys: ApiKeys) -> Self {
+        self.replication_keys = replication_keys;
+        self
+    }
+
+    pub fn mailer(mut self, mailer: Box<dyn Mailer + Send + Sync>) -> Self {
+        self.mailer = mailer;
+        self
+    }
+
+    pub fn breached_passwords(mut self, breached_passwords: Box<dyn BreachedPasswords + Send + Sync>) -> Self {
+        self.breached_passwords = breached_passwords;
+        self
+    }
+
+    pub fn audit_log(mut self, audit_log: Box<dyn AuditLog + Send + Sync>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    pub fn event_sink(mut self, event_sink: Box<dyn EventSink + Send + Sync>) -> Self {
+        self.event_sink = event_sink;
+        self
+    }
+
+    pub fn challenge_verifier(mut self, challenge_verifier: Box<dyn ChallengeVerifier + Send + Sync>) -> Self {
+        self.challenge_verifier = challenge_verifier;
+        self
+    }
+
+    pub fn geo_lookup(mut self, geo_lookup: Box<dyn GeoLookup + Send + Sync>) -> Self {
+        self.geo_lookup = Some(geo_lookup);
+        self
+    }
+
+    pub fn claims_provider(mut self, claims_provider: Box<dyn ClaimsProvider + Send + Sync>) -> Self {
+        self.claims_provider = Some(claims_provider);
+        self
+    }
+
+    pub fn log_filter(mut self, log_filter: Box<dyn LogFilter + Send + Sync>) -> Self {
+        self.log_filter = log_filter;
+        self
+    }
+
+    pub fn debug_capture(mut self, debug_capture: Arc<DebugCapture>) -> Self {
+        self.debug_capture = debug_capture;
+        self
+    }
+
+    // Also used for invite codes, as the invites are kept by the service itself.
+    pub fn id_generator(mut self, ids: Ids) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    pub fn job_statuses(mut self, job_statuses: JobStatuses) -> Self {
+        self.job_statuses = job_statuses;
+        self
+    }
+
+    pub fn store_metrics(mut self, store_metrics: StoreMetrics) -> Self {
+        self.store_metrics = store_metrics;
+        self
+    }
+
+    // Without, the names of `Config::reserved_usernames`, and no denied words.
+    pub fn reserved_usernames(mut self, reserved_usernames: ReservedUsernames) -> Self {
+        self.reserved_usernames = Some(reserved_usernames);
+        self
+    }
+
+    // Without, that of `Config` alone.
+    pub fn effective_config(mut self, effective_config: EffectiveConfig) -> Self {
+        self.effective_config = Some(effective_config);
+        self
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn build(self) -> AuthService {
+        AuthService {
+            users_service: self.users,
+            sessions_service: self.sessions,
+            invites_service: Box::new(Mutex::new(InvitesImpl::default().with_id_generator(self.ids.clone()))),
+            external_providers: self.external_providers,
+            api_keys: self.api_keys,
+            admin_api_keys: self.admin_api_keys,
+            admin_principals: self.admin_principals,
+            admin_policy: self
+                .admin_policy
+                .unwrap_or_else(|| Box::new(AllowList::from_grants(&self.config.admin_role_grants))),
+            replication_keys: self.replication_keys,
+            mailer: self.mailer,
+            breached_passwords: self.breached_passwords,
+            audit_log: self.audit_log,
+            event_sink: self.event_sink,
+            throttle: SourceThrottle::default(),
+            seen_nonces: SeenNonces::default(),
+            challenge_verifier: self.challenge_verifier,
+            geo_lookup: self.geo_lookup,
+            sign_in_locations: SignInLocations::default(),
+            claims_provider: self.claims_provider,
+            flags: FeatureFlags::from_config(&self.config),
+            reserved_usernames: self
+                .reserved_usernames
+                .unwrap_or_else(|| ReservedUsernames::new(&self.config.reserved_usernames)),
+            honeypot_usernames: HoneypotUsernames::new(&self.config.honeypot_usernames),
+            effective_config: self.effective_config.unwrap_or_else(|| EffectiveConfig::from_config(&self.config)),
+            metadata_cache: ResponseCache::new(self.config.response_cache_ttl),
+            usage_stats: UsageStats::from_config(&self.config),
+            config: self.config,
+            log_filter: self.log_filter,
+            debug_capture: self.debug_capture,
+            internal_errors: AtomicU64::new(0),
+            passwords_rehashed: AtomicU64::new(0),
+            sign_ins: AtomicU64::new(0),
+            refused_sign_ins: AtomicU64::new(0),
+            purged_audit_entries: AtomicU64::new(0),
+            purged_discarded_journals: AtomicU64::new(0),
+            stats_feed: watch::channel(GetStatsResponse::default()).0,
+            shadow_refusals: ShadowRefusals::default(),
+            job_statuses: self.job_statuses,
+            store_metrics: self.store_metrics,
+            rpc_metrics: RpcMetrics::default(),
+            store_outage: StoreOutage::default(),
+            ids: self.ids,
+        }
+    }
+}
+
+impl AuthService {
+    pub fn builder() -> AuthServiceBuilder {
+        AuthServiceBuilder::default()
+    }
+
+    // The stores, locked. Even when poisoned: handlers look for that up front (see
+    // `check_stores_are_sound`), and a store poisoned since is better used than panicked on.
+    pub(crate) fn users(&self) -> MutexGuard<'_, dyn UsersOps + Send + Sync + 'static> {
+        self.users_service.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn sessions(&self) -> MutexGuard<'_, dyn SessionsOps + Send + Sync + 'static> {
+        self.sessions_service.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn invites(&self) -> MutexGuard<'_, dyn InvitesOps + Send + Sync + 'static> {
+        self.invites_service.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // A handler that panicked while holding a store poisoned its lock. The request finding it so
+    // gets an internal error, and the lock is cleared for the requests after it: the stores change
+    // an entry at a time, so at worst the panicked request is half done, which beats refusing
+    // everybody until a restart.
+    pub(crate) fn check_stores_are_sound(&self) -> Result<(), Status> {
+        let mut poisoned = Vec::new();
+        if self.users_service.is_poisoned() {
+            self.users_service.clear_poison();
+            poisoned.push("users");
+        }
+        if self.sessions_service.is_poisoned() {
+            self.sessions_service.clear_poison();
+            poisoned.push("sessions");
+        }
+        if self.invites_service.is_poisoned() {
+            self.invites_service.clear_poison();
+            poisoned.push("invites");
+        }
+
+        if poisoned.is_empty() {
+            return Ok(());
+        }
+
+        self.internal_errors.fetch_add(1, Ordering::Relaxed);
+        error!("recovered the poisoned {} store lock(s)", poisoned.join(", "));
+        Err(Status::internal("internal error, retry"))
+    }
+
+    // Checks every store (see `UsersOps::check_store`), saying which one failed. A failure starts
+    // an outage (see `StoreOutage`), and the next check that passes ends it.
+    pub fn check_stores(&self) -> Result<(), Error> {
+        let result = self
+            .users()
+            .check_store()
+            .map_err(|e| Error::Storage(format!("users: {e}")))
+            .and_then(|_| self.sessions().check_store().map_err(|e| Error::Storage(format!("sessions: {e}"))));
+
+        self.store_outage.record(&result);
+        result
+    }
+
+    pub fn internal_errors(&self) -> u64 {
+        self.internal_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn outage_metrics(&self) -> OutageMetrics {
+        self.store_outage.metrics()
+    }
+
+    pub fn passwords_rehashed(&self) -> u64 {
+        self.passwords_rehashed.load(Ordering::Relaxed)
+    }
+
+    // Successful and refused sign-ins.
+    pub fn sign_ins(&self) -> (u64, u64) {
+        (self.sign_ins.load(Ordering::Relaxed), self.refused_sign_ins.load(Ordering::Relaxed))
+    }
+
+    // Before anything else, so oversized fields are neither logged nor looked up.
+    fn check_field_lengths<T: Validate>(&self, request: &Request<T>) -> Result<(), Status> {
+        request.get_ref().validate(&FieldLimits::from_config(&self.config))
+    }
+
+    // What every `Auth` handler starts with: the request held to the field limits, logged, and
+    // turned away while the stores are unsound. The handler's reply goes out through the call's
+    // `finish`, which times and counts it.
+    pub(crate) fn start_rpc<T: Validate + Message, R: ErrorMessage>(&self, request: &Request<T>) -> Result<RpcCall<R>, Status> {
+        let call = RpcCall::new(Locale::from_request(request), self.rpc_metrics.clone());
+
+        self.check_field_lengths(request)?;
+        self.log_request(R::RPC, request);
+        self.check_stores_are_sound()?;
+
+        Ok(call)
+    }
+
+    // The RPC and where it came from, and at debug its message with the secrets redacted as
+    // captures have them. Never the metadata, which carries the API keys.
+    pub(crate) fn log_request<T: Message>(&self, rpc: &str, request: &Request<T>) {
+        let peer = PeerInfo::from_request(request, &self.config.trusted_proxies).client_ip;
+        let peer = peer.map_or_else(|| String::from("unknown"), |ip| ip.to_string());
+        if QUIET_RPCS.contains(&rpc) {
+            debug!("Got a {} request from {}", rpc, peer);
+        } else {
+            info!("Got a {} request from {}", rpc, peer);
+        }
+        if let Some(message) = describe_request(rpc, request.get_ref()) {
+            debug!("{} request: {}", rpc, message);
+        }
+    }
+
+    // The same for `AuthAdmin` handlers, which are authenticated instead; those that touch the
+    // stores check them themselves.
+    pub(crate) fn start_admin_rpc<T: Message + AdminTarget, R: ErrorMessage>(&self, request: &Request<T>) -> Result<RpcCall<R>, Status> {
+        let call = RpcCall::new(Locale::from_request(request), self.rpc_metrics.clone());
+
+        self.log_request(R::RPC, request);
+        self.authorize_admin(request, R::RPC)?;
+
+        Ok(call)
+    }
+
+    // The admin API key has to be known, and the policy has to allow its principal `action` (the
+    // RPC) for the tenant of what the call acts on. x-tenant-id can't say otherwise: a target of
+    // another tenant than the one claimed is refused.
+    pub(crate) fn authorize_admin<T: AdminTarget>(&self, request: &Request<T>, action: &str) -> Result<(), Status> {
+        let metadata = request.metadata();
+        self.admin_api_keys.authenticate(metadata)?;
+
+        let claimed = metadata.get("x-tenant-id").and_then(|value| value.to_str().ok());
+        let target = request.get_ref();
+        let tenants = self.tenants_of(target);
+        if claimed.is_some_and(|claimed| tenants.iter().any(|tenant| tenant != claimed)) {
+            warn!("refused {} for tenant {:?}: its target is of {:?}", action, claimed, tenants);
+            return Err(Error::Policy(String::from("target outside the tenant")).into());
+        }
+
+        let has_target = target.target_user().is_some() || target.target_username().is_some() || !target.target_sessions().is_empty();
+        let tenant = match &tenants[..] {
+            // A target of no known tenant may be of any: only an admin of every tenant acts on it.
+            [] if has_target => {
+                if claimed.is_some() {
+                    warn!("refused {} for tenant {:?}: its target is of no known tenant", action, claimed);
+                    return Err(Error::Policy(String::from("target of no known tenant")).into());
+                }
+                None
+            }
+            [] => claimed,
+            [tenant] => Some(tenant.as_str()),
+            _ => None,
+        };
+        self.check_admin_policy(metadata, action, tenant)
+    }
+
+    // The same, for what isn't a gRPC call, such as the debug endpoint: it has no target, so the
+    // tenant is x-tenant-id's.
+    pub(crate) fn authorize_admin_metadata(&self, metadata: &MetadataMap, action: &str) -> Result<(), Status> {
+        self.admin_api_keys.authenticate(metadata)?;
+
+        let tenant = metadata.get("x-tenant-id").and_then(|value| value.to_str().ok());
+        self.check_admin_policy(metadata, action, tenant)
+    }
+
+    fn check_admin_policy(&self, metadata: &MetadataMap, action: &str, tenant: Option<&str>) -> Result<(), Status> {
+        let principal = self.admin_principals.principal(metadata);
+        if !self.admin_policy.allows(&AdminCall { principal: &principal, action, tenant }) {
+            warn!("refused {} to {} ({}) for tenant {:?}: not allowed by the admin policy", action, principal.name, principal.role, tenant);
+            return Err(Error::Policy(String::from("not allowed by the admin policy")).into());
+        }
+        Ok(())
+    }
+
+    // The tenants `target` belongs to: users are shared between tenants, so a user is of the
+    // tenants they ever had sessions for, as their record has it, and a session of the one it is
+    // pinned to. Sorted, without duplicates.
+    fn tenants_of(&self, target: &impl AdminTarget) -> Vec<String> {
+        let user_uuid = target.target_user().map(str::to_owned).or_else(|| {
+            target.target_username().and_then(|username| self.users().find_user_uuid(username))
+        });
+
+        let user_tenants = user_uuid.as_deref().map(|user_uuid| self.users().get_tenants(user_uuid)).unwrap_or_default();
+        let sessions = self.sessions();
+        let mut tenants: Vec<String> = user_uuid
+            .map(|user_uuid| sessions.get_sessions_of_user(&user_uuid))
+            .unwrap_or_default()
+            .into_iter()
+            .chain(target.target_sessions().iter().filter_map(|session_token| sessions.get_session(session_token)))
+            .filter_map(|session| session.tenant)
+            .chain(user_tenants)
+            .collect();
+        tenants.sort();
+        tenants.dedup();
+        tenants
+    }
+
+    // Past AUTH_USERS_HARD_LIMIT no user is added, and past AUTH_SESSIONS_HARD_LIMIT no session,
+    // so an in-memory deployment runs out of room before it runs out of memory.
+    fn check_user_capacity(&self) -> Result<(), Status> {
+        let limit = self.config.users_hard_limit;
+        if limit > 0 && self.users().user_count() >= limit {
+            warn!("refused a new user: {} users, the hard limit", limit);
+            return Err(Status::resource_exhausted("user limit reached"));
+        }
+        Ok(())
+    }
+
+    fn check_session_capacity(&self) -> Result<(), Status> {
+        let limit = self.config.sessions_hard_limit;
+        if limit > 0 && self.sessions().session_count() >= limit {
+            warn!("refused a new session: {} sessions, the hard limit", limit);
+            return Err(Status::resource_exhausted("session limit reached"));
+        }
+        Ok(())
+    }
+
+    // Creates the session, pinned to `tenant` if any and noting the client's `ip`, auditing any
+    // evicted to make room for it.
+    async fn create_session(
+        &self,
+        user_uuid: &str,
+        class: SessionClass,
+        scopes: Vec<String>,
+        tenant: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> String {
+        let claims = self.claims_for(user_uuid, class, tenant).await;
+        let session_token = self.sessions().create_session_with_claims(user_uuid, class, scopes, &claims);
+        self.pin_to_tenant(user_uuid, &session_token, tenant);
+        self.note_session_ip(&session_token, ip);
+        self.audit_evicted_sessions();
+        session_token
+    }
+
+    // Looked up before the sessions are locked, as the provider may take a while.
+    async fn claims_for(&self, user_uuid: &str, class: SessionClass, tenant: Option<&str>) -> Claims {
+        let mut claims = match &self.claims_provider {
+            Some(claims_provider) => claims_provider.claims(user_uuid, class).await.unwrap_or_else(|e| {
+                warn!("issuing a token without the claims of {}: {}", user_uuid, e);
+                Claims::new()
+            }),
+            None => Claims::new(),
+        };
+
+        // Ours whatever the provider says, so no token names another tenant than its session's.
+        claims.remove(TENANT_CLAIM);
+        if let Some(tenant) = tenant {
+            claims.insert(TENANT_CLAIM.to_owned(), tenant.into());
+        }
+        claims
+    }
+
+    // The user's record keeps the tenant too, for when they have signed out (see `tenants_of`).
+    fn pin_to_tenant(&self, user_uuid: &str, session_token: &str, tenant: Option<&str>) {
+        let Some(tenant) = tenant else { return };
+
+        self.sessions().pin_session(session_token, tenant);
+        match self.users().add_tenant(user_uuid, tenant) {
+            // Guests have no record.
+            Ok(()) | Err(StoreError::UserNotFound) => {}
+            Err(e) => warn!("failed to note tenant {} of {}: {}", tenant, user_uuid, e),
+        }
+    }
+
+    fn note_session_ip(&self, session_token: &str, ip: Option<IpAddr>) {
+        if let Some(ip) = ip {
+            self.sessions().set_session_ip(session_token, ip);
+        }
+    }
+
+    // The tenant a request is for (see `request_tenant`); requests for no known tenant of a
+    // multi-tenant deployment aren't served at all.
+    fn tenant_of<T>(&self, request: &Request<T>) -> Result<Option<String>, Status> {
+        request_tenant(request, &self.config.tenants).map_err(|e| {
+            warn!("request refused: {}", e);
+            Status::invalid_argument("unknown tenant")
+        })
+    }
+
+    // The client and the tenant a session is presented for (see `authenticated_session`).
+    fn caller_of<T>(&self, request: &Request<T>) -> Result<Caller, Status> {
+        Ok(Caller {
+            fingerprint: client_fingerprint(request, self.config.session_binding, &self.config.trusted_proxies),
+            tenant: self.tenant_of(request)?,
+        })
+    }
+
+    // A bound session only goes for the client it was bound to (see `client_fingerprint`);
+    // presented by another, it was most likely lifted from there, so it is audited.
+    fn check_binding(&self, session: &Session, fingerprint: Option<&str>) -> Result<(), StatusCode> {
+        if session.binding.is_none() || session.binding.as_deref() == fingerprint {
+            return Ok(());
+        }
+
+        warn!("session of {} presented by another client", session.user_uuid);
+        self.audit_log.record(AuditEvent::SessionBindingMismatch { user_uuid: session.user_uuid.clone() });
+        Err(StatusCode::Failure)
+    }
+
+    // A session only goes for the tenant it was created for; presented for another, it was most
+    // likely lifted from there, so it is audited.
+    fn check_tenant(&self, session: &Session, tenant: Option<&str>) -> Result<(), StatusCode> {
+        if session.tenant.as_deref() == tenant {
+            return Ok(());
+        }
+
+        warn!("session of {} presented for another tenant", session.user_uuid);
+        self.audit_log.record(AuditEvent::TenantMismatch {
+            user_uuid: session.user_uuid.clone(),
+            tenant: tenant.unwrap_or_default().to_owned(),
+        });
+        Err(StatusCode::TenantMismatch)
+    }
+
+    fn audit_evicted_sessions(&self) {
+        let evicted = self.sessions().take_evicted_sessions();
+        for session in evicted {
+            self.audit_log.record(AuditEvent::SessionEvicted {
+                user_uuid: session.user_uuid,
+                class: format!("{:?}", session.class),
+            });
+        }
+    }
+
+    // How many users and sessions the stores hold.
+    pub fn counts(&self) -> (usize, usize) {
+        (self.users().user_count(), self.sessions().session_count())
+    }
+
+    // Warns about the stores past their soft limits (AUTH_USERS_SOFT_LIMIT,
+    // AUTH_SESSIONS_SOFT_LIMIT), well before the hard ones turn anything away. Returns the warnings.
+    pub fn check_capacity(&self) -> Vec<String> {
+        let (users, sessions) = self.counts();
+        let over = |what: &str, count: usize, limit: usize| {
+            (limit > 0 && count >= limit).then(|| format!("{count} {what}, over the soft limit of {limit}"))
+        };
+
+        let warnings: Vec<String> = [
+            over("users", users, self.config.users_soft_limit),
+            over("sessions", sessions, self.config.sessions_soft_limit),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        for warning in &warnings {
+            warn!("{}", warning);
+        }
+        warnings
+    }
+
+    // In maintenance mode, RPCs that change accounts are turned away until the store is ours
+    // again.
+    fn check_not_in_maintenance(&self) -> Result<(), Status> {
+        if !self.flags.is_enabled(Flag::MaintenanceMode) {
+            return Ok(());
+        }
+
+        let mut status = Status::unavailable("in maintenance, retry later");
+        status
+            .metadata_mut()
+            .insert("retry-after", self.config.maintenance_retry_after.as_secs().into());
+        Err(status)
+    }
+
+    // Only active accounts may get a session; the others are refused with a status code that
+    // tells the client why.
+    fn check_account_is_active(&self, user_uuid: &str) -> Result<(), StatusCode> {
+        let status = self.users().get_account_status(user_uuid);
+
+        match status {
+            Some(AccountStatus::Active) => Ok(()),
+            Some(AccountStatus::Suspended) => Err(StatusCode::AccountSuspended),
+            Some(AccountStatus::Deleted) => Err(StatusCode::AccountDeleted),
+            Some(AccountStatus::PendingVerification) => Err(StatusCode::AccountPendingVerification),
+            Some(AccountStatus::PendingApproval) => Err(StatusCode::AccountPendingApproval),
+            // Refused as if there were no such account.
+            Some(AccountStatus::SoftDeleted) | None => Err(StatusCode::Failure),
+        }
+    }
+
+    // The session, for RPCs users make about themselves, if the caller may use it: bound to their
+    // client and pinned to their tenant, if at all. Guests have no account, and delegation tokens
+    // are only for acting on the user's behalf elsewhere.
+    fn authenticated_session(&self, session_token: &str, caller: &Caller) -> Result<Session, StatusCode> {
+        let session = self
+            .sessions()
+            .get_session(session_token)
+            .filter(|session| !matches!(session.class, SessionClass::Guest | SessionClass::Delegated))
+            .ok_or(StatusCode::Failure)?;
+
+        self.check_binding(&session, caller.fingerprint.as_deref())?;
+        self.check_tenant(&session, caller.tenant.as_deref())?;
+        self.check_account_is_active(&session.user_uuid)?;
+        Ok(session)
+    }
+
+    // What a token is good for, for services holding one they didn't mint: IntrospectToken, and
+    // `POST /introspect` next to the readiness check (see `Readiness`). Callers need an API key.
+    pub(crate) fn introspect(&self, metadata: &MetadataMap, token: &str) -> Result<IntrospectTokenResponse, Status> {
+        self.api_keys.authenticate(metadata)?;
+
+        let session = self.sessions().get_session(token);
+
+        // Tokens of accounts that were suspended since are no longer active.
+        let reply = session
+            .filter(|session| self.check_session_is_active(session).is_ok())
+            .map_or_else(IntrospectTokenResponse::default, |session| IntrospectTokenResponse {
+                active: true,
+                scopes: session.scopes,
+                sub: session.user_uuid,
+                exp: epoch_secs(session.expires_at),
+            });
+        Ok(reply)
+    }
+
+    // The session behind a token just issued, for `POST /oauth/token` to tell its scopes and
+    // expiry (see `Readiness`).
+    pub(crate) fn issued_session(&self, session_token: &str) -> Option<Session> {
+        self.sessions().get_session(session_token)
+    }
+
+    // The user behind the session (see `authenticated_session`).
+    fn authenticate_session(&self, session_token: &str, caller: &Caller) -> Result<String, StatusCode> {
+        self.authenticated_session(session_token, caller).map(|session| session.user_uuid)
+    }
+
+    // With `Flag::RequireCurrentTerms`, users must have accepted the current terms of service.
+    fn check_terms_are_current(&self, user_uuid: &str) -> Result<(), StatusCode> {
+        let Some(current) = self.config.terms_version.as_deref().filter(|_| self.flags.is_enabled(Flag::RequireCurrentTerms)) else {
+            return Ok(());
+        };
+
+        let accepted = self.users().get_accepted_terms_version(user_uuid).unwrap_or_default();
+
+        if is_older_version(&accepted, current) { Err(StatusCode::TermsUpdateRequired) } else { Ok(()) }
+    }
+
+    // For the ways in without a password to call AcceptTerms with: the request says which terms
+    // the user agreed to, as `sign_up`'s does.
+    fn record_accepted_terms(&self, user_uuid: &str, version: &str) {
+        if !version.is_empty() {
+            let _ = self.users().set_accepted_terms_version(user_uuid, version);
+        }
+    }
+
+    // Guests have no account to check.
+    fn check_session_is_active(&self, session: &Session) -> Result<(), StatusCode> {
+        match session.class {
+            SessionClass::Guest => Ok(()),
+            _ => self.check_account_is_active(&session.user_uuid),
+        }
+    }
+
+    // Whether `password` showed up in a breach, per the configured policy. Fails open: an
+    // unreachable breach database must not stop sign-ups.
+    async fn check_password_is_breached(&self, password: &str) -> bool {
+        let breached = match self.config.breached_password_mode {
+            // A derived key is in no breach; checking the password is up to the client.
+            _ if self.config.password_prehash.is_some() => false,
+            BreachedPasswordMode::Off => false,
+            _ => self
+                .breached_passwords
+                .is_breached(password)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("breached password check failed: {}", e);
+                    false
+                }),
+        };
+
+        if breached && self.config.breached_password_mode == BreachedPasswordMode::Shadow {
+            self.shadow_refusals.record("breached_password", "a breached password");
+            return false;
+        }
+        breached
+    }
+
+    // The rules a new password has to follow, per AUTH_PASSWORD_RULES_MODE.
+    fn check_password_rules(&self, password: &str) -> Result<(), StatusCode> {
+        // Only the client sees the password then, so the rules are up to it; what it sends has to
+        // be a derived key though, or it would be stored as one.
+        if self.config.password_prehash.is_some() {
+            return if is_derived_key(password) { Ok(()) } else { Err(StatusCode::PasswordNotPrehashed) };
+        }
+
+        if self.config.password_rules_mode == PolicyMode::Off
+            || password.chars().count() >= self.config.password_min_length
+        {
+            return Ok(());
+        }
+
+        match self.config.password_rules_mode {
+            PolicyMode::Shadow => {
+                self.shadow_refusals.record("password_rules", "a password too short");
+                Ok(())
+            }
+            _ => Err(StatusCode::PasswordTooShort),
+        }
+    }
+
+    // A honeypot is answered as if somebody had it already, as it looks to whoever tries it. Names
+    // with a ':' are those of federated accounts ("provider:subject"), which nobody else may take.
+    fn check_username_allowed(&self, username: &str) -> Result<(), StatusCode> {
+        if self.honeypot_usernames.contains(username) {
+            info!("refused honeypot username");
+            Err(StatusCode::UsernameTaken)
+        } else if username.contains(':') {
+            info!("refused username {:?} of a federated account", username.trim());
+            Err(StatusCode::UsernameNotAllowed)
+        } else if self.reserved_usernames.is_allowed(username) {
+            Ok(())
+        } else {
+            info!("refused reserved username {:?}", username.trim());
+            Err(StatusCode::UsernameNotAllowed)
+        }
+    }
+
+    async fn sign_up_reply(&self, req: &SignUpRequest, device: &Device) -> SignUpResponse {
+        if let Err(status_code) = self.check_challenge(device.ip, &req.challenge_response).await {
+            return SignUpResponse {
+                challenge: Some(self.challenge_verifier.issue()),
+                ..SignUpResponse::failure(status_code)
+            };
+        }
+
+        // Protected against enumeration, a honeypot goes on as a taken username does, to be
+        // answered the same way below.
+        let honeypot = match self.check_username_allowed(&req.username) {
+            Err(StatusCode::UsernameTaken) if self.config.enumeration_protection => true,
+            Err(status_code) => return SignUpResponse::failure(status_code),
+            Ok(()) => false,
+        };
+
+        if let Err(status_code) = self.check_password_rules(&req.password) {
+            return SignUpResponse::failure(status_code);
+        }
+
+        let password_breached = self.check_password_is_breached(&req.password).await;
+
+        if password_breached && self.config.breached_password_mode == BreachedPasswordMode::Enforce {
+            return SignUpResponse {
+                password_breached,
+                ..SignUpResponse::failure(StatusCode::PasswordBreached)
+            };
+        }
+
+        // Read once, so a flag flipped meanwhile can't leave the user pending with a SUCCESS reply.
+        let approval_required = self.flags.is_enabled(Flag::ApprovalRequired);
+        let pending_approval = || SignUpResponse {
+            password_breached,
+            ..SignUpResponse::failure(StatusCode::AccountPendingApproval)
+        };
+
+        // Of two sign ups racing for one username, exactly one gets it. Users awaiting approval are
+        // made pending under the same lock, so they can't sign in in between.
+        let create_user = || {
+            if honeypot {
+                return Err(StatusCode::UsernameTaken);
+            }
+            let mut users_service = self.users();
+            match users_service.create_if_absent(req.username.clone(), req.password.clone()) {
+                Ok(true) if approval_required => users_service
+                    .find_user_uuid(&req.username)
+                    .ok_or(StatusCode::Failure)
+                    .and_then(|user_uuid| {
+                        users_service
+                            .set_account_status(&user_uuid, AccountStatus::PendingApproval)
+                            .map_err(|_| StatusCode::Failure)
+                    }),
+                Ok(true) => Ok(()),
+                Ok(false) => Err(StatusCode::UsernameTaken),
+                Err(_) => Err(StatusCode::Failure),
+            }
+        };
+
+        // In invite-only mode, the invite stays locked until the user is created, so it can't be
+        // redeemed twice.
+        let created = if self.flags.is_enabled(Flag::InviteOnly) {
+            let mut invites_service = self.invites();
+
+            invites_service
+                .check_invite(&req.invite_code)
+                .map_err(|_| StatusCode::InvalidInvite)
+                .and_then(|_| create_user())
+                .and_then(|_| {
+                    invites_service
+                        .redeem_invite(&req.invite_code, &req.username)
+                        .map_err(|_| StatusCode::Failure)
+                })
+        } else {
+            create_user()
+        };
+
+        if created.is_ok() && !req.accepted_terms_version.is_empty() {
+            let mut users_service = self.users();
+            if let Some(user_uuid) = users_service.find_user_uuid(&req.username) {
+                let _ = users_service.set_accepted_terms_version(&user_uuid, &req.accepted_terms_version);
+            }
+        }
+
+        match created {
+            Ok(()) if approval_required => {
+                if let Some(user_uuid) = self.users().find_user_uuid(&req.username) {
+                    self.event_sink.publish(Event::SignUpPendingApproval { user_uuid, username: req.username.clone() });
+                }
+                pending_approval()
+            }
+            Ok(()) => SignUpResponse::success(password_breached),
+            // Answered as a new account would have been.
+            Err(StatusCode::UsernameTaken) if self.config.enumeration_protection => {
+                let outcome = if honeypot { "HoneypotUsername" } else { "UsernameTaken" };
+                self.record_masked_outcome("SignUp", &req.username, outcome);
+                if approval_required {
+                    pending_approval()
+                } else {
+                    SignUpResponse::success(password_breached)
+                }
+            }
+            Err(status_code) => SignUpResponse::failure(status_code),
+        }
+    }
+
+    async fn upgrade_guest_session_reply(
+        &self,
+        req: &UpgradeGuestSessionRequest,
+        device: &Device,
+        tenant: Option<&str>,
+    ) -> UpgradeGuestSessionResponse {
+        let session = self
+            .sessions()
+            .get_session(&req.session_token)
+            .filter(|session| session.class == SessionClass::Guest);
+
+        let Some(session) = session else {
+            return UpgradeGuestSessionResponse::failure(StatusCode::Failure);
+        };
+
+        if let Err(status_code) = self.check_tenant(&session, tenant) {
+            return UpgradeGuestSessionResponse::failure(status_code);
+        }
+
+        // As in `sign_up_reply`, a honeypot goes on as a taken username does.
+        let honeypot = match self.check_username_allowed(&req.username) {
+            Err(StatusCode::UsernameTaken) if self.config.enumeration_protection => true,
+            Err(status_code) => return UpgradeGuestSessionResponse::failure(status_code),
+            Ok(()) => false,
+        };
+
+        if let Err(status_code) = self.check_password_rules(&req.password) {
+            return UpgradeGuestSessionResponse::failure(status_code);
+        }
+
+        let password_breached = self.check_password_is_breached(&req.password).await;
+
+        if password_breached && self.config.breached_password_mode == BreachedPasswordMode::Enforce {
+            return UpgradeGuestSessionResponse {
+                password_breached,
+                ..UpgradeGuestSessionResponse::failure(StatusCode::PasswordBreached)
+            };
+        }
+
+        // Upgraded guests wait for approval like everybody else signing up, without a session.
+        let approval_required = self.flags.is_enabled(Flag::ApprovalRequired);
+        let pending_approval = || UpgradeGuestSessionResponse {
+            password_breached,
+            ..UpgradeGuestSessionResponse::failure(StatusCode::AccountPendingApproval)
+        };
+
+        let created = if honeypot {
+            Err(StoreError::UserAlreadyExists)
+        } else {
+            let mut users_service = self.users();
+            users_service
+                .create_user_with_uuid(session.user_uuid.clone(), req.username.clone(), req.password.clone())
+                .and_then(|_| {
+                    if approval_required {
+                        users_service.set_account_status(&session.user_uuid, AccountStatus::PendingApproval)
+                    } else {
+                        Ok(())
+                    }
+                })
+        };
+
+        match created {
+            Ok(()) => {}
+            // Answered as an upgrade to a free username would have been; the guest stays one.
+            Err(StoreError::UserAlreadyExists) if self.config.enumeration_protection => {
+                let outcome = if honeypot { "HoneypotUsername" } else { "UsernameTaken" };
+                self.record_masked_outcome("UpgradeGuestSession", &req.username, outcome);
+                self.sessions().delete_session(&req.session_token);
+                if approval_required {
+                    return pending_approval();
+                }
+                let session_token =
+                    self.create_session(&session.user_uuid, SessionClass::Guest, session.scopes, tenant, device.ip).await;
+                return UpgradeGuestSessionResponse::success(session.user_uuid, session_token, password_breached);
+            }
+            Err(e) => {
+                warn!("guest upgrade failed: {}", e);
+                let status_code = match e {
+                    StoreError::UserAlreadyExists => StatusCode::UsernameTaken,
+                    _ => StatusCode::Failure,
+                };
+                return UpgradeGuestSessionResponse::failure(status_code);
+            }
+        }
+
+        if approval_required {
+            self.sessions().delete_session(&req.session_token);
+            self.event_sink.publish(Event::SignUpPendingApproval {
+                user_uuid: session.user_uuid,
+                username: req.username.clone(),
+            });
+            return pending_approval();
+        }
+
+        // A fresh token, so whoever learnt the guest token doesn't get into the account.
+        let scopes = self.session_scopes(&session.user_uuid);
+        let claims = self.claims_for(&session.user_uuid, SessionClass::Standard, tenant).await;
+        let mut sessions_service = self.sessions();
+        sessions_service.delete_session(&req.session_token);
+        let session_token =
+            sessions_service.create_session_with_claims(&session.user_uuid, SessionClass::Standard, scopes, &claims);
+        drop(sessions_service);
+        self.pin_to_tenant(&session.user_uuid, &session_token, tenant);
+        self.note_session_ip(&session_token, device.ip);
+        self.audit_evicted_sessions();
+
+        UpgradeGuestSessionResponse::success(session.user_uuid, session_token, password_breached)
+    }
+
+    fn record_masked_outcome(&self, rpc: &str, username: &str, outcome: &str) {
+        self.audit_log.record(AuditEvent::OutcomeMasked {
+            rpc: rpc.to_owned(),
+            username: username.to_owned(),
+            outcome: outcome.to_owned(),
+        });
+    }
+
+    // Protected against enumeration, holds a reply back until `enumeration_response_time` after
+    // `started`, so it comes as late whatever it says. One that took longer still tells; the time
+    // should be well over how long the slowest outcome takes.
+    async fn pad_response(&self, started: tokio::time::Instant) {
+        if self.config.enumeration_protection {
+            tokio::time::sleep_until(started + self.config.enumeration_response_time).await;
+        }
+    }
+
+    // What a new session of the user may do: the defaults, then what the user was granted.
+    fn session_scopes(&self, user_uuid: &str) -> Vec<String> {
+        let mut scopes = self.config.default_scopes.clone();
+        for scope in self.users().get_scopes(user_uuid) {
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        scopes
+    }
+
+    // Refuses a sensitive request sent before (AUTH_REPLAY_PROTECTION): it needs a nonce not seen
+    // yet, and a timestamp within the window of now. Checked once the session is, so only its
+    // holder gets to fill the seen nonces.
+    fn check_not_replayed(&self, nonce: Option<&RequestNonce>) -> Result<(), StatusCode> {
+        let mode = self.config.replay_protection;
+        if mode == PolicyMode::Off {
+            return Ok(());
+        }
+
+        let Err(e) = self.seen_nonces.check(nonce, self.config.replay_window, SystemTime::now()) else { return Ok(()) };
+        if mode == PolicyMode::Shadow {
+            self.shadow_refusals.record("replay_protection", &format!("a request ({e})"));
+            return Ok(());
+        }
+
+        debug!("request refused as replayed: {}", e);
+        Err(StatusCode::RequestReplayed)
+    }
+
+    // Binds a new session to the client signing in, when the deployment binds sessions and the
+    // client can be told apart (see `client_fingerprint`).
+    fn bind_to_client(&self, session_token: &str, fingerprint: Option<&str>) {
+        if let Some(fingerprint) = fingerprint {
+            self.sessions().bind_session(session_token, fingerprint);
+        }
+    }
+
+    // Lets the user know (through the event sink) when they sign in from a new device.
+    fn note_device(&self, user_uuid: &str, device: &Device) {
+        let is_new = self.sessions().remember_device(user_uuid, &device.fingerprint());
+
+        if is_new {
+            self.event_sink.publish(Event::NewDeviceSignIn {
+                user_uuid: user_uuid.to_owned(),
+                ip: device.ip_string(),
+                user_agent: device.user_agent.clone(),
+            });
+        }
+    }
+
+    // Flags sign-ins from a country the user never signed in from, or from too far away from their
+    // last one, in the audit log and the events. Only refused with `Flag::MfaOnAnomaly`: the user
+    // has to finish signing in through a magic link, which remembers where they are.
+    fn check_sign_in_location(&self, user_uuid: &str, device: &Device) -> Result<(), StatusCode> {
+        let Some(location) = self.geo_lookup.as_ref().zip(device.ip).and_then(|(geo, ip)| geo.locate(ip)) else {
+            return Ok(());
+        };
+
+        let now = SystemTime::now();
+        let anomalies = self.sign_in_locations.anomalies(user_uuid, &location, now, self.config.impossible_travel_kmh);
+
+        for anomaly in &anomalies {
+            self.audit_log.record(AuditEvent::SignInAnomaly {
+                user_uuid: user_uuid.to_owned(),
+                ip: device.ip_string(),
+                reason: anomaly.describe(),
+            });
+            self.event_sink.publish(Event::SuspiciousSignIn {
+                user_uuid: user_uuid.to_owned(),
+                ip: device.ip_string(),
+                country: location.country.clone(),
+                reason: anomaly.describe(),
+            });
+        }
+
+        if !anomalies.is_empty() && self.flags.is_enabled(Flag::MfaOnAnomaly) {
+            return Err(StatusCode::MfaRequired);
+        }
+
+        self.sign_in_locations.remember(user_uuid, location, now);
+        Ok(())
+    }
+
+    fn remember_sign_in_location(&self, user_uuid: &str, device: &Device) {
+        if let Some(location) = self.geo_lookup.as_ref().zip(device.ip).and_then(|(geo, ip)| geo.locate(ip)) {
+            self.sign_in_locations.remember(user_uuid, location, SystemTime::now());
+        }
+    }
+
+    // Whether sign_up should be answered with a challenge instead, given its (possibly empty)
+    // answer to an earlier one.
+    async fn check_challenge(&self, ip: Option<IpAddr>, challenge_response: &str) -> Result<(), StatusCode> {
+        let required = match self.config.challenge_mode {
+            ChallengeMode::Off => false,
+            ChallengeMode::Suspicious => ip.is_some_and(|ip| self.throttle.is_suspicious(ip, &self.config)),
+            ChallengeMode::Always => true,
+        };
+
+        if !required {
+            return Ok(());
+        }
+        if challenge_response.is_empty() {
+            return Err(StatusCode::ChallengeRequired);
+        }
+
+        match self.challenge_verifier.verify(challenge_response, ip).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(StatusCode::ChallengeRequired),
+            Err(e) => {
+                warn!("challenge verification failed: {}", e);
+                Err(StatusCode::ChallengeRequired)
+            }
+        }
+    }
+
+    // While the password is at hand, moves it to the current hashing policy (see `PasswordPolicy`).
+    // Failing to is no reason to refuse the sign in: it is tried again the next time.
+    fn upgrade_password_hash(&self, user_uuid: &str, password: &str) {
+        match self.users().upgrade_password_hash(user_uuid, password) {
+            Ok(true) => {
+                self.passwords_rehashed.fetch_add(1, Ordering::Relaxed);
+                debug!("rehashed the password of {}", user_uuid);
+            }
+            Ok(false) => {}
+            Err(e) => warn!("failed to rehash the password of {}: {}", user_uuid, e),
+        }
+    }
+
+    fn note_failed_sign_in(&self, device: &Device, username: &str) {
+        let Some(ip) = device.ip.filter(|_| self.config.throttle_mode != PolicyMode::Off) else { return };
+
+        for event in self.throttle.record_failure(ip, username, &self.config) {
+            // Nobody was tarpitted or blocked, so it's not for the audit log.
+            if self.config.throttle_mode == PolicyMode::Shadow {
+                info!("shadow: {:?}", event);
+            } else {
+                self.audit_log.record(event);
+            }
+        }
+    }
+
+    // The user whose password this is. A directory is asked with the users unlocked, off the
+    // runtime's threads and within its timeout, so one slow server doesn't hold up every RPC.
+    async fn verify_password(&self, username: &str, password: &str) -> Option<String> {
+        let Some(directory) = self.users().directory() else {
+            return self.users().get_user_uuid(username.to_owned(), password.to_owned());
+        };
+        // An empty password would be an unauthenticated bind, which most servers accept.
+        if password.is_empty() {
+            return None;
+        }
+
+        let timeout = directory.timeout();
+        let (name, secret) = (username.to_owned(), password.to_owned());
+        let verified = tokio::task::spawn_blocking(move || directory.verify_credentials(&name, &secret));
+
+        let verified = tokio::time::timeout(timeout, verified)
+            .await
+            .map_err(|_| format!("timed out after {timeout:?}"))
+            .and_then(|joined| joined.map_err(|e| e.to_string()))
+            .and_then(|verified| verified.map_err(|e| e.to_string()));
+
+        match verified {
+            Ok(Some(identity)) => self.users().directory_user(username, &identity),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("ldap verification failed: {}", e);
+                None
+            }
+        }
+    }
+
+    // Holds back a password attempt from a source with too many failed ones: tarpitted, or
+    // refused while blocked. Anything taking a password goes through it, or is a way around it.
+    async fn throttle_sign_in(&self, device: &Device) -> Result<(), StatusCode> {
+        let Some(ip) = device.ip else { return Ok(()) };
+
+        match self.config.throttle_mode {
+            PolicyMode::Off => {}
+            // Not `check`, which would count the source as throttled.
+            PolicyMode::Shadow => {
+                if self.throttle.is_suspicious(ip, &self.config) {
+                    self.shadow_refusals.record("throttle", &format!("a sign in from {ip}"));
+                }
+            }
+            PolicyMode::Enforce => match self.throttle.check(ip, &self.config) {
+                Verdict::Allow => {}
+                Verdict::Tarpit(delay) => tokio::time::sleep(delay).await,
+                Verdict::Block => return Err(StatusCode::SourceBlocked),
+            },
+        }
+
+        Ok(())
+    }
+
+    // Records a sign-in as a honeypot, with everything known about where it came from, in the audit
+    // log (as an alert) and the events, so whoever watches either hears of a credential stuffing
+    // campaign when it starts.
+    fn note_honeypot_sign_in<T>(&self, request: &Request<T>, username: &str, device: &Device, tenant: Option<&str>) {
+        let peer = PeerInfo::from_request(request, &self.config.trusted_proxies);
+        let remote_ip = peer.remote_ip.map(|ip| ip.to_string()).unwrap_or_default();
+        let forwarded_for: Vec<&str> =
+            request.metadata().get_all("x-forwarded-for").iter().filter_map(|value| value.to_str().ok()).collect();
+
+        warn!("sign in as a honeypot from {} (connection {})", device.ip_string(), remote_ip);
+        self.audit_log.record(AuditEvent::HoneypotSignIn {
+            username: username.trim().to_owned(),
+            ip: device.ip_string(),
+            remote_ip: remote_ip.clone(),
+            user_agent: device.user_agent.clone(),
+        });
+        self.event_sink.publish(Event::HoneypotSignIn {
+            username: username.trim().to_owned(),
+            ip: device.ip_string(),
+            remote_ip,
+            forwarded_for: forwarded_for.join(", "),
+            user_agent: device.user_agent.clone(),
+            tenant: tenant.unwrap_or_default().to_owned(),
+        });
+    }
+
+    // Signing in during the grace period undoes a deletion request.
+    fn restore_deleted_account(&self, user_uuid: &str) {
+        let restored = {
+            let mut users_service = self.users();
+
+            let within_grace = users_service
+                .deletion_requested_at(user_uuid)
+                // A grace period past what the clock holds never runs out.
+                .is_some_and(|requested_at| {
+                    requested_at
+                        .checked_add(self.config.account_deletion_grace)
+                        .is_none_or(|grace_ends_at| grace_ends_at > SystemTime::now())
+                });
+
+            within_grace && users_service.set_account_status(user_uuid, AccountStatus::Active).is_ok()
+        };
+
+        if restored {
+            self.audit_log.record(AuditEvent::AccountRestored { user_uuid: user_uuid.to_owned() });
+        }
+    }
+
+    // Removes the accounts whose deletion grace period is over, along with their sessions. Run
+    // periodically by the `purge-deleted-accounts` job; returns how many accounts were purged.
+    pub fn purge_deleted_accounts(&self) -> usize {
+        let Some(cutoff) = SystemTime::now().checked_sub(self.config.account_deletion_grace) else { return 0 };
+
+        let purged: Vec<String> = {
+            let mut users_service = self.users();
+            let user_uuids = users_service.users_deleted_before(cutoff);
+            for user_uuid in &user_uuids {
+                users_service.delete_user(user_uuid.clone());
+            }
+            user_uuids
+        };
+
+        for user_uuid in &purged {
+            self.sessions().delete_sessions_of_user(user_uuid);
+
+            self.audit_log.record(AuditEvent::AccountPurged { user_uuid: user_uuid.clone() });
+        }
+
+        purged.len()
+    }
+
+    // Removes the accounts taken down by an operator `soft_delete_retention` ago, along with their
+    // sessions. Run periodically by the `purge-soft-deleted-accounts` job; returns how many
+    // accounts were purged.
+    pub fn purge_soft_deleted_accounts(&self) -> usize {
+        let Some(cutoff) = SystemTime::now().checked_sub(self.config.soft_delete_retention) else { return 0 };
+
+        let purged: Vec<String> = {
+            let mut users_service = self.users();
+            let user_uuids = users_service.users_soft_deleted_before(cutoff);
+            for user_uuid in &user_uuids {
+                users_service.delete_user(user_uuid.clone());
+            }
+            user_uuids
+        };
+
+        for user_uuid in &purged {
+            self.sessions().delete_sessions_of_user(user_uuid);
+
+            self.audit_log.record(AuditEvent::AccountPurged { user_uuid: user_uuid.clone() });
+        }
+
+        purged.len()
+    }
+
+    // Removes the accounts still pending verification `unverified_account_ttl` after they were
+    // created, with their sessions, so abandoned sign ups don't keep their usernames. Run
+    // periodically by the `purge-unverified-accounts` job; returns how many accounts were purged.
+    pub fn purge_unverified_accounts(&self) -> usize {
+        let Some(cutoff) = self.config.unverified_account_ttl.and_then(|ttl| SystemTime::now().checked_sub(ttl)) else {
+            return 0;
+        };
+
+        let purged: Vec<UserSummary> = {
+            let mut users_service = self.users();
+
+            // Oldest first, so the walk stops at the first account young enough to keep.
+            let mut expired = Vec::new();
+            let mut cursor = None;
+            loop {
+                let query = UserQuery {
+                    status: Some(AccountStatus::PendingVerification),
+                    cursor,
+                    limit: 1000,
+                    ..UserQuery::default()
+                };
+                let page = match users_service.list_users(&query) {
+                    Ok(page) => page,
+                    Err(e) => {
+                        warn!("failed to list unverified accounts: {}", e);
+                        break;
+                    }
+                };
+
+                let reached_cutoff = page.users.iter().any(|user| user.created_at >= cutoff);
+                expired.extend(page.users.into_iter().filter(|user| user.created_at < cutoff));
+                match page.next_cursor {
+                    Some(next_cursor) if !reached_cutoff => cursor = Some(next_cursor),
+                    _ => break,
+                }
+            }
+
+            for user in &expired {
+                users_service.delete_user(user.user_uuid.clone());
+            }
+            expired
+        };
+
+        for user in &purged {
+            self.sessions().delete_sessions_of_user(&user.user_uuid);
+
+            self.audit_log.record(AuditEvent::AccountPurged { user_uuid: user.user_uuid.clone() });
+            self.event_sink.publish(Event::UnverifiedAccountPurged {
+                user_uuid: user.user_uuid.clone(),
+                username: user.username.clone(),
+            });
+        }
+
+        purged.len()
+    }
+
+    // Forgets the audit entries past AUTH_AUDIT_RETENTION_SECS or AUTH_AUDIT_MAX_ENTRIES, and removes
+    // the entries dropped from the journals past AUTH_EVENT_LOG_RETENTION_SECS or
+    // AUTH_EVENT_LOG_MAX_BYTES. Run periodically by the `compact-logs` job; returns how many audit
+    // entries and discarded journal files went.
+    pub fn compact_logs(&self) -> Result<(usize, usize), JournalError> {
+        let now = SystemTime::now();
+        let cutoff = |retention: Option<Duration>| retention.and_then(|retention| now.checked_sub(retention));
+
+        let audit_entries = self.audit_log.purge(cutoff(self.config.audit_retention), self.config.audit_max_entries);
+        self.purged_audit_entries.fetch_add(audit_entries as u64, Ordering::Relaxed);
+
+        let discarded = match &self.config.event_log_dir {
+            Some(dir) => purge_discarded(Path::new(dir), cutoff(self.config.event_log_retention), self.config.event_log_max_bytes)?,
+            None => 0,
+        };
+        self.purged_discarded_journals.fetch_add(discarded as u64, Ordering::Relaxed);
+
+        Ok((audit_entries, discarded))
+    }
+
+    // Audit entries and discarded journal files removed by `compact_logs`, so far.
+    pub fn purged_logs(&self) -> (u64, u64) {
+        (self.purged_audit_entries.load(Ordering::Relaxed), self.purged_discarded_journals.load(Ordering::Relaxed))
+    }
+
+    // Frees the memory held by expired sessions. Run periodically by the `sweep-sessions` job;
+    // returns how many sessions were swept.
+    pub fn sweep_expired_sessions(&self) -> usize {
+        self.sessions().delete_expired_sessions()
+    }
+
+    // What `ExportMyData` and its admin equivalent hand out; None for unknown users.
+    pub(crate) fn export_user_data(&self, user_uuid: &str) -> Option<UserDataExport> {
+        let (username, status, metadata, accepted_terms_version) = {
+            let users_service = self.users();
+            (
+                users_service.get_username(user_uuid)?,
+                users_service.get_account_status(user_uuid)?,
+                users_service.get_metadata(user_uuid).unwrap_or_default(),
+                users_service.get_accepted_terms_version(user_uuid).unwrap_or_default(),
+            )
+        };
+
+        let sessions = self.sessions().get_sessions_of_user(user_uuid);
+
+        Some(UserDataExport {
+            user_uuid: user_uuid.to_owned(),
+            username,
+            account_status: format!("{:?}", status),
+            metadata,
+            sessions: sessions
+                .into_iter()
+                .map(|session| SessionRecord {
+                    class: format!("{:?}", session.class),
+                    created_at: epoch_secs(session.created_at),
+                    expires_at: epoch_secs(session.expires_at),
+                })
+                .collect(),
+            audit_entries: self
+                .audit_log
+                .entries_for(user_uuid)
+                .into_iter()
+                .map(AuditRecord::from)
+                .collect(),
+            accepted_terms_version,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Auth for AuthService {
+    async fn sign_in(
+        &self,
+        request: Request<SignInRequest>,
+    ) -> Result<Response<SignInResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_session_capacity()?;
+
+        let device = Device::from_request(&request, &self.config.trusted_proxies);
+        let fingerprint = client_fingerprint(&request, self.config.session_binding, &self.config.trusted_proxies);
+        let tenant = self.tenant_of(&request)?;
+
+        // Nobody has a honeypot's username, so whoever signs in as one is going through a list.
+        let is_honeypot = self.honeypot_usernames.contains(&request.get_ref().username);
+        if is_honeypot {
+            self.note_honeypot_sign_in(&request, &request.get_ref().username, &device, tenant.as_deref());
+        }
+        let req = request.into_inner();
+
+        if let Err(status_code) = self.throttle_sign_in(&device).await {
+            return call.finish(SignInResponse::failure(status_code));
+        }
+
+        let session_class = if req.remember_me { SessionClass::LongLived } else { SessionClass::Standard };
+
+        // Get user's uuid from `users_service`. Looked up for honeypots too, so they are refused
+        // as slowly as any wrong password.
+        let user_uuid = self.verify_password(&req.username, &req.password).await.filter(|_| !is_honeypot);
+
+        if let Some(user_uuid) = &user_uuid {
+            self.upgrade_password_hash(user_uuid, &req.password);
+        }
+
+        let user_uuid = user_uuid
+            .ok_or(StatusCode::Failure)
+            .inspect(|maybe_uuid| self.restore_deleted_account(maybe_uuid))
+            .and_then(|maybe_uuid| self.check_account_is_active(&maybe_uuid).map(|_| maybe_uuid))
+            .and_then(|maybe_uuid| self.check_terms_are_current(&maybe_uuid).map(|_| maybe_uuid))
+            .and_then(|maybe_uuid| self.check_sign_in_location(&maybe_uuid, &device).map(|_| maybe_uuid));
+
+        let reply: SignInResponse = match user_uuid {
+            Ok(maybe_uuid) => {
+                let scopes = self.session_scopes(&maybe_uuid);
+                let session_id = self.create_session(&maybe_uuid, session_class, scopes, tenant.as_deref(), device.ip).await;
+                self.bind_to_client(&session_id, fingerprint.as_deref());
+
+                self.note_device(&maybe_uuid, &device);
+
+                SignInResponse::success(maybe_uuid, session_id)
+            }
+            Err(status_code) => SignInResponse::failure(status_code),
+        };
+
+        // Wrong credentials; the other failures are about the account, not the caller.
+        if reply.status_code() == StatusCode::Failure {
+            self.note_failed_sign_in(&device, &req.username);
+        }
+        match reply.status_code() {
+            StatusCode::Success => self.sign_ins.fetch_add(1, Ordering::Relaxed),
+            _ => self.refused_sign_ins.fetch_add(1, Ordering::Relaxed),
+        };
+        let signed_in = (reply.status_code() == StatusCode::Success).then_some(reply.user_uuid.as_str());
+        self.usage_stats.record_sign_in(signed_in, SystemTime::now());
+
+        call.finish(reply)
+    }
+
+    async fn sign_up(
+        &self,
+        request: Request<SignUpRequest>,
+    ) -> Result<Response<SignUpResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_not_in_maintenance()?;
+
+        self.store_outage.check_change()?;
+
+        self.check_user_capacity()?;
+
+        let started = tokio::time::Instant::now();
+        let device = Device::from_request(&request, &self.config.trusted_proxies);
+        let req = request.into_inner();
+
+        let result = self.sign_up_reply(&req, &device).await;
+
+        self.pad_response(started).await;
+
+        call.finish(result)
+    }
+
+    async fn sign_out(
+        &self,
+        request: Request<SignOutRequest>,
+    ) -> Result<Response<SignOutResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        let req = request.into_inner();
+
+        let deleted = self.sessions().delete_session(&req.session_token);
+
+        let reply: SignOutResponse = match self.config.unknown_session_reply {
+            UnknownSessionReply::NotFound if !deleted => SignOutResponse::failure(StatusCode::SessionNotFound),
+            _ => SignOutResponse::success(),
+        };
+
+        call.finish(reply)
+    }
+
+    async fn exchange_external_token(
+        &self,
+        request: Request<ExchangeExternalTokenRequest>,
+    ) -> Result<Response<ExchangeExternalTokenResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_not_in_maintenance()?;
+
+        self.store_outage.check_change()?;
+
+        self.check_session_capacity()?;
+
+        let device = Device::from_request(&request, &self.config.trusted_proxies);
+        let fingerprint = client_fingerprint(&request, self.config.session_binding, &self.config.trusted_proxies);
+        let tenant = self.tenant_of(&request)?;
+        let req = request.into_inner();
+
+        // Verify the ID token with the upstream provider, then find (or provision) the local user
+        // it belongs to and give them a session of our own.
+        let user_uuid = self
+            .external_providers
+            .verify(&req.provider, &req.id_token, self.config.clock_skew_leeway)
+            .map_err(|e| (StatusCode::Failure, e))
+            .and_then(|subject| {
+                self.users().link_external_user(&req.provider, &subject).map_err(|e| (StatusCode::Failure, e.to_string()))
+            })
+            .and_then(|user_uuid| {
+                self.check_account_is_active(&user_uuid)
+                    .map(|_| user_uuid)
+                    .map_err(|status_code| (status_code, String::from("Error::AccountNotActive")))
+            })
+            .inspect(|user_uuid| self.record_accepted_terms(user_uuid, &req.accepted_terms_version))
+            .and_then(|user_uuid| {
+                self.check_terms_are_current(&user_uuid)
+                    .map(|_| user_uuid)
+                    .map_err(|status_code| (status_code, String::from("Error::TermsUpdateRequired")))
+            });
+
+        let reply: ExchangeExternalTokenResponse = match user_uuid {
+            Ok(user_uuid) => {
+                let scopes = self.session_scopes(&user_uuid);
+                let session_token =
+                    self.create_session(&user_uuid, SessionClass::Standard, scopes, tenant.as_deref(), device.ip).await;
+                self.bind_to_client(&session_token, fingerprint.as_deref());
+
+                ExchangeExternalTokenResponse::success(user_uuid, session_token)
+            }
+            Err((status_code, e)) => {
+                warn!("external token rejected: {}", e);
+                ExchangeExternalTokenResponse::failure(status_code)
+            }
+        };
+
+        call.finish(reply)
+    }
+
+    async fn introspect_token(
+        &self,
+        request: Request<IntrospectTokenRequest>,
+    ) -> Result<Response<IntrospectTokenResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        let reply: IntrospectTokenResponse = self.introspect(request.metadata(), &request.get_ref().token)?;
+
+        call.finish(reply)
+    }
+
+    async fn request_magic_link(
+        &self,
+        request: Request<RequestMagicLinkRequest>,
+    ) -> Result<Response<RequestMagicLinkResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        let started = tokio::time::Instant::now();
+        let device = Device::from_request(&request, &self.config.trusted_proxies);
+        let req = request.into_inner();
+
+        // Refused from a blocked source, like a sign in, and within the cooldown of the last link
+        // to the same username, known or not, so the reply still gives nothing away.
+        let allowed = self.throttle_sign_in(&device).await.and_then(|_| {
+            match self.throttle.allow_magic_link(&req.username.trim().to_lowercase(), &self.config) {
+                true => Ok(()),
+                false => Err(StatusCode::TooManyRequests),
+            }
+        });
+        if let Err(status_code) = allowed {
+            self.pad_response(started).await;
+            return call.finish(RequestMagicLinkResponse::failure(status_code));
+        }
+
+        let user_uuid = self.users().find_user_uuid(&req.username);
+
+        // Answered the same either way; protected against enumeration, it is also audited.
+        if user_uuid.is_none() && self.config.enumeration_protection {
+            self.record_masked_outcome("RequestMagicLink", &req.username, "UnknownUsername");
+        }
+
+        if let Some(user_uuid) = user_uuid {
+            let magic_link_token = self.sessions().create_magic_link(&user_uuid, self.config.magic_link_ttl);
+
+            let body = format!(
+                "Follow this link to sign in: {}{}\nIt can be used once, within {} minutes.",
+                self.config.magic_link_url,
+                magic_link_token,
+                self.config.magic_link_ttl.as_secs() / 60
+            );
+
+            if let Err(e) = self.mailer.send(&req.username, "Your sign-in link", &body) {
+                warn!("failed to send magic link: {}", e);
+            }
+        }
+
+        let reply: RequestMagicLinkResponse = RequestMagicLinkResponse::success();
+
+        self.pad_response(started).await;
+
+        call.finish(reply)
+    }
+
+    async fn consume_magic_link(
+        &self,
+        request: Request<ConsumeMagicLinkRequest>,
+    ) -> Result<Response<ConsumeMagicLinkResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_session_capacity()?;
+
+        let device = Device::from_request(&request, &self.config.trusted_proxies);
+        let fingerprint = client_fingerprint(&request, self.config.session_binding, &self.config.trusted_proxies);
+        let tenant = self.tenant_of(&request)?;
+        let req = request.into_inner();
+
+        let user_uuid = self.sessions().consume_magic_link(&req.magic_link_token);
+
+        let user_uuid = user_uuid
+            .ok_or(StatusCode::Failure)
+            .and_then(|user_uuid| self.check_account_is_active(&user_uuid).map(|_| user_uuid))
+            .inspect(|user_uuid| self.record_accepted_terms(user_uuid, &req.accepted_terms_version))
+            .and_then(|user_uuid| self.check_terms_are_current(&user_uuid).map(|_| user_uuid));
+
+        let reply: ConsumeMagicLinkResponse = match user_uuid {
+            Ok(user_uuid) => {
+                // Whoever got the link is the user, wherever they are.
+                self.remember_sign_in_location(&user_uuid, &device);
+
+                let scopes = self.session_scopes(&user_uuid);
+                let session_token =
+                    self.create_session(&user_uuid, SessionClass::Standard, scopes, tenant.as_deref(), device.ip).await;
+                self.bind_to_client(&session_token, fingerprint.as_deref());
+
+                ConsumeMagicLinkResponse::success(user_uuid, session_token)
+            }
+            Err(status_code) => ConsumeMagicLinkResponse::failure(status_code),
+        };
+
+        call.finish(reply)
+    }
+
+    async fn validate_session(
+        &self,
+        request: Request<ValidateSessionRequest>,
+    ) -> Result<Response<ValidateSessionResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.store_outage.check_staleness(self.config.max_staleness)?;
+
+        let fingerprint = client_fingerprint(&request, self.config.session_binding, &self.config.trusted_proxies);
+        let tenant = self.tenant_of(&request)?;
+        let req = request.into_inner();
+
+        // Checked before the touch: a replayed token doesn't keep the session going.
+        let session = self.sessions().get_session(&req.session_token);
+        if let Some(session) = &session {
+            let checked = self
+                .check_binding(session, fingerprint.as_deref())
+                .and_then(|_| self.check_tenant(session, tenant.as_deref()));
+            if let Err(status_code) = checked {
+                return call.finish(ValidateSessionResponse::failure(status_code));
+            }
+        }
+
+        let session = self.sessions().touch_session(&req.session_token);
+
+        let unknown = match self.config.unknown_session_reply {
+            UnknownSessionReply::Success => StatusCode::Failure,
+            UnknownSessionReply::NotFound => StatusCode::SessionNotFound,
+        };
+
+        let reply: ValidateSessionResponse = session
+            .ok_or(unknown)
+            .and_then(|session| self.check_session_is_active(&session).map(|_| session))
+            .map_or_else(ValidateSessionResponse::failure, |session| {
+                ValidateSessionResponse::success(session.user_uuid, epoch_secs(session.expires_at), session.scopes, session.tenant)
+            });
+
+        call.finish(reply)
+    }
+
+    async fn create_guest_session(
+        &self,
+        request: Request<CreateGuestSessionRequest>,
+    ) -> Result<Response<CreateGuestSessionResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_session_capacity()?;
+
+        let device = Device::from_request(&request, &self.config.trusted_proxies);
+        let fingerprint = client_fingerprint(&request, self.config.session_binding, &self.config.trusted_proxies);
+        let tenant = self.tenant_of(&request)?;
+
+        let guest_uuid = self.ids.new_id().to_string();
+
+        let session_token = self
+            .create_session(&guest_uuid, SessionClass::Guest, vec!["guest".to_owned()], tenant.as_deref(), device.ip)
+            .await;
+        self.bind_to_client(&session_token, fingerprint.as_deref());
+
+        let reply: CreateGuestSessionResponse = CreateGuestSessionResponse::success(guest_uuid, session_token);
+
+        call.finish(reply)
+    }
+
+    async fn upgrade_guest_session(
+        &self,
+        request: Request<UpgradeGuestSessionRequest>,
+    ) -> Result<Response<UpgradeGuestSessionResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_not_in_maintenance()?;
+
+        self.store_outage.check_change()?;
+
+        self.check_user_capacity()?;
+
+        let started = tokio::time::Instant::now();
+        let device = Device::from_request(&request, &self.config.trusted_proxies);
+        let tenant = self.tenant_of(&request)?;
+        let req = request.into_inner();
+
+        let reply = self.upgrade_guest_session_reply(&req, &device, tenant.as_deref()).await;
+
+        self.pad_response(started).await;
+
+        call.finish(reply)
+    }
+
+    async fn change_username(
+        &self,
+        request: Request<ChangeUsernameRequest>,
+    ) -> Result<Response<ChangeUsernameResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_not_in_maintenance()?;
+
+        self.store_outage.check_change()?;
+
+        let started = tokio::time::Instant::now();
+        let caller = self.caller_of(&request)?;
+        let nonce = RequestNonce::from_request(&request);
+        let req = request.into_inner();
+
+        let result = self
+            .authenticate_session(&req.session_token, &caller)
+            .and_then(|user_uuid| self.check_not_replayed(nonce.as_ref()).map(|_| user_uuid))
+            .and_then(|user_uuid| self.check_username_allowed(&req.new_username).map(|_| user_uuid))
+            .and_then(|user_uuid| {
+                let changed = self.users().change_username(&user_uuid, &req.new_username);
+
+                changed
+                    .map(|old_username| (user_uuid, old_username))
+                    .map_err(|e| match e {
+                        StoreError::UserAlreadyExists => StatusCode::UsernameTaken,
+                        _ => StatusCode::Failure,
+                    })
+            })
+            .map(|(user_uuid, old_username)| {
+                self.audit_log.record(AuditEvent::UsernameChanged {
+                    user_uuid,
+                    old_username,
+                    new_username: req.new_username.trim().to_owned(),
+                });
+            })
+            // Answered as a change to a free username would have been, honeypots included.
+            .or_else(|status_code| match status_code {
+                StatusCode::UsernameTaken if self.config.enumeration_protection => {
+                    let outcome =
+                        if self.honeypot_usernames.contains(&req.new_username) { "HoneypotUsername" } else { "UsernameTaken" };
+                    self.record_masked_outcome("ChangeUsername", &req.new_username, outcome);
+                    Ok(())
+                }
+                status_code => Err(status_code),
+            });
+
+        let reply: ChangeUsernameResponse = ChangeUsernameResponse::from_result(result);
+
+        self.pad_response(started).await;
+
+        call.finish(reply)
+    }
+
+    async fn change_password(
+        &self,
+        request: Request<ChangePasswordRequest>,
+    ) -> Result<Response<ChangePasswordResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_not_in_maintenance()?;
+
+        self.store_outage.check_change()?;
+
+        let device = Device::from_request(&request, &self.config.trusted_proxies);
+        let caller = self.caller_of(&request)?;
+        let nonce = RequestNonce::from_request(&request);
+        let req = request.into_inner();
+
+        let user_uuid = self
+            .authenticate_session(&req.session_token, &caller)
+            .and_then(|user_uuid| self.check_not_replayed(nonce.as_ref()).map(|_| user_uuid))
+            .and_then(|user_uuid| self.check_password_rules(&req.new_password).map(|_| user_uuid));
+
+        let user_uuid = match user_uuid {
+            Ok(user_uuid) => user_uuid,
+            Err(status_code) => return call.finish(ChangePasswordResponse::failure(status_code)),
+        };
+
+        // The current password is checked as by `sign_in`, or a stolen session would be a way to
+        // guess it around the throttle.
+        if let Err(status_code) = self.throttle_sign_in(&device).await {
+            return call.finish(ChangePasswordResponse::failure(status_code));
+        }
+
+        let password_breached = self.check_password_is_breached(&req.new_password).await;
+
+        if password_breached && self.config.breached_password_mode == BreachedPasswordMode::Enforce {
+            return call.finish(ChangePasswordResponse {
+                password_breached,
+                ..ChangePasswordResponse::failure(StatusCode::PasswordBreached)
+            });
+        }
+
+        let changed = self.users().update_password_if_matches(&user_uuid, &req.current_password, &req.new_password);
+
+        match changed {
+            Ok(true) => {}
+            Ok(false) => {
+                let username = self.users().get_username(&user_uuid).unwrap_or_default();
+                self.note_failed_sign_in(&device, &username);
+                return call.finish(ChangePasswordResponse::failure(StatusCode::Failure));
+            }
+            Err(e) => {
+                warn!("password change failed: {}", e);
+                return call.finish(ChangePasswordResponse::failure(StatusCode::Failure));
+            }
+        }
+
+        // Right after the change, with nothing awaited in between, rather than under both locks:
+        // validations would wait for the password to be hashed otherwise. The generation reaches
+        // the other replicas with the sessions, so theirs go too.
+        let sessions_revoked = self.sessions().advance_session_generation(&user_uuid);
+        self.audit_log.record(AuditEvent::PasswordChanged { user_uuid: user_uuid.clone(), sessions_revoked });
+
+        let scopes = self.session_scopes(&user_uuid);
+        let session_token =
+            self.create_session(&user_uuid, SessionClass::Standard, scopes, caller.tenant.as_deref(), device.ip).await;
+
+        let reply: ChangePasswordResponse = ChangePasswordResponse::success(session_token, password_breached);
+
+        call.finish(reply)
+    }
+
+    async fn set_user_metadata(
+        &self,
+        request: Request<SetUserMetadataRequest>,
+    ) -> Result<Response<SetUserMetadataResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_not_in_maintenance()?;
+
+        self.store_outage.check_change()?;
+
+        let caller = self.caller_of(&request)?;
+        let req = request.into_inner();
+
+        let result = self
+            .authenticate_session(&req.session_token, &caller)
+            .and_then(|user_uuid| {
+                self.metadata_cache.invalidate(&user_uuid);
+                self.users().set_metadata(&user_uuid, &req.key, &req.value).map_err(|_| StatusCode::Failure)
+            });
+
+        let reply: SetUserMetadataResponse = SetUserMetadataResponse::from_result(result);
+
+        call.finish(reply)
+    }
+
+    async fn get_user_metadata(
+        &self,
+        request: Request<GetUserMetadataRequest>,
+    ) -> Result<Response<GetUserMetadataResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        let caller = self.caller_of(&request)?;
+        let req = request.into_inner();
+
+        let reply: GetUserMetadataResponse = self
+            .authenticate_session(&req.session_token, &caller)
+            .and_then(|user_uuid| {
+                self.metadata_cache
+                    .get_or_load(user_uuid.clone(), || self.users().get_metadata(&user_uuid))
+                    .ok_or(StatusCode::Failure)
+            })
+            .map_or_else(GetUserMetadataResponse::failure, GetUserMetadataResponse::success);
+
+        with_cache_control(call.finish(reply), CacheScope::Private, self.config.response_cache_ttl)
+    }
+
+    async fn export_my_data(
+        &self,
+        request: Request<ExportMyDataRequest>,
+    ) -> Result<Response<ExportMyDataResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        let caller = self.caller_of(&request)?;
+        let nonce = RequestNonce::from_request(&request);
+        let req = request.into_inner();
+
+        let reply: ExportMyDataResponse = self
+            .authenticate_session(&req.session_token, &caller)
+            .and_then(|user_uuid| self.check_not_replayed(nonce.as_ref()).map(|_| user_uuid))
+            .and_then(|user_uuid| self.export_user_data(&user_uuid).ok_or(StatusCode::Failure))
+            .map_or_else(ExportMyDataResponse::failure, ExportMyDataResponse::success);
+
+        call.finish(reply)
+    }
+
+    async fn delete_account(
+        &self,
+        request: Request<DeleteAccountRequest>,
+    ) -> Result<Response<DeleteAccountResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_not_in_maintenance()?;
+
+        self.store_outage.check_change()?;
+
+        let caller = self.caller_of(&request)?;
+        let nonce = RequestNonce::from_request(&request);
+        let req = request.into_inner();
+
+        let result = self
+            .authenticate_session(&req.session_token, &caller)
+            .and_then(|user_uuid| self.check_not_replayed(nonce.as_ref()).map(|_| user_uuid))
+            .and_then(|user_uuid| {
+                let requested = self.users().request_deletion(&user_uuid);
+
+                requested.map(|_| user_uuid).map_err(|_| StatusCode::Failure)
+            })
+            .map(|user_uuid| {
+                // Signed out everywhere; signing in again restores the account.
+                self.sessions().delete_sessions_of_user(&user_uuid);
+
+                self.audit_log.record(AuditEvent::AccountDeletionRequested { user_uuid });
+            });
+
+        let reply: DeleteAccountResponse = DeleteAccountResponse::from_result(result);
+
+        call.finish(reply)
+    }
+
+    async fn accept_terms(
+        &self,
+        request: Request<AcceptTermsRequest>,
+    ) -> Result<Response<AcceptTermsResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_not_in_maintenance()?;
+
+        self.store_outage.check_change()?;
+
+        // A password is checked as by `sign_in`, or this would be a way to guess one around it.
+        let device = Device::from_request(&request, &self.config.trusted_proxies);
+        let tenant = self.tenant_of(&request)?;
+        let is_honeypot = self.honeypot_usernames.contains(&request.get_ref().username);
+        if is_honeypot {
+            self.note_honeypot_sign_in(&request, &request.get_ref().username, &device, tenant.as_deref());
+        }
+        let req = request.into_inner();
+
+        if let Err(status_code) = self.throttle_sign_in(&device).await {
+            return call.finish(AcceptTermsResponse::failure(status_code));
+        }
+
+        let user_uuid = self.verify_password(&req.username, &req.password).await.filter(|_| !is_honeypot);
+        if user_uuid.is_none() {
+            self.note_failed_sign_in(&device, &req.username);
+        }
+
+        let result = user_uuid
+            .filter(|_| !req.version.is_empty())
+            .ok_or(StatusCode::Failure)
+            .and_then(|user_uuid| {
+                self.users()
+                    .set_accepted_terms_version(&user_uuid, &req.version)
+                    .map_err(|_| StatusCode::Failure)
+            });
+
+        let reply: AcceptTermsResponse = AcceptTermsResponse::from_result(result);
+
+        call.finish(reply)
+    }
+
+    async fn create_delegation_token(
+        &self,
+        request: Request<CreateDelegationTokenRequest>,
+    ) -> Result<Response<CreateDelegationTokenResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_session_capacity()?;
+
+        let caller = self.caller_of(&request)?;
+        let nonce = RequestNonce::from_request(&request);
+        let req = request.into_inner();
+
+        let ttl = match req.ttl_secs {
+            0 => self.config.delegation_token_ttl,
+            ttl_secs => Duration::from_secs(ttl_secs).min(self.config.delegation_token_ttl),
+        };
+
+        let reply: CreateDelegationTokenResponse = self
+            .authenticated_session(&req.session_token, &caller)
+            .and_then(|_| self.check_not_replayed(nonce.as_ref()))
+            .and_then(|_| {
+                let delegated = self.sessions().create_delegated_session(&req.session_token, req.scopes, ttl);
+                self.audit_evicted_sessions();
+
+                delegated.map_err(|e| {
+                    warn!("delegation refused: {}", e);
+                    StatusCode::Failure
+                })
+            })
+            .map_or_else(CreateDelegationTokenResponse::failure, |(delegation_token, session)| {
+                CreateDelegationTokenResponse::success(delegation_token, epoch_secs(session.expires_at))
+            });
+
+        call.finish(reply)
+    }
+
+    async fn list_revoked_sessions(
+        &self,
+        request: Request<ListRevokedSessionsRequest>,
+    ) -> Result<Response<ListRevokedSessionsResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+        let req = request.into_inner();
+
+        let revocations = self.sessions().revoked_since(req.cursor);
+        let reply = ListRevokedSessionsResponse::success(
+            revocations.token_digests,
+            revocations.jwt_ids,
+            revocations.cursor,
+            revocations.reset,
+        );
+
+        call.finish(reply)
+    }
+
+    async fn get_server_info(
+        &self,
+        request: Request<GetServerInfoRequest>,
+    ) -> Result<Response<GetServerInfoResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        let reply = GetServerInfoResponse::success(
+            self.config.password_prehash.as_ref().map(Into::into),
+            self.config.unknown_session_reply == UnknownSessionReply::NotFound,
+            self.users().id_scheme(),
+            proto_fingerprint(),
+        );
+
+        // The same for everybody until the service restarts with other settings.
+        with_cache_control(call.finish(reply), CacheScope::Public, self.config.response_cache_ttl)
+    }
+
+    async fn start_web_authn_registration(
+        &self,
+        request: Request<StartWebAuthnRegistrationRequest>,
+    ) -> Result<Response<StartWebAuthnRegistrationResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.store_outage.check_change()?;
+
+        let caller = self.caller_of(&request)?;
+        let req = request.into_inner();
+
+        let Some(rp) = RelyingParty::from_config(&self.config) else {
+            return call.finish(StartWebAuthnRegistrationResponse::failure(StatusCode::Failure));
+        };
+        let user_uuid = match self.authenticate_session(&req.session_token, &caller) {
+            Ok(user_uuid) => user_uuid,
+            Err(status_code) => return call.finish(StartWebAuthnRegistrationResponse::failure(status_code)),
+        };
+
+        let credential_ids = self
+            .users()
+            .get_webauthn_credentials(&user_uuid)
+            .into_iter()
+            .map(|credential| credential.credential_id)
+            .collect();
+        let challenge = self
+            .sessions()
+            .create_webauthn_challenge(WebAuthnCeremony::Registration(user_uuid.clone()), self.config.webauthn_challenge_ttl);
+
+        let reply = StartWebAuthnRegistrationResponse::success(challenge, rp.id, user_uuid.into_bytes(), credential_ids);
+
+        call.finish(reply)
+    }
+
+    async fn finish_web_authn_registration(
+        &self,
+        request: Request<FinishWebAuthnRegistrationRequest>,
+    ) -> Result<Response<FinishWebAuthnRegistrationResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_not_in_maintenance()?;
+
+        self.store_outage.check_change()?;
+
+        let caller = self.caller_of(&request)?;
+        let req = request.into_inner();
+
+        let Some(rp) = RelyingParty::from_config(&self.config) else {
+            return call.finish(FinishWebAuthnRegistrationResponse::failure(StatusCode::Failure));
+        };
+        let user_uuid = match self.authenticate_session(&req.session_token, &caller) {
+            Ok(user_uuid) => user_uuid,
+            Err(status_code) => return call.finish(FinishWebAuthnRegistrationResponse::failure(status_code)),
+        };
+
+        // The challenge goes whatever else is wrong, so an answer can't be tried twice; it must
+        // have been handed out to this user, for a registration.
+        let ceremony = challenge_of(&req.client_data_json)
+            .ok()
+            .and_then(|challenge| self.sessions().consume_webauthn_challenge(&challenge));
+        if ceremony != Some(WebAuthnCeremony::Registration(user_uuid.clone())) {
+            return call.finish(FinishWebAuthnRegistrationResponse::failure(StatusCode::Failure));
+        }
+
+        let credential = match rp.verify_registration(&req.client_data_json, &req.attestation_object) {
+            Ok(credential) => credential,
+            Err(e) => {
+                debug!("passkey registration refused: {}", e);
+                return call.finish(FinishWebAuthnRegistrationResponse::failure(StatusCode::Failure));
+            }
+        };
+        let credential_id = credential.credential_id.clone();
+
+        let reply = match self.users().add_webauthn_credential(&user_uuid, credential) {
+            Ok(()) => FinishWebAuthnRegistrationResponse::success(credential_id),
+            Err(e) => {
+                warn!("passkey registration failed: {}", e);
+                FinishWebAuthnRegistrationResponse::failure(StatusCode::Failure)
+            }
+        };
+
+        call.finish(reply)
+    }
+
+    async fn start_web_authn_assertion(
+        &self,
+        request: Request<StartWebAuthnAssertionRequest>,
+    ) -> Result<Response<StartWebAuthnAssertionResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        let Some(rp) = RelyingParty::from_config(&self.config) else {
+            return call.finish(StartWebAuthnAssertionResponse::failure(StatusCode::Failure));
+        };
+        let challenge =
+            self.sessions().create_webauthn_challenge(WebAuthnCeremony::Assertion, self.config.webauthn_challenge_ttl);
+
+        call.finish(StartWebAuthnAssertionResponse::success(challenge, rp.id))
+    }
+
+    async fn finish_web_authn_assertion(
+        &self,
+        request: Request<FinishWebAuthnAssertionRequest>,
+    ) -> Result<Response<FinishWebAuthnAssertionResponse>, Status> {
+        let call = self.start_rpc(&request)?;
+
+        self.check_session_capacity()?;
+
+        let device = Device::from_request(&request, &self.config.trusted_proxies);
+        let fingerprint = client_fingerprint(&request, self.config.session_binding, &self.config.trusted_proxies);
+        let tenant = self.tenant_of(&request)?;
+        let req = request.into_inner();
+
+        let Some(rp) = RelyingParty::from_config(&self.config) else {
+            return call.finish(FinishWebAuthnAssertionResponse::failure(StatusCode::Failure));
+        };
+        if let Err(status_code) = self.throttle_sign_in(&device).await {
+            return call.finish(FinishWebAuthnAssertionResponse::failure(status_code));
+        }
+
+        let ceremony = challenge_of(&req.client_data_json)
+            .ok()
+            .and_then(|challenge| self.sessions().consume_webauthn_challenge(&challenge));
+        let found = self.users().find_webauthn_credential(&req.credential_id);
+
+        let verified = match (ceremony, found) {
+            (Some(WebAuthnCeremony::Assertion), Some((user_uuid, credential))) => rp
+                .verify_assertion(&credential, &req.client_data_json, &req.authenticator_data, &req.signature)
+                .map(|sign_count| (user_uuid, sign_count))
+                .map_err(|e| debug!("passkey sign in refused: {}", e)),
+            _ => Err(()),
+        };
+        let Ok((user_uuid, sign_count)) = verified else {
+            // Counted like a wrong password, against the source.
+            self.note_failed_sign_in(&device, "");
+            self.refused_sign_ins.fetch_add(1, Ordering::Relaxed);
+            return call.finish(FinishWebAuthnAssertionResponse::failure(StatusCode::Failure));
+        };
+        if let Err(e) = self.users().set_webauthn_sign_count(&req.credential_id, sign_count) {
+            warn!("failed to store the passkey's signature counter: {}", e);
+        }
+
+        let user_uuid = self
+            .check_account_is_active(&user_uuid)
+            .map(|_| user_uuid)
+            .inspect(|user_uuid| self.record_accepted_terms(user_uuid, &req.accepted_terms_version))
+            .and_then(|user_uuid| self.check_terms_are_current(&user_uuid).map(|_| user_uuid))
+            .and_then(|user_uuid| self.check_sign_in_location(&user_uuid, &device).map(|_| user_uuid));
+
+        let reply = match user_uuid {
+            Ok(user_uuid) => {
+                let class = if req.remember_me { SessionClass::LongLived } else { SessionClass::Standard };
+                let scopes = self.session_scopes(&user_uuid);
+                let session_token = self.create_session(&user_uuid, class, scopes, tenant.as_deref(), device.ip).await;
+                self.bind_to_client(&session_token, fingerprint.as_deref());
+
+                self.note_device(&user_uuid, &device);
+                self.sign_ins.fetch_add(1, Ordering::Relaxed);
+
+                FinishWebAuthnAssertionResponse::success(user_uuid, session_token)
+            }
+            Err(status_code) => {
+                self.refused_sign_ins.fetch_add(1, Ordering::Relaxed);
+                FinishWebAuthnAssertionResponse::failure(status_code)
+            }
+        };
+
+        call.finish(reply)
+    }
+}
+
+// Of the proto the service was built from, for GetServerInfo.
+fn proto_fingerprint() -> &'static str {
+    static FINGERPRINT: OnceLock<String> = OnceLock::new();
+    FINGERPRINT.get_or_init(|| fingerprint(authentication::DESCRIPTOR_SET).expect("the build writes a valid descriptor set"))
+}
+
+// Versions are compared as dot separated numbers ("1.10" is newer than "1.9"), or else must match.
+fn is_older_version(accepted: &str, current: &str) -> bool {
+    let parse = |version: &str| {
+        version
+            .split('.')
+            .map(|part| part.parse::<u64>().ok())
+            .collect::<Option<Vec<_>>>()
+    };
+
+    match (parse(accepted), parse(current)) {
+        (Some(accepted), Some(current)) => accepted < current,
+        _ => accepted != current,
+    }
+}
+
+impl From<AuditEntry> for AuditRecord {
+    fn from(entry: AuditEntry) -> Self {
+        AuditRecord {
+            recorded_at: epoch_secs(entry.recorded_at),
+            kind: entry.event.kind().to_owned(),
+            details: entry.event.details(),
+            user_uuid: entry.event.user_uuid().unwrap_or_default().to_owned(),
+        }
+    }
+}
+
+pub(crate) fn epoch_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |secs| secs.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use crate::breached::tests::test_bloom_filter;
+    use crate::challenge::tests::solve;
+    use crate::events::tests::RecordingEventSink;
+    use crate::federation::tests::{test_id_token, test_providers};
+    use crate::geo::tests::{berlin, sydney, FixedGeoLookup};
+    use crate::ldap::{Directory, DirectoryError, LdapUsersImpl};
+    use crate::mailer::tests::RecordingMailer;
+    use crate::prehash::PasswordPrehash;
+    use crate::users::{AccountStatus, PasswordPolicy, UserEvent};
+    use crate::webauthn::tests::FakeAuthenticator;
+    use auth_ids::IdScheme;
+    use pbkdf2::Algorithm;
+    use crate::{users::UsersImpl, sessions::{token_digest, SessionBinding, SessionsImpl}};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sign_in_should_fail_if_user_not_found() {
+        let auth_service = AuthService::builder().build();
+
+        let request = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+
+        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+        assert!(result.user_uuid.is_empty());
+        assert!(result.session_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_refuse_oversized_username() {
+        let auth_service = AuthService::builder().build();
+
+        let request = tonic::Request::new(SignInRequest {
+            username: "x".repeat(4 * 1024 * 1024),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+
+        let status = auth_service.sign_in(request).await.unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().starts_with("username"));
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_not_wait_on_a_slow_directory() {
+        struct SlowDirectory;
+
+        impl Directory for SlowDirectory {
+            fn verify_credentials(&self, _username: &str, _password: &str) -> Result<Option<String>, DirectoryError> {
+                std::thread::sleep(Duration::from_secs(2));
+                Ok(Some(String::from("entry-1")))
+            }
+
+            fn check(&self) -> Result<(), DirectoryError> {
+                Ok(())
+            }
+
+            fn timeout(&self) -> Duration {
+                Duration::from_millis(200)
+            }
+        }
+
+        let auth_service = AuthService::builder()
+            .users(LdapUsersImpl::new(SlowDirectory))
+            .build();
+
+        let request = tonic::Request::new(SignInRequest {
+            username: "jdoe".to_owned(),
+            password: "secret".to_owned(),
+            remember_me: false,
+        });
+
+        let started = std::time::Instant::now();
+        let (result, users_unlocked_after) = tokio::join!(auth_service.sign_in(request), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            auth_service.users().user_count();
+            started.elapsed()
+        });
+
+        // The users were free while the directory was asked, and the answer wasn't waited for.
+        assert!(users_unlocked_after < Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(result.unwrap().into_inner().status_code, StatusCode::Failure as i32);
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_fail_if_incorrect_password() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .build();
+
+        let request = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "wrong password".to_owned(),
+            remember_me: false,
+        });
+
+        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+        assert!(result.user_uuid.is_empty());
+        assert!(result.session_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_succeed() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .build();
+
+        let request = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+
+        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert!(!result.user_uuid.is_empty());
+        assert!(!result.session_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_rehash_outdated_password() {
+        let old_policy = PasswordPolicy { algorithm: Algorithm::Pbkdf2Sha256, rounds: 1_000 };
+        let mut users_service = UsersImpl::default().with_password_policy(old_policy);
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let new_policy = PasswordPolicy { algorithm: Algorithm::Pbkdf2Sha512, rounds: 2_000 };
+
+        let auth_service = AuthService::builder()
+            .users(users_service.with_password_policy(new_policy))
+            .build();
+        assert_eq!(auth_service.users().outdated_password_hashes(), 1);
+
+        let sign_in = |password: &str| tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: password.to_owned(),
+            remember_me: false,
+        });
+
+        auth_service.sign_in(sign_in("wrong")).await.unwrap();
+        assert_eq!(auth_service.users().outdated_password_hashes(), 1);
+
+        for _ in 0..2 {
+            let result = auth_service.sign_in(sign_in("654321")).await.unwrap().into_inner();
+            assert_eq!(result.status_code(), StatusCode::Success);
+        }
+        assert_eq!(auth_service.users().outdated_password_hashes(), 0);
+        assert_eq!(auth_service.passwords_rehashed(), 1);
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_move_passwords_over_to_argon2id() {
+        use crate::hashers::{password_hasher, HashAlgorithm};
+
+        let old_policy = PasswordPolicy { algorithm: Algorithm::Pbkdf2Sha256, rounds: 1_000 };
+        let mut users_service = UsersImpl::default().with_password_policy(old_policy);
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let config = Config {
+            password_hash_algorithm: HashAlgorithm::Argon2id,
+            password_hash_rounds: 1,
+            password_hash_memory_kib: 1_024,
+            ..Config::default()
+        };
+        let auth_service = AuthService::builder()
+            .users(users_service.with_password_hasher(password_hasher(&config)))
+            .config(config)
+            .build();
+        assert_eq!(auth_service.users().outdated_password_hashes(), 1);
+
+        let sign_in = || tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+
+        for _ in 0..2 {
+            let result = auth_service.sign_in(sign_in()).await.unwrap().into_inner();
+            assert_eq!(result.status_code(), StatusCode::Success);
+        }
+        assert_eq!(auth_service.users().outdated_password_hashes(), 0);
+        assert_eq!(auth_service.passwords_rehashed(), 1);
+        let user_uuid = auth_service.users().find_user_uuid("123456").unwrap();
+        let exported = auth_service.users().export_user(&user_uuid).unwrap();
+        assert!(matches!(&exported[0], UserEvent::UserCreated { password_hash, .. } if password_hash.starts_with("$argon2id$")));
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_move_passwords_over_to_bcrypt() {
+        use crate::hashers::{password_hasher, HashAlgorithm};
+
+        let old_policy = PasswordPolicy { algorithm: Algorithm::Pbkdf2Sha256, rounds: 1_000 };
+        let mut users_service = UsersImpl::default().with_password_policy(old_policy);
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let config = Config {
+            password_hash_algorithm: HashAlgorithm::Bcrypt,
+            password_hash_rounds: 4,
+            ..Config::default()
+        };
+        let auth_service = AuthService::builder()
+            .users(users_service.with_password_hasher(password_hasher(&config)))
+            .config(config)
+            .build();
+        assert_eq!(auth_service.users().outdated_password_hashes(), 1);
+
+        let sign_in = || tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+
+        for _ in 0..2 {
+            let result = auth_service.sign_in(sign_in()).await.unwrap().into_inner();
+            assert_eq!(result.status_code(), StatusCode::Success);
+        }
+        assert_eq!(auth_service.users().outdated_password_hashes(), 0);
+        assert_eq!(auth_service.passwords_rehashed(), 1);
+        let user_uuid = auth_service.users().find_user_uuid("123456").unwrap();
+        let exported = auth_service.users().export_user(&user_uuid).unwrap();
+        assert!(matches!(&exported[0], UserEvent::UserCreated { password_hash, .. } if password_hash.starts_with("$bcrypt$")));
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_move_passwords_over_to_scrypt() {
+        use crate::hashers::{password_hasher, HashAlgorithm};
+
+        let old_policy = PasswordPolicy { algorithm: Algorithm::Pbkdf2Sha256, rounds: 1_000 };
+        let mut users_service = UsersImpl::default().with_password_policy(old_policy);
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let config = Config {
+            password_hash_algorithm: HashAlgorithm::Scrypt,
+            password_hash_rounds: 4,
+            ..Config::default()
+        };
+        let auth_service = AuthService::builder()
+            .users(users_service.with_password_hasher(password_hasher(&config)))
+            .config(config)
+            .build();
+        assert_eq!(auth_service.users().outdated_password_hashes(), 1);
+
+        let sign_in = || tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+
+        for _ in 0..2 {
+            let result = auth_service.sign_in(sign_in()).await.unwrap().into_inner();
+            assert_eq!(result.status_code(), StatusCode::Success);
+        }
+        assert_eq!(auth_service.users().outdated_password_hashes(), 0);
+        assert_eq!(auth_service.passwords_rehashed(), 1);
+        let user_uuid = auth_service.users().find_user_uuid("123456").unwrap();
+        let exported = auth_service.users().export_user(&user_uuid).unwrap();
+        assert!(matches!(&exported[0], UserEvent::UserCreated { password_hash, .. } if password_hash.starts_with("$scrypt$")));
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_count_towards_usage_stats() {
+        let mut users_service = UsersImpl::default();
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+        let config = Config { usage_stats_file: Some("usage-stats.jsonl".to_owned()), ..Config::default() };
+        let auth_service = AuthService::builder().users(users_service).config(config).build();
+
+        for password in ["654321", "654321", "wrong"] {
+            let request = tonic::Request::new(SignInRequest {
+                username: "123456".to_owned(),
+                password: password.to_owned(),
+                remember_me: false,
+            });
+            auth_service.sign_in(request).await.unwrap();
+        }
+
+        let days = auth_service.usage_stats.take_completed(SystemTime::now() + Duration::from_secs(24 * 60 * 60));
+        assert_eq!(days.len(), 1);
+        assert_eq!((days[0].daily_active_users, days[0].sign_ins, days[0].refused_sign_ins), (1, 2, 1));
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_recover_from_poisoned_users_lock() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .build();
+
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _users_service = auth_service.users();
+            panic!("a handler panics with the users lock held");
+        }));
+        assert!(auth_service.users_service.is_poisoned());
+
+        let request = || tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+
+        let status = auth_service.sign_in(request()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+        assert_eq!(auth_service.internal_errors(), 1);
+
+        let result = auth_service.sign_in(request()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(auth_service.internal_errors(), 1);
+    }
+
+    #[tokio::test]
+    async fn sign_in_with_remember_me_should_create_long_lived_session() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .build();
+
+        let request = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: true,
+        });
+
+        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        let session = auth_service
+            .sessions_service
+            .lock()
+            .unwrap()
+            .get_session(&result.session_token)
+            .unwrap();
+        assert_eq!(session.class, SessionClass::LongLived);
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_fail_if_username_exists() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .build();
+
+        let request = tonic::Request::new(SignUpRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            invite_code: String::new(),
+            accepted_terms_version: String::new(),
+            challenge_response: String::new(),
+        });
+
+        let result = auth_service.sign_up(request).await.unwrap();
+
+        assert_eq!(result.into_inner().status_code, StatusCode::UsernameTaken as i32);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_sign_ups_should_create_the_user_once() {
+        let auth_service = std::sync::Arc::new(AuthService::builder().build());
+
+        let sign_ups: Vec<_> = (0..8)
+            .map(|_| {
+                let auth_service = auth_service.clone();
+                tokio::spawn(async move {
+                    let request = tonic::Request::new(SignUpRequest {
+                        username: "123456".to_owned(),
+                        password: "654321".to_owned(),
+                        ..SignUpRequest::default()
+                    });
+                    auth_service.sign_up(request).await.unwrap().into_inner().status_code
+                })
+            })
+            .collect();
+
+        let mut status_codes = Vec::new();
+        for sign_up in sign_ups {
+            status_codes.push(sign_up.await.unwrap());
+        }
+
+        assert_eq!(status_codes.iter().filter(|code| **code == StatusCode::Success as i32).count(), 1);
+        assert_eq!(status_codes.iter().filter(|code| **code == StatusCode::UsernameTaken as i32).count(), 7);
+    }
+
+    // Hundreds of users signing up, in twice and out once, all at the same time. Guards the store
+    // locks: nothing deadlocks (the timeout), and no session goes missing or lingers.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_sign_ins_and_outs_should_keep_every_session() {
+        const USERS: usize = 200;
+
+        // Cheap hashes; the locks are what's under test.
+        let password_policy = PasswordPolicy { algorithm: Algorithm::Pbkdf2Sha256, rounds: 1_000 };
+        let auth_service = std::sync::Arc::new(
+            AuthService::builder()
+                .users(UsersImpl::default().with_password_policy(password_policy))
+                .build(),
+        );
+
+        let users: Vec<_> = (0..USERS)
+            .map(|i| {
+                let auth_service = auth_service.clone();
+                tokio::spawn(async move {
+                    let username = format!("user-{i}");
+                    let sign_up = tonic::Request::new(SignUpRequest {
+                        username: username.clone(),
+                        password: "654321".to_owned(),
+                        ..SignUpRequest::default()
+                    });
+                    assert_eq!(auth_service.sign_up(sign_up).await.unwrap().into_inner().status_code(), StatusCode::Success);
+
+                    let mut session_tokens = Vec::new();
+                    for _ in 0..2 {
+                        let sign_in = tonic::Request::new(SignInRequest {
+                            username: username.clone(),
+                            password: "654321".to_owned(),
+                            remember_me: false,
+                        });
+                        let signed_in = auth_service.sign_in(sign_in).await.unwrap().into_inner();
+                        assert_eq!(signed_in.status_code(), StatusCode::Success);
+                        session_tokens.push(signed_in.session_token);
+                    }
+
+                    let sign_out = tonic::Request::new(SignOutRequest { session_token: session_tokens.remove(0) });
+                    assert_eq!(auth_service.sign_out(sign_out).await.unwrap().into_inner().status_code(), StatusCode::Success);
+
+                    session_tokens.remove(0)
+                })
+            })
+            .collect();
+
+        let session_tokens = tokio::time::timeout(Duration::from_secs(120), async {
+            let mut session_tokens = Vec::new();
+            for user in users {
+                session_tokens.push(user.await.unwrap());
+            }
+            session_tokens
+        })
+        .await
+        .expect("sign ins deadlocked");
+
+        assert_eq!(auth_service.users().user_count(), USERS);
+        assert_eq!(auth_service.sessions().session_count(), USERS);
+        for session_token in session_tokens {
+            let request = tonic::Request::new(ValidateSessionRequest { session_token });
+            assert_eq!(auth_service.validate_session(request).await.unwrap().into_inner().status_code(), StatusCode::Success);
+        }
+    }
+
+    // The same sessions signed out from many tasks at once: each goes exactly once.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_sign_outs_should_delete_each_session_once() {
+        const SESSIONS: usize = 100;
+        const SIGN_OUTS_PER_SESSION: usize = 4;
+
+        let mut sessions_service = SessionsImpl::default();
+        let session_tokens: Vec<String> =
+            (0..SESSIONS).map(|i| sessions_service.create_session(&format!("user-{i}"), SessionClass::Standard)).collect();
+
+        let auth_service = std::sync::Arc::new(AuthService::builder().sessions(sessions_service).build());
+
+        let sign_outs: Vec<_> = session_tokens
+            .iter()
+            .cycle()
+            .take(SESSIONS * SIGN_OUTS_PER_SESSION)
+            .map(|session_token| {
+                let auth_service = auth_service.clone();
+                let request = tonic::Request::new(SignOutRequest { session_token: session_token.clone() });
+                tokio::spawn(async move { auth_service.sign_out(request).await.unwrap().into_inner().status_code() })
+            })
+            .collect();
+
+        let status_codes = tokio::time::timeout(Duration::from_secs(60), async {
+            let mut status_codes = Vec::new();
+            for sign_out in sign_outs {
+                status_codes.push(sign_out.await.unwrap());
+            }
+            status_codes
+        })
+        .await
+        .expect("sign outs deadlocked");
+
+        assert_eq!(status_codes.iter().filter(|code| **code == StatusCode::Success).count(), SESSIONS);
+        assert_eq!(
+            status_codes.iter().filter(|code| **code == StatusCode::SessionNotFound).count(),
+            SESSIONS * (SIGN_OUTS_PER_SESSION - 1)
+        );
+        assert_eq!(auth_service.sessions().session_count(), 0);
+        assert_eq!(auth_service.sessions().revoked_since(0).token_digests.len(), SESSIONS);
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_succeed() {
+        let auth_service = AuthService::builder().build();
+
+        let request = tonic::Request::new(SignUpRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            invite_code: String::new(),
+            accepted_terms_version: String::new(),
+            challenge_response: String::new(),
+        });
+
+        let result = auth_service.sign_up(request).await.unwrap();
+
+        assert_eq!(result.into_inner().status_code, StatusCode::Success as i32);
+    }
+
+    #[tokio::test]
+    async fn reserved_usernames_should_not_be_signed_up_with_or_changed_to() {
+        let mut sessions_service = SessionsImpl::default();
+        let mut users_service = UsersImpl::default();
+        let user_uuid = users_service.link_external_user("google", "1234").unwrap();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .reserved_usernames(ReservedUsernames::new(&Config::default().reserved_usernames).with_denied_words(&["darn".to_owned()]))
+            .build();
+
+        let sign_up = |username: &str| tonic::Request::new(SignUpRequest {
+            username: username.to_owned(),
+            password: "654321".to_owned(),
+            ..SignUpRequest::default()
+        });
+
+        for username in [" Admin", "root", "darnit"] {
+            let result = auth_service.sign_up(sign_up(username)).await.unwrap().into_inner();
+            assert_eq!(result.status_code, StatusCode::UsernameNotAllowed as i32, "{username}");
+        }
+        assert!(auth_service.users().find_user_uuid("root").is_none());
+
+        let result = auth_service.sign_up(sign_up("rooted")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        let change = |new_username: &str| tonic::Request::new(ChangeUsernameRequest {
+            session_token: session_token.clone(),
+            new_username: new_username.to_owned(),
+        });
+        let result = auth_service.change_username(change("SUPPORT")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::UsernameNotAllowed as i32);
+        assert_eq!(auth_service.users().get_username(&user_uuid).unwrap(), "google:1234");
+    }
+
+    #[tokio::test]
+    async fn federated_usernames_should_not_be_squatted() {
+        let auth_service = AuthService::builder()
+            .external_providers(test_providers())
+            .build();
+
+        let request = tonic::Request::new(SignUpRequest {
+            username: "google:1234".to_owned(),
+            password: "654321".to_owned(),
+            ..SignUpRequest::default()
+        });
+        let result = auth_service.sign_up(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::UsernameNotAllowed as i32);
+
+        let request = tonic::Request::new(ExchangeExternalTokenRequest {
+            provider: "google".to_owned(),
+            id_token: test_id_token("1234", "https://accounts.google.com", u64::MAX / 2),
+            accepted_terms_version: String::new(),
+        });
+        let result = auth_service.exchange_external_token(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(auth_service.users().find_user_uuid("google:1234"), Some(result.user_uuid));
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_take_only_derived_keys_when_prehashed() {
+        let prehash = PasswordPrehash { iterations: 10, salt: "deployment".to_owned() };
+        let auth_service = AuthService::builder()
+            .config(Config { password_prehash: Some(prehash.clone()), ..Config::default() })
+            .build();
+
+        let info = auth_service.get_server_info(tonic::Request::new(GetServerInfoRequest {})).await.unwrap().into_inner();
+        assert_eq!(info.password_prehash.map(|prehash| prehash.iterations), Some(10));
+
+        let sign_up = |password: String| tonic::Request::new(SignUpRequest {
+            username: "123456".to_owned(),
+            password,
+            invite_code: String::new(),
+            accepted_terms_version: String::new(),
+            challenge_response: String::new(),
+        });
+
+        let plain = auth_service.sign_up(sign_up("654321".to_owned())).await.unwrap().into_inner();
+        assert_eq!(plain.status_code(), StatusCode::PasswordNotPrehashed);
+
+        let derived = auth_service.sign_up(sign_up(prehash.derive("654321"))).await.unwrap().into_inner();
+        assert_eq!(derived.status_code(), StatusCode::Success);
+
+        let request = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: prehash.derive("654321"),
+            remember_me: false,
+        });
+        let signed_in = auth_service.sign_in(request).await.unwrap().into_inner();
+        assert_eq!(signed_in.status_code(), StatusCode::Success);
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_refuse_past_the_user_limit() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .config(Config { users_soft_limit: 1, users_hard_limit: 1, ..Config::default() })
+            .build();
+
+        let request = tonic::Request::new(SignUpRequest {
+            username: "234567".to_owned(),
+            password: "654321".to_owned(),
+            invite_code: String::new(),
+            accepted_terms_version: String::new(),
+            challenge_response: String::new(),
+        });
+
+        let status = auth_service.sign_up(request).await.unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(status.message(), "user limit reached");
+        assert_eq!(auth_service.counts(), (1, 0));
+        assert_eq!(auth_service.check_capacity(), vec!["1 users, over the soft limit of 1".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn create_guest_session_should_refuse_past_the_session_limit() {
+        let auth_service = AuthService::builder()
+            .config(Config { sessions_hard_limit: 2, ..Config::default() })
+            .build();
+
+        for _ in 0..2 {
+            auth_service.create_guest_session(tonic::Request::new(CreateGuestSessionRequest {})).await.unwrap();
+        }
+        let status = auth_service.create_guest_session(tonic::Request::new(CreateGuestSessionRequest {})).await.unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(status.message(), "session limit reached");
+        assert_eq!(auth_service.counts(), (0, 2));
+        assert!(auth_service.check_capacity().is_empty());
+    }
+
+    #[tokio::test]
+    async fn evicted_sessions_should_be_audited() {
+        use crate::sessions::EvictionPolicy;
+
+        let auth_service = AuthService::builder()
+            .sessions(SessionsImpl::default().with_max_sessions(1, EvictionPolicy::LeastRecentlyUsed))
+            .build();
+
+        let first = auth_service.create_guest_session(tonic::Request::new(CreateGuestSessionRequest {})).await.unwrap();
+        auth_service.create_guest_session(tonic::Request::new(CreateGuestSessionRequest {})).await.unwrap();
+
+        let audit_entries = auth_service.audit_log.entries_for(&first.into_inner().guest_uuid);
+        assert_eq!(audit_entries.len(), 1);
+        assert_eq!(audit_entries[0].event.kind(), "SessionEvicted");
+        assert_eq!(audit_entries[0].event.details()["class"], "Guest");
+    }
+
+    #[tokio::test]
+    async fn claims_provider_should_add_claims_to_session_tokens() {
+        use jsonwebtoken::{decode, Algorithm as JwtAlgorithm, DecodingKey, Validation};
+        use serde_json::{json, Value};
+
+        use crate::tokens::JwtTokens;
+
+        // Guests are on the free plan; there's nothing to know about anybody else.
+        struct Plans;
+
+        #[tonic::async_trait]
+        impl ClaimsProvider for Plans {
+            async fn claims(&self, _user_uuid: &str, class: SessionClass) -> Result<Claims, String> {
+                match class {
+                    SessionClass::Guest => Ok(json!({ "plan": "free" }).as_object().unwrap().clone()),
+                    _ => Err(String::from("Error::UnknownUser")),
+                }
+            }
+        }
+
+        let auth_service = AuthService::builder()
+            .sessions(SessionsImpl::default().with_token_generator(Box::new(JwtTokens::new("auth".to_owned(), b"secret"))))
+            .claims_provider(Box::new(Plans))
+            .build();
+
+        let guest = auth_service
+            .create_guest_session(tonic::Request::new(CreateGuestSessionRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let claims = decode::<Value>(&guest.session_token, &DecodingKey::from_secret(b"secret"), &Validation::new(JwtAlgorithm::HS256))
+            .unwrap()
+            .claims;
+        assert_eq!(claims["plan"], "free");
+        assert_eq!(claims["sub"], guest.guest_uuid);
+
+        // Without them when the provider fails.
+        assert!(auth_service.claims_for(&guest.guest_uuid, SessionClass::Standard, None).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn store_outage_should_refuse_account_changes_but_keep_validating_sessions() {
+        use crate::journal::FileJournal;
+
+        let path = std::env::temp_dir().join(format!("users-outage-{}.jsonl", std::process::id()));
+        let mut users_service = UsersImpl::default().with_journal(Box::new(FileJournal::open(&path).unwrap())).unwrap();
+        users_service.create_user("123456".to_owned(), "654321".to_owned()).unwrap();
+        let user_uuid = users_service.find_user_uuid("123456").unwrap();
+        let mut sessions_service = SessionsImpl::default();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
+
+        let auth_service = AuthService::builder().users(users_service).sessions(sessions_service).build();
+        let sign_up = || {
+            tonic::Request::new(SignUpRequest {
+                username: "234567".to_owned(),
+                password: "654321".to_owned(),
+                invite_code: String::new(),
+                accepted_terms_version: String::new(),
+                challenge_response: String::new(),
+            })
+        };
+        let validate = || tonic::Request::new(ValidateSessionRequest { session_token: session_token.clone() });
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(auth_service.check_stores().is_err());
+
+        assert_eq!(auth_service.sign_up(sign_up()).await.unwrap_err().code(), tonic::Code::Unavailable);
+        let result = auth_service.validate_session(validate()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        std::fs::write(&path, "").unwrap();
+        assert!(auth_service.check_stores().is_ok());
+
+        let result = auth_service.sign_up(sign_up()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        let metrics = auth_service.outage_metrics();
+        assert_eq!((metrics.down_for, metrics.refused_changes, metrics.stale_validations), (None, 1, 1));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sign_out_should_succeed() {
+        let mut sessions_service = SessionsImpl::default();
+        let session_token = sessions_service.create_session("123456", SessionClass::Standard);
+
+        let auth_service = AuthService::builder()
+            .sessions(sessions_service)
+            .build();
+
+        let request = tonic::Request::new(SignOutRequest { session_token });
+
+        let result = auth_service.sign_out(request).await.unwrap();
+
+        assert_eq!(result.into_inner().status_code, StatusCode::Success as i32);
+    }
+
+    #[tokio::test]
+    async fn sign_out_should_report_unknown_session() {
+        let auth_service = AuthService::builder().build();
+
+        let request = tonic::Request::new(SignOutRequest { session_token: "unknown".to_owned() });
+        let result = auth_service.sign_out(request).await.unwrap();
+        assert_eq!(result.into_inner().status_code(), StatusCode::SessionNotFound);
+
+        let request = tonic::Request::new(SignOutRequest { session_token: "".to_owned() });
+        let status = auth_service.sign_out(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn unknown_sessions_should_not_be_told_apart_unless_configured() {
+        let auth_service = AuthService::builder()
+            .config(Config { unknown_session_reply: UnknownSessionReply::Success, ..Config::default() })
+            .build();
+
+        let request = tonic::Request::new(SignOutRequest { session_token: "unknown".to_owned() });
+        let result = auth_service.sign_out(request).await.unwrap();
+        assert_eq!(result.into_inner().status_code(), StatusCode::Success);
+
+        let request = tonic::Request::new(ValidateSessionRequest { session_token: "unknown".to_owned() });
+        let result = auth_service.validate_session(request).await.unwrap();
+        assert_eq!(result.into_inner().status_code(), StatusCode::Failure);
+
+        let request = tonic::Request::new(GetServerInfoRequest {});
+        assert!(!auth_service.get_server_info(request).await.unwrap().into_inner().reports_unknown_sessions);
+
+        let auth_service = AuthService::builder().build();
+
+        let request = tonic::Request::new(ValidateSessionRequest { session_token: "unknown".to_owned() });
+        let result = auth_service.validate_session(request).await.unwrap();
+        assert_eq!(result.into_inner().status_code(), StatusCode::SessionNotFound);
+
+        let request = tonic::Request::new(GetServerInfoRequest {});
+        assert!(auth_service.get_server_info(request).await.unwrap().into_inner().reports_unknown_sessions);
+    }
+
+    #[tokio::test]
+    async fn get_server_info_should_say_what_user_ids_look_like() {
+        let auth_service = AuthService::builder().build();
+        let info = auth_service.get_server_info(tonic::Request::new(GetServerInfoRequest {})).await.unwrap().into_inner();
+        assert_eq!(info.user_id_scheme, "uuid-v4");
+
+        let auth_service = AuthService::builder().users(UsersImpl::default().with_id_scheme(IdScheme::UuidV7)).build();
+        let info = auth_service.get_server_info(tonic::Request::new(GetServerInfoRequest {})).await.unwrap().into_inner();
+        assert_eq!(info.user_id_scheme, "uuid-v7");
+    }
+
+    #[tokio::test]
+    async fn get_server_info_should_match_the_proto_clients_are_built_from() {
+        let auth_service = AuthService::builder().build();
+        let info = auth_service.get_server_info(tonic::Request::new(GetServerInfoRequest {})).await.unwrap().into_inner();
+        assert_eq!(info.proto_fingerprint, auth_client::descriptor::compiled_fingerprint());
+    }
+
+    #[tokio::test]
+    async fn sign_out_should_be_listed_as_revocation() {
+        let mut sessions_service = SessionsImpl::default();
+        let session_token = sessions_service.create_session("123456", SessionClass::Standard);
+
+        let auth_service = AuthService::builder()
+            .sessions(sessions_service)
+            .build();
+
+        let list = |cursor| tonic::Request::new(ListRevokedSessionsRequest { cursor });
+        let before = auth_service.list_revoked_sessions(list(0)).await.unwrap().into_inner();
+
+        let request = tonic::Request::new(SignOutRequest { session_token: session_token.clone() });
+        auth_service.sign_out(request).await.unwrap();
+
+        let after = auth_service.list_revoked_sessions(list(before.cursor)).await.unwrap().into_inner();
+        assert_eq!(after.status_code(), StatusCode::Success);
+        assert_eq!(after.revoked_token_digests, vec![token_digest(&session_token)]);
+        assert!(!after.reset);
+    }
+
+    #[tokio::test]
+    async fn exchange_external_token_should_reuse_linked_user() {
+        let auth_service = AuthService::builder()
+            .external_providers(test_providers())
+            .build();
+
+        let exchange = || tonic::Request::new(ExchangeExternalTokenRequest {
+            provider: "google".to_owned(),
+            id_token: test_id_token("1234", "https://accounts.google.com", u64::MAX / 2),
+            accepted_terms_version: String::new(),
+        });
+
+        let first = auth_service.exchange_external_token(exchange()).await.unwrap().into_inner();
+        let second = auth_service.exchange_external_token(exchange()).await.unwrap().into_inner();
+
+        assert_eq!(first.status_code, StatusCode::Success as i32);
+        assert_eq!(first.user_uuid, second.user_uuid);
+        assert_ne!(first.session_token, second.session_token);
+    }
+
+    #[tokio::test]
+    async fn exchange_external_token_should_fail_for_unknown_provider() {
+        let auth_service = AuthService::builder()
+            .external_providers(test_providers())
+            .build();
+
+        let request = tonic::Request::new(ExchangeExternalTokenRequest {
+            provider: "github".to_owned(),
+            id_token: test_id_token("1234", "https://accounts.google.com", u64::MAX / 2),
+            accepted_terms_version: String::new(),
+        });
+
+        let result = auth_service.exchange_external_token(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+        assert!(result.session_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn introspect_token_should_report_active_session() {
+        let mut users_service = UsersImpl::default();
+        let mut sessions_service = SessionsImpl::default();
+
+        let user_uuid = users_service.link_external_user("google", "1234").unwrap();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .api_keys(ApiKeys::new(vec!["key-1".to_owned()]))
+            .build();
+
+        let introspect = |token: &str| {
+            let mut request = tonic::Request::new(IntrospectTokenRequest { token: token.to_owned() });
+            request.metadata_mut().insert("x-api-key", "key-1".parse().unwrap());
+            request
+        };
+
+        let result = auth_service.introspect_token(introspect(&session_token)).await.unwrap().into_inner();
+
+        assert!(result.active);
+        assert_eq!(result.sub, user_uuid);
+
+        let result = auth_service.introspect_token(introspect("unknown")).await.unwrap().into_inner();
+
+        assert!(!result.active);
+        assert!(result.sub.is_empty());
+
+        let stats = &auth_service.rpc_metrics.snapshot()["IntrospectToken"];
+        assert_eq!((stats.calls, stats.errors), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn introspect_token_should_require_api_key() {
+        let auth_service = AuthService::builder()
+            .api_keys(ApiKeys::new(vec!["key-1".to_owned()]))
+            .build();
+
+        let request = tonic::Request::new(IntrospectTokenRequest { token: "some-token".to_owned() });
+
+        let result = auth_service.introspect_token(request).await;
+
+        assert_eq!(result.unwrap_err().code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn magic_link_should_sign_in_once() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("jdoe@example.com".to_owned(), "654321".to_owned());
+
+        let mailer = RecordingMailer::default();
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .mailer(Box::new(mailer.clone()))
+            .build();
+
+        let request = tonic::Request::new(RequestMagicLinkRequest {
+            username: "jdoe@example.com".to_owned(),
+        });
+
+        let result = auth_service.request_magic_link(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        let (to, body) = mailer.sent.lock().unwrap().pop().expect("should send a magic link");
+        let magic_link_token = body
+            .split_once("token=")
+            .and_then(|(_, rest)| rest.split_whitespace().next())
+            .unwrap()
+            .to_owned();
+
+        assert_eq!(to, "jdoe@example.com");
+
+        let consume = || tonic::Request::new(ConsumeMagicLinkRequest {
+            magic_link_token: magic_link_token.clone(),
+            accepted_terms_version: String::new(),
+        });
+
+        let result = auth_service.consume_magic_link(consume()).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert!(!result.session_token.is_empty());
+
+        let result = auth_service.consume_magic_link(consume()).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+    }
+
+    #[tokio::test]
+    async fn passkey_should_sign_in_once_registered() {
+        let mut users_service = UsersImpl::default();
+        let mut sessions_service = SessionsImpl::default();
+
+        users_service.create_user("jdoe".to_owned(), "654321".to_owned()).unwrap();
+        let user_uuid = users_service.get_user_uuid("jdoe".to_owned(), "654321".to_owned()).unwrap();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
+
+        let config = Config { webauthn_rp_id: Some("example.com".to_owned()), ..Config::default() };
+        let mut authenticator = FakeAuthenticator::new(&RelyingParty::from_config(&config).unwrap(), b"passkey-1");
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .config(config)
+            .build();
+
+        let start = StartWebAuthnRegistrationRequest { session_token: session_token.clone() };
+        let started = auth_service.start_web_authn_registration(tonic::Request::new(start)).await.unwrap().into_inner();
+        assert_eq!(started.status_code, StatusCode::Success as i32);
+        assert_eq!(started.rp_id, "example.com");
+        assert_eq!(started.user_handle, user_uuid.as_bytes());
+
+        let (client_data_json, attestation_object) = authenticator.register(&started.challenge);
+        let finish = || tonic::Request::new(FinishWebAuthnRegistrationRequest {
+            session_token: session_token.clone(),
+            client_data_json: client_data_json.clone(),
+            attestation_object: attestation_object.clone(),
+        });
+        let result = auth_service.finish_web_authn_registration(finish()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.credential_id, b"passkey-1");
+
+        // The challenge is gone.
+        let result = auth_service.finish_web_authn_registration(finish()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+
+        let started = auth_service.start_web_authn_assertion(tonic::Request::new(StartWebAuthnAssertionRequest {})).await.unwrap().into_inner();
+        let (client_data_json, authenticator_data, signature) = authenticator.assert(&started.challenge);
+        let finish = || tonic::Request::new(FinishWebAuthnAssertionRequest {
+            credential_id: b"passkey-1".to_vec(),
+            client_data_json: client_data_json.clone(),
+            authenticator_data: authenticator_data.clone(),
+            signature: signature.clone(),
+            accepted_terms_version: String::new(),
+            remember_me: false,
+        });
+        let result = auth_service.finish_web_authn_assertion(finish()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.user_uuid, user_uuid);
+        assert!(!result.session_token.is_empty());
+
+        let result = auth_service.finish_web_authn_assertion(finish()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+    }
+
+    #[tokio::test]
+    async fn passkeys_should_be_refused_without_a_relying_party() {
+        let auth_service = AuthService::builder().build();
+
+        let result = auth_service.start_web_authn_assertion(tonic::Request::new(StartWebAuthnAssertionRequest {})).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+        assert!(result.challenge.is_empty());
+    }
+
+    #[tokio::test]
+    async fn magic_link_should_not_reveal_unknown_user() {
+        let mailer = RecordingMailer::default();
+
+        let auth_service = AuthService::builder()
+            .mailer(Box::new(mailer.clone()))
+            .build();
+
+        let request = tonic::Request::new(RequestMagicLinkRequest {
+            username: "nobody@example.com".to_owned(),
+        });
+
+        let result = auth_service.request_magic_link(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert!(mailer.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn magic_link_should_take_as_long_for_unknown_user_when_protected() {
+        let auth_service = AuthService::builder()
+            .config(Config {
+                enumeration_protection: true,
+                enumeration_response_time: Duration::from_millis(100),
+                ..Config::default()
+            })
+            .build();
+
+        let started = std::time::Instant::now();
+        let request = tonic::Request::new(RequestMagicLinkRequest { username: "nobody@example.com".to_owned() });
+        let result = auth_service.request_magic_link(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(
+            auth_service.audit_log.recent(1)[0].event,
+            AuditEvent::OutcomeMasked {
+                rpc: "RequestMagicLink".to_owned(),
+                username: "nobody@example.com".to_owned(),
+                outcome: "UnknownUsername".to_owned(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn magic_link_should_be_refused_again_within_the_cooldown() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("jdoe@example.com".to_owned(), "654321".to_owned());
+
+        let mailer = RecordingMailer::default();
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .mailer(Box::new(mailer.clone()))
+            .build();
+
+        let request_magic_link = |username: &str| {
+            tonic::Request::new(RequestMagicLinkRequest { username: username.to_owned() })
+        };
+
+        let result = auth_service.request_magic_link(request_magic_link("jdoe@example.com")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        let result = auth_service.request_magic_link(request_magic_link("JDoe@example.com")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::TooManyRequests as i32);
+        assert_eq!(mailer.sent.lock().unwrap().len(), 1);
+
+        // Unknown usernames the same, so the refusal doesn't tell them apart.
+        let result = auth_service.request_magic_link(request_magic_link("nobody@example.com")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        let result = auth_service.request_magic_link(request_magic_link("nobody@example.com")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::TooManyRequests as i32);
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_not_reveal_taken_usernames_when_protected() {
+        let mut users_service = UsersImpl::default();
+        let _ = users_service.create_user("taken".to_owned(), "654321".to_owned());
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .config(Config {
+                honeypot_usernames: vec!["bait".to_owned()],
+                enumeration_protection: true,
+                enumeration_response_time: Duration::from_millis(100),
+                ..Config::default()
+            })
+            .build();
+
+        let sign_up = |username: &str| {
+            tonic::Request::new(SignUpRequest {
+                username: username.to_owned(),
+                password: "password".to_owned(),
+                invite_code: String::new(),
+                accepted_terms_version: String::new(),
+                challenge_response: String::new(),
+            })
+        };
+
+        let started = std::time::Instant::now();
+        let new = auth_service.sign_up(sign_up("new")).await.unwrap().into_inner();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(new.status_code, StatusCode::Success as i32);
+
+        for username in ["taken", "bait"] {
+            assert_eq!(auth_service.sign_up(sign_up(username)).await.unwrap().into_inner(), new);
+        }
+
+        // Still taken by whoever had it, and only the audit log knows.
+        assert!(auth_service.users().get_user_uuid("taken".to_owned(), "password".to_owned()).is_none());
+        let outcomes: Vec<AuditEvent> = auth_service.audit_log.recent(2).into_iter().map(|entry| entry.event).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                AuditEvent::OutcomeMasked {
+                    rpc: "SignUp".to_owned(),
+                    username: "bait".to_owned(),
+                    outcome: "HoneypotUsername".to_owned(),
+                },
+                AuditEvent::OutcomeMasked {
+                    rpc: "SignUp".to_owned(),
+                    username: "taken".to_owned(),
+                    outcome: "UsernameTaken".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn upgrades_and_username_changes_should_not_reveal_taken_usernames_when_protected() {
+        let mut users_service = UsersImpl::default();
+        let mut sessions_service = SessionsImpl::default();
+        let _ = users_service.create_user("taken".to_owned(), "654321".to_owned());
+        let user_uuid = users_service.link_external_user("google", "1234").unwrap();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .config(Config {
+                honeypot_usernames: vec!["bait".to_owned()],
+                enumeration_protection: true,
+                enumeration_response_time: Duration::from_millis(100),
+                ..Config::default()
+            })
+            .build();
+
+        for username in ["taken", "bait", "new"] {
+            let guest = auth_service.create_guest_session(tonic::Request::new(CreateGuestSessionRequest {})).await.unwrap().into_inner();
+            let request = tonic::Request::new(UpgradeGuestSessionRequest {
+                session_token: guest.session_token,
+                username: username.to_owned(),
+                password: "password".to_owned(),
+            });
+
+            let started = std::time::Instant::now();
+            let result = auth_service.upgrade_guest_session(request).await.unwrap().into_inner();
+            assert!(started.elapsed() >= Duration::from_millis(100));
+            assert_eq!(result.status_code, StatusCode::Success as i32, "{username}");
+            assert_eq!(result.user_uuid, guest.guest_uuid);
+            assert!(!result.session_token.is_empty());
+        }
+        assert!(auth_service.users().get_user_uuid("taken".to_owned(), "password".to_owned()).is_none());
+
+        for username in ["taken", "bait"] {
+            let request = tonic::Request::new(ChangeUsernameRequest {
+                session_token: session_token.clone(),
+                new_username: username.to_owned(),
+            });
+            let result = auth_service.change_username(request).await.unwrap().into_inner();
+            assert_eq!(result.status_code, StatusCode::Success as i32, "{username}");
+        }
+        assert_eq!(auth_service.users().get_username(&user_uuid).unwrap(), "google:1234");
+
+        let outcomes: Vec<(String, String)> = auth_service
+            .audit_log
+            .recent(4)
+            .into_iter()
+            .filter_map(|entry| match entry.event {
+                AuditEvent::OutcomeMasked { rpc, username, .. } => Some((rpc, username)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [("ChangeUsername", "bait"), ("ChangeUsername", "taken"), ("UpgradeGuestSession", "bait"), ("UpgradeGuestSession", "taken")]
+                .map(|(rpc, username)| (rpc.to_owned(), username.to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_reject_breached_password_when_enforced() {
+        let auth_service = AuthService::builder()
+            .breached_passwords(Box::new(test_bloom_filter()))
+            .config(Config {
+                breached_password_mode: BreachedPasswordMode::Enforce,
+                ..Config::default()
+            })
+            .build();
+
+        let request = tonic::Request::new(SignUpRequest {
+            username: "123456".to_owned(),
+            password: "password123".to_owned(),
+            invite_code: String::new(),
+            accepted_terms_version: String::new(),
+            challenge_response: String::new(),
+        });
+
+        let result = auth_service.sign_up(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::PasswordBreached as i32);
+        assert!(result.password_breached);
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_warn_about_breached_password() {
+        let auth_service = AuthService::builder()
+            .breached_passwords(Box::new(test_bloom_filter()))
+            .config(Config {
+                breached_password_mode: BreachedPasswordMode::Warn,
+                ..Config::default()
+            })
+            .build();
+
+        let request = tonic::Request::new(SignUpRequest {
+            username: "123456".to_owned(),
+            password: "password123".to_owned(),
+            invite_code: String::new(),
+            accepted_terms_version: String::new(),
+            challenge_response: String::new(),
+        });
+
+        let result = auth_service.sign_up(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert!(result.password_breached);
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_only_count_what_shadowed_policies_would_refuse() {
+        let sign_up = |password_rules_mode, breached_password_mode| {
+            let auth_service = AuthService::builder()
+                .breached_passwords(Box::new(test_bloom_filter()))
+                .config(Config {
+                    password_min_length: 12,
+                    password_rules_mode,
+                    breached_password_mode,
+                    ..Config::default()
+                })
+                .build();
+
+            async move {
+                let request = tonic::Request::new(SignUpRequest {
+                    username: "123456".to_owned(),
+                    password: "password123".to_owned(),
+                    invite_code: String::new(),
+                    accepted_terms_version: String::new(),
+                    challenge_response: String::new(),
+                });
+                let result = auth_service.sign_up(request).await.unwrap().into_inner();
+                (result, auth_service.shadow_refusals.snapshot())
+            }
+        };
+
+        let (result, shadow_refusals) = sign_up(PolicyMode::Enforce, BreachedPasswordMode::Off).await;
+        assert_eq!(result.status_code(), StatusCode::PasswordTooShort);
+        assert!(shadow_refusals.is_empty());
+
+        let (result, shadow_refusals) = sign_up(PolicyMode::Shadow, BreachedPasswordMode::Shadow).await;
+        assert_eq!(result.status_code(), StatusCode::Success);
+        assert!(!result.password_breached);
+        assert_eq!(shadow_refusals, BTreeMap::from([("breached_password", 1), ("password_rules", 1)]));
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_fail_if_account_suspended() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+        let user_uuid = users_service.find_user_uuid("123456").unwrap();
+        let _ = users_service.set_account_status(&user_uuid, AccountStatus::Suspended);
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .build();
+
+        let request = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+
+        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+
+        assert_eq!(result.status_code, StatusCode::AccountSuspended as i32);
+        assert!(result.session_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn validate_session_should_report_session_owner() {
+        let mut users_service = UsersImpl::default();
+        let mut sessions_service = SessionsImpl::default().with_sliding_expiry(Duration::from_secs(24 * 60 * 60));
+
+        let user_uuid = users_service.link_external_user("google", "1234").unwrap();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .build();
+
+        let validate = |session_token: &str| {
+            tonic::Request::new(ValidateSessionRequest { session_token: session_token.to_owned() })
+        };
+
+        let result = auth_service.validate_session(validate(&session_token)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.user_uuid, user_uuid);
+        assert!(result.expires_at > 0);
+
+        let result = auth_service.validate_session(validate("unknown")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::SessionNotFound as i32);
+        assert!(result.user_uuid.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sessions_should_carry_default_and_granted_scopes() {
+        let mut users_service = UsersImpl::default();
+        users_service.create_user("123456".to_owned(), "654321".to_owned()).unwrap();
+        let user_uuid = users_service.find_user_uuid("123456").unwrap();
+        users_service.set_scopes(&user_uuid, &["admin".to_owned(), "profile:read".to_owned()]).unwrap();
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .config(Config { default_scopes: vec!["profile:read".to_owned()], ..Config::default() })
+            .build();
+
+        let request = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        let request = tonic::Request::new(ValidateSessionRequest { session_token: result.session_token });
+        let result = auth_service.validate_session(request).await.unwrap().into_inner();
+        assert_eq!(result.scopes, vec!["profile:read".to_owned(), "admin".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn delegation_tokens_should_not_act_on_the_account() {
+        let mut users_service = UsersImpl::default();
+        users_service.create_user("123456".to_owned(), "654321".to_owned()).unwrap();
+        let user_uuid = users_service.find_user_uuid("123456").unwrap();
+
+        let mut sessions_service = SessionsImpl::default();
+        let session_token =
+            sessions_service.create_scoped_session(&user_uuid, SessionClass::Standard, vec!["profile:read".to_owned()]);
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .build();
+
+        let request = tonic::Request::new(CreateDelegationTokenRequest {
+            session_token: session_token.clone(),
+            scopes: vec!["profile:read".to_owned()],
+            ttl_secs: 24 * 60 * 60,
+        });
+        let result = auth_service.create_delegation_token(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        // Capped at AUTH_DELEGATION_TOKEN_TTL_SECS.
+        assert!(result.expires_at <= epoch_secs(SystemTime::now() + Config::default().delegation_token_ttl));
+
+        let validate = |session_token: &str| {
+            tonic::Request::new(ValidateSessionRequest { session_token: session_token.to_owned() })
+        };
+        let validated = auth_service.validate_session(validate(&result.delegation_token)).await.unwrap().into_inner();
+        assert_eq!(validated.user_uuid, user_uuid);
+        assert_eq!(validated.scopes, vec!["profile:read".to_owned()]);
+
+        let request = tonic::Request::new(ChangeUsernameRequest {
+            session_token: result.delegation_token.clone(),
+            new_username: "renamed".to_owned(),
+        });
+        let renamed = auth_service.change_username(request).await.unwrap().into_inner();
+        assert_eq!(renamed.status_code, StatusCode::Failure as i32);
+
+        // Signing out of the session revokes what was delegated from it.
+        let request = tonic::Request::new(SignOutRequest { session_token });
+        auth_service.sign_out(request).await.unwrap();
+        let validated = auth_service.validate_session(validate(&result.delegation_token)).await.unwrap().into_inner();
+        assert_eq!(validated.status_code, StatusCode::SessionNotFound as i32);
+    }
+
+    #[tokio::test]
+    async fn delegation_tokens_should_refuse_guests_and_other_tenants() {
+        let mut users_service = UsersImpl::default();
+        users_service.create_user("123456".to_owned(), "654321".to_owned()).unwrap();
+        let user_uuid = users_service.find_user_uuid("123456").unwrap();
+
+        let mut sessions_service = SessionsImpl::default();
+        let guest_token = sessions_service.create_scoped_session("guest-1", SessionClass::Guest, vec!["guest".to_owned()]);
+        let session_token =
+            sessions_service.create_scoped_session(&user_uuid, SessionClass::Standard, vec!["profile:read".to_owned()]);
+        sessions_service.pin_session(&guest_token, "acme");
+        sessions_service.pin_session(&session_token, "acme");
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .config(Config { tenants: vec!["acme".to_owned(), "globex".to_owned()], ..Config::default() })
+            .build();
+
+        let delegate = |session_token: &str, scope: &str, tenant: &str| {
+            let mut request = tonic::Request::new(CreateDelegationTokenRequest {
+                session_token: session_token.to_owned(),
+                scopes: vec![scope.to_owned()],
+                ttl_secs: 60,
+            });
+            request.metadata_mut().insert("x-tenant-id", tenant.parse().unwrap());
+            request
+        };
+
+        let result = auth_service.create_delegation_token(delegate(&guest_token, "guest", "acme")).await.unwrap().into_inner();
+        assert_eq!(result.status_code(), StatusCode::Failure);
+
+        let result = auth_service.create_delegation_token(delegate(&session_token, "profile:read", "globex")).await.unwrap().into_inner();
+        assert_eq!(result.status_code(), StatusCode::TenantMismatch);
+
+        let result = auth_service.create_delegation_token(delegate(&session_token, "profile:read", "acme")).await.unwrap().into_inner();
+        assert_eq!(result.status_code(), StatusCode::Success);
+        let delegated = auth_service.sessions().get_session(&result.delegation_token).unwrap();
+        assert_eq!(delegated.tenant.as_deref(), Some("acme"));
+    }
+
+    #[tokio::test]
+    async fn delegation_tokens_should_keep_the_binding_of_their_parent() {
+        let mut users_service = UsersImpl::default();
+        users_service.create_user("123456".to_owned(), "654321".to_owned()).unwrap();
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .config(Config {
+                session_binding: SessionBinding::DeviceKey,
+                default_scopes: vec!["profile:read".to_owned()],
+                ..Config::default()
+            })
+            .build();
+
+        let mut sign_in = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+        sign_in.metadata_mut().insert("x-device-key", "laptop".parse().unwrap());
+        let session_token = auth_service.sign_in(sign_in).await.unwrap().into_inner().session_token;
+
+        let delegate = |device_key: &str| {
+            let mut request = tonic::Request::new(CreateDelegationTokenRequest {
+                session_token: session_token.clone(),
+                scopes: vec!["profile:read".to_owned()],
+                ttl_secs: 60,
+            });
+            request.metadata_mut().insert("x-device-key", device_key.parse().unwrap());
+            request
+        };
+
+        let result = auth_service.create_delegation_token(delegate("phone")).await.unwrap().into_inner();
+        assert_eq!(result.status_code(), StatusCode::Failure);
+
+        let result = auth_service.create_delegation_token(delegate("laptop")).await.unwrap().into_inner();
+        assert_eq!(result.status_code(), StatusCode::Success);
+        let parent = auth_service.sessions().get_session(&session_token).unwrap();
+        let delegated = auth_service.sessions().get_session(&result.delegation_token).unwrap();
+        assert!(delegated.binding.is_some());
+        assert_eq!(delegated.binding, parent.binding);
+    }
+
+    #[tokio::test]
+    async fn account_rpcs_should_refuse_token_from_another_client() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .config(Config { session_binding: SessionBinding::DeviceKey, ..Config::default() })
+            .build();
+
+        let mut sign_in = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+        sign_in.metadata_mut().insert("x-device-key", "laptop".parse().unwrap());
+        let session_token = auth_service.sign_in(sign_in).await.unwrap().into_inner().session_token;
+
+        fn from_device<T>(mut request: tonic::Request<T>, device_key: &str) -> tonic::Request<T> {
+            request.metadata_mut().insert("x-device-key", device_key.parse().unwrap());
+            request
+        }
+
+        let export = || tonic::Request::new(ExportMyDataRequest { session_token: session_token.clone() });
+        let result = auth_service.export_my_data(from_device(export(), "phone")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+        let result = auth_service.export_my_data(export()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+
+        let delete = tonic::Request::new(DeleteAccountRequest { session_token: session_token.clone() });
+        let result = auth_service.delete_account(from_device(delete, "phone")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+
+        let result = auth_service.export_my_data(from_device(export(), "laptop")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        let user_uuid = auth_service.users().find_user_uuid("123456").unwrap();
+        assert_eq!(auth_service.users().get_account_status(&user_uuid), Some(AccountStatus::Active));
+        let mismatches = auth_service
+            .audit_log
+            .entries_for(&user_uuid)
+            .into_iter()
+            .filter(|entry| entry.event.kind() == "SessionBindingMismatch")
+            .count();
+        assert_eq!(mismatches, 3);
+    }
+
+    #[tokio::test]
+    async fn account_rpcs_should_refuse_token_of_another_tenant() {
+        let mut users_service = UsersImpl::default();
+        users_service.create_user("123456".to_owned(), "654321".to_owned()).unwrap();
+        let user_uuid = users_service.find_user_uuid("123456").unwrap();
+
+        let mut sessions_service = SessionsImpl::default();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
+        sessions_service.pin_session(&session_token, "acme");
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .config(Config { tenants: vec!["acme".to_owned(), "globex".to_owned()], ..Config::default() })
+            .build();
+
+        fn for_tenant<T>(mut request: tonic::Request<T>, tenant: &str) -> tonic::Request<T> {
+            request.metadata_mut().insert("x-tenant-id", tenant.parse().unwrap());
+            request
+        }
+
+        let change = || tonic::Request::new(ChangePasswordRequest {
+            session_token: session_token.clone(),
+            current_password: "654321".to_owned(),
+            new_password: "a new password".to_owned(),
+        });
+        let result = auth_service.change_password(for_tenant(change(), "globex")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::TenantMismatch as i32);
+
+        let export = tonic::Request::new(ExportMyDataRequest { session_token: session_token.clone() });
+        let result = auth_service.export_my_data(for_tenant(export, "globex")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::TenantMismatch as i32);
+
+        let result = auth_service.change_password(for_tenant(change(), "acme")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        let session = auth_service.sessions().get_session(&result.session_token).unwrap();
+        assert_eq!(session.tenant.as_deref(), Some("acme"));
+    }
+
+    #[tokio::test]
+    async fn upgraded_guest_should_keep_uuid() {
+        let auth_service = AuthService::builder()
+            .api_keys(ApiKeys::new(vec!["key-1".to_owned()]))
+            .build();
+
+        let guest = auth_service
+            .create_guest_session(tonic::Request::new(CreateGuestSessionRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(guest.status_code, StatusCode::Success as i32);
+
+        let mut request = tonic::Request::new(IntrospectTokenRequest { token: guest.session_token.clone() });
+        request.metadata_mut().insert("x-api-key", "key-1".parse().unwrap());
+        let result = auth_service.introspect_token(request).await.unwrap().into_inner();
+        assert!(result.active);
+        assert_eq!(result.scopes, vec!["guest".to_owned()]);
+
+        let upgrade = || tonic::Request::new(UpgradeGuestSessionRequest {
+            session_token: guest.session_token.clone(),
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+        });
+
+        let result = auth_service.upgrade_guest_session(upgrade()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.user_uuid, guest.guest_uuid);
+        assert_ne!(result.session_token, guest.session_token);
+
+        // The guest session is gone, so it can't be upgraded twice.
+        let result = auth_service.upgrade_guest_session(upgrade()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
 
-use crate::{sessions::SessionsOps, users::UsersOps};
+        let request = tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+        assert_eq!(result.user_uuid, guest.guest_uuid);
+    }
 
-use tonic::{Request, Response, Status};
+    #[tokio::test]
+    async fn change_username_should_keep_sessions_valid() {
+        let mut users_service = UsersImpl::default();
+        let mut sessions_service = SessionsImpl::default();
 
-use authentication::auth_server::Auth;
-use authentication::{
-    SignInRequest, SignInResponse, SignOutRequest, SignOutResponse, SignUpRequest, SignUpResponse,
-    StatusCode,
-};
+        let user_uuid = users_service.link_external_user("google", "1234").unwrap();
+        let _ = users_service.create_user("asmith".to_owned(), "654321".to_owned());
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
 
-pub mod authentication {
-    tonic::include_proto!("authentication");
-}
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .build();
 
-// Re-exporting
-pub use authentication::auth_server::AuthServer;
-pub use tonic::transport::Server;
+        let change = |new_username: &str| tonic::Request::new(ChangeUsernameRequest {
+            session_token: session_token.clone(),
+            new_username: new_username.to_owned(),
+        });
 
-pub struct AuthService {
-    users_service: Box<Mutex<dyn UsersOps + Send + Sync>>,
-    sessions_service: Box<Mutex<dyn SessionsOps + Send + Sync>>,
-}
+        let result = auth_service.change_username(change("ASmith")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::UsernameTaken as i32);
 
-impl AuthService {
-    pub fn new(
-        users_service: Box<Mutex<dyn UsersOps + Send + Sync>>,
-        sessions_service: Box<Mutex<dyn SessionsOps + Send + Sync>>,
-    ) -> Self {
-        Self {
-            users_service,
-            sessions_service,
+        let result = auth_service.change_username(change("jdoe")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        let validate = tonic::Request::new(ValidateSessionRequest { session_token: session_token.clone() });
+        let result = auth_service.validate_session(validate).await.unwrap().into_inner();
+        assert_eq!(result.user_uuid, user_uuid);
+
+        let audit_entries = auth_service.audit_log.entries_for(&user_uuid);
+        assert_eq!(audit_entries.len(), 1);
+        assert_eq!(
+            audit_entries[0].event,
+            AuditEvent::UsernameChanged {
+                user_uuid,
+                old_username: "google:1234".to_owned(),
+                new_username: "jdoe".to_owned(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn change_password_should_sign_out_every_session_of_the_user() {
+        let mut users_service = UsersImpl::default();
+        let mut sessions_service = SessionsImpl::default();
+
+        users_service.create_user("jdoe".to_owned(), "old password".to_owned()).unwrap();
+        let user_uuid = users_service.get_user_uuid("jdoe".to_owned(), "old password".to_owned()).unwrap();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
+        let stolen = sessions_service.create_session(&user_uuid, SessionClass::LongLived);
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .build();
+
+        let change = |current_password: &str| tonic::Request::new(ChangePasswordRequest {
+            session_token: session_token.clone(),
+            current_password: current_password.to_owned(),
+            new_password: "new password".to_owned(),
+        });
+        let validate = |session_token: &str| tonic::Request::new(ValidateSessionRequest { session_token: session_token.to_owned() });
+
+        let result = auth_service.change_password(change("wrong password")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+        let result = auth_service.validate_session(validate(&stolen)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        let result = auth_service.change_password(change("old password")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        for session_token in [&session_token, &stolen] {
+            let validated = auth_service.validate_session(validate(session_token)).await.unwrap().into_inner();
+            assert_eq!(validated.status_code, StatusCode::SessionNotFound as i32);
         }
+        let validated = auth_service.validate_session(validate(&result.session_token)).await.unwrap().into_inner();
+        assert_eq!(validated.user_uuid, user_uuid);
+
+        assert_eq!(auth_service.users().get_user_uuid("jdoe".to_owned(), "new password".to_owned()), Some(user_uuid.clone()));
+        assert_eq!(
+            auth_service.audit_log.entries_for(&user_uuid)[0].event,
+            AuditEvent::PasswordChanged { user_uuid, sessions_revoked: 2 }
+        );
     }
-}
 
-#[tonic::async_trait]
-impl Auth for AuthService {
-    async fn sign_in(
-        &self,
-        request: Request<SignInRequest>,
-    ) -> Result<Response<SignInResponse>, Status> {
-        println!("Got a request: {:?}", request);
+    #[tokio::test]
+    async fn user_metadata_should_round_trip() {
+        let mut users_service = UsersImpl::default();
+        let mut sessions_service = SessionsImpl::default();
 
-        let req = request.into_inner();
+        let user_uuid = users_service.link_external_user("google", "1234").unwrap();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
 
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .build();
 
-        // Get user's uuid from `users_service`. Panic if the lock is poisoned.
-        let reply: SignInResponse = 
-            if self.users_service.is_poisoned() 
-                    { panic!("user service lock seems broken!") }
-            else {
-               let uuid = match self.users_service.lock() {
-                                Ok(user) => user.get_user_uuid(req.username, req.password),
-                                Err(x) => None
-               };
-               uuid
+        let request = tonic::Request::new(SetUserMetadataRequest {
+            session_token: session_token.clone(),
+            key: "locale".to_owned(),
+            value: "en-GB".to_owned(),
+        });
+        let result = auth_service.set_user_metadata(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
 
-            }
-            .map(|maybe_uuid| {
+        let request = tonic::Request::new(GetUserMetadataRequest { session_token });
+        let result = auth_service.get_user_metadata(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+        assert_eq!(result.metadata.get("locale").map(String::as_str), Some("en-GB"));
 
-                let session = self
-                .sessions_service
-                .lock()
-                .expect("session service lock seems broken!")
-                .create_session(&maybe_uuid);
+        let request = tonic::Request::new(GetUserMetadataRequest { session_token: "unknown".to_owned() });
+        let result = auth_service.get_user_metadata(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Failure as i32);
+    }
 
-                (maybe_uuid, session)
-            })
-            .map_or_else(
-                || {
-                    SignInResponse {
-                        status_code: 0,
-                        user_uuid: "Not assigned".to_owned(),
-                        session_token: "Not created".to_owned(),
-                    }
-                }, 
-                |(maybe_uuid,session_id)| {
-                    SignInResponse {
-                        status_code: 1,
-                        user_uuid: maybe_uuid.to_owned(),
-                        session_token: session_id.to_owned(),   
-                    }
-                } 
-            );
+    #[tokio::test]
+    async fn user_metadata_should_be_cached_until_changed() {
+        use crate::response_cache::CACHE_CONTROL_HEADER;
 
+        let mut users_service = UsersImpl::default();
+        let mut sessions_service = SessionsImpl::default();
+
+        let user_uuid = users_service.link_external_user("google", "1234").unwrap();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
+        users_service.set_metadata(&user_uuid, "locale", "en-GB").unwrap();
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .build();
+        let get_locale = || async {
+            let request = tonic::Request::new(GetUserMetadataRequest { session_token: session_token.clone() });
+            let response = auth_service.get_user_metadata(request).await.unwrap();
+            let cache_control = response.metadata().get(CACHE_CONTROL_HEADER).unwrap().to_str().unwrap().to_owned();
+            (response.into_inner().metadata.get("locale").cloned(), cache_control)
+        };
 
-        // Match on `result`. If `result` is `None` return a SignInResponse with a the `status_code` set to `Failure`
-        // and `user_uuid`/`session_token` set to empty strings.
-        // let user_uuid: String = todo!();
+        assert_eq!(get_locale().await, (Some("en-GB".to_owned()), "private, max-age=60".to_owned()));
 
-        // let session_token: String = todo!(); // Create new session using `sessions_service`. Panic if the lock is poisoned.
+        // Behind the service's back: still the cached reply.
+        auth_service.users().set_metadata(&user_uuid, "locale", "fr-FR").unwrap();
+        assert_eq!(get_locale().await.0.as_deref(), Some("en-GB"));
 
-        // let reply: SignInResponse = todo!(); // Create a `SignInResponse` with `status_code` set to `Success`
+        let request = tonic::Request::new(SetUserMetadataRequest {
+            session_token: session_token.clone(),
+            key: "locale".to_owned(),
+            value: "de-DE".to_owned(),
+        });
+        auth_service.set_user_metadata(request).await.unwrap();
+        assert_eq!(get_locale().await.0.as_deref(), Some("de-DE"));
 
-        Ok(Response::new(reply))
+        let info = auth_service.get_server_info(tonic::Request::new(GetServerInfoRequest {})).await.unwrap();
+        assert_eq!(info.metadata().get(CACHE_CONTROL_HEADER).unwrap(), "public, max-age=60");
     }
 
-    async fn sign_up(
-        &self,
-        request: Request<SignUpRequest>,
-    ) -> Result<Response<SignUpResponse>, Status> {
-        println!("Got a request: {:?}", request);
+    #[tokio::test]
+    async fn export_my_data_should_include_sessions_and_audit_entries() {
+        let mut users_service = UsersImpl::default();
+        let mut sessions_service = SessionsImpl::default();
 
-        let req = request.into_inner();
+        let user_uuid = users_service.link_external_user("google", "1234").unwrap();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
+        let _ = sessions_service.create_session(&user_uuid, SessionClass::LongLived);
 
-        let result: SignUpResponse = self
-        .users_service
-        .lock()
-        .expect("user service lock seems broken!")
-        .create_user(req.username, req.password)
-        .map_or_else(
-            |_| {
-                SignUpResponse {
-                    status_code: StatusCode::Failure.into()
-                }
-            },
-            |v| {
-                SignUpResponse {
-                    status_code: StatusCode::Success.into()
-                }
-            }
-        );
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .build();
+
+        let request = tonic::Request::new(ChangeUsernameRequest {
+            session_token: session_token.clone(),
+            new_username: "jdoe".to_owned(),
+        });
+        let _ = auth_service.change_username(request).await.unwrap();
 
-        Ok(Response::new(result))
-        
+        let request = tonic::Request::new(ExportMyDataRequest { session_token });
+        let result = auth_service.export_my_data(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
 
-       
+        let data = result.data.unwrap();
+        assert_eq!(data.user_uuid, user_uuid);
+        assert_eq!(data.username, "jdoe");
+        assert_eq!(data.account_status, "Active");
+        assert_eq!(data.sessions.len(), 2);
+        assert_eq!(data.audit_entries.len(), 1);
+        assert_eq!(data.audit_entries[0].kind, "UsernameChanged");
     }
 
-    async fn sign_out(
-        &self,
-        request: Request<SignOutRequest>,
-    ) -> Result<Response<SignOutResponse>, Status> {
-        println!("Got a request: {:?}", request);
+    #[tokio::test]
+    async fn sensitive_requests_should_need_fresh_nonce_when_replay_protected() {
+        let mut users_service = UsersImpl::default();
 
-        let req = request.into_inner();
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+        let user_uuid = users_service.find_user_uuid("123456").unwrap();
 
-        // TODO: Delete session using `sessions_service`.
-        
-        // Create `SignOutResponse` with `status_code` set to `Success`
+        let mut sessions_service = SessionsImpl::default();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
 
-        self
-        .sessions_service
-        .lock()
-        .expect("user service lock seems broken, while signing out!")
-        .delete_session(&req.session_token)
-        ;
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .config(Config { replay_protection: PolicyMode::Enforce, ..Config::default() })
+            .build();
 
-        let reply: SignOutResponse = SignOutResponse {
-            status_code: StatusCode::Success.into()
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let export = |nonce: Option<&str>, sent_at: u64| {
+            let mut request = tonic::Request::new(ExportMyDataRequest { session_token: session_token.clone() });
+            if let Some(nonce) = nonce {
+                request.metadata_mut().insert("x-request-nonce", nonce.parse().unwrap());
+                request.metadata_mut().insert("x-request-timestamp", sent_at.to_string().parse().unwrap());
+            }
+            request
         };
 
-        Ok(Response::new(reply))
+        let result = auth_service.export_my_data(export(Some("first"), now)).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        // A timestamp past what the clock holds is refused like any other.
+        for (nonce, sent_at) in [(Some("first"), now), (None, now), (Some("stale"), now - 3_600), (Some("last"), u64::MAX)] {
+            let result = auth_service.export_my_data(export(nonce, sent_at)).await.unwrap().into_inner();
+            assert_eq!(result.status_code, StatusCode::RequestReplayed as i32);
+        }
     }
 
-}
+    #[tokio::test]
+    async fn deleted_account_should_be_restored_by_sign_in_within_grace_period() {
+        let mut users_service = UsersImpl::default();
 
-#[cfg(test)]
-mod tests {
-    use crate::{users::UsersImpl, sessions::SessionsImpl};
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+        let user_uuid = users_service.find_user_uuid("123456").unwrap();
 
-    use super::*;
+        let mut sessions_service = SessionsImpl::default();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
 
-    #[tokio::test]
-    async fn sign_in_should_fail_if_user_not_found() {
-        let users_service = Box::new(Mutex::new(UsersImpl::default()));
-        let sessions_service = Box::new(Mutex::new(SessionsImpl::default()));
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .build();
 
-        let auth_service = AuthService::new(users_service, sessions_service);
+        let request = tonic::Request::new(DeleteAccountRequest { session_token: session_token.clone() });
+        let result = auth_service.delete_account(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        // Signed out
+        let request = tonic::Request::new(ValidateSessionRequest { session_token });
+        let result = auth_service.validate_session(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::SessionNotFound as i32);
+
+        // Still in the grace period, so nothing to purge yet.
+        assert_eq!(auth_service.purge_deleted_accounts(), 0);
 
         let request = tonic::Request::new(SignInRequest {
             username: "123456".to_owned(),
             password: "654321".to_owned(),
+            remember_me: false,
         });
-
         let result = auth_service.sign_in(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+    }
+
+    #[tokio::test]
+    async fn deleted_account_should_be_purged_after_grace_period() {
+        let mut users_service = UsersImpl::default();
+
+        let user_uuid = users_service.link_external_user("google", "1234").unwrap();
+        let _ = users_service.request_deletion(&user_uuid);
 
-        assert_eq!(result.status_code, StatusCode::Failure.into());
-        assert_eq!(result.user_uuid.is_empty(), true);
-        assert_eq!(result.session_token.is_empty(), true);
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .config(Config {
+                account_deletion_grace: Duration::ZERO,
+                ..Config::default()
+            })
+            .build();
+
+        assert_eq!(auth_service.purge_deleted_accounts(), 1);
+        assert!(auth_service.users_service.lock().unwrap().get_account_status(&user_uuid).is_none());
     }
 
     #[tokio::test]
-    async fn sign_in_should_fail_if_incorrect_password() {
+    async fn a_grace_period_longer_than_the_epoch_should_purge_nothing() {
         let mut users_service = UsersImpl::default();
 
         let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+        let user_uuid = users_service.find_user_uuid("123456").unwrap();
+        let _ = users_service.request_deletion(&user_uuid);
 
-        let users_service = Box::new(Mutex::new(users_service));
-        let sessions_service = Box::new(Mutex::new(SessionsImpl::default()));
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .config(Config {
+                account_deletion_grace: Duration::MAX,
+                ..Config::default()
+            })
+            .build();
 
-        let auth_service = AuthService::new(users_service, sessions_service);
+        assert_eq!(auth_service.purge_deleted_accounts(), 0);
+        assert!(auth_service.users_service.lock().unwrap().get_account_status(&user_uuid).is_some());
 
+        // And a sign in within it restores the account.
         let request = tonic::Request::new(SignInRequest {
             username: "123456".to_owned(),
-            password: "wrong password".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
         });
-
         let result = auth_service.sign_in(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+    }
 
-        assert_eq!(result.status_code, StatusCode::Failure.into());
-        assert_eq!(result.user_uuid.is_empty(), true);
-        assert_eq!(result.session_token.is_empty(), true);
+    #[tokio::test]
+    async fn unverified_accounts_should_be_purged_after_their_ttl() {
+        use crate::events::tests::RecordingEventSink;
+
+        let event_sink = RecordingEventSink::default();
+        let auth_service = |unverified_account_ttl: Option<Duration>| {
+            let mut users_service = UsersImpl::default();
+            for username in ["alice", "bob"] {
+                let _ = users_service.create_user(username.to_owned(), "password".to_owned());
+            }
+            let alice = users_service.find_user_uuid("alice").unwrap();
+            let _ = users_service.set_account_status(&alice, AccountStatus::PendingVerification);
+
+            AuthService::builder()
+                .users(users_service)
+                .event_sink(Box::new(event_sink.clone()))
+                .config(Config { unverified_account_ttl, ..Config::default() })
+                .build()
+        };
+
+        // Kept for good unless configured, and until the TTL is over.
+        assert_eq!(auth_service(None).purge_unverified_accounts(), 0);
+        assert_eq!(auth_service(Some(Duration::from_secs(60))).purge_unverified_accounts(), 0);
+
+        let auth_service = auth_service(Some(Duration::ZERO));
+        let alice = auth_service.users().find_user_uuid("alice").unwrap();
+        assert_eq!(auth_service.purge_unverified_accounts(), 1);
+        assert!(auth_service.users().find_user_uuid("alice").is_none());
+        assert!(auth_service.users().find_user_uuid("bob").is_some());
+        assert_eq!(
+            *event_sink.events.lock().unwrap(),
+            [Event::UnverifiedAccountPurged { user_uuid: alice.clone(), username: "alice".to_owned() }]
+        );
+        assert_eq!(auth_service.audit_log.entries_for(&alice)[0].event, AuditEvent::AccountPurged { user_uuid: alice });
+
+        // The username is free again.
+        let request = tonic::Request::new(SignUpRequest {
+            username: "alice".to_owned(),
+            password: "654321".to_owned(),
+            ..SignUpRequest::default()
+        });
+        let result = auth_service.sign_up(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
     }
 
     #[tokio::test]
-    async fn sign_in_should_succeed() {
-        let mut users_service = UsersImpl::default();
+    async fn sign_up_should_require_invite_when_invite_only() {
+        let auth_service = AuthService::builder()
+            .config(Config {
+                invite_only: true,
+                ..Config::default()
+            })
+            .build();
 
-        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+        let invite = auth_service
+            .invites_service
+            .lock()
+            .unwrap()
+            .create_invite(Duration::from_secs(60));
 
-        let users_service = Box::new(Mutex::new(users_service));
-        let sessions_service = Box::new(Mutex::new(SessionsImpl::default()));
+        let sign_up = |username: &str| tonic::Request::new(SignUpRequest {
+            username: username.to_owned(),
+            password: "654321".to_owned(),
+            invite_code: invite.code.clone(),
+            accepted_terms_version: String::new(),
+            challenge_response: String::new(),
+        });
+
+        let request = tonic::Request::new(SignUpRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            invite_code: String::new(),
+            accepted_terms_version: String::new(),
+            challenge_response: String::new(),
+        });
+        let result = auth_service.sign_up(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::InvalidInvite as i32);
 
-        let auth_service = AuthService::new(users_service, sessions_service);
+        let result = auth_service.sign_up(sign_up("123456")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
 
-        let request = tonic::Request::new(SignInRequest {
+        // Single use
+        let result = auth_service.sign_up(sign_up("654321")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::InvalidInvite as i32);
+    }
+
+    #[test]
+    fn should_compare_terms_versions() {
+        assert!(is_older_version("1.9", "1.10"));
+        assert!(is_older_version("", "1"));
+        assert!(!is_older_version("2", "1.10"));
+        assert!(is_older_version("2023-draft", "2024"));
+    }
+
+    #[tokio::test]
+    async fn sign_in_should_require_current_terms() {
+        let auth_service = AuthService::builder()
+            .config(Config {
+                terms_version: Some("2".to_owned()),
+                require_current_terms: true,
+                ..Config::default()
+            })
+            .build();
+
+        let request = tonic::Request::new(SignUpRequest {
             username: "123456".to_owned(),
             password: "654321".to_owned(),
+            invite_code: String::new(),
+            accepted_terms_version: "1".to_owned(),
+            challenge_response: String::new(),
         });
+        let _ = auth_service.sign_up(request).await.unwrap();
 
-        let result = auth_service.sign_in(request).await.unwrap().into_inner();
+        let sign_in = || tonic::Request::new(SignInRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            remember_me: false,
+        });
+
+        let result = auth_service.sign_in(sign_in()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::TermsUpdateRequired as i32);
+        assert!(result.session_token.is_empty());
+
+        let request = tonic::Request::new(AcceptTermsRequest {
+            username: "123456".to_owned(),
+            password: "654321".to_owned(),
+            version: "2".to_owned(),
+        });
+        let result = auth_service.accept_terms(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
 
-        assert_eq!(result.status_code, StatusCode::Success.into());
-        assert_eq!(result.user_uuid.is_empty(), false);
-        assert_eq!(result.session_token.is_empty(), false);
+        let result = auth_service.sign_in(sign_in()).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
     }
 
     #[tokio::test]
-    async fn sign_up_should_fail_if_username_exists() {
+    async fn external_token_should_require_current_terms() {
+        let auth_service = AuthService::builder()
+            .external_providers(test_providers())
+            .config(Config {
+                terms_version: Some("2".to_owned()),
+                require_current_terms: true,
+                ..Config::default()
+            })
+            .build();
+
+        let exchange = |accepted_terms_version: &str| tonic::Request::new(ExchangeExternalTokenRequest {
+            provider: "google".to_owned(),
+            id_token: test_id_token("1234", "https://accounts.google.com", u64::MAX / 2),
+            accepted_terms_version: accepted_terms_version.to_owned(),
+        });
+
+        let result = auth_service.exchange_external_token(exchange("")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::TermsUpdateRequired as i32);
+        assert!(result.session_token.is_empty());
+
+        let result = auth_service.exchange_external_token(exchange("2")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+    }
+
+    #[tokio::test]
+    async fn magic_link_should_require_current_terms() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("jdoe@example.com".to_owned(), "654321".to_owned());
+
+        let mailer = RecordingMailer::default();
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .mailer(Box::new(mailer.clone()))
+            .config(Config {
+                terms_version: Some("2".to_owned()),
+                require_current_terms: true,
+                // Asks for two links in a row.
+                magic_link_cooldown: Duration::ZERO,
+                ..Config::default()
+            })
+            .build();
+
+        let magic_link_token = || async {
+            let request = tonic::Request::new(RequestMagicLinkRequest { username: "jdoe@example.com".to_owned() });
+            auth_service.request_magic_link(request).await.unwrap();
+
+            let (_, body) = mailer.sent.lock().unwrap().pop().expect("should send a magic link");
+            body.split_once("token=")
+                .and_then(|(_, rest)| rest.split_whitespace().next())
+                .unwrap()
+                .to_owned()
+        };
+
+        let request = tonic::Request::new(ConsumeMagicLinkRequest {
+            magic_link_token: magic_link_token().await,
+            accepted_terms_version: String::new(),
+        });
+        let result = auth_service.consume_magic_link(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::TermsUpdateRequired as i32);
+        assert!(result.session_token.is_empty());
+
+        let request = tonic::Request::new(ConsumeMagicLinkRequest {
+            magic_link_token: magic_link_token().await,
+            accepted_terms_version: "2".to_owned(),
+        });
+        let result = auth_service.consume_magic_link(request).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+    }
+
+    #[tokio::test]
+    async fn accept_terms_should_be_throttled_like_sign_in() {
         let mut users_service = UsersImpl::default();
 
         let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
 
-        let users_service = Box::new(Mutex::new(users_service));
-        let sessions_service = Box::new(Mutex::new(SessionsImpl::default()));
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .config(Config {
+                throttle_ip_tarpit_after: 10,
+                throttle_ip_block_after: 3,
+                ..Config::default()
+            })
+            .build();
 
-        let auth_service = AuthService::new(users_service, sessions_service);
+        // The throttle goes by the address a request came from, so it needs a connection.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let connect_info = tonic::transport::server::Connected::connect_info(&stream);
 
-        let request = tonic::Request::new(SignUpRequest {
+        let accept_terms = |password: &str| {
+            let mut request = tonic::Request::new(AcceptTermsRequest {
+                username: "123456".to_owned(),
+                password: password.to_owned(),
+                version: "2".to_owned(),
+            });
+            request.extensions_mut().insert(connect_info.clone());
+            request
+        };
+
+        for _ in 0..3 {
+            let result = auth_service.accept_terms(accept_terms("wrong password")).await.unwrap().into_inner();
+            assert_eq!(result.status_code, StatusCode::Failure as i32);
+        }
+
+        // Blocked, so even the right password is no longer tried.
+        let result = auth_service.accept_terms(accept_terms("654321")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::SourceBlocked as i32);
+    }
+
+    #[tokio::test]
+    async fn change_password_should_be_throttled_like_sign_in() {
+        let mut users_service = UsersImpl::default();
+        let mut sessions_service = SessionsImpl::default();
+
+        users_service.create_user("jdoe".to_owned(), "old password".to_owned()).unwrap();
+        let user_uuid = users_service.find_user_uuid("jdoe").unwrap();
+        let session_token = sessions_service.create_session(&user_uuid, SessionClass::Standard);
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .config(Config {
+                throttle_ip_tarpit_after: 10,
+                throttle_ip_block_after: 3,
+                ..Config::default()
+            })
+            .build();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let connect_info = tonic::transport::server::Connected::connect_info(&stream);
+
+        let change = |current_password: &str| {
+            let mut request = tonic::Request::new(ChangePasswordRequest {
+                session_token: session_token.clone(),
+                current_password: current_password.to_owned(),
+                new_password: "new password".to_owned(),
+            });
+            request.extensions_mut().insert(connect_info.clone());
+            request
+        };
+
+        for _ in 0..3 {
+            let result = auth_service.change_password(change("wrong password")).await.unwrap().into_inner();
+            assert_eq!(result.status_code, StatusCode::Failure as i32);
+        }
+
+        // Blocked, so even the right password is no longer tried.
+        let result = auth_service.change_password(change("old password")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::SourceBlocked as i32);
+    }
+
+    #[tokio::test]
+    async fn sign_in_from_new_device_should_publish_event() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let event_sink = RecordingEventSink::default();
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .event_sink(Box::new(event_sink.clone()))
+            .build();
+
+        let sign_in = |user_agent: &str| {
+            let mut request = tonic::Request::new(SignInRequest {
+                username: "123456".to_owned(),
+                password: "654321".to_owned(),
+                remember_me: false,
+            });
+            request.metadata_mut().insert("user-agent", user_agent.parse().unwrap());
+            request
+        };
+
+        for user_agent in ["laptop", "laptop", "phone"] {
+            let result = auth_service.sign_in(sign_in(user_agent)).await.unwrap().into_inner();
+            assert_eq!(result.status_code, StatusCode::Success as i32);
+        }
+
+        let events = event_sink.events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], Event::NewDeviceSignIn { user_agent, .. } if user_agent == "phone"));
+    }
+
+    #[tokio::test]
+    async fn validate_session_should_refuse_token_from_another_client() {
+        let mut users_service = UsersImpl::default();
+
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .config(Config { session_binding: SessionBinding::DeviceKey, ..Config::default() })
+            .build();
+
+        let mut sign_in = tonic::Request::new(SignInRequest {
             username: "123456".to_owned(),
             password: "654321".to_owned(),
+            remember_me: false,
         });
+        sign_in.metadata_mut().insert("x-device-key", "laptop".parse().unwrap());
+        let session_token = auth_service.sign_in(sign_in).await.unwrap().into_inner().session_token;
 
-        let result = auth_service.sign_up(request).await.unwrap();
+        let validate = |device_key: Option<&str>| {
+            let mut request = tonic::Request::new(ValidateSessionRequest { session_token: session_token.clone() });
+            if let Some(device_key) = device_key {
+                request.metadata_mut().insert("x-device-key", device_key.parse().unwrap());
+            }
+            request
+        };
 
-        assert_eq!(result.into_inner().status_code, StatusCode::Failure.into());
+        let result = auth_service.validate_session(validate(Some("laptop"))).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
+
+        for device_key in [Some("phone"), None] {
+            let result = auth_service.validate_session(validate(device_key)).await.unwrap().into_inner();
+            assert_eq!(result.status_code, StatusCode::Failure as i32);
+        }
+
+        let user_uuid = auth_service.users().find_user_uuid("123456").unwrap();
+        let audit_entries = auth_service.audit_log.entries_for(&user_uuid);
+        assert_eq!(audit_entries.len(), 2);
+        assert_eq!(audit_entries[0].event.kind(), "SessionBindingMismatch");
     }
 
     #[tokio::test]
-    async fn sign_up_should_succeed() {
-        let users_service = Box::new(Mutex::new(UsersImpl::default()));
-        let sessions_service = Box::new(Mutex::new(SessionsImpl::default()));
+    async fn validate_session_should_refuse_token_of_another_tenant() {
+        use jsonwebtoken::{decode, Algorithm as JwtAlgorithm, DecodingKey, Validation};
+        use serde_json::{json, Value};
 
-        let auth_service = AuthService::new(users_service, sessions_service);
+        use crate::tokens::JwtTokens;
 
-        let request = tonic::Request::new(SignUpRequest {
+        // Tries to move every token over to another tenant.
+        struct Spoofer;
+
+        #[tonic::async_trait]
+        impl ClaimsProvider for Spoofer {
+            async fn claims(&self, _user_uuid: &str, _class: SessionClass) -> Result<Claims, String> {
+                Ok(json!({ "tenant": "globex" }).as_object().unwrap().clone())
+            }
+        }
+
+        let mut users_service = UsersImpl::default();
+        let _ = users_service.create_user("123456".to_owned(), "654321".to_owned());
+
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(SessionsImpl::default().with_token_generator(Box::new(JwtTokens::new("auth".to_owned(), b"secret"))))
+            .claims_provider(Box::new(Spoofer))
+            .config(Config { tenants: vec!["acme".to_owned(), "globex".to_owned()], ..Config::default() })
+            .build();
+
+        fn for_tenant<T>(mut request: tonic::Request<T>, tenant: Option<&str>) -> tonic::Request<T> {
+            if let Some(tenant) = tenant {
+                request.metadata_mut().insert("x-tenant-id", tenant.parse().unwrap());
+            }
+            request
+        }
+
+        let sign_in = SignInRequest { username: "123456".to_owned(), password: "654321".to_owned(), remember_me: false };
+        let session_token = auth_service.sign_in(for_tenant(tonic::Request::new(sign_in), Some("acme"))).await.unwrap().into_inner().session_token;
+
+        let claims = decode::<Value>(&session_token, &DecodingKey::from_secret(b"secret"), &Validation::new(JwtAlgorithm::HS256))
+            .unwrap()
+            .claims;
+        assert_eq!(claims["tenant"], "acme");
+
+        let validate = |tenant| {
+            for_tenant(tonic::Request::new(ValidateSessionRequest { session_token: session_token.clone() }), tenant)
+        };
+
+        let result = auth_service.validate_session(validate(Some("acme"))).await.unwrap().into_inner();
+        assert_eq!(result.status_code(), StatusCode::Success);
+        assert_eq!(result.tenant, "acme");
+
+        let result = auth_service.validate_session(validate(Some("globex"))).await.unwrap().into_inner();
+        assert_eq!(result.status_code(), StatusCode::TenantMismatch);
+
+        // Naming no tenant, or one that doesn't exist, gets nowhere either.
+        for tenant in [None, Some("initech"), Some("ACME")] {
+            let status = auth_service.validate_session(validate(tenant)).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+
+        let user_uuid = auth_service.users().find_user_uuid("123456").unwrap();
+        let audit_entries = auth_service.audit_log.entries_for(&user_uuid);
+        assert_eq!(audit_entries.len(), 1);
+        assert_eq!(audit_entries[0].event.kind(), "TenantMismatch");
+        assert_eq!(audit_entries[0].event.details()["tenant"], "globex");
+    }
+
+    #[tokio::test]
+    async fn sign_up_should_require_solved_challenge() {
+        let auth_service = AuthService::builder()
+            .challenge_verifier(Box::new(ProofOfWork::new(8)))
+            .config(Config {
+                challenge_mode: ChallengeMode::Always,
+                ..Config::default()
+            })
+            .build();
+
+        let sign_up = |challenge_response: &str| tonic::Request::new(SignUpRequest {
             username: "123456".to_owned(),
             password: "654321".to_owned(),
+            invite_code: String::new(),
+            accepted_terms_version: String::new(),
+            challenge_response: challenge_response.to_owned(),
         });
 
-        let result = auth_service.sign_up(request).await.unwrap();
+        let result = auth_service.sign_up(sign_up("")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::ChallengeRequired as i32);
+        let challenge = result.challenge.unwrap();
+        assert_eq!(challenge.kind, "pow");
 
-        assert_eq!(result.into_inner().status_code, StatusCode::Success.into());
+        let result = auth_service.sign_up(sign_up("bogus:0")).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::ChallengeRequired as i32);
+
+        let result = auth_service.sign_up(sign_up(&solve(&challenge))).await.unwrap().into_inner();
+        assert_eq!(result.status_code, StatusCode::Success as i32);
     }
 
-    #[tokio::test]
-    async fn sign_out_should_succeed() {
-        let users_service = Box::new(Mutex::new(UsersImpl::default()));
-        let sessions_service = Box::new(Mutex::new(SessionsImpl::default()));
+    #[test]
+    fn sign_in_from_far_away_should_be_flagged_and_need_mfa_when_enabled() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let geo_lookup = FixedGeoLookup(HashMap::from([(ip("192.0.2.1"), berlin()), (ip("198.51.100.1"), sydney())]));
+        let event_sink = RecordingEventSink::default();
 
-        let auth_service = AuthService::new(users_service, sessions_service);
+        let auth_service = AuthService::builder()
+            .geo_lookup(Box::new(geo_lookup))
+            .event_sink(Box::new(event_sink.clone()))
+            .config(Config { mfa_on_anomaly: true, ..Config::default() })
+            .build();
 
-        let request = tonic::Request::new(SignOutRequest {
-            session_token: "".to_owned()
-        });
+        let device = |ip: &str| Device { ip: Some(ip.parse().unwrap()), user_agent: String::new() };
 
-        let result = auth_service.sign_out(request).await.unwrap();
+        assert_eq!(auth_service.check_sign_in_location("123456", &device("192.0.2.1")), Ok(()));
+
+        // A new country, moments after signing in from Berlin.
+        let result = auth_service.check_sign_in_location("123456", &device("198.51.100.1"));
+        assert_eq!(result, Err(StatusCode::MfaRequired));
+        assert_eq!(auth_service.audit_log.entries_for("123456").len(), 2);
+        assert!(matches!(
+            &event_sink.events.lock().unwrap()[0],
+            Event::SuspiciousSignIn { country, .. } if country == "AU"
+        ));
 
-        assert_eq!(result.into_inner().status_code, StatusCode::Success.into());
+        // Only flagged from now on; still unusual, as the refused sign in wasn't remembered.
+        auth_service.flags.set(Flag::MfaOnAnomaly, false);
+        assert_eq!(auth_service.check_sign_in_location("123456", &device("198.51.100.1")), Ok(()));
+        assert_eq!(event_sink.events.lock().unwrap().len(), 4);
     }
 }
@@ -0,0 +1,262 @@
+use std::{
+    env,
+    f64::consts::LN_2,
+    fs::File,
+    io::{BufRead, BufReader},
+    str::FromStr,
+};
+
+use sha1::{Digest, Sha1};
+
+// Whether a password is known to have leaked in a data breach.
+#[tonic::async_trait]
+pub trait BreachedPasswords {
+    async fn is_breached(&self, password: &str) -> Result<bool, String>;
+}
+
+// How sign-up treats a breached password.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BreachedPasswordMode {
+    #[default]
+    Off,
+    // Accept the password, but let the client know so it can nudge the user.
+    Warn,
+    // Only count the passwords `Enforce` would refuse (see `PolicyMode::Shadow`); the client is
+    // not told.
+    Shadow,
+    Enforce,
+}
+
+impl FromStr for BreachedPasswordMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "warn" => Ok(Self::Warn),
+            "shadow" => Ok(Self::Shadow),
+            "enforce" => Ok(Self::Enforce),
+            _ => Err(format!("Error::UnknownBreachedPasswordMode: {s}")),
+        }
+    }
+}
+
+// The haveibeenpwned range API. Only the first 5 hex digits of the password's SHA-1 leave this
+// process (k-anonymity); the matching is done locally on the returned suffixes.
+pub struct HibpRangeApi {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HibpRangeApi {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl BreachedPasswords for HibpRangeApi {
+    async fn is_breached(&self, password: &str) -> Result<bool, String> {
+        let hash = sha1_hex(password);
+        let (prefix, suffix) = hash.split_at(5);
+
+        let body = self
+            .client
+            .get(format!("{}/{}", self.url, prefix))
+            .header("Add-Padding", "true")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Failed to query {}.\n{e:?}", self.url))?
+            .text()
+            .await
+            .map_err(|e| format!("Failed to read response of {}.\n{e:?}", self.url))?;
+
+        // Lines look like "<35 hex digits suffix>:<count>". Padding entries have a count of 0.
+        Ok(body.lines().any(|line| {
+            line.split_once(':').is_some_and(|(candidate, count)| {
+                candidate.eq_ignore_ascii_case(suffix) && count.trim() != "0"
+            })
+        }))
+    }
+}
+
+// An offline set of breached password hashes. May report false positives (at the configured
+// rate), never false negatives.
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u64,
+}
+
+impl BloomFilter {
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let n = expected_items.max(1) as f64;
+        let num_bits = (-(n * false_positive_rate.ln()) / (LN_2 * LN_2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * LN_2).round().max(1.0) as u64;
+
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    // Builds a filter from a file of SHA-1 hashes in hex, one per line, optionally followed by
+    // ":<count>" like the downloadable haveibeenpwned lists.
+    pub fn from_hash_list(path: &str, false_positive_rate: f64) -> Result<Self, String> {
+        let open = || File::open(path).map(BufReader::new).map_err(|e| format!("Failed to open {path}.\n{e:?}"));
+
+        let expected_items = open()?.lines().count();
+        let mut bloom_filter = Self::new(expected_items, false_positive_rate);
+
+        for line in open()?.lines() {
+            let line = line.map_err(|e| format!("Failed to read {path}.\n{e:?}"))?;
+            let hash = line.split(':').next().unwrap_or_default().trim();
+
+            if let Some(hash) = parse_sha1_hex(hash) {
+                bloom_filter.insert_hash(&hash);
+            }
+        }
+
+        Ok(bloom_filter)
+    }
+
+    fn insert_hash(&mut self, hash: &[u8; 20]) {
+        for position in self.positions(hash).collect::<Vec<_>>() {
+            self.bits[(position / 64) as usize] |= 1 << (position % 64);
+        }
+    }
+
+    fn contains_hash(&self, hash: &[u8; 20]) -> bool {
+        self.positions(hash)
+            .all(|position| self.bits[(position / 64) as usize] & (1 << (position % 64)) != 0)
+    }
+
+    // SHA-1 output is already uniformly distributed, so two slices of it are enough to derive
+    // all the bit positions (double hashing).
+    fn positions(&self, hash: &[u8; 20]) -> impl Iterator<Item = u64> + '_ {
+        let h1 = u64::from_be_bytes(hash[0..8].try_into().unwrap());
+        let h2 = u64::from_be_bytes(hash[8..16].try_into().unwrap()) | 1;
+
+        (0..self.num_hashes).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits)
+    }
+}
+
+#[tonic::async_trait]
+impl BreachedPasswords for BloomFilter {
+    async fn is_breached(&self, password: &str) -> Result<bool, String> {
+        Ok(self.contains_hash(&Sha1::digest(password.as_bytes()).into()))
+    }
+}
+
+// Asks haveibeenpwned first and falls back to the offline filter when it can't be reached.
+#[derive(Default)]
+pub struct BreachedPasswordChecker {
+    online: Option<HibpRangeApi>,
+    offline: Option<BloomFilter>,
+}
+
+impl BreachedPasswordChecker {
+    pub fn new(online: Option<HibpRangeApi>, offline: Option<BloomFilter>) -> Self {
+        Self { online, offline }
+    }
+
+    // AUTH_HIBP_URL defaults to the public range API; set it to "off" to stay offline.
+    // AUTH_BREACHED_PASSWORDS_FILE points to a hash list loaded into the offline filter.
+    pub fn from_env() -> Result<Self, String> {
+        let online = match env::var("AUTH_HIBP_URL").as_deref() {
+            Ok("off") => None,
+            Ok(url) => Some(HibpRangeApi::new(url.to_owned())),
+            Err(_) => Some(HibpRangeApi::new("https://api.pwnedpasswords.com/range".to_owned())),
+        };
+
+        let offline = env::var("AUTH_BREACHED_PASSWORDS_FILE")
+            .ok()
+            .map(|path| BloomFilter::from_hash_list(&path, 0.001))
+            .transpose()?;
+
+        Ok(Self::new(online, offline))
+    }
+}
+
+#[tonic::async_trait]
+impl BreachedPasswords for BreachedPasswordChecker {
+    async fn is_breached(&self, password: &str) -> Result<bool, String> {
+        let online_result = match &self.online {
+            Some(online) => online.is_breached(password).await,
+            None => Err(String::from("Error::NoOnlineCheck")),
+        };
+
+        match (online_result, &self.offline) {
+            (Ok(breached), _) => Ok(breached),
+            (Err(_), Some(offline)) => offline.is_breached(password).await,
+            (Err(e), None) => Err(e),
+        }
+    }
+}
+
+fn sha1_hex(password: &str) -> String {
+    Sha1::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect()
+}
+
+fn parse_sha1_hex(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() { return None };
+
+    let mut hash = [0u8; 20];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+
+    Some(hash)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn test_bloom_filter() -> BloomFilter {
+        let mut bloom_filter = BloomFilter::new(100, 0.001);
+        bloom_filter.insert_hash(&Sha1::digest(b"password123").into());
+        bloom_filter
+    }
+
+    #[tokio::test]
+    async fn bloom_filter_should_contain_inserted_passwords_only() {
+        let bloom_filter = test_bloom_filter();
+
+        assert_eq!(bloom_filter.is_breached("password123").await, Ok(true));
+        assert_eq!(bloom_filter.is_breached("correct horse battery staple").await, Ok(false));
+    }
+
+    #[test]
+    fn should_parse_hash_list_entries() {
+        // SHA-1 of "password"
+        let hash = parse_sha1_hex("5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8").unwrap();
+
+        assert_eq!(hash, <[u8; 20]>::from(Sha1::digest(b"password")));
+        assert!(parse_sha1_hex("5BAA61E4").is_none());
+    }
+
+    #[tokio::test]
+    async fn checker_should_fall_back_to_offline_filter() {
+        // Nothing listens on the discard port, so the online check fails straight away.
+        let checker = BreachedPasswordChecker::new(
+            Some(HibpRangeApi::new("http://127.0.0.1:9".to_owned())),
+            Some(test_bloom_filter()),
+        );
+
+        assert_eq!(checker.is_breached("password123").await, Ok(true));
+    }
+
+    #[tokio::test]
+    async fn checker_should_fail_without_any_source() {
+        assert!(BreachedPasswordChecker::default().is_breached("password123").await.is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    env,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use auth_ids::Ids;
+
+use crate::auth::authentication::Challenge;
+
+// Tells humans from scripts before letting them sign up.
+#[tonic::async_trait]
+pub trait ChallengeVerifier {
+    // What the client has to solve, sent back with `CHALLENGE_REQUIRED`.
+    fn issue(&self) -> Challenge;
+    async fn verify(&self, response: &str, ip: Option<IpAddr>) -> Result<bool, String>;
+}
+
+// When sign_up asks for a challenge.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChallengeMode {
+    Off,
+    // Only for sources that look abusive, i.e. the ones `SourceThrottle` is slowing down.
+    #[default]
+    Suspicious,
+    Always,
+}
+
+impl FromStr for ChallengeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "suspicious" => Ok(Self::Suspicious),
+            "always" => Ok(Self::Always),
+            _ => Err(format!("Error::UnknownChallengeMode: {s}")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+// hCaptcha and Cloudflare Turnstile share the same "siteverify" API: the widget hands the client
+// a token, which we pass on together with our secret.
+pub struct CaptchaVerifier {
+    client: reqwest::Client,
+    kind: String,
+    verify_url: String,
+    site_key: String,
+    secret: String,
+}
+
+impl CaptchaVerifier {
+    pub fn hcaptcha(site_key: String, secret: String) -> Self {
+        Self::new("hcaptcha", "https://api.hcaptcha.com/siteverify", site_key, secret)
+    }
+
+    pub fn turnstile(site_key: String, secret: String) -> Self {
+        Self::new("turnstile", "https://challenges.cloudflare.com/turnstile/v0/siteverify", site_key, secret)
+    }
+
+    fn new(kind: &str, verify_url: &str, site_key: String, secret: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            kind: kind.to_owned(),
+            verify_url: verify_url.to_owned(),
+            site_key,
+            secret,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ChallengeVerifier for CaptchaVerifier {
+    fn issue(&self) -> Challenge {
+        Challenge {
+            kind: self.kind.clone(),
+            site_key: self.site_key.clone(),
+            ..Challenge::default()
+        }
+    }
+
+    async fn verify(&self, response: &str, ip: Option<IpAddr>) -> Result<bool, String> {
+        let mut form = vec![("secret", self.secret.clone()), ("response", response.to_owned())];
+        if let Some(ip) = ip {
+            form.push(("remoteip", ip.to_string()));
+        }
+
+        let body = self
+            .client
+            .post(&self.verify_url)
+            .form(&form)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Failed to query {}.\n{e:?}", self.verify_url))?
+            .text()
+            .await
+            .map_err(|e| format!("Failed to read response of {}.\n{e:?}", self.verify_url))?;
+
+        serde_json::from_str::<SiteVerifyResponse>(&body)
+            .map(|response| response.success)
+            .map_err(|e| format!("Failed to parse response of {}.\n{e:?}", self.verify_url))
+    }
+}
+
+// Needs no third party: the client has to find a `solution` such that the SHA-1 of
+// "<nonce>:<solution>" starts with `difficulty` zero bits, and answers "<nonce>:<solution>".
+pub struct ProofOfWork {
+    difficulty: u32,
+    ttl: Duration,
+    // Issued nonces, each good for one answer.
+    nonces: Mutex<HashMap<String, Instant>>,
+    ids: Ids,
+}
+
+impl ProofOfWork {
+    pub fn new(difficulty: u32) -> Self {
+        Self {
+            difficulty,
+            ttl: Duration::from_secs(10 * 60),
+            nonces: Mutex::new(HashMap::new()),
+            ids: Ids::default(),
+        }
+    }
+
+    pub fn with_id_generator(mut self, ids: Ids) -> Self {
+        self.ids = ids;
+        self
+    }
+}
+
+pub fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in bytes {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+#[tonic::async_trait]
+impl ChallengeVerifier for ProofOfWork {
+    fn issue(&self) -> Challenge {
+        let now = Instant::now();
+        let nonce = self.ids.new_id().to_string();
+
+        let mut nonces = self.nonces.lock().unwrap_or_else(PoisonError::into_inner);
+        nonces.retain(|_, expires_at| *expires_at > now);
+        nonces.insert(nonce.clone(), now + self.ttl);
+
+        Challenge {
+            kind: "pow".to_owned(),
+            nonce,
+            difficulty: self.difficulty,
+            ..Challenge::default()
+        }
+    }
+
+    async fn verify(&self, response: &str, _ip: Option<IpAddr>) -> Result<bool, String> {
+        let Some((nonce, _)) = response.split_once(':') else { return Ok(false) };
+
+        let issued = self
+            .nonces
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(nonce)
+            .is_some_and(|expires_at| expires_at > Instant::now());
+
+        Ok(issued && leading_zero_bits(&Sha1::digest(response.as_bytes())) >= self.difficulty)
+    }
+}
+
+// AUTH_CHALLENGE_PROVIDER picks hcaptcha or turnstile (both need AUTH_CHALLENGE_SITE_KEY and
+// AUTH_CHALLENGE_SECRET); anything else falls back to a proof of work of AUTH_POW_DIFFICULTY bits.
+pub fn challenge_verifier_from_env(ids: &Ids) -> Result<Box<dyn ChallengeVerifier + Send + Sync>, String> {
+    let keys = || -> Result<(String, String), String> {
+        let site_key = env::var("AUTH_CHALLENGE_SITE_KEY").map_err(|_| String::from("Error::MissingChallengeSiteKey"))?;
+        let secret = env::var("AUTH_CHALLENGE_SECRET").map_err(|_| String::from("Error::MissingChallengeSecret"))?;
+        Ok((site_key, secret))
+    };
+
+    match env::var("AUTH_CHALLENGE_PROVIDER").as_deref() {
+        Ok("hcaptcha") => keys().map(|(site_key, secret)| Box::new(CaptchaVerifier::hcaptcha(site_key, secret)) as _),
+        Ok("turnstile") => keys().map(|(site_key, secret)| Box::new(CaptchaVerifier::turnstile(site_key, secret)) as _),
+        _ => {
+            let difficulty = env::var("AUTH_POW_DIFFICULTY").ok().and_then(|d| d.parse().ok()).unwrap_or(20);
+            Ok(Box::new(ProofOfWork::new(difficulty).with_id_generator(ids.clone())))
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn solve(challenge: &Challenge) -> String {
+        (0u64..)
+            .map(|solution| format!("{}:{solution}", challenge.nonce))
+            .find(|response| leading_zero_bits(&Sha1::digest(response.as_bytes())) >= challenge.difficulty)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_verify_proof_of_work_once() {
+        let proof_of_work = ProofOfWork::new(8);
+
+        let challenge = proof_of_work.issue();
+        let response = solve(&challenge);
+
+        assert!(!proof_of_work.verify("unknown:0", None).await.unwrap());
+        assert!(proof_of_work.verify(&response, None).await.unwrap());
+        assert!(!proof_of_work.verify(&response, None).await.unwrap());
+    }
+
+    #[test]
+    fn should_count_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0, 0b0001_0000, 0]), 11);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+    }
+}
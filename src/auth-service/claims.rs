@@ -0,0 +1,18 @@
+use serde_json::{Map, Value};
+
+use crate::sessions::SessionClass;
+
+// Extra claims of a session token, by name.
+pub type Claims = Map<String, Value>;
+
+// The claims every JWT session token has (see `JwtTokens`); a provider can't replace them.
+pub const RESERVED_CLAIMS: [&str; 7] = ["iss", "sub", "iat", "exp", "jti", "class", "scope"];
+
+// Adds deployment specific claims (plan tier, org id, ...) to the session tokens issued at sign in,
+// e.g. looked up in another service; see `AuthServiceBuilder::claims_provider`. Only tokens that
+// can carry claims, JWTs, get them. Sign-ins wait for the answer, so it had better be quick; when
+// it fails, the token is issued without them.
+#[tonic::async_trait]
+pub trait ClaimsProvider {
+    async fn claims(&self, user_uuid: &str, class: SessionClass) -> Result<Claims, String>;
+}
@@ -0,0 +1,33 @@
+use std::time::{Duration, SystemTime};
+
+use tracing::warn;
+
+// Whether a time that came from `source` (another replica, an identity provider) is ahead of ours
+// by more than `leeway`, with a warning when it is: clocks that far apart are worth fixing before
+// they start failing sign-ins. A time behind ours tells nothing, as what carried it may have been
+// on its way for a while.
+pub fn is_ahead(source: &str, their_time: SystemTime, now: SystemTime, leeway: Duration) -> bool {
+    match their_time.duration_since(now) {
+        Ok(ahead) if ahead > leeway => {
+            warn!("the clock of {} is {}s ahead of ours, more than the {}s allowed", source, ahead.as_secs(), leeway.as_secs());
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_tell_clocks_ahead_by_more_than_the_leeway() {
+        let now = SystemTime::now();
+        let leeway = Duration::from_secs(60);
+
+        assert!(!is_ahead("peer", now + Duration::from_secs(30), now, leeway));
+        assert!(is_ahead("peer", now + Duration::from_secs(90), now, leeway));
+        assert!(!is_ahead("peer", now - Duration::from_secs(3600), now, leeway));
+        assert!(is_ahead("peer", now + Duration::from_secs(1), now, Duration::ZERO));
+    }
+}
@@ -0,0 +1,633 @@
+use std::{env, net::SocketAddr, str::FromStr, time::Duration};
+
+use auth_ids::IdScheme;
+use tonic::codec::CompressionEncoding;
+
+use crate::{
+    admin_policy::RoleGrant, breached::BreachedPasswordMode, error::Error, challenge::ChallengeMode, hashers::{Argon2idHasher, HashAlgorithm}, ip_rules::Cidr, jobs::JobInterval, load_shedding::RpcTimeout,
+    policy::PolicyMode, prehash::PasswordPrehash, quotas::KeyQuota, sessions::{EvictionPolicy, SessionBinding, UnknownSessionReply}, users::{check_scopes, PasswordPolicy, MAX_USERNAME_LEN},
+};
+
+// Runtime settings of the auth-service. Everything comes from AUTH_* environment variables, so
+// the same image can be configured from docker-compose.
+#[derive(Clone, Debug)]
+pub struct Config {
+    // The magic link sent to users is this prefix followed by the token.
+    pub magic_link_url: String,
+    pub magic_link_ttl: Duration,
+    // How long after asking for a magic link a username can't ask for another one.
+    pub magic_link_cooldown: Duration,
+    // Passkeys (see `RelyingParty`): off unless the relying party id, a domain, is set. The origin
+    // defaults to https on that domain. Challenges can be answered once, within the TTL.
+    pub webauthn_rp_id: Option<String>,
+    pub webauthn_origin: Option<String>,
+    pub webauthn_challenge_ttl: Duration,
+    // Password policy. Each rule can be rolled out in shadow mode first (see `PolicyMode`).
+    pub breached_password_mode: BreachedPasswordMode,
+    pub password_min_length: usize,
+    pub password_rules_mode: PolicyMode,
+    // How new passwords are hashed ("pbkdf2-sha256", "pbkdf2-sha512", "argon2id", "bcrypt" or
+    // "scrypt", see `password_hasher`); passwords hashed otherwise, or with fewer rounds or less
+    // memory, are hashed again when their user signs in. Rounds are argon2id's passes (2 unless
+    // set), bcrypt's cost (12) and scrypt's log2 of N (17).
+    pub password_hash_algorithm: HashAlgorithm,
+    pub password_hash_rounds: u32,
+    pub password_hash_memory_kib: u32,
+    // When set, clients send a key derived from the password rather than the password itself (see
+    // `PasswordPrehash`): AUTH_PASSWORD_PREHASH_ITERATIONS, 0 for off, and
+    // AUTH_PASSWORD_PREHASH_SALT.
+    pub password_prehash: Option<PasswordPrehash>,
+    // What the ids of new users look like: "uuid-v4", "uuid-v7" or "ulid" (see `IdScheme`). A users
+    // journal keeps the scheme it was started with.
+    pub user_id_scheme: IdScheme,
+    // How far the clocks of the replicas, and of the identity providers, may be from ours: sessions
+    // hold this long past their expiry, and ID tokens too, or issued this long from now.
+    pub clock_skew_leeway: Duration,
+    // Sessions
+    pub session_ttl: Duration,
+    // Sessions created with "remember me"
+    pub long_lived_session_ttl: Duration,
+    // When set, the TTLs above are idle timeouts that every `ValidateSession` resets, and this is
+    // how long a session may live at most.
+    pub session_absolute_ttl: Option<Duration>,
+    // How long a delegation token lives at most (see `CreateDelegationToken`), and when the
+    // request doesn't say.
+    pub delegation_token_ttl: Duration,
+    // How long a deleted account can still be restored by signing in, before it is purged.
+    pub account_deletion_grace: Duration,
+    // How long an account taken down by an operator (SoftDeleteUser) can still be restored
+    // (RestoreUser), before it is purged.
+    pub soft_delete_retention: Duration,
+    // How long an account may wait for verification before it is purged and its username freed;
+    // accounts pending verification are kept for good without.
+    pub unverified_account_ttl: Option<Duration>,
+    // How often the background maintenance jobs (purging, ...) run (see `Scheduler`). Jobs with an
+    // interval of their own are listed as `<job>=<seconds>`; every run is delayed by up to
+    // `job_jitter`.
+    pub maintenance_interval: Duration,
+    pub job_intervals: Vec<JobInterval>,
+    pub job_jitter: Duration,
+    // Only people holding an invite (see `CreateInvite`) may sign up. This, `require_current_terms`,
+    // `maintenance_mode`, `mfa_on_anomaly` and `approval_required` are where the `FeatureFlags`
+    // start from.
+    pub invite_only: bool,
+    pub invite_ttl: Duration,
+    // New users can't sign in until an operator approves them (see `ApproveUser`), e.g. for a
+    // closed beta.
+    pub approval_required: bool,
+    // The current terms of service, and whether sign_in refuses users who accepted an older one.
+    pub terms_version: Option<String>,
+    pub require_current_terms: bool,
+    // Scopes every signed in user's sessions get, on top of their own (see `SetUserScopes`).
+    pub default_scopes: Vec<String>,
+    // Failed sign-ins counted per address and per /24 (see `SourceThrottle`) within the window.
+    // Past the tarpit limits sign_in answers after a delay, past the block limits it refuses
+    // the source for the cooldown. In shadow mode, sources are only counted.
+    pub throttle_window: Duration,
+    pub throttle_ip_tarpit_after: u32,
+    pub throttle_ip_block_after: u32,
+    pub throttle_subnet_tarpit_after: u32,
+    pub throttle_subnet_block_after: u32,
+    pub throttle_tarpit_delay: Duration,
+    pub throttle_block_cooldown: Duration,
+    pub throttle_mode: PolicyMode,
+    // Sign-ins are placed with this MaxMind City database, and flagged in the audit log and the
+    // events when from a new country or faster than `impossible_travel_kmh` from the last one
+    // (see `SignInLocations`). With `mfa_on_anomaly`, flagged sign-ins are also refused until
+    // finished through a magic link.
+    pub geoip_db: Option<String>,
+    pub impossible_travel_kmh: f64,
+    pub mfa_on_anomaly: bool,
+    // Where audit entries are exported to (see `AuditExporter`), besides the container's output:
+    // JSONL files, moved aside past `audit_file_max_bytes` with the `audit_file_keep` most recent
+    // kept, syslog over UDP, and an endpoint taking Elasticsearch `_bulk` requests.
+    pub audit_file: Option<String>,
+    pub audit_file_max_bytes: u64,
+    pub audit_file_keep: usize,
+    pub audit_syslog_addr: Option<SocketAddr>,
+    pub audit_http_url: Option<String>,
+    // How long audit entries are kept, and how many at most, before the `compact-logs` job forgets
+    // them; for good, and as many as there are, without.
+    pub audit_retention: Option<Duration>,
+    pub audit_max_entries: Option<usize>,
+    // Allow/deny rules for source addresses (see `AccessLists`), re-read by the maintenance task.
+    pub ip_rules_file: Option<String>,
+    // Event sourcing mode: users and sessions are journaled to `users.jsonl` and `sessions.jsonl`
+    // in this directory, and rebuilt from there on start (see `ReplayProjection`).
+    pub event_log_dir: Option<String>,
+    // How long the entries dropped from the journals (see `FileJournal::truncate_after`) are kept,
+    // and how many bytes of them at most, before the `compact-logs` job removes them.
+    pub event_log_retention: Option<Duration>,
+    pub event_log_max_bytes: Option<u64>,
+    // Shared by the replicas of one store: whoever holds the lease in this file is the leader, and
+    // the only one running the jobs that must run once (see `Scheduler::with_leader_lease`).
+    pub leader_lease_file: Option<String>,
+    // The other replicas, e.g. `http://auth-1:50051`, which get every change to the sessions made
+    // here (see `SessionReplicator`).
+    pub replication_peers: Vec<String>,
+    // A read-only replica refuses every RPC that would change the stores, pointing the caller at
+    // the primary (see `ReadOnly`).
+    pub read_only: bool,
+    pub primary_url: Option<String>,
+    // Load balancers and gateways whose `x-forwarded-for`/`x-real-ip` are believed (see
+    // `PeerInfo`). Without any, clients are identified by the connection's address.
+    pub trusted_proxies: Vec<Cidr>,
+    // Whether responses say how long the handler and the stores took (see `ServerTimingService`).
+    // Off by default: the timings tell callers something about what was looked up.
+    pub server_timing: bool,
+    // Usernames kept for the system's own accounts, and a file of words no username may contain
+    // (see `ReservedUsernames`).
+    pub reserved_usernames: Vec<String>,
+    pub username_deny_file: Option<String>,
+    // Usernames nobody may sign in as, kept as bait for credential stuffing (see
+    // `HoneypotUsernames`); more are set at runtime by SetHoneypotUsername.
+    pub honeypot_usernames: Vec<String>,
+    // For deployments where whether somebody has an account is itself sensitive: sign_up and
+    // RequestMagicLink answer the same, and take at least `enumeration_response_time`, whether the
+    // username is somebody's or not. What really happened goes to the audit log only.
+    pub enumeration_protection: bool,
+    pub enumeration_response_time: Duration,
+    // Which admin RPCs each role may call, beyond admins calling all of them (see `AllowList`); or
+    // the rules of a file instead (see `ExpressionPolicy`). Who has which role is up to
+    // AUTH_ADMIN_PRINCIPALS.
+    pub admin_role_grants: Vec<RoleGrant>,
+    pub admin_policy_file: Option<String>,
+    // When sign_up asks for a CAPTCHA or proof of work (see `ChallengeVerifier`).
+    pub challenge_mode: ChallengeMode,
+    // How responses are compressed for clients that accept it. Compressed requests are always
+    // accepted.
+    pub grpc_compression: GrpcCompression,
+    // Listeners: TCP on each of these addresses and/or a unix socket at this path, for sidecars on
+    // the same host.
+    pub listen_addrs: Vec<SocketAddr>,
+    pub unix_socket: Option<String>,
+    // When set, the admin service is served on these addresses only, instead of next to `Auth`.
+    pub admin_listen_addrs: Vec<SocketAddr>,
+    // Serves `GET /readyz` and `POST /introspect` over plain HTTP (see `Readiness`).
+    pub health_listen_addr: Option<SocketAddr>,
+    // Serves CPU profiles and what the runtime has going, to admins, over plain HTTP (see
+    // `DebugEndpoint`). Best kept on an address only operators reach; refused with admin TLS.
+    pub debug_listen_addr: Option<SocketAddr>,
+    // Lets the next version of the service bind the same addresses while this one still serves
+    // (SO_REUSEPORT; see `bind_tcp`), for upgrades without a load balancer in front. Sockets passed
+    // in by systemd (see `activated_listeners`) are used in any case.
+    pub reuse_port: bool,
+    // How long the calls in flight get to finish once the service is told to stop (SIGTERM).
+    pub drain_timeout: Duration,
+    // PEM files for TLS on the admin listeners; with a client CA, admins need a certificate it
+    // signed (mTLS).
+    pub admin_tls_cert: Option<String>,
+    pub admin_tls_key: Option<String>,
+    pub admin_tls_client_ca: Option<String>,
+    // Also serve the services under the unversioned "authentication" package, for clients that
+    // have not moved to "authentication.v1" yet.
+    pub serve_legacy_package: bool,
+    // Requests per minute of each caller presenting an `x-api-key`; 0 for no quota.
+    pub api_key_quota_per_minute: u32,
+    // Keys with a quota of their own, as `<key>=<requests per minute>`.
+    pub api_key_quotas: Vec<KeyQuota>,
+    // Below this percentage of its quota left, a key's responses carry a warning and when the
+    // quota is whole again (see `auth_client::quota`), for callers to slow down before being
+    // refused; 0 for no warning.
+    pub api_key_quota_warning_percent: u32,
+    // Start in maintenance mode (see `SetMaintenanceMode`), and what clients are told to wait.
+    pub maintenance_mode: bool,
+    pub maintenance_retry_after: Duration,
+    // Load shedding (see `LoadShedder`): at most this many requests in flight, 0 for no cap, and
+    // how long an RPC may take, 0 for no limit. RPCs with a budget of their own are listed as
+    // `<Method>=<milliseconds>`.
+    pub max_in_flight_requests: usize,
+    pub rpc_timeout: Duration,
+    pub rpc_timeouts: Vec<RpcTimeout>,
+    // The longest username, password and token a request may carry, in bytes (see `FieldLimits`).
+    pub max_username_len: usize,
+    pub max_password_len: usize,
+    pub max_token_len: usize,
+    // Calls to the users and sessions stores taking longer than this are logged (see
+    // `StoreMetrics`); 0 logs none.
+    pub slow_store_operation: Duration,
+    // How many users and sessions the stores may hold: past the soft limits it is logged, at the
+    // hard ones new users and sessions are refused (see `AuthService::check_capacity`). 0 for none.
+    pub users_soft_limit: usize,
+    pub users_hard_limit: usize,
+    pub sessions_soft_limit: usize,
+    pub sessions_hard_limit: usize,
+    // The in-memory sessions store holds at most this many sessions, 0 for no cap; past it, the
+    // session `session_eviction` picks ("lru" or "expiry") is signed out for the new one.
+    pub max_sessions: usize,
+    pub session_eviction: EvictionPolicy,
+    // What sessions are bound to at sign in: "off", "client-cert" or "device-key" (see
+    // `SessionBinding`).
+    pub session_binding: SessionBinding,
+    // What SignOut and ValidateSession answer for tokens of no session: "not-found" or "success"
+    // (see `UnknownSessionReply`).
+    pub unknown_session_reply: UnknownSessionReply,
+    // The tenants of a multi-tenant deployment, named by the gateway in `x-tenant-id`; sessions are
+    // pinned to theirs (see `request_tenant`). Empty for a single tenant.
+    pub tenants: Vec<String>,
+    // While the stores can't persist (see `StoreOutage`), how long sessions are still validated
+    // from memory; 0 for as long as it lasts.
+    pub max_staleness: Duration,
+    // Whether ChangeUsername, ChangePassword, DeleteAccount, ExportMyData and CreateDelegationToken
+    // need a fresh nonce (see `RequestNonce`), and how far the timestamp sent with it may be from
+    // now.
+    pub replay_protection: PolicyMode,
+    pub replay_window: Duration,
+    // The share of the calls whose whole request and reply are kept for debugging (see
+    // `DebugCapture`), 0 to 1, and how many are kept; admins can change the rate while running.
+    pub debug_capture_rate: f64,
+    pub debug_capture_size: usize,
+    // How long GetServerInfo and GetUserMetadata replies may be kept, here and by the caches in
+    // front of the service (see `ResponseCache`); 0 for not at all.
+    pub response_cache_ttl: Duration,
+    // Where the daily usage stats go (see `UsageStats`): appended to a file, POSTed to a URL, or
+    // both; nothing is counted without either. Counts are reported in multiples of
+    // `usage_stats_bucket`, after noise of scale 1/`usage_stats_epsilon` (0 for none).
+    pub usage_stats_file: Option<String>,
+    pub usage_stats_url: Option<String>,
+    pub usage_stats_bucket: u64,
+    pub usage_stats_epsilon: f64,
+    // Where a line is written for each call (see `AccessLogService`): a file, or "-" for stdout;
+    // none without it. Only `access_log_rate` of the successful calls (0 to 1) are; failed ones
+    // always are.
+    pub access_log: Option<String>,
+    pub access_log_rate: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GrpcCompression {
+    #[default]
+    Off,
+    Gzip,
+}
+
+impl GrpcCompression {
+    pub fn encoding(self) -> Option<CompressionEncoding> {
+        match self {
+            Self::Off => None,
+            Self::Gzip => Some(CompressionEncoding::Gzip),
+        }
+    }
+}
+
+impl FromStr for GrpcCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "gzip" => Ok(Self::Gzip),
+            // tonic only has a zstd codec from 0.10 on.
+            _ => Err(format!("Error::UnsupportedCompression: {s}")),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            magic_link_url: "http://localhost:8080/magic-link?token=".to_owned(),
+            magic_link_ttl: Duration::from_secs(15 * 60),
+            magic_link_cooldown: Duration::from_secs(60),
+            webauthn_rp_id: None,
+            webauthn_origin: None,
+            webauthn_challenge_ttl: Duration::from_secs(5 * 60),
+            breached_password_mode: BreachedPasswordMode::Off,
+            password_min_length: 1,
+            password_rules_mode: PolicyMode::Enforce,
+            password_hash_algorithm: HashAlgorithm::default(),
+            password_hash_rounds: PasswordPolicy::default().rounds,
+            password_hash_memory_kib: Argon2idHasher::default().memory_kib,
+            password_prehash: None,
+            user_id_scheme: IdScheme::default(),
+            clock_skew_leeway: Duration::from_secs(60),
+            session_ttl: Duration::from_secs(60 * 60),
+            long_lived_session_ttl: Duration::from_secs(30 * 24 * 60 * 60),
+            session_absolute_ttl: None,
+            delegation_token_ttl: Duration::from_secs(5 * 60),
+            account_deletion_grace: Duration::from_secs(30 * 24 * 60 * 60),
+            soft_delete_retention: Duration::from_secs(90 * 24 * 60 * 60),
+            unverified_account_ttl: None,
+            maintenance_interval: Duration::from_secs(60),
+            job_intervals: Vec::new(),
+            job_jitter: Duration::from_secs(5),
+            invite_only: false,
+            approval_required: false,
+            invite_ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            terms_version: None,
+            require_current_terms: false,
+            default_scopes: Vec::new(),
+            throttle_window: Duration::from_secs(10 * 60),
+            throttle_ip_tarpit_after: 5,
+            throttle_ip_block_after: 20,
+            throttle_subnet_tarpit_after: 20,
+            throttle_subnet_block_after: 100,
+            throttle_tarpit_delay: Duration::from_secs(2),
+            throttle_block_cooldown: Duration::from_secs(15 * 60),
+            throttle_mode: PolicyMode::Enforce,
+            geoip_db: None,
+            // About what an airliner does.
+            impossible_travel_kmh: 1000.0,
+            mfa_on_anomaly: false,
+            audit_file: None,
+            audit_file_max_bytes: 100 * 1024 * 1024,
+            audit_file_keep: 5,
+            audit_syslog_addr: None,
+            audit_http_url: None,
+            audit_retention: None,
+            audit_max_entries: None,
+            ip_rules_file: None,
+            event_log_dir: None,
+            event_log_retention: None,
+            event_log_max_bytes: None,
+            leader_lease_file: None,
+            replication_peers: Vec::new(),
+            read_only: false,
+            primary_url: None,
+            trusted_proxies: Vec::new(),
+            server_timing: false,
+            reserved_usernames: ["admin", "administrator", "root", "support", "system"].map(String::from).to_vec(),
+            username_deny_file: None,
+            honeypot_usernames: Vec::new(),
+            enumeration_protection: false,
+            enumeration_response_time: Duration::from_millis(500),
+            admin_role_grants: Vec::new(),
+            admin_policy_file: None,
+            challenge_mode: ChallengeMode::Suspicious,
+            grpc_compression: GrpcCompression::Off,
+            // [::0] listens on all the configured network interfaces, which Docker needs.
+            // See: https://stackoverflow.com/questions/39525820/docker-port-forwarding-not-working
+            // Port 50051 is the recommended gRPC port.
+            listen_addrs: vec![SocketAddr::from(([0u16; 8], 50051))],
+            unix_socket: None,
+            admin_listen_addrs: Vec::new(),
+            health_listen_addr: None,
+            debug_listen_addr: None,
+            reuse_port: false,
+            drain_timeout: Duration::from_secs(30),
+            admin_tls_cert: None,
+            admin_tls_key: None,
+            admin_tls_client_ca: None,
+            serve_legacy_package: true,
+            api_key_quota_per_minute: 0,
+            api_key_quotas: Vec::new(),
+            api_key_quota_warning_percent: 20,
+            maintenance_mode: false,
+            maintenance_retry_after: Duration::from_secs(60),
+            max_in_flight_requests: 1024,
+            rpc_timeout: Duration::from_secs(30),
+            rpc_timeouts: Vec::new(),
+            max_username_len: MAX_USERNAME_LEN,
+            max_password_len: 1024,
+            // External id tokens (JWTs) are the longest.
+            max_token_len: 8 * 1024,
+            slow_store_operation: Duration::from_millis(100),
+            users_soft_limit: 0,
+            users_hard_limit: 0,
+            sessions_soft_limit: 0,
+            sessions_hard_limit: 0,
+            max_sessions: 0,
+            session_eviction: EvictionPolicy::default(),
+            session_binding: SessionBinding::default(),
+            unknown_session_reply: UnknownSessionReply::default(),
+            tenants: Vec::new(),
+            max_staleness: Duration::from_secs(5 * 60),
+            replay_protection: PolicyMode::Off,
+            replay_window: Duration::from_secs(5 * 60),
+            debug_capture_rate: 0.0,
+            debug_capture_size: 200,
+            response_cache_ttl: Duration::from_secs(60),
+            usage_stats_file: None,
+            usage_stats_url: None,
+            usage_stats_bucket: 10,
+            usage_stats_epsilon: 0.0,
+            access_log: None,
+            access_log_rate: 1.0,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Error> {
+        let default = Self::default();
+
+        let default_scopes = env_list("AUTH_DEFAULT_SCOPES", default.default_scopes)?;
+        check_scopes(&default_scopes).map_err(|e| Error::Config(format!("{e}: AUTH_DEFAULT_SCOPES")))?;
+
+        let password_hash_algorithm = env_or("AUTH_PASSWORD_HASH_ALGORITHM", default.password_hash_algorithm)?;
+        let password_hash_rounds = env_or("AUTH_PASSWORD_HASH_ROUNDS", password_hash_algorithm.default_rounds())?;
+        if !password_hash_algorithm.takes_rounds(password_hash_rounds) {
+            return Err(Error::Config(format!("Error::InvalidConfig: AUTH_PASSWORD_HASH_ROUNDS={password_hash_rounds}")));
+        }
+
+        // Clients would all have to derive keys again, so the salt has to be set rather than
+        // defaulted to something that may change.
+        let password_prehash = match env_or("AUTH_PASSWORD_PREHASH_ITERATIONS", 0)? {
+            0 => None,
+            iterations => match env::var("AUTH_PASSWORD_PREHASH_SALT") {
+                Ok(salt) if !salt.is_empty() => Some(PasswordPrehash { iterations, salt }),
+                _ => return Err(Error::Config(String::from("Error::InvalidConfig: AUTH_PASSWORD_PREHASH_SALT"))),
+            },
+        };
+
+        let debug_capture_rate = env_or("AUTH_DEBUG_CAPTURE_RATE", default.debug_capture_rate)?;
+        if !(0.0..=1.0).contains(&debug_capture_rate) {
+            return Err(Error::Config(format!("Error::InvalidConfig: AUTH_DEBUG_CAPTURE_RATE={debug_capture_rate}")));
+        }
+
+        let usage_stats_bucket = env_or("AUTH_USAGE_STATS_BUCKET", default.usage_stats_bucket)?;
+        if usage_stats_bucket == 0 {
+            return Err(Error::Config(String::from("Error::InvalidConfig: AUTH_USAGE_STATS_BUCKET=0")));
+        }
+        let usage_stats_epsilon = env_or("AUTH_USAGE_STATS_EPSILON", default.usage_stats_epsilon)?;
+        if !usage_stats_epsilon.is_finite() || usage_stats_epsilon < 0.0 {
+            return Err(Error::Config(format!("Error::InvalidConfig: AUTH_USAGE_STATS_EPSILON={usage_stats_epsilon}")));
+        }
+
+        let access_log_rate = env_or("AUTH_ACCESS_LOG_RATE", default.access_log_rate)?;
+        if !(0.0..=1.0).contains(&access_log_rate) {
+            return Err(Error::Config(format!("Error::InvalidConfig: AUTH_ACCESS_LOG_RATE={access_log_rate}")));
+        }
+
+        Ok(Self {
+            magic_link_url: env::var("AUTH_MAGIC_LINK_URL").unwrap_or(default.magic_link_url),
+            magic_link_ttl: Duration::from_secs(env_or("AUTH_MAGIC_LINK_TTL_SECS", default.magic_link_ttl.as_secs())?),
+            magic_link_cooldown: Duration::from_secs(env_or(
+                "AUTH_MAGIC_LINK_COOLDOWN_SECS",
+                default.magic_link_cooldown.as_secs(),
+            )?),
+            webauthn_rp_id: env_opt("AUTH_WEBAUTHN_RP_ID")?,
+            webauthn_origin: env_opt("AUTH_WEBAUTHN_ORIGIN")?,
+            webauthn_challenge_ttl: Duration::from_secs(env_or(
+                "AUTH_WEBAUTHN_CHALLENGE_TTL_SECS",
+                default.webauthn_challenge_ttl.as_secs(),
+            )?),
+            breached_password_mode: env_or("AUTH_BREACHED_PASSWORD_MODE", default.breached_password_mode)?,
+            password_min_length: env_or("AUTH_PASSWORD_MIN_LENGTH", default.password_min_length)?,
+            password_rules_mode: env_or("AUTH_PASSWORD_RULES_MODE", default.password_rules_mode)?,
+            password_hash_algorithm,
+            password_hash_rounds,
+            password_hash_memory_kib: env_or("AUTH_PASSWORD_HASH_MEMORY_KIB", default.password_hash_memory_kib)?,
+            password_prehash,
+            user_id_scheme: env_or("AUTH_USER_ID_SCHEME", default.user_id_scheme)?,
+            clock_skew_leeway: Duration::from_secs(env_or("AUTH_CLOCK_SKEW_LEEWAY_SECS", default.clock_skew_leeway.as_secs())?),
+            session_ttl: Duration::from_secs(env_or("AUTH_SESSION_TTL_SECS", default.session_ttl.as_secs())?),
+            long_lived_session_ttl: Duration::from_secs(env_or(
+                "AUTH_LONG_LIVED_SESSION_TTL_SECS",
+                default.long_lived_session_ttl.as_secs(),
+            )?),
+            session_absolute_ttl: env_opt("AUTH_SESSION_ABSOLUTE_TTL_SECS")?.map(Duration::from_secs),
+            delegation_token_ttl: Duration::from_secs(env_or(
+                "AUTH_DELEGATION_TOKEN_TTL_SECS",
+                default.delegation_token_ttl.as_secs(),
+            )?),
+            account_deletion_grace: Duration::from_secs(env_or(
+                "AUTH_ACCOUNT_DELETION_GRACE_SECS",
+                default.account_deletion_grace.as_secs(),
+            )?),
+            soft_delete_retention: Duration::from_secs(env_or(
+                "AUTH_SOFT_DELETE_RETENTION_SECS",
+                default.soft_delete_retention.as_secs(),
+            )?),
+            unverified_account_ttl: env_opt("AUTH_UNVERIFIED_ACCOUNT_TTL_SECS")?.map(Duration::from_secs),
+            maintenance_interval: Duration::from_secs(env_or(
+                "AUTH_MAINTENANCE_INTERVAL_SECS",
+                default.maintenance_interval.as_secs(),
+            )?),
+            job_intervals: env_list("AUTH_JOB_INTERVALS", default.job_intervals)?,
+            job_jitter: Duration::from_millis(env_or("AUTH_JOB_JITTER_MS", default.job_jitter.as_millis() as u64)?),
+            invite_only: env_or("AUTH_INVITE_ONLY", default.invite_only)?,
+            approval_required: env_or("AUTH_APPROVAL_REQUIRED", default.approval_required)?,
+            invite_ttl: Duration::from_secs(env_or("AUTH_INVITE_TTL_SECS", default.invite_ttl.as_secs())?),
+            terms_version: env_opt("AUTH_TERMS_VERSION")?,
+            require_current_terms: env_or("AUTH_REQUIRE_CURRENT_TERMS", default.require_current_terms)?,
+            default_scopes,
+            throttle_window: Duration::from_secs(env_or("AUTH_THROTTLE_WINDOW_SECS", default.throttle_window.as_secs())?),
+            throttle_ip_tarpit_after: env_or("AUTH_THROTTLE_IP_TARPIT_AFTER", default.throttle_ip_tarpit_after)?,
+            throttle_ip_block_after: env_or("AUTH_THROTTLE_IP_BLOCK_AFTER", default.throttle_ip_block_after)?,
+            throttle_subnet_tarpit_after: env_or(
+                "AUTH_THROTTLE_SUBNET_TARPIT_AFTER",
+                default.throttle_subnet_tarpit_after,
+            )?,
+            throttle_subnet_block_after: env_or("AUTH_THROTTLE_SUBNET_BLOCK_AFTER", default.throttle_subnet_block_after)?,
+            throttle_tarpit_delay: Duration::from_millis(env_or(
+                "AUTH_THROTTLE_TARPIT_DELAY_MS",
+                default.throttle_tarpit_delay.as_millis() as u64,
+            )?),
+            throttle_block_cooldown: Duration::from_secs(env_or(
+                "AUTH_THROTTLE_BLOCK_COOLDOWN_SECS",
+                default.throttle_block_cooldown.as_secs(),
+            )?),
+            throttle_mode: env_or("AUTH_THROTTLE_MODE", default.throttle_mode)?,
+            geoip_db: env_opt("AUTH_GEOIP_DB")?,
+            impossible_travel_kmh: env_or("AUTH_IMPOSSIBLE_TRAVEL_KMH", default.impossible_travel_kmh)?,
+            mfa_on_anomaly: env_or("AUTH_MFA_ON_ANOMALY", default.mfa_on_anomaly)?,
+            audit_file: env_opt("AUTH_AUDIT_FILE")?,
+            audit_file_max_bytes: env_or("AUTH_AUDIT_FILE_MAX_BYTES", default.audit_file_max_bytes)?,
+            audit_file_keep: env_or("AUTH_AUDIT_FILE_KEEP", default.audit_file_keep)?,
+            audit_syslog_addr: env_opt("AUTH_AUDIT_SYSLOG_ADDR")?,
+            audit_http_url: env_opt("AUTH_AUDIT_HTTP_URL")?,
+            audit_retention: env_opt("AUTH_AUDIT_RETENTION_SECS")?.map(Duration::from_secs),
+            audit_max_entries: env_opt("AUTH_AUDIT_MAX_ENTRIES")?,
+            ip_rules_file: env_opt("AUTH_IP_RULES_FILE")?,
+            event_log_dir: env_opt("AUTH_EVENT_LOG_DIR")?,
+            event_log_retention: env_opt("AUTH_EVENT_LOG_RETENTION_SECS")?.map(Duration::from_secs),
+            event_log_max_bytes: env_opt("AUTH_EVENT_LOG_MAX_BYTES")?,
+            leader_lease_file: env_opt("AUTH_LEADER_LEASE_FILE")?,
+            replication_peers: env_list("AUTH_REPLICATION_PEERS", default.replication_peers)?,
+            read_only: env_or("AUTH_READ_ONLY", default.read_only)?,
+            primary_url: env_opt("AUTH_PRIMARY_URL")?,
+            trusted_proxies: env_list("AUTH_TRUSTED_PROXIES", default.trusted_proxies)?,
+            server_timing: env_or("AUTH_SERVER_TIMING", default.server_timing)?,
+            reserved_usernames: env_list("AUTH_RESERVED_USERNAMES", default.reserved_usernames)?,
+            username_deny_file: env_opt("AUTH_USERNAME_DENY_FILE")?,
+            honeypot_usernames: env_list("AUTH_HONEYPOT_USERNAMES", default.honeypot_usernames)?,
+            enumeration_protection: env_or("AUTH_ENUMERATION_PROTECTION", default.enumeration_protection)?,
+            enumeration_response_time: Duration::from_millis(env_or(
+                "AUTH_ENUMERATION_RESPONSE_MS",
+                default.enumeration_response_time.as_millis() as u64,
+            )?),
+            admin_role_grants: env_list("AUTH_ADMIN_ROLE_GRANTS", default.admin_role_grants)?,
+            admin_policy_file: env_opt("AUTH_ADMIN_POLICY_FILE")?,
+            challenge_mode: env_or("AUTH_CHALLENGE_MODE", default.challenge_mode)?,
+            grpc_compression: env_or("AUTH_GRPC_COMPRESSION", default.grpc_compression)?,
+            listen_addrs: env_list("AUTH_LISTEN_ADDRS", default.listen_addrs)?,
+            unix_socket: env_opt("AUTH_UNIX_SOCKET")?,
+            admin_listen_addrs: env_list("AUTH_ADMIN_LISTEN_ADDRS", default.admin_listen_addrs)?,
+            health_listen_addr: env_opt("AUTH_HEALTH_LISTEN_ADDR")?,
+            debug_listen_addr: env_opt("AUTH_DEBUG_LISTEN_ADDR")?,
+            reuse_port: env_or("AUTH_REUSE_PORT", default.reuse_port)?,
+            drain_timeout: Duration::from_secs(env_or("AUTH_DRAIN_TIMEOUT_SECS", default.drain_timeout.as_secs())?),
+            admin_tls_cert: env_opt("AUTH_ADMIN_TLS_CERT")?,
+            admin_tls_key: env_opt("AUTH_ADMIN_TLS_KEY")?,
+            admin_tls_client_ca: env_opt("AUTH_ADMIN_TLS_CLIENT_CA")?,
+            serve_legacy_package: env_or("AUTH_SERVE_LEGACY_PACKAGE", default.serve_legacy_package)?,
+            api_key_quota_per_minute: env_or("AUTH_API_KEY_QUOTA_PER_MINUTE", default.api_key_quota_per_minute)?,
+            api_key_quotas: env_list("AUTH_API_KEY_QUOTAS", default.api_key_quotas)?,
+            api_key_quota_warning_percent: env_or(
+                "AUTH_API_KEY_QUOTA_WARNING_PERCENT",
+                default.api_key_quota_warning_percent,
+            )?,
+            maintenance_mode: env_or("AUTH_MAINTENANCE_MODE", default.maintenance_mode)?,
+            maintenance_retry_after: Duration::from_secs(env_or(
+                "AUTH_MAINTENANCE_RETRY_AFTER_SECS",
+                default.maintenance_retry_after.as_secs(),
+            )?),
+            max_in_flight_requests: env_or("AUTH_MAX_IN_FLIGHT_REQUESTS", default.max_in_flight_requests)?,
+            rpc_timeout: Duration::from_millis(env_or("AUTH_RPC_TIMEOUT_MS", default.rpc_timeout.as_millis() as u64)?),
+            rpc_timeouts: env_list("AUTH_RPC_TIMEOUTS", default.rpc_timeouts)?,
+            max_username_len: env_or("AUTH_MAX_USERNAME_LEN", default.max_username_len)?,
+            max_password_len: env_or("AUTH_MAX_PASSWORD_LEN", default.max_password_len)?,
+            max_token_len: env_or("AUTH_MAX_TOKEN_LEN", default.max_token_len)?,
+            slow_store_operation: Duration::from_millis(env_or(
+                "AUTH_SLOW_STORE_OPERATION_MS",
+                default.slow_store_operation.as_millis() as u64,
+            )?),
+            users_soft_limit: env_or("AUTH_USERS_SOFT_LIMIT", default.users_soft_limit)?,
+            users_hard_limit: env_or("AUTH_USERS_HARD_LIMIT", default.users_hard_limit)?,
+            sessions_soft_limit: env_or("AUTH_SESSIONS_SOFT_LIMIT", default.sessions_soft_limit)?,
+            sessions_hard_limit: env_or("AUTH_SESSIONS_HARD_LIMIT", default.sessions_hard_limit)?,
+            max_sessions: env_or("AUTH_MAX_SESSIONS", default.max_sessions)?,
+            session_eviction: env_or("AUTH_SESSION_EVICTION", default.session_eviction)?,
+            session_binding: env_or("AUTH_SESSION_BINDING", default.session_binding)?,
+            unknown_session_reply: env_or("AUTH_UNKNOWN_SESSION_REPLY", default.unknown_session_reply)?,
+            tenants: env_list("AUTH_TENANTS", default.tenants)?,
+            max_staleness: Duration::from_secs(env_or("AUTH_MAX_STALENESS_SECS", default.max_staleness.as_secs())?),
+            replay_protection: env_or("AUTH_REPLAY_PROTECTION", default.replay_protection)?,
+            replay_window: Duration::from_secs(env_or("AUTH_REPLAY_WINDOW_SECS", default.replay_window.as_secs())?),
+            debug_capture_rate,
+            debug_capture_size: env_or("AUTH_DEBUG_CAPTURE_SIZE", default.debug_capture_size)?,
+            response_cache_ttl: Duration::from_secs(env_or("AUTH_RESPONSE_CACHE_TTL_SECS", default.response_cache_ttl.as_secs())?),
+            usage_stats_file: env_opt("AUTH_USAGE_STATS_FILE")?,
+            usage_stats_url: env_opt("AUTH_USAGE_STATS_URL")?,
+            usage_stats_bucket,
+            usage_stats_epsilon,
+            access_log: env_opt("AUTH_ACCESS_LOG")?,
+            access_log_rate,
+        })
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T, Error> {
+    Ok(env_opt(name)?.unwrap_or(default))
+}
+
+// A comma separated list; set but empty means an empty list.
+fn env_list<T: FromStr>(name: &str, default: Vec<T>) -> Result<Vec<T>, Error> {
+    match env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| item.parse().map_err(|_| Error::Config(format!("Error::InvalidConfig: {name}={value}"))))
+            .collect(),
+        Err(_) => Ok(default),
+    }
+}
+
+fn env_opt<T: FromStr>(name: &str) -> Result<Option<T>, Error> {
+    match env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|_| Error::Config(format!("Error::InvalidConfig: {name}={value}"))),
+        Err(_) => Ok(None),
+    }
+}
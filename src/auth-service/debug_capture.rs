@@ -0,0 +1,510 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use prost::Message;
+use rand_core::{OsRng, RngCore};
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Service},
+    server::NamedService,
+    transport::Body,
+    Code, Status,
+};
+
+use crate::auth::authentication::*;
+use crate::config::Config;
+
+// The longest a captured message is kept, in bytes.
+const MAX_MESSAGE_LEN: usize = 4 * 1024;
+
+// A field that can hold a secret, or what a user keeps about themselves.
+trait Redact {
+    fn redact(&mut self);
+}
+
+impl Redact for String {
+    fn redact(&mut self) {
+        if !self.is_empty() {
+            *self = String::from("<redacted>");
+        }
+    }
+}
+
+impl Redact for Vec<String> {
+    fn redact(&mut self) {
+        self.iter_mut().for_each(Redact::redact);
+    }
+}
+
+impl Redact for HashMap<String, String> {
+    fn redact(&mut self) {
+        self.values_mut().for_each(Redact::redact);
+    }
+}
+
+impl Redact for Vec<SessionSummary> {
+    fn redact(&mut self) {
+        self.iter_mut().for_each(|session| session.session_token.redact());
+    }
+}
+
+// Whole messages are left out.
+impl<T> Redact for Option<T> {
+    fn redact(&mut self) {
+        *self = None;
+    }
+}
+
+// The request and reply of a call, as text.
+type Describe = fn(&[u8], &[u8]) -> (String, String);
+
+// The unary RPCs that are captured, with the fields of their messages to redact. StreamStats never
+// ends, and ListDebugCaptures would capture the captures.
+macro_rules! captured {
+    ($($method:ident($request:ident { $($request_field:ident),* }, $response:ident { $($response_field:ident),* })),* $(,)?) => {
+        fn describer(method: &str) -> Option<Describe> {
+            match method {
+                $(stringify!($method) => Some(|request, response| (
+                    describe(request, |_message: &mut $request| { $(_message.$request_field.redact();)* }),
+                    describe(response, |_message: &mut $response| { $(_message.$response_field.redact();)* }),
+                )),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+captured!(
+    SignUp(SignUpRequest { password, invite_code, challenge_response }, SignUpResponse {}),
+    SignIn(SignInRequest { password }, SignInResponse { session_token }),
+    SignOut(SignOutRequest { session_token }, SignOutResponse {}),
+    ExchangeExternalToken(ExchangeExternalTokenRequest { id_token }, ExchangeExternalTokenResponse { session_token }),
+    IntrospectToken(IntrospectTokenRequest { token }, IntrospectTokenResponse {}),
+    RequestMagicLink(RequestMagicLinkRequest {}, RequestMagicLinkResponse {}),
+    ConsumeMagicLink(ConsumeMagicLinkRequest { magic_link_token }, ConsumeMagicLinkResponse { session_token }),
+    ValidateSession(ValidateSessionRequest { session_token }, ValidateSessionResponse {}),
+    CreateGuestSession(CreateGuestSessionRequest {}, CreateGuestSessionResponse { session_token }),
+    UpgradeGuestSession(UpgradeGuestSessionRequest { session_token, password }, UpgradeGuestSessionResponse { session_token }),
+    ChangeUsername(ChangeUsernameRequest { session_token }, ChangeUsernameResponse {}),
+    ChangePassword(ChangePasswordRequest { session_token, current_password, new_password }, ChangePasswordResponse { session_token }),
+    SetUserMetadata(SetUserMetadataRequest { session_token, value }, SetUserMetadataResponse {}),
+    GetUserMetadata(GetUserMetadataRequest { session_token }, GetUserMetadataResponse { metadata }),
+    ExportMyData(ExportMyDataRequest { session_token }, ExportMyDataResponse { data }),
+    DeleteAccount(DeleteAccountRequest { session_token }, DeleteAccountResponse {}),
+    AcceptTerms(AcceptTermsRequest { password }, AcceptTermsResponse {}),
+    CreateDelegationToken(CreateDelegationTokenRequest { session_token }, CreateDelegationTokenResponse { delegation_token }),
+    ListRevokedSessions(ListRevokedSessionsRequest {}, ListRevokedSessionsResponse {}),
+    GetServerInfo(GetServerInfoRequest {}, GetServerInfoResponse {}),
+    StartWebAuthnRegistration(StartWebAuthnRegistrationRequest { session_token }, StartWebAuthnRegistrationResponse {}),
+    FinishWebAuthnRegistration(FinishWebAuthnRegistrationRequest { session_token }, FinishWebAuthnRegistrationResponse {}),
+    StartWebAuthnAssertion(StartWebAuthnAssertionRequest {}, StartWebAuthnAssertionResponse {}),
+    FinishWebAuthnAssertion(FinishWebAuthnAssertionRequest {}, FinishWebAuthnAssertionResponse { session_token }),
+    SuspendUser(SuspendUserRequest {}, SuspendUserResponse {}),
+    UnsuspendUser(UnsuspendUserRequest {}, UnsuspendUserResponse {}),
+    SoftDeleteUser(SoftDeleteUserRequest {}, SoftDeleteUserResponse {}),
+    RestoreUser(RestoreUserRequest {}, RestoreUserResponse {}),
+    ApproveUser(ApproveUserRequest {}, ApproveUserResponse {}),
+    RejectUser(RejectUserRequest {}, RejectUserResponse {}),
+    ListPendingUsers(ListPendingUsersRequest {}, ListPendingUsersResponse {}),
+    SetUserScopes(SetUserScopesRequest {}, SetUserScopesResponse {}),
+    RevokeLongLivedSessions(RevokeLongLivedSessionsRequest {}, RevokeLongLivedSessionsResponse {}),
+    RevokeSessions(RevokeSessionsRequest { session_tokens }, RevokeSessionsResponse {}),
+    ListSessions(ListSessionsRequest {}, ListSessionsResponse { sessions }),
+    ExportUserData(ExportUserDataRequest {}, ExportUserDataResponse { data }),
+    ListUsers(ListUsersRequest {}, ListUsersResponse {}),
+    CreateInvite(CreateInviteRequest {}, CreateInviteResponse { invite_code }),
+    ReserveUsername(ReserveUsernameRequest {}, ReserveUsernameResponse {}),
+    SetHoneypotUsername(SetHoneypotUsernameRequest {}, SetHoneypotUsernameResponse {}),
+    SetMaintenanceMode(SetMaintenanceModeRequest {}, SetMaintenanceModeResponse {}),
+    SetFeatureFlag(SetFeatureFlagRequest {}, SetFeatureFlagResponse {}),
+    SetLogLevel(SetLogLevelRequest {}, SetLogLevelResponse {}),
+    GetStats(GetStatsRequest {}, GetStatsResponse {}),
+    // Redacted already.
+    GetEffectiveConfig(GetEffectiveConfigRequest {}, GetEffectiveConfigResponse {}),
+    ReplayProjection(ReplayProjectionRequest {}, ReplayProjectionResponse {}),
+    ListRecentAuditEvents(ListRecentAuditEventsRequest {}, ListRecentAuditEventsResponse {}),
+    ListThrottledSources(ListThrottledSourcesRequest {}, ListThrottledSourcesResponse {}),
+    GetRateLimitState(GetRateLimitStateRequest {}, GetRateLimitStateResponse {}),
+    ClearLockout(ClearLockoutRequest {}, ClearLockoutResponse {}),
+    SetDebugCapture(SetDebugCaptureRequest {}, SetDebugCaptureResponse {}),
+);
+
+// The messages of a gRPC body, one per line.
+fn describe<M: Message + Default + Debug>(mut frames: &[u8], redact: fn(&mut M)) -> String {
+    let mut messages = Vec::new();
+
+    while !frames.is_empty() {
+        let Some((&[compressed, a, b, c, d], rest)) = frames.split_first_chunk::<5>() else {
+            messages.push(String::from("<truncated>"));
+            break;
+        };
+        let len = u32::from_be_bytes([a, b, c, d]) as usize;
+        if rest.len() < len {
+            messages.push(String::from("<truncated>"));
+            break;
+        }
+        let (payload, next) = rest.split_at(len);
+
+        messages.push(if compressed != 0 {
+            format!("<compressed, {len} bytes>")
+        } else {
+            match M::decode(payload) {
+                Ok(mut message) => {
+                    redact(&mut message);
+                    format!("{message:?}")
+                }
+                Err(_) => String::from("<undecodable>"),
+            }
+        });
+        frames = next;
+    }
+
+    let mut described = messages.join("\n");
+    if described.len() > MAX_MESSAGE_LEN {
+        let mut end = MAX_MESSAGE_LEN;
+        while !described.is_char_boundary(end) {
+            end -= 1;
+        }
+        described.truncate(end);
+        described.push_str("...");
+    }
+    described
+}
+
+// A message as it goes in a gRPC body.
+fn frame(message: &impl Message) -> Bytes {
+    let mut frame = vec![0];
+    frame.extend((message.encoded_len() as u32).to_be_bytes());
+    frame.extend(message.encode_to_vec());
+    frame.into()
+}
+
+// The request of `method`, redacted as its captures are; None for the RPCs that aren't captured.
+pub(crate) fn describe_request(method: &str, request: &impl Message) -> Option<String> {
+    describer(method).map(|describe| describe(&frame(request), &[]).0)
+}
+
+pub(crate) fn grpc_status(headers: &http::HeaderMap) -> Option<Code> {
+    let status = headers.get("grpc-status")?.to_str().ok()?.parse::<i32>().ok()?;
+    Some(Code::from(status))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedCall {
+    pub captured_at: SystemTime,
+    // The RPC, e.g. "SignIn".
+    pub method: String,
+    pub request: String,
+    pub response: String,
+    pub grpc_status: Code,
+    pub duration: Duration,
+}
+
+// Whole requests and replies, sanitized, of a share of the calls (the sample rate, 0 to 1), the
+// latest `capacity` of them: to look into a client's problem without raising the log level for
+// everybody. Admins change the rate while running (SetDebugCapture).
+pub struct DebugCapture {
+    // An f64, as bits.
+    sample_rate: AtomicU64,
+    capacity: usize,
+    calls: Mutex<VecDeque<CapturedCall>>,
+}
+
+impl Default for DebugCapture {
+    fn default() -> Self {
+        Self::from_config(&Config::default())
+    }
+}
+
+impl DebugCapture {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            sample_rate: AtomicU64::new(config.debug_capture_rate.to_bits()),
+            capacity: config.debug_capture_size,
+            calls: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        f64::from_bits(self.sample_rate.load(Ordering::Relaxed))
+    }
+
+    pub fn set_sample_rate(&self, sample_rate: f64) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&sample_rate) {
+            return Err(format!("Error::InvalidSampleRate: {sample_rate}"));
+        }
+        self.sample_rate.store(sample_rate.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn clear(&self) {
+        self.calls.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+
+    // Whether to capture the next call.
+    fn sampled(&self) -> bool {
+        match self.sample_rate() {
+            rate if rate <= 0.0 => false,
+            rate if rate >= 1.0 => true,
+            rate => (OsRng.next_u64() as f64) < rate * u64::MAX as f64,
+        }
+    }
+
+    fn record(&self, call: CapturedCall) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut calls = self.calls.lock().unwrap_or_else(PoisonError::into_inner);
+        if calls.len() >= self.capacity {
+            calls.pop_front();
+        }
+        calls.push_back(call);
+    }
+
+    // The latest `limit` calls, of `method` only when given, the latest first.
+    pub fn calls(&self, limit: usize, method: Option<&str>) -> Vec<CapturedCall> {
+        self.calls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .rev()
+            .filter(|call| method.is_none_or(|method| call.method == method))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+// Captures the sampled calls (see `DebugCapture`): their request is read whole before it is
+// passed on, and their reply copied as it goes out.
+#[derive(Clone)]
+pub struct DebugCaptureService<S> {
+    inner: S,
+    capture: Arc<DebugCapture>,
+}
+
+impl<S> DebugCaptureService<S> {
+    pub fn new(inner: S, capture: Arc<DebugCapture>) -> Self {
+        Self { inner, capture }
+    }
+}
+
+impl<S> Service<http::Request<Body>> for DebugCaptureService<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let method = request.uri().path().rsplit('/').next().unwrap_or_default().to_owned();
+        let Some(describe) = describer(&method).filter(|_| self.capture.sampled()) else {
+            return Box::pin(self.inner.call(request));
+        };
+
+        // The service that was made ready goes with the call; a clone takes its place.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let capture = self.capture.clone();
+
+        Box::pin(async move {
+            let pending = PendingCall { capture, method, describe, captured_at: SystemTime::now(), started_at: Instant::now() };
+
+            let (parts, body) = request.into_parts();
+            let request = match hyper::body::to_bytes(body).await {
+                Ok(request) => request,
+                Err(e) => return Ok(Status::internal(format!("failed to read the request: {e}")).to_http()),
+            };
+
+            let response = inner.call(http::Request::from_parts(parts, Body::from(request.clone()))).await?;
+            let (parts, body) = response.into_parts();
+            let body = CapturingBody {
+                inner: body,
+                request,
+                response: Vec::new(),
+                // Errors come as headers only.
+                grpc_status: grpc_status(&parts.headers),
+                pending: Some(pending),
+            };
+            Ok(http::Response::from_parts(parts, body.boxed_unsync()))
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for DebugCaptureService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+struct PendingCall {
+    capture: Arc<DebugCapture>,
+    method: String,
+    describe: Describe,
+    captured_at: SystemTime,
+    started_at: Instant,
+}
+
+// A reply, passed on as it is while a copy is kept; the call is recorded once the reply is done
+// with, sent or not.
+struct CapturingBody {
+    inner: BoxBody,
+    request: Bytes,
+    response: Vec<u8>,
+    grpc_status: Option<Code>,
+    pending: Option<PendingCall>,
+}
+
+impl HttpBody for CapturingBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Status>>> {
+        let polled = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(data))) = &polled {
+            self.response.extend_from_slice(data);
+        }
+        polled
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<http::HeaderMap>, Status>> {
+        let polled = Pin::new(&mut self.inner).poll_trailers(cx);
+        if let Poll::Ready(Ok(Some(trailers))) = &polled {
+            self.grpc_status = grpc_status(trailers).or(self.grpc_status);
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CapturingBody {
+    fn drop(&mut self) {
+        let Some(pending) = self.pending.take() else { return };
+        let (request, response) = (pending.describe)(&self.request, &self.response);
+
+        pending.capture.record(CapturedCall {
+            captured_at: pending.captured_at,
+            method: pending.method,
+            request,
+            response,
+            // No status: the caller went away before the reply was done.
+            grpc_status: self.grpc_status.unwrap_or(Code::Cancelled),
+            duration: pending.started_at.elapsed(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    fn capture(sample_rate: f64, size: usize) -> Arc<DebugCapture> {
+        let config = Config { debug_capture_rate: sample_rate, debug_capture_size: size, ..Config::default() };
+        Arc::new(DebugCapture::from_config(&config))
+    }
+
+    #[tokio::test]
+    async fn should_capture_calls_with_secrets_redacted() {
+        let capture = capture(1.0, 10);
+        let reply = SignInResponse { session_token: "secret-token".to_owned(), ..SignInResponse::default() };
+        let service = DebugCaptureService::new(
+            service_fn(move |request: http::Request<Body>| {
+                let reply = frame(&reply);
+                async move {
+                    // The request still gets through whole.
+                    let request = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                    assert_eq!(SignInRequest::decode(&request[5..]).unwrap().username, "alice");
+
+                    let body = Body::from(reply).map_err(|e| Status::internal(e.to_string())).boxed_unsync();
+                    let mut response = http::Response::new(body);
+                    response.headers_mut().insert("grpc-status", "0".parse().unwrap());
+                    Ok::<_, Infallible>(response)
+                }
+            }),
+            capture.clone(),
+        );
+
+        let request = SignInRequest { username: "alice".to_owned(), password: "hunter2".to_owned(), remember_me: false };
+        let request = http::Request::builder().uri("/authentication.v1.Auth/SignIn").body(Body::from(frame(&request))).unwrap();
+        let response = service.oneshot(request).await.unwrap();
+        let reply = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(SignInResponse::decode(&reply[5..]).unwrap().session_token, "secret-token");
+
+        let calls = capture.calls(10, Some("SignIn"));
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].grpc_status, Code::Ok);
+        assert!(calls[0].request.contains("alice"), "{}", calls[0].request);
+        assert!(!calls[0].request.contains("hunter2"), "{}", calls[0].request);
+        assert!(!calls[0].response.contains("secret-token"), "{}", calls[0].response);
+    }
+
+    #[test]
+    fn should_keep_the_latest_calls() {
+        let capture = capture(0.0, 2);
+        for method in ["SignIn", "SignUp", "SignIn"] {
+            capture.record(CapturedCall {
+                captured_at: SystemTime::now(),
+                method: method.to_owned(),
+                request: String::new(),
+                response: String::new(),
+                grpc_status: Code::Ok,
+                duration: Duration::ZERO,
+            });
+        }
+
+        let methods: Vec<String> = capture.calls(10, None).into_iter().map(|call| call.method).collect();
+        assert_eq!(methods, ["SignIn", "SignUp"]);
+        assert_eq!(capture.calls(10, Some("SignUp")).len(), 1);
+
+        assert!(!capture.sampled());
+        assert!(capture.set_sample_rate(1.5).is_err());
+        capture.set_sample_rate(1.0).unwrap();
+        assert!(capture.sampled());
+    }
+
+    #[test]
+    fn should_describe_what_it_cannot_decode() {
+        let (request, _) = describer("SignIn").unwrap()(&[1, 0, 0, 0, 2, 0xff, 0xff], &[0, 0, 0]);
+        assert_eq!(request, "<compressed, 2 bytes>");
+
+        assert!(describer("StreamStats").is_none());
+    }
+
+    #[test]
+    fn should_describe_requests_for_the_logs_redacted() {
+        let request = ChangePasswordRequest {
+            session_token: "secret-token".to_owned(),
+            current_password: "hunter1".to_owned(),
+            new_password: "hunter2".to_owned(),
+        };
+        let described = describe_request("ChangePassword", &request).unwrap();
+        assert!(!described.contains("secret-token") && !described.contains("hunter"));
+
+        assert_eq!(describe_request("StreamStats", &StreamStatsRequest::default()), None);
+    }
+}
@@ -0,0 +1,254 @@
+use std::process::ExitCode;
+
+use tonic::Status;
+
+use crate::{
+    audit_export::ExportError, hashers::HashError, journal::JournalError, ldap::DirectoryError, migrate::MigrationError,
+    replication::ReplicationError,
+};
+
+// What goes wrong, by what it is about, for the service and the binaries around it. Each kind
+// turns into a gRPC status (see `From<Error> for Status`) and into an exit code (see `exit_code`)
+// here and nowhere else. The messages are the `Error::Xxx` strings the parts have always
+// returned; only their kind is new.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    // Settings missing, malformed or not going together: found on start, fixed by an operator.
+    #[error("{0}")]
+    Config(String),
+    // A store, journal, lease or file the service keeps its state in, failing or out of reach.
+    #[error("{0}")]
+    Storage(String),
+    // Keys, certificates and tokens: unreadable, invalid, or failing to sign or verify.
+    #[error("{0}")]
+    Crypto(String),
+    // Listeners, connections and calls to other services.
+    #[error("{0}")]
+    Transport(String),
+    // A caller or request that a policy refuses: the admin policy, IP rules, missing keys.
+    #[error("{0}")]
+    Policy(String),
+}
+
+// What the user and session stores refuse or fail with, for callers to match on by variant. They
+// read as the `Error::Xxx` strings they always did.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum StoreError {
+    #[error("Error::UserNotFound")]
+    UserNotFound,
+    #[error("Error::UserAlreadyExists")]
+    UserAlreadyExists,
+    #[error("Error::UserUuidAlreadyExists")]
+    UserUuidAlreadyExists,
+    #[error("Error::InvalidUsername")]
+    InvalidUsername,
+    #[error("Error::InvalidPassword")]
+    InvalidPassword,
+    #[error("Error::InvalidMetadataKey")]
+    InvalidMetadataKey,
+    #[error("Error::MetadataValueTooLong")]
+    MetadataValueTooLong,
+    #[error("Error::TooManyMetadataEntries")]
+    TooManyMetadataEntries,
+    #[error("Error::InvalidScope")]
+    InvalidScope,
+    #[error("Error::TooManyScopes")]
+    TooManyScopes,
+    #[error("Error::InvalidCursor")]
+    InvalidCursor,
+    #[error("Error::InvalidExport")]
+    InvalidExport,
+    #[error("Error::SessionNotFound")]
+    SessionNotFound,
+    #[error("Error::SessionNotDelegable")]
+    SessionNotDelegable,
+    #[error("Error::ScopeNotHeld")]
+    ScopeNotHeld,
+    #[error("Error::CredentialNotFound")]
+    CredentialNotFound,
+    #[error("Error::CredentialAlreadyRegistered")]
+    CredentialAlreadyRegistered,
+    #[error("Error::DirectoryIsReadOnly")]
+    DirectoryIsReadOnly,
+    #[error("Error::ListingUnsupported")]
+    ListingUnsupported,
+    #[error("Error::NoJournal")]
+    NoJournal,
+    #[error(transparent)]
+    Journal(#[from] JournalError),
+    #[error(transparent)]
+    Directory(#[from] DirectoryError),
+    #[error(transparent)]
+    Hashing(#[from] HashError),
+}
+
+impl Error {
+    // As sysexits(3) has them, so supervisors tell a bad setting, which restarting won't fix,
+    // from a peer being down, which it may.
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            Error::Config(_) => 78,    // EX_CONFIG
+            Error::Storage(_) => 74,   // EX_IOERR
+            Error::Crypto(_) => 65,    // EX_DATAERR
+            Error::Transport(_) => 69, // EX_UNAVAILABLE
+            Error::Policy(_) => 77,    // EX_NOPERM
+        })
+    }
+}
+
+impl From<Error> for Status {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Config(message) | Error::Crypto(message) => Status::internal(message),
+            Error::Storage(message) | Error::Transport(message) => Status::unavailable(message),
+            Error::Policy(message) => Status::permission_denied(message),
+        }
+    }
+}
+
+// Refusals are for the caller to fix; the stores being out of reach is retried.
+impl From<StoreError> for Status {
+    fn from(error: StoreError) -> Self {
+        let message = error.to_string();
+        match error {
+            StoreError::UserNotFound | StoreError::SessionNotFound | StoreError::CredentialNotFound => Status::not_found(message),
+            StoreError::UserAlreadyExists | StoreError::UserUuidAlreadyExists | StoreError::CredentialAlreadyRegistered => {
+                Status::already_exists(message)
+            }
+            StoreError::InvalidUsername
+            | StoreError::InvalidPassword
+            | StoreError::InvalidMetadataKey
+            | StoreError::MetadataValueTooLong
+            | StoreError::TooManyMetadataEntries
+            | StoreError::InvalidScope
+            | StoreError::TooManyScopes
+            | StoreError::InvalidCursor
+            | StoreError::InvalidExport => Status::invalid_argument(message),
+            StoreError::SessionNotDelegable | StoreError::ScopeNotHeld => Status::permission_denied(message),
+            StoreError::DirectoryIsReadOnly | StoreError::ListingUnsupported => Status::unimplemented(message),
+            StoreError::NoJournal => Status::failed_precondition(message),
+            StoreError::Journal(_) | StoreError::Directory(_) | StoreError::Hashing(_) => Error::from(error).into(),
+        }
+    }
+}
+
+impl From<StoreError> for Error {
+    fn from(error: StoreError) -> Self {
+        match error {
+            StoreError::Directory(error) => error.into(),
+            StoreError::Hashing(_) => Error::Crypto(error.to_string()),
+            _ => Error::Storage(error.to_string()),
+        }
+    }
+}
+
+impl From<JournalError> for Error {
+    fn from(error: JournalError) -> Self {
+        Error::Storage(error.to_string())
+    }
+}
+
+impl From<DirectoryError> for Error {
+    fn from(error: DirectoryError) -> Self {
+        match error {
+            DirectoryError::MissingUrl | DirectoryError::MissingUserDnTemplate | DirectoryError::InvalidTimeout => {
+                Error::Config(error.to_string())
+            }
+            DirectoryError::Unreachable(_) | DirectoryError::BindFailed(_) | DirectoryError::ThreadPanicked => {
+                Error::Transport(error.to_string())
+            }
+        }
+    }
+}
+
+impl From<ReplicationError> for Error {
+    fn from(error: ReplicationError) -> Self {
+        match error {
+            ReplicationError::InvalidPeer(_) | ReplicationError::InvalidKey => Error::Config(error.to_string()),
+            ReplicationError::InvalidEvent => Error::Transport(error.to_string()),
+        }
+    }
+}
+
+impl From<MigrationError> for Error {
+    fn from(error: MigrationError) -> Self {
+        match error {
+            MigrationError::UnknownBackend(_) => Error::Config(error.to_string()),
+            MigrationError::Store(error) => error.into(),
+            MigrationError::TargetNotEmpty | MigrationError::ImportFailed { .. } | MigrationError::Journal(_) => {
+                Error::Storage(error.to_string())
+            }
+        }
+    }
+}
+
+// Only met setting the sinks up; failed exports are retried, then dropped.
+impl From<ExportError> for Error {
+    fn from(error: ExportError) -> Self {
+        Error::Config(error.to_string())
+    }
+}
+
+impl From<tonic::transport::Error> for Error {
+    fn from(error: tonic::transport::Error) -> Self {
+        Error::Transport(error.to_string())
+    }
+}
+
+// The service's files go through its stores and loaders, which say what they were at; the I/O
+// left is its sockets.
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Transport(error.to_string())
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(error: hyper::Error) -> Self {
+        Error::Transport(error.to_string())
+    }
+}
+
+// A key or setting to be sent as metadata, with characters metadata can't carry.
+impl From<tonic::metadata::errors::InvalidMetadataValue> for Error {
+    fn from(error: tonic::metadata::errors::InvalidMetadataValue) -> Self {
+        Error::Config(error.to_string())
+    }
+}
+
+// What a call answered, as seen by its caller.
+impl From<Status> for Error {
+    fn from(status: Status) -> Self {
+        Error::Transport(format!("{:?}: {}", status.code(), status.message()))
+    }
+}
+
+// Whatever a binary exits with, printed and turned into its exit code.
+pub fn exit_with(result: Result<(), Error>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            e.exit_code()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    #[test]
+    fn should_map_each_kind_to_a_status_and_an_exit_code() {
+        let status = Status::from(Error::Policy(String::from("not allowed by the admin policy")));
+        assert_eq!((status.code(), status.message()), (Code::PermissionDenied, "not allowed by the admin policy"));
+        assert_eq!(Status::from(Error::Storage(String::new())).code(), Code::Unavailable);
+        assert_eq!(Status::from(Error::Crypto(String::new())).code(), Code::Internal);
+
+        assert_eq!(Error::Config(String::new()).exit_code(), ExitCode::from(78));
+        assert_eq!(Error::Transport(String::new()).exit_code(), ExitCode::from(69));
+        assert_eq!(Error::from(Status::unavailable("down")).to_string(), "Unavailable: down");
+    }
+}
@@ -0,0 +1,141 @@
+use std::env;
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+// Things other systems may want to react to, e.g. to notify the user.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    // A sign in from a device the user hasn't used before.
+    NewDeviceSignIn {
+        user_uuid: String,
+        ip: String,
+        user_agent: String,
+    },
+    // A sign in from a country the user never signed in from, or too far from their last one (see
+    // `SignInLocations`).
+    SuspiciousSignIn {
+        user_uuid: String,
+        ip: String,
+        country: String,
+        reason: String,
+    },
+    // With `Flag::ApprovalRequired`, a new user waiting for an operator; then what the operator
+    // decided.
+    SignUpPendingApproval {
+        user_uuid: String,
+        username: String,
+    },
+    UserApproved {
+        user_uuid: String,
+    },
+    UserRejected {
+        user_uuid: String,
+    },
+    // An account that was never verified, purged AUTH_UNVERIFIED_ACCOUNT_TTL_SECS after it was
+    // created; its username is free again.
+    UnverifiedAccountPurged {
+        user_uuid: String,
+        username: String,
+    },
+    // Somebody tried to sign in as a honeypot (see `HoneypotUsernames`): likely a credential
+    // stuffing campaign starting. `ip` is the client as far as the trusted proxies tell,
+    // `remote_ip` the other end of the connection, `forwarded_for` the proxy headers as sent.
+    HoneypotSignIn {
+        username: String,
+        ip: String,
+        remote_ip: String,
+        forwarded_for: String,
+        user_agent: String,
+        tenant: String,
+    },
+}
+
+// Publishing must not hold up the RPC that caused the event, so sinks deliver in the background.
+pub trait EventSink {
+    fn publish(&self, event: Event);
+}
+
+// Logs events, for when nothing else is told of them (no AUTH_WEBHOOK_URL).
+#[derive(Default)]
+pub struct LoggingEventSink;
+
+impl EventSink for LoggingEventSink {
+    fn publish(&self, event: Event) {
+        info!("event: {:?}", event);
+    }
+}
+
+// POSTs every event as JSON to a URL.
+pub struct WebhookEventSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookEventSink {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+}
+
+impl EventSink for WebhookEventSink {
+    fn publish(&self, event: Event) {
+        let request = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&event).unwrap_or_default());
+        let url = self.url.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = request.send().await.and_then(|response| response.error_for_status()) {
+                warn!("Failed to deliver event to {}.\n{e:?}", url);
+            }
+        });
+    }
+}
+
+// AUTH_WEBHOOK_URL, when set, receives every event; otherwise they are only printed.
+pub fn event_sink_from_env() -> Box<dyn EventSink + Send + Sync> {
+    match env::var("AUTH_WEBHOOK_URL") {
+        Ok(url) => Box::new(WebhookEventSink::new(url)),
+        Err(_) => Box::new(LoggingEventSink),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    // Keeps published events, for tests to inspect.
+    #[derive(Clone, Default)]
+    pub struct RecordingEventSink {
+        pub events: Arc<Mutex<Vec<Event>>>,
+    }
+
+    impl EventSink for RecordingEventSink {
+        fn publish(&self, event: Event) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn should_serialize_event_with_type_tag() {
+        let event = Event::NewDeviceSignIn {
+            user_uuid: "123456".to_owned(),
+            ip: "10.0.0.1".to_owned(),
+            user_agent: "curl".to_owned(),
+        };
+
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"NewDeviceSignIn","user_uuid":"123456","ip":"10.0.0.1","user_agent":"curl"}"#
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use crate::clock_skew;
+
+// An upstream OpenID Connect provider (Google, GitHub, a corporate IdP, ...), whose ID tokens
+// we accept in exchange for a local session.
+pub struct ExternalProvider {
+    issuer: String,
+    audience: String,
+    algorithm: Algorithm,
+    decoding_key: DecodingKey,
+}
+
+// Only the subject is needed to link the token to a local user. `exp`, `iss` and `aud` are
+// checked by `jsonwebtoken` itself; `iat`, when there is one, here.
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    #[serde(default)]
+    iat: Option<u64>,
+}
+
+impl ExternalProvider {
+    pub fn new(issuer: String, audience: String, algorithm: Algorithm, decoding_key: DecodingKey) -> Self {
+        Self {
+            issuer,
+            audience,
+            algorithm,
+            decoding_key,
+        }
+    }
+
+    // `leeway` is how far apart the provider's clock and ours may be, both for `exp` and `iat`.
+    fn verify(&self, id_token: &str, leeway: Duration) -> Result<String, String> {
+        let mut validation = Validation::new(self.algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.leeway = leeway.as_secs();
+
+        let claims = decode::<IdTokenClaims>(id_token, &self.decoding_key, &validation)
+            .map_err(|e| format!("Error::InvalidIdToken: {e}"))?
+            .claims;
+
+        let issued_at = claims.iat.map(|iat| UNIX_EPOCH + Duration::from_secs(iat));
+        if issued_at.is_some_and(|issued_at| clock_skew::is_ahead(&self.issuer, issued_at, SystemTime::now(), leeway)) {
+            return Err(String::from("Error::InvalidIdToken: issued in the future"));
+        }
+        Ok(claims.sub)
+    }
+}
+
+#[derive(Default)]
+pub struct ExternalProviders {
+    providers: HashMap<String, ExternalProvider>,
+}
+
+impl ExternalProviders {
+    // Providers are listed in AUTH_EXTERNAL_PROVIDERS (e.g. "google,github"). For each of them,
+    // AUTH_EXTERNAL_PROVIDER_<NAME>_ISSUER and AUTH_EXTERNAL_PROVIDER_<NAME>_AUDIENCE must be set,
+    // along with either a PEM encoded RSA public key (AUTH_EXTERNAL_PROVIDER_<NAME>_KEY_FILE, RS256)
+    // or a shared secret (AUTH_EXTERNAL_PROVIDER_<NAME>_SECRET, HS256).
+    pub fn from_env() -> Result<Self, String> {
+        let mut external_providers = Self::default();
+
+        let names = env::var("AUTH_EXTERNAL_PROVIDERS").unwrap_or_default();
+
+        for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let var = |suffix: &str| env::var(format!("AUTH_EXTERNAL_PROVIDER_{}_{}", name.to_uppercase(), suffix));
+
+            let issuer = var("ISSUER").map_err(|_| format!("Error::MissingIssuer for provider {name}"))?;
+            let audience = var("AUDIENCE").map_err(|_| format!("Error::MissingAudience for provider {name}"))?;
+
+            let (algorithm, decoding_key) = match (var("KEY_FILE"), var("SECRET")) {
+                (Ok(key_file), _) => {
+                    let pem = fs::read(&key_file).map_err(|e| format!("Failed to read {key_file}.\n{e:?}"))?;
+                    let decoding_key = DecodingKey::from_rsa_pem(&pem).map_err(|e| format!("Failed to parse {key_file}.\n{e:?}"))?;
+                    (Algorithm::RS256, decoding_key)
+                }
+                (_, Ok(secret)) => (Algorithm::HS256, DecodingKey::from_secret(secret.as_bytes())),
+                _ => return Err(format!("Error::MissingKey for provider {name}")),
+            };
+
+            external_providers.insert(name, ExternalProvider::new(issuer, audience, algorithm, decoding_key));
+        }
+
+        Ok(external_providers)
+    }
+
+    pub fn insert(&mut self, name: &str, provider: ExternalProvider) {
+        self.providers.insert(name.to_owned(), provider);
+    }
+
+    // Returns the subject of a valid ID token issued by `provider`.
+    pub fn verify(&self, provider: &str, id_token: &str, leeway: Duration) -> Result<String, String> {
+        self.providers
+            .get(provider)
+            .ok_or_else(|| format!("Error::UnknownProvider: {provider}"))?
+            .verify(id_token, leeway)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+
+    use super::*;
+
+    const LEEWAY: Duration = Duration::from_secs(60);
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        iss: &'a str,
+        aud: &'a str,
+        exp: u64,
+    }
+
+    pub fn test_providers() -> ExternalProviders {
+        let mut external_providers = ExternalProviders::default();
+        external_providers.insert(
+            "google",
+            ExternalProvider::new(
+                "https://accounts.google.com".to_owned(),
+                "our-client-id".to_owned(),
+                Algorithm::HS256,
+                DecodingKey::from_secret(b"secret"),
+            ),
+        );
+        external_providers
+    }
+
+    pub fn test_id_token(sub: &str, iss: &str, exp: u64) -> String {
+        let claims = TestClaims { sub, iss, aud: "our-client-id", exp };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap()
+    }
+
+    #[test]
+    fn should_verify_id_token() {
+        let id_token = test_id_token("1234", "https://accounts.google.com", u64::MAX / 2);
+
+        assert_eq!(test_providers().verify("google", &id_token, LEEWAY).unwrap(), "1234");
+    }
+
+    #[test]
+    fn should_reject_id_token_from_another_issuer() {
+        let id_token = test_id_token("1234", "https://evil.example.com", u64::MAX / 2);
+
+        assert!(test_providers().verify("google", &id_token, LEEWAY).is_err());
+    }
+
+    #[test]
+    fn should_reject_expired_id_token() {
+        let id_token = test_id_token("1234", "https://accounts.google.com", 1);
+
+        assert!(test_providers().verify("google", &id_token, LEEWAY).is_err());
+    }
+
+    #[test]
+    fn should_allow_for_the_providers_clock_only_as_far_as_the_leeway() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let id_token = |exp: u64, iat: u64| {
+            let claims = serde_json::json!({
+                "sub": "1234", "iss": "https://accounts.google.com", "aud": "our-client-id", "exp": exp, "iat": iat,
+            });
+            encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap()
+        };
+
+        // Expired 30s ago, or issued 30s from now, by our clock.
+        assert!(test_providers().verify("google", &id_token(now - 30, now - 3600), LEEWAY).is_ok());
+        assert!(test_providers().verify("google", &id_token(now + 3600, now + 30), LEEWAY).is_ok());
+        assert!(test_providers().verify("google", &id_token(now - 30, now - 3600), Duration::ZERO).is_err());
+
+        let issued_ahead = test_providers().verify("google", &id_token(now + 3600, now + 300), LEEWAY);
+        assert_eq!(issued_ahead, Err(String::from("Error::InvalidIdToken: issued in the future")));
+    }
+
+    #[test]
+    fn should_reject_unknown_provider() {
+        let id_token = test_id_token("1234", "https://accounts.google.com", u64::MAX / 2);
+
+        assert!(test_providers().verify("github", &id_token, LEEWAY).is_err());
+    }
+}
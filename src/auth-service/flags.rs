@@ -0,0 +1,102 @@
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::config::Config;
+
+// The optional behaviours handlers look up when a request comes in, rather than in `Config`, so
+// they can be flipped at runtime (see `SetFeatureFlag`), e.g. on one replica before the others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flag {
+    // Only people holding an invite may sign up.
+    InviteOnly,
+    // RPCs that change accounts are turned away (see `SetMaintenanceMode`).
+    MaintenanceMode,
+    // sign_in refuses users who accepted older terms of service than `terms_version`.
+    RequireCurrentTerms,
+    // sign_in answers sign-ins flagged as unusual (see `SignInLocations`) with MFA_REQUIRED.
+    MfaOnAnomaly,
+    // New users wait in `AccountStatus::PendingApproval` until an admin approves them.
+    ApprovalRequired,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 5] = [
+        Flag::InviteOnly,
+        Flag::MaintenanceMode,
+        Flag::RequireCurrentTerms,
+        Flag::MfaOnAnomaly,
+        Flag::ApprovalRequired,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::InviteOnly => "invite_only",
+            Self::MaintenanceMode => "maintenance_mode",
+            Self::RequireCurrentTerms => "require_current_terms",
+            Self::MfaOnAnomaly => "mfa_on_anomaly",
+            Self::ApprovalRequired => "approval_required",
+        }
+    }
+}
+
+impl FromStr for Flag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|flag| flag.name() == s)
+            .ok_or_else(|| format!("Error::UnknownFeatureFlag: {s}"))
+    }
+}
+
+// Every flag, starting as configured (AUTH_INVITE_ONLY, AUTH_MAINTENANCE_MODE,
+// AUTH_REQUIRE_CURRENT_TERMS, AUTH_MFA_ON_ANOMALY and AUTH_APPROVAL_REQUIRED) until an admin flips it. Changes last until the next restart.
+#[derive(Debug, Default)]
+pub struct FeatureFlags([AtomicBool; Flag::ALL.len()]);
+
+impl FeatureFlags {
+    pub fn from_config(config: &Config) -> Self {
+        let flags = Self::default();
+        flags.set(Flag::InviteOnly, config.invite_only);
+        flags.set(Flag::MaintenanceMode, config.maintenance_mode);
+        flags.set(Flag::RequireCurrentTerms, config.require_current_terms);
+        flags.set(Flag::MfaOnAnomaly, config.mfa_on_anomaly);
+        flags.set(Flag::ApprovalRequired, config.approval_required);
+        flags
+    }
+
+    pub fn is_enabled(&self, flag: Flag) -> bool {
+        self.0[flag as usize].load(Ordering::Relaxed)
+    }
+
+    // Returns whether the flag was on.
+    pub fn set(&self, flag: Flag, enabled: bool) -> bool {
+        self.0[flag as usize].swap(enabled, Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<&'static str, bool> {
+        Flag::ALL.into_iter().map(|flag| (flag.name(), self.is_enabled(flag))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_start_as_configured_and_parse_every_name() {
+        let flags = FeatureFlags::from_config(&Config { invite_only: true, ..Config::default() });
+
+        assert!(flags.is_enabled(Flag::InviteOnly));
+        assert!(!flags.is_enabled(Flag::MaintenanceMode));
+
+        for flag in Flag::ALL {
+            assert_eq!(flag.name().parse(), Ok(flag));
+        }
+        assert!("mfa_required".parse::<Flag>().is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use maxminddb::{geoip2, Reader};
+
+// Where an address is, as far as the GeoIP database knows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    // ISO 3166-1 alpha-2, e.g. "DE".
+    pub country: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Location {
+    // Great-circle distance.
+    pub fn km_to(&self, other: &Location) -> f64 {
+        const EARTH_RADIUS_KM: f64 = 6371.0;
+
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.longitude - self.longitude).to_radians();
+
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+pub trait GeoLookup {
+    // None for private addresses and those the database doesn't place.
+    fn locate(&self, ip: IpAddr) -> Option<Location>;
+}
+
+// A MaxMind GeoIP2 or GeoLite2 City database (AUTH_GEOIP_DB), read into memory on start.
+pub struct MaxMindGeoLookup(Reader<Vec<u8>>);
+
+impl MaxMindGeoLookup {
+    pub fn open(path: &str) -> Result<Self, String> {
+        Reader::open_readfile(path).map(Self).map_err(|e| format!("Error::InvalidGeoIpDatabase: {path}: {e}"))
+    }
+}
+
+impl GeoLookup for MaxMindGeoLookup {
+    fn locate(&self, ip: IpAddr) -> Option<Location> {
+        let city: geoip2::City = self.0.lookup(ip).ok()?;
+        let location = city.location?;
+
+        Some(Location {
+            country: city.country?.iso_code?.to_owned(),
+            latitude: location.latitude?,
+            longitude: location.longitude?,
+        })
+    }
+}
+
+// What is unusual about where a sign in came from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Anomaly {
+    // The first from this country, for a user who signed in from elsewhere before.
+    NewCountry { country: String },
+    // Further from the previous sign in than anyone could have travelled since.
+    ImpossibleTravel { from: String, to: String, km: f64, elapsed: Duration },
+}
+
+impl Anomaly {
+    pub fn describe(&self) -> String {
+        match self {
+            Anomaly::NewCountry { country } => format!("first sign in from {country}"),
+            Anomaly::ImpossibleTravel { from, to, km, elapsed } => {
+                format!("{km:.0} km from {from} to {to} in {} minutes", elapsed.as_secs() / 60)
+            }
+        }
+    }
+}
+
+// GeoIP locations are off by up to a few hundred kilometres, so nothing closer is flagged.
+const MIN_TRAVEL_KM: f64 = 500.0;
+
+#[derive(Default)]
+struct SeenFrom {
+    countries: HashSet<String>,
+    last: Option<(Location, SystemTime)>,
+}
+
+// Where each user signed in from so far: the countries, and the last location. Kept in memory,
+// so a restart only means a few more sign-ins flagged as from a new country.
+#[derive(Default)]
+pub struct SignInLocations(Mutex<HashMap<String, SeenFrom>>);
+
+impl SignInLocations {
+    // Signing in from `location` at `at`, compared to the user's earlier sign-ins. A user's first
+    // sign in is never unusual.
+    pub fn anomalies(&self, user_uuid: &str, location: &Location, at: SystemTime, max_speed_kmh: f64) -> Vec<Anomaly> {
+        let users = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(seen) = users.get(user_uuid) else { return Vec::new() };
+
+        let mut anomalies = Vec::new();
+        if !seen.countries.contains(&location.country) {
+            anomalies.push(Anomaly::NewCountry { country: location.country.clone() });
+        }
+        if let Some((last, last_at)) = &seen.last {
+            let km = last.km_to(location);
+            let elapsed = at.duration_since(*last_at).unwrap_or_default();
+
+            if km > MIN_TRAVEL_KM && km > max_speed_kmh * elapsed.as_secs_f64() / 3600.0 {
+                anomalies.push(Anomaly::ImpossibleTravel {
+                    from: last.country.clone(),
+                    to: location.country.clone(),
+                    km,
+                    elapsed,
+                });
+            }
+        }
+        anomalies
+    }
+
+    // Once the sign in went through.
+    pub fn remember(&self, user_uuid: &str, location: Location, at: SystemTime) {
+        let mut users = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let seen = users.entry(user_uuid.to_owned()).or_default();
+
+        seen.countries.insert(location.country.clone());
+        seen.last = Some((location, at));
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Places the addresses it was given.
+    #[derive(Default)]
+    pub struct FixedGeoLookup(pub HashMap<IpAddr, Location>);
+
+    impl GeoLookup for FixedGeoLookup {
+        fn locate(&self, ip: IpAddr) -> Option<Location> {
+            self.0.get(&ip).cloned()
+        }
+    }
+
+    pub fn berlin() -> Location {
+        Location { country: "DE".to_owned(), latitude: 52.52, longitude: 13.40 }
+    }
+
+    pub fn sydney() -> Location {
+        Location { country: "AU".to_owned(), latitude: -33.87, longitude: 151.21 }
+    }
+
+    #[test]
+    fn should_flag_new_countries_and_impossible_travel() {
+        let locations = SignInLocations::default();
+        let now = SystemTime::now();
+        let hamburg = Location { latitude: 53.55, longitude: 9.99, ..berlin() };
+
+        assert!(locations.anomalies("123456", &berlin(), now, 1000.0).is_empty());
+        locations.remember("123456", berlin(), now);
+
+        // 255 km within the minute: closer than GeoIP can tell apart.
+        assert!(locations.anomalies("123456", &hamburg, now + Duration::from_secs(60), 1000.0).is_empty());
+
+        let anomalies = locations.anomalies("123456", &sydney(), now + Duration::from_secs(60 * 60), 1000.0);
+        assert_eq!(anomalies.len(), 2);
+        assert_eq!(anomalies[0], Anomaly::NewCountry { country: "AU".to_owned() });
+        assert!(matches!(&anomalies[1], Anomaly::ImpossibleTravel { km, .. } if (*km - 16_000.0).abs() < 100.0));
+
+        // A day later, the flight could have been taken; the country is still new.
+        let anomalies = locations.anomalies("123456", &sydney(), now + Duration::from_secs(24 * 60 * 60), 1000.0);
+        assert_eq!(anomalies, vec![Anomaly::NewCountry { country: "AU".to_owned() }]);
+    }
+}
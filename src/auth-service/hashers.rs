@@ -0,0 +1,464 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+use argon2::{Argon2, Params, Version};
+use pbkdf2::{
+    password_hash::{Ident, Output, ParamsString, PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString},
+    Algorithm,
+};
+use rand_core::OsRng;
+use scrypt::Scrypt;
+
+use crate::config::Config;
+use crate::users::PasswordPolicy;
+
+// A hasher failing to hash, in its own words.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("Failed to hash password.\n{0}")]
+pub struct HashError(pub String);
+
+// A way of hashing passwords. Hashes are PHC strings (`$<algorithm>$<params>$<salt>$<hash>`), so
+// each says how it was made: a deployment can change hashers without touching the stored ones,
+// which are moved over as their users sign in (see `UsersOps::upgrade_password_hash`).
+pub trait PasswordHasher: Send + Sync {
+    // Whether hashes with this PHC identifier (e.g. "pbkdf2-sha256", "argon2id") are its own.
+    fn handles(&self, algorithm: &str) -> bool;
+    fn hash(&self, password: &str) -> Result<String, HashError>;
+    fn verify(&self, password_hash: &PasswordHash, password: &str) -> bool;
+    // One of its own hashes, made with weaker parameters than it uses now.
+    fn is_outdated(&self, password_hash: &PasswordHash) -> bool;
+}
+
+// What new passwords are hashed with (AUTH_PASSWORD_HASH_ALGORITHM), by PHC identifier.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Pbkdf2(Algorithm),
+    Argon2id,
+    Bcrypt,
+    Scrypt,
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        Self::Pbkdf2(PasswordPolicy::default().algorithm)
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "argon2id" => Ok(Self::Argon2id),
+            "bcrypt" => Ok(Self::Bcrypt),
+            "scrypt" => Ok(Self::Scrypt),
+            _ => s.parse().map(Self::Pbkdf2).map_err(|_| format!("Error::UnknownHashAlgorithm: {s}")),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    // What AUTH_PASSWORD_HASH_ROUNDS is, unless set: PBKDF2's rounds, argon2id's passes, bcrypt's
+    // cost and scrypt's log2 of N.
+    pub fn default_rounds(&self) -> u32 {
+        match self {
+            Self::Pbkdf2(_) => PasswordPolicy::default().rounds,
+            Self::Argon2id => Argon2idHasher::default().passes,
+            Self::Bcrypt => BcryptHasher::default().cost,
+            Self::Scrypt => ScryptHasher::default().log_n.into(),
+        }
+    }
+
+    // Whether the hasher can take that many rounds: bcrypt's cost is 4 to 31, scrypt's N has to
+    // fit in memory addressable at all.
+    pub fn takes_rounds(&self, rounds: u32) -> bool {
+        match self {
+            Self::Pbkdf2(_) | Self::Argon2id => rounds > 0,
+            Self::Bcrypt => (BCRYPT_MIN_COST..=BCRYPT_MAX_COST).contains(&rounds),
+            Self::Scrypt => u8::try_from(rounds).is_ok_and(|log_n| log_n > 0 && scrypt_params(log_n).is_ok()),
+        }
+    }
+}
+
+// The hasher the settings pick for new passwords: PBKDF2 with AUTH_PASSWORD_HASH_ROUNDS, argon2id
+// with as many passes over AUTH_PASSWORD_HASH_MEMORY_KIB, or bcrypt or scrypt at that cost.
+pub fn password_hasher(config: &Config) -> Arc<dyn PasswordHasher> {
+    match config.password_hash_algorithm {
+        HashAlgorithm::Pbkdf2(algorithm) => Arc::new(PasswordPolicy { algorithm, rounds: config.password_hash_rounds }),
+        HashAlgorithm::Argon2id => Arc::new(Argon2idHasher {
+            memory_kib: config.password_hash_memory_kib,
+            passes: config.password_hash_rounds,
+        }),
+        HashAlgorithm::Bcrypt => Arc::new(BcryptHasher { cost: config.password_hash_rounds }),
+        HashAlgorithm::Scrypt => Arc::new(ScryptHasher { log_n: config.password_hash_rounds.try_into().unwrap_or(u8::MAX) }),
+    }
+}
+
+// argon2id, memory-hard where PBKDF2 isn't. Like PBKDF2's rounds, the memory and passes a hash
+// was made with are in it, so raising them moves hashes over as their users sign in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Argon2idHasher {
+    pub memory_kib: u32,
+    pub passes: u32,
+}
+
+impl Default for Argon2idHasher {
+    fn default() -> Self {
+        Self { memory_kib: Params::DEFAULT_M_COST, passes: Params::DEFAULT_T_COST }
+    }
+}
+
+impl PasswordHasher for Argon2idHasher {
+    fn handles(&self, algorithm: &str) -> bool {
+        algorithm == argon2::Algorithm::Argon2id.ident().as_str()
+    }
+
+    fn hash(&self, password: &str) -> Result<String, HashError> {
+        let params = Params::new(self.memory_kib, self.passes, Params::DEFAULT_P_COST, None).map_err(|e| HashError(e.to_string()))?;
+        let salt = SaltString::generate(&mut OsRng);
+
+        Argon2::new(argon2::Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| HashError(e.to_string()))
+    }
+
+    // The parameters are read from the hash.
+    fn verify(&self, password_hash: &PasswordHash, password: &str) -> bool {
+        Argon2::default().verify_password(password.as_bytes(), password_hash).is_ok()
+    }
+
+    fn is_outdated(&self, password_hash: &PasswordHash) -> bool {
+        let Ok(params) = Params::try_from(password_hash) else { return false };
+        params.m_cost() < self.memory_kib || params.t_cost() < self.passes
+    }
+}
+
+const BCRYPT_MIN_COST: u32 = 4;
+const BCRYPT_MAX_COST: u32 = 31;
+// bcrypt only reads this much of a password, its terminating NUL included.
+const BCRYPT_MAX_INPUT: usize = 72;
+const BCRYPT_SALT_LEN: usize = 16;
+// Of the 24 bytes bcrypt makes, the last is left out, as every bcrypt implementation does.
+const BCRYPT_OUTPUT_LEN: usize = 23;
+
+// bcrypt, kept as a PHC string like the others (`$bcrypt$r=<cost>$<salt>$<hash>`) rather than
+// the `$2b$` format, which doesn't parse as one. Only the first 72 bytes of a password count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BcryptHasher {
+    pub cost: u32,
+}
+
+impl Default for BcryptHasher {
+    fn default() -> Self {
+        Self { cost: 12 }
+    }
+}
+
+impl BcryptHasher {
+    fn output(cost: u32, salt: [u8; BCRYPT_SALT_LEN], password: &str) -> Result<Output, HashError> {
+        if !(BCRYPT_MIN_COST..=BCRYPT_MAX_COST).contains(&cost) {
+            return Err(HashError(format!("Error::InvalidBcryptCost: {cost}")));
+        }
+        let mut input: Vec<u8> = password.bytes().chain([0]).collect();
+        input.truncate(BCRYPT_MAX_INPUT);
+
+        Output::new(&bcrypt::bcrypt(cost, salt, &input)[..BCRYPT_OUTPUT_LEN]).map_err(|e| HashError(e.to_string()))
+    }
+
+    fn cost_of(password_hash: &PasswordHash) -> Option<u32> {
+        password_hash.params.get_decimal("r")
+    }
+}
+
+impl PasswordHasher for BcryptHasher {
+    fn handles(&self, algorithm: &str) -> bool {
+        algorithm == "bcrypt"
+    }
+
+    fn hash(&self, password: &str) -> Result<String, HashError> {
+        let salt = SaltString::generate(&mut OsRng);
+        let mut salt_bytes = [0u8; BCRYPT_SALT_LEN];
+        salt.decode_b64(&mut salt_bytes).map_err(|e| HashError(e.to_string()))?;
+        let mut params = ParamsString::new();
+        params.add_decimal("r", self.cost).map_err(|e| HashError(e.to_string()))?;
+
+        let password_hash = PasswordHash {
+            algorithm: Ident::new("bcrypt").map_err(|e| HashError(e.to_string()))?,
+            version: None,
+            params,
+            salt: Some(salt.as_salt()),
+            hash: Some(Self::output(self.cost, salt_bytes, password)?),
+        };
+        Ok(password_hash.to_string())
+    }
+
+    // The cost is read from the hash; outputs are compared in constant time.
+    fn verify(&self, password_hash: &PasswordHash, password: &str) -> bool {
+        let (Some(cost), Some(salt), Some(hash)) = (Self::cost_of(password_hash), password_hash.salt, password_hash.hash) else {
+            return false;
+        };
+        let mut salt_bytes = [0u8; BCRYPT_SALT_LEN];
+        if salt.decode_b64(&mut salt_bytes).map(<[u8]>::len) != Ok(BCRYPT_SALT_LEN) {
+            return false;
+        }
+        Self::output(cost, salt_bytes, password).is_ok_and(|output| output == hash)
+    }
+
+    fn is_outdated(&self, password_hash: &PasswordHash) -> bool {
+        Self::cost_of(password_hash).is_some_and(|cost| cost < self.cost)
+    }
+}
+
+// scrypt's parameters for N = 2^log_n, with the usual block size (8) and no parallelism.
+fn scrypt_params(log_n: u8) -> Result<scrypt::Params, HashError> {
+    scrypt::Params::new(log_n, scrypt::Params::RECOMMENDED_R, scrypt::Params::RECOMMENDED_P, scrypt::Params::RECOMMENDED_LEN)
+        .map_err(|e| HashError(e.to_string()))
+}
+
+// scrypt, memory-hard like argon2id: N = 2^log_n blocks of 1 KiB. Raising log_n moves hashes over
+// as their users sign in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScryptHasher {
+    pub log_n: u8,
+}
+
+impl Default for ScryptHasher {
+    fn default() -> Self {
+        Self { log_n: scrypt::Params::RECOMMENDED_LOG_N }
+    }
+}
+
+impl PasswordHasher for ScryptHasher {
+    fn handles(&self, algorithm: &str) -> bool {
+        algorithm == scrypt::ALG_ID.as_str()
+    }
+
+    fn hash(&self, password: &str) -> Result<String, HashError> {
+        let salt = SaltString::generate(&mut OsRng);
+
+        Scrypt
+            .hash_password_customized(password.as_bytes(), None, None, scrypt_params(self.log_n)?, &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| HashError(e.to_string()))
+    }
+
+    // The parameters are read from the hash.
+    fn verify(&self, password_hash: &PasswordHash, password: &str) -> bool {
+        Scrypt.verify_password(password.as_bytes(), password_hash).is_ok()
+    }
+
+    fn is_outdated(&self, password_hash: &PasswordHash) -> bool {
+        let Ok(params) = scrypt::Params::try_from(password_hash) else { return false };
+        params.log_n() < self.log_n
+    }
+}
+
+// The hasher new passwords get, and those that only verify the hashes of earlier ones. PBKDF2
+// (`PasswordPolicy`) always verifies, as it was the only hasher before, and so do the others, so
+// a deployment can go back from any of them.
+#[derive(Clone)]
+pub struct PasswordHashers {
+    current: Arc<dyn PasswordHasher>,
+    previous: Vec<Arc<dyn PasswordHasher>>,
+}
+
+impl Default for PasswordHashers {
+    fn default() -> Self {
+        Self::new(Arc::new(PasswordPolicy::default()))
+    }
+}
+
+impl fmt::Debug for PasswordHashers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordHashers").finish_non_exhaustive()
+    }
+}
+
+impl PasswordHashers {
+    pub fn new(current: Arc<dyn PasswordHasher>) -> Self {
+        Self {
+            current,
+            previous: vec![
+                Arc::new(PasswordPolicy::default()),
+                Arc::new(Argon2idHasher::default()),
+                Arc::new(BcryptHasher::default()),
+                Arc::new(ScryptHasher::default()),
+            ],
+        }
+    }
+
+    // Verifies the hashes of a hasher no longer current, until they are all moved over.
+    pub fn with_previous(mut self, hasher: Arc<dyn PasswordHasher>) -> Self {
+        self.previous.insert(0, hasher);
+        self
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, HashError> {
+        self.current.hash(password)
+    }
+
+    // Hashes no hasher handles, or that can't be read, never verify.
+    pub fn verify(&self, password_hash: &str, password: &str) -> bool {
+        let Ok(parsed_hash) = PasswordHash::new(password_hash) else { return false };
+        self.hasher_of(&parsed_hash).is_some_and(|hasher| hasher.verify(&parsed_hash, password))
+    }
+
+    // Made by another hasher, or with weaker parameters. Hashes that can't be verified are left
+    // alone: there is nothing to move them over from.
+    pub fn is_outdated(&self, password_hash: &str) -> bool {
+        let Ok(parsed_hash) = PasswordHash::new(password_hash) else { return false };
+        match self.current.handles(parsed_hash.algorithm.as_str()) {
+            true => self.current.is_outdated(&parsed_hash),
+            false => self.hasher_of(&parsed_hash).is_some(),
+        }
+    }
+
+    fn hasher_of(&self, password_hash: &PasswordHash) -> Option<&Arc<dyn PasswordHasher>> {
+        let algorithm = password_hash.algorithm.as_str();
+        std::iter::once(&self.current).chain(&self.previous).find(|hasher| hasher.handles(algorithm))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Not a hash at all, for trying out another hasher: `$plain$v=<version>$<password>`.
+    pub struct PlainHasher {
+        pub version: u32,
+    }
+
+    impl PasswordHasher for PlainHasher {
+        fn handles(&self, algorithm: &str) -> bool {
+            algorithm == "plain"
+        }
+
+        fn hash(&self, password: &str) -> Result<String, HashError> {
+            Ok(format!("$plain$v={}${}", self.version, password))
+        }
+
+        fn verify(&self, password_hash: &PasswordHash, password: &str) -> bool {
+            password_hash.salt.is_some_and(|salt| salt.as_str() == password)
+        }
+
+        fn is_outdated(&self, password_hash: &PasswordHash) -> bool {
+            password_hash.version.is_some_and(|version| version < self.version)
+        }
+    }
+
+    #[test]
+    fn should_hash_with_argon2id_and_move_pbkdf2_hashes_over() {
+        // Cheap, for the tests; the defaults take a while unoptimized.
+        let argon2id = Argon2idHasher { memory_kib: 1_024, passes: 1 };
+        let pbkdf2 = PasswordPolicy { algorithm: Algorithm::Pbkdf2Sha256, rounds: 1_000 };
+        let old_hash = pbkdf2.hash("password").unwrap();
+
+        let hashers = PasswordHashers::new(Arc::new(argon2id));
+        let new_hash = hashers.hash("password").unwrap();
+
+        assert!(new_hash.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+        assert!(hashers.verify(&new_hash, "password"));
+        assert!(!hashers.verify(&new_hash, "wrong"));
+        assert!(!hashers.is_outdated(&new_hash));
+        assert!(hashers.verify(&old_hash, "password"));
+        assert!(hashers.is_outdated(&old_hash));
+
+        let stronger = PasswordHashers::new(Arc::new(Argon2idHasher { memory_kib: 2_048, passes: 1 }));
+        assert!(stronger.is_outdated(&new_hash));
+
+        // And back: argon2id hashes still verify under PBKDF2, to be moved over in turn.
+        let hashers = PasswordHashers::new(Arc::new(pbkdf2));
+        assert!(hashers.verify(&new_hash, "password"));
+        assert!(hashers.is_outdated(&new_hash));
+    }
+
+    #[test]
+    fn should_hash_with_bcrypt_and_move_pbkdf2_hashes_over() {
+        let pbkdf2 = PasswordPolicy { algorithm: Algorithm::Pbkdf2Sha256, rounds: 1_000 };
+        let old_hash = pbkdf2.hash("password").unwrap();
+
+        let hashers = PasswordHashers::new(Arc::new(BcryptHasher { cost: 4 }));
+        let new_hash = hashers.hash("password").unwrap();
+
+        assert!(new_hash.starts_with("$bcrypt$r=4$"));
+        assert_eq!(PasswordHash::new(&new_hash).unwrap().to_string(), new_hash);
+        assert!(hashers.verify(&new_hash, "password"));
+        assert!(!hashers.verify(&new_hash, "wrong"));
+        assert!(!hashers.is_outdated(&new_hash));
+        assert!(hashers.verify(&old_hash, "password"));
+        assert!(hashers.is_outdated(&old_hash));
+        assert!(PasswordHashers::new(Arc::new(BcryptHasher { cost: 5 })).is_outdated(&new_hash));
+
+        // As bcrypt goes, only the first 72 bytes count.
+        let long_hash = hashers.hash(&"x".repeat(72)).unwrap();
+        assert!(hashers.verify(&long_hash, &"x".repeat(80)));
+        assert!(!hashers.verify(&long_hash, &"x".repeat(71)));
+
+        let hashers = PasswordHashers::new(Arc::new(pbkdf2));
+        assert!(hashers.verify(&new_hash, "password"));
+        assert!(hashers.is_outdated(&new_hash));
+    }
+
+    #[test]
+    fn should_hash_with_scrypt_and_move_pbkdf2_hashes_over() {
+        let pbkdf2 = PasswordPolicy { algorithm: Algorithm::Pbkdf2Sha256, rounds: 1_000 };
+        let old_hash = pbkdf2.hash("password").unwrap();
+
+        let hashers = PasswordHashers::new(Arc::new(ScryptHasher { log_n: 4 }));
+        let new_hash = hashers.hash("password").unwrap();
+
+        assert!(new_hash.starts_with("$scrypt$ln=4,r=8,p=1$"));
+        assert_eq!(PasswordHash::new(&new_hash).unwrap().to_string(), new_hash);
+        assert!(hashers.verify(&new_hash, "password"));
+        assert!(!hashers.verify(&new_hash, "wrong"));
+        assert!(!hashers.is_outdated(&new_hash));
+        assert!(hashers.verify(&old_hash, "password"));
+        assert!(hashers.is_outdated(&old_hash));
+        assert!(PasswordHashers::new(Arc::new(ScryptHasher { log_n: 5 })).is_outdated(&new_hash));
+
+        let hashers = PasswordHashers::new(Arc::new(pbkdf2));
+        assert!(hashers.verify(&new_hash, "password"));
+        assert!(hashers.is_outdated(&new_hash));
+    }
+
+    #[test]
+    fn should_parse_hash_algorithms() {
+        assert_eq!("argon2id".parse(), Ok(HashAlgorithm::Argon2id));
+        assert_eq!("bcrypt".parse(), Ok(HashAlgorithm::Bcrypt));
+        assert_eq!("scrypt".parse(), Ok(HashAlgorithm::Scrypt));
+        assert_eq!("pbkdf2-sha512".parse(), Ok(HashAlgorithm::Pbkdf2(Algorithm::Pbkdf2Sha512)));
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn should_only_take_rounds_the_hasher_can() {
+        assert!(HashAlgorithm::Bcrypt.takes_rounds(HashAlgorithm::Bcrypt.default_rounds()));
+        assert!(!HashAlgorithm::Bcrypt.takes_rounds(3));
+        assert!(!HashAlgorithm::Bcrypt.takes_rounds(600_000));
+        assert!(HashAlgorithm::Scrypt.takes_rounds(HashAlgorithm::Scrypt.default_rounds()));
+        assert!(!HashAlgorithm::Scrypt.takes_rounds(0));
+        assert!(!HashAlgorithm::Scrypt.takes_rounds(600_000));
+        assert!(!HashAlgorithm::Argon2id.takes_rounds(0));
+    }
+
+    #[test]
+    fn should_verify_with_the_hasher_of_each_hash() {
+        let pbkdf2 = PasswordPolicy { algorithm: Algorithm::Pbkdf2Sha256, rounds: 1_000 };
+        let old_hash = pbkdf2.hash("password").unwrap();
+
+        let hashers = PasswordHashers::new(Arc::new(PlainHasher { version: 2 }));
+        let new_hash = hashers.hash("password").unwrap();
+
+        assert_eq!(new_hash, "$plain$v=2$password");
+        assert!(hashers.verify(&new_hash, "password"));
+        assert!(!hashers.verify(&new_hash, "wrong"));
+        assert!(hashers.verify(&old_hash, "password"));
+        assert!(!hashers.verify("$unknown$v=1$password", "password"));
+        assert!(!hashers.verify("not a hash", "password"));
+
+        assert!(!hashers.is_outdated(&new_hash));
+        assert!(hashers.is_outdated("$plain$v=1$password"));
+        assert!(hashers.is_outdated(&old_hash));
+        assert!(!hashers.is_outdated("$unknown$v=1$password"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use tonic::Request;
+
+use crate::auth::authentication::*;
+
+// The error message catalogs, built in; see messages/en.txt for the format.
+const CATALOGS: &[(&str, &str)] = &[
+    ("en", include_str!("messages/en.txt")),
+    ("de", include_str!("messages/de.txt")),
+    ("es", include_str!("messages/es.txt")),
+    ("fr", include_str!("messages/fr.txt")),
+];
+
+const FALLBACK: &str = "en";
+
+type Catalog = HashMap<&'static str, &'static str>;
+
+fn catalogs() -> &'static HashMap<&'static str, Catalog> {
+    static PARSED: OnceLock<HashMap<&'static str, Catalog>> = OnceLock::new();
+
+    PARSED.get_or_init(|| {
+        CATALOGS
+            .iter()
+            .map(|(language, catalog)| {
+                let messages = catalog
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .filter_map(|line| line.split_once('='))
+                    .map(|(key, message)| (key.trim(), message.trim()))
+                    .collect();
+                (*language, messages)
+            })
+            .collect()
+    })
+}
+
+// The language error messages are written in, picked from the `accept-language` metadata.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Locale {
+    language: &'static str,
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Self { language: FALLBACK }
+    }
+}
+
+impl Locale {
+    pub fn from_request<T>(request: &Request<T>) -> Self {
+        request
+            .metadata()
+            .get("accept-language")
+            .and_then(|accept_language| accept_language.to_str().ok())
+            .map(Self::negotiate)
+            .unwrap_or_default()
+    }
+
+    // The language we have with the highest weight in e.g. "de-CH, fr;q=0.8, en;q=0.5", going by
+    // the primary subtag only.
+    pub fn negotiate(accept_language: &str) -> Self {
+        let mut ranges: Vec<(&str, f32)> = accept_language
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let weight = match parts.find_map(|part| part.trim().strip_prefix("q=")) {
+                    Some(weight) => weight.trim().parse().ok()?,
+                    None => 1.0,
+                };
+                Some((tag, weight))
+            })
+            .filter(|(_, weight)| *weight > 0.0)
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges
+            .into_iter()
+            .find_map(|(tag, _)| {
+                let primary = tag.split('-').next()?.to_ascii_lowercase();
+                catalogs().get_key_value(primary.as_str()).map(|(language, _)| Self { language })
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn message(&self, rpc: &str, status_code: StatusCode) -> &'static str {
+        let code = status_code.as_str_name();
+        let rpc_code = format!("{rpc}.{code}");
+
+        [self.language, FALLBACK]
+            .iter()
+            .flat_map(|language| [rpc_code.as_str(), code].map(|key| catalogs()[language].get(key)))
+            .flatten()
+            .copied()
+            .next()
+            .unwrap_or(code)
+    }
+
+    // Fills in the error message of a reply about to be sent; successes have none.
+    pub fn localize<T: ErrorMessage>(&self, mut reply: T) -> T {
+        let status_code = StatusCode::from_i32(reply.status_code()).unwrap_or(StatusCode::Failure);
+        if status_code != StatusCode::Success {
+            reply.set_error_message(self.message(T::RPC, status_code).to_owned());
+        }
+        reply
+    }
+}
+
+// The replies with a status code, and so an error message.
+pub trait ErrorMessage {
+    const RPC: &'static str;
+
+    fn status_code(&self) -> i32;
+    fn set_error_message(&mut self, error_message: String);
+}
+
+macro_rules! error_message {
+    ($($response:ty => $rpc:literal),* $(,)?) => {
+        $(impl ErrorMessage for $response {
+            const RPC: &'static str = $rpc;
+
+            fn status_code(&self) -> i32 {
+                self.status_code
+            }
+
+            fn set_error_message(&mut self, error_message: String) {
+                self.error_message = error_message;
+            }
+        })*
+    };
+}
+
+error_message!(
+    SignUpResponse => "SignUp",
+    SignInResponse => "SignIn",
+    SignOutResponse => "SignOut",
+    ExchangeExternalTokenResponse => "ExchangeExternalToken",
+    RequestMagicLinkResponse => "RequestMagicLink",
+    ConsumeMagicLinkResponse => "ConsumeMagicLink",
+    ValidateSessionResponse => "ValidateSession",
+    CreateGuestSessionResponse => "CreateGuestSession",
+    UpgradeGuestSessionResponse => "UpgradeGuestSession",
+    ChangeUsernameResponse => "ChangeUsername",
+    ChangePasswordResponse => "ChangePassword",
+    SetUserMetadataResponse => "SetUserMetadata",
+    GetUserMetadataResponse => "GetUserMetadata",
+    ExportMyDataResponse => "ExportMyData",
+    DeleteAccountResponse => "DeleteAccount",
+    AcceptTermsResponse => "AcceptTerms",
+    CreateDelegationTokenResponse => "CreateDelegationToken",
+    ListRevokedSessionsResponse => "ListRevokedSessions",
+    GetServerInfoResponse => "GetServerInfo",
+    StartWebAuthnRegistrationResponse => "StartWebAuthnRegistration",
+    FinishWebAuthnRegistrationResponse => "FinishWebAuthnRegistration",
+    StartWebAuthnAssertionResponse => "StartWebAuthnAssertion",
+    FinishWebAuthnAssertionResponse => "FinishWebAuthnAssertion",
+    SuspendUserResponse => "SuspendUser",
+    UnsuspendUserResponse => "UnsuspendUser",
+    SoftDeleteUserResponse => "SoftDeleteUser",
+    RestoreUserResponse => "RestoreUser",
+    ApproveUserResponse => "ApproveUser",
+    RejectUserResponse => "RejectUser",
+    SetUserScopesResponse => "SetUserScopes",
+    RevokeLongLivedSessionsResponse => "RevokeLongLivedSessions",
+    RevokeSessionsResponse => "RevokeSessions",
+    ListSessionsResponse => "ListSessions",
+    ExportUserDataResponse => "ExportUserData",
+    ListUsersResponse => "ListUsers",
+    ListPendingUsersResponse => "ListPendingUsers",
+    CreateInviteResponse => "CreateInvite",
+    ReserveUsernameResponse => "ReserveUsername",
+    SetHoneypotUsernameResponse => "SetHoneypotUsername",
+    SetMaintenanceModeResponse => "SetMaintenanceMode",
+    SetFeatureFlagResponse => "SetFeatureFlag",
+    SetLogLevelResponse => "SetLogLevel",
+    GetStatsResponse => "GetStats",
+    ListRecentAuditEventsResponse => "ListRecentAuditEvents",
+    ListThrottledSourcesResponse => "ListThrottledSources",
+    GetRateLimitStateResponse => "GetRateLimitState",
+    ClearLockoutResponse => "ClearLockout",
+    SetDebugCaptureResponse => "SetDebugCapture",
+    ListDebugCapturesResponse => "ListDebugCaptures",
+    GetEffectiveConfigResponse => "GetEffectiveConfig",
+    ReplayProjectionResponse => "ReplayProjection",
+    ReplicateSessionEventsResponse => "ReplicateSessionEvents",
+);
+
+// Shaped after RFC 7662, introspection has no status code: an unknown token is just inactive.
+impl ErrorMessage for IntrospectTokenResponse {
+    const RPC: &'static str = "IntrospectToken";
+
+    fn status_code(&self) -> i32 {
+        StatusCode::Success as i32
+    }
+
+    fn set_error_message(&mut self, _error_message: String) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_negotiate_supported_language() {
+        assert_eq!(Locale::negotiate("de-CH, fr;q=0.8, en;q=0.5"), Locale { language: "de" });
+        assert_eq!(Locale::negotiate("ja, fr;q=0.8, de;q=0.9"), Locale { language: "de" });
+        assert_eq!(Locale::negotiate("ja, *;q=0.5"), Locale::default());
+        assert_eq!(Locale::negotiate("fr;q=0, es"), Locale { language: "es" });
+    }
+
+    #[test]
+    fn should_prefer_rpc_messages_and_fall_back_to_english() {
+        let german = Locale::negotiate("de");
+        assert_eq!(german.message("SignIn", StatusCode::Failure), "Falscher Benutzername oder falsches Passwort.");
+        assert_eq!(german.message("SignOut", StatusCode::Failure), "Die Anfrage ist fehlgeschlagen.");
+
+        let reply = german.localize(SignInResponse::failure(StatusCode::AccountSuspended));
+        assert_eq!(reply.error_message, "Dieses Konto ist gesperrt.");
+        let reply = german.localize(SignInResponse::success("123456".to_owned(), "token".to_owned()));
+        assert!(reply.error_message.is_empty());
+    }
+
+    #[test]
+    fn every_catalog_should_only_have_known_keys() {
+        let english = &catalogs()[FALLBACK];
+        for (language, catalog) in catalogs() {
+            for key in catalog.keys() {
+                let code = key.rsplit('.').next().unwrap();
+                assert!(StatusCode::from_str_name(code).is_some(), "{language}: unknown status code in {key}");
+                assert!(english.contains_key(key), "{language}: {key} is missing in English");
+            }
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use auth_ids::Ids;
+
+// Single-use codes letting someone sign up while the service is invite-only.
+pub trait InvitesOps {
+    fn create_invite(&mut self, ttl: Duration) -> Invite;
+    // Fails if the code is unknown, expired or used up.
+    fn check_invite(&self, code: &str) -> Result<(), String>;
+    fn redeem_invite(&mut self, code: &str, redeemed_by: &str) -> Result<(), String>;
+}
+
+#[derive(Clone, Debug)]
+pub struct Invite {
+    pub code: String,
+    pub expires_at: SystemTime,
+    pub redeemed_at: Option<SystemTime>,
+    // The username that signed up with it.
+    pub redeemed_by: Option<String>,
+}
+
+#[derive(Default)]
+pub struct InvitesImpl {
+    code_to_invite: HashMap<String, Invite>,
+    ids: Ids,
+}
+
+impl InvitesImpl {
+    pub fn with_id_generator(mut self, ids: Ids) -> Self {
+        self.ids = ids;
+        self
+    }
+}
+
+impl InvitesOps for InvitesImpl {
+    fn create_invite(&mut self, ttl: Duration) -> Invite {
+        let now = SystemTime::now();
+
+        let invite = Invite {
+            code: self.ids.new_id().simple().to_string(),
+            expires_at: now + ttl,
+            redeemed_at: None,
+            redeemed_by: None,
+        };
+
+        self.code_to_invite.insert(invite.code.clone(), invite.clone());
+
+        invite
+    }
+
+    fn check_invite(&self, code: &str) -> Result<(), String> {
+        match self.code_to_invite.get(code) {
+            None => Err(String::from("Error::UnknownInvite")),
+            Some(invite) if invite.redeemed_at.is_some() => Err(String::from("Error::InviteAlreadyRedeemed")),
+            Some(invite) if invite.expires_at <= SystemTime::now() => Err(String::from("Error::InviteExpired")),
+            Some(_) => Ok(()),
+        }
+    }
+
+    fn redeem_invite(&mut self, code: &str, redeemed_by: &str) -> Result<(), String> {
+        self.check_invite(code)?;
+
+        let invite = self.code_to_invite.get_mut(code).ok_or_else(|| String::from("Error::UnknownInvite"))?;
+        invite.redeemed_at = Some(SystemTime::now());
+        invite.redeemed_by = Some(redeemed_by.to_owned());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_redeem_invite_once() {
+        let mut invites_service = InvitesImpl::default();
+        let invite = invites_service.create_invite(Duration::from_secs(60));
+
+        assert!(invites_service.check_invite(&invite.code).is_ok());
+        assert!(invites_service.redeem_invite(&invite.code, "jdoe").is_ok());
+        assert!(invites_service.redeem_invite(&invite.code, "jdoe").is_err());
+        assert_eq!(
+            invites_service.code_to_invite[&invite.code].redeemed_by.as_deref(),
+            Some("jdoe")
+        );
+    }
+
+    #[test]
+    fn should_reject_expired_or_unknown_invite() {
+        let mut invites_service = InvitesImpl::default();
+        let invite = invites_service.create_invite(Duration::ZERO);
+
+        assert!(invites_service.check_invite(&invite.code).is_err());
+        assert!(invites_service.check_invite("unknown").is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    fs,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use tonic::{Request, Status};
+use tracing::info;
+
+use crate::{error::Error, peer::PeerInfo};
+
+// An address block such as "10.0.0.0/8" or "2001:db8::/32". A bare address is a block of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    // On a dual-stack socket IPv4 clients show up as IPv4-mapped IPv6 addresses (::ffff:a.b.c.d),
+    // which are matched as the IPv4 addresses they are.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Error::InvalidCidr: {s}");
+
+        let (network, prefix_len) = s.split_once('/').unwrap_or((s, ""));
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            "" => max_prefix_len,
+            prefix_len => prefix_len.parse().map_err(|_| invalid())?,
+        };
+
+        if prefix_len > max_prefix_len {
+            return Err(invalid());
+        }
+
+        Ok(Self { network, prefix_len })
+    }
+}
+
+// A source is refused when a deny rule matches it, or when there are allow rules and none of
+// them matches it. No rules at all lets everyone in.
+#[derive(Clone, Debug, Default)]
+pub struct IpRules {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl IpRules {
+    // Requests without a peer address (e.g. over a unix socket) only pass when nothing is
+    // allowlisted.
+    pub fn is_allowed(&self, ip: Option<IpAddr>) -> bool {
+        match ip {
+            Some(ip) => {
+                !self.deny.iter().any(|cidr| cidr.contains(ip))
+                    && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+            }
+            None => self.allow.is_empty(),
+        }
+    }
+}
+
+// One set of rules for the public `Auth` RPCs, one for `AuthAdmin`.
+#[derive(Clone, Debug, Default)]
+pub struct AccessLists {
+    pub auth: IpRules,
+    pub admin: IpRules,
+}
+
+impl FromStr for AccessLists {
+    type Err = String;
+
+    // One rule per line, "<auth|admin> <allow|deny> <cidr>". Blank lines and lines starting
+    // with '#' are ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut access_lists = Self::default();
+
+        for line in s.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let invalid = || format!("Error::InvalidIpRule: {line}");
+
+            let [service, action, cidr] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(invalid());
+            };
+
+            let rules = match service {
+                "auth" => &mut access_lists.auth,
+                "admin" => &mut access_lists.admin,
+                _ => return Err(invalid()),
+            };
+            let list = match action {
+                "allow" => &mut rules.allow,
+                "deny" => &mut rules.deny,
+                _ => return Err(invalid()),
+            };
+            list.push(cidr.parse()?);
+        }
+
+        Ok(access_lists)
+    }
+}
+
+impl AccessLists {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {path}.\n{e:?}"))?
+            .parse()
+    }
+}
+
+// Shared between the interceptors and the maintenance task that reloads the file.
+pub type SharedAccessLists = Arc<RwLock<AccessLists>>;
+
+// An interceptor refusing the sources that `rules` (the auth or admin ones) does not allow. It
+// runs before the request reaches any handler.
+pub fn ip_filter(
+    access_lists: SharedAccessLists,
+    trusted_proxies: Arc<Vec<Cidr>>,
+    rules: fn(&AccessLists) -> &IpRules,
+) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |request: Request<()>| {
+        let ip = PeerInfo::from_request(&request, &trusted_proxies).client_ip;
+        let allowed = rules(&access_lists.read().unwrap_or_else(PoisonError::into_inner)).is_allowed(ip);
+
+        if allowed {
+            Ok(request)
+        } else {
+            info!("refused request from {:?}", ip);
+            Err(Error::Policy(String::from("requests from this address are not allowed")).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_match_cidr() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+
+        assert!(cidr.contains("10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains("10.2.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::1".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains("::ffff:10.2.0.1".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains("203.0.113.7".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn should_apply_rules_per_service() {
+        let access_lists: AccessLists = "
+            # operators only
+            admin allow 10.0.0.0/8
+            auth deny 203.0.113.0/24
+        "
+        .parse()
+        .unwrap();
+
+        let ip = |ip: &str| Some(ip.parse().unwrap());
+
+        assert!(access_lists.admin.is_allowed(ip("10.0.0.1")));
+        assert!(!access_lists.admin.is_allowed(ip("192.0.2.1")));
+        assert!(!access_lists.admin.is_allowed(None));
+        assert!(access_lists.auth.is_allowed(ip("192.0.2.1")));
+        assert!(!access_lists.auth.is_allowed(ip("203.0.113.9")));
+        assert!(access_lists.auth.is_allowed(None));
+        assert!("admin permit 10.0.0.0/8".parse::<AccessLists>().is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use rand_core::{OsRng, RngCore};
+use tokio::task::{spawn_blocking, JoinHandle};
+use tracing::{debug, error, info, warn};
+
+use crate::config::Config;
+use crate::lease::Lease;
+
+// How often the leader lease is renewed, unless `renew-leader-lease` has an interval of its own.
+// It is taken for three times as long, so a renewal or two may be late without a change of leader.
+const LEASE_RENEWAL_INTERVAL: Duration = Duration::from_secs(10);
+const LEASE_RENEWALS_PER_TTL: u32 = 3;
+
+// An interval of its own for one job, written `<job>=<seconds>`, e.g. `sweep-sessions=30`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JobInterval {
+    pub job: String,
+    pub interval: Duration,
+}
+
+impl FromStr for JobInterval {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (job, secs) = value.split_once('=').ok_or_else(|| String::from("Error::InvalidJobInterval"))?;
+        let secs = secs.trim().parse().map_err(|_| String::from("Error::InvalidJobInterval"))?;
+
+        Ok(Self { job: job.trim().to_owned(), interval: Duration::from_secs(secs) })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JobStatus {
+    pub interval: Duration,
+    pub runs: u64,
+    pub panics: u64,
+    pub last_run_at: Option<SystemTime>,
+    pub last_duration: Duration,
+    // What the last panic said, if the job ever panicked.
+    pub last_panic: Option<String>,
+    // Runs of a singleton job left to the leader, on a replica that isn't.
+    pub skipped_runs: u64,
+}
+
+// Where the scheduler keeps track of its jobs, shared with whoever reports on them.
+#[derive(Clone, Default)]
+pub struct JobStatuses(Arc<Mutex<BTreeMap<&'static str, JobStatus>>>);
+
+impl JobStatuses {
+    pub fn snapshot(&self) -> Vec<(&'static str, JobStatus)> {
+        let statuses = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        statuses.iter().map(|(name, status)| (*name, status.clone())).collect()
+    }
+
+    fn update(&self, name: &'static str, update: impl FnOnce(&mut JobStatus)) {
+        update(self.0.lock().unwrap_or_else(PoisonError::into_inner).entry(name).or_default());
+    }
+}
+
+struct Job {
+    name: &'static str,
+    interval: Duration,
+    // Only run by the leader, see `Scheduler::with_leader_lease`.
+    singleton: bool,
+    task: Arc<dyn Fn() + Send + Sync>,
+}
+
+// Runs the recurring maintenance jobs (purging, sweeping, reloading, ...), each on its own
+// interval. Runs are delayed by a random jitter, so replicas started together don't all hit the
+// stores at once, and run on the blocking pool, as jobs take the store locks. A job that panics
+// is recorded as such and runs again next time; the other jobs don't notice.
+pub struct Scheduler {
+    default_interval: Duration,
+    intervals: BTreeMap<String, Duration>,
+    jitter: Duration,
+    jobs: Vec<Job>,
+    statuses: JobStatuses,
+    // Whether this replica holds the leader lease; None without one, when it runs everything.
+    leader: Option<Arc<AtomicBool>>,
+}
+
+impl Scheduler {
+    pub fn new(default_interval: Duration, intervals: Vec<JobInterval>, jitter: Duration) -> Self {
+        Self {
+            default_interval,
+            intervals: intervals.into_iter().map(|interval| (interval.job, interval.interval)).collect(),
+            jitter,
+            jobs: Vec::new(),
+            statuses: JobStatuses::default(),
+            leader: None,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.maintenance_interval, config.job_intervals.clone(), config.job_jitter)
+    }
+
+    pub fn with_job(self, name: &'static str, task: impl Fn() + Send + Sync + 'static) -> Self {
+        self.with(name, false, Arc::new(task))
+    }
+
+    // A job that must run once across the replicas sharing a store, e.g. the purge: with a leader
+    // lease, only the replica holding it runs the job.
+    pub fn with_singleton_job(self, name: &'static str, task: impl Fn() + Send + Sync + 'static) -> Self {
+        self.with(name, true, Arc::new(task))
+    }
+
+    fn with(mut self, name: &'static str, singleton: bool, task: Arc<dyn Fn() + Send + Sync>) -> Self {
+        let interval = self.intervals.get(name).copied().unwrap_or(self.default_interval);
+        self.statuses.update(name, |status| status.interval = interval);
+        self.jobs.push(Job { name, interval, singleton, task });
+        self
+    }
+
+    // Singleton jobs only run on the replica holding `lease`, which every replica keeps trying to
+    // take (or renew) in the `renew-leader-lease` job.
+    pub fn with_leader_lease(mut self, lease: Box<dyn Lease>) -> Self {
+        let interval = self.intervals.get("renew-leader-lease").copied().unwrap_or(LEASE_RENEWAL_INTERVAL);
+        let ttl = interval * LEASE_RENEWALS_PER_TTL;
+        let leader = Arc::new(AtomicBool::new(false));
+        self.leader = Some(leader.clone());
+
+        let renew = move || {
+            let leading = lease.try_acquire(ttl).unwrap_or_else(|e| {
+                // Better no leader for a while than two.
+                warn!("failed to renew the leader lease: {}", e);
+                false
+            });
+            match (leader.swap(leading, Ordering::Relaxed), leading) {
+                (false, true) => info!("took the leader lease, running the singleton jobs"),
+                (true, false) => info!("lost the leader lease, leaving the singleton jobs to the leader"),
+                _ => {}
+            }
+        };
+        // Before the first tick of the singleton jobs, so the leader doesn't skip it.
+        renew();
+
+        self.with("renew-leader-lease", false, Arc::new(renew))
+    }
+
+    pub fn statuses(&self) -> JobStatuses {
+        self.statuses.clone()
+    }
+
+    pub fn start(self) -> Vec<JoinHandle<()>> {
+        let jitter = self.jitter;
+
+        self.jobs
+            .into_iter()
+            .map(|job| {
+                let statuses = self.statuses.clone();
+                let leader = self.leader.clone().filter(|_| job.singleton);
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(job.interval);
+                    loop {
+                        interval.tick().await;
+                        tokio::time::sleep(random_delay(jitter)).await;
+
+                        if leader.as_ref().is_some_and(|leader| !leader.load(Ordering::Relaxed)) {
+                            debug!("job {} left to the leader", job.name);
+                            statuses.update(job.name, |status| status.skipped_runs += 1);
+                            continue;
+                        }
+                        run(&job, &statuses).await;
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+async fn run(job: &Job, statuses: &JobStatuses) {
+    let started_at = Instant::now();
+    let task = job.task.clone();
+    let result = spawn_blocking(move || task()).await;
+
+    statuses.update(job.name, |status| {
+        status.runs += 1;
+        status.last_run_at = Some(SystemTime::now());
+        status.last_duration = started_at.elapsed();
+
+        if let Err(e) = &result {
+            status.panics += 1;
+            status.last_panic = Some(e.to_string());
+        }
+    });
+
+    match result {
+        Ok(()) => debug!("job {} done in {:?}", job.name, started_at.elapsed()),
+        Err(e) => error!("job {} failed: {}", job.name, e),
+    }
+}
+
+fn random_delay(jitter: Duration) -> Duration {
+    match jitter.as_millis() as u64 {
+        0 => Duration::ZERO,
+        millis => Duration::from_millis(OsRng.next_u64() % millis),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn panicking_job_should_not_stop_the_others() {
+        let runs = Arc::new(AtomicU64::new(0));
+        let counted = runs.clone();
+
+        let scheduler = Scheduler::new(Duration::from_millis(10), vec!["panics=3600".parse().unwrap()], Duration::ZERO)
+            .with_job("counts", move || {
+                counted.fetch_add(1, Ordering::Relaxed);
+            })
+            .with_job("panics", || panic!("boom"));
+        let statuses = scheduler.statuses();
+        let handles = scheduler.start();
+
+        // Printing the panic, with a backtrace, may take a while.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for _ in 0..100 {
+            if statuses.snapshot().iter().any(|(name, status)| *name == "panics" && status.runs > 0) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        handles.iter().for_each(JoinHandle::abort);
+
+        let statuses: BTreeMap<_, _> = statuses.snapshot().into_iter().collect();
+        assert!(runs.load(Ordering::Relaxed) > 1);
+        assert_eq!(statuses["counts"].runs, runs.load(Ordering::Relaxed));
+        assert_eq!(statuses["counts"].panics, 0);
+
+        // The first tick is right away; the next one in an hour.
+        assert_eq!(statuses["panics"].interval, Duration::from_secs(3600));
+        assert_eq!(statuses["panics"].runs, 1);
+        assert_eq!(statuses["panics"].panics, 1);
+        assert!(statuses["panics"].last_panic.as_deref().unwrap().contains("panic"));
+    }
+
+    // Always held by another replica.
+    struct LeaseElsewhere;
+
+    impl Lease for LeaseElsewhere {
+        fn try_acquire(&self, _ttl: Duration) -> Result<bool, String> {
+            Ok(false)
+        }
+    }
+
+    #[tokio::test]
+    async fn singleton_jobs_should_only_run_on_the_leader() {
+        let scheduler = Scheduler::new(Duration::from_millis(10), vec![], Duration::ZERO)
+            .with_leader_lease(Box::new(LeaseElsewhere))
+            .with_singleton_job("singleton", || {})
+            .with_job("everywhere", || {});
+        let statuses = scheduler.statuses();
+        let handles = scheduler.start();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        handles.iter().for_each(JoinHandle::abort);
+
+        let statuses: BTreeMap<_, _> = statuses.snapshot().into_iter().collect();
+        assert_eq!(statuses["singleton"].runs, 0);
+        assert!(statuses["singleton"].skipped_runs > 1);
+        assert!(statuses["everywhere"].runs > 1);
+        assert!(statuses["renew-leader-lease"].runs > 0);
+    }
+}
@@ -0,0 +1,393 @@
+use std::{
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    io::ErrorKind,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+// The version of the format `FileJournal` writes its entries in (see `decode_line`). Fields that can
+// be decoded from a line that hasn't got them (`#[serde(default)]`) don't need a new one.
+pub const ENTRY_VERSION: u64 = 1;
+
+// Something that happened to a store, and when.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry<E> {
+    pub at: SystemTime,
+    pub event: E,
+}
+
+// What a journal fails with, naming the file and line.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum JournalError {
+    // The file can't be written or read, or the disk it is on.
+    #[error("Error::JournalUnavailable: {0}")]
+    Unavailable(String),
+    // An entry of this version or older that can't be read (see `decode_line`).
+    #[error("Error::InvalidJournal: {0}")]
+    Invalid(String),
+}
+
+// Where a store in event sourcing mode appends its changes before making them. The store itself is
+// a projection of the journal: replaying the entries in order gives it back.
+pub trait Journal<E>: Debug + Send + Sync {
+    fn append(&self, event: &E) -> Result<(), JournalError>;
+    fn entries(&self) -> Result<Vec<JournalEntry<E>>, JournalError>;
+    // Drops everything after `until`, for going back to that point in time; returns how many
+    // entries went.
+    fn truncate_after(&self, until: SystemTime) -> Result<usize, JournalError>;
+    // Whether entries can still be written and read back, without adding any to the journal.
+    fn check(&self) -> Result<(), JournalError>;
+}
+
+// One JSON entry per line, `{"v":<ENTRY_VERSION>,"at":...,"event":...}`. Entries dropped by
+// `truncate_after` are kept next to the file, in `<file>.<nanos since the epoch>.discarded`, in case
+// going back was a mistake.
+#[derive(Debug)]
+pub struct FileJournal<E> {
+    path: PathBuf,
+    file: Mutex<File>,
+    events: PhantomData<fn(E)>,
+}
+
+impl<E> FileJournal<E> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, JournalError> {
+        let path = path.as_ref().to_owned();
+        let file = append_to(&path)?;
+
+        Ok(Self { path, file: Mutex::new(file), events: PhantomData })
+    }
+}
+
+// A line as written, with when it happened and its entry, if this version can read it.
+struct Line<E> {
+    text: String,
+    at: SystemTime,
+    entry: Option<JournalEntry<E>>,
+}
+
+impl<E: DeserializeOwned> FileJournal<E> {
+    fn lines(&self) -> Result<Vec<Line<E>>, JournalError> {
+        let file = File::open(&self.path).map_err(|e| JournalError::Unavailable(format!("{}: {e}", self.path.display())))?;
+
+        BufReader::new(file)
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+            .map(|(number, line)| {
+                let line = line.map_err(|e| JournalError::Unavailable(format!("{}: {e}", self.path.display())))?;
+                let (at, entry) = decode_line(&line)
+                    .map_err(|e| JournalError::Invalid(format!("{}:{}: {e}", self.path.display(), number + 1)))?;
+                Ok(Line { text: line, at, entry })
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+struct VersionedEntry<'a, E> {
+    v: u64,
+    #[serde(flatten)]
+    entry: &'a JournalEntry<E>,
+}
+
+fn encode_entry<E: Serialize>(entry: &JournalEntry<E>) -> Result<String, JournalError> {
+    serde_json::to_string(&VersionedEntry { v: ENTRY_VERSION, entry }).map_err(|e| JournalError::Unavailable(e.to_string()))
+}
+
+// Lines without a version are from before there was one, version 0. Those of a newer version are
+// read as far as this one understands them, so going back to it after an upgrade keeps the
+// sessions: fields it doesn't know are ignored, and an entry it can't read at all (an event it
+// doesn't know) is skipped, but kept in the file. Only such entries of its own version or older
+// make the journal invalid.
+fn decode_line<E: DeserializeOwned>(line: &str) -> Result<(SystemTime, Option<JournalEntry<E>>), String> {
+    let value: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let version = value.get("v").and_then(Value::as_u64).unwrap_or(0);
+    let at = serde_json::from_value(value.get("at").cloned().unwrap_or_default()).map_err(|e| e.to_string())?;
+
+    match serde_json::from_value(value) {
+        Ok(entry) => Ok((at, Some(entry))),
+        Err(e) if version > ENTRY_VERSION => {
+            warn!("skipping a journal entry of version {} (this is {}): {}", version, ENTRY_VERSION, e);
+            Ok((at, None))
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Removes what `truncate_after` moved aside in `dir`: the entries discarded before `cutoff`, and
+// the oldest past `max_bytes` in all. The journals themselves are the stores, so they are kept
+// whole. Returns how many files went.
+pub fn purge_discarded(dir: &Path, cutoff: Option<SystemTime>, max_bytes: Option<u64>) -> Result<usize, JournalError> {
+    let unavailable = |path: &Path, e: std::io::Error| JournalError::Unavailable(format!("{}: {e}", path.display()));
+    let files = match fs::read_dir(dir) {
+        Ok(files) => files,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(unavailable(dir, e)),
+    };
+
+    let mut discarded: Vec<(SystemTime, u64, PathBuf)> = files
+        .filter_map(Result::ok)
+        .filter_map(|file| Some((discarded_at(&file.path())?, file.metadata().ok()?.len(), file.path())))
+        .collect();
+    // The newest first, as they are the ones kept.
+    discarded.sort_by_key(|(at, _, _)| std::cmp::Reverse(*at));
+
+    let mut kept_bytes = 0;
+    let mut purged = 0;
+    for (at, len, path) in discarded {
+        let expired = cutoff.is_some_and(|cutoff| at < cutoff);
+        if !expired {
+            kept_bytes += len;
+        }
+        if expired || max_bytes.is_some_and(|max_bytes| kept_bytes > max_bytes) {
+            match fs::remove_file(&path) {
+                Ok(()) => purged += 1,
+                // Another replica's job got there first.
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(unavailable(&path, e)),
+            }
+        }
+    }
+    Ok(purged)
+}
+
+// When `truncate_after` wrote the file, going by its name: `<journal>.<nanos>.discarded`.
+fn discarded_at(path: &Path) -> Option<SystemTime> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".discarded")?;
+    let nanos = name.rsplit_once('.')?.1.parse().ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos))
+}
+
+fn append_to(path: &Path) -> Result<File, JournalError> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| JournalError::Unavailable(format!("{}: {e}", path.display())))
+}
+
+impl<E: Debug + Serialize + DeserializeOwned> Journal<E> for FileJournal<E> {
+    fn append(&self, event: &E) -> Result<(), JournalError> {
+        let mut line = encode_entry(&JournalEntry { at: SystemTime::now(), event })?;
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|e| JournalError::Unavailable(format!("{}: {e}", self.path.display())))
+    }
+
+    fn entries(&self) -> Result<Vec<JournalEntry<E>>, JournalError> {
+        let _file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(self.lines()?.into_iter().filter_map(|line| line.entry).collect())
+    }
+
+    // Lines are moved as they were written, those this version can't read too.
+    fn truncate_after(&self, until: SystemTime) -> Result<usize, JournalError> {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let (kept, dropped): (Vec<_>, Vec<_>) = self.lines()?.into_iter().partition(|line| line.at <= until);
+        if dropped.is_empty() {
+            return Ok(0);
+        }
+
+        let write = |path: &Path, lines: &[Line<E>]| {
+            let lines: String = lines.iter().map(|line| format!("{}\n", line.text)).collect();
+            fs::write(path, lines).map_err(|e| JournalError::Unavailable(format!("{}: {e}", path.display())))
+        };
+
+        let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos();
+        write(&PathBuf::from(format!("{}.{nanos}.discarded", self.path.display())), &dropped)?;
+
+        // Written aside and renamed over, so a crash leaves either journal whole.
+        let rewritten = PathBuf::from(format!("{}.rewrite", self.path.display()));
+        write(&rewritten, &kept)?;
+        fs::rename(&rewritten, &self.path).map_err(|e| JournalError::Unavailable(format!("{}: {e}", self.path.display())))?;
+        *file = append_to(&self.path)?;
+
+        Ok(dropped.len())
+    }
+
+    // Goes through a probe file next to the journal, on the same disk, and makes sure the journal
+    // itself is still there.
+    fn check(&self) -> Result<(), JournalError> {
+        let probe = PathBuf::from(format!("{}.probe", self.path.display()));
+        let written = format!("{:?}\n", SystemTime::now());
+        let unavailable = |e: std::io::Error| JournalError::Unavailable(format!("{}: {e}", probe.display()));
+
+        fs::write(&probe, &written).map_err(unavailable)?;
+        let read = fs::read_to_string(&probe).map_err(unavailable)?;
+        fs::remove_file(&probe).map_err(unavailable)?;
+        if read != written {
+            return Err(JournalError::Unavailable(format!("{}: read back something else", probe.display())));
+        }
+
+        fs::metadata(&self.path)
+            .map(|_| ())
+            .map_err(|e| JournalError::Unavailable(format!("{}: {e}", self.path.display())))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::*;
+
+    // Keeps entries in memory, shared between clones, for tests to replay.
+    #[derive(Clone, Debug)]
+    pub struct MemoryJournal<E> {
+        pub entries: Arc<Mutex<Vec<JournalEntry<E>>>>,
+    }
+
+    impl<E> Default for MemoryJournal<E> {
+        fn default() -> Self {
+            Self { entries: Arc::default() }
+        }
+    }
+
+    impl<E: Clone + Debug + Send> Journal<E> for MemoryJournal<E> {
+        fn append(&self, event: &E) -> Result<(), JournalError> {
+            self.entries.lock().unwrap().push(JournalEntry { at: SystemTime::now(), event: event.clone() });
+            Ok(())
+        }
+
+        fn entries(&self) -> Result<Vec<JournalEntry<E>>, JournalError> {
+            Ok(self.entries.lock().unwrap().clone())
+        }
+
+        fn truncate_after(&self, until: SystemTime) -> Result<usize, JournalError> {
+            let mut entries = self.entries.lock().unwrap();
+            let before = entries.len();
+            entries.retain(|entry| entry.at <= until);
+            Ok(before - entries.len())
+        }
+
+        fn check(&self) -> Result<(), JournalError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_read_back_and_truncate_entries() {
+        let path = std::env::temp_dir().join(format!("journal-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let journal = FileJournal::<String>::open(&path).unwrap();
+        journal.append(&"first".to_owned()).unwrap();
+        let until = SystemTime::now();
+        journal.append(&"second".to_owned()).unwrap();
+
+        let events = |journal: &FileJournal<String>| {
+            journal.entries().unwrap().into_iter().map(|entry| entry.event).collect::<Vec<_>>()
+        };
+        assert_eq!(events(&journal), ["first", "second"]);
+
+        assert_eq!(journal.truncate_after(until), Ok(1));
+        journal.append(&"third".to_owned()).unwrap();
+        assert_eq!(events(&FileJournal::open(&path).unwrap()), ["first", "third"]);
+
+        let discarded: Vec<_> = fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|other| other.to_string_lossy().starts_with(&*path.to_string_lossy()) && *other != path)
+            .collect();
+        assert_eq!(discarded.len(), 1);
+        assert!(fs::read_to_string(&discarded[0]).unwrap().contains("second"));
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&discarded[0]).unwrap();
+    }
+
+    #[test]
+    fn should_purge_old_discarded_entries_and_those_past_the_size() {
+        let dir = std::env::temp_dir().join(format!("discarded-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let discard = |nanos: u64, bytes: usize| {
+            let path = dir.join(format!("users.jsonl.{nanos}.discarded"));
+            fs::write(&path, "x".repeat(bytes)).unwrap();
+            path
+        };
+        let journal = dir.join("users.jsonl");
+        fs::write(&journal, "{}").unwrap();
+        let old = discard(1_000, 10);
+        let bulky = discard(2_000_000_000_000_000_000, 60);
+        let newest = discard(3_000_000_000_000_000_000, 50);
+
+        let cutoff = Some(UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!(purge_discarded(&dir, cutoff, None), Ok(1));
+        assert!(!old.exists());
+        assert_eq!(purge_discarded(&dir, cutoff, Some(100)), Ok(1));
+        assert!(!bulky.exists());
+        assert!(newest.exists() && journal.exists());
+        assert_eq!(purge_discarded(&dir.join("missing"), cutoff, Some(0)), Ok(0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_read_entries_of_other_versions() {
+        use crate::sessions::SessionEvent;
+
+        let path = std::env::temp_dir().join(format!("journal-versions-{}.jsonl", std::process::id()));
+        let at = |secs: u64| format!(r#""at":{{"secs_since_epoch":{secs},"nanos_since_epoch":0}}"#);
+        let lines = [
+            format!(r#"{{{},"event":{{"type":"SessionDeleted","session_token":"before-versions"}}}}"#, at(1)),
+            format!(r#"{{"v":2,{},"event":{{"type":"SessionDeleted","session_token":"newer","reason":"expired"}}}}"#, at(2)),
+            format!(r#"{{"v":2,{},"event":{{"type":"SessionMoved","session_token":"unknown"}}}}"#, at(3)),
+        ];
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+
+        let journal = FileJournal::<SessionEvent>::open(&path).unwrap();
+        journal.append(&SessionEvent::SessionDeleted { session_token: "current".to_owned() }).unwrap();
+        let tokens = |journal: &FileJournal<SessionEvent>| {
+            journal
+                .entries()
+                .unwrap()
+                .into_iter()
+                .map(|entry| match entry.event {
+                    SessionEvent::SessionDeleted { session_token } => session_token,
+                    other => panic!("{other:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tokens(&journal), ["before-versions", "newer", "current"]);
+        assert!(fs::read_to_string(&path).unwrap().lines().last().unwrap().starts_with(r#"{"v":1,"#));
+
+        // What this version can't read goes on being kept.
+        assert_eq!(journal.truncate_after(UNIX_EPOCH + Duration::from_secs(5)), Ok(1));
+        assert_eq!(tokens(&journal), ["before-versions", "newer"]);
+        assert!(fs::read_to_string(&path).unwrap().contains("SessionMoved"));
+
+        // Only a newer version's entries may be unreadable.
+        fs::write(&path, format!(r#"{{"v":1,{},"event":{{"type":"SessionMoved"}}}}"#, at(1)) + "\n").unwrap();
+        assert!(matches!(journal.entries(), Err(JournalError::Invalid(_))));
+
+        for discarded in fs::read_dir(std::env::temp_dir()).unwrap().filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            if discarded.to_string_lossy().starts_with(&*path.to_string_lossy()) {
+                fs::remove_file(discarded).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn check_should_fail_once_the_journal_is_gone() {
+        let path = std::env::temp_dir().join(format!("journal-check-{}.jsonl", std::process::id()));
+        let journal = FileJournal::<String>::open(&path).unwrap();
+        assert_eq!(journal.check(), Ok(()));
+
+        fs::remove_file(&path).unwrap();
+        assert!(journal.check().is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    convert::Infallible,
+    marker::PhantomData,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tonic::{
+    body::BoxBody,
+    codegen::{http, BoxFuture, Service},
+    server::NamedService,
+    transport::Body,
+};
+use tower::util::BoxCloneService;
+
+// A service as the listeners serve it: gRPC requests in, responses (or a status) out.
+pub type RpcService = BoxCloneService<http::Request<Body>, http::Response<BoxBody>, Infallible>;
+
+// Middleware of an application embedding the service (see `serve_with_layers`): its own
+// authentication, tenant extraction, request filters, ... Called with the name of the service
+// ("authentication.v1.Auth" or "authentication.v1.AuthAdmin") and the service, once per listener,
+// it returns the service wrapped, or as it is. Session replication is left alone.
+//
+// Layers go inside the load shedder, so shed requests never reach them, and in front of everything
+// else: IP rules, quotas, read-only mode and the handlers.
+pub type CustomLayer = Arc<dyn Fn(&'static str, RpcService) -> RpcService + Send + Sync>;
+
+// `inner` behind `layers`, the first one outermost; still routed by the name of `S`.
+pub struct LayeredService<S> {
+    inner: RpcService,
+    named: PhantomData<fn() -> S>,
+}
+
+impl<S> LayeredService<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible> + NamedService + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    pub fn new(inner: S, layers: &[CustomLayer]) -> Self {
+        let inner = layers.iter().rev().fold(BoxCloneService::new(inner), |service, layer| layer(S::NAME, service));
+        Self { inner, named: PhantomData }
+    }
+}
+
+// Not derived: that would need `S: Clone`, and `S` is only a name here.
+impl<S> Clone for LayeredService<S> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), named: PhantomData }
+    }
+}
+
+impl<S> Service<http::Request<Body>> for LayeredService<S> {
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        self.inner.call(request)
+    }
+}
+
+impl<S: NamedService> NamedService for LayeredService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tonic::body::empty_body;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct Named;
+
+    impl Service<http::Request<Body>> for Named {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: http::Request<Body>) -> Self::Future {
+            Box::pin(async { Ok(http::Response::new(empty_body())) })
+        }
+    }
+
+    impl NamedService for Named {
+        const NAME: &'static str = "test.v1.Named";
+    }
+
+    // Notes its tag on the way in.
+    fn tagging(tag: &'static str, seen: Arc<Mutex<Vec<String>>>) -> CustomLayer {
+        Arc::new(move |name, service: RpcService| {
+            let seen = seen.clone();
+            BoxCloneService::new(service_fn(move |request| {
+                seen.lock().unwrap().push(format!("{tag} {name}"));
+                service.clone().oneshot(request)
+            }))
+        })
+    }
+
+    #[tokio::test]
+    async fn should_run_layers_first_one_outermost() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let layers = [tagging("outer", seen.clone()), tagging("inner", seen.clone())];
+
+        let service = LayeredService::new(Named, &layers);
+        assert_eq!(<LayeredService<Named> as NamedService>::NAME, "test.v1.Named");
+
+        let response = service.oneshot(http::Request::new(Body::empty())).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(*seen.lock().unwrap(), ["outer test.v1.Named", "inner test.v1.Named"]);
+    }
+}
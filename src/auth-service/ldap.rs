@@ -0,0 +1,514 @@
+use std::{collections::{BTreeSet, HashMap}, env, sync::{Arc, Mutex, PoisonError}, thread, time::{Duration, SystemTime}};
+
+use ldap3::{dn_escape, LdapConn, LdapConnSettings, Scope, SearchEntry};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::error::StoreError;
+use crate::users::{check_scopes, set_metadata_entry, AccountStatus, UserEvent, UserPage, UserQuery, UsersOps};
+use crate::webauthn::WebAuthnCredential;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What setting up or asking the directory fails with.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum DirectoryError {
+    #[error("Error::MissingLdapUrl")]
+    MissingUrl,
+    #[error("Error::MissingLdapUserDnTemplate")]
+    MissingUserDnTemplate,
+    #[error("Error::InvalidLdapTimeout")]
+    InvalidTimeout,
+    #[error("Failed to connect to {0}")]
+    Unreachable(String),
+    #[error("Failed to bind as {0}")]
+    BindFailed(String),
+    #[error("Error::LdapThreadPanicked")]
+    ThreadPanicked,
+}
+
+// Verifies a username/password pair against a directory. The calls block on a server that may be
+// slow, so the service makes them off its users lock and its runtime's threads, within `timeout`
+// (see `AuthService::verify_password`).
+pub trait Directory: Send + Sync {
+    // What the directory knows the user by when the password is theirs, e.g. the entryUUID of
+    // their entry: the same however the username was typed. None for a wrong password.
+    fn verify_credentials(&self, username: &str, password: &str) -> Result<Option<String>, DirectoryError>;
+    // Whether the directory can be reached.
+    fn check(&self) -> Result<(), DirectoryError>;
+    fn timeout(&self) -> Duration;
+}
+
+// Checks credentials by binding to an LDAP / Active Directory server as the user.
+pub struct LdapDirectory {
+    url: String,
+    // e.g. "uid={username},ou=people,dc=example,dc=com" or "{username}@corp.example.com" for AD
+    user_dn_template: String,
+    timeout: Duration,
+}
+
+impl LdapDirectory {
+    pub fn new(url: String, user_dn_template: String) -> Self {
+        Self { url, user_dn_template, timeout: DEFAULT_TIMEOUT }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn user_dn(&self, username: &str) -> String {
+        self.user_dn_template.replace("{username}", &dn_escape(username))
+    }
+
+    pub fn from_env() -> Result<Self, DirectoryError> {
+        let url = env::var("AUTH_LDAP_URL").map_err(|_| DirectoryError::MissingUrl)?;
+        let user_dn_template = env::var("AUTH_LDAP_USER_DN_TEMPLATE").map_err(|_| DirectoryError::MissingUserDnTemplate)?;
+        let timeout = match env::var("AUTH_LDAP_TIMEOUT_MS") {
+            Ok(millis) => Duration::from_millis(millis.parse().map_err(|_| DirectoryError::InvalidTimeout)?),
+            Err(_) => DEFAULT_TIMEOUT,
+        };
+
+        Ok(Self::new(url, user_dn_template).with_timeout(timeout))
+    }
+
+    // `LdapConn` drives its own runtime, which may not be started from a tokio worker thread.
+    fn with_connection<T: Send>(
+        &self,
+        f: impl FnOnce(&mut LdapConn) -> Result<T, DirectoryError> + Send,
+    ) -> Result<T, DirectoryError> {
+        thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let settings = LdapConnSettings::new().set_conn_timeout(self.timeout);
+                    let mut ldap = LdapConn::with_settings(settings, &self.url)
+                        .map_err(|e| DirectoryError::Unreachable(format!("{}.\n{e:?}", self.url)))?;
+                    ldap.with_timeout(self.timeout);
+
+                    let result = f(&mut ldap);
+                    let _ = ldap.unbind();
+
+                    result
+                })
+                .join()
+                .map_err(|_| DirectoryError::ThreadPanicked)?
+        })
+    }
+}
+
+// The entryUUID of the entry the user bound as, or objectGUID on Active Directory, which no
+// spelling of the username changes. A template that isn't a DN ("{username}@corp.example.com")
+// has no entry to read; its name is compared without case, as the directory does.
+fn identity_of(ldap: &mut LdapConn, user_dn: &str) -> String {
+    let entry = ldap
+        .search(user_dn, Scope::Base, "(objectClass=*)", vec!["entryUUID", "objectGUID"])
+        .and_then(|result| result.success())
+        .ok()
+        .and_then(|(entries, _)| entries.into_iter().next())
+        .map(SearchEntry::construct);
+
+    let Some(entry) = entry else { return user_dn.to_lowercase() };
+
+    let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+    entry
+        .attrs
+        .get("entryUUID")
+        .and_then(|values| values.first().cloned())
+        .or_else(|| entry.bin_attrs.get("objectGUID").and_then(|values| values.first()).map(|guid| hex(guid)))
+        .or_else(|| entry.attrs.get("objectGUID").and_then(|values| values.first()).map(|guid| hex(guid.as_bytes())))
+        .unwrap_or_else(|| entry.dn.to_lowercase())
+}
+
+impl Directory for LdapDirectory {
+    fn verify_credentials(&self, username: &str, password: &str) -> Result<Option<String>, DirectoryError> {
+        let user_dn = self.user_dn(username);
+
+        self.with_connection(|ldap| {
+            let bound = ldap
+                .simple_bind(&user_dn, password)
+                .map_err(|e| DirectoryError::BindFailed(format!("{user_dn}.\n{e:?}")))?;
+
+            Ok(bound.success().is_ok().then(|| identity_of(ldap, &user_dn)))
+        })
+    }
+
+    // Connecting is all there is to check: binding takes a user's password.
+    fn check(&self) -> Result<(), DirectoryError> {
+        self.with_connection(|_| Ok(()))
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+// Users live in the directory; we only keep the uuids handed out to them. A uuid is derived from
+// what the directory knows the user by (see `Directory::verify_credentials`), so it stays the same
+// across restarts and replicas.
+pub struct LdapUsersImpl<D: Directory = LdapDirectory> {
+    directory: Arc<D>,
+    username_to_uuid: Mutex<HashMap<String, String>>,
+    // Local overrides, e.g. users suspended through the admin API. Everyone else is active.
+    uuid_to_status: HashMap<String, AccountStatus>,
+    uuid_to_metadata: HashMap<String, HashMap<String, String>>,
+    uuid_to_deletion: HashMap<String, SystemTime>,
+    uuid_to_soft_deletion: HashMap<String, SystemTime>,
+    uuid_to_terms_version: HashMap<String, String>,
+    uuid_to_scopes: HashMap<String, Vec<String>>,
+    uuid_to_tenants: HashMap<String, BTreeSet<String>>,
+    uuid_to_credentials: HashMap<String, Vec<WebAuthnCredential>>,
+}
+
+impl<D: Directory> LdapUsersImpl<D> {
+    pub fn new(directory: D) -> Self {
+        Self {
+            directory: Arc::new(directory),
+            username_to_uuid: Mutex::new(HashMap::new()),
+            uuid_to_status: HashMap::new(),
+            uuid_to_metadata: HashMap::new(),
+            uuid_to_deletion: HashMap::new(),
+            uuid_to_soft_deletion: HashMap::new(),
+            uuid_to_terms_version: HashMap::new(),
+            uuid_to_scopes: HashMap::new(),
+            uuid_to_tenants: HashMap::new(),
+            uuid_to_credentials: HashMap::new(),
+        }
+    }
+}
+
+impl<D: Directory + 'static> UsersOps for LdapUsersImpl<D> {
+    fn create_user(&mut self, _username: String, _password: String) -> Result<(), StoreError> {
+        Err(StoreError::DirectoryIsReadOnly)
+    }
+
+    fn create_user_with_uuid(&mut self, _user_uuid: String, _username: String, _password: String) -> Result<(), StoreError> {
+        Err(StoreError::DirectoryIsReadOnly)
+    }
+
+    fn create_if_absent(&mut self, _username: String, _password: String) -> Result<bool, StoreError> {
+        Err(StoreError::DirectoryIsReadOnly)
+    }
+
+    // Passwords are changed in the directory.
+    fn update_password_if_matches(&mut self, _user_uuid: &str, _current_password: &str, _new_password: &str) -> Result<bool, StoreError> {
+        Err(StoreError::DirectoryIsReadOnly)
+    }
+
+    // Blocks on the directory; the service goes through `directory` and `directory_user` instead.
+    fn get_user_uuid(&self, username: String, password: String) -> Option<String> {
+        // An empty password would be an unauthenticated bind, which most servers accept.
+        if password.is_empty() { return None };
+
+        match self.directory.verify_credentials(&username, &password) {
+            Ok(Some(identity)) => self.directory_user(&username, &identity),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("ldap verification failed: {}", e);
+                None
+            }
+        }
+    }
+
+    fn directory(&self) -> Option<Arc<dyn Directory>> {
+        Some(self.directory.clone())
+    }
+
+    fn directory_user(&self, username: &str, identity: &str) -> Option<String> {
+        let user_uuid = Uuid::new_v5(&Uuid::NAMESPACE_X500, identity.as_bytes()).to_string();
+
+        self.username_to_uuid
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(username.to_owned(), user_uuid.clone());
+
+        Some(user_uuid)
+    }
+
+    // The directory keeps the passwords, hashed however it does.
+    fn upgrade_password_hash(&mut self, _user_uuid: &str, _password: &str) -> Result<bool, StoreError> {
+        Ok(false)
+    }
+
+    fn outdated_password_hashes(&self) -> usize {
+        0
+    }
+
+    fn delete_user(&mut self, user_uuid: String) {
+        self.username_to_uuid
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, uuid| *uuid != user_uuid);
+
+        // The directory entry itself stays; the user shows up as new on their next sign in.
+        self.uuid_to_status.remove(&user_uuid);
+        self.uuid_to_metadata.remove(&user_uuid);
+        self.uuid_to_deletion.remove(&user_uuid);
+        self.uuid_to_soft_deletion.remove(&user_uuid);
+        self.uuid_to_terms_version.remove(&user_uuid);
+        self.uuid_to_scopes.remove(&user_uuid);
+        self.uuid_to_tenants.remove(&user_uuid);
+        self.uuid_to_credentials.remove(&user_uuid);
+    }
+
+    fn link_external_user(&mut self, _provider: &str, _subject: &str) -> Result<String, StoreError> {
+        Err(StoreError::DirectoryIsReadOnly)
+    }
+
+    fn change_username(&mut self, _user_uuid: &str, _new_username: &str) -> Result<String, StoreError> {
+        Err(StoreError::DirectoryIsReadOnly)
+    }
+
+    // Only users who signed in with a password before are known here.
+    fn find_user_uuid(&self, username: &str) -> Option<String> {
+        self.username_to_uuid
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(username)
+            .cloned()
+    }
+
+    fn get_username(&self, user_uuid: &str) -> Option<String> {
+        self.username_to_uuid
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|(_, uuid)| *uuid == user_uuid)
+            .map(|(username, _)| username.clone())
+    }
+
+    fn get_account_status(&self, user_uuid: &str) -> Option<AccountStatus> {
+        let known = self
+            .username_to_uuid
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .any(|uuid| uuid == user_uuid);
+
+        known.then(|| self.uuid_to_status.get(user_uuid).copied().unwrap_or_default())
+    }
+
+    fn set_account_status(&mut self, user_uuid: &str, status: AccountStatus) -> Result<(), StoreError> {
+        self.get_account_status(user_uuid).ok_or(StoreError::UserNotFound)?;
+        self.uuid_to_status.insert(user_uuid.to_owned(), status);
+
+        if status != AccountStatus::Deleted {
+            self.uuid_to_deletion.remove(user_uuid);
+        }
+        if status != AccountStatus::SoftDeleted {
+            self.uuid_to_soft_deletion.remove(user_uuid);
+        }
+
+        Ok(())
+    }
+
+    // Kept locally as well; the directory is read-only to us.
+    fn get_metadata(&self, user_uuid: &str) -> Option<HashMap<String, String>> {
+        self.get_account_status(user_uuid)?;
+        Some(self.uuid_to_metadata.get(user_uuid).cloned().unwrap_or_default())
+    }
+
+    fn set_metadata(&mut self, user_uuid: &str, key: &str, value: &str) -> Result<(), StoreError> {
+        self.get_account_status(user_uuid).ok_or(StoreError::UserNotFound)?;
+        set_metadata_entry(self.uuid_to_metadata.entry(user_uuid.to_owned()).or_default(), key, value)
+    }
+
+    fn request_deletion(&mut self, user_uuid: &str) -> Result<(), StoreError> {
+        self.set_account_status(user_uuid, AccountStatus::Deleted)?;
+        self.uuid_to_deletion.insert(user_uuid.to_owned(), SystemTime::now());
+        Ok(())
+    }
+
+    fn deletion_requested_at(&self, user_uuid: &str) -> Option<SystemTime> {
+        self.uuid_to_deletion.get(user_uuid).copied()
+    }
+
+    fn users_deleted_before(&self, cutoff: SystemTime) -> Vec<String> {
+        self.uuid_to_deletion
+            .iter()
+            .filter(|(_, requested_at)| **requested_at < cutoff)
+            .map(|(user_uuid, _)| user_uuid.clone())
+            .collect()
+    }
+
+    // The account stays in the directory; only its access through here is taken down.
+    fn soft_delete(&mut self, user_uuid: &str) -> Result<(), StoreError> {
+        self.set_account_status(user_uuid, AccountStatus::SoftDeleted)?;
+        self.uuid_to_soft_deletion.insert(user_uuid.to_owned(), SystemTime::now());
+        Ok(())
+    }
+
+    fn users_soft_deleted_before(&self, cutoff: SystemTime) -> Vec<String> {
+        self.uuid_to_soft_deletion
+            .iter()
+            .filter(|(_, deleted_at)| **deleted_at < cutoff)
+            .map(|(user_uuid, _)| user_uuid.clone())
+            .collect()
+    }
+
+    fn get_accepted_terms_version(&self, user_uuid: &str) -> Option<String> {
+        self.uuid_to_terms_version.get(user_uuid).cloned()
+    }
+
+    fn set_accepted_terms_version(&mut self, user_uuid: &str, version: &str) -> Result<(), StoreError> {
+        self.get_account_status(user_uuid).ok_or(StoreError::UserNotFound)?;
+        self.uuid_to_terms_version.insert(user_uuid.to_owned(), version.to_owned());
+        Ok(())
+    }
+
+    fn get_scopes(&self, user_uuid: &str) -> Vec<String> {
+        self.uuid_to_scopes.get(user_uuid).cloned().unwrap_or_default()
+    }
+
+    fn set_scopes(&mut self, user_uuid: &str, scopes: &[String]) -> Result<(), StoreError> {
+        self.get_account_status(user_uuid).ok_or(StoreError::UserNotFound)?;
+        check_scopes(scopes)?;
+
+        if scopes.is_empty() {
+            self.uuid_to_scopes.remove(user_uuid);
+        } else {
+            self.uuid_to_scopes.insert(user_uuid.to_owned(), scopes.to_vec());
+        }
+        Ok(())
+    }
+
+    fn get_tenants(&self, user_uuid: &str) -> Vec<String> {
+        self.uuid_to_tenants.get(user_uuid).into_iter().flatten().cloned().collect()
+    }
+
+    fn add_tenant(&mut self, user_uuid: &str, tenant: &str) -> Result<(), StoreError> {
+        self.get_account_status(user_uuid).ok_or(StoreError::UserNotFound)?;
+        self.uuid_to_tenants.entry(user_uuid.to_owned()).or_default().insert(tenant.to_owned());
+        Ok(())
+    }
+
+    fn get_webauthn_credentials(&self, user_uuid: &str) -> Vec<WebAuthnCredential> {
+        self.uuid_to_credentials.get(user_uuid).cloned().unwrap_or_default()
+    }
+
+    fn find_webauthn_credential(&self, credential_id: &[u8]) -> Option<(String, WebAuthnCredential)> {
+        self.uuid_to_credentials.iter().find_map(|(user_uuid, credentials)| {
+            let credential = credentials.iter().find(|credential| credential.credential_id == credential_id)?;
+            Some((user_uuid.clone(), credential.clone()))
+        })
+    }
+
+    fn add_webauthn_credential(&mut self, user_uuid: &str, credential: WebAuthnCredential) -> Result<(), StoreError> {
+        self.get_account_status(user_uuid).ok_or(StoreError::UserNotFound)?;
+        if self.find_webauthn_credential(&credential.credential_id).is_some() { return Err(StoreError::CredentialAlreadyRegistered)};
+        self.uuid_to_credentials.entry(user_uuid.to_owned()).or_default().push(credential);
+        Ok(())
+    }
+
+    fn set_webauthn_sign_count(&mut self, credential_id: &[u8], sign_count: u32) -> Result<(), StoreError> {
+        let credential = self
+            .uuid_to_credentials
+            .values_mut()
+            .flatten()
+            .find(|credential| credential.credential_id == credential_id)
+            .ok_or(StoreError::CredentialNotFound)?;
+        credential.sign_count = sign_count;
+        Ok(())
+    }
+
+    // We only know the users who signed in since the start, and not when they were created; the
+    // directory is the place to list users.
+    fn list_users(&self, _query: &UserQuery) -> Result<UserPage, StoreError> {
+        Err(StoreError::ListingUnsupported)
+    }
+
+    // Only the users seen since the start, as for `list_users`.
+    fn user_count(&self) -> usize {
+        self.username_to_uuid.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    // Derived from the user's directory entry, so the same every time.
+    fn id_scheme(&self) -> &'static str {
+        "uuid-v5"
+    }
+
+    // The password hashes stay in the directory, so the users do too.
+    fn export_user(&self, _user_uuid: &str) -> Option<Vec<UserEvent>> {
+        None
+    }
+
+    fn import_user(&mut self, _events: Vec<UserEvent>) -> Result<(), StoreError> {
+        Err(StoreError::DirectoryIsReadOnly)
+    }
+
+    fn check_store(&self) -> Result<(), StoreError> {
+        Ok(self.directory.check()?)
+    }
+
+    // Nothing here is event sourced: the directory holds the users.
+    fn replay(&mut self, _until: Option<SystemTime>) -> Result<usize, StoreError> {
+        Err(StoreError::NoJournal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeDirectory;
+
+    // Names compared without case, as directories do.
+    impl Directory for FakeDirectory {
+        fn verify_credentials(&self, username: &str, password: &str) -> Result<Option<String>, DirectoryError> {
+            Ok((username.eq_ignore_ascii_case("jdoe") && password == "secret").then(|| String::from("entry-1")))
+        }
+
+        fn check(&self) -> Result<(), DirectoryError> {
+            Ok(())
+        }
+
+        fn timeout(&self) -> Duration {
+            Duration::from_secs(1)
+        }
+    }
+
+    #[test]
+    fn should_retrieve_stable_user_uuid() {
+        let user_service = LdapUsersImpl::new(FakeDirectory);
+
+        let user_uuid = user_service.get_user_uuid("jdoe".to_owned(), "secret".to_owned());
+
+        assert!(user_uuid.is_some());
+        assert_eq!(user_uuid, LdapUsersImpl::new(FakeDirectory).get_user_uuid("jdoe".to_owned(), "secret".to_owned()));
+        assert_eq!(user_service.username_to_uuid.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn should_fail_to_retrieve_user_uuid_with_incorrect_or_empty_password() {
+        let user_service = LdapUsersImpl::new(FakeDirectory);
+
+        assert!(user_service.get_user_uuid("jdoe".to_owned(), "wrong".to_owned()).is_none());
+        assert!(user_service.get_user_uuid("jdoe".to_owned(), "".to_owned()).is_none());
+    }
+
+    #[test]
+    fn should_not_create_users() {
+        let mut user_service = LdapUsersImpl::new(FakeDirectory);
+
+        assert!(user_service.create_user("jdoe".to_owned(), "secret".to_owned()).is_err());
+    }
+
+    #[test]
+    fn should_give_one_uuid_however_the_username_is_typed() {
+        let user_service = LdapUsersImpl::new(FakeDirectory);
+
+        let user_uuid = user_service.get_user_uuid("jdoe".to_owned(), "secret".to_owned());
+
+        assert!(user_uuid.is_some());
+        assert_eq!(user_uuid, user_service.get_user_uuid("JDoe".to_owned(), "secret".to_owned()));
+    }
+
+    #[test]
+    fn should_escape_username_in_dn() {
+        let directory = LdapDirectory::new(
+            "ldap://localhost".to_owned(),
+            "uid={username},ou=people,dc=example,dc=com".to_owned(),
+        );
+
+        assert_eq!(directory.user_dn("x,ou=admins"), "uid=x\\2cou\\3dadmins,ou=people,dc=example,dc=com");
+    }
+}
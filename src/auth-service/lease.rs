@@ -0,0 +1,87 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rand_core::{OsRng, RngCore};
+
+// Held by one replica at a time, until it expires unless renewed: the replica holding it is the
+// leader, and runs the jobs that must only run once across replicas sharing a store (see
+// `Scheduler::with_leader_lease`). A leader that dies stops renewing, and another replica takes
+// over once the lease runs out.
+pub trait Lease: Send + Sync {
+    // Takes the lease, or renews it when held already, for `ttl` from now. False while another
+    // holder's lease is still good.
+    fn try_acquire(&self, ttl: Duration) -> Result<bool, String>;
+}
+
+// A lease kept in a file the replicas share (e.g. next to the event log), as `<holder> <expiry
+// in nanos since the epoch>`. Two replicas finding it expired at the same moment may both lead
+// until the next renewal, when the one whose write was lost finds out; the jobs behind it have to
+// be fine running twice once in a while.
+pub struct FileLease {
+    path: PathBuf,
+    // Made up per process, so a restarted replica doesn't take its old lease for its own.
+    holder: String,
+}
+
+impl FileLease {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), holder: format!("{}-{:016x}", std::process::id(), OsRng.next_u64()) }
+    }
+
+    fn read(&self) -> Option<(String, SystemTime)> {
+        let content = fs::read_to_string(&self.path).ok()?;
+        let (holder, nanos) = content.trim().rsplit_once(' ')?;
+        let nanos: u64 = nanos.parse().ok()?;
+
+        Some((holder.to_owned(), UNIX_EPOCH + Duration::from_nanos(nanos)))
+    }
+}
+
+impl Lease for FileLease {
+    fn try_acquire(&self, ttl: Duration) -> Result<bool, String> {
+        let now = SystemTime::now();
+        if let Some((holder, expires_at)) = self.read() {
+            if holder != self.holder && expires_at > now {
+                return Ok(false);
+            }
+        }
+
+        let nanos = (now + ttl).duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        // Written aside and renamed over, so nobody reads half a lease.
+        let written = PathBuf::from(format!("{}.{}", self.path.display(), self.holder));
+        fs::write(&written, format!("{} {nanos}\n", self.holder))
+            .and_then(|_| fs::rename(&written, &self.path))
+            .map_err(|e| format!("Error::LeaseUnavailable: {}: {e}", self.path.display()))?;
+
+        // Someone else may have written theirs at the same time; whoever's is there won.
+        Ok(self.read().is_some_and(|(holder, _)| holder == self.holder))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_lead_until_the_lease_runs_out() {
+        let path = std::env::temp_dir().join(format!("lease-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let first = FileLease::new(&path);
+        let second = FileLease::new(&path);
+
+        assert_eq!(first.try_acquire(Duration::from_millis(100)), Ok(true));
+        assert_eq!(second.try_acquire(Duration::from_millis(100)), Ok(false));
+        // Renewing is taking it again.
+        assert_eq!(first.try_acquire(Duration::from_millis(100)), Ok(true));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(second.try_acquire(Duration::from_secs(60)), Ok(true));
+        assert_eq!(first.try_acquire(Duration::from_secs(60)), Ok(false));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    convert::Infallible,
+    task::{Context, Poll},
+};
+
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Service},
+    server::NamedService,
+    transport::Body,
+};
+use tracing::warn;
+
+// Moves a request for `/<legacy>/<method>` over to `/<current>/<method>`.
+fn rewrite_path(uri: &http::Uri, legacy: &str, current: &str) -> http::Uri {
+    let Some(method) = uri.path().strip_prefix(&format!("/{legacy}/")) else { return uri.clone() };
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = format!("/{current}/{method}").parse().ok();
+
+    http::Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+}
+
+// Serves a service of the unversioned "authentication" package, which clients used before
+// "authentication.v1". The messages did not change, so its requests are simply handed to the v1
+// service under the new path.
+macro_rules! legacy_service {
+    ($name:ident, $legacy:literal, $current:literal) => {
+        #[derive(Clone)]
+        pub struct $name<S>(pub S);
+
+        impl<S> Service<http::Request<Body>> for $name<S>
+        where
+            S: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible>,
+        {
+            type Response = S::Response;
+            type Error = S::Error;
+            type Future = S::Future;
+
+            fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+                self.0.poll_ready(cx)
+            }
+
+            fn call(&mut self, mut request: http::Request<Body>) -> Self::Future {
+                warn!("deprecated: {} called, clients should move to {}", request.uri().path(), $current);
+
+                *request.uri_mut() = rewrite_path(request.uri(), $legacy, $current);
+                self.0.call(request)
+            }
+        }
+
+        impl<S> NamedService for $name<S> {
+            const NAME: &'static str = $legacy;
+        }
+    };
+}
+
+legacy_service!(LegacyAuth, "authentication.Auth", "authentication.v1.Auth");
+legacy_service!(LegacyAuthAdmin, "authentication.AuthAdmin", "authentication.v1.AuthAdmin");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_rewrite_legacy_path_only() {
+        let rewrite = |uri: &str| {
+            rewrite_path(&uri.parse().unwrap(), "authentication.Auth", "authentication.v1.Auth").to_string()
+        };
+
+        assert_eq!(rewrite("/authentication.Auth/SignIn"), "/authentication.v1.Auth/SignIn");
+        assert_eq!(rewrite("http://localhost:50051/authentication.Auth/SignUp"), "http://localhost:50051/authentication.v1.Auth/SignUp");
+        assert_eq!(rewrite("/authentication.AuthAdmin/SuspendUser"), "/authentication.AuthAdmin/SuspendUser");
+    }
+}
@@ -0,0 +1,70 @@
+// The auth service, as a library: the binary wires it up from the environment (see `server::serve`,
+// which other applications and integration tests can run in-process too), fuzz targets and other
+// harnesses drive the handlers in-process.
+
+// `tonic::Status` is what every handler returns; boxing it would only add noise.
+#![allow(clippy::result_large_err)]
+
+pub mod access_log;
+pub mod admin;
+pub mod admin_policy;
+pub mod api_keys;
+pub mod audit;
+pub mod audit_export;
+pub mod auth;
+pub mod breached;
+pub mod challenge;
+pub mod clock_skew;
+pub mod claims;
+pub mod config;
+pub mod debug_capture;
+pub mod effective_config;
+pub mod error;
+pub mod events;
+pub mod federation;
+pub mod flags;
+pub mod geo;
+pub mod hashers;
+pub mod i18n;
+pub mod invites;
+pub mod ip_rules;
+pub mod jobs;
+pub mod journal;
+pub mod layers;
+pub mod lease;
+pub mod ldap;
+pub mod legacy;
+pub mod listeners;
+pub mod load_shedding;
+pub mod logging;
+pub mod mailer;
+pub mod migrate;
+pub mod nonces;
+pub mod outage;
+pub mod peer;
+pub mod policy;
+pub mod prehash;
+pub mod profiling;
+#[cfg(test)]
+mod proto_compat;
+pub mod quotas;
+pub mod read_only;
+pub mod readiness;
+pub mod replication;
+pub mod response_cache;
+pub mod responses;
+#[cfg(test)]
+mod response_golden;
+pub mod rpc;
+pub mod server;
+pub mod server_timing;
+pub mod sessions;
+pub mod store_metrics;
+pub mod tenants;
+pub mod throttle;
+pub mod tokens;
+pub mod usage_stats;
+pub mod usernames;
+pub mod users;
+pub mod validation;
+pub mod webauthn;
@@ -0,0 +1,128 @@
+use std::{
+    env, fs, io,
+    net::SocketAddr,
+    os::fd::{FromRawFd, RawFd},
+    process,
+};
+
+use socket2::{Domain, Socket, Type};
+use tokio::net::{TcpListener, UnixListener};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+// The first file descriptor systemd passes (SD_LISTEN_FDS_START).
+const LISTEN_FDS_START: RawFd = 3;
+
+// What a socket passed in by systemd is named (FileDescriptorName=) to serve the admin service.
+pub const ADMIN_FD_NAME: &str = "admin";
+
+// Binds a TCP listener. On Linux "[::]" also takes IPv4 connections by default, which makes
+// binding "0.0.0.0" on the same port fail; so an IPv6 listener is made IPv6-only when an IPv4
+// listener on the same port is in `all`. With `reuse_port`, another process can bind the address
+// too (SO_REUSEPORT), and the kernel spreads the connections between them: a new version starts
+// next to the old one, which then drains.
+pub fn bind_tcp(addr: SocketAddr, all: &[SocketAddr], reuse_port: bool) -> io::Result<TcpListenerStream> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+
+    if addr.is_ipv6() {
+        let v4_on_same_port = all.iter().any(|other| other.is_ipv4() && other.port() == addr.port());
+        socket.set_only_v6(v4_on_same_port)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(reuse_port)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(TcpListenerStream::new(TcpListener::from_std(socket.into())?))
+}
+
+// The listening sockets systemd passed in (socket activation), with their names, when it started
+// this process; None otherwise. They take the place of the configured addresses: the sockets outlive
+// the process, so a restarted one serves the connections that queued up meanwhile.
+pub fn activated_listeners() -> io::Result<Option<Vec<(String, TcpListenerStream)>>> {
+    let var = |name| env::var(name).ok();
+    let Some(fds) = activated_fds(var("LISTEN_PID"), var("LISTEN_FDS"), var("LISTEN_FDNAMES"), process::id()) else {
+        return Ok(None);
+    };
+    // Taken: a second `serve`, or a child, must not take the same descriptors.
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    let listeners = fds
+        .into_iter()
+        .map(|(fd, name)| {
+            // SAFETY: systemd passes these open, for this process only (LISTEN_PID), and nothing
+            // else here takes them.
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            if socket.local_addr()?.as_socket().is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("fd {fd} ({name}) is not a TCP socket")));
+            }
+            socket.set_nonblocking(true)?;
+            Ok((name, TcpListenerStream::new(TcpListener::from_std(socket.into())?)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok(Some(listeners))
+}
+
+// The descriptors LISTEN_FDS says were passed, if LISTEN_PID is this process, each with its name
+// of LISTEN_FDNAMES (colon separated; empty when missing).
+fn activated_fds(listen_pid: Option<String>, listen_fds: Option<String>, names: Option<String>, pid: u32) -> Option<Vec<(RawFd, String)>> {
+    if listen_pid?.parse::<u32>().ok()? != pid {
+        return None;
+    }
+    let count: RawFd = listen_fds?.parse().ok()?;
+    let names = names.unwrap_or_default();
+    let names: Vec<&str> = names.split(':').collect();
+
+    Some((0..count).map(|n| (LISTEN_FDS_START + n, names.get(n as usize).copied().unwrap_or_default().to_owned())).collect())
+}
+
+pub fn bind_unix(path: &str) -> io::Result<UnixListenerStream> {
+    // A socket file left behind by a previous run would make the bind fail.
+    let _ = fs::remove_file(path);
+
+    Ok(UnixListenerStream::new(UnixListener::bind(path)?))
+}
+
+pub fn server_tls_config(cert: &str, key: &str, client_ca: Option<&str>) -> Result<ServerTlsConfig, String> {
+    let read = |path: &str| fs::read(path).map_err(|e| format!("Failed to read {path}.\n{e:?}"));
+
+    let tls_config = ServerTlsConfig::new().identity(Identity::from_pem(read(cert)?, read(key)?));
+
+    Ok(match client_ca {
+        Some(client_ca) => tls_config.client_ca_root(Certificate::from_pem(read(client_ca)?)),
+        None => tls_config,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_only_take_the_descriptors_passed_to_this_process() {
+        let fds = |pid: &str, fds: &str, names: Option<&str>| {
+            activated_fds(Some(pid.to_owned()), Some(fds.to_owned()), names.map(String::from), 42)
+        };
+
+        assert_eq!(fds("41", "2", None), None);
+        assert_eq!(activated_fds(None, Some(String::from("2")), None, 42), None);
+        assert_eq!(
+            fds("42", "2", Some("auth:admin")),
+            Some(vec![(3, String::from("auth")), (4, String::from(ADMIN_FD_NAME))])
+        );
+        assert_eq!(fds("42", "1", None), Some(vec![(3, String::new())]));
+    }
+
+    #[tokio::test]
+    async fn should_share_a_port_with_another_process_when_reusing_it() {
+        let any_port = SocketAddr::from(([127, 0, 0, 1], 0));
+        let first = bind_tcp(any_port, &[], true).unwrap();
+        let addr = first.as_ref().local_addr().unwrap();
+
+        assert!(bind_tcp(addr, &[], true).is_ok());
+        assert!(bind_tcp(addr, &[], false).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Service},
+    server::NamedService,
+    transport::Body,
+    Status,
+};
+
+use crate::config::Config;
+
+// A time budget of its own for one RPC, written `<Method>=<milliseconds>`, e.g. `SignUp=5000`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpcTimeout {
+    pub method: String,
+    pub timeout: Duration,
+}
+
+impl FromStr for RpcTimeout {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (method, millis) = value.split_once('=').ok_or_else(|| String::from("Error::InvalidRpcTimeout"))?;
+        let millis = millis.trim().parse().map_err(|_| String::from("Error::InvalidRpcTimeout"))?;
+
+        Ok(Self { method: method.trim().to_owned(), timeout: Duration::from_millis(millis) })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoadSheddingMetrics {
+    pub in_flight_requests: usize,
+    // Turned away because too many requests were in flight already.
+    pub overloaded_requests: u64,
+    // Given up on once over their time budget.
+    pub timed_out_requests: u64,
+}
+
+// Rather than queuing work it can't keep up with, the server turns requests away: past the cap on
+// requests in flight with RESOURCE_EXHAUSTED, and past their time budget with UNAVAILABLE. Both
+// are worth retrying, after a while, against this or another instance.
+pub struct LoadShedder {
+    // 0 means no cap.
+    max_in_flight: usize,
+    // Duration::ZERO means no budget.
+    default_timeout: Duration,
+    timeouts: HashMap<String, Duration>,
+    in_flight: AtomicUsize,
+    overloaded_requests: AtomicU64,
+    timed_out_requests: AtomicU64,
+}
+
+impl LoadShedder {
+    pub fn new(max_in_flight: usize, default_timeout: Duration, timeouts: Vec<RpcTimeout>) -> Self {
+        Self {
+            max_in_flight,
+            default_timeout,
+            timeouts: timeouts.into_iter().map(|timeout| (timeout.method, timeout.timeout)).collect(),
+            in_flight: AtomicUsize::new(0),
+            overloaded_requests: AtomicU64::new(0),
+            timed_out_requests: AtomicU64::new(0),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.max_in_flight_requests, config.rpc_timeout, config.rpc_timeouts.clone())
+    }
+
+    // A place among the requests in flight, given back when dropped; None when there is none left.
+    fn admit(self: &Arc<Self>) -> Option<InFlight> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let admitted = InFlight(self.clone());
+
+        if self.max_in_flight > 0 && in_flight >= self.max_in_flight {
+            self.overloaded_requests.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        Some(admitted)
+    }
+
+    // The budget of the RPC at `path` ("/authentication.v1.Auth/SignUp").
+    fn timeout(&self, path: &str) -> Duration {
+        let method = path.rsplit('/').next().unwrap_or_default();
+        self.timeouts.get(method).copied().unwrap_or(self.default_timeout)
+    }
+
+    pub fn metrics(&self) -> LoadSheddingMetrics {
+        LoadSheddingMetrics {
+            in_flight_requests: self.in_flight.load(Ordering::Relaxed),
+            overloaded_requests: self.overloaded_requests.load(Ordering::Relaxed),
+            timed_out_requests: self.timed_out_requests.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct InFlight(Arc<LoadShedder>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Puts a service behind the load shedder. Wraps the other layers, so shed requests cost as little
+// as possible.
+#[derive(Clone)]
+pub struct LoadSheddingService<S> {
+    inner: S,
+    shedder: Arc<LoadShedder>,
+}
+
+impl<S> LoadSheddingService<S> {
+    pub fn new(inner: S, shedder: Arc<LoadShedder>) -> Self {
+        Self { inner, shedder }
+    }
+}
+
+impl<S> Service<http::Request<Body>> for LoadSheddingService<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let Some(in_flight) = self.shedder.admit() else {
+            let mut status = Status::resource_exhausted("too many requests in flight, retry later");
+            status.metadata_mut().insert("retry-after", 1.into());
+            return Box::pin(async move { Ok(status.to_http()) });
+        };
+
+        let timeout = self.shedder.timeout(request.uri().path());
+        let response = self.inner.call(request);
+        let shedder = self.shedder.clone();
+
+        Box::pin(async move {
+            let _in_flight = in_flight;
+            if timeout.is_zero() {
+                return response.await;
+            }
+
+            match tokio::time::timeout(timeout, response).await {
+                Ok(response) => response,
+                Err(_) => {
+                    shedder.timed_out_requests.fetch_add(1, Ordering::Relaxed);
+                    Ok(Status::unavailable("timed out, retry later").to_http())
+                }
+            }
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for LoadSheddingService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::body::empty_body;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    #[test]
+    fn should_parse_rpc_timeout() {
+        assert_eq!(
+            "SignUp=5000".parse::<RpcTimeout>(),
+            Ok(RpcTimeout { method: "SignUp".to_owned(), timeout: Duration::from_secs(5) })
+        );
+        assert!("SignUp".parse::<RpcTimeout>().is_err());
+
+        let shedder = LoadShedder::new(0, Duration::from_secs(30), vec!["SignUp=5000".parse().unwrap()]);
+        assert_eq!(shedder.timeout("/authentication.v1.Auth/SignUp"), Duration::from_secs(5));
+        assert_eq!(shedder.timeout("/authentication.v1.Auth/SignIn"), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn should_refuse_requests_over_the_cap() {
+        let shedder = Arc::new(LoadShedder::new(2, Duration::ZERO, vec![]));
+
+        let first = shedder.admit().unwrap();
+        let _second = shedder.admit().unwrap();
+        assert!(shedder.admit().is_none());
+
+        drop(first);
+        assert!(shedder.admit().is_some());
+        assert_eq!(
+            shedder.metrics(),
+            LoadSheddingMetrics { in_flight_requests: 1, overloaded_requests: 1, timed_out_requests: 0 }
+        );
+    }
+
+    #[tokio::test]
+    async fn should_give_up_on_requests_over_budget() {
+        let shedder = Arc::new(LoadShedder::new(0, Duration::from_millis(10), vec![]));
+        let slow = service_fn(|_: http::Request<Body>| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok::<_, Infallible>(http::Response::new(empty_body()))
+        });
+
+        let request = http::Request::builder().uri("/authentication.v1.Auth/SignUp").body(Body::empty()).unwrap();
+        let response = LoadSheddingService::new(slow, shedder.clone()).oneshot(request).await.unwrap();
+
+        assert_eq!(response.headers()["grpc-status"], (tonic::Code::Unavailable as i32).to_string().as_str());
+        assert_eq!(shedder.metrics().timed_out_requests, 1);
+        assert_eq!(shedder.metrics().in_flight_requests, 0);
+    }
+}
@@ -0,0 +1,134 @@
+use std::env;
+
+use hyper::http;
+use opentelemetry::{
+    propagation::{Extractor, TextMapPropagator},
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    trace::TraceContextExt,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::{info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter, Registry};
+
+// The log filter, in `EnvFilter` syntax: a default level and per-module directives, such as
+// "info,auth::sessions=debug,h2=warn".
+pub trait LogFilter {
+    fn current(&self) -> String;
+    fn set(&self, directives: &str) -> Result<(), String>;
+}
+
+// The filter of the global subscriber, changeable while running.
+pub struct ReloadableLogFilter(reload::Handle<EnvFilter, Registry>);
+
+impl LogFilter for ReloadableLogFilter {
+    fn current(&self) -> String {
+        self.0.with_current(|filter| filter.to_string()).unwrap_or_default()
+    }
+
+    fn set(&self, directives: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(directives).map_err(|e| format!("Error::InvalidLogFilter: {e}"))?;
+        self.0.reload(filter).map_err(|e| format!("Failed to reload the log filter.\n{e:?}"))
+    }
+}
+
+// For when no subscriber was set up, e.g. in tests.
+pub struct FixedLogFilter;
+
+impl LogFilter for FixedLogFilter {
+    fn current(&self) -> String {
+        String::new()
+    }
+
+    fn set(&self, _directives: &str) -> Result<(), String> {
+        Err(String::from("Error::LogFilterNotReloadable"))
+    }
+}
+
+// Installs the global subscriber, filtered by AUTH_LOG ("info" when unset). With
+// OTEL_EXPORTER_OTLP_ENDPOINT set, the spans are exported there too.
+pub fn init_logging() -> Result<ReloadableLogFilter, String> {
+    let directives = env::var("AUTH_LOG").unwrap_or_else(|_| String::from("info"));
+    let filter = EnvFilter::try_new(&directives).map_err(|e| format!("Error::InvalidConfig: AUTH_LOG={directives}\n{e:?}"))?;
+
+    let tracer = match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(_) => Some(
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_env())
+                .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new("service.name", "auth")])))
+                .install_batch(opentelemetry::runtime::Tokio)
+                .map_err(|e| format!("Failed to set up trace export.\n{e:?}"))?,
+        ),
+        Err(_) => None,
+    };
+
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .try_init()
+        .map_err(|e| format!("Failed to set up logging.\n{e:?}"))?;
+
+    Ok(ReloadableLogFilter(handle))
+}
+
+struct HeaderExtractor<'a>(&'a http::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+// The span an RPC is handled in. It continues the caller's trace, when the request came with a
+// W3C `traceparent` (as the health check's do), and names it, so the logs can be found by it even
+// when the spans aren't exported.
+pub fn rpc_span(request: &http::Request<()>) -> Span {
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(request.headers()));
+    let span_context = parent.span().span_context().clone();
+
+    let span = match span_context.is_valid() {
+        true => info_span!("rpc", path = %request.uri().path(), trace_id = %span_context.trace_id()),
+        false => info_span!("rpc", path = %request.uri().path()),
+    };
+    span.set_parent(parent);
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_continue_the_callers_trace() {
+        let request = http::Request::get("/authentication.v1.Auth/SignIn")
+            .header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+            .body(())
+            .unwrap();
+        let parent = TraceContextPropagator::new().extract(&HeaderExtractor(request.headers()));
+
+        assert_eq!(parent.span().span_context().trace_id().to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert!(!TraceContextPropagator::new().extract(&HeaderExtractor(&http::HeaderMap::new())).has_active_span());
+    }
+
+    #[test]
+    fn should_reload_valid_filter_only() {
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        // Keeps the filter alive, without making it global.
+        let _subscriber = tracing_subscriber::registry().with(filter);
+        let log_filter = ReloadableLogFilter(handle);
+
+        log_filter.set("warn,auth::sessions=debug").unwrap();
+        assert!(log_filter.current().contains("auth::sessions=debug"));
+
+        assert!(log_filter.set("auth::sessions=loud").is_err());
+        assert!(log_filter.current().contains("auth::sessions=debug"));
+    }
+}
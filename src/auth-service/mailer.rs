@@ -0,0 +1,38 @@
+use tracing::warn;
+
+// Delivers messages (magic links, notifications, ...) to users. Usernames double as addresses.
+pub trait Mailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String>;
+}
+
+// Logs who a message was for instead of sending it, until an SMTP relay is wired in. Never the
+// body: a magic link in the logs would sign in whoever reads them.
+#[derive(Default)]
+pub struct LoggingMailer;
+
+impl Mailer for LoggingMailer {
+    fn send(&self, to: &str, subject: &str, _body: &str) -> Result<(), String> {
+        warn!("no mailer configured, so mail to {} ({}) was not sent", to, subject);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    // Keeps sent messages as (to, body) pairs, for tests to inspect.
+    #[derive(Clone, Default)]
+    pub struct RecordingMailer {
+        pub sent: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    impl Mailer for RecordingMailer {
+        fn send(&self, to: &str, _subject: &str, body: &str) -> Result<(), String> {
+            self.sent.lock().unwrap().push((to.to_owned(), body.to_owned()));
+            Ok(())
+        }
+    }
+}
@@ -1,32 +1,36 @@
-use std::sync::Mutex;
+use std::process::ExitCode;
 
-mod auth;
-mod sessions;
-mod users;
-
-use auth::*;
-use sessions::{SessionsImpl, SessionsOps};
-use users::{UsersImpl, UsersOps};
+use auth::config::Config;
+use auth::error::{exit_with, Error};
+use auth::logging::init_logging;
+use auth::server::serve_with_log_filter;
+use tokio::signal::unix::{signal, SignalKind};
 
+// Exits with a code telling what went wrong (see `Error::exit_code`): a bad setting, which a
+// restart won't fix, or a store or peer that may be back by then.
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Here we are using ip 0.0.0.0 so the service is listening on all the configured network interfaces. This is needed for Docker to work, which we will add later on.
-    // See: https://stackoverflow.com/questions/39525820/docker-port-forwarding-not-working
-    // Port 50051 is the recommended gRPC port.
-    let addr = "[::0]:50051".parse()?;
-
-    let users_service: Box<Mutex<dyn UsersOps + Send + Sync + 'static>> = Box::new(Mutex::new(UsersImpl::default()));   
-    let sessions_service: Box<Mutex<dyn SessionsOps + Send + Sync + 'static>> = Box::new(Mutex::new(SessionsImpl::default())); 
-
-    let auth_service = AuthService::new(users_service, sessions_service);
-
-    println!("auth-server, starts at {:?}", addr);
+async fn main() -> ExitCode {
+    exit_with(run().await)
+}
 
-    // Instantiate gRPC server
-    Server::builder()
-        .add_service(AuthServer::new(auth_service))
-        .serve(addr)
-        .await?;
+async fn run() -> Result<(), Error> {
+    // AUTH_LOG filters the logs, e.g. "info,auth::sessions=debug"; SetLogLevel changes it while running.
+    let log_filter = init_logging().map_err(Error::Config)?;
+
+    let config = Config::from_env()?;
+
+    // Stop as soon as any listener fails. SIGTERM (what docker and systemd send) and ctrl-c drain
+    // the calls in flight first, for AUTH_DRAIN_TIMEOUT_SECS at most, so an upgrade taking over the
+    // sockets (AUTH_REUSE_PORT, or systemd socket activation) drops none.
+    let mut terminate = signal(SignalKind::terminate())?;
+    let stop = async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    };
+    let drain_timeout = config.drain_timeout;
+    serve_with_log_filter(config, Box::new(log_filter)).await?.wait_until(stop, drain_timeout).await?;
 
     Ok(())
 }
@@ -0,0 +1,219 @@
+use std::{path::Path, str::FromStr, time::SystemTime};
+
+use crate::{
+    error::StoreError,
+    journal::{FileJournal, JournalError},
+    sessions::{SessionEvent, SessionsImpl, SessionsOps},
+    users::{UserOrder, UserQuery, UsersImpl, UsersOps},
+};
+
+// What a migration fails with; the stores' own errors are kept as they are.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MigrationError {
+    #[error("Error::UnknownBackend: {0}")]
+    UnknownBackend(String),
+    #[error("Error::TargetNotEmpty")]
+    TargetNotEmpty,
+    #[error("{error}: {user_uuid}")]
+    ImportFailed { user_uuid: String, error: StoreError },
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Journal(#[from] JournalError),
+}
+
+// Where users and sessions are kept, for `auth-admin migrate`: `memory` (empty; as a target, a
+// dry run) or `event-log:<dir>`, a directory of journals as AUTH_EVENT_LOG_DIR keeps them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Memory,
+    EventLog(String),
+}
+
+impl FromStr for Backend {
+    type Err = MigrationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "memory" => Ok(Self::Memory),
+            Some(("event-log", dir)) if !dir.is_empty() => Ok(Self::EventLog(dir.to_owned())),
+            _ => Err(MigrationError::UnknownBackend(s.to_owned())),
+        }
+    }
+}
+
+impl Backend {
+    pub fn open(&self) -> Result<(UsersImpl, SessionsImpl), MigrationError> {
+        match self {
+            Backend::Memory => Ok((UsersImpl::default(), SessionsImpl::default())),
+            Backend::EventLog(dir) => {
+                let dir = Path::new(dir);
+                let users = UsersImpl::default().with_journal(Box::new(FileJournal::open(dir.join("users.jsonl"))?))?;
+                let sessions =
+                    SessionsImpl::default().with_journal(Box::new(FileJournal::open(dir.join("sessions.jsonl"))?))?;
+                Ok((users, sessions))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub users: usize,
+    pub sessions: usize,
+    // Users the source couldn't hand out (see `UsersOps::export_user`).
+    pub skipped_users: Vec<String>,
+    // What the verification pass found different in the target, one line each.
+    pub mismatches: Vec<String>,
+}
+
+// Copies every user and live session from one pair of stores to another, `batch_size` at a time,
+// calling `progress` after each batch; then reads both back to check the target holds the same.
+// The target has to be empty, so a migration run twice fails instead of mixing the two.
+pub fn migrate(
+    from_users: &dyn UsersOps,
+    from_sessions: &dyn SessionsOps,
+    to_users: &mut dyn UsersOps,
+    to_sessions: &mut dyn SessionsOps,
+    batch_size: usize,
+    mut progress: impl FnMut(&MigrationReport),
+) -> Result<MigrationReport, MigrationError> {
+    if to_users.user_count() > 0 || to_sessions.session_count() > 0 {
+        return Err(MigrationError::TargetNotEmpty);
+    }
+
+    let batch_size = batch_size.max(1);
+    let mut report = MigrationReport::default();
+
+    for_each_user_page(from_users, batch_size, |user_uuids| {
+        for user_uuid in user_uuids {
+            match from_users.export_user(&user_uuid) {
+                Some(events) => {
+                    to_users
+                        .import_user(events)
+                        .map_err(|error| MigrationError::ImportFailed { user_uuid: user_uuid.clone(), error })?;
+                    report.users += 1;
+                }
+                None => report.skipped_users.push(user_uuid),
+            }
+        }
+        progress(&report);
+        Ok(())
+    })?;
+
+    // Parents come before their delegated sessions, so every batch finds the parents it needs.
+    let sessions = from_sessions.export_sessions();
+    for batch in sessions.chunks(batch_size) {
+        report.sessions += to_sessions.apply_replicated(batch.to_vec());
+        progress(&report);
+    }
+
+    report.mismatches = verify(from_users, &sessions, to_users, to_sessions, batch_size)?;
+    Ok(report)
+}
+
+fn verify(
+    from_users: &dyn UsersOps,
+    sessions: &[SessionEvent],
+    to_users: &dyn UsersOps,
+    to_sessions: &dyn SessionsOps,
+    batch_size: usize,
+) -> Result<Vec<String>, MigrationError> {
+    let mut mismatches = Vec::new();
+
+    for_each_user_page(from_users, batch_size, |user_uuids| {
+        for user_uuid in user_uuids {
+            let Some(exported) = from_users.export_user(&user_uuid) else { continue };
+            if to_users.export_user(&user_uuid).as_ref() != Some(&exported) {
+                mismatches.push(format!("user {user_uuid} differs"));
+            }
+        }
+        Ok(())
+    })?;
+
+    for event in sessions {
+        let SessionEvent::SessionCreated { session_token, user_uuid, expires_at, .. } = event else { continue };
+        match to_sessions.get_session(session_token) {
+            Some(session) if session.user_uuid == *user_uuid && session.expires_at == *expires_at => {}
+            Some(_) => mismatches.push(format!("session of {user_uuid} differs")),
+            // Sessions that expired since the export are fine to miss.
+            None if *expires_at <= SystemTime::now() => {}
+            None => mismatches.push(format!("session of {user_uuid} is missing")),
+        }
+    }
+
+    Ok(mismatches)
+}
+
+// The uuids of every user of `users`, a page at a time.
+fn for_each_user_page(
+    users: &dyn UsersOps,
+    batch_size: usize,
+    mut each: impl FnMut(Vec<String>) -> Result<(), MigrationError>,
+) -> Result<(), MigrationError> {
+    let mut cursor = None;
+    loop {
+        let query = UserQuery { order: UserOrder::CreatedAt, cursor, limit: batch_size, ..UserQuery::default() };
+        let page = users.list_users(&query)?;
+
+        each(page.users.into_iter().map(|user| user.user_uuid).collect())?;
+
+        match page.next_cursor {
+            Some(next_cursor) => cursor = Some(next_cursor),
+            None => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{sessions::SessionClass, users::AccountStatus};
+
+    use super::*;
+
+    #[test]
+    fn should_copy_users_and_sessions_and_verify_them() {
+        let (mut from_users, mut from_sessions) = Backend::Memory.open().unwrap();
+        for username in ["alice", "bob", "carol"] {
+            from_users.create_user(username.to_owned(), "password".to_owned()).unwrap();
+        }
+        let bob = from_users.find_user_uuid("bob").unwrap();
+        from_users.set_account_status(&bob, AccountStatus::Suspended).unwrap();
+        from_users.set_metadata(&bob, "locale", "fr").unwrap();
+        let external = from_users.link_external_user("google", "1234").unwrap();
+        let session_token = from_sessions.create_scoped_session(&bob, SessionClass::Standard, vec!["profile:read".to_owned()]);
+        let (delegated, _) = from_sessions
+            .create_delegated_session(&session_token, vec!["profile:read".to_owned()], Duration::from_secs(60))
+            .unwrap();
+
+        let (mut to_users, mut to_sessions) = Backend::Memory.open().unwrap();
+        let mut batches = 0;
+        let report =
+            migrate(&from_users, &from_sessions, &mut to_users, &mut to_sessions, 2, |_| batches += 1).unwrap();
+
+        assert_eq!((report.users, report.sessions), (4, 2));
+        assert!(report.skipped_users.is_empty());
+        assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+        // Two pages of users, one of sessions.
+        assert_eq!(batches, 3);
+
+        assert_eq!(to_users.get_user_uuid("alice".to_owned(), "password".to_owned()), from_users.find_user_uuid("alice"));
+        assert_eq!(to_users.get_account_status(&bob), Some(AccountStatus::Suspended));
+        assert_eq!(to_users.link_external_user("google", "1234"), Ok(external));
+        assert_eq!(to_sessions.get_session(&delegated).unwrap().parent, Some(session_token.clone()));
+
+        // Once is enough.
+        let rerun = migrate(&from_users, &from_sessions, &mut to_users, &mut to_sessions, 2, |_| {});
+        assert_eq!(rerun, Err(MigrationError::TargetNotEmpty));
+    }
+
+    #[test]
+    fn should_parse_backends() {
+        assert_eq!("memory".parse(), Ok(Backend::Memory));
+        assert_eq!("event-log:/var/lib/auth".parse(), Ok(Backend::EventLog("/var/lib/auth".to_owned())));
+        assert!("postgres://localhost".parse::<Backend>().is_err());
+        assert!("event-log:".parse::<Backend>().is_err());
+    }
+}
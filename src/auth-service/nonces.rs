@@ -0,0 +1,131 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tonic::Request;
+
+// Longer nonces are refused rather than kept.
+const MAX_NONCE_LEN: usize = 128;
+
+// What a client sends along with a sensitive RPC so a captured copy of the request can't be sent
+// again: a nonce it never used before, in `x-request-nonce`, and when it sent the request, in
+// `x-request-timestamp` (seconds since the epoch).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestNonce {
+    pub nonce: String,
+    pub sent_at: SystemTime,
+}
+
+impl RequestNonce {
+    pub fn from_request<T>(request: &Request<T>) -> Option<Self> {
+        let header = |name| request.metadata().get(name).and_then(|value| value.to_str().ok());
+
+        let nonce = header("x-request-nonce").filter(|nonce| !nonce.is_empty())?;
+        let sent_at = header("x-request-timestamp")?.parse().ok()?;
+        // Past what a `SystemTime` holds is no timestamp at all.
+        let sent_at = UNIX_EPOCH.checked_add(Duration::from_secs(sent_at))?;
+
+        Some(Self { nonce: nonce.to_owned(), sent_at })
+    }
+}
+
+// The nonces seen lately. A request is only taken within `window` of when it says it was sent,
+// so each nonce is kept until then; after that the timestamp gives a replay away.
+#[derive(Default)]
+pub struct SeenNonces(Mutex<HashMap<String, SystemTime>>);
+
+impl SeenNonces {
+    // Ok, and the nonce remembered, the first time it comes with a timestamp within `window` of
+    // `now`; otherwise why the request is refused.
+    pub fn check(&self, nonce: Option<&RequestNonce>, window: Duration, now: SystemTime) -> Result<(), String> {
+        let nonce = nonce.ok_or_else(|| String::from("Error::NonceMissing"))?;
+        if nonce.nonce.len() > MAX_NONCE_LEN {
+            return Err(String::from("Error::NonceTooLong"));
+        }
+
+        let skew = now.duration_since(nonce.sent_at).unwrap_or_else(|e| e.duration());
+        let expires_at = nonce.sent_at.checked_add(window).filter(|_| skew <= window);
+        let Some(expires_at) = expires_at else {
+            return Err(String::from("Error::RequestTimestampOutOfWindow"));
+        };
+
+        let mut seen = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if seen.contains_key(&nonce.nonce) {
+            return Err(String::from("Error::NonceReused"));
+        }
+        seen.insert(nonce.nonce.clone(), expires_at);
+        Ok(())
+    }
+
+    // Drops the nonces whose requests are out of the window by now.
+    pub fn forget_expired(&self, now: SystemTime) {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).retain(|_, expires_at| *expires_at > now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nonce(nonce: &str, sent_at: SystemTime) -> RequestNonce {
+        RequestNonce { nonce: nonce.to_owned(), sent_at }
+    }
+
+    #[test]
+    fn should_take_each_nonce_once_within_the_window() {
+        let seen = SeenNonces::default();
+        let window = Duration::from_secs(300);
+        let now = SystemTime::now();
+
+        assert_eq!(seen.check(Some(&nonce("a", now)), window, now), Ok(()));
+        assert_eq!(seen.check(Some(&nonce("a", now)), window, now), Err(String::from("Error::NonceReused")));
+        assert_eq!(seen.check(Some(&nonce("b", now - window * 2)), window, now), Err(String::from("Error::RequestTimestampOutOfWindow")));
+        assert_eq!(seen.check(Some(&nonce("c", now + window * 2)), window, now), Err(String::from("Error::RequestTimestampOutOfWindow")));
+        assert_eq!(seen.check(None, window, now), Err(String::from("Error::NonceMissing")));
+
+        // Forgotten once its request is out of the window anyway.
+        seen.forget_expired(now + window / 2);
+        assert_eq!(seen.len(), 1);
+        seen.forget_expired(now + window * 2);
+        assert!(seen.is_empty());
+    }
+
+    #[test]
+    fn should_read_nonce_from_metadata() {
+        let mut request = Request::new(());
+        request.metadata_mut().insert("x-request-nonce", "abc".parse().unwrap());
+        assert_eq!(RequestNonce::from_request(&request), None);
+
+        request.metadata_mut().insert("x-request-timestamp", "1700000000".parse().unwrap());
+        assert_eq!(
+            RequestNonce::from_request(&request),
+            Some(nonce("abc", UNIX_EPOCH + Duration::from_secs(1_700_000_000)))
+        );
+    }
+
+    #[test]
+    fn should_not_take_timestamps_past_the_end_of_time() {
+        let mut request = Request::new(());
+        request.metadata_mut().insert("x-request-nonce", "abc".parse().unwrap());
+        request.metadata_mut().insert("x-request-timestamp", u64::MAX.to_string().parse().unwrap());
+        assert_eq!(RequestNonce::from_request(&request), None);
+
+        // Nor a window that runs past it.
+        let seen = SeenNonces::default();
+        let now = SystemTime::now();
+        assert_eq!(
+            seen.check(Some(&nonce("a", now)), Duration::MAX, now),
+            Err(String::from("Error::RequestTimestampOutOfWindow"))
+        );
+    }
+}
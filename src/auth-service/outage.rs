@@ -0,0 +1,124 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use tonic::Status;
+use tracing::{info, warn};
+
+use crate::error::Error;
+
+// Whether what the stores persist to (the journals, the LDAP directory) answered the last store
+// check (see `AuthService::check_stores`), and since when it hasn't. While it is down, changes to
+// accounts are refused with UNAVAILABLE instead of being half made, and sessions are validated from
+// memory for `max_staleness` at most. Everything resumes with the first check that passes.
+#[derive(Default)]
+pub struct StoreOutage {
+    // Since when, and the last failure.
+    down: Mutex<Option<(Instant, String)>>,
+    refused_changes: AtomicU64,
+    stale_validations: AtomicU64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutageMetrics {
+    pub down_for: Option<Duration>,
+    // Account changes refused, and sessions validated from memory, while down; so far.
+    pub refused_changes: u64,
+    pub stale_validations: u64,
+}
+
+impl StoreOutage {
+    pub fn record(&self, result: &Result<(), Error>) {
+        let mut down = self.down.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match (result, down.as_ref()) {
+            (Err(e), None) => {
+                warn!("store down, serving sessions from memory: {}", e);
+                *down = Some((Instant::now(), e.to_string()));
+            }
+            (Err(e), Some((since, _))) => *down = Some((*since, e.to_string())),
+            (Ok(()), Some((since, _))) => {
+                info!("store back after {:?}", since.elapsed());
+                *down = None;
+            }
+            (Ok(()), None) => {}
+        }
+    }
+
+    pub fn down_for(&self) -> Option<Duration> {
+        self.down.lock().unwrap_or_else(PoisonError::into_inner).as_ref().map(|(since, _)| since.elapsed())
+    }
+
+    // For RPCs that change accounts.
+    pub fn check_change(&self) -> Result<(), Status> {
+        if self.down_for().is_none() {
+            return Ok(());
+        }
+
+        self.refused_changes.fetch_add(1, Ordering::Relaxed);
+        Err(Error::Storage(String::from("store unavailable, retry later")).into())
+    }
+
+    // For validating sessions: fine from memory until the store has been down for longer than
+    // `max_staleness` (0 for no limit), as sessions revoked elsewhere meanwhile aren't seen here.
+    pub fn check_staleness(&self, max_staleness: Duration) -> Result<(), Status> {
+        let Some(down_for) = self.down_for() else { return Ok(()) };
+
+        if !max_staleness.is_zero() && down_for > max_staleness {
+            return Err(Error::Storage(String::from("store unavailable for too long, retry later")).into());
+        }
+        self.stale_validations.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn metrics(&self) -> OutageMetrics {
+        OutageMetrics {
+            down_for: self.down_for(),
+            refused_changes: self.refused_changes.load(Ordering::Relaxed),
+            stale_validations: self.stale_validations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    #[test]
+    fn should_refuse_changes_until_the_store_is_back() {
+        let outage = StoreOutage::default();
+        assert!(outage.check_change().is_ok());
+
+        outage.record(&Err(Error::Storage(String::from("users: Error::JournalUnavailable"))));
+        assert_eq!(outage.check_change().unwrap_err().code(), Code::Unavailable);
+        assert!(outage.check_staleness(Duration::from_secs(60)).is_ok());
+
+        outage.record(&Ok(()));
+        assert!(outage.check_change().is_ok());
+        assert_eq!(
+            outage.metrics(),
+            OutageMetrics { down_for: None, refused_changes: 1, stale_validations: 1 }
+        );
+    }
+
+    #[test]
+    fn should_stop_validating_past_max_staleness() {
+        let outage = StoreOutage::default();
+        let since = Instant::now() - Duration::from_secs(120);
+        *outage.down.lock().unwrap() = Some((since, String::from("users: Error::DirectoryUnavailable")));
+
+        assert!(outage.check_staleness(Duration::from_secs(60)).is_err());
+        assert!(outage.check_staleness(Duration::from_secs(300)).is_ok());
+        assert!(outage.check_staleness(Duration::ZERO).is_ok());
+
+        // Still down: the outage is counted from its start.
+        outage.record(&Err(Error::Storage(String::from("users: Error::DirectoryUnavailable"))));
+        assert!(outage.down_for().unwrap() >= Duration::from_secs(120));
+    }
+}
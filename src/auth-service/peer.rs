@@ -0,0 +1,105 @@
+use std::net::IpAddr;
+
+use tonic::{metadata::MetadataMap, Request};
+
+use crate::ip_rules::Cidr;
+
+// Who sent a request. Behind a load balancer or gateway the connection comes from the proxy, and
+// the client is read off `x-forwarded-for` (or `x-real-ip`) instead, but only as far as trusted
+// proxies vouch for it: anyone else can write those headers too.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PeerInfo {
+    // The other end of the connection; None over a unix socket.
+    pub remote_ip: Option<IpAddr>,
+    // The client, as far as we can tell. What the throttle, the ip rules, the audit log and the
+    // sessions' devices go by.
+    pub client_ip: Option<IpAddr>,
+}
+
+impl PeerInfo {
+    pub fn from_request<T>(request: &Request<T>, trusted_proxies: &[Cidr]) -> Self {
+        Self::new(request.remote_addr().map(|addr| addr.ip()), request.metadata(), trusted_proxies)
+    }
+
+    // Addresses are canonical: an IPv4 client of a dual-stack socket is its IPv4 address, not the
+    // IPv4-mapped IPv6 one, so that it is throttled, audited and matched the same either way.
+    pub fn new(remote_ip: Option<IpAddr>, metadata: &MetadataMap, trusted_proxies: &[Cidr]) -> Self {
+        let remote_ip = remote_ip.map(|ip| ip.to_canonical());
+        let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|cidr| cidr.contains(ip));
+
+        let header = |name| {
+            metadata
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .collect::<Vec<_>>()
+        };
+
+        // Every proxy appends the address it got the request from, so walking back from the end,
+        // each address is vouched for by the one after it, up to the first that isn't trusted.
+        let forwarded_for = header("x-forwarded-for");
+        let hops = if forwarded_for.is_empty() { header("x-real-ip") } else { forwarded_for };
+
+        let mut client_ip = remote_ip;
+        for hop in hops.iter().rev() {
+            match (client_ip, hop.parse::<IpAddr>()) {
+                (Some(ip), Ok(hop)) if is_trusted(ip) => client_ip = Some(hop.to_canonical()),
+                _ => break,
+            }
+        }
+
+        Self { remote_ip, client_ip }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_ip(remote_ip: &str, headers: &[(&'static str, &str)]) -> IpAddr {
+        let trusted_proxies: Vec<Cidr> = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut metadata = MetadataMap::new();
+        for (name, value) in headers {
+            metadata.append(*name, value.parse().unwrap());
+        }
+
+        PeerInfo::new(Some(remote_ip.parse().unwrap()), &metadata, &trusted_proxies).client_ip.unwrap()
+    }
+
+    #[test]
+    fn should_only_believe_trusted_proxies() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        // No proxy, or one we don't trust: the headers are the caller's word only.
+        assert_eq!(client_ip("203.0.113.7", &[]), ip("203.0.113.7"));
+        assert_eq!(client_ip("203.0.113.7", &[("x-forwarded-for", "198.51.100.1")]), ip("203.0.113.7"));
+
+        assert_eq!(client_ip("10.0.0.1", &[("x-forwarded-for", "198.51.100.1")]), ip("198.51.100.1"));
+        assert_eq!(client_ip("10.0.0.1", &[("x-real-ip", "198.51.100.1")]), ip("198.51.100.1"));
+
+        // Through two proxies of ours; whatever the client claimed before that is ignored.
+        assert_eq!(
+            client_ip("10.0.0.1", &[("x-forwarded-for", "192.0.2.9, 198.51.100.1, 10.0.0.2")]),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            client_ip("10.0.0.1", &[("x-forwarded-for", "192.0.2.9"), ("x-forwarded-for", "198.51.100.1")]),
+            ip("198.51.100.1")
+        );
+
+        // Garbage stops the walk at the last proxy that could be believed.
+        assert_eq!(client_ip("10.0.0.1", &[("x-forwarded-for", "unknown")]), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn should_see_ipv4_mapped_addresses_as_ipv4() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert_eq!(client_ip("::ffff:203.0.113.7", &[]), ip("203.0.113.7"));
+        // A proxy of ours, connecting over IPv6 and forwarding a mapped client.
+        assert_eq!(client_ip("::ffff:10.0.0.1", &[("x-forwarded-for", "::ffff:198.51.100.1")]), ip("198.51.100.1"));
+        assert_eq!(client_ip("2001:db8::1", &[]), ip("2001:db8::1"));
+    }
+}
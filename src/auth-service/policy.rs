@@ -0,0 +1,46 @@
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{Mutex, PoisonError},
+};
+
+use tracing::info;
+
+// How a policy that turns requests away is rolled out. In `Shadow` mode it is evaluated as it
+// would be when enforced, but what it would have refused is only logged and counted (see
+// `ShadowRefusals`), so operators can tell how many users it would hit before enforcing it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PolicyMode {
+    Off,
+    Shadow,
+    #[default]
+    Enforce,
+}
+
+impl FromStr for PolicyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "shadow" => Ok(Self::Shadow),
+            "enforce" => Ok(Self::Enforce),
+            _ => Err(format!("Error::UnknownPolicyMode: {s}")),
+        }
+    }
+}
+
+// The requests each policy in shadow mode would have refused, so far, for GetStats.
+#[derive(Default)]
+pub struct ShadowRefusals(Mutex<BTreeMap<&'static str, u64>>);
+
+impl ShadowRefusals {
+    pub fn record(&self, policy: &'static str, what: &str) {
+        info!("shadow: {} would have refused {}", policy, what);
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner).entry(policy).or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
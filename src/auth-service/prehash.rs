@@ -0,0 +1,64 @@
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+
+use crate::auth::authentication::PasswordPrehash as ProtoPasswordPrehash;
+
+pub const PREHASH_ALGORITHM: &str = "PBKDF2-HMAC-SHA256";
+// Bytes; sent hex encoded, so twice as many characters.
+pub const PREHASH_KEY_LEN: usize = 32;
+
+// Client side pre-hashing of passwords (AUTH_PASSWORD_PREHASH_ITERATIONS): clients send a key
+// derived from the password in its place, so the password itself never reaches the service, not
+// even inside TLS. The service takes the key for the password and hashes it again, as it would
+// any password (see `PasswordPolicy`). GetServerInfo tells clients the parameters.
+//
+// The salt is the deployment's rather than the user's, so renaming an account doesn't change the
+// key; the service salts each user's hash of it anyway.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PasswordPrehash {
+    pub iterations: u32,
+    pub salt: String,
+}
+
+impl PasswordPrehash {
+    // What clients send for `password`: the derived key, lowercase hex.
+    pub fn derive(&self, password: &str) -> String {
+        let mut key = [0u8; PREHASH_KEY_LEN];
+        pbkdf2_hmac::<Sha256>(password.as_bytes(), self.salt.as_bytes(), self.iterations, &mut key);
+
+        key.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+// Whether `password` can be a derived key at all; a client sending the password itself is caught
+// at sign up, before it gets stored.
+pub fn is_derived_key(password: &str) -> bool {
+    password.len() == PREHASH_KEY_LEN * 2 && password.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+impl From<&PasswordPrehash> for ProtoPasswordPrehash {
+    fn from(prehash: &PasswordPrehash) -> Self {
+        Self {
+            algorithm: PREHASH_ALGORITHM.to_owned(),
+            iterations: prehash.iterations,
+            salt: prehash.salt.clone(),
+            key_length: PREHASH_KEY_LEN as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_derive_keys_clients_can_reproduce() {
+        // RFC 7914, section 11: PBKDF2-HMAC-SHA256 of "passwd" with salt "salt" and 1 iteration.
+        let prehash = PasswordPrehash { iterations: 1, salt: "salt".to_owned() };
+        assert_eq!(prehash.derive("passwd"), "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc");
+
+        assert!(is_derived_key(&prehash.derive("passwd")));
+        assert!(!is_derived_key("passwd"));
+        assert!(!is_derived_key(&prehash.derive("passwd").to_uppercase()));
+    }
+}
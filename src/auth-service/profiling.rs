@@ -0,0 +1,248 @@
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, PoisonError},
+    time::Duration,
+};
+
+use hyper::{
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tokio::{runtime::Handle, sync::Mutex};
+use tonic::{metadata::MetadataMap, Code};
+use tracing::info;
+
+use crate::{
+    auth::AuthService,
+    ip_rules::{Cidr, SharedAccessLists},
+    peer::PeerInfo,
+};
+
+const DEFAULT_PROFILE_SECS: u64 = 30;
+const MAX_PROFILE_SECS: u64 = 300;
+// Samples a second: not a divisor of the usual timer frequencies, so samples don't line up with
+// periodic work.
+const PROFILE_FREQUENCY: i32 = 99;
+
+// What a production instance is doing, on demand, for when it is slow or busy and nobody knows
+// why: served over plain HTTP (AUTH_DEBUG_LISTEN_ADDR), to admins only (the `x-api-key` of an
+// admin, allowed `Profile` or `DumpTasks` by the admin policy) calling from where the admin
+// access list allows. Plain HTTP as it is, it won't start next to an admin service behind TLS.
+//
+// - `GET /debug/pprof/profile?seconds=<n>` samples the CPU for n seconds (30 by default, at most
+//   300) and answers folded stacks, one line a stack with its sample count: what flamegraph.pl,
+//   inferno or speedscope take. One profile at a time.
+// - `GET /debug/tasks` answers what the runtime has going: its workers, the tasks alive and
+//   waiting, and the background jobs with their last run, as JSON.
+#[derive(Clone)]
+pub struct DebugEndpoint {
+    auth_service: Arc<AuthService>,
+    access_lists: SharedAccessLists,
+    trusted_proxies: Arc<Vec<Cidr>>,
+    profiling: Arc<Mutex<()>>,
+}
+
+impl DebugEndpoint {
+    pub fn new(auth_service: Arc<AuthService>, access_lists: SharedAccessLists, trusted_proxies: Arc<Vec<Cidr>>) -> Self {
+        Self { auth_service, access_lists, trusted_proxies, profiling: Arc::default() }
+    }
+
+    async fn respond(&self, request: Request<Body>, remote_addr: SocketAddr) -> Response<Body> {
+        let action = match (request.method(), request.uri().path()) {
+            (&Method::GET, "/debug/pprof/profile") => "Profile",
+            (&Method::GET, "/debug/tasks") => "DumpTasks",
+            _ => return reply(StatusCode::NOT_FOUND, String::new()),
+        };
+
+        let metadata = MetadataMap::from_headers(request.headers().clone());
+        let peer = PeerInfo::new(Some(remote_addr.ip()), &metadata, &self.trusted_proxies).client_ip;
+        if !self.access_lists.read().unwrap_or_else(PoisonError::into_inner).admin.is_allowed(peer) {
+            return reply(StatusCode::FORBIDDEN, String::from("requests from this address are not allowed\n"));
+        }
+        if let Err(status) = self.auth_service.authorize_admin_metadata(&metadata, action) {
+            let code = match status.code() {
+                Code::PermissionDenied => StatusCode::FORBIDDEN,
+                _ => StatusCode::UNAUTHORIZED,
+            };
+            return reply(code, format!("{}\n", status.message()));
+        }
+
+        match action {
+            "Profile" => {
+                let seconds = query_param(request.uri().query(), "seconds")
+                    .and_then(|seconds| seconds.parse().ok())
+                    .unwrap_or(DEFAULT_PROFILE_SECS)
+                    .clamp(1, MAX_PROFILE_SECS);
+                match self.profile(Duration::from_secs(seconds)).await {
+                    Ok(folded) => reply(StatusCode::OK, folded),
+                    Err(e) => reply(StatusCode::SERVICE_UNAVAILABLE, format!("{e}\n")),
+                }
+            }
+            _ => reply(StatusCode::OK, self.tasks()),
+        }
+    }
+
+    async fn profile(&self, duration: Duration) -> Result<String, String> {
+        let Ok(_profiling) = self.profiling.try_lock() else {
+            return Err(String::from("Error::ProfileInProgress"));
+        };
+        info!("profiling the CPU for {:?}", duration);
+
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(PROFILE_FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(|e| format!("Error::ProfileFailed: {e}"))?;
+        tokio::time::sleep(duration).await;
+        let report = guard.report().build().map_err(|e| format!("Error::ProfileFailed: {e}"))?;
+
+        // The root of each stack first, as flamegraph tools expect.
+        let mut folded = String::new();
+        for (frames, count) in &report.data {
+            let symbols: Vec<String> = frames.frames.iter().rev().flat_map(|frame| frame.iter().rev().map(|symbol| symbol.name())).collect();
+            let _ = writeln!(folded, "{};{} {}", frames.thread_name_or_id(), symbols.join(";"), count);
+        }
+        Ok(folded)
+    }
+
+    fn tasks(&self) -> String {
+        let metrics = Handle::current().metrics();
+        let jobs: Vec<serde_json::Value> = self
+            .auth_service
+            .job_statuses
+            .snapshot()
+            .into_iter()
+            .map(|(name, status)| {
+                serde_json::json!({
+                    "name": name,
+                    "runs": status.runs,
+                    "panics": status.panics,
+                    "last_run_at": status.last_run_at.and_then(|at| at.duration_since(std::time::UNIX_EPOCH).ok()).map(|at| at.as_secs()),
+                    "last_duration_ms": status.last_duration.as_millis() as u64,
+                })
+            })
+            .collect();
+
+        serde_json::json!({
+            "workers": metrics.num_workers(),
+            "alive_tasks": metrics.num_alive_tasks(),
+            "queued_tasks": metrics.global_queue_depth(),
+            "jobs": jobs,
+        })
+        .to_string()
+    }
+
+    // Binds right away, so a taken address is found out on start; serves once awaited. Also
+    // returns the address bound, which port 0 leaves to the system.
+    pub fn serve(
+        self,
+        addr: SocketAddr,
+    ) -> Result<(SocketAddr, impl Future<Output = Result<(), hyper::Error>>), hyper::Error> {
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let endpoint = self.clone();
+            let remote_addr = conn.remote_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let endpoint = endpoint.clone();
+                    async move { Ok::<_, Infallible>(endpoint.respond(request, remote_addr).await) }
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&addr)?.serve(make_service);
+        Ok((server.local_addr(), server))
+    }
+}
+
+fn reply(status: StatusCode, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?.split('&').filter_map(|pair| pair.split_once('=')).find(|(key, _)| *key == name).map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use crate::{
+        admin_policy::{AdminPrincipals, AllowList},
+        api_keys::ApiKeys,
+        ip_rules::AccessLists,
+    };
+
+    use super::*;
+
+    const OPERATOR: &str = "10.0.0.7:40000";
+
+    fn request(path: &str, api_key: &str) -> Request<Body> {
+        Request::get(path).header("x-api-key", api_key).body(Body::empty()).unwrap()
+    }
+
+    fn endpoint(auth_service: AuthService, access_lists: &str) -> DebugEndpoint {
+        let access_lists = Arc::new(RwLock::new(access_lists.parse::<AccessLists>().unwrap()));
+        DebugEndpoint::new(Arc::new(auth_service), access_lists, Arc::default())
+    }
+
+    #[tokio::test]
+    async fn should_answer_admins_only() {
+        let auth_service = AuthService::builder()
+            .admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned(), "support-key".to_owned()]))
+            .admin_principals(AdminPrincipals::new([("support-key".to_owned(), "jo:support".parse().unwrap())]))
+            .admin_policy(Box::new(AllowList::default()))
+            .build();
+        let endpoint = endpoint(auth_service, "");
+        let respond = |request| endpoint.respond(request, OPERATOR.parse().unwrap());
+
+        let response = respond(request("/debug/tasks", "admin-key")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let tasks: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(tasks["alive_tasks"].is_u64());
+
+        assert_eq!(respond(request("/debug/tasks", "unknown-key")).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(respond(request("/debug/pprof/profile", "support-key")).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(respond(request("/debug/other", "admin-key")).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn should_answer_admin_sources_only() {
+        let auth_service = AuthService::builder().admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()])).build();
+        let endpoint = endpoint(auth_service, "admin allow 10.0.0.0/8");
+
+        let response = endpoint.respond(request("/debug/tasks", "admin-key"), OPERATOR.parse().unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Even with the key of an admin.
+        let response = endpoint.respond(request("/debug/tasks", "admin-key"), "203.0.113.7:40000".parse().unwrap()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn should_profile_one_at_a_time() {
+        let auth_service = AuthService::builder().admin_api_keys(ApiKeys::new(vec!["admin-key".to_owned()])).build();
+        let endpoint = endpoint(auth_service, "");
+
+        let (first, second) = tokio::join!(
+            endpoint.respond(request("/debug/pprof/profile?seconds=1", "admin-key"), OPERATOR.parse().unwrap()),
+            endpoint.respond(request("/debug/pprof/profile?seconds=1", "admin-key"), OPERATOR.parse().unwrap()),
+        );
+        let mut statuses = [first.status(), second.status()];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]);
+    }
+
+    #[test]
+    fn should_read_query_parameters() {
+        assert_eq!(query_param(Some("format=folded&seconds=10"), "seconds"), Some("10"));
+        assert_eq!(query_param(Some("seconds"), "seconds"), None);
+        assert_eq!(query_param(None, "seconds"), None);
+    }
+}
@@ -0,0 +1,43 @@
+// Guards the wire format: every field, enum value and RPC in the golden file has to still be
+// there, with the same number and type. Adding things is fine; after doing so, regenerate the
+// golden file with `UPDATE_PROTO_GOLDEN=1 cargo test proto_compat`.
+
+use std::{env, fs};
+
+use auth_client::descriptor::wire_entries;
+
+use crate::auth::authentication::DESCRIPTOR_SET;
+const GOLDEN_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/proto/authentication/v1/golden.txt");
+
+fn field_type(value: &str) -> Option<&str> {
+    value.split_once(' ').map(|(_, field_type)| field_type)
+}
+
+#[test]
+fn proto_should_stay_wire_compatible_with_golden() {
+    let current = wire_entries(DESCRIPTOR_SET).unwrap();
+
+    if env::var("UPDATE_PROTO_GOLDEN").is_ok() {
+        let golden: String = current.iter().map(|(key, value)| format!("{key} = {value}\n")).collect();
+        fs::write(GOLDEN_PATH, golden).unwrap();
+        return;
+    }
+
+    let golden = fs::read_to_string(GOLDEN_PATH).unwrap();
+
+    let broken: Vec<String> = golden
+        .lines()
+        .filter_map(|line| line.split_once(" = "))
+        .filter(|(key, value)| match current.get(*key) {
+            // A field may be renamed, as long as its number and type stay. A reserved number
+            // has no type, so it can't come back as a field either.
+            Some(current_value) if key.starts_with("field ") => field_type(current_value) != field_type(value),
+            // Enum values are sent as numbers, but clients match on the names.
+            Some(current_value) => current_value != value,
+            None => true,
+        })
+        .map(|(key, value)| format!("{key} = {value} (now {:?})", current.get(key)))
+        .collect();
+
+    assert!(broken.is_empty(), "wire-incompatible proto changes:\n{}", broken.join("\n"));
+}
@@ -0,0 +1,264 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Service},
+    metadata::MetadataMap,
+    server::NamedService,
+    transport::Body,
+    Status,
+};
+
+use auth_client::quota::{LIMIT_HEADER, REMAINING_HEADER, RESET_HEADER, WARNING_HEADER};
+
+use crate::config::Config;
+
+// A quota of its own for one API key, written `<key>=<requests per minute>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyQuota {
+    pub key: String,
+    pub per_minute: u32,
+}
+
+impl FromStr for KeyQuota {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (key, per_minute) = value.split_once('=').ok_or_else(|| String::from("Error::InvalidKeyQuota"))?;
+        let per_minute = per_minute.trim().parse().map_err(|_| String::from("Error::InvalidKeyQuota"))?;
+
+        Ok(Self { key: key.trim().to_owned(), per_minute })
+    }
+}
+
+// Refilled evenly over a minute, and never holding more than a minute's worth, so that is also
+// the largest burst.
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+// Where a key stands after a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuotaState {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // Until the next token, when none is left.
+    pub retry_after: Duration,
+    // Until the bucket is full again.
+    pub reset_after: Duration,
+    // Little enough left that the caller had better slow down.
+    pub warning: bool,
+}
+
+impl QuotaState {
+    pub(crate) fn write_to(&self, metadata: &mut MetadataMap) {
+        metadata.insert(LIMIT_HEADER, self.limit.into());
+        metadata.insert(REMAINING_HEADER, self.remaining.into());
+        if self.warning {
+            metadata.insert(WARNING_HEADER, "quota nearly used up".parse().unwrap());
+            metadata.insert(RESET_HEADER, (self.reset_after.as_secs_f64().ceil() as u64).into());
+        }
+        if !self.allowed {
+            metadata.insert("retry-after", self.retry_after.as_secs().max(1).into());
+        }
+    }
+}
+
+// Request quotas of the callers presenting an `x-api-key`, whatever they call. Requests without
+// a key are left to the throttle.
+pub struct ApiKeyQuotas {
+    default_per_minute: u32,
+    per_key: HashMap<String, u32>,
+    warning_percent: u32,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl ApiKeyQuotas {
+    // A quota of 0 means unlimited.
+    pub fn new(default_per_minute: u32, per_key: Vec<KeyQuota>) -> Self {
+        Self {
+            default_per_minute,
+            per_key: per_key.into_iter().map(|quota| (quota.key, quota.per_minute)).collect(),
+            warning_percent: 0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.api_key_quota_per_minute, config.api_key_quotas.clone())
+            .with_warning_percent(config.api_key_quota_warning_percent)
+    }
+
+    // Warns callers with less than this percentage of their quota left; 0 for never.
+    pub fn with_warning_percent(mut self, warning_percent: u32) -> Self {
+        self.warning_percent = warning_percent;
+        self
+    }
+
+    fn limit(&self, key: &str) -> u32 {
+        self.per_key.get(key).copied().unwrap_or(self.default_per_minute)
+    }
+
+    // Takes a token for the request. `None` when the key has no quota.
+    pub fn take(&self, key: &str) -> Option<QuotaState> {
+        let limit = self.limit(key);
+        if limit == 0 {
+            return None;
+        }
+
+        let per_second = f64::from(limit) / 60.0;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let bucket = buckets
+            .entry(key.to_owned())
+            .or_insert(Bucket { tokens: f64::from(limit), refilled_at: now });
+
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.refilled_at).as_secs_f64() * per_second)
+            .min(f64::from(limit));
+        bucket.refilled_at = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        Some(QuotaState {
+            allowed,
+            limit,
+            remaining: bucket.tokens as u32,
+            retry_after: Duration::from_secs_f64(((1.0 - bucket.tokens) / per_second).max(0.0)),
+            reset_after: Duration::from_secs_f64((f64::from(limit) - bucket.tokens) / per_second),
+            warning: allowed && bucket.tokens * 100.0 < f64::from(limit) * f64::from(self.warning_percent),
+        })
+    }
+
+    // Forgets the buckets that are full again, so keys seen once don't stay around.
+    pub fn forget_full(&self) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+
+        buckets.retain(|key, bucket| {
+            let limit = f64::from(self.limit(key));
+            bucket.tokens + now.duration_since(bucket.refilled_at).as_secs_f64() * limit / 60.0 < limit
+        });
+    }
+}
+
+// Puts a service behind the quotas: requests over quota get RESOURCE_EXHAUSTED, and every
+// answer to a keyed request carries the quota headers.
+#[derive(Clone)]
+pub struct QuotaService<S> {
+    inner: S,
+    quotas: Arc<ApiKeyQuotas>,
+}
+
+impl<S> QuotaService<S> {
+    pub fn new(inner: S, quotas: Arc<ApiKeyQuotas>) -> Self {
+        Self { inner, quotas }
+    }
+}
+
+impl<S> Service<http::Request<Body>> for QuotaService<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let state = request
+            .headers()
+            .get("x-api-key")
+            .and_then(|key| key.to_str().ok())
+            .and_then(|key| self.quotas.take(key));
+
+        let Some(state) = state else { return Box::pin(self.inner.call(request)) };
+
+        if !state.allowed {
+            let mut status = Status::resource_exhausted("API key quota exceeded");
+            state.write_to(status.metadata_mut());
+            return Box::pin(async move { Ok(status.to_http()) });
+        }
+
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = response.await?;
+            let mut metadata = MetadataMap::new();
+            state.write_to(&mut metadata);
+            response.headers_mut().extend(metadata.into_headers());
+            Ok(response)
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for QuotaService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_key_quota() {
+        assert_eq!(
+            "gateway=600".parse::<KeyQuota>(),
+            Ok(KeyQuota { key: "gateway".to_owned(), per_minute: 600 })
+        );
+        assert!("gateway".parse::<KeyQuota>().is_err());
+    }
+
+    #[test]
+    fn should_refuse_requests_over_quota() {
+        let quotas = ApiKeyQuotas::new(0, vec![KeyQuota { key: "key-1".to_owned(), per_minute: 2 }]);
+
+        assert_eq!(quotas.take("key-2"), None);
+
+        assert!(quotas.take("key-1").unwrap().allowed);
+        let state = quotas.take("key-1").unwrap();
+        assert!(state.allowed);
+        assert_eq!(state.remaining, 0);
+
+        let state = quotas.take("key-1").unwrap();
+        assert!(!state.allowed);
+        assert!(state.retry_after > Duration::from_secs(25));
+    }
+
+    #[test]
+    fn should_warn_when_quota_is_nearly_used_up() {
+        let quotas = ApiKeyQuotas::new(10, Vec::new()).with_warning_percent(20);
+
+        for _ in 0..8 {
+            assert!(!quotas.take("key-1").unwrap().warning);
+        }
+        let state = quotas.take("key-1").unwrap();
+        assert!(state.warning);
+        assert!(state.reset_after > Duration::from_secs(50));
+
+        let mut metadata = MetadataMap::new();
+        state.write_to(&mut metadata);
+        let quota = auth_client::quota::Quota::from_metadata(&metadata).unwrap();
+        assert_eq!((quota.limit, quota.remaining, quota.warning), (10, 1, true));
+        assert_eq!(quota.reset_after, Some(Duration::from_secs(54)));
+
+        assert!(!ApiKeyQuotas::new(10, Vec::new()).take("key-1").unwrap().warning);
+    }
+}
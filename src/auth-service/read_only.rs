@@ -0,0 +1,143 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Service},
+    metadata::MetadataValue,
+    server::NamedService,
+    transport::Body,
+    Status,
+};
+
+use crate::config::Config;
+
+// What a read-only replica still answers: the RPCs that only look at the stores, and the admin
+// RPCs about the replica itself.
+const READ_ONLY_METHODS: &[&str] = &[
+    "ValidateSession",
+    "IntrospectToken",
+    "ListRevokedSessions",
+    "GetServerInfo",
+    "GetUserMetadata",
+    "ExportMyData",
+    "ExportUserData",
+    "ListUsers",
+    "ListSessions",
+    "ListPendingUsers",
+    "GetStats",
+    "StreamStats",
+    "ListRecentAuditEvents",
+    "ListThrottledSources",
+    "GetRateLimitState",
+    "ListDebugCaptures",
+    "GetEffectiveConfig",
+    "SetDebugCapture",
+    "SetLogLevel",
+    "SetMaintenanceMode",
+    "SetFeatureFlag",
+];
+
+// A replica serving validation traffic off a replicated or shared store (see `SessionReplicator`)
+// turns away everything that would change it, with FAILED_PRECONDITION and, when known, the
+// primary to send it to in `x-auth-primary`. Writes then only ever happen on the primary, and
+// validation scales out without replicas disagreeing.
+#[derive(Clone, Debug, Default)]
+pub struct ReadOnly {
+    pub enabled: bool,
+    pub primary_url: Option<String>,
+}
+
+impl ReadOnly {
+    pub fn from_config(config: &Config) -> Self {
+        Self { enabled: config.read_only, primary_url: config.primary_url.clone() }
+    }
+
+    // Whether the RPC at `path` ("/authentication.v1.Auth/SignUp") may go through.
+    fn allows(&self, path: &str) -> bool {
+        !self.enabled || READ_ONLY_METHODS.contains(&path.rsplit('/').next().unwrap_or_default())
+    }
+
+    fn refusal(&self) -> Status {
+        let mut status = Status::failed_precondition("read-only replica, send changes to the primary");
+        if let Some(primary_url) = self.primary_url.as_deref().and_then(|url| MetadataValue::try_from(url).ok()) {
+            status.metadata_mut().insert("x-auth-primary", primary_url);
+        }
+        status
+    }
+}
+
+#[derive(Clone)]
+pub struct ReadOnlyService<S> {
+    inner: S,
+    read_only: Arc<ReadOnly>,
+}
+
+impl<S> ReadOnlyService<S> {
+    pub fn new(inner: S, read_only: Arc<ReadOnly>) -> Self {
+        Self { inner, read_only }
+    }
+}
+
+impl<S> Service<http::Request<Body>> for ReadOnlyService<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        if !self.read_only.allows(request.uri().path()) {
+            let refusal = self.read_only.refusal();
+            return Box::pin(async move { Ok(refusal.to_http()) });
+        }
+
+        Box::pin(self.inner.call(request))
+    }
+}
+
+impl<S: NamedService> NamedService for ReadOnlyService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::body::empty_body;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn should_refuse_changes_with_the_primary() {
+        let read_only = Arc::new(ReadOnly { enabled: true, primary_url: Some("http://auth-primary:50051".to_owned()) });
+        let service = ReadOnlyService::new(
+            service_fn(|_: http::Request<Body>| async { Ok::<_, Infallible>(http::Response::new(empty_body())) }),
+            read_only,
+        );
+        let request = |path| http::Request::builder().uri(path).body(Body::empty()).unwrap();
+
+        let response = service.clone().oneshot(request("/authentication.v1.Auth/SignUp")).await.unwrap();
+        assert_eq!(response.headers()["grpc-status"], (tonic::Code::FailedPrecondition as i32).to_string().as_str());
+        assert_eq!(response.headers()["x-auth-primary"], "http://auth-primary:50051");
+
+        let response = service.oneshot(request("/authentication.v1.Auth/ValidateSession")).await.unwrap();
+        assert!(!response.headers().contains_key("grpc-status"));
+    }
+
+    #[test]
+    fn should_allow_everything_when_disabled() {
+        assert!(ReadOnly::default().allows("/authentication.v1.AuthAdmin/SuspendUser"));
+        assert!(!ReadOnly { enabled: true, primary_url: None }.allows("/authentication.v1.AuthAdmin/SuspendUser"));
+    }
+}
@@ -0,0 +1,388 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, PoisonError, RwLock},
+    time::SystemTime,
+};
+
+use hyper::{
+    header::{CACHE_CONTROL, CONTENT_TYPE, PRAGMA},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde_json::json;
+use tonic::metadata::MetadataMap;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+use tonic::Code;
+
+use crate::auth::authentication::{auth_server::Auth, SignInRequest, StatusCode as ReplyCode};
+use crate::auth::AuthService;
+use crate::error::Error;
+use crate::ip_rules::{Cidr, SharedAccessLists};
+use crate::peer::PeerInfo;
+use crate::quotas::ApiKeyQuotas;
+
+// Whether the instance should get traffic: the last store check passed (see
+// `AuthService::check_stores`). Served over plain HTTP, at `GET /readyz`, for load balancers and
+// orchestrators that can't speak gRPC: 200 when ready, 503 with what failed otherwise.
+//
+// With the service at hand (see `with_auth_service`), two endpoints for clients that only speak
+// HTTP, both held to what holds the gRPC service: the auth access list of AUTH_IP_RULES_FILE and
+// the API key quotas.
+// - `POST /introspect` answers what a token is good for as RFC 7662 has it, for resource servers:
+//   the token in a form body (`token=<token>`), an API key in `x-api-key`, and
+//   `{"active": false}` for tokens that aren't, whyever not.
+// - `POST /oauth/token` issues tokens as RFC 6749 has it, for OAuth2 client libraries: the
+//   _password_ grant only, which signs in as SignIn does (throttling included) and answers with
+//   the session token as a bearer token, its scopes and when it expires. Scopes asked for are
+//   ignored: the token gets those of its user, which the reply says.
+#[derive(Clone, Default)]
+pub struct Readiness {
+    failure: Arc<RwLock<Option<String>>>,
+    endpoints: Option<AuthEndpoints>,
+}
+
+#[derive(Clone)]
+struct AuthEndpoints {
+    auth_service: Arc<AuthService>,
+    access_lists: SharedAccessLists,
+    trusted_proxies: Arc<Vec<Cidr>>,
+    quotas: Arc<ApiKeyQuotas>,
+}
+
+impl Readiness {
+    pub fn with_auth_service(
+        mut self,
+        auth_service: Arc<AuthService>,
+        access_lists: SharedAccessLists,
+        trusted_proxies: Arc<Vec<Cidr>>,
+        quotas: Arc<ApiKeyQuotas>,
+    ) -> Self {
+        self.endpoints = Some(AuthEndpoints { auth_service, access_lists, trusted_proxies, quotas });
+        self
+    }
+
+    pub fn set(&self, result: Result<(), Error>) {
+        *self.failure.write().unwrap_or_else(PoisonError::into_inner) = result.err().map(|e| e.to_string());
+    }
+
+    pub fn get(&self) -> Result<(), String> {
+        match &*self.failure.read().unwrap_or_else(PoisonError::into_inner) {
+            Some(failure) => Err(failure.clone()),
+            None => Ok(()),
+        }
+    }
+
+    async fn respond(&self, request: Request<Body>, connect_info: TcpConnectInfo) -> Response<Body> {
+        match (request.method(), request.uri().path(), &self.endpoints) {
+            (&Method::GET, "/readyz", _) => match self.get() {
+                Ok(()) => reply(StatusCode::OK, String::from("ready\n")),
+                Err(failure) => reply(StatusCode::SERVICE_UNAVAILABLE, format!("not ready: {failure}\n")),
+            },
+            (&Method::POST, "/introspect" | "/oauth/token", Some(endpoints)) => endpoints.respond(request, connect_info).await,
+            _ => reply(StatusCode::NOT_FOUND, String::new()),
+        }
+    }
+
+    // Binds right away, so a taken address is found out on start; serves once awaited. Also
+    // returns the address bound, which port 0 leaves to the system.
+    pub fn serve(
+        self,
+        addr: SocketAddr,
+    ) -> Result<(SocketAddr, impl Future<Output = Result<(), hyper::Error>>), hyper::Error> {
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let readiness = self.clone();
+            let connect_info = conn.connect_info();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let readiness = readiness.clone();
+                    let connect_info = connect_info.clone();
+                    async move { Ok::<_, Infallible>(readiness.respond(request, connect_info).await) }
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&addr)?.serve(make_service);
+        Ok((server.local_addr(), server))
+    }
+}
+
+impl AuthEndpoints {
+    async fn respond(&self, request: Request<Body>, connect_info: TcpConnectInfo) -> Response<Body> {
+        let metadata = MetadataMap::from_headers(request.headers().clone());
+        let remote_ip = connect_info.remote_addr().map(|remote_addr| remote_addr.ip());
+        let peer = PeerInfo::new(remote_ip, &metadata, &self.trusted_proxies).client_ip;
+        if !self.access_lists.read().unwrap_or_else(PoisonError::into_inner).auth.is_allowed(peer) {
+            return reply(StatusCode::FORBIDDEN, String::from("requests from this address are not allowed\n"));
+        }
+
+        // Charged before the key is checked, as `QuotaService` does.
+        let quota = metadata.get("x-api-key").and_then(|key| key.to_str().ok()).and_then(|key| self.quotas.take(key));
+        let mut response = match quota {
+            Some(state) if !state.allowed => json_reply(StatusCode::TOO_MANY_REQUESTS, json!({ "error": "quota_exceeded" })),
+            _ if request.uri().path() == "/introspect" => self.introspect(&metadata, request).await,
+            _ => self.token(metadata, connect_info, request).await,
+        };
+        if let Some(state) = quota {
+            let mut quota_headers = MetadataMap::new();
+            state.write_to(&mut quota_headers);
+            response.headers_mut().extend(quota_headers.into_headers());
+        }
+        response
+    }
+
+    async fn introspect(&self, metadata: &MetadataMap, request: Request<Body>) -> Response<Body> {
+        let Ok(body) = hyper::body::to_bytes(request.into_body()).await else {
+            return reply(StatusCode::BAD_REQUEST, String::new());
+        };
+        let Some(token) = form_urlencoded::parse(&body).find(|(key, _)| key == "token").map(|(_, token)| token) else {
+            return json_reply(StatusCode::BAD_REQUEST, json!({ "error": "invalid_request" }));
+        };
+
+        match self.auth_service.introspect(metadata, &token) {
+            Ok(reply) if reply.active => json_reply(
+                StatusCode::OK,
+                json!({
+                    "active": true,
+                    "scope": reply.scopes.join(" "),
+                    "sub": reply.sub,
+                    "exp": reply.exp,
+                }),
+            ),
+            Ok(_) => json_reply(StatusCode::OK, json!({ "active": false })),
+            Err(_) => json_reply(StatusCode::UNAUTHORIZED, json!({ "error": "invalid_client" })),
+        }
+    }
+
+    async fn token(&self, metadata: MetadataMap, connect_info: TcpConnectInfo, request: Request<Body>) -> Response<Body> {
+        let Ok(body) = hyper::body::to_bytes(request.into_body()).await else {
+            return reply(StatusCode::BAD_REQUEST, String::new());
+        };
+        let mut form: HashMap<String, String> = form_urlencoded::parse(&body).into_owned().collect();
+
+        match form.get("grant_type").map(String::as_str) {
+            Some("password") => {}
+            Some(_) => return oauth_error(StatusCode::BAD_REQUEST, "unsupported_grant_type", ""),
+            None => return oauth_error(StatusCode::BAD_REQUEST, "invalid_request", "grant_type is required"),
+        }
+        let (Some(username), Some(password)) = (form.remove("username"), form.remove("password")) else {
+            return oauth_error(StatusCode::BAD_REQUEST, "invalid_request", "username and password are required");
+        };
+
+        // As the gRPC server would hand it over: the headers, and the connection for whom to
+        // throttle.
+        let mut sign_in = tonic::Request::new(SignInRequest { username, password, remember_me: false });
+        *sign_in.metadata_mut() = metadata;
+        sign_in.extensions_mut().insert(connect_info);
+
+        let reply = match self.auth_service.sign_in(sign_in).await {
+            Ok(reply) => reply.into_inner(),
+            Err(status) if matches!(status.code(), Code::Unavailable | Code::ResourceExhausted) => {
+                return oauth_error(StatusCode::SERVICE_UNAVAILABLE, "temporarily_unavailable", status.message());
+            }
+            Err(status) => return oauth_error(StatusCode::BAD_REQUEST, "invalid_request", status.message()),
+        };
+        if reply.status_code() != ReplyCode::Success {
+            return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", &reply.error_message);
+        }
+        let Some(session) = self.auth_service.issued_session(&reply.session_token) else {
+            return oauth_error(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "");
+        };
+
+        let expires_in = session.expires_at.duration_since(SystemTime::now()).unwrap_or_default().as_secs();
+        let mut response = json_reply(
+            StatusCode::OK,
+            json!({
+                "access_token": reply.session_token,
+                "token_type": "Bearer",
+                "expires_in": expires_in,
+                "scope": session.scopes.join(" "),
+            }),
+        );
+        response.headers_mut().insert(CACHE_CONTROL, "no-store".parse().expect("a valid header value"));
+        response.headers_mut().insert(PRAGMA, "no-cache".parse().expect("a valid header value"));
+        response
+    }
+}
+
+// An error reply of RFC 6749, section 5.2; the description left out when there's none.
+fn oauth_error(status: StatusCode, error: &str, description: &str) -> Response<Body> {
+    let body = match description {
+        "" => json!({ "error": error }),
+        _ => json!({ "error": error, "error_description": description }),
+    };
+    json_reply(status, body)
+}
+
+fn reply(status: StatusCode, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+fn json_reply(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    let mut response = reply(status, body.to_string());
+    response.headers_mut().insert(CONTENT_TYPE, "application/json".parse().expect("a valid header value"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::api_keys::ApiKeys;
+    use crate::ip_rules::AccessLists;
+    use crate::quotas::KeyQuota;
+    use crate::sessions::{SessionClass, SessionsImpl, SessionsOps};
+    use crate::users::{UsersImpl, UsersOps};
+
+    use super::*;
+
+    fn readiness(access_lists: &str, quotas: ApiKeyQuotas) -> (Readiness, String) {
+        let mut users_service = UsersImpl::default();
+        let _ = users_service.create_user("alice".to_owned(), "password".to_owned());
+        let mut sessions_service = SessionsImpl::default();
+        let session_token = sessions_service.create_scoped_session(
+            "guest-1",
+            SessionClass::Guest,
+            vec!["guest".to_owned(), "profile:read".to_owned()],
+        );
+        let auth_service = AuthService::builder()
+            .users(users_service)
+            .sessions(sessions_service)
+            .api_keys(ApiKeys::new(vec!["key-1".to_owned()]))
+            .build();
+        let access_lists = Arc::new(RwLock::new(access_lists.parse::<AccessLists>().unwrap()));
+        let readiness = Readiness::default().with_auth_service(Arc::new(auth_service), access_lists, Arc::default(), Arc::new(quotas));
+
+        (readiness, session_token)
+    }
+
+    // A connection from the loopback, as the server would see it.
+    async fn caller() -> TcpConnectInfo {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        TcpStream::connect(listener.local_addr().unwrap()).await.unwrap().connect_info()
+    }
+
+    fn form(path: &str, body: String, api_key: Option<&str>) -> Request<Body> {
+        let mut request = Request::post(path).header(CONTENT_TYPE, "application/x-www-form-urlencoded");
+        if let Some(api_key) = api_key {
+            request = request.header("x-api-key", api_key);
+        }
+        request.body(Body::from(body)).unwrap()
+    }
+
+    fn introspect(body: String, api_key: Option<&str>) -> Request<Body> {
+        form("/introspect", body, api_key)
+    }
+
+    fn token(body: &str) -> Request<Body> {
+        form("/oauth/token", body.to_owned(), None)
+    }
+
+    async fn json(response: Response<Body>) -> Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice::<Value>(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_report_the_last_check() {
+        let readiness = Readiness::default();
+        let caller = caller().await;
+        let respond = |request| readiness.respond(request, caller.clone());
+        let readyz = || Request::get("/readyz").body(Body::empty()).unwrap();
+
+        assert_eq!(respond(readyz()).await.status(), StatusCode::OK);
+
+        readiness.set(Err(Error::Storage(String::from("users: Error::JournalUnavailable"))));
+        assert_eq!(respond(readyz()).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        readiness.set(Ok(()));
+        assert_eq!(respond(readyz()).await.status(), StatusCode::OK);
+        assert_eq!(respond(Request::get("/").body(Body::empty()).unwrap()).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn should_introspect_tokens_for_callers_with_an_api_key() {
+        let (readiness, session_token) = readiness("", ApiKeyQuotas::new(0, Vec::new()));
+        let caller = caller().await;
+        let respond = |request| readiness.respond(request, caller.clone());
+
+        let response = respond(introspect(format!("token={session_token}"), Some("key-1"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let reply = json(response).await;
+        assert_eq!(reply["active"], true);
+        assert_eq!(reply["scope"], "guest profile:read");
+        assert_eq!(reply["sub"], "guest-1");
+
+        let response = respond(introspect(String::from("token=unknown&token_type_hint=access_token"), Some("key-1"))).await;
+        assert_eq!(json(response).await, json!({ "active": false }));
+
+        let response = respond(introspect(format!("token={session_token}"), None)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = respond(introspect(String::new(), Some("key-1"))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Not served without the service.
+        let response = Readiness::default().respond(introspect(format!("token={session_token}"), Some("key-1")), caller.clone()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn should_hold_introspection_to_the_auth_access_list_and_quotas() {
+        let caller = caller().await;
+
+        let (denied, session_token) = readiness("auth deny 127.0.0.0/8", ApiKeyQuotas::new(0, Vec::new()));
+        let response = denied.respond(introspect(format!("token={session_token}"), Some("key-1")), caller.clone()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = denied.respond(token("grant_type=password&username=alice&password=password"), caller.clone()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let quotas = ApiKeyQuotas::new(0, vec![KeyQuota { key: "key-1".to_owned(), per_minute: 1 }]);
+        let (readiness, session_token) = readiness("", quotas);
+
+        let response = readiness.respond(introspect(format!("token={session_token}"), Some("key-1")), caller.clone()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-ratelimit-remaining"], "0");
+
+        let response = readiness.respond(introspect(format!("token={session_token}"), Some("key-1")), caller.clone()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key("retry-after"));
+    }
+
+    #[tokio::test]
+    async fn should_issue_bearer_tokens_for_the_password_grant() {
+        let (readiness, _) = readiness("", ApiKeyQuotas::new(0, Vec::new()));
+        let caller = caller().await;
+        let respond = |request| readiness.respond(request, caller.clone());
+
+        let response = respond(token("grant_type=password&username=alice&password=password&scope=admin")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CACHE_CONTROL], "no-store");
+        let reply = json(response).await;
+        assert_eq!(reply["token_type"], "Bearer");
+        assert!(reply["expires_in"].as_u64().unwrap() > 0);
+        // The scopes of the user (none here), not those asked for.
+        assert_eq!(reply["scope"], "");
+
+        // The token is a session token like any other.
+        let access_token = reply["access_token"].as_str().unwrap();
+        let response = respond(introspect(format!("token={access_token}"), Some("key-1"))).await;
+        assert_eq!(json(response).await["active"], true);
+
+        let response = respond(token("grant_type=password&username=alice&password=wrong")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json(response).await["error"], "invalid_grant");
+
+        let response = respond(token("grant_type=password&username=alice")).await;
+        assert_eq!(json(response).await["error"], "invalid_request");
+
+        let response = respond(token("grant_type=client_credentials&client_id=app&client_secret=secret")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json(response).await, json!({ "error": "unsupported_grant_type" }));
+    }
+}
@@ -0,0 +1,341 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use auth_client::status;
+use tokio::{
+    sync::broadcast::{self, error::RecvError, error::TryRecvError},
+    task::JoinHandle,
+};
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    transport::{Channel, Endpoint},
+    Request, Response, Status,
+};
+use tracing::{debug, warn};
+
+use crate::auth::authentication::session_replication_client::SessionReplicationClient;
+use crate::auth::authentication::session_replication_server::SessionReplication;
+use crate::auth::authentication::{
+    replicated_session_event, ReplicateSessionEventsRequest, ReplicateSessionEventsResponse, ReplicatedSessionEvent,
+    SessionBound, SessionCreated, SessionDeleted, SessionExtended, SessionGenerationAdvanced, SessionPinned, StatusCode,
+};
+use crate::auth::{epoch_secs, AuthService};
+use crate::clock_skew;
+use crate::i18n::Locale;
+use crate::sessions::{SessionClass, SessionEvent};
+
+// Re-exporting
+pub use crate::auth::authentication::session_replication_server::SessionReplicationServer;
+
+// Changes not yet pushed to a peer, per peer. A peer that falls further behind misses some.
+const BUFFERED_EVENTS: usize = 10_000;
+// Changes pushed to a peer in one request, at most.
+const MAX_BATCH: usize = 500;
+
+// What setting up replication, or taking a peer's changes, fails with.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ReplicationError {
+    #[error("Error::InvalidReplicationPeer: {0}")]
+    InvalidPeer(String),
+    #[error("Error::InvalidReplicationKey")]
+    InvalidKey,
+    #[error("Error::InvalidSessionEvent")]
+    InvalidEvent,
+}
+
+impl From<SessionEvent> for ReplicatedSessionEvent {
+    fn from(event: SessionEvent) -> Self {
+        let event = match event {
+            SessionEvent::SessionCreated {
+                session_token,
+                user_uuid,
+                class,
+                created_at,
+                expires_at,
+                scopes,
+                parent,
+                generation,
+            } => {
+                replicated_session_event::Event::Created(SessionCreated {
+                    session_token,
+                    user_uuid,
+                    class: format!("{:?}", class),
+                    created_at: epoch_secs(created_at),
+                    expires_at: epoch_secs(expires_at),
+                    scopes,
+                    parent_session_token: parent.unwrap_or_default(),
+                    generation,
+                })
+            }
+            SessionEvent::SessionDeleted { session_token } => {
+                replicated_session_event::Event::Deleted(SessionDeleted { session_token })
+            }
+            SessionEvent::SessionExtended { session_token, expires_at } => {
+                replicated_session_event::Event::Extended(SessionExtended {
+                    session_token,
+                    expires_at: epoch_secs(expires_at),
+                })
+            }
+            SessionEvent::SessionBound { session_token, binding } => {
+                replicated_session_event::Event::Bound(SessionBound { session_token, binding })
+            }
+            SessionEvent::SessionPinned { session_token, tenant } => {
+                replicated_session_event::Event::Pinned(SessionPinned { session_token, tenant })
+            }
+            SessionEvent::GenerationAdvanced { user_uuid, generation } => {
+                replicated_session_event::Event::GenerationAdvanced(SessionGenerationAdvanced { user_uuid, generation })
+            }
+        };
+
+        Self { event: Some(event) }
+    }
+}
+
+impl TryFrom<ReplicatedSessionEvent> for SessionEvent {
+    type Error = ReplicationError;
+
+    fn try_from(event: ReplicatedSessionEvent) -> Result<Self, Self::Error> {
+        let time = |secs: i64| UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64);
+
+        match event.event.ok_or(ReplicationError::InvalidEvent)? {
+            replicated_session_event::Event::Created(created) => Ok(SessionEvent::SessionCreated {
+                session_token: created.session_token,
+                user_uuid: created.user_uuid,
+                class: match created.class.as_str() {
+                    "Standard" => SessionClass::Standard,
+                    "LongLived" => SessionClass::LongLived,
+                    "Guest" => SessionClass::Guest,
+                    "Delegated" => SessionClass::Delegated,
+                    _ => return Err(ReplicationError::InvalidEvent),
+                },
+                created_at: time(created.created_at),
+                expires_at: time(created.expires_at),
+                scopes: created.scopes,
+                parent: Some(created.parent_session_token).filter(|parent| !parent.is_empty()),
+                generation: created.generation,
+            }),
+            replicated_session_event::Event::Deleted(deleted) => {
+                Ok(SessionEvent::SessionDeleted { session_token: deleted.session_token })
+            }
+            replicated_session_event::Event::Extended(extended) => Ok(SessionEvent::SessionExtended {
+                session_token: extended.session_token,
+                expires_at: time(extended.expires_at),
+            }),
+            replicated_session_event::Event::Bound(bound) => Ok(SessionEvent::SessionBound {
+                session_token: bound.session_token,
+                binding: bound.binding,
+            }),
+            replicated_session_event::Event::Pinned(pinned) => Ok(SessionEvent::SessionPinned {
+                session_token: pinned.session_token,
+                tenant: pinned.tenant,
+            }),
+            replicated_session_event::Event::GenerationAdvanced(advanced) => Ok(SessionEvent::GenerationAdvanced {
+                user_uuid: advanced.user_uuid,
+                generation: advanced.generation,
+            }),
+        }
+    }
+}
+
+// Pushes every change to the sessions made here to the other replicas, so a session is good on
+// whichever replica the next request lands. Best effort, like the sessions' journal: changes a
+// peer doesn't take (it is down, or too far behind) are not sent again. Each peer gets its own
+// task, so a slow one doesn't hold up the others. Only sessions are replicated: users have to be
+// shared some other way (e.g. LDAP), or a replica still turns away the sessions of users it
+// doesn't know.
+pub struct SessionReplicator {
+    peers: Vec<(String, SessionReplicationClient<Channel>)>,
+    key: MetadataValue<Ascii>,
+    events: broadcast::Sender<SessionEvent>,
+}
+
+impl SessionReplicator {
+    pub fn new(peers: &[String], key: &str) -> Result<Self, ReplicationError> {
+        let peers = peers
+            .iter()
+            .map(|peer| {
+                let endpoint = Endpoint::from_shared(peer.clone())
+                    .map_err(|e| ReplicationError::InvalidPeer(format!("{peer}: {e}")))?;
+                Ok((peer.clone(), SessionReplicationClient::new(endpoint.connect_lazy())))
+            })
+            .collect::<Result<_, ReplicationError>>()?;
+        let key = key.parse().map_err(|_| ReplicationError::InvalidKey)?;
+
+        Ok(Self { peers, key, events: broadcast::channel(BUFFERED_EVENTS).0 })
+    }
+
+    // What the sessions send their changes to (see `SessionsImpl::with_replication`).
+    pub fn sender(&self) -> broadcast::Sender<SessionEvent> {
+        self.events.clone()
+    }
+
+    pub fn start(self) -> Vec<JoinHandle<()>> {
+        self.peers
+            .into_iter()
+            .map(|(peer, client)| tokio::spawn(push_to(peer, client, self.key.clone(), self.events.subscribe())))
+            .collect()
+    }
+}
+
+async fn push_to(
+    peer: String,
+    mut client: SessionReplicationClient<Channel>,
+    key: MetadataValue<Ascii>,
+    mut events: broadcast::Receiver<SessionEvent>,
+) {
+    loop {
+        let mut batch = match events.recv().await {
+            Ok(event) => vec![event],
+            Err(RecvError::Lagged(missed)) => {
+                warn!("{} session changes were not replicated to {}", missed, peer);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        while batch.len() < MAX_BATCH {
+            match events.try_recv() {
+                Ok(event) => batch.push(event),
+                Err(TryRecvError::Lagged(missed)) => warn!("{} session changes were not replicated to {}", missed, peer),
+                Err(_) => break,
+            }
+        }
+
+        let count = batch.len();
+        let mut request = Request::new(ReplicateSessionEventsRequest {
+            events: batch.into_iter().map(ReplicatedSessionEvent::from).collect(),
+        });
+        request.metadata_mut().insert("x-api-key", key.clone());
+
+        let replicated = client.replicate_session_events(request).await.and_then(|response| {
+            let reply = response.into_inner();
+            status::check(reply.status_code, &reply.error_message)
+        });
+        match replicated {
+            Ok(()) => debug!("replicated {} session changes to {}", count, peer),
+            Err(e) => warn!("failed to replicate {} session changes to {}: {}", count, peer, e),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl SessionReplication for AuthService {
+    async fn replicate_session_events(
+        &self,
+        request: Request<ReplicateSessionEventsRequest>,
+    ) -> Result<Response<ReplicateSessionEventsResponse>, Status> {
+        // Every session change on every peer comes through here; not worth an info line each.
+        debug!("Got a replication request of {} events", request.get_ref().events.len());
+
+        self.replication_keys.authenticate(request.metadata())?;
+        self.check_stores_are_sound()?;
+
+        let locale = Locale::from_request(&request);
+        let peer = request.remote_addr().map_or_else(|| String::from("a replica"), |addr| format!("replica {addr}"));
+        let req = request.into_inner();
+
+        // All or nothing, so a bad event doesn't leave the batch half applied.
+        let events: Result<Vec<SessionEvent>, ReplicationError> = req.events.into_iter().map(SessionEvent::try_from).collect();
+
+        // Once a batch, and only a warning: the sessions still hold for as long as they were given.
+        let latest_created = events.iter().flatten().filter_map(|event| match event {
+            SessionEvent::SessionCreated { created_at, .. } => Some(*created_at),
+            _ => None,
+        });
+        if let Some(created_at) = latest_created.max() {
+            clock_skew::is_ahead(&peer, created_at, SystemTime::now(), self.config.clock_skew_leeway);
+        }
+
+        let reply = match events {
+            Ok(events) => ReplicateSessionEventsResponse::success(self.sessions().apply_replicated(events) as u64),
+            Err(e) => {
+                warn!("refused replicated session changes: {}", e);
+                ReplicateSessionEventsResponse::failure(StatusCode::Failure)
+            }
+        };
+
+        Ok(Response::new(locale.localize(reply)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+
+    use super::*;
+    use crate::api_keys::ApiKeys;
+    use crate::sessions::{SessionsImpl, SessionsOps};
+
+    #[test]
+    fn should_convert_events_both_ways() {
+        let event = SessionEvent::SessionCreated {
+            session_token: "token".to_owned(),
+            user_uuid: "123456".to_owned(),
+            class: SessionClass::LongLived,
+            created_at: UNIX_EPOCH + Duration::from_secs(1000),
+            expires_at: UNIX_EPOCH + Duration::from_secs(2000),
+            scopes: vec!["profile:read".to_owned()],
+            parent: Some("parent".to_owned()),
+            generation: 2,
+        };
+        assert_eq!(SessionEvent::try_from(ReplicatedSessionEvent::from(event.clone())), Ok(event));
+
+        let event = SessionEvent::GenerationAdvanced { user_uuid: "123456".to_owned(), generation: 3 };
+        assert_eq!(SessionEvent::try_from(ReplicatedSessionEvent::from(event.clone())), Ok(event));
+
+        assert!(SessionEvent::try_from(ReplicatedSessionEvent { event: None }).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_replicate_sessions_to_peer() {
+        let peer_service = AuthService::builder()
+            .replication_keys(ApiKeys::new(vec!["replication-key".to_owned()]))
+            .build();
+        let peer_service = std::sync::Arc::new(peer_service);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(SessionReplicationServer::from_arc(peer_service.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let replicator = SessionReplicator::new(&[format!("http://{addr}")], "replication-key").unwrap();
+        let mut sessions = SessionsImpl::default().with_replication(replicator.sender());
+        let handles = replicator.start();
+
+        let eventually = |expected: bool, session_token: String| {
+            let peer_service = peer_service.clone();
+            async move {
+                for _ in 0..100 {
+                    if peer_service.sessions().get_session(&session_token).is_some() == expected {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                panic!("session {session_token} was not replicated");
+            }
+        };
+
+        let session_token = sessions.create_session("123456", SessionClass::Standard);
+        eventually(true, session_token.clone()).await;
+        assert_eq!(peer_service.sessions().get_sessions_of_user("123456").len(), 1);
+
+        sessions.delete_session(&session_token);
+        eventually(false, session_token).await;
+
+        handles.iter().for_each(JoinHandle::abort);
+    }
+
+    #[tokio::test]
+    async fn should_require_replication_key() {
+        let auth_service = AuthService::builder().build();
+
+        let mut request = Request::new(ReplicateSessionEventsRequest::default());
+        request.metadata_mut().insert("x-api-key", "admin-key".parse().unwrap());
+
+        let status = auth_service.replicate_session_events(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+}
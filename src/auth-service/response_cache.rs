@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use tonic::{Response, Status};
+
+use crate::{auth::authentication::StatusCode, i18n::ErrorMessage};
+
+// How long browsers and gateways may keep a response, as in HTTP's Cache-Control.
+pub const CACHE_CONTROL_HEADER: &str = "cache-control";
+
+// The most replies one cache keeps; past it, new ones are answered but not kept until the
+// expired ones are forgotten.
+const MAX_ENTRIES: usize = 10_000;
+
+// Replies of read-heavy RPCs that rarely change, kept per key for `ttl` (AUTH_RESPONSE_CACHE_TTL_SECS,
+// 0 for none) so the stores aren't asked every time, e.g. GetUserMetadata per user. The handlers
+// changing what is kept invalidate it. Kept on this instance only: a change made through another
+// replica shows here once `ttl` is over.
+pub struct ResponseCache<K, V> {
+    ttl: Duration,
+    entries: Mutex<HashMap<K, (V, Instant)>>,
+}
+
+impl<K: Eq + Hash, V: Clone> ResponseCache<K, V> {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, entries: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries.get(key).filter(|(_, cached_at)| cached_at.elapsed() < self.ttl).map(|(value, _)| value.clone())
+    }
+
+    // The cached reply for `key`, or else `load`'s, kept for next time.
+    pub fn get_or_load(&self, key: K, load: impl FnOnce() -> Option<V>) -> Option<V> {
+        if let Some(value) = self.get(&key) {
+            return Some(value);
+        }
+        let value = load()?;
+        self.insert(key, value.clone());
+        Some(value)
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() < MAX_ENTRIES || entries.contains_key(&key) {
+            entries.insert(key, (value, Instant::now()));
+        }
+    }
+
+    pub fn invalidate(&self, key: &K) {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).remove(key);
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+
+    // Drops the replies kept longer than `ttl`; run by the maintenance job.
+    pub fn forget_expired(&self) {
+        let ttl = self.ttl;
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).retain(|_, (_, cached_at)| cached_at.elapsed() < ttl);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Who may keep a reply: any cache on the way (`Public`), or the caller's own only (`Private`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheScope {
+    Public,
+    Private,
+}
+
+// Says in `CACHE_CONTROL_HEADER` how long a successful reply may be kept; failures, and every
+// reply when `ttl` is 0, are not to be kept.
+pub fn with_cache_control<R: ErrorMessage>(
+    response: Result<Response<R>, Status>,
+    scope: CacheScope,
+    ttl: Duration,
+) -> Result<Response<R>, Status> {
+    let mut response = response?;
+    let value = match (response.get_ref().status_code() == i32::from(StatusCode::Success), ttl.as_secs()) {
+        (true, secs) if secs > 0 => match scope {
+            CacheScope::Public => format!("public, max-age={secs}"),
+            CacheScope::Private => format!("private, max-age={secs}"),
+        },
+        _ => String::from("no-store"),
+    };
+    if let Ok(value) = value.parse() {
+        response.metadata_mut().insert(CACHE_CONTROL_HEADER, value);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::authentication::SignOutResponse;
+
+    use super::*;
+
+    #[test]
+    fn should_keep_replies_until_invalidated_or_expired() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        let mut loads = 0;
+
+        assert_eq!(cache.get_or_load("alice", || { loads += 1; Some(1) }), Some(1));
+        assert_eq!(cache.get_or_load("alice", || { loads += 1; Some(2) }), Some(1));
+        assert_eq!(loads, 1);
+
+        cache.invalidate(&"alice");
+        assert_eq!(cache.get_or_load("alice", || Some(3)), Some(3));
+        assert_eq!(cache.get_or_load("bob", || None), None);
+        assert_eq!(cache.len(), 1);
+
+        let cache = ResponseCache::new(Duration::ZERO);
+        cache.insert("alice", 1);
+        assert_eq!(cache.get(&"alice"), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn should_say_how_long_replies_may_be_kept() {
+        let cache_control = |reply, scope, ttl| {
+            let response = with_cache_control(Ok(Response::new(reply)), scope, ttl).unwrap();
+            response.metadata().get(CACHE_CONTROL_HEADER).unwrap().to_str().unwrap().to_owned()
+        };
+        let minute = Duration::from_secs(60);
+
+        assert_eq!(cache_control(SignOutResponse::success(), CacheScope::Public, minute), "public, max-age=60");
+        assert_eq!(cache_control(SignOutResponse::success(), CacheScope::Private, minute), "private, max-age=60");
+        assert_eq!(cache_control(SignOutResponse::success(), CacheScope::Private, Duration::ZERO), "no-store");
+        assert_eq!(cache_control(SignOutResponse::failure(StatusCode::Failure), CacheScope::Public, minute), "no-store");
+    }
+}
@@ -0,0 +1,256 @@
+// Pins the exact responses of sign_up, sign_in, sign_out and validate_session, for every way they
+// can go. A change to a status code or a field shows up as a diff of the golden file, to be
+// reviewed like any other; after an intended change, regenerate it with
+// `UPDATE_RESPONSE_GOLDEN=1 cargo test response_golden`.
+//
+// Ids are seeded, so user uuids, session tokens and challenge nonces are the same on every run.
+// Expiry times are written relative to now.
+
+use std::{
+    env,
+    fmt::Debug,
+    fs,
+    time::{Duration, SystemTime},
+};
+
+use auth_ids::{Ids, SeededIds};
+use tokio::net::{TcpListener, TcpStream};
+use tonic::{transport::server::Connected, Request, Response, Status};
+
+use crate::auth::authentication::auth_server::Auth;
+use crate::auth::authentication::{SignInRequest, SignOutRequest, SignUpRequest, ValidateSessionRequest};
+use crate::auth::{epoch_secs, AuthService};
+use crate::breached::{tests::test_bloom_filter, BreachedPasswordMode};
+use crate::challenge::{ChallengeMode, ProofOfWork};
+use crate::config::Config;
+use crate::sessions::SessionsImpl;
+use crate::tokens::UuidTokens;
+use crate::users::{AccountStatus, UsersImpl};
+
+const GOLDEN_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/auth-service/response_golden.txt");
+
+const USERNAME: &str = "alice";
+const PASSWORD: &str = "correct horse";
+
+#[derive(Default)]
+struct Golden(String);
+
+impl Golden {
+    fn record<T: Debug>(&mut self, case: &str, result: Result<Response<T>, Status>) {
+        let rendered = match result {
+            Ok(response) => format!("{:?}", response.into_inner()),
+            Err(status) => format!(
+                "Status {{ code: {:?}, message: {:?}, retry_after: {:?} }}",
+                status.code(),
+                status.message(),
+                status.metadata().get("retry-after").and_then(|value| value.to_str().ok()),
+            ),
+        };
+
+        self.0 += &format!("{case}\n    {}\n", relative_expiry(&rendered));
+    }
+}
+
+// "expires_at: 1700000000" becomes "expires_at: now+60min"; 0 (no expiry) stays.
+fn relative_expiry(rendered: &str) -> String {
+    const FIELD: &str = "expires_at: ";
+    let Some(start) = rendered.find(FIELD).map(|at| at + FIELD.len()) else { return rendered.to_owned() };
+
+    let digits = rendered[start..].chars().take_while(char::is_ascii_digit).count();
+    let expires_at: i64 = rendered[start..start + digits].parse().unwrap_or_default();
+    if expires_at == 0 {
+        return rendered.to_owned();
+    }
+
+    let minutes = (expires_at - epoch_secs(SystemTime::now()) + 30) / 60;
+    format!("{}now+{minutes}min{}", &rendered[..start], &rendered[start + digits..])
+}
+
+fn service(config: Config) -> AuthService {
+    let ids = Ids::new(SeededIds::new(1));
+    let sessions = SessionsImpl::default()
+        .with_token_generator(Box::new(UuidTokens::new(ids.clone())))
+        .with_id_generator(ids.clone());
+
+    AuthService::builder()
+        .users(UsersImpl::default().with_id_generator(ids.clone()))
+        .sessions(sessions)
+        .breached_passwords(Box::new(test_bloom_filter()))
+        .challenge_verifier(Box::new(ProofOfWork::new(8).with_id_generator(ids.clone())))
+        .id_generator(ids)
+        .config(config)
+        .build()
+}
+
+// With alice signed up.
+async fn service_with_user(config: Config) -> AuthService {
+    let auth_service = service(config);
+    auth_service.sign_up(sign_up(USERNAME, PASSWORD)).await.unwrap();
+    auth_service
+}
+
+fn set_status(auth_service: &AuthService, status: AccountStatus) {
+    let mut users_service = auth_service.users_service.lock().unwrap();
+    let user_uuid = users_service.find_user_uuid(USERNAME).unwrap();
+    match status {
+        AccountStatus::Deleted => users_service.request_deletion(&user_uuid).unwrap(),
+        _ => users_service.set_account_status(&user_uuid, status).unwrap(),
+    }
+}
+
+fn sign_up(username: &str, password: &str) -> Request<SignUpRequest> {
+    Request::new(SignUpRequest {
+        username: username.to_owned(),
+        password: password.to_owned(),
+        ..SignUpRequest::default()
+    })
+}
+
+fn sign_in(password: &str, remember_me: bool) -> Request<SignInRequest> {
+    Request::new(SignInRequest { username: USERNAME.to_owned(), password: password.to_owned(), remember_me })
+}
+
+async fn signed_in_token(auth_service: &AuthService, remember_me: bool) -> String {
+    auth_service.sign_in(sign_in(PASSWORD, remember_me)).await.unwrap().into_inner().session_token
+}
+
+fn validate(session_token: &str) -> Request<ValidateSessionRequest> {
+    Request::new(ValidateSessionRequest { session_token: session_token.to_owned() })
+}
+
+// Comes from 127.0.0.1, as far as the handlers can tell.
+async fn from_localhost<T>(mut request: Request<T>) -> Request<T> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    request.extensions_mut().insert(stream.connect_info());
+    request
+}
+
+async fn sign_up_cases(golden: &mut Golden) {
+    let auth_service = service(Config::default());
+    golden.record("sign_up: new user", auth_service.sign_up(sign_up(USERNAME, PASSWORD)).await);
+    golden.record("sign_up: username taken", auth_service.sign_up(sign_up(USERNAME, PASSWORD)).await);
+    golden.record("sign_up: empty username", auth_service.sign_up(sign_up("", PASSWORD)).await);
+    golden.record("sign_up: empty password", auth_service.sign_up(sign_up("bob", "")).await);
+
+    let auth_service = service(Config { breached_password_mode: BreachedPasswordMode::Warn, ..Config::default() });
+    golden.record("sign_up: breached password, warned", auth_service.sign_up(sign_up(USERNAME, "password123")).await);
+
+    let auth_service = service(Config { breached_password_mode: BreachedPasswordMode::Enforce, ..Config::default() });
+    golden.record("sign_up: breached password, refused", auth_service.sign_up(sign_up(USERNAME, "password123")).await);
+
+    let auth_service = service(Config { challenge_mode: ChallengeMode::Always, ..Config::default() });
+    golden.record("sign_up: challenge not answered", auth_service.sign_up(sign_up(USERNAME, PASSWORD)).await);
+    let mut request = sign_up(USERNAME, PASSWORD);
+    request.get_mut().challenge_response = "bogus:0".to_owned();
+    golden.record("sign_up: challenge answered wrong", auth_service.sign_up(request).await);
+
+    let auth_service = service(Config { invite_only: true, ..Config::default() });
+    golden.record("sign_up: invite only, no invite", auth_service.sign_up(sign_up(USERNAME, PASSWORD)).await);
+
+    let auth_service = service(Config { maintenance_mode: true, ..Config::default() });
+    golden.record("sign_up: in maintenance", auth_service.sign_up(sign_up(USERNAME, PASSWORD)).await);
+
+    let auth_service = service(Config { approval_required: true, ..Config::default() });
+    golden.record("sign_up: approval required", auth_service.sign_up(sign_up(USERNAME, PASSWORD)).await);
+}
+
+async fn sign_in_cases(golden: &mut Golden) {
+    let auth_service = service_with_user(Config::default()).await;
+    golden.record("sign_in: right password", auth_service.sign_in(sign_in(PASSWORD, false)).await);
+    golden.record("sign_in: remember me", auth_service.sign_in(sign_in(PASSWORD, true)).await);
+    golden.record("sign_in: wrong password", auth_service.sign_in(sign_in("wrong", false)).await);
+    let request = Request::new(SignInRequest { username: "bob".to_owned(), password: PASSWORD.to_owned(), remember_me: false });
+    golden.record("sign_in: unknown user", auth_service.sign_in(request).await);
+
+    set_status(&auth_service, AccountStatus::Suspended);
+    golden.record("sign_in: suspended", auth_service.sign_in(sign_in(PASSWORD, false)).await);
+    set_status(&auth_service, AccountStatus::PendingVerification);
+    golden.record("sign_in: pending verification", auth_service.sign_in(sign_in(PASSWORD, false)).await);
+    set_status(&auth_service, AccountStatus::PendingApproval);
+    golden.record("sign_in: pending approval", auth_service.sign_in(sign_in(PASSWORD, false)).await);
+
+    let auth_service = service_with_user(Config::default()).await;
+    set_status(&auth_service, AccountStatus::Deleted);
+    golden.record("sign_in: deleted, within grace", auth_service.sign_in(sign_in(PASSWORD, false)).await);
+
+    let auth_service = service_with_user(Config { account_deletion_grace: Duration::ZERO, ..Config::default() }).await;
+    set_status(&auth_service, AccountStatus::Deleted);
+    golden.record("sign_in: deleted, grace over", auth_service.sign_in(sign_in(PASSWORD, false)).await);
+
+    let auth_service = service_with_user(Config {
+        terms_version: Some("2".to_owned()),
+        require_current_terms: true,
+        ..Config::default()
+    })
+    .await;
+    golden.record("sign_in: terms not accepted", auth_service.sign_in(sign_in(PASSWORD, false)).await);
+
+    let auth_service = service_with_user(Config {
+        throttle_ip_tarpit_after: 1,
+        throttle_ip_block_after: 1,
+        ..Config::default()
+    })
+    .await;
+    let _ = auth_service.sign_in(from_localhost(sign_in("wrong", false)).await).await;
+    golden.record("sign_in: source blocked", auth_service.sign_in(from_localhost(sign_in(PASSWORD, false)).await).await);
+}
+
+async fn sign_out_cases(golden: &mut Golden) {
+    let auth_service = service_with_user(Config::default()).await;
+    let session_token = signed_in_token(&auth_service, false).await;
+    let sign_out = || Request::new(SignOutRequest { session_token: session_token.clone() });
+
+    golden.record("sign_out: signed in", auth_service.sign_out(sign_out()).await);
+    golden.record("sign_out: already signed out", auth_service.sign_out(sign_out()).await);
+    golden.record("sign_out: unknown token", auth_service.sign_out(Request::new(SignOutRequest { session_token: "unknown".to_owned() })).await);
+    golden.record("sign_out: empty token", auth_service.sign_out(Request::new(SignOutRequest::default())).await);
+}
+
+async fn validate_session_cases(golden: &mut Golden) {
+    let auth_service = service_with_user(Config::default()).await;
+    let session_token = signed_in_token(&auth_service, false).await;
+    golden.record("validate_session: signed in", auth_service.validate_session(validate(&session_token)).await);
+    let long_lived = signed_in_token(&auth_service, true).await;
+    golden.record("validate_session: remember me", auth_service.validate_session(validate(&long_lived)).await);
+    golden.record("validate_session: unknown token", auth_service.validate_session(validate("unknown")).await);
+
+    set_status(&auth_service, AccountStatus::Suspended);
+    golden.record("validate_session: owner suspended", auth_service.validate_session(validate(&session_token)).await);
+
+    let auth_service = service_with_user(Config::default()).await;
+    let session_token = signed_in_token(&auth_service, false).await;
+    auth_service.sign_out(Request::new(SignOutRequest { session_token: session_token.clone() })).await.unwrap();
+    golden.record("validate_session: signed out", auth_service.validate_session(validate(&session_token)).await);
+
+    let guest = auth_service.create_guest_session(Request::new(Default::default())).await.unwrap().into_inner();
+    golden.record("validate_session: guest", auth_service.validate_session(validate(&guest.session_token)).await);
+}
+
+#[tokio::test]
+async fn responses_should_match_golden() {
+    let mut golden = Golden::default();
+    sign_up_cases(&mut golden).await;
+    sign_in_cases(&mut golden).await;
+    sign_out_cases(&mut golden).await;
+    validate_session_cases(&mut golden).await;
+
+    if env::var("UPDATE_RESPONSE_GOLDEN").is_ok() {
+        fs::write(GOLDEN_PATH, &golden.0).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(GOLDEN_PATH).unwrap();
+    let changed: Vec<String> = expected
+        .lines()
+        .zip(golden.0.lines())
+        .filter(|(expected, actual)| expected != actual)
+        .map(|(expected, actual)| format!("- {expected}\n+ {actual}"))
+        .collect();
+
+    assert!(
+        changed.is_empty() && expected.lines().count() == golden.0.lines().count(),
+        "responses changed (regenerate with UPDATE_RESPONSE_GOLDEN=1 if intended):\n{}",
+        changed.join("\n")
+    );
+}
@@ -0,0 +1,370 @@
+// How the handlers build their replies, so a status code is always spelt as a `StatusCode` and
+// the fields a status doesn't use are left empty. Failures carry nothing but their status code:
+// `Locale::localize` fills in the error message.
+
+use std::collections::HashMap;
+
+use crate::auth::authentication::*;
+
+macro_rules! failures {
+    ($($response:ty),* $(,)?) => {
+        $(impl $response {
+            pub fn failure(status_code: StatusCode) -> Self {
+                Self { status_code: status_code.into(), ..Self::default() }
+            }
+        })*
+    };
+}
+
+// The replies that are a status code and nothing else.
+macro_rules! statuses {
+    ($($response:ty),* $(,)?) => {
+        $(impl $response {
+            pub fn success() -> Self {
+                Self { status_code: StatusCode::Success.into(), ..Self::default() }
+            }
+
+            pub fn from_result<T>(result: Result<T, StatusCode>) -> Self {
+                result.map_or_else(Self::failure, |_| Self::success())
+            }
+        })*
+    };
+}
+
+failures!(
+    SignUpResponse,
+    SignInResponse,
+    SignOutResponse,
+    ExchangeExternalTokenResponse,
+    RequestMagicLinkResponse,
+    ConsumeMagicLinkResponse,
+    ValidateSessionResponse,
+    CreateGuestSessionResponse,
+    UpgradeGuestSessionResponse,
+    ChangeUsernameResponse,
+    ChangePasswordResponse,
+    SetUserMetadataResponse,
+    GetUserMetadataResponse,
+    ExportMyDataResponse,
+    DeleteAccountResponse,
+    AcceptTermsResponse,
+    CreateDelegationTokenResponse,
+    ListRevokedSessionsResponse,
+    GetServerInfoResponse,
+    StartWebAuthnRegistrationResponse,
+    FinishWebAuthnRegistrationResponse,
+    StartWebAuthnAssertionResponse,
+    FinishWebAuthnAssertionResponse,
+    SuspendUserResponse,
+    UnsuspendUserResponse,
+    SoftDeleteUserResponse,
+    RestoreUserResponse,
+    ApproveUserResponse,
+    RejectUserResponse,
+    SetUserScopesResponse,
+    RevokeLongLivedSessionsResponse,
+    RevokeSessionsResponse,
+    ListSessionsResponse,
+    ExportUserDataResponse,
+    ListUsersResponse,
+    ListPendingUsersResponse,
+    CreateInviteResponse,
+    ReserveUsernameResponse,
+    SetHoneypotUsernameResponse,
+    SetMaintenanceModeResponse,
+    SetFeatureFlagResponse,
+    SetLogLevelResponse,
+    GetStatsResponse,
+    ListRecentAuditEventsResponse,
+    ListThrottledSourcesResponse,
+    GetRateLimitStateResponse,
+    ClearLockoutResponse,
+    SetDebugCaptureResponse,
+    ListDebugCapturesResponse,
+    GetEffectiveConfigResponse,
+    ReplayProjectionResponse,
+    ReplicateSessionEventsResponse,
+);
+
+statuses!(
+    SignOutResponse,
+    RequestMagicLinkResponse,
+    ChangeUsernameResponse,
+    SetUserMetadataResponse,
+    DeleteAccountResponse,
+    AcceptTermsResponse,
+    SuspendUserResponse,
+    UnsuspendUserResponse,
+    SoftDeleteUserResponse,
+    RestoreUserResponse,
+    ApproveUserResponse,
+    RejectUserResponse,
+    SetUserScopesResponse,
+    ReserveUsernameResponse,
+    SetHoneypotUsernameResponse,
+    SetMaintenanceModeResponse,
+    SetDebugCaptureResponse,
+);
+
+impl SignUpResponse {
+    pub fn success(password_breached: bool) -> Self {
+        Self { status_code: StatusCode::Success.into(), password_breached, ..Self::default() }
+    }
+}
+
+impl SignInResponse {
+    pub fn success(user_uuid: String, session_token: String) -> Self {
+        Self { status_code: StatusCode::Success.into(), user_uuid, session_token, ..Self::default() }
+    }
+}
+
+impl ExchangeExternalTokenResponse {
+    pub fn success(user_uuid: String, session_token: String) -> Self {
+        Self { status_code: StatusCode::Success.into(), user_uuid, session_token, ..Self::default() }
+    }
+}
+
+impl ConsumeMagicLinkResponse {
+    pub fn success(user_uuid: String, session_token: String) -> Self {
+        Self { status_code: StatusCode::Success.into(), user_uuid, session_token, ..Self::default() }
+    }
+}
+
+impl ValidateSessionResponse {
+    pub fn success(user_uuid: String, expires_at: i64, scopes: Vec<String>, tenant: Option<String>) -> Self {
+        let tenant = tenant.unwrap_or_default();
+        Self { status_code: StatusCode::Success.into(), user_uuid, expires_at, scopes, tenant, ..Self::default() }
+    }
+}
+
+impl CreateGuestSessionResponse {
+    pub fn success(guest_uuid: String, session_token: String) -> Self {
+        Self { status_code: StatusCode::Success.into(), guest_uuid, session_token, ..Self::default() }
+    }
+}
+
+impl UpgradeGuestSessionResponse {
+    pub fn success(user_uuid: String, session_token: String, password_breached: bool) -> Self {
+        Self { status_code: StatusCode::Success.into(), user_uuid, session_token, password_breached, ..Self::default() }
+    }
+}
+
+impl ChangePasswordResponse {
+    pub fn success(session_token: String, password_breached: bool) -> Self {
+        Self { status_code: StatusCode::Success.into(), session_token, password_breached, ..Self::default() }
+    }
+}
+
+impl CreateDelegationTokenResponse {
+    pub fn success(delegation_token: String, expires_at: i64) -> Self {
+        Self { status_code: StatusCode::Success.into(), delegation_token, expires_at, ..Self::default() }
+    }
+}
+
+impl ListRevokedSessionsResponse {
+    pub fn success(revoked_token_digests: Vec<String>, revoked_jwt_ids: Vec<String>, cursor: u64, reset: bool) -> Self {
+        Self {
+            status_code: StatusCode::Success.into(),
+            revoked_token_digests,
+            revoked_jwt_ids,
+            cursor,
+            reset,
+            ..Self::default()
+        }
+    }
+}
+
+impl GetServerInfoResponse {
+    pub fn success(
+        password_prehash: Option<PasswordPrehash>,
+        reports_unknown_sessions: bool,
+        user_id_scheme: &str,
+        proto_fingerprint: &str,
+    ) -> Self {
+        Self {
+            status_code: StatusCode::Success.into(),
+            password_prehash,
+            reports_unknown_sessions,
+            user_id_scheme: user_id_scheme.to_owned(),
+            proto_fingerprint: proto_fingerprint.to_owned(),
+            ..Self::default()
+        }
+    }
+}
+
+impl StartWebAuthnRegistrationResponse {
+    pub fn success(challenge: Vec<u8>, rp_id: String, user_handle: Vec<u8>, credential_ids: Vec<Vec<u8>>) -> Self {
+        Self { status_code: StatusCode::Success.into(), challenge, rp_id, user_handle, credential_ids, ..Self::default() }
+    }
+}
+
+impl FinishWebAuthnRegistrationResponse {
+    pub fn success(credential_id: Vec<u8>) -> Self {
+        Self { status_code: StatusCode::Success.into(), credential_id, ..Self::default() }
+    }
+}
+
+impl StartWebAuthnAssertionResponse {
+    pub fn success(challenge: Vec<u8>, rp_id: String) -> Self {
+        Self { status_code: StatusCode::Success.into(), challenge, rp_id, ..Self::default() }
+    }
+}
+
+impl FinishWebAuthnAssertionResponse {
+    pub fn success(user_uuid: String, session_token: String) -> Self {
+        Self { status_code: StatusCode::Success.into(), user_uuid, session_token, ..Self::default() }
+    }
+}
+
+impl GetUserMetadataResponse {
+    pub fn success(metadata: HashMap<String, String>) -> Self {
+        Self { status_code: StatusCode::Success.into(), metadata, ..Self::default() }
+    }
+}
+
+impl ExportMyDataResponse {
+    pub fn success(data: UserDataExport) -> Self {
+        Self { status_code: StatusCode::Success.into(), data: Some(data), ..Self::default() }
+    }
+}
+
+impl RevokeLongLivedSessionsResponse {
+    pub fn success(revoked_count: u64) -> Self {
+        Self { status_code: StatusCode::Success.into(), revoked_count, ..Self::default() }
+    }
+}
+
+impl RevokeSessionsResponse {
+    pub fn success(revoked_count: u64) -> Self {
+        Self { status_code: StatusCode::Success.into(), revoked_count, ..Self::default() }
+    }
+}
+
+impl ListSessionsResponse {
+    pub fn success(sessions: Vec<SessionSummary>, next_page_token: String) -> Self {
+        Self { status_code: StatusCode::Success.into(), sessions, next_page_token, ..Self::default() }
+    }
+}
+
+impl ExportUserDataResponse {
+    pub fn success(data: UserDataExport) -> Self {
+        Self { status_code: StatusCode::Success.into(), data: Some(data), ..Self::default() }
+    }
+}
+
+impl ListUsersResponse {
+    pub fn success(users: Vec<UserSummary>, next_page_token: String) -> Self {
+        Self { status_code: StatusCode::Success.into(), users, next_page_token, ..Self::default() }
+    }
+}
+
+impl ListPendingUsersResponse {
+    pub fn success(users: Vec<UserSummary>, next_page_token: String) -> Self {
+        Self { status_code: StatusCode::Success.into(), users, next_page_token, ..Self::default() }
+    }
+}
+
+impl ListRecentAuditEventsResponse {
+    pub fn success(events: Vec<AuditRecord>) -> Self {
+        Self { status_code: StatusCode::Success.into(), events, ..Self::default() }
+    }
+}
+
+impl ListThrottledSourcesResponse {
+    pub fn success(sources: Vec<ThrottledSource>) -> Self {
+        Self { status_code: StatusCode::Success.into(), sources, ..Self::default() }
+    }
+}
+
+impl GetRateLimitStateResponse {
+    pub fn success(username_failures: u32, sources: Vec<ThrottledSource>) -> Self {
+        Self { status_code: StatusCode::Success.into(), username_failures, sources, ..Self::default() }
+    }
+}
+
+impl ClearLockoutResponse {
+    pub fn success(cleared_sources: Vec<String>) -> Self {
+        Self { status_code: StatusCode::Success.into(), cleared_sources, ..Self::default() }
+    }
+}
+
+impl ListDebugCapturesResponse {
+    pub fn success(calls: Vec<CapturedCall>, sample_rate: f64) -> Self {
+        Self { status_code: StatusCode::Success.into(), calls, sample_rate, ..Self::default() }
+    }
+}
+
+impl GetEffectiveConfigResponse {
+    pub fn success(settings: Vec<ConfigSetting>) -> Self {
+        Self { status_code: StatusCode::Success.into(), settings, ..Self::default() }
+    }
+}
+
+impl CreateInviteResponse {
+    pub fn success(invite_code: String, expires_at: i64) -> Self {
+        Self { status_code: StatusCode::Success.into(), invite_code, expires_at, ..Self::default() }
+    }
+}
+
+impl SetFeatureFlagResponse {
+    pub fn success(flags: HashMap<String, bool>) -> Self {
+        Self { status_code: StatusCode::Success.into(), flags, ..Self::default() }
+    }
+}
+
+impl SetLogLevelResponse {
+    pub fn success(filter: String) -> Self {
+        Self { status_code: StatusCode::Success.into(), filter, ..Self::default() }
+    }
+}
+
+impl GetStatsResponse {
+    pub fn success(
+        jobs: Vec<JobStats>,
+        internal_errors: u64,
+        tarpitted_requests: u64,
+        blocked_requests: u64,
+        passwords_rehashed: u64,
+        outdated_password_hashes: u64,
+        shadow_refusals: HashMap<String, u64>,
+    ) -> Self {
+        Self {
+            status_code: StatusCode::Success.into(),
+            jobs,
+            internal_errors,
+            tarpitted_requests,
+            blocked_requests,
+            passwords_rehashed,
+            outdated_password_hashes,
+            shadow_refusals,
+            ..Self::default()
+        }
+    }
+}
+
+impl ReplayProjectionResponse {
+    pub fn success(user_events: u64, session_events: u64) -> Self {
+        Self { status_code: StatusCode::Success.into(), user_events, session_events, ..Self::default() }
+    }
+}
+
+impl ReplicateSessionEventsResponse {
+    pub fn success(applied: u64) -> Self {
+        Self { status_code: StatusCode::Success.into(), applied, ..Self::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_only_set_the_status_code_of_failures() {
+        let reply = SignInResponse::failure(StatusCode::AccountSuspended);
+        assert_eq!(reply.status_code(), StatusCode::AccountSuspended);
+        assert_eq!(reply, SignInResponse { status_code: StatusCode::AccountSuspended.into(), ..Default::default() });
+
+        assert_eq!(SignOutResponse::from_result(Ok::<_, StatusCode>(())).status_code(), StatusCode::Success);
+        assert_eq!(SignOutResponse::from_result::<()>(Err(StatusCode::Failure)).status_code(), StatusCode::Failure);
+    }
+}
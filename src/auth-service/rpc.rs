@@ -0,0 +1,80 @@
+use std::{
+    collections::BTreeMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use auth_client::status::AuthError;
+use tonic::{Response, Status};
+use tracing::debug;
+
+use crate::{
+    i18n::{ErrorMessage, Locale},
+    store_metrics::OperationStats,
+};
+
+// How the handlers did, per RPC, like `StoreMetrics` for the stores: calls, the failed ones (a
+// reply other than SUCCESS, or an error), and how long they took.
+#[derive(Clone, Default)]
+pub struct RpcMetrics(Arc<Mutex<BTreeMap<&'static str, OperationStats>>>);
+
+impl RpcMetrics {
+    pub fn record(&self, rpc: &'static str, duration: Duration, failed: bool) {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).entry(rpc).or_default().record(duration, failed);
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<&'static str, OperationStats> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+// A call to a handler, from `AuthService::start_rpc` until `finish` sends the reply off. It is
+// counted in `RpcMetrics` either way: a call cut short by an error counts as failed.
+pub struct RpcCall<R: ErrorMessage> {
+    locale: Locale,
+    started_at: Instant,
+    metrics: RpcMetrics,
+    failed: bool,
+    reply: PhantomData<fn() -> R>,
+}
+
+impl<R: ErrorMessage> RpcCall<R> {
+    pub fn new(locale: Locale, metrics: RpcMetrics) -> Self {
+        Self { locale, started_at: Instant::now(), metrics, failed: true, reply: PhantomData }
+    }
+
+    // The reply, with its error message in the caller's language.
+    pub fn finish(mut self, reply: R) -> Result<Response<R>, Status> {
+        self.failed = AuthError::from_i32(reply.status_code()).is_some();
+        Ok(Response::new(self.locale.localize(reply)))
+    }
+}
+
+impl<R: ErrorMessage> Drop for RpcCall<R> {
+    fn drop(&mut self) {
+        let duration = self.started_at.elapsed();
+        debug!("{} done in {:?}{}", R::RPC, duration, if self.failed { ", failed" } else { "" });
+        self.metrics.record(R::RPC, duration, self.failed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::authentication::{SignOutResponse, StatusCode};
+
+    use super::*;
+
+    #[test]
+    fn should_count_calls_by_how_they_ended() {
+        let metrics = RpcMetrics::default();
+
+        let _ = RpcCall::new(Locale::default(), metrics.clone()).finish(SignOutResponse::success());
+        let _ = RpcCall::new(Locale::default(), metrics.clone()).finish(SignOutResponse::failure(StatusCode::Failure));
+        // Cut short by an error.
+        drop(RpcCall::<SignOutResponse>::new(Locale::default(), metrics.clone()));
+
+        let stats = &metrics.snapshot()["SignOut"];
+        assert_eq!((stats.calls, stats.errors), (3, 2));
+    }
+}
@@ -0,0 +1,768 @@
+use std::{
+    env,
+    future::Future,
+    net::SocketAddr,
+    path::Path,
+    sync::{atomic::Ordering, Arc, PoisonError, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use auth_ids::ids_from_env;
+use tokio::{
+    sync::watch,
+    task::{JoinHandle, JoinSet},
+};
+use tonic::{codec::CompressionEncoding, service::interceptor::InterceptedService, transport::ServerTlsConfig};
+use tracing::{debug, error, info, warn};
+
+use crate::access_log::{AccessLog, AccessLogService};
+use crate::admin::AuthAdminServer;
+use crate::admin_policy::{AdminPrincipals, AllowList, ExpressionPolicy, Policy};
+use crate::api_keys::ApiKeys;
+use crate::audit::InMemoryAuditLog;
+use crate::audit_export::AuditExporter;
+use crate::auth::{AuthServer, AuthService, Server};
+use crate::breached::BreachedPasswordChecker;
+use crate::challenge::challenge_verifier_from_env;
+use crate::config::Config;
+use crate::debug_capture::{DebugCapture, DebugCaptureService};
+use crate::effective_config::EffectiveConfig;
+use crate::events::event_sink_from_env;
+use crate::federation::ExternalProviders;
+use crate::geo::MaxMindGeoLookup;
+use crate::ip_rules::{ip_filter, AccessLists};
+use crate::jobs::Scheduler;
+use crate::journal::FileJournal;
+use crate::layers::{CustomLayer, LayeredService};
+use crate::lease::FileLease;
+use crate::ldap::{LdapDirectory, LdapUsersImpl};
+use crate::legacy::{LegacyAuth, LegacyAuthAdmin};
+use crate::hashers::password_hasher;
+use crate::listeners::{activated_listeners, bind_tcp, bind_unix, server_tls_config, ADMIN_FD_NAME};
+use crate::load_shedding::{LoadShedder, LoadSheddingService};
+use crate::logging::{rpc_span, FixedLogFilter, LogFilter};
+use crate::mailer::LoggingMailer;
+use crate::quotas::{ApiKeyQuotas, QuotaService};
+use crate::read_only::{ReadOnly, ReadOnlyService};
+use crate::profiling::DebugEndpoint;
+use crate::readiness::Readiness;
+use crate::replication::{SessionReplicationServer, SessionReplicator};
+use crate::server_timing::ServerTimingService;
+use crate::sessions::SessionsImpl;
+use crate::store_metrics::{InstrumentedSessions, InstrumentedUsers, StoreMetrics};
+use crate::tokens::token_generator_from_env;
+use crate::usage_stats::UsageStatsExporter;
+use crate::usernames::ReservedUsernames;
+use crate::users::UsersImpl;
+
+pub use crate::error::Error;
+
+// The auth service, up and serving (see `serve`). Dropping it stops the service, without waiting
+// for the calls in flight.
+pub struct Serving {
+    addrs: Vec<SocketAddr>,
+    admin_addrs: Vec<SocketAddr>,
+    health_addr: Option<SocketAddr>,
+    debug_addr: Option<SocketAddr>,
+    shutdown: watch::Sender<()>,
+    listeners: JoinSet<Result<(), tonic::transport::Error>>,
+    // The background jobs, the replication and audit export pushes, the readiness and debug
+    // listeners.
+    background: Vec<JoinHandle<()>>,
+}
+
+impl Serving {
+    // Where `Auth` is served, in the order of `Config::listen_addrs`; with port 0, the port the
+    // system picked.
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    // Where the admin service has listeners of its own (`Config::admin_listen_addrs`); when it
+    // has none, it is served next to `Auth`.
+    pub fn admin_addrs(&self) -> &[SocketAddr] {
+        &self.admin_addrs
+    }
+
+    pub fn health_addr(&self) -> Option<SocketAddr> {
+        self.health_addr
+    }
+
+    pub fn debug_addr(&self) -> Option<SocketAddr> {
+        self.debug_addr
+    }
+
+    // Serves until a listener fails.
+    pub async fn wait(mut self) -> Result<(), Error> {
+        while let Some(result) = self.listeners.join_next().await {
+            result.map_err(|e| Error::Transport(format!("Error::ListenerFailed: {e}")))??;
+        }
+        Ok(())
+    }
+
+    // Stops taking connections, lets the calls in flight finish, then stops the background jobs.
+    pub async fn shutdown(self) -> Result<(), Error> {
+        self.shutdown.send_replace(());
+        self.wait().await
+    }
+
+    // Serves until a listener fails, or until `stop` (e.g. SIGTERM); then shuts down, giving the
+    // calls in flight `drain_timeout` to finish. With another process serving the same sockets
+    // (see `Config::reuse_port`), none of their connections are dropped.
+    pub async fn wait_until(mut self, stop: impl Future<Output = ()>, drain_timeout: Duration) -> Result<(), Error> {
+        tokio::pin!(stop);
+        loop {
+            tokio::select! {
+                result = self.listeners.join_next() => match result {
+                    Some(result) => result.map_err(|e| Error::Transport(format!("Error::ListenerFailed: {e}")))??,
+                    None => return Ok(()),
+                },
+                _ = &mut stop => break,
+            }
+        }
+
+        info!("draining, for {:?} at most", drain_timeout);
+        match tokio::time::timeout(drain_timeout, self.shutdown()).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("calls still in flight after {:?}, dropped", drain_timeout);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        // The listeners go with their `JoinSet`.
+        for task in &self.background {
+            task.abort();
+        }
+    }
+}
+
+// Runs the auth service in-process, as the `auth` binary does, for applications embedding it and
+// for integration tests: binds every listener of `config` (port 0 takes any free port, see
+// `Serving::addrs`), starts the background jobs, and returns once it serves. What `config` doesn't
+// hold still comes from the environment: the API keys, AUTH_USERS_BACKEND, the session token
+// format, ...
+pub async fn serve(config: Config) -> Result<Serving, Error> {
+    serve_with_log_filter(config, Box::new(FixedLogFilter)).await
+}
+
+// Like `serve`, with the log filter SetLogLevel changes (see `init_logging`).
+pub async fn serve_with_log_filter(config: Config, log_filter: Box<dyn LogFilter + Send + Sync>) -> Result<Serving, Error> {
+    serve_with_layers(config, log_filter, Vec::new()).await
+}
+
+// Like `serve_with_log_filter`, with the embedding application's own middleware in front of the
+// RPCs (see `CustomLayer`).
+pub async fn serve_with_layers(
+    config: Config,
+    log_filter: Box<dyn LogFilter + Send + Sync>,
+    layers: Vec<CustomLayer>,
+) -> Result<Serving, Error> {
+    // AUTH_ID_SEED makes every id made up below reproducible, in builds with the deterministic-ids
+    // feature.
+    let ids = ids_from_env("AUTH_ID_SEED").map_err(Error::Config)?;
+
+    // AUTH_EVENT_LOG_DIR keeps users and sessions as journals of their changes, replayed here.
+    let journal_path = |file| config.event_log_dir.as_ref().map(|dir| Path::new(dir).join(file));
+
+    // Every call to the stores is timed; AUTH_SLOW_STORE_OPERATION_MS logs the slow ones.
+    let store_metrics = StoreMetrics::new(config.slow_store_operation);
+    let instrumented = |users, backend| InstrumentedUsers::new(users, backend, store_metrics.clone());
+
+    // AUTH_USERS_BACKEND=ldap verifies credentials against an LDAP/AD server (see `LdapDirectory::from_env`).
+    let auth_service = match env::var("AUTH_USERS_BACKEND").as_deref() {
+        Ok("ldap") => AuthService::builder().users(InstrumentedUsers::new(
+            LdapUsersImpl::new(LdapDirectory::from_env()?),
+            "ldap",
+            store_metrics.clone(),
+        )),
+        _ => {
+            // AUTH_USER_ID_SCHEME picks what user ids look like. AUTH_PASSWORD_HASH_ALGORITHM,
+            // AUTH_PASSWORD_HASH_ROUNDS and AUTH_PASSWORD_HASH_MEMORY_KIB: older hashes are redone
+            // at sign in.
+            let users = UsersImpl::default()
+                .with_id_generator(ids.clone())
+                .with_id_scheme(config.user_id_scheme)
+                .with_password_hasher(password_hasher(&config));
+            match journal_path("users.jsonl") {
+                Some(path) => AuthService::builder()
+                    .users(instrumented(users.with_journal(Box::new(FileJournal::open(path)?))?, "memory")),
+                None => AuthService::builder().users(instrumented(users, "memory")),
+            }
+        }
+    };
+    let ip_rules_file = config.ip_rules_file.clone();
+    let send_compression = config.grpc_compression.encoding();
+    let listen_addrs = config.listen_addrs.clone();
+    let unix_socket = config.unix_socket.clone();
+    let admin_listen_addrs = config.admin_listen_addrs.clone();
+    let health_listen_addr = config.health_listen_addr;
+    let debug_listen_addr = config.debug_listen_addr;
+    let reuse_port = config.reuse_port;
+    let serve_legacy_package = config.serve_legacy_package;
+
+    // Sockets passed in by systemd take the place of the addresses.
+    let activated = activated_listeners()?;
+    let has_admin_listeners = match &activated {
+        Some(listeners) => listeners.iter().any(|(name, _)| name == ADMIN_FD_NAME),
+        None => !admin_listen_addrs.is_empty(),
+    };
+    let has_tcp_listeners = match &activated {
+        Some(listeners) => !listeners.is_empty(),
+        None => !listen_addrs.is_empty() || !admin_listen_addrs.is_empty(),
+    };
+
+    // The debug endpoint would take admin API keys over plain HTTP.
+    if config.admin_tls_cert.is_some() && debug_listen_addr.is_some() {
+        return Err(Error::Config(String::from("Error::DebugEndpointWithAdminTls")));
+    }
+    let admin_tls = match (&config.admin_tls_cert, &config.admin_tls_key) {
+        (Some(cert), Some(key)) => Some(server_tls_config(cert, key, config.admin_tls_client_ca.as_deref()).map_err(Error::Crypto)?),
+        (None, None) => None,
+        _ => return Err(Error::Config(String::from("Error::IncompleteAdminTlsConfig"))),
+    };
+    // The public listeners would serve the admin service without TLS.
+    if admin_tls.is_some() && !has_admin_listeners {
+        return Err(Error::Config(String::from("Error::AdminTlsWithoutAdminListenAddrs")));
+    }
+    if !has_tcp_listeners && unix_socket.is_none() {
+        return Err(Error::Config(String::from("Error::NoListeners")));
+    }
+
+    // AUTH_IP_RULES_FILE restricts who may call the auth and admin RPCs.
+    let access_lists = match &ip_rules_file {
+        Some(path) => AccessLists::from_file(path).map_err(Error::Config)?,
+        None => AccessLists::default(),
+    };
+    let access_lists = Arc::new(RwLock::new(access_lists));
+    // AUTH_TRUSTED_PROXIES lists the proxies whose forwarding headers say who the client is.
+    let trusted_proxies = Arc::new(config.trusted_proxies.clone());
+
+    // AUTH_API_KEY_QUOTA_PER_MINUTE and AUTH_API_KEY_QUOTAS limit the callers presenting an API key.
+    let quotas = Arc::new(ApiKeyQuotas::from_config(&config));
+
+    // AUTH_MAX_IN_FLIGHT_REQUESTS, AUTH_RPC_TIMEOUT_MS and AUTH_RPC_TIMEOUTS shed the load the
+    // service can't keep up with.
+    let shedder = Arc::new(LoadShedder::from_config(&config));
+
+    // AUTH_READ_ONLY leaves the changes to the primary at AUTH_PRIMARY_URL.
+    let read_only = Arc::new(ReadOnly::from_config(&config));
+
+    // AUTH_DEBUG_CAPTURE_RATE and AUTH_DEBUG_CAPTURE_SIZE keep whole calls for debugging (see
+    // `SetDebugCapture`).
+    let debug_capture = Arc::new(DebugCapture::from_config(&config));
+
+    // AUTH_SERVER_TIMING tells callers how long their calls took in here (see `ServerTimingService`).
+    let server_timing = config.server_timing;
+
+    // AUTH_ACCESS_LOG and AUTH_ACCESS_LOG_RATE write a line per call (see `AccessLog`).
+    let access_log = AccessLog::from_config(&config).map_err(Error::Config)?.map(Arc::new);
+
+    // AUTH_SESSION_TOKEN_FORMAT picks what session tokens look like (see `token_generator_from_env`).
+    // AUTH_CLOCK_SKEW_LEEWAY_SECS keeps sessions past their expiry for the replicas' clocks.
+    let sessions = SessionsImpl::new(config.session_ttl, config.long_lived_session_ttl)
+        .with_token_generator(token_generator_from_env(&ids).map_err(Error::Crypto)?)
+        .with_id_generator(ids.clone())
+        .with_clock_skew_leeway(config.clock_skew_leeway);
+    let sessions = match config.session_absolute_ttl {
+        Some(absolute_ttl) => sessions.with_sliding_expiry(absolute_ttl),
+        None => sessions,
+    };
+    let sessions = match journal_path("sessions.jsonl") {
+        Some(path) => sessions.with_journal(Box::new(FileJournal::open(path)?))?,
+        None => sessions,
+    };
+    // AUTH_MAX_SESSIONS bounds the memory the sessions take, signing the AUTH_SESSION_EVICTION
+    // choice out for each new one past it.
+    let sessions = match config.max_sessions {
+        0 => sessions,
+        max_sessions => sessions.with_max_sessions(max_sessions, config.session_eviction),
+    };
+
+    // AUTH_REPLICATION_PEERS get every change to the sessions made here, and AUTH_REPLICATION_KEY
+    // lets the peers push theirs, so a session is good on every replica.
+    let replication_key = env::var("AUTH_REPLICATION_KEY").ok().filter(|key| !key.is_empty());
+    let replicator = match (&replication_key, config.replication_peers.is_empty()) {
+        (Some(key), false) => Some(SessionReplicator::new(&config.replication_peers, key)?),
+        (None, false) => return Err(Error::Config(String::from("Error::ReplicationWithoutKey"))),
+        (_, true) => None,
+    };
+    let sessions = match &replicator {
+        Some(replicator) => sessions.with_replication(replicator.sender()),
+        None => sessions,
+    };
+    let sessions = InstrumentedSessions::new(sessions, "memory", store_metrics.clone());
+
+    // AUTH_MAINTENANCE_INTERVAL_SECS, AUTH_JOB_INTERVALS and AUTH_JOB_JITTER_MS schedule the
+    // background jobs below.
+    let scheduler = Scheduler::from_config(&config);
+    // AUTH_LEADER_LEASE_FILE leaves purging and sweeping to one replica of those sharing it.
+    let scheduler = match &config.leader_lease_file {
+        Some(path) => scheduler.with_leader_lease(Box::new(FileLease::new(path))),
+        None => scheduler,
+    };
+
+    // AUTH_USAGE_STATS_FILE and AUTH_USAGE_STATS_URL get how many signed in each day, as counts
+    // bucketed by AUTH_USAGE_STATS_BUCKET and noised by AUTH_USAGE_STATS_EPSILON.
+    let usage_stats_exporter = UsageStatsExporter::from_config(&config);
+
+    // AUTH_AUDIT_FILE, AUTH_AUDIT_SYSLOG_ADDR and AUTH_AUDIT_HTTP_URL get the audit log too.
+    let audit_exporter = AuditExporter::from_config(&config)?;
+    let audit_log = InMemoryAuditLog::default().with_export(audit_exporter.sender());
+    let dropped_audit_entries = audit_exporter.dropped();
+
+    // AUTH_GEOIP_DB places sign-ins, so those from unusual places get flagged.
+    let geo_lookup = config.geoip_db.as_deref().map(MaxMindGeoLookup::open).transpose().map_err(Error::Config)?;
+
+    // AUTH_RESERVED_USERNAMES and AUTH_USERNAME_DENY_FILE keep the names of system accounts, and
+    // offensive ones, from being signed up with.
+    let reserved_usernames = ReservedUsernames::new(&config.reserved_usernames);
+    let reserved_usernames = match &config.username_deny_file {
+        Some(path) => reserved_usernames.with_deny_file(path).map_err(Error::Config)?,
+        None => reserved_usernames,
+    };
+
+    // AUTH_ADMIN_PRINCIPALS says who is behind each admin API key, and AUTH_ADMIN_ROLE_GRANTS (or
+    // the rules of AUTH_ADMIN_POLICY_FILE) which admin RPCs they may call.
+    let admin_principals = AdminPrincipals::from_env("AUTH_ADMIN_PRINCIPALS").map_err(Error::Config)?;
+    let admin_policy: Box<dyn Policy + Send + Sync> = match &config.admin_policy_file {
+        Some(path) => Box::new(ExpressionPolicy::from_file(path).map_err(Error::Policy)?),
+        None => Box::new(AllowList::from_grants(&config.admin_role_grants)),
+    };
+
+    // What the service runs with, for whoever has to find out why it doesn't behave: logged now,
+    // and answered by GetEffectiveConfig.
+    let effective_config = EffectiveConfig::from_config(&config)
+        .with("users_backend", match env::var("AUTH_USERS_BACKEND").as_deref() {
+            Ok("ldap") => "ldap",
+            _ => "memory",
+        })
+        .with("session_token_format", env::var("AUTH_SESSION_TOKEN_FORMAT").unwrap_or_else(|_| String::from("uuid")))
+        .with("event_sink", match env::var("AUTH_WEBHOOK_URL") {
+            Ok(_) => "webhook",
+            Err(_) => "stdout",
+        })
+        .with("replication_key", if replication_key.is_some() { "set" } else { "unset" })
+        .with("admin_principals", admin_principals.len().to_string());
+    effective_config.log();
+
+    let auth_service = auth_service
+        .sessions(sessions)
+        .external_providers(ExternalProviders::from_env().map_err(Error::Config)?)
+        .api_keys(ApiKeys::from_env("AUTH_API_KEYS"))
+        .admin_api_keys(ApiKeys::from_env("AUTH_ADMIN_API_KEYS"))
+        .admin_principals(admin_principals)
+        .admin_policy(admin_policy)
+        .replication_keys(ApiKeys::new(replication_key.clone()))
+        .mailer(Box::new(LoggingMailer))
+        .breached_passwords(Box::new(BreachedPasswordChecker::from_env().map_err(Error::Config)?))
+        .audit_log(Box::new(audit_log))
+        .event_sink(event_sink_from_env())
+        .challenge_verifier(challenge_verifier_from_env(&ids).map_err(Error::Config)?)
+        .log_filter(log_filter)
+        .debug_capture(debug_capture.clone())
+        .job_statuses(scheduler.statuses())
+        .store_metrics(store_metrics.clone())
+        .id_generator(ids)
+        .reserved_usernames(reserved_usernames)
+        .effective_config(effective_config)
+        .config(config);
+    let auth_service = Arc::new(match geo_lookup {
+        Some(geo_lookup) => auth_service.geo_lookup(Box::new(geo_lookup)),
+        None => auth_service,
+    }
+    .build());
+
+    // Better not to start than to take traffic and fail every RPC: the stores have to work (the
+    // journals can be written, the directory can be reached, ...).
+    auth_service.check_stores().map_err(|e| Error::Storage(format!("Error::StoreCheckFailed: {e}")))?;
+    let readiness =
+        Readiness::default().with_auth_service(auth_service.clone(), access_lists.clone(), trusted_proxies.clone(), quotas.clone());
+
+    // Background maintenance: purge accounts whose deletion grace period is over, those never
+    // verified (AUTH_UNVERIFIED_ACCOUNT_TTL_SECS) and those soft-deleted longer than
+    // AUTH_SOFT_DELETE_RETENTION_SECS, sweep expired sessions, forget sign-in failures that no
+    // longer count, quotas that are full again, nonces out of the replay window and cached replies
+    // past AUTH_RESPONSE_CACHE_TTL_SECS, pick up changes to the ip rules, warn about stores nearing
+    // their capacity, and log the metrics.
+    let purged_service = auth_service.clone();
+    let purged_unverified_service = auth_service.clone();
+    let purged_soft_deleted_service = auth_service.clone();
+    let swept_service = auth_service.clone();
+    let forgetting_service = auth_service.clone();
+    let forgotten_quotas = quotas.clone();
+    let logged_service = auth_service.clone();
+    let checked_service = auth_service.clone();
+    let capacity_service = auth_service.clone();
+    let compacted_service = auth_service.clone();
+    let checked_readiness = readiness.clone();
+    let logged_shedder = shedder.clone();
+    let logged_store_metrics = store_metrics.clone();
+    // Purging is for the primary to do; a read-only replica only forgets what expired.
+    let scheduler = match read_only.enabled {
+        true => scheduler,
+        false => scheduler
+            .with_singleton_job("purge-deleted-accounts", move || {
+                let purged = purged_service.purge_deleted_accounts();
+                if purged > 0 { info!("purged {} deleted accounts", purged) };
+            })
+            .with_singleton_job("purge-unverified-accounts", move || {
+                let purged = purged_unverified_service.purge_unverified_accounts();
+                if purged > 0 { info!("purged {} unverified accounts", purged) };
+            })
+            .with_singleton_job("purge-soft-deleted-accounts", move || {
+                let purged = purged_soft_deleted_service.purge_soft_deleted_accounts();
+                if purged > 0 { info!("purged {} soft-deleted accounts", purged) };
+            }),
+    };
+    let scheduler = scheduler
+        .with_singleton_job("sweep-sessions", move || {
+            let swept = swept_service.sweep_expired_sessions();
+            if swept > 0 { debug!("swept {} expired sessions", swept) };
+        })
+        .with_job("forget-expired-limits", move || {
+            forgetting_service.throttle.forget_expired(&forgetting_service.config);
+            forgetting_service.seen_nonces.forget_expired(SystemTime::now());
+            forgetting_service.metadata_cache.forget_expired();
+            forgotten_quotas.forget_full();
+        })
+        .with_job("check-stores", move || {
+            let result = checked_service.check_stores();
+            if let Err(e) = &result { warn!("store check failed: {}", e) };
+            checked_readiness.set(result);
+        })
+        .with_job("check-capacity", move || {
+            capacity_service.check_capacity();
+        })
+        .with_job("compact-logs", move || match compacted_service.compact_logs() {
+            Ok((0, 0)) => {}
+            Ok((audit_entries, discarded)) => {
+                info!("purged {} audit entries and {} discarded journal files", audit_entries, discarded)
+            }
+            Err(e) => warn!("failed to compact the logs: {}", e),
+        })
+        .with_job("log-metrics", move || {
+            debug!("throttle metrics: {:?}", logged_service.throttle.metrics());
+            debug!("internal errors: {}", logged_service.internal_errors());
+            debug!("store outage metrics: {:?}", logged_service.outage_metrics());
+            debug!("load shedding metrics: {:?}", logged_shedder.metrics());
+            debug!("audit entries not exported: {}", dropped_audit_entries.load(Ordering::Relaxed));
+            for ((store, backend, operation), stats) in logged_store_metrics.snapshot() {
+                debug!(
+                    "{} store metrics ({}): {} calls={} errors={} total={:?}",
+                    store, backend, operation, stats.calls, stats.errors, stats.total
+                );
+            }
+            for (rpc, stats) in logged_service.rpc_metrics.snapshot() {
+                debug!("rpc metrics: {} calls={} failures={} total={:?}", rpc, stats.calls, stats.errors, stats.total);
+            }
+        });
+    // Every replica sends its own, as each counts the sign-ins it served.
+    let scheduler = match usage_stats_exporter {
+        Some(exporter) => {
+            let counted_service = auth_service.clone();
+            scheduler.with_job("export-usage-stats", move || {
+                exporter.export(counted_service.usage_stats.take_completed(SystemTime::now()));
+            })
+        }
+        None => scheduler,
+    };
+    let scheduler = match ip_rules_file {
+        Some(path) => {
+            let reloaded_access_lists = access_lists.clone();
+            scheduler.with_job("reload-ip-rules", move || match AccessLists::from_file(&path) {
+                Ok(reloaded) => *reloaded_access_lists.write().unwrap_or_else(PoisonError::into_inner) = reloaded,
+                // Keep the rules we have rather than opening up.
+                Err(e) => warn!("failed to reload ip rules: {}", e),
+            })
+        }
+        None => scheduler,
+    };
+
+    let mut auth_server = AuthServer::from_arc(auth_service.clone()).accept_compressed(CompressionEncoding::Gzip);
+    let mut auth_admin_server = AuthAdminServer::from_arc(auth_service.clone()).accept_compressed(CompressionEncoding::Gzip);
+    // Replicas only, but over the admin listeners: they are the ones inside the deployment.
+    let replication_server = replication_key.is_some().then(|| SessionReplicationServer::from_arc(auth_service.clone()));
+    if let Some(encoding) = send_compression {
+        auth_server = auth_server.send_compressed(encoding);
+        auth_admin_server = auth_admin_server.send_compressed(encoding);
+    }
+
+    // Instantiate gRPC server, once per listener, with the services that listener serves
+    let router = |with_auth: bool, with_admin: bool, tls: Option<ServerTlsConfig>| {
+        // Every RPC in a span of its own, joining the caller's trace (OTEL_EXPORTER_OTLP_ENDPOINT).
+        let mut server = Server::builder().trace_fn(rpc_span);
+        if let Some(tls) = tls {
+            server = server.tls_config(tls)?;
+        }
+
+        let auth = with_auth.then(|| AccessLogService::new(LoadSheddingService::new(
+            LayeredService::new(
+                ReadOnlyService::new(
+                    QuotaService::new(
+                        InterceptedService::new(
+                            DebugCaptureService::new(ServerTimingService::new(auth_server.clone(), server_timing), debug_capture.clone()),
+                            ip_filter(access_lists.clone(), trusted_proxies.clone(), |access_lists| &access_lists.auth),
+                        ),
+                        quotas.clone(),
+                    ),
+                    read_only.clone(),
+                ),
+                &layers,
+            ),
+            shedder.clone(),
+        ), access_log.clone()));
+        let admin = with_admin.then(|| AccessLogService::new(LoadSheddingService::new(
+            LayeredService::new(
+                ReadOnlyService::new(
+                    QuotaService::new(
+                        InterceptedService::new(
+                            DebugCaptureService::new(ServerTimingService::new(auth_admin_server.clone(), server_timing), debug_capture.clone()),
+                            ip_filter(access_lists.clone(), trusted_proxies.clone(), |access_lists| &access_lists.admin),
+                        ),
+                        quotas.clone(),
+                    ),
+                    read_only.clone(),
+                ),
+                &layers,
+            ),
+            shedder.clone(),
+        ), access_log.clone()));
+
+        // Not shed: a replica missing changes is worse off than one answering slowly.
+        let replication = replication_server.clone().filter(|_| with_admin).map(|replication_server| {
+            InterceptedService::new(
+                replication_server,
+                ip_filter(access_lists.clone(), trusted_proxies.clone(), |access_lists| &access_lists.admin),
+            )
+        });
+
+        Ok::<_, tonic::transport::Error>(server
+            .add_optional_service(auth.clone().filter(|_| serve_legacy_package).map(LegacyAuth))
+            .add_optional_service(admin.clone().filter(|_| serve_legacy_package).map(LegacyAuthAdmin))
+            .add_optional_service(auth)
+            .add_optional_service(admin)
+            .add_optional_service(replication))
+    };
+
+    // The admin service goes next to `Auth`, unless it has listeners of its own.
+    let admin_on_public = !has_admin_listeners;
+    let all_addrs = [listen_addrs.as_slice(), admin_listen_addrs.as_slice()].concat();
+
+    // Every listener stops taking connections once this is sent to (or dropped).
+    let (shutdown, _) = watch::channel(());
+    let stopped = || {
+        let mut shutdown = shutdown.subscribe();
+        async move {
+            let _ = shutdown.changed().await;
+        }
+    };
+
+    // Bound before anything is served, so a taken address leaves nothing running.
+    let (bound, admin_bound) = match activated {
+        Some(listeners) => {
+            let (admin, public): (Vec<_>, Vec<_>) = listeners.into_iter().partition(|(name, _)| name == ADMIN_FD_NAME);
+            (public.into_iter().map(|(_, incoming)| incoming).collect(), admin.into_iter().map(|(_, incoming)| incoming).collect())
+        }
+        None => (
+            listen_addrs.iter().map(|addr| bind_tcp(*addr, &all_addrs, reuse_port)).collect::<Result<Vec<_>, _>>()?,
+            admin_listen_addrs.iter().map(|addr| bind_tcp(*addr, &all_addrs, reuse_port)).collect::<Result<Vec<_>, _>>()?,
+        ),
+    };
+    let unix_bound = unix_socket.as_deref().map(bind_unix).transpose()?;
+    let health = health_listen_addr.map(|addr| readiness.serve(addr)).transpose()?;
+    let debug = debug_listen_addr.map(|addr| DebugEndpoint::new(auth_service.clone(), access_lists.clone(), trusted_proxies.clone()).serve(addr)).transpose()?;
+
+    let mut listeners = JoinSet::new();
+    let mut addrs = Vec::new();
+    let mut admin_addrs = Vec::new();
+
+    for incoming in bound {
+        let addr = incoming.as_ref().local_addr()?;
+        info!("auth-server, starts at {:?}", addr);
+        listeners.spawn(router(true, admin_on_public, None)?.serve_with_incoming_shutdown(incoming, stopped()));
+        addrs.push(addr);
+    }
+
+    for incoming in admin_bound {
+        let addr = incoming.as_ref().local_addr()?;
+        info!("auth-server (admin), starts at {:?}", addr);
+        listeners.spawn(router(false, true, admin_tls.clone())?.serve_with_incoming_shutdown(incoming, stopped()));
+        admin_addrs.push(addr);
+    }
+
+    if let (Some(path), Some(incoming)) = (unix_socket, unix_bound) {
+        info!("auth-server, starts at unix:{}", path);
+        listeners.spawn(router(true, admin_on_public, None)?.serve_with_incoming_shutdown(incoming, stopped()));
+    }
+
+    let mut background = scheduler.start();
+    auth_service.start_stats_feed();
+    if let Some(replicator) = replicator {
+        background.extend(replicator.start());
+    }
+    background.extend(audit_exporter.start());
+
+    // Not a listener of the RPCs: its failing doesn't stop the service.
+    let health_addr = health.map(|(addr, server)| {
+        info!("auth-server (readiness), starts at {:?}", addr);
+        background.push(tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("readiness listener failed: {}", e);
+            }
+        }));
+        addr
+    });
+    let debug_addr = debug.map(|(addr, server)| {
+        info!("auth-server (debug), starts at {:?}", addr);
+        background.push(tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("debug listener failed: {}", e);
+            }
+        }));
+        addr
+    });
+
+    Ok(Serving { addrs, admin_addrs, health_addr, debug_addr, shutdown, listeners, background })
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Request;
+
+    use crate::auth::authentication::{auth_client::AuthClient, CreateGuestSessionRequest};
+
+    use super::*;
+
+    fn config() -> Config {
+        Config { listen_addrs: vec![SocketAddr::from(([127, 0, 0, 1], 0))], ..Config::default() }
+    }
+
+    #[tokio::test]
+    async fn should_serve_on_the_port_it_was_given_and_shut_down() {
+        let serving = serve(config()).await.unwrap();
+        let addr = serving.addrs()[0];
+        assert_ne!(addr.port(), 0);
+
+        let mut client = AuthClient::connect(format!("http://{addr}")).await.unwrap();
+        client.create_guest_session(Request::new(CreateGuestSessionRequest {})).await.unwrap();
+
+        serving.shutdown().await.unwrap();
+        assert!(AuthClient::connect(format!("http://{addr}")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_say_how_long_calls_took_when_asked() {
+        use crate::server_timing::SERVER_TIMING_HEADER;
+
+        let serving = serve(Config { server_timing: true, ..config() }).await.unwrap();
+        let mut client = AuthClient::connect(format!("http://{}", serving.addrs()[0])).await.unwrap();
+
+        let response = client.create_guest_session(Request::new(CreateGuestSessionRequest {})).await.unwrap();
+        let server_timing = response.metadata().get(SERVER_TIMING_HEADER).unwrap().to_str().unwrap();
+        assert!(server_timing.starts_with("handler;dur="), "{server_timing}");
+
+        serving.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_write_a_line_per_call_to_the_access_log() {
+        let path = std::env::temp_dir().join(format!("access-{}.log", std::process::id()));
+        let serving = serve(Config { access_log: Some(path.to_string_lossy().into_owned()), ..config() }).await.unwrap();
+        let mut client = AuthClient::connect(format!("http://{}", serving.addrs()[0])).await.unwrap();
+
+        client.create_guest_session(Request::new(CreateGuestSessionRequest {})).await.unwrap();
+        serving.shutdown().await.unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let line: serde_json::Value = serde_json::from_str(written.lines().next().unwrap()).unwrap();
+        assert_eq!(line["method"], "/authentication.v1.Auth/CreateGuestSession");
+        assert_eq!(line["grpcStatus"], 0);
+        assert_eq!(line["peer"], "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn should_put_custom_layers_in_front_of_the_rpcs() {
+        use tonic::{codegen::http, server::NamedService, transport::Body, Code, Status};
+        use tower::{service_fn, util::BoxCloneService, ServiceExt};
+
+        use crate::layers::RpcService;
+
+        // An embedder's own check, for Auth only.
+        let layer: CustomLayer = Arc::new(|name, service: RpcService| {
+            if name != AuthServer::<AuthService>::NAME {
+                return service;
+            }
+            BoxCloneService::new(service_fn(move |request: http::Request<Body>| {
+                let service = service.clone();
+                async move {
+                    match request.headers().contains_key("x-embedder-key") {
+                        true => service.oneshot(request).await,
+                        false => Ok(Status::permission_denied("no x-embedder-key").to_http()),
+                    }
+                }
+            }))
+        });
+
+        let serving = serve_with_layers(config(), Box::new(FixedLogFilter), vec![layer]).await.unwrap();
+        let mut client = AuthClient::connect(format!("http://{}", serving.addrs()[0])).await.unwrap();
+
+        let status = client.create_guest_session(Request::new(CreateGuestSessionRequest {})).await.unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+
+        let mut request = Request::new(CreateGuestSessionRequest {});
+        request.metadata_mut().insert("x-embedder-key", "secret".parse().unwrap());
+        client.create_guest_session(request).await.unwrap();
+
+        serving.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_hand_over_to_a_new_instance_on_the_same_port() {
+        let old = serve(Config { reuse_port: true, ..config() }).await.unwrap();
+        let addr = old.addrs()[0];
+        let new = serve(Config { reuse_port: true, listen_addrs: vec![addr], ..config() }).await.unwrap();
+        assert_eq!(new.addrs(), &[addr]);
+
+        // The old one stops once told to, and its port keeps serving.
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let draining = tokio::spawn(old.wait_until(
+            async move {
+                let _ = stopped.await;
+            },
+            Duration::from_secs(5),
+        ));
+        stop.send(()).unwrap();
+        draining.await.unwrap().unwrap();
+
+        let mut client = AuthClient::connect(format!("http://{addr}")).await.unwrap();
+        client.create_guest_session(Request::new(CreateGuestSessionRequest {})).await.unwrap();
+        new.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_refuse_to_start_without_listeners() {
+        let config = Config { listen_addrs: Vec::new(), ..Config::default() };
+
+        let error = serve(config).await.err().unwrap();
+        assert_eq!(error.to_string(), "Error::NoListeners");
+    }
+
+    #[tokio::test]
+    async fn should_refuse_a_plain_debug_endpoint_next_to_admin_tls() {
+        let config = Config {
+            admin_tls_cert: Some("admin.crt".to_owned()),
+            admin_tls_key: Some("admin.key".to_owned()),
+            debug_listen_addr: Some("127.0.0.1:0".parse().unwrap()),
+            ..Config::default()
+        };
+
+        let error = serve(config).await.err().unwrap();
+        assert_eq!(error.to_string(), "Error::DebugEndpointWithAdminTls");
+    }
+}
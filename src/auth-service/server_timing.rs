@@ -0,0 +1,120 @@
+use std::{
+    cell::Cell,
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tonic::{
+    body::BoxBody,
+    codegen::{http, Service},
+    server::NamedService,
+    transport::Body,
+};
+
+// Where responses say how long the service took (AUTH_SERVER_TIMING), as in HTTP's Server-Timing:
+// `handler;dur=12.345, store;dur=3.210`, in milliseconds. What the caller measured beyond
+// `handler` was spent on the network, or waiting to be served.
+pub const SERVER_TIMING_HEADER: &str = "server-timing";
+
+tokio::task_local! {
+    // The time the stores took so far, for the call being handled.
+    static STORE_TIME: Cell<Duration>;
+}
+
+// Counts `duration` towards the store time of the call being handled, if any (see `StoreMetrics`).
+pub fn add_store_time(duration: Duration) {
+    let _ = STORE_TIME.try_with(|store_time| store_time.set(store_time.get() + duration));
+}
+
+fn header_value(handler: Duration, store: Duration) -> String {
+    let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    format!("handler;dur={:.3}, store;dur={:.3}", millis(handler), millis(store))
+}
+
+// Times the calls to a service, and the stores within them, into `SERVER_TIMING_HEADER`. A stream
+// is timed until its response headers, i.e. its first message.
+#[derive(Clone)]
+pub struct ServerTimingService<S> {
+    inner: S,
+    enabled: bool,
+}
+
+impl<S> ServerTimingService<S> {
+    pub fn new(inner: S, enabled: bool) -> Self {
+        Self { inner, enabled }
+    }
+}
+
+impl<S> Service<http::Request<Body>> for ServerTimingService<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let response = self.inner.call(request);
+        if !self.enabled {
+            return Box::pin(response);
+        }
+
+        Box::pin(async move {
+            let started_at = Instant::now();
+            let (store_time, response) = STORE_TIME
+                .scope(Cell::new(Duration::ZERO), async move {
+                    let response = response.await;
+                    (STORE_TIME.with(Cell::get), response)
+                })
+                .await;
+
+            let mut response = response?;
+            if let Ok(value) = header_value(started_at.elapsed(), store_time).parse() {
+                response.headers_mut().insert(SERVER_TIMING_HEADER, value);
+            }
+            Ok(response)
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for ServerTimingService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::body::empty_body;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    fn handler() -> impl Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible, Future = impl Send> + Clone {
+        service_fn(|_request: http::Request<Body>| async {
+            add_store_time(Duration::from_millis(2));
+            add_store_time(Duration::from_millis(3));
+            Ok::<_, Infallible>(http::Response::new(empty_body()))
+        })
+    }
+
+    #[tokio::test]
+    async fn should_report_handler_and_store_time() {
+        let service = ServerTimingService::new(handler(), true);
+        let response = service.oneshot(http::Request::new(Body::empty())).await.unwrap();
+
+        let value = response.headers()[SERVER_TIMING_HEADER].to_str().unwrap();
+        assert!(value.starts_with("handler;dur="), "{value}");
+        assert!(value.ends_with(", store;dur=5.000"), "{value}");
+
+        let service = ServerTimingService::new(handler(), false);
+        let response = service.oneshot(http::Request::new(Body::empty())).await.unwrap();
+        assert!(!response.headers().contains_key(SERVER_TIMING_HEADER));
+    }
+}
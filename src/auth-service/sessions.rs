@@ -1,53 +1,1633 @@
-use std::collections::HashMap;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    ops::Bound::{Excluded, Unbounded},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use uuid::Uuid;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use tokio::sync::broadcast;
+use tonic::Request;
+use tracing::{debug, warn};
+
+use auth_ids::Ids;
+use rand_core::{OsRng, RngCore};
+
+use crate::claims::Claims;
+use crate::error::StoreError;
+use crate::ip_rules::Cidr;
+use crate::journal::Journal;
+use crate::peer::PeerInfo;
+use crate::tokens::{jwt_id, TokenGenerator, UuidTokens};
+use crate::webauthn::WebAuthnCeremony;
+
+// How many WebAuthn challenges are kept at most; past that, the one expiring first goes. Anybody
+// may ask for a sign-in challenge.
+const MAX_WEBAUTHN_CHALLENGES: usize = 100_000;
 
 pub trait SessionsOps {
-    fn create_session(&mut self, user_uuid: &str) -> String;
-    fn delete_session(&mut self, user_uuid: &str);
+    fn create_session(&mut self, user_uuid: &str, class: SessionClass) -> String {
+        self.create_scoped_session(user_uuid, class, Vec::new())
+    }
+    // A session that may only do what `scopes` say (see `UsersOps::get_scopes`); services taking
+    // its token check them, we only hand them out.
+    fn create_scoped_session(&mut self, user_uuid: &str, class: SessionClass, scopes: Vec<String>) -> String;
+    // Like `create_scoped_session`, with claims for the token to carry (see `ClaimsProvider`). Stores
+    // whose tokens carry none leave them out.
+    fn create_session_with_claims(
+        &mut self,
+        user_uuid: &str,
+        class: SessionClass,
+        scopes: Vec<String>,
+        _claims: &Claims,
+    ) -> String {
+        self.create_scoped_session(user_uuid, class, scopes)
+    }
+    // A `Delegated` session on behalf of the user of `parent_token`, with some of its scopes, for
+    // `ttl` at most; it goes when the parent does, and is bound and pinned as the parent is.
+    // Returns the token and the session.
+    fn create_delegated_session(
+        &mut self,
+        parent_token: &str,
+        scopes: Vec<String>,
+        ttl: Duration,
+    ) -> Result<(String, Session), StoreError>;
+    // Whether there was a session to delete.
+    fn delete_session(&mut self, session_token: &str) -> bool;
+    // Expired sessions are never returned.
+    fn get_session(&self, session_token: &str) -> Option<Session>;
+    // Like `get_session`, but counts as activity on the session (see `with_sliding_expiry`).
+    fn touch_session(&mut self, session_token: &str) -> Option<Session>;
+    fn delete_sessions_of_class(&mut self, class: SessionClass) -> usize;
+    fn get_sessions_of_user(&self, user_uuid: &str) -> Vec<Session>;
+    fn delete_sessions_of_user(&mut self, user_uuid: &str) -> usize;
+    // In one go, for revoking many sessions at once. Unknown tokens are skipped.
+    fn delete_sessions(&mut self, session_tokens: &[String]) -> usize;
+    fn delete_sessions_of_user_created_before(&mut self, user_uuid: &str, cutoff: SystemTime) -> usize;
+    // Voids every session of the user, e.g. when their password changes, by moving them to a new
+    // session generation: sessions from an older one are refused wherever they turn up, including
+    // ones whose creation reaches a replica only after this. Returns how many sessions went here.
+    fn advance_session_generation(&mut self, user_uuid: &str) -> usize;
+    // Drops expired sessions and magic links, which are never returned anyway; returns how many
+    // sessions went.
+    fn delete_expired_sessions(&mut self) -> usize;
+    // The sessions that ended before their expiry after `cursor`, as `token_digest`s.
+    fn revoked_since(&self, cursor: u64) -> Revocations;
+    // Changes made on another replica (see `SessionsImpl::with_replication`); they are journaled
+    // here but not passed on. Returns how many were applied.
+    fn apply_replicated(&mut self, events: Vec<SessionEvent>) -> usize;
+    // Expired sessions count until they are swept (see `delete_expired_sessions`).
+    fn session_count(&self) -> usize;
+    // The sessions that haven't expired, as the changes that recreate them in another store (see
+    // `migrate`), each delegated session after its parent. `apply_replicated` takes them in.
+    fn export_sessions(&self) -> Vec<SessionEvent>;
+    // The sessions evicted to make room for new ones since the last call (see
+    // `SessionsImpl::with_max_sessions`), for the audit log.
+    fn take_evicted_sessions(&mut self) -> Vec<Session> {
+        Vec::new()
+    }
+    // See `UsersOps::check_store`.
+    fn check_store(&self) -> Result<(), StoreError>;
+    // See `SessionsImpl::with_journal`.
+    fn replay(&mut self, until: Option<SystemTime>) -> Result<usize, StoreError>;
+    fn create_magic_link(&mut self, user_uuid: &str, ttl: Duration) -> String;
+    fn consume_magic_link(&mut self, magic_link_token: &str) -> Option<String>;
+    // 32 random bytes for a WebAuthn ceremony to sign (see `RelyingParty`), which can be answered
+    // once, within `ttl`.
+    fn create_webauthn_challenge(&mut self, ceremony: WebAuthnCeremony, ttl: Duration) -> Vec<u8>;
+    // What the challenge was made for; gone whether or not it is still valid.
+    fn consume_webauthn_challenge(&mut self, challenge: &[u8]) -> Option<WebAuthnCeremony>;
+    // Adds the device to the ones seen for the user. Returns true when it is new and the user has
+    // used other devices before; a user's very first device is nothing to warn about.
+    fn remember_device(&mut self, user_uuid: &str, fingerprint: &str) -> bool;
+    // Binds the session to a client (see `client_fingerprint`); returns false when there is no
+    // such session.
+    fn bind_session(&mut self, session_token: &str, binding: &str) -> bool;
+    // Pins the session to a tenant (see `request_tenant`); returns false when there is no such
+    // session.
+    fn pin_session(&mut self, session_token: &str, tenant: &str) -> bool;
+    // Notes the address the session was created from, for `list_sessions`; returns false when
+    // there is no such session.
+    fn set_session_ip(&mut self, session_token: &str, ip: IpAddr) -> bool;
+    // A page of the sessions that haven't expired, the oldest first, going by an index rather than
+    // through every session where the store can.
+    fn list_sessions(&self, query: &SessionQuery) -> Result<SessionPage, StoreError>;
+}
+
+// Where a request came from, as far as we can tell.
+#[derive(Clone, Debug, Default)]
+pub struct Device {
+    pub ip: Option<IpAddr>,
+    pub user_agent: String,
+}
+
+impl Device {
+    pub fn from_request<T>(request: &Request<T>, trusted_proxies: &[Cidr]) -> Self {
+        Self {
+            ip: PeerInfo::from_request(request, trusted_proxies).client_ip,
+            user_agent: request
+                .metadata()
+                .get("user-agent")
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .unwrap_or_default()
+                .to_owned(),
+        }
+    }
+
+    pub fn ip_string(&self) -> String {
+        self.ip.map(|ip| ip.to_string()).unwrap_or_default()
+    }
+
+    // Hashed, so the cache doesn't keep IP addresses around.
+    pub fn fingerprint(&self) -> String {
+        Sha1::digest(format!("{}|{}", self.ip_string(), self.user_agent).as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+// What sessions get bound to at sign in (AUTH_SESSION_BINDING), so their tokens are refused by
+// ValidateSession when presented by any other client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SessionBinding {
+    #[default]
+    Off,
+    // The client's TLS certificate: the connection's own, or as a trusted proxy terminating TLS
+    // passes its hash on in `x-client-cert-hash`.
+    ClientCert,
+    // A key the client keeps for good, sent in `x-device-key`.
+    DeviceKey,
+}
+
+impl FromStr for SessionBinding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "client-cert" => Ok(Self::ClientCert),
+            "device-key" => Ok(Self::DeviceKey),
+            _ => Err(format!("Error::UnknownSessionBinding: {s}")),
+        }
+    }
+}
+
+// What SignOut and ValidateSession answer for a token of no session (AUTH_UNKNOWN_SESSION_REPLY).
+// Saying so helps clients tell a typo from an expired session, but lets anyone probe which tokens
+// are live; answering as for any other token gives nothing away.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnknownSessionReply {
+    // SignOut succeeds, ValidateSession fails as it does for a suspended owner.
+    Success,
+    // Both answer SESSION_NOT_FOUND.
+    #[default]
+    NotFound,
+}
+
+impl FromStr for UnknownSessionReply {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "success" => Ok(Self::Success),
+            "not-found" => Ok(Self::NotFound),
+            _ => Err(format!("Error::UnknownSessionReply: {s}")),
+        }
+    }
+}
+
+// The client sending `request`, as `binding` tells them apart, hashed; None when it doesn't say
+// (or binding is off), and the session is left unbound. A service validating sessions for its
+// own clients passes their certificate hash or device key on the same way.
+pub fn client_fingerprint<T>(request: &Request<T>, binding: SessionBinding, trusted_proxies: &[Cidr]) -> Option<String> {
+    let hex = |digest: &[u8]| digest.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+    let header = |name| request.metadata().get(name).and_then(|value| value.to_str().ok()).filter(|value| !value.is_empty());
+
+    match binding {
+        SessionBinding::Off => None,
+        SessionBinding::ClientCert => match request.peer_certs().as_deref().and_then(|certs| certs.first()) {
+            Some(cert) => Some(hex(&Sha256::digest(cert.get_ref()))),
+            None => {
+                // Anyone else could write the header too.
+                let remote_ip = request.remote_addr().map(|addr| addr.ip())?;
+                trusted_proxies.iter().any(|cidr| cidr.contains(remote_ip)).then_some(())?;
+                header("x-client-cert-hash").map(str::to_ascii_lowercase)
+            }
+        },
+        SessionBinding::DeviceKey => header("x-device-key").map(|key| hex(&Sha256::digest(key.as_bytes()))),
+    }
+}
+
+// Sessions of each class live for their own TTL, and can be revoked as a group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SessionClass {
+    Standard,
+    // "Remember me"
+    LongLived,
+    // Not backed by a user account (yet); the uuid is made up for the guest.
+    Guest,
+    // Minted from another session for a service acting on the user's behalf (see
+    // `create_delegated_session`). Never extended, and can't be delegated any further.
+    Delegated,
+}
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub user_uuid: String,
+    pub class: SessionClass,
+    pub created_at: SystemTime,
+    pub expires_at: SystemTime,
+    pub scopes: Vec<String>,
+    // The session a `Delegated` one was minted from.
+    pub parent: Option<String>,
+    // The client the session is bound to (see `client_fingerprint`).
+    pub binding: Option<String>,
+    // The tenant it was created for, in multi-tenant mode (see `request_tenant`).
+    pub tenant: Option<String>,
+    // Where it was created from, when known. Like devices, only a hint: neither journaled nor
+    // replicated.
+    pub ip: Option<IpAddr>,
+    // When it was created or last validated on this instance; also not journaled.
+    pub last_used_at: SystemTime,
+    // The user's session generation when it was created (see `advance_session_generation`).
+    pub generation: u64,
+}
+
+// Which sessions `SessionsOps::list_sessions` returns a page of. The filters combine.
+#[derive(Clone, Debug, Default)]
+pub struct SessionQuery {
+    pub user_uuid: Option<String>,
+    pub created_before: Option<SystemTime>,
+    // Only sessions not used for longer than this.
+    pub idle_for: Option<Duration>,
+    // Only sessions created from these addresses.
+    pub ip_prefix: Option<Cidr>,
+    // Where the previous page left off, see `SessionPage::next_cursor`.
+    pub cursor: Option<String>,
+    pub limit: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SessionPage {
+    // With their tokens, so they can be revoked.
+    pub sessions: Vec<(String, Session)>,
+    // None on the last page. Opaque to callers.
+    pub next_cursor: Option<String>,
+}
+
+// The position of a session in `SessionsImpl::created_index`: its creation time, and the digest
+// of its token to tell apart sessions created at the same time. Cursors are `<nanos>.<digest>`,
+// so they don't give the tokens away.
+type SessionKey = (SystemTime, String);
+
+fn session_key(session_token: &str, session: &Session) -> SessionKey {
+    (session.created_at, token_digest(session_token))
+}
+
+fn cursor_of(key: &SessionKey) -> String {
+    format!("{}.{}", key.0.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos(), key.1)
+}
+
+fn session_cursor(cursor: &str) -> Result<SessionKey, StoreError> {
+    let invalid = || StoreError::InvalidCursor;
+
+    let (nanos, digest) = cursor.split_once('.').ok_or_else(invalid)?;
+    let nanos = nanos.parse().map_err(|_| invalid())?;
+
+    Ok((UNIX_EPOCH + Duration::from_nanos(nanos), digest.to_owned()))
+}
+
+// Hex SHA-256 of a session token: how revocations are told to other services, which only need to
+// recognise the tokens they already hold.
+pub fn token_digest(session_token: &str) -> String {
+    Sha256::digest(session_token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Revocations {
+    pub token_digests: Vec<String>,
+    // The `jti` of those that are JWTs, for services checking them by that claim.
+    pub jwt_ids: Vec<String>,
+    pub cursor: u64,
+    // Some revocations since the cursor asked for are no longer known.
+    pub reset: bool,
+}
+
+// Revocations kept for `revoked_since`; older ones are forgotten.
+const MAX_REVOCATIONS: usize = 10_000;
+
+// Evicted sessions kept for `take_evicted_sessions`, should nobody come for them.
+const MAX_EVICTED: usize = 1_000;
+
+// Which session goes when a full store needs room for a new one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    // The one created or validated longest ago.
+    #[default]
+    LeastRecentlyUsed,
+    // The one closest to expiring anyway.
+    SoonestExpiring,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Self::LeastRecentlyUsed),
+            "expiry" => Ok(Self::SoonestExpiring),
+            _ => Err(format!("Error::UnknownEvictionPolicy: {s}")),
+        }
+    }
+}
+
+// The sessions of a capped store in the order they would be evicted in, by rank: a use count
+// for `LeastRecentlyUsed`, the expiry (in microseconds) for `SoonestExpiring`.
+struct Eviction {
+    max_sessions: usize,
+    policy: EvictionPolicy,
+    ranks: HashMap<String, u128>,
+    order: BTreeSet<(u128, String)>,
+    uses: u128,
+    evicted: VecDeque<Session>,
+}
+
+impl Eviction {
+    fn rank(&mut self, session_token: &str, expires_at: SystemTime) {
+        let rank = match self.policy {
+            EvictionPolicy::LeastRecentlyUsed => {
+                self.uses += 1;
+                self.uses
+            }
+            EvictionPolicy::SoonestExpiring => expires_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros(),
+        };
+
+        self.forget(session_token);
+        self.ranks.insert(session_token.to_owned(), rank);
+        self.order.insert((rank, session_token.to_owned()));
+    }
+
+    fn forget(&mut self, session_token: &str) {
+        if let Some(rank) = self.ranks.remove(session_token) {
+            self.order.remove(&(rank, session_token.to_owned()));
+        }
+    }
+
+    fn next(&self) -> Option<String> {
+        self.order.first().map(|(_, session_token)| session_token.clone())
+    }
+}
+
+// The changes to the sessions of a `SessionsImpl`, see `UserEvent`. Magic links and devices are
+// left out: they are short-lived, or only a hint.
+// Fields added later need a default, so the entries journaled before them still read (see
+// `ENTRY_VERSION`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SessionEvent {
+    SessionCreated {
+        session_token: String,
+        user_uuid: String,
+        class: SessionClass,
+        created_at: SystemTime,
+        expires_at: SystemTime,
+        // Missing from journals written before sessions had scopes.
+        #[serde(default)]
+        scopes: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        parent: Option<String>,
+        // Missing from journals written before generations, which were all 0.
+        #[serde(default)]
+        generation: u64,
+    },
+    SessionDeleted { session_token: String },
+    // Touched, in sliding mode.
+    SessionExtended { session_token: String, expires_at: SystemTime },
+    SessionBound { session_token: String, binding: String },
+    SessionPinned { session_token: String, tenant: String },
+    // The user's sessions from before `generation` are void.
+    GenerationAdvanced { user_uuid: String, generation: u64 },
 }
 
-#[derive(Default)]
 pub struct SessionsImpl {
-    uuid_to_session: HashMap<String, String>,
+    sessions: HashMap<String, Session>,
+    // The tokens of each user's sessions, so a user's sessions are found without a scan. Kept in
+    // step with `sessions` by `insert_session` and `remove_session`.
+    uuid_to_tokens: HashMap<String, HashSet<String>>,
+    // The delegated sessions minted from each session, so they go with it.
+    parent_to_children: HashMap<String, HashSet<String>>,
+    // The tokens by `session_key`, for listing sessions a page at a time.
+    created_index: BTreeMap<SessionKey, String>,
+    magic_link_to_uuid: HashMap<String, (String, Instant)>,
+    webauthn_challenges: HashMap<Vec<u8>, (WebAuthnCeremony, Instant)>,
+    uuid_to_devices: HashMap<String, HashSet<String>>,
+    // The session generation of each user whose sessions were ever voided; 0 for the others.
+    generations: HashMap<String, u64>,
+    standard_ttl: Duration,
+    long_lived_ttl: Duration,
+    // Set in sliding mode, where the TTLs above are idle timeouts.
+    absolute_ttl: Option<Duration>,
+    token_generator: Box<dyn TokenGenerator + Send + Sync>,
+    // For magic links; session tokens come from the token generator.
+    ids: Ids,
+    // Set in event sourcing mode.
+    journal: Option<Box<dyn Journal<SessionEvent>>>,
+    // Set when the sessions are replicated.
+    replication: Option<broadcast::Sender<SessionEvent>>,
+    // The latest revocations, by sequence number, with the token digest and the JWT id if any, and
+    // the last number given out. Numbers start from the time the store was created, so a cursor
+    // from before a restart is seen as too old.
+    revocations: VecDeque<(u64, String, Option<String>)>,
+    revocation_seq: u64,
+    // How long past its expiry a session still holds, for the clocks of the replicas that made it
+    // and of this one being apart.
+    clock_skew_leeway: Duration,
+    // Set when the number of sessions is capped.
+    eviction: Option<Eviction>,
 }
 
-impl SessionsOps for SessionsImpl {
-    fn create_session(&mut self, user_uuid: &str) -> String {
-        let session: String = Uuid::new_v4().to_string(); 
+impl Default for SessionsImpl {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(60 * 60), Duration::from_secs(30 * 24 * 60 * 60))
+    }
+}
+
+impl SessionsImpl {
+    pub fn new(standard_ttl: Duration, long_lived_ttl: Duration) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            uuid_to_tokens: HashMap::new(),
+            parent_to_children: HashMap::new(),
+            created_index: BTreeMap::new(),
+            magic_link_to_uuid: HashMap::new(),
+            webauthn_challenges: HashMap::new(),
+            uuid_to_devices: HashMap::new(),
+            generations: HashMap::new(),
+            standard_ttl,
+            long_lived_ttl,
+            absolute_ttl: None,
+            token_generator: Box::new(UuidTokens::default()),
+            ids: Ids::default(),
+            journal: None,
+            replication: None,
+            revocations: VecDeque::new(),
+            revocation_seq: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64,
+            clock_skew_leeway: Duration::ZERO,
+            eviction: None,
+        }
+    }
+
+    pub fn with_clock_skew_leeway(mut self, leeway: Duration) -> Self {
+        self.clock_skew_leeway = leeway;
+        self
+    }
+
+    // Sessions that expire before this are gone: now, less the leeway.
+    fn expiry_cutoff(&self) -> SystemTime {
+        SystemTime::now().checked_sub(self.clock_skew_leeway).unwrap_or(UNIX_EPOCH)
+    }
+
+    fn generation_of(&self, user_uuid: &str) -> u64 {
+        self.generations.get(user_uuid).copied().unwrap_or_default()
+    }
+
+    // Neither expired nor voided by a newer generation of its user's sessions.
+    fn is_current(&self, session: &Session) -> bool {
+        session.expires_at > self.expiry_cutoff() && session.generation >= self.generation_of(&session.user_uuid)
+    }
+
+    // Moves the user on to `generation`, dropping their sessions from before it. Only applied here:
+    // the change that led to it is what gets journaled and replicated.
+    fn advance_generation(&mut self, user_uuid: &str, generation: u64) {
+        self.generations.insert(user_uuid.to_owned(), generation);
+
+        let session_tokens: Vec<String> = self
+            .uuid_to_tokens
+            .get(user_uuid)
+            .into_iter()
+            .flatten()
+            .filter(|session_token| self.sessions.get(*session_token).is_some_and(|session| session.generation < generation))
+            .cloned()
+            .collect();
+        for session_token in session_tokens {
+            self.apply(SessionEvent::SessionDeleted { session_token });
+        }
+    }
+
+    // At most `max_sessions` sessions: creating one more first evicts one, as chosen by `policy`,
+    // together with its delegated sessions. Evicted sessions are deleted like signed out ones, so
+    // they are revoked and replicated too; see `take_evicted_sessions` for auditing them.
+    pub fn with_max_sessions(mut self, max_sessions: usize, policy: EvictionPolicy) -> Self {
+        let mut eviction = Eviction {
+            max_sessions: max_sessions.max(1),
+            policy,
+            ranks: HashMap::new(),
+            order: BTreeSet::new(),
+            uses: 0,
+            evicted: VecDeque::new(),
+        };
 
-        println!("creating new session: {}", session);
-        // TODO: Insert session into `uuid_to_session`.
-        self.uuid_to_session.insert(user_uuid.to_string(), session.clone());
+        // Sessions already here, e.g. from the journal, count as used when they were created.
+        let mut sessions: Vec<(&String, &Session)> = self.sessions.iter().collect();
+        sessions.sort_by_key(|(_, session)| session.created_at);
+        for (session_token, session) in sessions {
+            eviction.rank(session_token, session.expires_at);
+        }
+
+        self.eviction = Some(eviction);
+        self
+    }
+
+    // Every touch pushes the expiry back by the session's TTL, but never past `absolute_ttl`
+    // after the session was created.
+    pub fn with_sliding_expiry(mut self, absolute_ttl: Duration) -> Self {
+        self.absolute_ttl = Some(absolute_ttl);
+        self
+    }
+
+    pub fn with_token_generator(mut self, token_generator: Box<dyn TokenGenerator + Send + Sync>) -> Self {
+        self.token_generator = token_generator;
+        self
+    }
+
+    pub fn with_id_generator(mut self, ids: Ids) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    // Event sourcing mode: the sessions are whatever the journal says, and every change goes
+    // through it first.
+    pub fn with_journal(mut self, journal: Box<dyn Journal<SessionEvent>>) -> Result<Self, StoreError> {
+        self.journal = Some(journal);
+        self.replay(None)?;
+        Ok(self)
+    }
+
+    // Every change is also sent to `replication`, for the other replicas (see `SessionReplicator`).
+    pub fn with_replication(mut self, replication: broadcast::Sender<SessionEvent>) -> Self {
+        self.replication = Some(replication);
+        self
+    }
+
+    // Unlike users, sessions still change when the journal fails: refusing to sign anyone in or
+    // out over it would be worse than losing a few sessions on a restart.
+    fn record(&mut self, event: SessionEvent) {
+        // Nobody listening is fine: the peers come and go.
+        if let Some(replication) = &self.replication {
+            let _ = replication.send(event.clone());
+        }
+        self.journal_and_apply(event);
+    }
+
+    fn journal_and_apply(&mut self, event: SessionEvent) {
+        if let Some(journal) = &self.journal {
+            if let Err(e) = journal.append(&event) {
+                warn!("failed to journal session change: {}", e);
+            }
+        }
+        self.apply(event);
+    }
+
+    fn apply(&mut self, event: SessionEvent) {
+        match event {
+            SessionEvent::SessionCreated { session_token, user_uuid, class, created_at, expires_at, scopes, parent, generation } => {
+                // Created on a replica that hadn't heard of the newer generation yet; one that
+                // has heard of a newer one than ours tells us about it.
+                match generation.cmp(&self.generation_of(&user_uuid)) {
+                    Ordering::Less => {
+                        debug!("dropped a session of {} from a voided generation", user_uuid);
+                        return;
+                    }
+                    Ordering::Greater => self.advance_generation(&user_uuid, generation),
+                    Ordering::Equal => {}
+                }
+                self.uuid_to_tokens.entry(user_uuid.clone()).or_default().insert(session_token.clone());
+                if let Some(parent) = &parent {
+                    self.parent_to_children.entry(parent.clone()).or_default().insert(session_token.clone());
+                }
+                if let Some(eviction) = &mut self.eviction {
+                    eviction.rank(&session_token, expires_at);
+                }
+                let session = Session {
+                    user_uuid,
+                    class,
+                    created_at,
+                    expires_at,
+                    scopes,
+                    parent,
+                    binding: None,
+                    tenant: None,
+                    ip: None,
+                    last_used_at: created_at,
+                    generation,
+                };
+                self.created_index.insert(session_key(&session_token, &session), session_token.clone());
+                self.sessions.insert(session_token, session);
+            }
+            SessionEvent::SessionDeleted { session_token } => {
+                let Some(session) = self.sessions.remove(&session_token) else { return };
+                self.created_index.remove(&session_key(&session_token, &session));
+                if let Some(eviction) = &mut self.eviction {
+                    eviction.forget(&session_token);
+                }
+
+                // Expired sessions going is no news to anyone.
+                if session.expires_at > self.expiry_cutoff() {
+                    self.revocation_seq += 1;
+                    self.revocations.push_back((self.revocation_seq, token_digest(&session_token), jwt_id(&session_token)));
+                    if self.revocations.len() > MAX_REVOCATIONS {
+                        self.revocations.pop_front();
+                    }
+                }
+
+                if let Some(tokens) = self.uuid_to_tokens.get_mut(&session.user_uuid) {
+                    tokens.remove(&session_token);
+                    if tokens.is_empty() {
+                        self.uuid_to_tokens.remove(&session.user_uuid);
+                    }
+                }
+                if let Some(children) = session.parent.and_then(|parent| self.parent_to_children.get_mut(&parent)) {
+                    children.remove(&session_token);
+                }
+                self.parent_to_children.retain(|_, children| !children.is_empty());
+            }
+            SessionEvent::SessionExtended { session_token, expires_at } => {
+                if let Some(session) = self.sessions.get_mut(&session_token) {
+                    session.expires_at = expires_at;
+                    if let Some(eviction) = self.eviction.as_mut().filter(|eviction| eviction.policy == EvictionPolicy::SoonestExpiring) {
+                        eviction.rank(&session_token, expires_at);
+                    }
+                }
+            }
+            SessionEvent::SessionBound { session_token, binding } => {
+                if let Some(session) = self.sessions.get_mut(&session_token) {
+                    session.binding = Some(binding);
+                }
+            }
+            SessionEvent::SessionPinned { session_token, tenant } => {
+                if let Some(session) = self.sessions.get_mut(&session_token) {
+                    session.tenant = Some(tenant);
+                }
+            }
+            SessionEvent::GenerationAdvanced { user_uuid, generation } => {
+                if generation > self.generation_of(&user_uuid) {
+                    self.advance_generation(&user_uuid, generation);
+                }
+            }
+        }
+    }
+
+    fn remove_session(&mut self, session_token: &str) -> Option<Session> {
+        let session = self.sessions.get(session_token)?.clone();
+
+        // Its delegated sessions first, each as a change of its own, so replicas drop them too.
+        let children: Vec<String> =
+            self.parent_to_children.get(session_token).into_iter().flatten().cloned().collect();
+        for child in children {
+            self.record(SessionEvent::SessionDeleted { session_token: child });
+        }
+
+        self.record(SessionEvent::SessionDeleted { session_token: session_token.to_owned() });
+        Some(session)
+    }
+
+    // Removes the sessions `predicate` holds for, returning how many.
+    fn remove_sessions_where(&mut self, predicate: impl Fn(&Session) -> bool) -> usize {
+        let session_tokens: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| predicate(session))
+            .map(|(session_token, _)| session_token.clone())
+            .collect();
+
+        session_tokens.iter().filter(|session_token| self.remove_session(session_token).is_some()).count()
+    }
+
+    // The same for the sessions of one user, going by the index.
+    fn remove_sessions_of_user_where(&mut self, user_uuid: &str, predicate: impl Fn(&Session) -> bool) -> usize {
+        let session_tokens: Vec<String> = self
+            .uuid_to_tokens
+            .get(user_uuid)
+            .into_iter()
+            .flatten()
+            .filter(|session_token| self.sessions.get(*session_token).is_some_and(&predicate))
+            .cloned()
+            .collect();
+
+        session_tokens.iter().filter(|session_token| self.remove_session(session_token).is_some()).count()
+    }
+
+    // Evicts sessions until there is room for one more, when capped.
+    fn make_room(&mut self) {
+        while let Some(eviction) = self.eviction.as_ref().filter(|eviction| self.sessions.len() >= eviction.max_sessions) {
+            let Some(session_token) = eviction.next() else { return };
+            let Some(session) = self.remove_session(&session_token) else { return };
+
+            debug!("evicted {:?} session of {}", session.class, session.user_uuid);
+            if let Some(eviction) = &mut self.eviction {
+                eviction.evicted.push_back(session);
+                if eviction.evicted.len() > MAX_EVICTED {
+                    eviction.evicted.pop_front();
+                }
+            }
+        }
+    }
+
+    fn ttl(&self, class: SessionClass) -> Duration {
+        match class {
+            SessionClass::Standard | SessionClass::Guest | SessionClass::Delegated => self.standard_ttl,
+            SessionClass::LongLived => self.long_lived_ttl,
+        }
+    }
+
+    fn insert_session(
+        &mut self,
+        user_uuid: &str,
+        class: SessionClass,
+        scopes: Vec<String>,
+        expires_at: SystemTime,
+        parent: Option<String>,
+        claims: &Claims,
+    ) -> String {
+        self.make_room();
+
+        let session: String = self.token_generator.generate(user_uuid, class, &scopes, expires_at, claims);
+
+        debug!("creating new {:?} session for {}", class, user_uuid);
+        self.record(SessionEvent::SessionCreated {
+            session_token: session.clone(),
+            user_uuid: user_uuid.to_string(),
+            class,
+            created_at: SystemTime::now(),
+            expires_at,
+            scopes,
+            parent,
+            generation: self.generation_of(user_uuid),
+        });
 
         session
     }
+}
 
-    fn delete_session(&mut self, user_uuid: &str) {
-        // TODO: Delete session from `uuid_to_session`.
+impl SessionsOps for SessionsImpl {
+    fn create_scoped_session(&mut self, user_uuid: &str, class: SessionClass, scopes: Vec<String>) -> String {
+        self.create_session_with_claims(user_uuid, class, scopes, &Claims::new())
+    }
+
+    fn create_session_with_claims(
+        &mut self,
+        user_uuid: &str,
+        class: SessionClass,
+        scopes: Vec<String>,
+        claims: &Claims,
+    ) -> String {
+        let now = SystemTime::now();
+        let expires_at = match self.absolute_ttl {
+            Some(absolute_ttl) => now + self.ttl(class).min(absolute_ttl),
+            None => now + self.ttl(class),
+        };
 
-        self.uuid_to_session.remove(user_uuid);
+        self.insert_session(user_uuid, class, scopes, expires_at, None, claims)
+    }
+
+    fn create_delegated_session(
+        &mut self,
+        parent_token: &str,
+        scopes: Vec<String>,
+        ttl: Duration,
+    ) -> Result<(String, Session), StoreError> {
+        let parent = self.get_session(parent_token).ok_or(StoreError::SessionNotFound)?;
+
+        // Guests have no account to act on behalf of, and delegates can't pass on what they were lent.
+        if matches!(parent.class, SessionClass::Guest | SessionClass::Delegated) {
+            return Err(StoreError::SessionNotDelegable);
+        }
+        if scopes.is_empty() || !scopes.iter().all(|scope| parent.scopes.contains(scope)) {
+            return Err(StoreError::ScopeNotHeld);
+        }
+
+        // Room is made first, as it may be the parent that goes; the child would outlive it then.
+        self.make_room();
+        let parent = self.get_session(parent_token).ok_or(StoreError::SessionNotFound)?;
+
+        let expires_at = (SystemTime::now() + ttl).min(parent.expires_at);
+        let session_token =
+            self.insert_session(&parent.user_uuid, SessionClass::Delegated, scopes, expires_at, Some(parent_token.to_owned()), &Claims::new());
+        // Good for the parent's client and tenant only, like the parent.
+        if let Some(binding) = parent.binding {
+            self.bind_session(&session_token, &binding);
+        }
+        if let Some(tenant) = parent.tenant {
+            self.pin_session(&session_token, &tenant);
+        }
+
+        let session = self.sessions.get(&session_token).cloned().ok_or(StoreError::SessionNotFound)?;
+        Ok((session_token, session))
+    }
+
+    fn delete_session(&mut self, session_token: &str) -> bool {
+        self.remove_session(session_token).is_some()
+    }
+
+    fn get_session(&self, session_token: &str) -> Option<Session> {
+        self.sessions.get(session_token).filter(|session| self.is_current(session)).cloned()
+    }
+
+    fn touch_session(&mut self, session_token: &str) -> Option<Session> {
+        let now = SystemTime::now();
+        let idle_ttl = self.ttl(self.sessions.get(session_token)?.class);
+        let absolute_ttl = self.absolute_ttl;
+
+        let session = self.sessions.get(session_token)?;
+        if !self.is_current(session) {
+            self.remove_session(session_token);
+            return None;
+        }
+
+        let expires_at = session.expires_at;
+        let class = session.class;
+        let created_at = session.created_at;
+        if let Some(session) = self.sessions.get_mut(session_token) {
+            session.last_used_at = now;
+        }
+        if let Some(eviction) = self.eviction.as_mut().filter(|eviction| eviction.policy == EvictionPolicy::LeastRecentlyUsed) {
+            eviction.rank(session_token, expires_at);
+        }
+
+        if let Some(absolute_ttl) = absolute_ttl.filter(|_| class != SessionClass::Delegated) {
+            let expires_at = (now + idle_ttl).min(created_at + absolute_ttl);
+            self.record(SessionEvent::SessionExtended { session_token: session_token.to_owned(), expires_at });
+        }
+
+        self.sessions.get(session_token).cloned()
+    }
+
+    fn delete_sessions_of_class(&mut self, class: SessionClass) -> usize {
+        self.remove_sessions_where(|session| session.class == class)
+    }
+
+    fn get_sessions_of_user(&self, user_uuid: &str) -> Vec<Session> {
+        let cutoff = self.expiry_cutoff();
+
+        self.uuid_to_tokens
+            .get(user_uuid)
+            .into_iter()
+            .flatten()
+            .filter_map(|session_token| self.sessions.get(session_token))
+            .filter(|session| session.expires_at > cutoff)
+            .cloned()
+            .collect()
+    }
+
+    fn delete_sessions_of_user(&mut self, user_uuid: &str) -> usize {
+        self.remove_sessions_of_user_where(user_uuid, |_| true)
+    }
+
+    fn delete_sessions(&mut self, session_tokens: &[String]) -> usize {
+        session_tokens
+            .iter()
+            .filter(|session_token| self.remove_session(session_token).is_some())
+            .count()
+    }
+
+    fn delete_sessions_of_user_created_before(&mut self, user_uuid: &str, cutoff: SystemTime) -> usize {
+        self.remove_sessions_of_user_where(user_uuid, |session| session.created_at < cutoff)
+    }
+
+    fn advance_session_generation(&mut self, user_uuid: &str) -> usize {
+        let sessions = self.uuid_to_tokens.get(user_uuid).map_or(0, HashSet::len);
+        let generation = self.generation_of(user_uuid) + 1;
+        self.record(SessionEvent::GenerationAdvanced { user_uuid: user_uuid.to_owned(), generation });
+
+        sessions - self.uuid_to_tokens.get(user_uuid).map_or(0, HashSet::len)
+    }
+
+    fn delete_expired_sessions(&mut self) -> usize {
+        let now = Instant::now();
+        self.magic_link_to_uuid.retain(|_, (_, expires_at)| *expires_at > now);
+        self.webauthn_challenges.retain(|_, (_, expires_at)| *expires_at > now);
+
+        let cutoff = self.expiry_cutoff();
+        self.remove_sessions_where(|session| session.expires_at <= cutoff)
+    }
+
+    fn revoked_since(&self, cursor: u64) -> Revocations {
+        let oldest = self.revocations.front().map_or(self.revocation_seq + 1, |(seq, _, _)| *seq);
+        let since = || self.revocations.iter().filter(|(seq, _, _)| *seq > cursor);
+        Revocations {
+            token_digests: since().map(|(_, token_digest, _)| token_digest.clone()).collect(),
+            jwt_ids: since().filter_map(|(_, _, jwt_id)| jwt_id.clone()).collect(),
+            cursor: self.revocation_seq,
+            reset: cursor > self.revocation_seq || cursor + 1 < oldest,
+        }
+    }
+
+    fn apply_replicated(&mut self, events: Vec<SessionEvent>) -> usize {
+        let applied = events.len();
+        for event in events {
+            self.journal_and_apply(event);
+        }
+        applied
+    }
+
+    fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    fn export_sessions(&self) -> Vec<SessionEvent> {
+        let cutoff = self.expiry_cutoff();
+        let mut sessions: Vec<(&String, &Session)> =
+            self.sessions.iter().filter(|(_, session)| session.expires_at > cutoff).collect();
+        sessions.sort_by_key(|(session_token, session)| (session.parent.is_some(), session.created_at, *session_token));
+
+        // The generations too, so the other store also refuses the sessions voided here should they
+        // turn up late, e.g. from a replica.
+        let mut generations: Vec<(&String, &u64)> = self.generations.iter().collect();
+        generations.sort();
+        let generations = generations.into_iter().map(|(user_uuid, generation)| SessionEvent::GenerationAdvanced {
+            user_uuid: user_uuid.clone(),
+            generation: *generation,
+        });
+
+        let sessions = sessions.into_iter().flat_map(|(session_token, session)| {
+            let created = SessionEvent::SessionCreated {
+                session_token: session_token.clone(),
+                user_uuid: session.user_uuid.clone(),
+                class: session.class,
+                created_at: session.created_at,
+                expires_at: session.expires_at,
+                scopes: session.scopes.clone(),
+                parent: session.parent.clone(),
+                generation: session.generation,
+            };
+            let bound = session.binding.clone().map(|binding| SessionEvent::SessionBound {
+                session_token: session_token.clone(),
+                binding,
+            });
+            let pinned = session.tenant.clone().map(|tenant| SessionEvent::SessionPinned {
+                session_token: session_token.clone(),
+                tenant,
+            });
+            [Some(created), bound, pinned].into_iter().flatten()
+        });
+
+        generations.chain(sessions).collect()
+    }
+
+    fn take_evicted_sessions(&mut self) -> Vec<Session> {
+        self.eviction.as_mut().map(|eviction| eviction.evicted.drain(..).collect()).unwrap_or_default()
+    }
+
+    fn check_store(&self) -> Result<(), StoreError> {
+        Ok(self.journal.as_ref().map_or(Ok(()), |journal| journal.check())?)
+    }
+
+    // Rebuilds the sessions from the journal, up to `until` when given; anything later is dropped
+    // from the journal too. Returns how many events were replayed.
+    fn replay(&mut self, until: Option<SystemTime>) -> Result<usize, StoreError> {
+        let journal = self.journal.as_ref().ok_or(StoreError::NoJournal)?;
+
+        if let Some(until) = until {
+            journal.truncate_after(until)?;
+        }
+        let entries = journal.entries()?;
+
+        self.sessions.clear();
+        self.uuid_to_tokens.clear();
+        self.parent_to_children.clear();
+        self.created_index.clear();
+        self.generations.clear();
+        let replayed = entries.len();
+        for entry in entries {
+            self.apply(entry.event);
+        }
+
+        Ok(replayed)
+    }
+
+    fn create_magic_link(&mut self, user_uuid: &str, ttl: Duration) -> String {
+        let now = Instant::now();
+
+        // Nobody will ever consume the expired ones, so drop them while we are here.
+        self.magic_link_to_uuid.retain(|_, (_, expires_at)| *expires_at > now);
+
+        let magic_link_token: String = self.ids.new_id().to_string();
+        self.magic_link_to_uuid
+            .insert(magic_link_token.clone(), (user_uuid.to_string(), now + ttl));
+
+        magic_link_token
+    }
+
+    // A magic link can be used only once: it is removed whether or not it is still valid.
+    fn consume_magic_link(&mut self, magic_link_token: &str) -> Option<String> {
+        self.magic_link_to_uuid
+            .remove(magic_link_token)
+            .filter(|(_, expires_at)| *expires_at > Instant::now())
+            .map(|(user_uuid, _)| user_uuid)
+    }
+
+    fn create_webauthn_challenge(&mut self, ceremony: WebAuthnCeremony, ttl: Duration) -> Vec<u8> {
+        let now = Instant::now();
+
+        if self.webauthn_challenges.len() >= MAX_WEBAUTHN_CHALLENGES {
+            self.webauthn_challenges.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        if self.webauthn_challenges.len() >= MAX_WEBAUTHN_CHALLENGES {
+            let first = self.webauthn_challenges.iter().min_by_key(|(_, (_, expires_at))| *expires_at).map(|(challenge, _)| challenge.clone());
+            if let Some(challenge) = first {
+                self.webauthn_challenges.remove(&challenge);
+            }
+        }
+
+        let mut challenge = vec![0u8; 32];
+        OsRng.fill_bytes(&mut challenge);
+        self.webauthn_challenges.insert(challenge.clone(), (ceremony, now + ttl));
+
+        challenge
+    }
+
+    fn consume_webauthn_challenge(&mut self, challenge: &[u8]) -> Option<WebAuthnCeremony> {
+        self.webauthn_challenges
+            .remove(challenge)
+            .filter(|(_, expires_at)| *expires_at > Instant::now())
+            .map(|(ceremony, _)| ceremony)
+    }
+
+    fn remember_device(&mut self, user_uuid: &str, fingerprint: &str) -> bool {
+        let devices = self.uuid_to_devices.entry(user_uuid.to_owned()).or_default();
+        let seen_others = !devices.is_empty();
+
+        devices.insert(fingerprint.to_owned()) && seen_others
+    }
+
+    fn bind_session(&mut self, session_token: &str, binding: &str) -> bool {
+        if !self.sessions.contains_key(session_token) {
+            return false;
+        }
+
+        self.record(SessionEvent::SessionBound { session_token: session_token.to_owned(), binding: binding.to_owned() });
+        true
+    }
+
+    fn pin_session(&mut self, session_token: &str, tenant: &str) -> bool {
+        if !self.sessions.contains_key(session_token) {
+            return false;
+        }
+
+        self.record(SessionEvent::SessionPinned { session_token: session_token.to_owned(), tenant: tenant.to_owned() });
+        true
+    }
+
+    fn set_session_ip(&mut self, session_token: &str, ip: IpAddr) -> bool {
+        let Some(session) = self.sessions.get_mut(session_token) else { return false };
+        session.ip = Some(ip);
+        true
+    }
+
+    fn list_sessions(&self, query: &SessionQuery) -> Result<SessionPage, StoreError> {
+        let from = query.cursor.as_deref().map(session_cursor).transpose()?;
+
+        // A user's sessions are few enough to sort; everybody's are walked in the index.
+        let keys: Box<dyn Iterator<Item = (SessionKey, &String)>> = match &query.user_uuid {
+            Some(user_uuid) => {
+                let mut keys: Vec<(SessionKey, &String)> = self
+                    .uuid_to_tokens
+                    .get(user_uuid)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|session_token| {
+                        self.sessions.get(session_token).map(|session| (session_key(session_token, session), session_token))
+                    })
+                    .filter(|(key, _)| from.as_ref().is_none_or(|from| key > from))
+                    .collect();
+                keys.sort();
+                Box::new(keys.into_iter())
+            }
+            None => {
+                let range = match &from {
+                    Some(from) => self.created_index.range((Excluded(from), Unbounded)),
+                    None => self.created_index.range::<SessionKey, _>(..),
+                };
+                Box::new(range.map(|(key, session_token)| (key.clone(), session_token)))
+            }
+        };
+
+        let now = SystemTime::now();
+        let cutoff = self.expiry_cutoff();
+        let mut sessions: Vec<(SessionKey, &String, &Session)> = keys
+            .take_while(|((created_at, _), _)| query.created_before.is_none_or(|before| *created_at < before))
+            .filter_map(|(key, session_token)| self.sessions.get(session_token).map(|session| (key, session_token, session)))
+            .filter(|(_, _, session)| session.expires_at > cutoff)
+            .filter(|(_, _, session)| {
+                query.idle_for.is_none_or(|idle_for| now.duration_since(session.last_used_at).unwrap_or_default() > idle_for)
+            })
+            .filter(|(_, _, session)| {
+                query.ip_prefix.as_ref().is_none_or(|prefix| session.ip.is_some_and(|ip| prefix.contains(ip)))
+            })
+            .take(query.limit + 1)
+            .collect();
+
+        let next_cursor = (sessions.len() > query.limit).then(|| {
+            sessions.truncate(query.limit);
+            sessions.last().map(|(key, _, _)| cursor_of(key))
+        });
+
+        Ok(SessionPage {
+            sessions: sessions
+                .into_iter()
+                .map(|(_, session_token, session)| (session_token.clone(), session.clone()))
+                .collect(),
+            next_cursor: next_cursor.flatten(),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::journal::tests::MemoryJournal;
+    use crate::tokens::{JwtTokens, RandomTokens};
 
     #[test]
     fn should_create_session() {
         let mut session_service = SessionsImpl::default();
-        assert_eq!(session_service.uuid_to_session.len(), 0);
-        let session = session_service.create_session("123456");
-        assert_eq!(session_service.uuid_to_session.len(), 1);
-        assert_eq!(session_service.uuid_to_session.get("123456").unwrap(), &session);
+        assert_eq!(session_service.sessions.len(), 0);
+        let session = session_service.create_session("123456", SessionClass::Standard);
+        assert_eq!(session_service.sessions.len(), 1);
+        assert_eq!(session_service.sessions.get(&session).unwrap().user_uuid, "123456");
+    }
+
+    #[test]
+    fn should_create_session_under_generated_token() {
+        let mut session_service = SessionsImpl::default().with_token_generator(Box::new(RandomTokens));
+        let session = session_service.create_session("123456", SessionClass::Standard);
+        assert_eq!(session.len(), 64);
+        assert_eq!(session_service.get_session(&session).unwrap().user_uuid, "123456");
     }
 
     #[test]
     fn should_delete_session() {
         let mut session_service = SessionsImpl::default();
-        session_service.create_session("123456");
-        session_service.delete_session("123456");
-        assert_eq!(session_service.uuid_to_session.len(), 0);
+        let session = session_service.create_session("123456", SessionClass::Standard);
+        session_service.delete_session(&session);
+        assert_eq!(session_service.sessions.len(), 0);
+    }
+
+    #[test]
+    fn should_retrieve_user_uuid() {
+        let mut session_service = SessionsImpl::default();
+        let session = session_service.create_session("123456", SessionClass::Standard);
+        assert_eq!(session_service.get_session(&session).unwrap().user_uuid, "123456");
+        assert!(session_service.get_session("unknown").is_none());
+    }
+
+    #[test]
+    fn should_consume_magic_link_once() {
+        let mut session_service = SessionsImpl::default();
+        let magic_link = session_service.create_magic_link("123456", Duration::from_secs(60));
+        assert_eq!(session_service.consume_magic_link(&magic_link), Some("123456".to_owned()));
+        assert_eq!(session_service.consume_magic_link(&magic_link), None);
+    }
+
+    #[test]
+    fn should_consume_webauthn_challenge_once() {
+        let mut session_service = SessionsImpl::default();
+        let registration = WebAuthnCeremony::Registration("123456".to_owned());
+        let challenge = session_service.create_webauthn_challenge(registration.clone(), Duration::from_secs(60));
+        let expired = session_service.create_webauthn_challenge(WebAuthnCeremony::Assertion, Duration::ZERO);
+
+        assert_eq!(challenge.len(), 32);
+        assert_eq!(session_service.consume_webauthn_challenge(&challenge), Some(registration));
+        assert_eq!(session_service.consume_webauthn_challenge(&challenge), None);
+        assert_eq!(session_service.consume_webauthn_challenge(&expired), None);
+    }
+
+    #[test]
+    fn should_not_consume_expired_magic_link() {
+        let mut session_service = SessionsImpl::default();
+        let magic_link = session_service.create_magic_link("123456", Duration::ZERO);
+        assert_eq!(session_service.consume_magic_link(&magic_link), None);
+    }
+
+    #[test]
+    fn should_not_retrieve_expired_session() {
+        let mut session_service = SessionsImpl::new(Duration::ZERO, Duration::from_secs(60));
+        let standard = session_service.create_session("123456", SessionClass::Standard);
+        let long_lived = session_service.create_session("123456", SessionClass::LongLived);
+        assert!(session_service.get_session(&standard).is_none());
+        assert!(session_service.get_session(&long_lived).is_some());
+    }
+
+    #[test]
+    fn should_keep_sessions_past_their_expiry_for_the_clock_skew() {
+        let mut session_service =
+            SessionsImpl::new(Duration::ZERO, Duration::ZERO).with_clock_skew_leeway(Duration::from_secs(60));
+        let session_token = session_service.create_session("123456", SessionClass::Standard);
+
+        assert!(session_service.get_session(&session_token).is_some());
+        assert!(session_service.touch_session(&session_token).is_some());
+        assert_eq!(session_service.get_sessions_of_user("123456").len(), 1);
+        assert_eq!(session_service.delete_expired_sessions(), 0);
+
+        let mut session_service = session_service.with_clock_skew_leeway(Duration::ZERO);
+        assert!(session_service.get_session(&session_token).is_none());
+        assert_eq!(session_service.delete_expired_sessions(), 1);
+    }
+
+    #[test]
+    fn should_delete_sessions_of_class() {
+        let mut session_service = SessionsImpl::default();
+        let standard = session_service.create_session("123456", SessionClass::Standard);
+        session_service.create_session("123456", SessionClass::LongLived);
+        session_service.create_session("654321", SessionClass::LongLived);
+        assert_eq!(session_service.delete_sessions_of_class(SessionClass::LongLived), 2);
+        assert!(session_service.get_session(&standard).is_some());
+    }
+
+    #[test]
+    fn should_delete_sessions_in_batch() {
+        let mut session_service = SessionsImpl::default();
+        let first = session_service.create_session("123456", SessionClass::Standard);
+        let second = session_service.create_session("123456", SessionClass::LongLived);
+        let other = session_service.create_session("654321", SessionClass::Standard);
+
+        assert_eq!(session_service.delete_sessions(&[first, "unknown".to_owned(), other.clone()]), 2);
+        assert!(session_service.get_session(&second).is_some());
+
+        let cutoff = SystemTime::now();
+        let newer = session_service.create_session("123456", SessionClass::Standard);
+        assert_eq!(session_service.delete_sessions_of_user_created_before("123456", cutoff), 1);
+        assert!(session_service.get_session(&second).is_none());
+        assert!(session_service.get_session(&newer).is_some());
+    }
+
+    #[test]
+    fn should_keep_user_index_in_step() {
+        let mut session_service = SessionsImpl::new(Duration::ZERO, Duration::from_secs(60));
+        let expired = session_service.create_session("123456", SessionClass::Standard);
+        session_service.create_session("123456", SessionClass::LongLived);
+        session_service.create_session("654321", SessionClass::LongLived);
+
+        assert_eq!(session_service.get_sessions_of_user("123456").len(), 1);
+        assert_eq!(session_service.uuid_to_tokens["123456"].len(), 2);
+
+        assert!(session_service.touch_session(&expired).is_none());
+        assert_eq!(session_service.uuid_to_tokens["123456"].len(), 1);
+
+        assert_eq!(session_service.delete_sessions_of_class(SessionClass::LongLived), 2);
+        assert!(session_service.uuid_to_tokens.is_empty());
+        assert!(session_service.get_sessions_of_user("654321").is_empty());
+    }
+
+    #[test]
+    fn delegated_sessions_should_be_narrower_and_go_with_their_parent() {
+        let mut session_service = SessionsImpl::default();
+        let scopes = vec!["profile:read".to_owned(), "orders:write".to_owned()];
+        let parent = session_service.create_scoped_session("123456", SessionClass::Standard, scopes);
+
+        let (child, session) =
+            session_service.create_delegated_session(&parent, vec!["profile:read".to_owned()], Duration::from_secs(60)).unwrap();
+        assert_eq!(session.user_uuid, "123456");
+        assert_eq!(session.class, SessionClass::Delegated);
+        assert_eq!(session.parent.as_deref(), Some(parent.as_str()));
+        assert!(session.expires_at <= SystemTime::now() + Duration::from_secs(60));
+
+        let delegate = |session_service: &mut SessionsImpl, parent: &str, scope: &str| {
+            session_service.create_delegated_session(parent, vec![scope.to_owned()], Duration::from_secs(60))
+        };
+        assert_eq!(delegate(&mut session_service, &parent, "admin").unwrap_err(), StoreError::ScopeNotHeld);
+        assert_eq!(delegate(&mut session_service, &child, "profile:read").unwrap_err(), StoreError::SessionNotDelegable);
+        assert!(session_service.create_delegated_session(&parent, vec![], Duration::from_secs(60)).is_err());
+
+        session_service.bind_session(&parent, "laptop");
+        let (_, bound) = delegate(&mut session_service, &parent, "orders:write").unwrap();
+        assert_eq!(bound.binding.as_deref(), Some("laptop"));
+
+        session_service.delete_session(&parent);
+        assert!(session_service.get_session(&child).is_none());
+        assert!(session_service.uuid_to_tokens.is_empty());
+        assert!(session_service.parent_to_children.is_empty());
+
+        let guest = session_service.create_scoped_session("654321", SessionClass::Guest, vec!["guest".to_owned()]);
+        assert_eq!(delegate(&mut session_service, &guest, "guest").unwrap_err(), StoreError::SessionNotDelegable);
+    }
+
+    #[test]
+    fn should_delete_expired_sessions() {
+        let mut session_service = SessionsImpl::new(Duration::ZERO, Duration::from_secs(60));
+        session_service.create_session("123456", SessionClass::Standard);
+        let long_lived = session_service.create_session("123456", SessionClass::LongLived);
+        session_service.create_magic_link("123456", Duration::ZERO);
+
+        assert_eq!(session_service.delete_expired_sessions(), 1);
+        assert!(session_service.get_session(&long_lived).is_some());
+        assert!(session_service.magic_link_to_uuid.is_empty());
+        assert_eq!(session_service.uuid_to_tokens["123456"].len(), 1);
+    }
+
+    #[test]
+    fn should_list_revocations_since_cursor() {
+        let mut session_service = SessionsImpl::new(Duration::ZERO, Duration::from_secs(60));
+        let start = session_service.revoked_since(0).cursor;
+        assert!(session_service.revoked_since(start).token_digests.is_empty());
+
+        let first = session_service.create_session("123456", SessionClass::LongLived);
+        session_service.delete_session(&first);
+        // Expired, so not a revocation.
+        session_service.create_session("123456", SessionClass::Standard);
+        session_service.delete_expired_sessions();
+
+        let revocations = session_service.revoked_since(start);
+        assert_eq!(revocations.token_digests, vec![token_digest(&first)]);
+        assert!(!revocations.reset);
+
+        let second = session_service.create_session("123456", SessionClass::LongLived);
+        session_service.delete_session(&second);
+        let since_first = session_service.revoked_since(revocations.cursor);
+        assert_eq!(since_first.token_digests, vec![token_digest(&second)]);
+
+        // From another store, or before a restart.
+        assert!(session_service.revoked_since(since_first.cursor + 1).reset);
+        assert!(session_service.revoked_since(1).reset);
+    }
+
+    #[test]
+    fn should_list_jwt_ids_of_revoked_jwts() {
+        let mut session_service = SessionsImpl::default();
+        let uuid_token = session_service.create_session("123456", SessionClass::Standard);
+        session_service.delete_session(&uuid_token);
+
+        let mut session_service = session_service.with_token_generator(Box::new(JwtTokens::new("auth".to_owned(), b"secret")));
+        let jwt = session_service.create_session("123456", SessionClass::Standard);
+        session_service.delete_session(&jwt);
+
+        let revocations = session_service.revoked_since(0);
+        assert_eq!(revocations.token_digests, vec![token_digest(&uuid_token), token_digest(&jwt)]);
+        assert_eq!(revocations.jwt_ids, vec![jwt_id(&jwt).unwrap()]);
+        assert_eq!(jwt_id(&uuid_token), None);
+    }
+
+    #[test]
+    fn should_rebuild_sessions_from_journal() {
+        let journal = MemoryJournal::default();
+        let mut session_service = SessionsImpl::new(Duration::from_secs(60), Duration::from_secs(60))
+            .with_sliding_expiry(Duration::from_secs(90))
+            .with_journal(Box::new(journal.clone()))
+            .unwrap();
+
+        let signed_out = session_service.create_session("123456", SessionClass::Standard);
+        let kept = session_service.create_session("123456", SessionClass::LongLived);
+        session_service.delete_session(&signed_out);
+        let touched = session_service.touch_session(&kept).unwrap();
+
+        let rebuilt = SessionsImpl::default().with_journal(Box::new(journal)).unwrap();
+        assert!(rebuilt.get_session(&signed_out).is_none());
+        assert_eq!(rebuilt.get_session(&kept).unwrap().expires_at, touched.expires_at);
+        assert_eq!(rebuilt.get_sessions_of_user("123456").len(), 1);
+    }
+
+    #[test]
+    fn should_void_sessions_of_older_generations_wherever_they_turn_up() {
+        let (replication, mut replicated) = broadcast::channel(16);
+        let journal = MemoryJournal::default();
+        let mut session_service =
+            SessionsImpl::default().with_journal(Box::new(journal.clone())).unwrap().with_replication(replication);
+        let start = session_service.revoked_since(0).cursor;
+
+        let stolen = session_service.create_session("123456", SessionClass::LongLived);
+        let other_user = session_service.create_session("654321", SessionClass::Standard);
+        assert_eq!(session_service.advance_session_generation("123456"), 1);
+        let fresh = session_service.create_session("123456", SessionClass::Standard);
+
+        assert!(session_service.get_session(&stolen).is_none());
+        assert!(session_service.get_session(&other_user).is_some());
+        assert_eq!(session_service.get_session(&fresh).unwrap().generation, 1);
+        assert_eq!(session_service.revoked_since(start).token_digests, vec![token_digest(&stolen)]);
+
+        let rebuilt = SessionsImpl::default().with_journal(Box::new(journal)).unwrap();
+        assert!(rebuilt.get_session(&stolen).is_none());
+        assert!(rebuilt.get_session(&fresh).is_some());
+
+        // A replica hearing of the new generation before the stolen session's creation.
+        let mut events: Vec<SessionEvent> = std::iter::from_fn(|| replicated.try_recv().ok()).collect();
+        events.swap(0, 2);
+        let mut replica = SessionsImpl::default();
+        replica.apply_replicated(events);
+        assert!(replica.get_session(&stolen).is_none());
+        assert!(replica.get_session(&fresh).is_some());
+
+        // And one that missed it, until a session of the new generation shows up.
+        let mut replica = SessionsImpl::default();
+        let created = |session_token: &str, generation| SessionEvent::SessionCreated {
+            session_token: session_token.to_owned(),
+            user_uuid: "123456".to_owned(),
+            class: SessionClass::Standard,
+            created_at: SystemTime::now(),
+            expires_at: SystemTime::now() + Duration::from_secs(60),
+            scopes: Vec::new(),
+            parent: None,
+            generation,
+        };
+        replica.apply_replicated(vec![created(&stolen, 0), created(&fresh, 1)]);
+        assert!(replica.get_session(&stolen).is_none());
+        assert_eq!(replica.export_sessions()[0], SessionEvent::GenerationAdvanced { user_uuid: "123456".to_owned(), generation: 1 });
+    }
+
+    #[test]
+    fn touch_should_slide_expiry_up_to_absolute_ttl() {
+        let mut session_service = SessionsImpl::new(Duration::from_secs(60), Duration::from_secs(60))
+            .with_sliding_expiry(Duration::from_secs(90));
+        let session = session_service.create_session("123456", SessionClass::Standard);
+
+        // Pretend the session has been idle for 45 of its 60 seconds.
+        let idle = session_service.sessions.get_mut(&session).unwrap();
+        idle.created_at -= Duration::from_secs(45);
+        idle.expires_at -= Duration::from_secs(45);
+        let idle = idle.clone();
+
+        // Another 60 seconds would overshoot the 90 seconds absolute maximum.
+        let touched = session_service.touch_session(&session).unwrap();
+        assert!(touched.expires_at > idle.expires_at);
+        assert_eq!(touched.expires_at, touched.created_at + Duration::from_secs(90));
+    }
+
+    #[test]
+    fn touch_should_not_revive_expired_session() {
+        let mut session_service = SessionsImpl::new(Duration::ZERO, Duration::ZERO)
+            .with_sliding_expiry(Duration::from_secs(90));
+        let session = session_service.create_session("123456", SessionClass::Standard);
+
+        assert!(session_service.touch_session(&session).is_none());
+        assert!(session_service.sessions.is_empty());
+    }
+
+    #[test]
+    fn should_evict_least_recently_used_session_when_full() {
+        let mut session_service = SessionsImpl::default().with_max_sessions(2, EvictionPolicy::LeastRecentlyUsed);
+        let first = session_service.create_session("123456", SessionClass::Standard);
+        let second = session_service.create_session("654321", SessionClass::Standard);
+        assert!(session_service.touch_session(&first).is_some());
+
+        let third = session_service.create_session("123456", SessionClass::Standard);
+
+        assert_eq!(session_service.sessions.len(), 2);
+        assert!(session_service.get_session(&first).is_some());
+        assert!(session_service.get_session(&second).is_none());
+        assert!(session_service.get_session(&third).is_some());
+        // Gone like a signed out session, so the other services hear of it.
+        assert_eq!(session_service.revoked_since(0).token_digests, vec![token_digest(&second)]);
+
+        let evicted = session_service.take_evicted_sessions();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].user_uuid, "654321");
+        assert!(session_service.take_evicted_sessions().is_empty());
+    }
+
+    #[test]
+    fn should_evict_soonest_expiring_session_when_full() {
+        let mut session_service = SessionsImpl::default().with_max_sessions(2, EvictionPolicy::SoonestExpiring);
+        let long_lived = session_service.create_session("123456", SessionClass::LongLived);
+        let standard = session_service.create_session("654321", SessionClass::Standard);
+        assert!(session_service.touch_session(&standard).is_some());
+
+        session_service.create_session("654321", SessionClass::LongLived);
+
+        assert!(session_service.get_session(&long_lived).is_some());
+        assert!(session_service.get_session(&standard).is_none());
+        assert_eq!(session_service.take_evicted_sessions()[0].class, SessionClass::Standard);
+    }
+
+    #[test]
+    fn should_not_delegate_from_a_session_evicted_to_make_room() {
+        let mut session_service = SessionsImpl::default().with_max_sessions(2, EvictionPolicy::LeastRecentlyUsed);
+        let parent = session_service.create_scoped_session("123456", SessionClass::Standard, vec!["profile:read".to_owned()]);
+        let other = session_service.create_session("654321", SessionClass::Standard);
+        assert!(session_service.touch_session(&other).is_some());
+
+        let result = session_service.create_delegated_session(&parent, vec!["profile:read".to_owned()], Duration::from_secs(60));
+
+        assert_eq!(result.unwrap_err(), StoreError::SessionNotFound);
+        assert!(session_service.get_session(&parent).is_none());
+        assert!(session_service.sessions.values().all(|session| session.class != SessionClass::Delegated));
+        assert!(session_service.parent_to_children.is_empty());
+    }
+
+    #[test]
+    fn should_report_new_device_after_first_one() {
+        let mut session_service = SessionsImpl::default();
+
+        assert!(!session_service.remember_device("123456", "laptop"));
+        assert!(!session_service.remember_device("123456", "laptop"));
+        assert!(session_service.remember_device("123456", "phone"));
+        assert!(!session_service.remember_device("654321", "phone"));
+    }
+
+    #[test]
+    fn should_keep_session_binding_through_journal_and_export() {
+        let journal = MemoryJournal::default();
+        let mut session_service = SessionsImpl::default().with_journal(Box::new(journal.clone())).unwrap();
+        let session = session_service.create_session("123456", SessionClass::Standard);
+
+        assert!(session_service.bind_session(&session, "fingerprint"));
+        assert!(!session_service.bind_session("unknown", "fingerprint"));
+
+        let rebuilt = SessionsImpl::default().with_journal(Box::new(journal)).unwrap();
+        assert_eq!(rebuilt.get_session(&session).unwrap().binding.as_deref(), Some("fingerprint"));
+
+        let mut migrated = SessionsImpl::default();
+        migrated.apply_replicated(rebuilt.export_sessions());
+        assert_eq!(migrated.get_session(&session).unwrap().binding.as_deref(), Some("fingerprint"));
+    }
+
+    #[test]
+    fn should_keep_tenant_of_sessions_and_their_delegates() {
+        let mut session_service = SessionsImpl::default();
+        let session = session_service.create_scoped_session("123456", SessionClass::Standard, vec!["profile:read".to_owned()]);
+        assert!(session_service.pin_session(&session, "acme"));
+
+        let (delegated, _) = session_service
+            .create_delegated_session(&session, vec!["profile:read".to_owned()], Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(session_service.get_session(&delegated).unwrap().tenant.as_deref(), Some("acme"));
+
+        let mut migrated = SessionsImpl::default();
+        migrated.apply_replicated(session_service.export_sessions());
+        assert_eq!(migrated.get_session(&session).unwrap().tenant.as_deref(), Some("acme"));
+        assert_eq!(migrated.get_session(&delegated).unwrap().tenant.as_deref(), Some("acme"));
+    }
+
+    #[test]
+    fn should_list_sessions_a_page_at_a_time() {
+        let mut session_service = SessionsImpl::default();
+        let tokens = ["alice", "bob", "alice"].map(|user_uuid| session_service.create_session(user_uuid, SessionClass::Standard));
+        assert!(session_service.set_session_ip(&tokens[0], "203.0.113.7".parse().unwrap()));
+        assert!(session_service.set_session_ip(&tokens[1], "198.51.100.1".parse().unwrap()));
+        assert!(!session_service.set_session_ip("unknown", "198.51.100.1".parse().unwrap()));
+
+        let listed = |session_service: &SessionsImpl, query: SessionQuery| {
+            let page = session_service.list_sessions(&SessionQuery { limit: 10, ..query }).unwrap();
+            page.sessions.into_iter().map(|(session_token, _)| session_token).collect::<BTreeSet<_>>()
+        };
+
+        let first = session_service.list_sessions(&SessionQuery { limit: 2, ..SessionQuery::default() }).unwrap();
+        assert_eq!(first.sessions.len(), 2);
+        assert!(first.sessions[0].1.created_at <= first.sessions[1].1.created_at);
+        let second = session_service
+            .list_sessions(&SessionQuery { limit: 2, cursor: first.next_cursor.clone(), ..SessionQuery::default() })
+            .unwrap();
+        assert_eq!(second.sessions.len(), 1);
+        assert!(second.next_cursor.is_none());
+        let seen: BTreeSet<String> = first.sessions.into_iter().chain(second.sessions).map(|(session_token, _)| session_token).collect();
+        assert_eq!(seen, BTreeSet::from(tokens.clone()));
+
+        let alice = listed(&session_service, SessionQuery { user_uuid: Some("alice".to_owned()), ..SessionQuery::default() });
+        assert_eq!(alice, BTreeSet::from([tokens[0].clone(), tokens[2].clone()]));
+        let from_network = listed(&session_service, SessionQuery { ip_prefix: Some("203.0.113.0/24".parse().unwrap()), ..SessionQuery::default() });
+        assert_eq!(from_network, BTreeSet::from([tokens[0].clone()]));
+        assert!(listed(&session_service, SessionQuery { created_before: Some(UNIX_EPOCH), ..SessionQuery::default() }).is_empty());
+
+        let idle = SessionQuery { idle_for: Some(Duration::from_secs(60)), ..SessionQuery::default() };
+        assert!(listed(&session_service, idle.clone()).is_empty());
+        session_service.sessions.get_mut(&tokens[1]).unwrap().last_used_at -= Duration::from_secs(120);
+        assert_eq!(listed(&session_service, idle.clone()), BTreeSet::from([tokens[1].clone()]));
+        session_service.touch_session(&tokens[1]);
+        assert!(listed(&session_service, idle).is_empty());
+
+        session_service.delete_session(&tokens[0]);
+        assert_eq!(listed(&session_service, SessionQuery::default()), BTreeSet::from([tokens[1].clone(), tokens[2].clone()]));
+
+        let garbage = SessionQuery { cursor: Some("garbage".to_owned()), ..SessionQuery::default() };
+        assert_eq!(session_service.list_sessions(&garbage).unwrap_err(), StoreError::InvalidCursor);
+    }
+
+    #[test]
+    fn should_fingerprint_clients_as_binding_says() {
+        let request = |headers: &[(&'static str, &str)]| {
+            let mut request = Request::new(());
+            for (name, value) in headers {
+                request.metadata_mut().insert(*name, value.parse().unwrap());
+            }
+            request
+        };
+
+        let device_key = request(&[("x-device-key", "key")]);
+        assert_eq!(client_fingerprint(&device_key, SessionBinding::Off, &[]), None);
+        assert_eq!(client_fingerprint(&device_key, SessionBinding::DeviceKey, &[]).unwrap().len(), 64);
+        assert_ne!(
+            client_fingerprint(&device_key, SessionBinding::DeviceKey, &[]),
+            client_fingerprint(&request(&[("x-device-key", "other")]), SessionBinding::DeviceKey, &[])
+        );
+        assert_eq!(client_fingerprint(&request(&[]), SessionBinding::DeviceKey, &[]), None);
+
+        // Not over TLS, and not from a proxy we trust.
+        assert_eq!(client_fingerprint(&request(&[("x-client-cert-hash", "ab")]), SessionBinding::ClientCert, &[]), None);
     }
 }
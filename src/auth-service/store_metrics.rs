@@ -0,0 +1,280 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime},
+};
+
+use tracing::warn;
+
+use crate::{
+    claims::Claims,
+    error::StoreError,
+    ldap::Directory,
+    server_timing::add_store_time,
+    sessions::{Revocations, Session, SessionClass, SessionEvent, SessionPage, SessionQuery, SessionsOps},
+    users::{AccountStatus, UserEvent, UserPage, UserQuery, UsersOps},
+    webauthn::{WebAuthnCeremony, WebAuthnCredential},
+};
+
+// Upper bounds of the duration buckets; slower calls only count towards the last one.
+pub const BUCKETS: [Duration; 9] = [
+    Duration::from_micros(100),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+];
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OperationStats {
+    pub calls: u64,
+    // Calls that returned an error.
+    pub errors: u64,
+    pub total: Duration,
+    // Not cumulative: the last one is for what no bound holds.
+    pub buckets: [u64; BUCKETS.len() + 1],
+}
+
+impl OperationStats {
+    pub fn record(&mut self, duration: Duration, failed: bool) {
+        self.calls += 1;
+        self.errors += u64::from(failed);
+        self.total += duration;
+        self.buckets[BUCKETS.iter().position(|bound| duration <= *bound).unwrap_or(BUCKETS.len())] += 1;
+    }
+}
+
+// (store, backend, operation), e.g. ("users", "memory", "get_user_uuid").
+pub type OperationKey = (&'static str, &'static str, &'static str);
+
+// How long every call to the stores took, and how many failed, per store, backend and operation;
+// calls slower than `slow_threshold` are logged. Tells a store that got slow from a handler that
+// did. Only the time spent in the store counts, not the wait for its lock.
+#[derive(Clone)]
+pub struct StoreMetrics {
+    operations: Arc<Mutex<BTreeMap<OperationKey, OperationStats>>>,
+    slow_threshold: Duration,
+}
+
+impl StoreMetrics {
+    // Duration::ZERO logs nothing.
+    pub fn new(slow_threshold: Duration) -> Self {
+        Self { operations: Arc::default(), slow_threshold }
+    }
+
+    pub fn record(&self, (store, backend, operation): OperationKey, duration: Duration, failed: bool) {
+        add_store_time(duration);
+        if !self.slow_threshold.is_zero() && duration > self.slow_threshold {
+            warn!("slow {} store operation {} ({}): {:?}", store, operation, backend, duration);
+        }
+
+        let mut operations = self.operations.lock().unwrap_or_else(PoisonError::into_inner);
+        operations.entry((store, backend, operation)).or_default().record(duration, failed);
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<OperationKey, OperationStats> {
+        self.operations.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+impl Default for StoreMetrics {
+    fn default() -> Self {
+        Self::new(Duration::ZERO)
+    }
+}
+
+// What a store call returned, as far as the error counters go.
+trait StoreResult {
+    fn failed(&self) -> bool {
+        false
+    }
+}
+
+impl<T> StoreResult for Result<T, StoreError> {
+    fn failed(&self) -> bool {
+        self.is_err()
+    }
+}
+
+impl<T> StoreResult for Option<T> {}
+impl<T> StoreResult for Vec<T> {}
+impl StoreResult for () {}
+impl StoreResult for bool {}
+impl StoreResult for usize {}
+impl StoreResult for String {}
+impl StoreResult for &'static str {}
+impl StoreResult for Revocations {}
+
+// A store of `$ops` that times every call to the one it wraps.
+macro_rules! instrumented {
+    (
+        $name:ident: $ops:ident, $store:literal;
+        mut { $(fn $mut_fn:ident($($mut_arg:ident: $mut_ty:ty),*) -> $mut_ret:ty;)* }
+        ref { $(fn $ref_fn:ident($($ref_arg:ident: $ref_ty:ty),*) -> $ref_ret:ty;)* }
+    ) => {
+        pub struct $name<S> {
+            inner: S,
+            backend: &'static str,
+            metrics: StoreMetrics,
+        }
+
+        impl<S: $ops> $name<S> {
+            // `backend` tells the stores behind the same trait apart, e.g. "memory" or "ldap".
+            pub fn new(inner: S, backend: &'static str, metrics: StoreMetrics) -> Self {
+                Self { inner, backend, metrics }
+            }
+        }
+
+        impl<S: $ops> $ops for $name<S> {
+            $(
+                fn $mut_fn(&mut self, $($mut_arg: $mut_ty),*) -> $mut_ret {
+                    let started_at = Instant::now();
+                    let result = self.inner.$mut_fn($($mut_arg),*);
+                    self.metrics.record(($store, self.backend, stringify!($mut_fn)), started_at.elapsed(), result.failed());
+                    result
+                }
+            )*
+            $(
+                fn $ref_fn(&self, $($ref_arg: $ref_ty),*) -> $ref_ret {
+                    let started_at = Instant::now();
+                    let result = self.inner.$ref_fn($($ref_arg),*);
+                    self.metrics.record(($store, self.backend, stringify!($ref_fn)), started_at.elapsed(), result.failed());
+                    result
+                }
+            )*
+        }
+    };
+}
+
+instrumented! {
+    InstrumentedUsers: UsersOps, "users";
+    mut {
+        fn create_user(username: String, password: String) -> Result<(), StoreError>;
+        fn create_user_with_uuid(user_uuid: String, username: String, password: String) -> Result<(), StoreError>;
+        fn create_if_absent(username: String, password: String) -> Result<bool, StoreError>;
+        fn update_password_if_matches(user_uuid: &str, current_password: &str, new_password: &str) -> Result<bool, StoreError>;
+        fn upgrade_password_hash(user_uuid: &str, password: &str) -> Result<bool, StoreError>;
+        fn delete_user(user_uuid: String) -> ();
+        fn link_external_user(provider: &str, subject: &str) -> Result<String, StoreError>;
+        fn set_account_status(user_uuid: &str, status: AccountStatus) -> Result<(), StoreError>;
+        fn change_username(user_uuid: &str, new_username: &str) -> Result<String, StoreError>;
+        fn set_metadata(user_uuid: &str, key: &str, value: &str) -> Result<(), StoreError>;
+        fn request_deletion(user_uuid: &str) -> Result<(), StoreError>;
+        fn soft_delete(user_uuid: &str) -> Result<(), StoreError>;
+        fn set_accepted_terms_version(user_uuid: &str, version: &str) -> Result<(), StoreError>;
+        fn set_scopes(user_uuid: &str, scopes: &[String]) -> Result<(), StoreError>;
+        fn add_tenant(user_uuid: &str, tenant: &str) -> Result<(), StoreError>;
+        fn add_webauthn_credential(user_uuid: &str, credential: WebAuthnCredential) -> Result<(), StoreError>;
+        fn set_webauthn_sign_count(credential_id: &[u8], sign_count: u32) -> Result<(), StoreError>;
+        fn replay(until: Option<SystemTime>) -> Result<usize, StoreError>;
+        fn import_user(events: Vec<UserEvent>) -> Result<(), StoreError>;
+    }
+    ref {
+        fn get_user_uuid(username: String, password: String) -> Option<String>;
+        fn directory() -> Option<Arc<dyn Directory>>;
+        fn directory_user(username: &str, identity: &str) -> Option<String>;
+        fn outdated_password_hashes() -> usize;
+        fn find_user_uuid(username: &str) -> Option<String>;
+        fn get_username(user_uuid: &str) -> Option<String>;
+        fn get_account_status(user_uuid: &str) -> Option<AccountStatus>;
+        fn get_metadata(user_uuid: &str) -> Option<HashMap<String, String>>;
+        fn deletion_requested_at(user_uuid: &str) -> Option<SystemTime>;
+        fn users_deleted_before(cutoff: SystemTime) -> Vec<String>;
+        fn users_soft_deleted_before(cutoff: SystemTime) -> Vec<String>;
+        fn get_accepted_terms_version(user_uuid: &str) -> Option<String>;
+        fn get_scopes(user_uuid: &str) -> Vec<String>;
+        fn get_tenants(user_uuid: &str) -> Vec<String>;
+        fn get_webauthn_credentials(user_uuid: &str) -> Vec<WebAuthnCredential>;
+        fn find_webauthn_credential(credential_id: &[u8]) -> Option<(String, WebAuthnCredential)>;
+        fn list_users(query: &UserQuery) -> Result<UserPage, StoreError>;
+        fn user_count() -> usize;
+        fn id_scheme() -> &'static str;
+        fn export_user(user_uuid: &str) -> Option<Vec<UserEvent>>;
+        fn check_store() -> Result<(), StoreError>;
+    }
+}
+
+instrumented! {
+    InstrumentedSessions: SessionsOps, "sessions";
+    mut {
+        fn create_scoped_session(user_uuid: &str, class: SessionClass, scopes: Vec<String>) -> String;
+        fn create_session_with_claims(user_uuid: &str, class: SessionClass, scopes: Vec<String>, claims: &Claims) -> String;
+        fn create_delegated_session(parent_token: &str, scopes: Vec<String>, ttl: Duration) -> Result<(String, Session), StoreError>;
+        fn delete_session(session_token: &str) -> bool;
+        fn touch_session(session_token: &str) -> Option<Session>;
+        fn delete_sessions_of_class(class: SessionClass) -> usize;
+        fn delete_sessions_of_user(user_uuid: &str) -> usize;
+        fn delete_sessions(session_tokens: &[String]) -> usize;
+        fn delete_sessions_of_user_created_before(user_uuid: &str, cutoff: SystemTime) -> usize;
+        fn advance_session_generation(user_uuid: &str) -> usize;
+        fn delete_expired_sessions() -> usize;
+        fn apply_replicated(events: Vec<SessionEvent>) -> usize;
+        fn replay(until: Option<SystemTime>) -> Result<usize, StoreError>;
+        fn create_magic_link(user_uuid: &str, ttl: Duration) -> String;
+        fn consume_magic_link(magic_link_token: &str) -> Option<String>;
+        fn create_webauthn_challenge(ceremony: WebAuthnCeremony, ttl: Duration) -> Vec<u8>;
+        fn consume_webauthn_challenge(challenge: &[u8]) -> Option<WebAuthnCeremony>;
+        fn remember_device(user_uuid: &str, fingerprint: &str) -> bool;
+        fn bind_session(session_token: &str, binding: &str) -> bool;
+        fn pin_session(session_token: &str, tenant: &str) -> bool;
+        fn set_session_ip(session_token: &str, ip: IpAddr) -> bool;
+        fn take_evicted_sessions() -> Vec<Session>;
+    }
+    ref {
+        fn get_session(session_token: &str) -> Option<Session>;
+        fn get_sessions_of_user(user_uuid: &str) -> Vec<Session>;
+        fn revoked_since(cursor: u64) -> Revocations;
+        fn session_count() -> usize;
+        fn export_sessions() -> Vec<SessionEvent>;
+        fn list_sessions(query: &SessionQuery) -> Result<SessionPage, StoreError>;
+        fn check_store() -> Result<(), StoreError>;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{sessions::SessionsImpl, users::UsersImpl};
+
+    use super::*;
+
+    #[test]
+    fn should_count_calls_and_errors_per_operation() {
+        let metrics = StoreMetrics::default();
+        let mut users = InstrumentedUsers::new(UsersImpl::default(), "memory", metrics.clone());
+        let mut sessions = InstrumentedSessions::new(SessionsImpl::default(), "memory", metrics.clone());
+
+        users.create_user("username".to_owned(), "password".to_owned()).unwrap();
+        assert!(users.create_user("username".to_owned(), "password".to_owned()).is_err());
+        let user_uuid = users.find_user_uuid("username").unwrap();
+        let token = sessions.create_session(&user_uuid, SessionClass::Standard);
+        assert!(sessions.get_session(&token).is_some());
+
+        let snapshot = metrics.snapshot();
+        let create_user = &snapshot[&("users", "memory", "create_user")];
+        assert_eq!((create_user.calls, create_user.errors), (2, 1));
+        assert_eq!(create_user.buckets.iter().sum::<u64>(), 2);
+        assert_eq!(snapshot[&("users", "memory", "find_user_uuid")].errors, 0);
+        // The default method goes through the one it calls.
+        assert_eq!(snapshot[&("sessions", "memory", "create_scoped_session")].calls, 1);
+        assert_eq!(snapshot[&("sessions", "memory", "get_session")].calls, 1);
+    }
+
+    #[test]
+    fn should_put_durations_in_their_bucket() {
+        let metrics = StoreMetrics::default();
+        let key = ("users", "memory", "list_users");
+
+        metrics.record(key, Duration::from_micros(50), false);
+        metrics.record(key, Duration::from_millis(1), false);
+        metrics.record(key, Duration::from_secs(3), true);
+
+        let stats = &metrics.snapshot()[&key];
+        assert_eq!(stats.buckets, [1, 0, 1, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(stats.total, Duration::from_secs(3) + Duration::from_micros(1050));
+    }
+}
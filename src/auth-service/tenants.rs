@@ -0,0 +1,46 @@
+use tonic::Request;
+
+// Multi-tenant mode (AUTH_TENANTS): one deployment serves several tenants, and the gateway in front
+// says which one each request is for in `x-tenant-id`. A session is pinned to the tenant it was
+// created for and only validates for that one; JWT session tokens carry it in this claim. Users
+// are shared between tenants.
+pub const TENANT_CLAIM: &str = "tenant";
+
+// The tenant `request` is for: always None when the deployment isn't multi-tenant, whatever the
+// request says; otherwise one of `tenants`, or Error::UnknownTenant.
+pub fn request_tenant<T>(request: &Request<T>, tenants: &[String]) -> Result<Option<String>, String> {
+    if tenants.is_empty() {
+        return Ok(None);
+    }
+
+    let tenant = request.metadata().get("x-tenant-id").and_then(|value| value.to_str().ok()).unwrap_or_default();
+    match tenants.iter().find(|known| *known == tenant) {
+        Some(tenant) => Ok(Some(tenant.clone())),
+        None => Err(format!("Error::UnknownTenant: {tenant}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(tenant: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(tenant) = tenant {
+            request.metadata_mut().insert("x-tenant-id", tenant.parse().unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn should_only_take_known_tenants() {
+        let tenants = ["acme".to_owned(), "globex".to_owned()];
+
+        assert_eq!(request_tenant(&request(Some("acme")), &tenants), Ok(Some("acme".to_owned())));
+        assert_eq!(request_tenant(&request(Some("initech")), &tenants), Err(String::from("Error::UnknownTenant: initech")));
+        assert_eq!(request_tenant(&request(None), &tenants), Err(String::from("Error::UnknownTenant: ")));
+
+        // Not multi-tenant: the header means nothing.
+        assert_eq!(request_tenant(&request(Some("acme")), &[]), Ok(None));
+    }
+}
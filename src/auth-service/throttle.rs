@@ -0,0 +1,480 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{audit::AuditEvent, config::Config};
+
+// Where failed sign-ins come from: a single address, or the network around it. Credential
+// stuffing tends to spread over many addresses of the same network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Source {
+    Ip(IpAddr),
+    // The /24 of an IPv4 address, the /64 of an IPv6 one.
+    Subnet(IpAddr),
+}
+
+impl Source {
+    fn subnet_of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => {
+                let [a, b, c, _] = ip.octets();
+                Source::Subnet([a, b, c, 0].into())
+            }
+            IpAddr::V6(ip) => {
+                let mask = !0u128 << 64;
+                Source::Subnet(Ipv6Addr::from(u128::from(ip) & mask).into())
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Source::Ip(ip) => ip.to_string(),
+            Source::Subnet(IpAddr::V4(ip)) => format!("{ip}/24"),
+            Source::Subnet(IpAddr::V6(ip)) => format!("{ip}/64"),
+        }
+    }
+
+    // (tarpit after, block after) failures within the window.
+    fn limits(&self, config: &Config) -> (u32, u32) {
+        match self {
+            Source::Ip(_) => (config.throttle_ip_tarpit_after, config.throttle_ip_block_after),
+            Source::Subnet(_) => (config.throttle_subnet_tarpit_after, config.throttle_subnet_block_after),
+        }
+    }
+}
+
+struct Failures {
+    window_started_at: Instant,
+    count: u32,
+    blocked_until: Option<Instant>,
+}
+
+// How many of the addresses a username failed to sign in from are kept, the latest ones, and for
+// how many usernames at most: past that, sign-ins as other usernames are only counted per source,
+// so spraying made up usernames can't take up the memory.
+const ADDRESSES_PER_USERNAME: usize = 16;
+const MAX_USERNAMES: usize = 100_000;
+
+// Failed sign-ins as a username, and the addresses they came from: the sources to clear when its
+// user can't sign in anymore (see `SourceThrottle::clear_lockout`).
+struct UsernameFailures {
+    window_started_at: Instant,
+    last_failed_at: Instant,
+    count: u32,
+    ips: Vec<IpAddr>,
+}
+
+// What sign_in should do with a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    // Answer, but only after the delay.
+    Tarpit(Duration),
+    Block,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThrottleMetrics {
+    pub tarpitted_requests: u64,
+    pub blocked_requests: u64,
+}
+
+// A source with failed sign-ins in its current window, or blocked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThrottledSource {
+    // An address, or a network as "192.0.2.0/24".
+    pub source: String,
+    pub failures: u32,
+    // How much longer it is refused, when blocked.
+    pub blocked_for: Option<Duration>,
+}
+
+// What the throttle knows of a username.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UsernameState {
+    // Within the current window.
+    pub failures: u32,
+    // The addresses its failed sign-ins came from, and their networks, as far as they are still
+    // counted or blocked.
+    pub sources: Vec<ThrottledSource>,
+}
+
+// Counts failed sign-ins per source over a fixed window. Sources over the tarpit limit get slowed
+// down; sources over the block limit get refused for a cooldown period.
+#[derive(Default)]
+pub struct SourceThrottle {
+    failures: Mutex<HashMap<Source, Failures>>,
+    usernames: Mutex<HashMap<String, UsernameFailures>>,
+    // When each username last asked for a magic link.
+    magic_links: Mutex<HashMap<String, Instant>>,
+    tarpitted_requests: AtomicU64,
+    blocked_requests: AtomicU64,
+}
+
+impl SourceThrottle {
+    pub fn check(&self, ip: IpAddr, config: &Config) -> Verdict {
+        let verdict = self.verdict(ip, config);
+
+        match verdict {
+            Verdict::Tarpit(_) => self.tarpitted_requests.fetch_add(1, Ordering::Relaxed),
+            Verdict::Block => self.blocked_requests.fetch_add(1, Ordering::Relaxed),
+            Verdict::Allow => 0,
+        };
+
+        verdict
+    }
+
+    // Like `check`, for other RPCs that want to know without counting it as a throttled request.
+    pub fn is_suspicious(&self, ip: IpAddr, config: &Config) -> bool {
+        self.verdict(ip, config) != Verdict::Allow
+    }
+
+    fn verdict(&self, ip: IpAddr, config: &Config) -> Verdict {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut verdict = Verdict::Allow;
+        for source in [Source::Ip(ip), Source::subnet_of(ip)] {
+            let Some(entry) = failures.get(&source) else { continue };
+            let (tarpit_after, _) = source.limits(config);
+
+            if entry.blocked_until.is_some_and(|blocked_until| blocked_until > now) {
+                verdict = Verdict::Block;
+                break;
+            }
+            if entry.count >= tarpit_after && now < entry.window_started_at + config.throttle_window {
+                verdict = Verdict::Tarpit(config.throttle_tarpit_delay);
+            }
+        }
+
+        verdict
+    }
+
+    // A failed sign-in as `username` from `ip`. Returns what has to go in the audit log: a source
+    // that just crossed one of its limits.
+    pub fn record_failure(&self, ip: IpAddr, username: &str, config: &Config) -> Vec<AuditEvent> {
+        let now = Instant::now();
+        self.record_username_failure(ip, username, now, config);
+        let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut events = Vec::new();
+        for source in [Source::Ip(ip), Source::subnet_of(ip)] {
+            let (tarpit_after, block_after) = source.limits(config);
+            let entry = failures.entry(source).or_insert(Failures {
+                window_started_at: now,
+                count: 0,
+                blocked_until: None,
+            });
+
+            if now >= entry.window_started_at + config.throttle_window {
+                entry.window_started_at = now;
+                entry.count = 0;
+            }
+            entry.count += 1;
+
+            // Past the limit still after a cooldown ran out within the window: blocked again.
+            let blocked = entry.blocked_until.is_some_and(|blocked_until| blocked_until > now);
+            if entry.count >= block_after && !blocked {
+                entry.blocked_until = Some(now + config.throttle_block_cooldown);
+                events.push(AuditEvent::SourceBlocked { source: source.describe() });
+            } else if entry.count == tarpit_after {
+                events.push(AuditEvent::SourceTarpitted { source: source.describe() });
+            }
+        }
+
+        events
+    }
+
+    fn record_username_failure(&self, ip: IpAddr, username: &str, now: Instant, config: &Config) {
+        let mut usernames = self.usernames.lock().unwrap_or_else(PoisonError::into_inner);
+        if usernames.len() >= MAX_USERNAMES && !usernames.contains_key(username) {
+            return;
+        }
+        let entry = usernames.entry(username.to_owned()).or_insert(UsernameFailures {
+            window_started_at: now,
+            last_failed_at: now,
+            count: 0,
+            ips: Vec::new(),
+        });
+
+        if now >= entry.window_started_at + config.throttle_window {
+            entry.window_started_at = now;
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last_failed_at = now;
+
+        entry.ips.retain(|known| *known != ip);
+        if entry.ips.len() == ADDRESSES_PER_USERNAME {
+            entry.ips.remove(0);
+        }
+        entry.ips.push(ip);
+    }
+
+    // Whether `username` may be sent a magic link: not within `magic_link_cooldown` of the last
+    // one, so nobody can flood an inbox with them. Counts as asking for one when it may.
+    pub fn allow_magic_link(&self, username: &str, config: &Config) -> bool {
+        let now = Instant::now();
+        let mut magic_links = self.magic_links.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if magic_links.get(username).is_some_and(|requested_at| now < *requested_at + config.magic_link_cooldown) {
+            return false;
+        }
+        if magic_links.len() < MAX_USERNAMES || magic_links.contains_key(username) {
+            magic_links.insert(username.to_owned(), now);
+        }
+        true
+    }
+
+    // Drops the sources that are neither blocked nor within a window anymore.
+    pub fn forget_expired(&self, config: &Config) {
+        let now = Instant::now();
+
+        self.failures
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, entry| {
+                entry.blocked_until.is_some_and(|blocked_until| blocked_until > now)
+                    || now < entry.window_started_at + config.throttle_window
+            });
+
+        // Kept as long as one of their sources may be blocked.
+        let kept_for = config.throttle_window.max(config.throttle_block_cooldown);
+        self.usernames
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, entry| now < entry.last_failed_at + kept_for);
+
+        self.magic_links
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, requested_at| now < *requested_at + config.magic_link_cooldown);
+    }
+
+    // The `limit` sources with the most failed sign-ins, the blocked ones first; for operators
+    // watching an attack.
+    pub fn top_sources(&self, limit: usize, config: &Config) -> Vec<ThrottledSource> {
+        let now = Instant::now();
+
+        let mut sources: Vec<ThrottledSource> = self
+            .failures
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter_map(|(source, entry)| throttled_source(source, entry, now, config))
+            .collect();
+
+        sources.sort_by(|a, b| {
+            b.blocked_for.is_some().cmp(&a.blocked_for.is_some()).then(b.failures.cmp(&a.failures)).then(a.source.cmp(&b.source))
+        });
+        sources.truncate(limit);
+        sources
+    }
+
+    // An address, and its network.
+    pub fn state_of_ip(&self, ip: IpAddr, config: &Config) -> Vec<ThrottledSource> {
+        self.state_of(&[ip], config)
+    }
+
+    pub fn state_of_username(&self, username: &str, config: &Config) -> UsernameState {
+        let now = Instant::now();
+        let (failures, ips) = match self.usernames.lock().unwrap_or_else(PoisonError::into_inner).get(username) {
+            Some(entry) if now < entry.window_started_at + config.throttle_window => (entry.count, entry.ips.clone()),
+            Some(entry) => (0, entry.ips.clone()),
+            None => (0, Vec::new()),
+        };
+        UsernameState { failures, sources: self.state_of(&ips, config) }
+    }
+
+    fn state_of(&self, ips: &[IpAddr], config: &Config) -> Vec<ThrottledSource> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut sources: Vec<Source> = Vec::new();
+        for source in ips.iter().flat_map(|ip| [Source::Ip(*ip), Source::subnet_of(*ip)]) {
+            if !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+        sources
+            .iter()
+            .filter_map(|source| throttled_source(source, failures.get(source)?, now, config))
+            .collect()
+    }
+
+    // Lets a user sign in again straight away: forgets the failed sign-ins, and blocks, of the
+    // addresses their failed sign-ins came from and of their networks. Whoever else failed from
+    // those gets a clean slate too. Returns the sources there was something to forget of.
+    pub fn clear_lockout(&self, username: &str) -> Vec<String> {
+        let Some(entry) = self.usernames.lock().unwrap_or_else(PoisonError::into_inner).remove(username) else {
+            return Vec::new();
+        };
+        let mut failures = self.failures.lock().unwrap_or_else(PoisonError::into_inner);
+
+        entry
+            .ips
+            .iter()
+            .flat_map(|ip| [Source::Ip(*ip), Source::subnet_of(*ip)])
+            .filter(|source| failures.remove(source).is_some())
+            .map(|source| source.describe())
+            .collect()
+    }
+
+    pub fn metrics(&self) -> ThrottleMetrics {
+        ThrottleMetrics {
+            tarpitted_requests: self.tarpitted_requests.load(Ordering::Relaxed),
+            blocked_requests: self.blocked_requests.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// None when the source is neither blocked nor within a window anymore.
+fn throttled_source(source: &Source, entry: &Failures, now: Instant, config: &Config) -> Option<ThrottledSource> {
+    let blocked_for = entry.blocked_until.filter(|blocked_until| *blocked_until > now).map(|blocked_until| blocked_until - now);
+    let in_window = now < entry.window_started_at + config.throttle_window;
+    (blocked_for.is_some() || in_window).then(|| ThrottledSource {
+        source: source.describe(),
+        failures: entry.count,
+        blocked_for,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Config {
+        Config {
+            throttle_ip_tarpit_after: 2,
+            throttle_ip_block_after: 3,
+            throttle_subnet_tarpit_after: 4,
+            throttle_subnet_block_after: 5,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn should_tarpit_then_block_ip() {
+        let config = test_config();
+        let throttle = SourceThrottle::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert!(throttle.record_failure(ip, "alice", &config).is_empty());
+        assert_eq!(throttle.check(ip, &config), Verdict::Allow);
+
+        assert_eq!(
+            throttle.record_failure(ip, "alice", &config),
+            vec![AuditEvent::SourceTarpitted { source: "192.0.2.1".to_owned() }]
+        );
+        assert_eq!(throttle.check(ip, &config), Verdict::Tarpit(config.throttle_tarpit_delay));
+
+        assert_eq!(
+            throttle.record_failure(ip, "alice", &config),
+            vec![AuditEvent::SourceBlocked { source: "192.0.2.1".to_owned() }]
+        );
+        assert_eq!(throttle.check(ip, &config), Verdict::Block);
+
+        assert_eq!(throttle.metrics(), ThrottleMetrics { tarpitted_requests: 1, blocked_requests: 1 });
+    }
+
+    #[test]
+    fn should_block_again_failures_after_the_cooldown() {
+        let config = Config {
+            throttle_block_cooldown: Duration::ZERO,
+            ..test_config()
+        };
+        let throttle = SourceThrottle::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..3 {
+            throttle.record_failure(ip, "alice", &config);
+        }
+        // The cooldown is over, but the window is not.
+        assert_eq!(throttle.check(ip, &config), Verdict::Tarpit(config.throttle_tarpit_delay));
+
+        assert_eq!(
+            throttle.record_failure(ip, "alice", &config)[0],
+            AuditEvent::SourceBlocked { source: "192.0.2.1".to_owned() }
+        );
+    }
+
+    #[test]
+    fn should_block_subnet_spread_over_addresses() {
+        let config = test_config();
+        let throttle = SourceThrottle::default();
+
+        let events: Vec<AuditEvent> = (1..=5)
+            .flat_map(|host| throttle.record_failure(format!("192.0.2.{host}").parse().unwrap(), "alice", &config))
+            .collect();
+
+        assert_eq!(events.last(), Some(&AuditEvent::SourceBlocked { source: "192.0.2.0/24".to_owned() }));
+        assert_eq!(throttle.check("192.0.2.200".parse().unwrap(), &config), Verdict::Block);
+        assert_eq!(throttle.check("192.0.3.1".parse().unwrap(), &config), Verdict::Allow);
+    }
+
+    #[test]
+    fn should_hold_back_magic_links_within_the_cooldown() {
+        let config = test_config();
+        let throttle = SourceThrottle::default();
+
+        assert!(throttle.allow_magic_link("alice", &config));
+        assert!(!throttle.allow_magic_link("alice", &config));
+        assert!(throttle.allow_magic_link("bob", &config));
+
+        throttle.forget_expired(&config);
+        assert_eq!(throttle.magic_links.lock().unwrap().len(), 2);
+
+        let config = Config { magic_link_cooldown: Duration::ZERO, ..config };
+        throttle.forget_expired(&config);
+        assert!(throttle.magic_links.lock().unwrap().is_empty());
+        assert!(throttle.allow_magic_link("alice", &config));
+        assert!(throttle.allow_magic_link("alice", &config));
+    }
+
+    #[test]
+    fn should_list_top_sources_blocked_first() {
+        let config = test_config();
+        let throttle = SourceThrottle::default();
+
+        for host in [1, 1, 1, 2, 2] {
+            throttle.record_failure(format!("192.0.2.{host}").parse().unwrap(), "alice", &config);
+        }
+
+        let sources = throttle.top_sources(2, &config);
+        assert_eq!(sources.len(), 2);
+        // Both blocked, at their fifth and third failure.
+        assert_eq!((sources[0].source.as_str(), sources[0].failures), ("192.0.2.0/24", 5));
+        assert_eq!((sources[1].source.as_str(), sources[1].failures), ("192.0.2.1", 3));
+        assert!(sources.iter().all(|source| source.blocked_for.is_some()));
+
+        assert_eq!(throttle.top_sources(10, &config).last().map(|source| source.failures), Some(2));
+    }
+
+    #[test]
+    fn should_clear_only_the_sources_a_username_failed_from() {
+        let config = test_config();
+        let throttle = SourceThrottle::default();
+
+        for ip in ["192.0.2.1", "198.51.100.1", "192.0.2.1"] {
+            throttle.record_failure(ip.parse().unwrap(), "alice", &config);
+        }
+        throttle.record_failure("203.0.113.1".parse().unwrap(), "bob", &config);
+
+        let state = throttle.state_of_username("alice", &config);
+        assert_eq!(state.failures, 3);
+        let sources: Vec<_> = state.sources.iter().map(|source| source.source.as_str()).collect();
+        assert_eq!(sources, ["198.51.100.1", "198.51.100.0/24", "192.0.2.1", "192.0.2.0/24"]);
+
+        assert_eq!(throttle.clear_lockout("alice").len(), 4);
+        assert!(throttle.clear_lockout("alice").is_empty());
+        assert_eq!(throttle.top_sources(10, &config).len(), 2);
+        assert_eq!(throttle.state_of_username("bob", &config).failures, 1);
+    }
+}
@@ -0,0 +1,234 @@
+use std::{env, time::SystemTime};
+
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use tracing::warn;
+
+use auth_ids::Ids;
+
+use crate::{
+    auth::epoch_secs,
+    claims::{Claims, RESERVED_CLAIMS},
+    sessions::SessionClass,
+};
+
+// Makes up the token handed out for a new session. The store keeps the session under it, so
+// whatever the format, a token is only valid as long as its session is. Formats that carry no
+// claims ignore `claims` (see `ClaimsProvider`).
+pub trait TokenGenerator {
+    fn generate(
+        &self,
+        user_uuid: &str,
+        class: SessionClass,
+        scopes: &[String],
+        expires_at: SystemTime,
+        claims: &Claims,
+    ) -> String;
+}
+
+// A random UUID, as tokens always were.
+#[derive(Default)]
+pub struct UuidTokens {
+    ids: Ids,
+}
+
+impl UuidTokens {
+    pub fn new(ids: Ids) -> Self {
+        Self { ids }
+    }
+}
+
+impl TokenGenerator for UuidTokens {
+    fn generate(
+        &self,
+        _user_uuid: &str,
+        _class: SessionClass,
+        _scopes: &[String],
+        _expires_at: SystemTime,
+        _claims: &Claims,
+    ) -> String {
+        self.ids.new_id().to_string()
+    }
+}
+
+// 256 random bits, hex encoded; twice the entropy of a UUID.
+pub struct RandomTokens;
+
+impl TokenGenerator for RandomTokens {
+    fn generate(
+        &self,
+        _user_uuid: &str,
+        _class: SessionClass,
+        _scopes: &[String],
+        _expires_at: SystemTime,
+        _claims: &Claims,
+    ) -> String {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+#[derive(Serialize)]
+struct SessionClaims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    iat: i64,
+    exp: i64,
+    // Makes every token unique, even for sessions created in the same second.
+    jti: String,
+    class: &'a str,
+    // Space separated, as in OAuth 2.0 token introspection; left out when there are none.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    scope: String,
+    // From the `ClaimsProvider`, minus the ones above.
+    #[serde(flatten)]
+    extra: Claims,
+}
+
+// An HS256 signed JWT, so services holding the secret can read the user and expiry off the token
+// without asking us. With sliding expiry, `exp` is the first expiry; ValidateSession has the
+// current one.
+pub struct JwtTokens {
+    issuer: String,
+    encoding_key: EncodingKey,
+    ids: Ids,
+}
+
+impl JwtTokens {
+    pub fn new(issuer: String, secret: &[u8]) -> Self {
+        Self {
+            issuer,
+            encoding_key: EncodingKey::from_secret(secret),
+            ids: Ids::default(),
+        }
+    }
+
+    pub fn with_id_generator(mut self, ids: Ids) -> Self {
+        self.ids = ids;
+        self
+    }
+}
+
+impl TokenGenerator for JwtTokens {
+    fn generate(
+        &self,
+        user_uuid: &str,
+        class: SessionClass,
+        scopes: &[String],
+        expires_at: SystemTime,
+        claims: &Claims,
+    ) -> String {
+        let mut extra = claims.clone();
+        for name in RESERVED_CLAIMS {
+            if extra.remove(name).is_some() {
+                warn!("ignored the {} claim of the claims provider, it is ours", name);
+            }
+        }
+
+        let claims = SessionClaims {
+            iss: &self.issuer,
+            sub: user_uuid,
+            iat: epoch_secs(SystemTime::now()),
+            exp: epoch_secs(expires_at),
+            jti: self.ids.new_id().to_string(),
+            class: match class {
+                SessionClass::Standard => "standard",
+                SessionClass::LongLived => "long_lived",
+                SessionClass::Guest => "guest",
+                SessionClass::Delegated => "delegated",
+            },
+            scope: scopes.join(" "),
+            extra,
+        };
+
+        encode(&Header::default(), &claims, &self.encoding_key).expect("HS256 encoding seems broken!")
+    }
+}
+
+// The `jti` of a token of ours that is a JWT, None for the other formats. Only good for telling
+// other services which JWTs were revoked (see `Revocations`): the signature isn't checked.
+pub fn jwt_id(token: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct JwtId {
+        jti: String,
+    }
+
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+
+    decode::<JwtId>(token, &DecodingKey::from_secret(&[]), &validation).ok().map(|data| data.claims.jti)
+}
+
+// AUTH_SESSION_TOKEN_FORMAT picks uuid (the default), random or jwt. JWTs need
+// AUTH_SESSION_JWT_SECRET, and are issued by AUTH_SESSION_JWT_ISSUER ("auth-microservice").
+pub fn token_generator_from_env(ids: &Ids) -> Result<Box<dyn TokenGenerator + Send + Sync>, String> {
+    match env::var("AUTH_SESSION_TOKEN_FORMAT").as_deref() {
+        Err(_) | Ok("uuid") => Ok(Box::new(UuidTokens::new(ids.clone()))),
+        Ok("random") => Ok(Box::new(RandomTokens)),
+        Ok("jwt") => {
+            let secret = env::var("AUTH_SESSION_JWT_SECRET").map_err(|_| String::from("Error::MissingSessionJwtSecret"))?;
+            let issuer = env::var("AUTH_SESSION_JWT_ISSUER").unwrap_or_else(|_| String::from("auth-microservice"));
+            Ok(Box::new(JwtTokens::new(issuer, secret.as_bytes()).with_id_generator(ids.clone())))
+        }
+        Ok(format) => Err(format!("Error::UnsupportedTokenFormat: {format}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn random_tokens_should_be_256_bits_and_unique() {
+        let expires_at = SystemTime::now();
+        let token = RandomTokens.generate("1234", SessionClass::Standard, &[], expires_at, &Claims::new());
+
+        assert_eq!(token.len(), 64);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(token, RandomTokens.generate("1234", SessionClass::Standard, &[], expires_at, &Claims::new()));
+    }
+
+    #[test]
+    fn jwt_tokens_should_carry_user_and_expiry() {
+        #[derive(Deserialize)]
+        struct Claims {
+            sub: String,
+            exp: i64,
+            class: String,
+            scope: String,
+            plan: String,
+            org_id: u64,
+        }
+
+        let expires_at = SystemTime::now() + Duration::from_secs(60 * 60);
+        let scopes = ["profile:read".to_owned(), "admin".to_owned()];
+        // Ours can't be replaced.
+        let claims = json!({ "plan": "pro", "org_id": 42, "sub": "somebody else" }).as_object().unwrap().clone();
+        let token = JwtTokens::new("auth".to_owned(), b"secret")
+            .generate("1234", SessionClass::LongLived, &scopes, expires_at, &claims);
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&["auth"]);
+        let claims = decode::<Claims>(&token, &DecodingKey::from_secret(b"secret"), &validation)
+            .unwrap()
+            .claims;
+
+        assert_eq!(claims.sub, "1234");
+        assert_eq!(claims.exp, epoch_secs(expires_at));
+        assert_eq!(claims.class, "long_lived");
+        assert_eq!(claims.scope, "profile:read admin");
+        assert_eq!((claims.plan.as_str(), claims.org_id), ("pro", 42));
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashSet},
+    fs::OpenOptions,
+    hash::BuildHasher,
+    io::Write,
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rand_core::{OsRng, RngCore};
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::auth::epoch_secs;
+use crate::config::Config;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Default)]
+struct DayUsage {
+    // Hashed with a key of this process only, so users are told apart without being kept.
+    active_users: HashSet<u64>,
+    sign_ins: u64,
+    refused_sign_ins: u64,
+}
+
+// How many users signed in each day, and how many sign-ins there were, for product analytics to
+// be fed without access to the audit log. Nothing about a user leaves the service: days are
+// reported whole once over (see `take_completed`), as counts only. Each replica counts its own
+// sign-ins; a user signing in on two of them on the same day is counted by both.
+pub struct UsageStats {
+    // Only with AUTH_USAGE_STATS_FILE or AUTH_USAGE_STATS_URL, so days no one takes don't pile up.
+    enabled: bool,
+    key: RandomState,
+    days: Mutex<BTreeMap<u64, DayUsage>>,
+}
+
+impl Default for UsageStats {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+impl UsageStats {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled, key: RandomState::new(), days: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.usage_stats_file.is_some() || config.usage_stats_url.is_some())
+    }
+
+    // `user_uuid` is None for a refused sign-in.
+    pub fn record_sign_in(&self, user_uuid: Option<&str>, at: SystemTime) {
+        if !self.enabled {
+            return;
+        }
+        let mut days = self.days.lock().unwrap_or_else(PoisonError::into_inner);
+        let day = days.entry(day_of(at)).or_default();
+        match user_uuid {
+            Some(user_uuid) => {
+                day.sign_ins += 1;
+                day.active_users.insert(self.key.hash_one(user_uuid));
+            }
+            None => day.refused_sign_ins += 1,
+        }
+    }
+
+    // The days before `now`'s, forgotten once taken.
+    pub fn take_completed(&self, now: SystemTime) -> Vec<DailyUsage> {
+        let mut days = self.days.lock().unwrap_or_else(PoisonError::into_inner);
+        let today = days.split_off(&day_of(now));
+        std::mem::replace(&mut *days, today)
+            .into_iter()
+            .map(|(day, usage)| DailyUsage {
+                day_start: UNIX_EPOCH + DAY * day as u32,
+                daily_active_users: usage.active_users.len() as u64,
+                sign_ins: usage.sign_ins,
+                refused_sign_ins: usage.refused_sign_ins,
+            })
+            .collect()
+    }
+}
+
+fn day_of(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / DAY.as_secs()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DailyUsage {
+    // Midnight UTC.
+    pub day_start: SystemTime,
+    pub daily_active_users: u64,
+    pub sign_ins: u64,
+    pub refused_sign_ins: u64,
+}
+
+impl DailyUsage {
+    // Every count with Laplace noise of scale 1/`epsilon` (none for 0), then rounded down to a
+    // multiple of `bucket`, so a single user can't be told from the figures.
+    pub fn privatized(&self, bucket: u64, epsilon: f64) -> Self {
+        let count = |count: u64| {
+            let noise = if epsilon > 0.0 { laplace(1.0 / epsilon) } else { 0.0 };
+            let noisy = (count as f64 + noise).round().max(0.0) as u64;
+            noisy / bucket * bucket
+        };
+        Self {
+            day_start: self.day_start,
+            daily_active_users: count(self.daily_active_users),
+            sign_ins: count(self.sign_ins),
+            refused_sign_ins: count(self.refused_sign_ins),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "dayStart": epoch_secs(self.day_start),
+            "dailyActiveUsers": self.daily_active_users,
+            "signIns": self.sign_ins,
+            "refusedSignIns": self.refused_sign_ins,
+        })
+    }
+}
+
+// A sample of the Laplace distribution centred on 0.
+fn laplace(scale: f64) -> f64 {
+    let u = OsRng.next_u64() as f64 / u64::MAX as f64 - 0.5;
+    -scale * u.signum() * (1.0 - 2.0 * u.abs()).max(f64::MIN_POSITIVE).ln()
+}
+
+// Sends the completed days, privatized, where AUTH_USAGE_STATS_FILE (one JSON object a line) and
+// AUTH_USAGE_STATS_URL (a JSON array, POSTed) say; run by the `export-usage-stats` job. A day that
+// fails to go out is logged and not tried again.
+pub struct UsageStatsExporter {
+    file: Option<String>,
+    url: Option<String>,
+    bucket: u64,
+    epsilon: f64,
+    client: reqwest::Client,
+}
+
+impl UsageStatsExporter {
+    // None unless there is somewhere to send them.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if config.usage_stats_file.is_none() && config.usage_stats_url.is_none() {
+            return None;
+        }
+        Some(Self {
+            file: config.usage_stats_file.clone(),
+            url: config.usage_stats_url.clone(),
+            bucket: config.usage_stats_bucket,
+            epsilon: config.usage_stats_epsilon,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    pub fn export(&self, days: Vec<DailyUsage>) {
+        if days.is_empty() {
+            return;
+        }
+        let days: Vec<Value> = days.iter().map(|day| day.privatized(self.bucket, self.epsilon).to_json()).collect();
+
+        if let Some(path) = &self.file {
+            if let Err(e) = append_lines(path, &days) {
+                warn!("failed to write usage stats to {}: {}", path, e);
+            }
+        }
+        if let Some(url) = &self.url {
+            let request = self
+                .client
+                .post(url)
+                .header("Content-Type", "application/json")
+                .body(Value::Array(days).to_string());
+            let url = url.clone();
+            tokio::spawn(async move {
+                if let Err(e) = request.send().await.and_then(|response| response.error_for_status()) {
+                    warn!("Failed to deliver usage stats to {}.\n{e:?}", url);
+                }
+            });
+        }
+    }
+}
+
+fn append_lines(path: &str, lines: &[Value]) -> Result<(), String> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| e.to_string())?;
+    let lines: String = lines.iter().map(|line| format!("{line}\n")).collect();
+    file.write_all(lines.as_bytes()).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const MONDAY: u64 = 1_700_438_400;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn should_count_users_and_sign_ins_per_completed_day() {
+        let stats = UsageStats::new(true);
+        stats.record_sign_in(Some("alice"), at(MONDAY + 60));
+        stats.record_sign_in(Some("alice"), at(MONDAY + 120));
+        stats.record_sign_in(Some("bob"), at(MONDAY + 180));
+        stats.record_sign_in(None, at(MONDAY + 240));
+        stats.record_sign_in(Some("alice"), at(MONDAY + DAY.as_secs()));
+
+        let monday = DailyUsage { day_start: at(MONDAY), daily_active_users: 2, sign_ins: 3, refused_sign_ins: 1 };
+        assert_eq!(stats.take_completed(at(MONDAY + DAY.as_secs() + 60)), vec![monday]);
+        // Taken once; today's still counting.
+        assert_eq!(stats.take_completed(at(MONDAY + DAY.as_secs() + 60)), vec![]);
+        assert_eq!(stats.take_completed(at(MONDAY + 2 * DAY.as_secs())).len(), 1);
+
+        let disabled = UsageStats::default();
+        disabled.record_sign_in(Some("alice"), at(MONDAY));
+        assert_eq!(disabled.take_completed(at(MONDAY + DAY.as_secs())), vec![]);
+    }
+
+    #[test]
+    fn should_report_counts_in_buckets() {
+        let day = DailyUsage { day_start: at(MONDAY), daily_active_users: 7, sign_ins: 42, refused_sign_ins: 3 };
+
+        let bucketed = day.privatized(10, 0.0);
+        assert_eq!((bucketed.daily_active_users, bucketed.sign_ins, bucketed.refused_sign_ins), (0, 40, 0));
+        assert_eq!(
+            bucketed.to_json(),
+            json!({ "dayStart": MONDAY, "dailyActiveUsers": 0, "signIns": 40, "refusedSignIns": 0 })
+        );
+
+        // Noisy, but still counts.
+        for _ in 0..100 {
+            let noisy = day.privatized(1, 0.5);
+            assert!(noisy.sign_ins.abs_diff(42) < 100);
+        }
+    }
+
+    #[test]
+    fn should_append_completed_days_to_the_file() {
+        let path = std::env::temp_dir().join(format!("usage-stats-{}.jsonl", std::process::id()));
+        let config = Config { usage_stats_file: Some(path.to_string_lossy().into_owned()), usage_stats_bucket: 1, ..Config::default() };
+        let exporter = UsageStatsExporter::from_config(&config).unwrap();
+
+        let day = |start| DailyUsage { day_start: at(start), daily_active_users: 1, sign_ins: 2, refused_sign_ins: 0 };
+        exporter.export(vec![day(MONDAY)]);
+        exporter.export(vec![day(MONDAY + DAY.as_secs())]);
+
+        let written = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(written.lines().count(), 2);
+        let first: Value = serde_json::from_str(written.lines().next().unwrap()).unwrap();
+        assert_eq!(first, json!({ "dayStart": MONDAY, "dailyActiveUsers": 1, "signIns": 2, "refusedSignIns": 0 }));
+        assert!(UsageStatsExporter::from_config(&Config::default()).is_none());
+    }
+}
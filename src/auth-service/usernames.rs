@@ -0,0 +1,142 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    sync::{PoisonError, RwLock},
+};
+
+use crate::users::normalize_username;
+
+// Usernames nobody may sign up with or change to, so nobody passes for the system's own accounts:
+// the names of AUTH_RESERVED_USERNAMES, those with a word of AUTH_USERNAME_DENY_FILE in them, and
+// those an operator keeps for later (ReserveUsername). All ignoring case and surrounding spaces.
+#[derive(Debug, Default)]
+pub struct ReservedUsernames {
+    names: BTreeSet<String>,
+    // Matched anywhere in a username, e.g. profanity.
+    denied_words: Vec<String>,
+    // Set at runtime, on this instance only.
+    reserved: RwLock<BTreeSet<String>>,
+}
+
+impl ReservedUsernames {
+    pub fn new(names: &[String]) -> Self {
+        Self { names: names.iter().map(|name| normalize_username(name)).filter(|name| !name.is_empty()).collect(), ..Self::default() }
+    }
+
+    pub fn with_denied_words(mut self, words: &[String]) -> Self {
+        self.denied_words = words.iter().map(|word| normalize_username(word)).filter(|word| !word.is_empty()).collect();
+        self
+    }
+
+    // One word a line; blank lines and those starting with `#` are skipped.
+    pub fn with_deny_file(self, path: &str) -> Result<Self, String> {
+        let words: Vec<String> = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {path}.\n{e:?}"))?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect();
+        Ok(self.with_denied_words(&words))
+    }
+
+    pub fn is_allowed(&self, username: &str) -> bool {
+        let username = normalize_username(username);
+
+        !self.names.contains(&username)
+            && !self.denied_words.iter().any(|word| username.contains(word.as_str()))
+            && !self.reserved.read().unwrap_or_else(PoisonError::into_inner).contains(&username)
+    }
+
+    // Whether `username` wasn't reserved already.
+    pub fn reserve(&self, username: &str) -> bool {
+        self.reserved.write().unwrap_or_else(PoisonError::into_inner).insert(normalize_username(username))
+    }
+
+    // Whether `username` was reserved. Names of the configuration or deny file stay denied.
+    pub fn release(&self, username: &str) -> bool {
+        self.reserved.write().unwrap_or_else(PoisonError::into_inner).remove(&normalize_username(username))
+    }
+}
+
+// Usernames nobody has, left as bait for credential stuffing: signing in as one never succeeds, and
+// is flagged (see `AuthService::note_honeypot_sign_in`). Those of AUTH_HONEYPOT_USERNAMES, and those
+// an operator sets at runtime (SetHoneypotUsername), on this instance only; ignoring case and
+// surrounding spaces.
+#[derive(Debug, Default)]
+pub struct HoneypotUsernames {
+    names: RwLock<BTreeSet<String>>,
+}
+
+impl HoneypotUsernames {
+    pub fn new(names: &[String]) -> Self {
+        let names = names.iter().map(|name| normalize_username(name)).filter(|name| !name.is_empty()).collect();
+        Self { names: RwLock::new(names) }
+    }
+
+    pub fn contains(&self, username: &str) -> bool {
+        self.names.read().unwrap_or_else(PoisonError::into_inner).contains(&normalize_username(username))
+    }
+
+    // Whether `username` wasn't a honeypot already.
+    pub fn add(&self, username: &str) -> bool {
+        self.names.write().unwrap_or_else(PoisonError::into_inner).insert(normalize_username(username))
+    }
+
+    // Whether `username` was a honeypot.
+    pub fn remove(&self, username: &str) -> bool {
+        self.names.write().unwrap_or_else(PoisonError::into_inner).remove(&normalize_username(username))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn should_deny_configured_names_and_words_ignoring_case() {
+        let usernames = ReservedUsernames::new(&strings(&["admin", "Root"])).with_denied_words(&strings(&["darn"]));
+
+        assert!(!usernames.is_allowed("admin"));
+        assert!(!usernames.is_allowed(" ADMIN "));
+        assert!(!usernames.is_allowed("root"));
+        assert!(!usernames.is_allowed("DarnIt"));
+        assert!(usernames.is_allowed("admin2"));
+        assert!(usernames.is_allowed("alice"));
+    }
+
+    #[test]
+    fn should_deny_reserved_names_until_released() {
+        let usernames = ReservedUsernames::new(&strings(&["admin"]));
+
+        assert!(usernames.reserve("Billing"));
+        assert!(!usernames.reserve("billing"));
+        assert!(!usernames.is_allowed("billing"));
+
+        assert!(usernames.release("BILLING"));
+        assert!(!usernames.release("billing"));
+        assert!(usernames.is_allowed("billing"));
+
+        assert!(!usernames.release("admin"));
+        assert!(!usernames.is_allowed("admin"));
+    }
+
+    #[test]
+    fn should_keep_honeypots_until_removed() {
+        let honeypots = HoneypotUsernames::new(&strings(&["j.smith", " "]));
+
+        assert!(honeypots.contains(" J.Smith "));
+        assert!(!honeypots.add("j.smith"));
+        assert!(honeypots.add("Payroll"));
+        assert!(honeypots.contains("payroll"));
+
+        assert!(honeypots.remove("J.SMITH"));
+        assert!(!honeypots.remove("j.smith"));
+        assert!(!honeypots.contains("j.smith"));
+        assert!(!honeypots.contains(""));
+    }
+}
@@ -1,16 +1,288 @@
 use pbkdf2::{
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Pbkdf2,
+    password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString},
+    Algorithm, Params, Pbkdf2,
 };
 use rand_core::OsRng;
-use uuid::Uuid;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
-use std::collections::HashMap;
+use auth_ids::{IdScheme, Ids};
+
+use crate::error::StoreError;
+use crate::hashers::{HashError, PasswordHasher, PasswordHashers};
+use crate::journal::Journal;
+use crate::ldap::Directory;
+use crate::webauthn::WebAuthnCredential;
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Bound::{Excluded, Unbounded},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 pub trait UsersOps {
-    fn create_user(&mut self, username: String, password: String) -> Result<(), String>;
+    fn create_user(&mut self, username: String, password: String) -> Result<(), StoreError>;
+    // For users who already have a uuid, e.g. guests signing up.
+    fn create_user_with_uuid(&mut self, user_uuid: String, username: String, password: String) -> Result<(), StoreError>;
+    // Creates the user unless the username is taken, checking and inserting in one step: Ok(false)
+    // when it was taken. Callers use it instead of looking the username up first.
+    fn create_if_absent(&mut self, username: String, password: String) -> Result<bool, StoreError>;
     fn get_user_uuid(&self, username: String, password: String) -> Option<String>;
+    // A directory checking the passwords instead of the store (see `LdapUsersImpl`). Its checks
+    // block, so they are made with the store unlocked (see `AuthService::verify_password`).
+    fn directory(&self) -> Option<Arc<dyn Directory>>;
+    // The user the directory verified as `identity`, known as `username` from then on.
+    fn directory_user(&self, username: &str, identity: &str) -> Option<String>;
+    // Compare-and-swap of the password: only replaced while `current_password` is still the user's,
+    // so of two changes starting from the same password, one wins. Ok(false) when it isn't.
+    fn update_password_if_matches(&mut self, user_uuid: &str, current_password: &str, new_password: &str) -> Result<bool, StoreError>;
+    // Hashes the user's password again under the current `PasswordPolicy`, when it was hashed
+    // under an older one, from the password they just signed in with. Returns whether it was.
+    fn upgrade_password_hash(&mut self, user_uuid: &str, password: &str) -> Result<bool, StoreError>;
+    // How many passwords are still hashed under an older policy, waiting for their user to sign in.
+    fn outdated_password_hashes(&self) -> usize;
     fn delete_user(&mut self, user_uuid: String);
+    fn link_external_user(&mut self, provider: &str, subject: &str) -> Result<String, StoreError>;
+    fn find_user_uuid(&self, username: &str) -> Option<String>;
+    fn get_username(&self, user_uuid: &str) -> Option<String>;
+    fn get_account_status(&self, user_uuid: &str) -> Option<AccountStatus>;
+    fn set_account_status(&mut self, user_uuid: &str, status: AccountStatus) -> Result<(), StoreError>;
+    // Returns the old username. The uuid, and so the user's sessions, stay the same.
+    fn change_username(&mut self, user_uuid: &str, new_username: &str) -> Result<String, StoreError>;
+    // Small key/value pairs client applications keep per user (locale, theme, ...).
+    fn get_metadata(&self, user_uuid: &str) -> Option<HashMap<String, String>>;
+    // An empty value removes the key.
+    fn set_metadata(&mut self, user_uuid: &str, key: &str, value: &str) -> Result<(), StoreError>;
+    // Marks the account `Deleted`. Until it is purged, setting it back to `Active` undoes this.
+    fn request_deletion(&mut self, user_uuid: &str) -> Result<(), StoreError>;
+    fn deletion_requested_at(&self, user_uuid: &str) -> Option<SystemTime>;
+    fn users_deleted_before(&self, cutoff: SystemTime) -> Vec<String>;
+    // Marks the account `SoftDeleted`, for an operator: hidden from sign-in and listings, but kept
+    // until purged, so setting it back to `Active` restores it.
+    fn soft_delete(&mut self, user_uuid: &str) -> Result<(), StoreError>;
+    fn users_soft_deleted_before(&self, cutoff: SystemTime) -> Vec<String>;
+    fn get_accepted_terms_version(&self, user_uuid: &str) -> Option<String>;
+    fn set_accepted_terms_version(&mut self, user_uuid: &str, version: &str) -> Result<(), StoreError>;
+    // What the user's sessions may do on top of the defaults (AUTH_DEFAULT_SCOPES), e.g. "admin".
+    // Granted by operators; sessions get them when created, so a change shows at the next sign in.
+    fn get_scopes(&self, user_uuid: &str) -> Vec<String>;
+    // Replaces the user's scopes; an empty list takes them all away.
+    fn set_scopes(&mut self, user_uuid: &str, scopes: &[String]) -> Result<(), StoreError>;
+    // The tenants the user has had sessions for (see `request_tenant`), sorted. Kept once they
+    // sign out, as what an admin of a tenant may act on (see `AuthService::authorize_admin`).
+    fn get_tenants(&self, user_uuid: &str) -> Vec<String>;
+    // Notes a tenant the user has a session for; nothing changes when it is noted already.
+    fn add_tenant(&mut self, user_uuid: &str, tenant: &str) -> Result<(), StoreError>;
+    // The user's passkeys (see `RelyingParty`), in the order they were registered.
+    fn get_webauthn_credentials(&self, user_uuid: &str) -> Vec<WebAuthnCredential>;
+    // Whose passkey this is, and the passkey.
+    fn find_webauthn_credential(&self, credential_id: &[u8]) -> Option<(String, WebAuthnCredential)>;
+    // Refused when the credential is registered already, to this user or another.
+    fn add_webauthn_credential(&mut self, user_uuid: &str, credential: WebAuthnCredential) -> Result<(), StoreError>;
+    // The authenticator's signature counter, as of the latest sign-in with the passkey.
+    fn set_webauthn_sign_count(&mut self, credential_id: &[u8], sign_count: u32) -> Result<(), StoreError>;
+    fn list_users(&self, query: &UserQuery) -> Result<UserPage, StoreError>;
+    // How many users the store holds, for the capacity limits (see `AuthService::check_capacity`).
+    fn user_count(&self) -> usize;
+    // What the ids of new users look like, e.g. "uuid-v7" (see `IdScheme`).
+    fn id_scheme(&self) -> &'static str;
+    // The user as it is now, as the changes that recreate it in another store (see `migrate`);
+    // None for unknown users, or a store that can't hand out its password hashes.
+    fn export_user(&self, user_uuid: &str) -> Option<Vec<UserEvent>>;
+    // Recreates a user from `export_user`. Refused when the uuid or username is already taken.
+    fn import_user(&mut self, events: Vec<UserEvent>) -> Result<(), StoreError>;
+    // Whether the store works, for failing fast on start and for readiness (see `Readiness`).
+    fn check_store(&self) -> Result<(), StoreError>;
+    // See `UsersImpl::with_journal`.
+    fn replay(&mut self, until: Option<SystemTime>) -> Result<usize, StoreError>;
+}
+
+pub const MAX_USERNAME_LEN: usize = 256;
+pub const MAX_METADATA_ENTRIES: usize = 32;
+pub const MAX_METADATA_KEY_LEN: usize = 64;
+pub const MAX_METADATA_VALUE_LEN: usize = 1024;
+pub const MAX_SCOPES: usize = 32;
+pub const MAX_SCOPE_LEN: usize = 64;
+
+// Applies a `set_metadata` to a user's pairs, within the limits above.
+pub fn set_metadata_entry(metadata: &mut HashMap<String, String>, key: &str, value: &str) -> Result<(), StoreError> {
+    if key.is_empty() || key.len() > MAX_METADATA_KEY_LEN { return Err(StoreError::InvalidMetadataKey)};
+    if value.len() > MAX_METADATA_VALUE_LEN { return Err(StoreError::MetadataValueTooLong)};
+
+    if value.is_empty() {
+        metadata.remove(key);
+        return Ok(());
+    }
+
+    if !metadata.contains_key(key) && metadata.len() >= MAX_METADATA_ENTRIES {
+        return Err(StoreError::TooManyMetadataEntries);
+    }
+
+    metadata.insert(key.to_owned(), value.to_owned());
+    Ok(())
+}
+
+// Scopes go into tokens as a space separated list ("profile:read admin"), so they can't have
+// spaces of their own.
+pub fn check_scopes(scopes: &[String]) -> Result<(), StoreError> {
+    if scopes.len() > MAX_SCOPES { return Err(StoreError::TooManyScopes)};
+
+    let valid = |scope: &String| {
+        !scope.is_empty()
+            && scope.len() <= MAX_SCOPE_LEN
+            && scope.chars().all(|c| c.is_ascii_alphanumeric() || ":._-".contains(c))
+    };
+    if !scopes.iter().all(valid) { return Err(StoreError::InvalidScope)};
+
+    Ok(())
+}
+
+// How passwords are hashed with PBKDF2, the built-in `PasswordHasher`. Each hash names the
+// algorithm and rounds it was made with (as a PHC string), so changing these leaves existing
+// hashes readable; they are moved over one by one as their users sign in (see
+// `UsersOps::upgrade_password_hash`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    pub algorithm: Algorithm,
+    pub rounds: u32,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self { algorithm: Algorithm::Pbkdf2Sha256, rounds: Params::RECOMMENDED_ROUNDS as u32 }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn hash(&self, password: &str) -> Result<String, HashError> {
+        let salt = SaltString::generate(&mut OsRng);
+        let params = Params { rounds: self.rounds, ..Params::default() };
+
+        Pbkdf2
+            .hash_password_customized(password.as_bytes(), Some(self.algorithm.ident()), None, params, &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| HashError(format!("{e:?}")))
+    }
+
+    // Made with another algorithm, or fewer rounds. Hashes that can't be read are left alone: they
+    // never verify, so there is nothing to move them over from.
+    pub fn is_outdated(&self, password_hash: &str) -> bool {
+        PasswordHash::new(password_hash).is_ok_and(|parsed_hash| PasswordHasher::is_outdated(self, &parsed_hash))
+    }
+}
+
+impl PasswordHasher for PasswordPolicy {
+    fn handles(&self, algorithm: &str) -> bool {
+        [Algorithm::Pbkdf2Sha256, Algorithm::Pbkdf2Sha512].iter().any(|known| known.ident().as_str() == algorithm)
+    }
+
+    fn hash(&self, password: &str) -> Result<String, HashError> {
+        PasswordPolicy::hash(self, password)
+    }
+
+    fn verify(&self, password_hash: &PasswordHash, password: &str) -> bool {
+        Pbkdf2.verify_password(password.as_bytes(), password_hash).is_ok()
+    }
+
+    fn is_outdated(&self, password_hash: &PasswordHash) -> bool {
+        let Ok(params) = Params::try_from(password_hash) else { return false };
+        password_hash.algorithm != self.algorithm.ident() || params.rounds < self.rounds
+    }
+}
+
+// Usernames that only differ in case or surrounding whitespace are considered the same.
+pub fn normalize_username(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    Suspended,
+    // Waiting to be purged, see `UsersOps::request_deletion`.
+    Deleted,
+    // No flow moves accounts here yet, but sign_in already refuses them.
+    #[allow(dead_code)]
+    PendingVerification,
+    // Signed up while `Flag::ApprovalRequired` was on; see `ApproveUser` and `RejectUser`.
+    PendingApproval,
+    // Taken down by an operator, see `UsersOps::soft_delete`.
+    SoftDeleted,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UserOrder {
+    #[default]
+    CreatedAt,
+    Username,
+}
+
+// Which users `UsersOps::list_users` returns a page of, and in what order.
+#[derive(Clone, Debug, Default)]
+pub struct UserQuery {
+    pub status: Option<AccountStatus>,
+    pub created_after: Option<SystemTime>,
+    pub order: UserOrder,
+    pub descending: bool,
+    // Where the previous page left off, see `UserPage::next_cursor`.
+    pub cursor: Option<String>,
+    pub limit: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserSummary {
+    pub user_uuid: String,
+    pub username: String,
+    pub status: AccountStatus,
+    pub created_at: SystemTime,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserPage {
+    pub users: Vec<UserSummary>,
+    // None on the last page. Opaque to callers; only good for the same order.
+    pub next_cursor: Option<String>,
+}
+
+// Cursors hold the sort key of the last user on a page: `c:<nanos>.<uuid>` by creation time (the
+// uuid tells apart users created at the same time), `u:<username>` by username.
+fn cursor_of(user: &User, order: UserOrder) -> String {
+    match order {
+        UserOrder::CreatedAt => {
+            let nanos = user.created_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+            format!("c:{nanos}.{}", user.user_uuid)
+        }
+        UserOrder::Username => format!("u:{}", user.username),
+    }
+}
+
+fn created_at_cursor(cursor: &str) -> Result<(SystemTime, String), StoreError> {
+    let invalid = || StoreError::InvalidCursor;
+
+    let (nanos, user_uuid) = cursor.strip_prefix("c:").and_then(|key| key.split_once('.')).ok_or_else(invalid)?;
+    let nanos = nanos.parse().map_err(|_| invalid())?;
+
+    Ok((UNIX_EPOCH + Duration::from_nanos(nanos), user_uuid.to_owned()))
+}
+
+fn username_cursor(cursor: &str) -> Result<String, StoreError> {
+    cursor.strip_prefix("u:").map(str::to_owned).ok_or(StoreError::InvalidCursor)
+}
+
+// The entries of an ordered index past `cursor`, in the direction asked for.
+fn entries_after<'a, K: Ord, V>(
+    index: &'a BTreeMap<K, V>,
+    cursor: Option<&K>,
+    descending: bool,
+) -> Box<dyn Iterator<Item = (&'a K, &'a V)> + 'a> {
+    match (cursor, descending) {
+        (None, false) => Box::new(index.iter()),
+        (None, true) => Box::new(index.iter().rev()),
+        (Some(cursor), false) => Box::new(index.range((Excluded(cursor), Unbounded))),
+        (Some(cursor), true) => Box::new(index.range((Unbounded, Excluded(cursor))).rev()),
+    }
 }
 
 #[derive(Clone,Debug)]
@@ -18,68 +290,633 @@ pub struct User {
     user_uuid: String,
     username: String,
     password: String,
+    status: AccountStatus,
+    created_at: SystemTime,
+}
+
+// The changes to a `UsersImpl`. Each mutation is checked, then recorded as one of these: appended
+// to the journal, when there is one, and applied. Replaying the journal gives the users back.
+// Passwords are only ever in here hashed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum UserEvent {
+    UserCreated { user_uuid: String, username: String, password_hash: String, created_at: SystemTime },
+    ExternalUserLinked { provider: String, subject: String, user_uuid: String, created_at: SystemTime },
+    UserDeleted { user_uuid: String },
+    AccountStatusSet { user_uuid: String, status: AccountStatus },
+    DeletionRequested { user_uuid: String, requested_at: SystemTime },
+    SoftDeleted { user_uuid: String, deleted_at: SystemTime },
+    UsernameChanged { user_uuid: String, new_username: String },
+    MetadataSet { user_uuid: String, key: String, value: String },
+    TermsAccepted { user_uuid: String, version: String },
+    ScopesSet { user_uuid: String, scopes: Vec<String> },
+    TenantAdded { user_uuid: String, tenant: String },
+    WebAuthnCredentialAdded { user_uuid: String, credential: WebAuthnCredential },
+    WebAuthnSignCountSet { user_uuid: String, credential_id: Vec<u8>, sign_count: u32 },
+    PasswordRehashed { user_uuid: String, password_hash: String },
+    PasswordChanged { user_uuid: String, password_hash: String },
+    // First in a journal, when it is started (see `UsersImpl::with_journal`).
+    IdSchemeChosen { scheme: String },
 }
 
 #[derive(Default,Debug)]
 pub struct UsersImpl {
     uuid_to_user: HashMap<String, User>,
-    username_to_user: HashMap<String, User>,
+    // Ordered, so users can be listed by name a page at a time.
+    username_to_user: BTreeMap<String, User>,
+    // The same for listing them by when they were created.
+    created_index: BTreeMap<(SystemTime, String), ()>,
+    external_to_uuid: HashMap<(String, String), String>,
+    uuid_to_metadata: HashMap<String, HashMap<String, String>>,
+    uuid_to_deletion: HashMap<String, SystemTime>,
+    uuid_to_soft_deletion: HashMap<String, SystemTime>,
+    uuid_to_terms_version: HashMap<String, String>,
+    uuid_to_scopes: HashMap<String, Vec<String>>,
+    uuid_to_tenants: HashMap<String, BTreeSet<String>>,
+    uuid_to_credentials: HashMap<String, Vec<WebAuthnCredential>>,
+    credential_to_uuid: HashMap<Vec<u8>, String>,
+    password_hashers: PasswordHashers,
+    ids: Ids,
+    id_scheme: IdScheme,
+    // Set in event sourcing mode.
+    journal: Option<Box<dyn Journal<UserEvent>>>,
 }
 
-impl UsersOps for UsersImpl {
-    fn create_user(&mut self, username: String, password: String) -> Result<(), String> {
-        // TODO: Check if username already exist. If so return an error.
+impl UsersImpl {
+    pub fn with_id_generator(mut self, ids: Ids) -> Self {
+        self.ids = ids;
+        self
+    }
 
-        if self.username_to_user.contains_key(&username) { return Err(String::from("Error::UserAlreadyExists"))};
+    // For the ids of new users; a journal keeps the one it was started with.
+    pub fn with_id_scheme(mut self, id_scheme: IdScheme) -> Self {
+        self.id_scheme = id_scheme;
+        self
+    }
 
-        let salt = SaltString::generate(&mut OsRng);
+    pub fn with_password_policy(self, password_policy: PasswordPolicy) -> Self {
+        self.with_password_hasher(Arc::new(password_policy))
+    }
+
+    // Hashes new passwords with `hasher` instead of PBKDF2, e.g. argon2id; PBKDF2 hashes still
+    // verify, and are moved over as their users sign in.
+    pub fn with_password_hasher(mut self, hasher: Arc<dyn PasswordHasher>) -> Self {
+        self.password_hashers = PasswordHashers::new(hasher);
+        self
+    }
+
+    // Event sourcing mode: the users are whatever the journal says, and every change goes through
+    // it first. A new journal starts with the id scheme; one started before there was a choice
+    // has v4 UUIDs.
+    pub fn with_journal(mut self, journal: Box<dyn Journal<UserEvent>>) -> Result<Self, StoreError> {
+        let id_scheme = self.id_scheme;
+        self.journal = Some(journal);
+        if self.replay(None)? == 0 {
+            self.record(UserEvent::IdSchemeChosen { scheme: id_scheme.to_string() })?;
+        } else if self.id_scheme != id_scheme {
+            warn!("the users journal has {} ids, keeping them rather than {}", self.id_scheme, id_scheme);
+        }
+        Ok(self)
+    }
+
+    fn record(&mut self, event: UserEvent) -> Result<(), StoreError> {
+        if let Some(journal) = &self.journal {
+            journal.append(&event)?;
+        }
+        self.apply(event);
+        Ok(())
+    }
+
+    fn apply(&mut self, event: UserEvent) {
+        match event {
+            UserEvent::UserCreated { user_uuid, username, password_hash, created_at } => {
+                self.insert_user(User { user_uuid, username, password: password_hash, status: AccountStatus::Active, created_at });
+            }
+            UserEvent::ExternalUserLinked { provider, subject, user_uuid, created_at } => {
+                let username = format!("{provider}:{subject}");
+                self.insert_user(User { user_uuid: user_uuid.clone(), username, password: String::new(), status: AccountStatus::Active, created_at });
+                self.external_to_uuid.insert((provider, subject), user_uuid);
+            }
+            UserEvent::UserDeleted { user_uuid } => {
+                if let Some(an_existing_user) = self.uuid_to_user.remove_entry(&user_uuid) {
+                    self.username_to_user.remove(&an_existing_user.1.username);
+                    self.created_index.remove(&(an_existing_user.1.created_at, user_uuid.clone()));
+                };
+
+                self.external_to_uuid.retain(|_, uuid| *uuid != user_uuid);
+                self.uuid_to_metadata.remove(&user_uuid);
+                self.uuid_to_deletion.remove(&user_uuid);
+                self.uuid_to_soft_deletion.remove(&user_uuid);
+                self.uuid_to_terms_version.remove(&user_uuid);
+                self.uuid_to_scopes.remove(&user_uuid);
+                self.uuid_to_tenants.remove(&user_uuid);
+                for credential in self.uuid_to_credentials.remove(&user_uuid).into_iter().flatten() {
+                    self.credential_to_uuid.remove(&credential.credential_id);
+                }
+            }
+            UserEvent::AccountStatusSet { user_uuid, status } => {
+                self.update_user(&user_uuid, |user| user.status = status);
+                if status != AccountStatus::Deleted {
+                    self.uuid_to_deletion.remove(&user_uuid);
+                }
+                if status != AccountStatus::SoftDeleted {
+                    self.uuid_to_soft_deletion.remove(&user_uuid);
+                }
+            }
+            UserEvent::DeletionRequested { user_uuid, requested_at } => {
+                self.update_user(&user_uuid, |user| user.status = AccountStatus::Deleted);
+                self.uuid_to_deletion.insert(user_uuid, requested_at);
+            }
+            UserEvent::SoftDeleted { user_uuid, deleted_at } => {
+                self.update_user(&user_uuid, |user| user.status = AccountStatus::SoftDeleted);
+                self.uuid_to_deletion.remove(&user_uuid);
+                self.uuid_to_soft_deletion.insert(user_uuid, deleted_at);
+            }
+            UserEvent::UsernameChanged { user_uuid, new_username } => {
+                let Some(user) = self.uuid_to_user.get_mut(&user_uuid) else { return };
+                let old_username = std::mem::replace(&mut user.username, new_username.clone());
+
+                // Re-key the second map under the new name; the old one is free to be taken again.
+                self.username_to_user.remove(&old_username);
+                self.username_to_user.insert(new_username, user.clone());
+            }
+            UserEvent::MetadataSet { user_uuid, key, value } => {
+                let _ = set_metadata_entry(self.uuid_to_metadata.entry(user_uuid).or_default(), &key, &value);
+            }
+            UserEvent::TermsAccepted { user_uuid, version } => {
+                self.uuid_to_terms_version.insert(user_uuid, version);
+            }
+            UserEvent::ScopesSet { user_uuid, scopes } if scopes.is_empty() => {
+                self.uuid_to_scopes.remove(&user_uuid);
+            }
+            UserEvent::ScopesSet { user_uuid, scopes } => {
+                self.uuid_to_scopes.insert(user_uuid, scopes);
+            }
+            UserEvent::TenantAdded { user_uuid, tenant } => {
+                self.uuid_to_tenants.entry(user_uuid).or_default().insert(tenant);
+            }
+            UserEvent::WebAuthnCredentialAdded { user_uuid, credential } => {
+                self.credential_to_uuid.insert(credential.credential_id.clone(), user_uuid.clone());
+                self.uuid_to_credentials.entry(user_uuid).or_default().push(credential);
+            }
+            UserEvent::WebAuthnSignCountSet { user_uuid, credential_id, sign_count } => {
+                let credentials = self.uuid_to_credentials.get_mut(&user_uuid).into_iter().flatten();
+                for credential in credentials.filter(|credential| credential.credential_id == credential_id) {
+                    credential.sign_count = sign_count;
+                }
+            }
+            UserEvent::PasswordRehashed { user_uuid, password_hash } | UserEvent::PasswordChanged { user_uuid, password_hash } => {
+                self.update_user(&user_uuid, |user| user.password = password_hash.clone());
+            }
+            UserEvent::IdSchemeChosen { scheme } => match scheme.parse() {
+                Ok(id_scheme) => self.id_scheme = id_scheme,
+                Err(e) => warn!("ignoring the id scheme in the users journal: {}", e),
+            },
+        }
+    }
+
+    fn insert_user(&mut self, user: User) {
+        self.created_index.insert((user.created_at, user.user_uuid.clone()), ());
+        self.uuid_to_user.insert(user.user_uuid.clone(), user.clone());
+        self.username_to_user.insert(user.username.clone(), user);
+    }
+
+    // Both maps hold their own copy of the user.
+    fn update_user(&mut self, user_uuid: &str, update: impl Fn(&mut User)) {
+        if let Some(user) = self.uuid_to_user.get_mut(user_uuid) {
+            update(user);
+            if let Some(user) = self.username_to_user.get_mut(&user.username) {
+                update(user);
+            }
+        }
+    }
+}
 
-        let hashed_password = Pbkdf2
-            .hash_password(password.as_bytes(), &salt)
-            .map_err(|e| format!("Failed to hash password.\n{e:?}"))?
-            .to_string();
+impl UsersOps for UsersImpl {
+    fn create_user(&mut self, username: String, password: String) -> Result<(), StoreError> {
+        self.create_user_with_uuid(self.id_scheme.new_id(&self.ids), username, password)
+    }
 
-        let user_uuid = Uuid::new_v4().to_string();
+    fn create_user_with_uuid(&mut self, user_uuid: String, username: String, password: String) -> Result<(), StoreError> {
+        if username.trim().is_empty() || username.len() > MAX_USERNAME_LEN { return Err(StoreError::InvalidUsername)};
+        if password.is_empty() { return Err(StoreError::InvalidPassword)};
+        if self.username_to_user.contains_key(&username) { return Err(StoreError::UserAlreadyExists)};
+        if self.uuid_to_user.contains_key(&user_uuid) { return Err(StoreError::UserUuidAlreadyExists)};
 
-        let user: User = User { username: username.clone(), user_uuid: user_uuid.clone(), password: hashed_password };
+        let hashed_password = self.password_hashers.hash(&password)?;
 
-        self.uuid_to_user.insert(user_uuid, user.clone());
-        self.username_to_user.insert(username,user);
+        self.record(UserEvent::UserCreated { user_uuid, username, password_hash: hashed_password, created_at: SystemTime::now() })?;
 
-        println!("username_to_user {:?}",self.username_to_user);
+        debug!("username_to_user {:?}",self.username_to_user);
 
         Ok(())
     }
 
-    // TODO [NS]: Rewrite this function as a series of map/and_then transformations
+    // `create_user_with_uuid` looks the username up and inserts it under the same borrow.
+    fn create_if_absent(&mut self, username: String, password: String) -> Result<bool, StoreError> {
+        match self.create_user(username, password) {
+            Ok(()) => Ok(true),
+            Err(StoreError::UserAlreadyExists) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
     fn get_user_uuid(&self, username: String, password: String) -> Option<String> {
+        self.username_to_user
+            .get(&username)
+            .filter(|an_existing_user| self.password_hashers.verify(&an_existing_user.password, &password))
+            .map(|an_existing_user| an_existing_user.user_uuid.clone())
+    }
+
+    // The passwords are here.
+    fn directory(&self) -> Option<Arc<dyn Directory>> {
+        None
+    }
+
+    fn directory_user(&self, _username: &str, _identity: &str) -> Option<String> {
+        None
+    }
+
+    // The password is checked again, so a wrong one can never end up stored.
+    fn upgrade_password_hash(&mut self, user_uuid: &str, password: &str) -> Result<bool, StoreError> {
+        let user = self.uuid_to_user.get(user_uuid).ok_or(StoreError::UserNotFound)?;
+        if !self.password_hashers.is_outdated(&user.password) { return Ok(false) };
+        if !self.password_hashers.verify(&user.password, password) { return Err(StoreError::InvalidPassword) };
+
+        let password_hash = self.password_hashers.hash(password)?;
+        self.record(UserEvent::PasswordRehashed { user_uuid: user_uuid.to_owned(), password_hash })?;
+
+        Ok(true)
+    }
+
+    fn update_password_if_matches(&mut self, user_uuid: &str, current_password: &str, new_password: &str) -> Result<bool, StoreError> {
+        let user = self.uuid_to_user.get(user_uuid).ok_or(StoreError::UserNotFound)?;
+        if !self.password_hashers.verify(&user.password, current_password) { return Ok(false) };
+        if new_password.is_empty() { return Err(StoreError::InvalidPassword) };
+
+        let password_hash = self.password_hashers.hash(new_password)?;
+        self.record(UserEvent::PasswordChanged { user_uuid: user_uuid.to_owned(), password_hash })?;
+
+        Ok(true)
+    }
+
+    fn outdated_password_hashes(&self) -> usize {
+        self.uuid_to_user.values().filter(|user| self.password_hashers.is_outdated(&user.password)).count()
+    }
 
-        let maybe_an_existing_user = self.username_to_user.get(&username)?;
-        let maybe_a_hashed_password = maybe_an_existing_user.password.clone();
-        let parsed_hash = PasswordHash::new(&maybe_a_hashed_password).ok()?;
-        if let Some(result_of_verification) = Pbkdf2.verify_password(password.as_bytes(), &parsed_hash).ok() {
-            return Some(maybe_an_existing_user.user_uuid.clone());
+    fn delete_user(&mut self, user_uuid: String) {
+        if let Err(e) = self.record(UserEvent::UserDeleted { user_uuid }) {
+            warn!("failed to delete user: {}", e);
         }
-        else {
-            return None;
+    }
+
+    // Returns the uuid of the local user linked to `subject` at `provider`, provisioning one on
+    // first use. Provisioned users have no password, so they can only sign in through the provider.
+    fn link_external_user(&mut self, provider: &str, subject: &str) -> Result<String, StoreError> {
+        let external_id = (provider.to_owned(), subject.to_owned());
+
+        if let Some(user_uuid) = self.external_to_uuid.get(&external_id) {
+            return Ok(user_uuid.clone());
         }
 
+        let username = format!("{provider}:{subject}");
+
+        if self.username_to_user.contains_key(&username) { return Err(StoreError::UserAlreadyExists)};
+
+        let user_uuid = self.id_scheme.new_id(&self.ids);
+
+        self.record(UserEvent::ExternalUserLinked {
+            provider: provider.to_owned(),
+            subject: subject.to_owned(),
+            user_uuid: user_uuid.clone(),
+            created_at: SystemTime::now(),
+        })?;
+
+        Ok(user_uuid)
     }
 
-    fn delete_user(&mut self, user_uuid: String) {
-        // TODO: Remove user from `username_to_user` and `uuid_to_user`.
+    // Looks a user up without checking their password; for flows that authenticate by other means.
+    fn find_user_uuid(&self, username: &str) -> Option<String> {
+        self.username_to_user
+            .get(username)
+            .map(|an_existing_user| an_existing_user.user_uuid.clone())
+    }
+
+    fn get_username(&self, user_uuid: &str) -> Option<String> {
+        self.uuid_to_user.get(user_uuid).map(|user| user.username.clone())
+    }
+
+    fn get_account_status(&self, user_uuid: &str) -> Option<AccountStatus> {
+        self.uuid_to_user.get(user_uuid).map(|user| user.status)
+    }
+
+    fn set_account_status(&mut self, user_uuid: &str, status: AccountStatus) -> Result<(), StoreError> {
+        if !self.uuid_to_user.contains_key(user_uuid) { return Err(StoreError::UserNotFound)};
+
+        self.record(UserEvent::AccountStatusSet { user_uuid: user_uuid.to_owned(), status })
+    }
+
+    fn change_username(&mut self, user_uuid: &str, new_username: &str) -> Result<String, StoreError> {
+        let new_username = new_username.trim();
+        if new_username.is_empty() || new_username.len() > MAX_USERNAME_LEN { return Err(StoreError::InvalidUsername)};
+
+        let normalized = normalize_username(new_username);
+        let taken = self
+            .username_to_user
+            .values()
+            .any(|user| user.user_uuid != user_uuid && normalize_username(&user.username) == normalized);
+        if taken { return Err(StoreError::UserAlreadyExists)};
+
+        let old_username = self.get_username(user_uuid).ok_or(StoreError::UserNotFound)?;
+        self.record(UserEvent::UsernameChanged { user_uuid: user_uuid.to_owned(), new_username: new_username.to_owned() })?;
+
+        Ok(old_username)
+    }
+
+    fn get_metadata(&self, user_uuid: &str) -> Option<HashMap<String, String>> {
+        self.uuid_to_user
+            .contains_key(user_uuid)
+            .then(|| self.uuid_to_metadata.get(user_uuid).cloned().unwrap_or_default())
+    }
+
+    fn set_metadata(&mut self, user_uuid: &str, key: &str, value: &str) -> Result<(), StoreError> {
+        if !self.uuid_to_user.contains_key(user_uuid) { return Err(StoreError::UserNotFound)};
+
+        // Tried on a copy first, so only changes that are within the limits get recorded.
+        let mut metadata = self.uuid_to_metadata.get(user_uuid).cloned().unwrap_or_default();
+        set_metadata_entry(&mut metadata, key, value)?;
+
+        self.record(UserEvent::MetadataSet { user_uuid: user_uuid.to_owned(), key: key.to_owned(), value: value.to_owned() })
+    }
+
+    fn request_deletion(&mut self, user_uuid: &str) -> Result<(), StoreError> {
+        if !self.uuid_to_user.contains_key(user_uuid) { return Err(StoreError::UserNotFound)};
+
+        self.record(UserEvent::DeletionRequested { user_uuid: user_uuid.to_owned(), requested_at: SystemTime::now() })
+    }
+
+    fn deletion_requested_at(&self, user_uuid: &str) -> Option<SystemTime> {
+        self.uuid_to_deletion.get(user_uuid).copied()
+    }
+
+    fn users_deleted_before(&self, cutoff: SystemTime) -> Vec<String> {
+        self.uuid_to_deletion
+            .iter()
+            .filter(|(_, requested_at)| **requested_at < cutoff)
+            .map(|(user_uuid, _)| user_uuid.clone())
+            .collect()
+    }
+
+    fn soft_delete(&mut self, user_uuid: &str) -> Result<(), StoreError> {
+        if !self.uuid_to_user.contains_key(user_uuid) { return Err(StoreError::UserNotFound)};
+
+        self.record(UserEvent::SoftDeleted { user_uuid: user_uuid.to_owned(), deleted_at: SystemTime::now() })
+    }
+
+    fn users_soft_deleted_before(&self, cutoff: SystemTime) -> Vec<String> {
+        self.uuid_to_soft_deletion
+            .iter()
+            .filter(|(_, deleted_at)| **deleted_at < cutoff)
+            .map(|(user_uuid, _)| user_uuid.clone())
+            .collect()
+    }
+
+    fn get_accepted_terms_version(&self, user_uuid: &str) -> Option<String> {
+        self.uuid_to_terms_version.get(user_uuid).cloned()
+    }
+
+    fn set_accepted_terms_version(&mut self, user_uuid: &str, version: &str) -> Result<(), StoreError> {
+        if !self.uuid_to_user.contains_key(user_uuid) { return Err(StoreError::UserNotFound)};
+
+        self.record(UserEvent::TermsAccepted { user_uuid: user_uuid.to_owned(), version: version.to_owned() })
+    }
+
+    fn get_scopes(&self, user_uuid: &str) -> Vec<String> {
+        self.uuid_to_scopes.get(user_uuid).cloned().unwrap_or_default()
+    }
+
+    fn set_scopes(&mut self, user_uuid: &str, scopes: &[String]) -> Result<(), StoreError> {
+        if !self.uuid_to_user.contains_key(user_uuid) { return Err(StoreError::UserNotFound)};
+        check_scopes(scopes)?;
+
+        self.record(UserEvent::ScopesSet { user_uuid: user_uuid.to_owned(), scopes: scopes.to_vec() })
+    }
+
+    fn get_tenants(&self, user_uuid: &str) -> Vec<String> {
+        self.uuid_to_tenants.get(user_uuid).into_iter().flatten().cloned().collect()
+    }
+
+    fn add_tenant(&mut self, user_uuid: &str, tenant: &str) -> Result<(), StoreError> {
+        if !self.uuid_to_user.contains_key(user_uuid) { return Err(StoreError::UserNotFound)};
+        if self.uuid_to_tenants.get(user_uuid).is_some_and(|tenants| tenants.contains(tenant)) {
+            return Ok(());
+        }
+
+        self.record(UserEvent::TenantAdded { user_uuid: user_uuid.to_owned(), tenant: tenant.to_owned() })
+    }
+
+    fn get_webauthn_credentials(&self, user_uuid: &str) -> Vec<WebAuthnCredential> {
+        self.uuid_to_credentials.get(user_uuid).cloned().unwrap_or_default()
+    }
+
+    fn find_webauthn_credential(&self, credential_id: &[u8]) -> Option<(String, WebAuthnCredential)> {
+        let user_uuid = self.credential_to_uuid.get(credential_id)?;
+        let credential = self.uuid_to_credentials.get(user_uuid)?.iter().find(|credential| credential.credential_id == credential_id)?;
+        Some((user_uuid.clone(), credential.clone()))
+    }
+
+    fn add_webauthn_credential(&mut self, user_uuid: &str, credential: WebAuthnCredential) -> Result<(), StoreError> {
+        if !self.uuid_to_user.contains_key(user_uuid) { return Err(StoreError::UserNotFound)};
+        if self.credential_to_uuid.contains_key(&credential.credential_id) { return Err(StoreError::CredentialAlreadyRegistered)};
+
+        self.record(UserEvent::WebAuthnCredentialAdded { user_uuid: user_uuid.to_owned(), credential })
+    }
+
+    fn set_webauthn_sign_count(&mut self, credential_id: &[u8], sign_count: u32) -> Result<(), StoreError> {
+        let user_uuid = self.credential_to_uuid.get(credential_id).ok_or(StoreError::CredentialNotFound)?.clone();
 
-        if let Some(an_existing_user) = self.uuid_to_user.remove_entry(&user_uuid) {
-            self.username_to_user.remove(&an_existing_user.1.username);
+        self.record(UserEvent::WebAuthnSignCountSet { user_uuid, credential_id: credential_id.to_vec(), sign_count })
+    }
+
+    fn user_count(&self) -> usize {
+        self.uuid_to_user.len()
+    }
+
+    fn id_scheme(&self) -> &'static str {
+        self.id_scheme.as_str()
+    }
+
+    fn export_user(&self, user_uuid: &str) -> Option<Vec<UserEvent>> {
+        let user = self.uuid_to_user.get(user_uuid)?;
+        let user_uuid = user_uuid.to_owned();
+        let external = self.external_to_uuid.iter().find(|(_, uuid)| **uuid == user_uuid).map(|(external, _)| external);
+
+        let mut events = match external {
+            Some((provider, subject)) => {
+                let mut events = vec![UserEvent::ExternalUserLinked {
+                    provider: provider.clone(),
+                    subject: subject.clone(),
+                    user_uuid: user_uuid.clone(),
+                    created_at: user.created_at,
+                }];
+                if user.username != format!("{provider}:{subject}") {
+                    events.push(UserEvent::UsernameChanged { user_uuid: user_uuid.clone(), new_username: user.username.clone() });
+                }
+                events
+            }
+            None => vec![UserEvent::UserCreated {
+                user_uuid: user_uuid.clone(),
+                username: user.username.clone(),
+                password_hash: user.password.clone(),
+                created_at: user.created_at,
+            }],
         };
 
-        return
+        if let Some(requested_at) = self.uuid_to_deletion.get(&user_uuid) {
+            events.push(UserEvent::DeletionRequested { user_uuid: user_uuid.clone(), requested_at: *requested_at });
+        }
+        if let Some(deleted_at) = self.uuid_to_soft_deletion.get(&user_uuid) {
+            events.push(UserEvent::SoftDeleted { user_uuid: user_uuid.clone(), deleted_at: *deleted_at });
+        } else if user.status != AccountStatus::Active {
+            events.push(UserEvent::AccountStatusSet { user_uuid: user_uuid.clone(), status: user.status });
+        }
+        // Sorted, so the same user always exports the same.
+        let metadata: BTreeMap<_, _> = self.uuid_to_metadata.get(&user_uuid).into_iter().flatten().collect();
+        for (key, value) in metadata {
+            events.push(UserEvent::MetadataSet { user_uuid: user_uuid.clone(), key: key.clone(), value: value.clone() });
+        }
+        if let Some(version) = self.uuid_to_terms_version.get(&user_uuid) {
+            events.push(UserEvent::TermsAccepted { user_uuid: user_uuid.clone(), version: version.clone() });
+        }
+        if let Some(scopes) = self.uuid_to_scopes.get(&user_uuid) {
+            events.push(UserEvent::ScopesSet { user_uuid: user_uuid.clone(), scopes: scopes.clone() });
+        }
+        for tenant in self.uuid_to_tenants.get(&user_uuid).into_iter().flatten() {
+            events.push(UserEvent::TenantAdded { user_uuid: user_uuid.clone(), tenant: tenant.clone() });
+        }
+        for credential in self.uuid_to_credentials.get(&user_uuid).into_iter().flatten() {
+            events.push(UserEvent::WebAuthnCredentialAdded { user_uuid: user_uuid.clone(), credential: credential.clone() });
+        }
+
+        Some(events)
+    }
+
+    fn import_user(&mut self, events: Vec<UserEvent>) -> Result<(), StoreError> {
+        let (user_uuid, username) = match events.first() {
+            Some(UserEvent::UserCreated { user_uuid, username, .. }) => (user_uuid.clone(), username.clone()),
+            Some(UserEvent::ExternalUserLinked { provider, subject, user_uuid, .. }) => {
+                (user_uuid.clone(), format!("{provider}:{subject}"))
+            }
+            _ => return Err(StoreError::InvalidExport),
+        };
+        if self.uuid_to_user.contains_key(&user_uuid) || self.username_to_user.contains_key(&username) {
+            return Err(StoreError::UserAlreadyExists);
+        }
+        let taken_credential = |event: &UserEvent| {
+            matches!(event, UserEvent::WebAuthnCredentialAdded { credential, .. } if self.credential_to_uuid.contains_key(&credential.credential_id))
+        };
+        if events.iter().any(taken_credential) {
+            return Err(StoreError::CredentialAlreadyRegistered);
+        }
+
+        for event in events {
+            self.record(event)?;
+        }
+        Ok(())
+    }
+
+    // In memory, the users are fine as long as the journal, if any, is.
+    fn check_store(&self) -> Result<(), StoreError> {
+        Ok(self.journal.as_ref().map_or(Ok(()), |journal| journal.check())?)
+    }
+
+    // Rebuilds the users from the journal, up to `until` when given; anything later is dropped
+    // from the journal too. Returns how many events were replayed.
+    fn replay(&mut self, until: Option<SystemTime>) -> Result<usize, StoreError> {
+        let journal = self.journal.as_ref().ok_or(StoreError::NoJournal)?;
+
+        if let Some(until) = until {
+            journal.truncate_after(until)?;
+        }
+        let entries = journal.entries()?;
+
+        *self = UsersImpl {
+            password_hashers: self.password_hashers.clone(),
+            ids: self.ids.clone(),
+            // Journals from before there was a choice don't say.
+            id_scheme: if entries.is_empty() { self.id_scheme } else { IdScheme::default() },
+            journal: self.journal.take(),
+            ..UsersImpl::default()
+        };
+        let replayed = entries.len();
+        for entry in entries {
+            self.apply(entry.event);
+        }
+
+        Ok(replayed)
+    }
+
+    // Walks the index of the order asked for from the cursor on, so a page costs about as much as
+    // the users it skips for not matching the filters, however many come before it.
+    fn list_users(&self, query: &UserQuery) -> Result<UserPage, StoreError> {
+        let users: Box<dyn Iterator<Item = &User>> = match query.order {
+            UserOrder::Username => {
+                let from = query.cursor.as_deref().map(username_cursor).transpose()?;
+                Box::new(entries_after(&self.username_to_user, from.as_ref(), query.descending).map(|(_, user)| user))
+            }
+            UserOrder::CreatedAt => {
+                let mut from = query.cursor.as_deref().map(created_at_cursor).transpose()?;
+                let created_after = query.created_after.unwrap_or(UNIX_EPOCH);
+                let descending = query.descending;
+
+                // Going forward, whoever was created up to `created_after` is skipped outright;
+                // going back, the walk stops there.
+                if !descending && from.as_ref().is_none_or(|(created_at, _)| *created_at < created_after) {
+                    from = Some((created_after, String::new()));
+                }
+
+                Box::new(
+                    entries_after(&self.created_index, from.as_ref(), descending)
+                        .take_while(move |((created_at, _), _)| !descending || *created_at > created_after)
+                        .filter_map(|((_, user_uuid), _)| self.uuid_to_user.get(user_uuid)),
+                )
+            }
+        };
+
+        let mut users: Vec<&User> = users
+            // Soft-deleted users only show when asked for.
+            .filter(|user| query.status.map_or(user.status != AccountStatus::SoftDeleted, |status| user.status == status))
+            .filter(|user| query.created_after.is_none_or(|after| user.created_at > after))
+            .take(query.limit + 1)
+            .collect();
+
+        let next_cursor = (users.len() > query.limit).then(|| {
+            users.truncate(query.limit);
+            users.last().map(|user| cursor_of(user, query.order))
+        });
+
+        Ok(UserPage {
+            users: users
+                .into_iter()
+                .map(|user| UserSummary {
+                    user_uuid: user.user_uuid.clone(),
+                    username: user.username.clone(),
+                    status: user.status,
+                    created_at: user.created_at,
+                })
+                .collect(),
+            next_cursor: next_cursor.flatten(),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use auth_ids::SeededIds;
+
     use super::*;
+    use crate::hashers::tests::PlainHasher;
+    use crate::journal::tests::MemoryJournal;
 
     #[test]
     fn should_create_user() {
@@ -92,6 +929,19 @@ mod tests {
         assert_eq!(user_service.username_to_user.len(), 1);
     }
 
+    #[test]
+    fn should_create_same_uuids_from_same_seed() {
+        let create = || {
+            let mut user_service = UsersImpl::default().with_id_generator(Ids::new(SeededIds::new(42)));
+            user_service
+                .create_user("username".to_owned(), "password".to_owned())
+                .expect("should create user");
+            user_service.find_user_uuid("username").unwrap()
+        };
+
+        assert_eq!(create(), create());
+    }
+
     #[test]
     fn should_fail_creating_user_with_existing_username() {
         let mut user_service = UsersImpl::default();
@@ -104,6 +954,111 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn should_create_if_absent_only_once() {
+        let mut user_service = UsersImpl::default();
+
+        assert_eq!(user_service.create_if_absent("username".to_owned(), "password".to_owned()), Ok(true));
+        assert_eq!(user_service.create_if_absent("username".to_owned(), "other".to_owned()), Ok(false));
+        assert!(user_service.create_if_absent(String::new(), "password".to_owned()).is_err());
+
+        // The first one's password stays.
+        assert!(user_service.get_user_uuid("username".to_owned(), "password".to_owned()).is_some());
+    }
+
+    #[test]
+    fn should_update_password_only_if_it_matches() {
+        let policy = PasswordPolicy { algorithm: Algorithm::Pbkdf2Sha256, rounds: 1_000 };
+        let mut user_service = UsersImpl::default().with_password_policy(policy);
+        user_service.create_user("username".to_owned(), "password".to_owned()).unwrap();
+        let user_uuid = user_service.find_user_uuid("username").unwrap();
+
+        assert_eq!(user_service.update_password_if_matches(&user_uuid, "password", "first"), Ok(true));
+        // A second change starting from the same password lost the race.
+        assert_eq!(user_service.update_password_if_matches(&user_uuid, "password", "second"), Ok(false));
+        assert!(user_service.update_password_if_matches("unknown", "first", "second").is_err());
+
+        assert_eq!(user_service.get_user_uuid("username".to_owned(), "first".to_owned()), Some(user_uuid));
+    }
+
+    #[test]
+    fn should_only_rehash_passwords_under_an_older_policy() {
+        let policy = |algorithm, rounds| PasswordPolicy { algorithm, rounds };
+        let mut user_service = UsersImpl::default().with_password_policy(policy(Algorithm::Pbkdf2Sha256, 1_000));
+        user_service.create_user("username".to_owned(), "password".to_owned()).unwrap();
+        let user_uuid = user_service.find_user_uuid("username").unwrap();
+
+        assert_eq!(user_service.outdated_password_hashes(), 0);
+        assert_eq!(user_service.upgrade_password_hash(&user_uuid, "password"), Ok(false));
+
+        // Fewer rounds than the hash has is no reason to redo it.
+        user_service = user_service.with_password_policy(policy(Algorithm::Pbkdf2Sha256, 500));
+        assert_eq!(user_service.outdated_password_hashes(), 0);
+
+        for newer in [policy(Algorithm::Pbkdf2Sha256, 2_000), policy(Algorithm::Pbkdf2Sha512, 2_000)] {
+            user_service = user_service.with_password_policy(newer);
+            assert_eq!(user_service.outdated_password_hashes(), 1);
+            assert!(user_service.upgrade_password_hash(&user_uuid, "wrong").is_err());
+            assert_eq!(user_service.upgrade_password_hash(&user_uuid, "password"), Ok(true));
+            assert_eq!(user_service.outdated_password_hashes(), 0);
+        }
+
+        assert!(user_service.username_to_user["username"].password.starts_with("$pbkdf2-sha512$i=2000,"));
+        assert_eq!(user_service.get_user_uuid("username".to_owned(), "password".to_owned()), Some(user_uuid));
+    }
+
+    #[test]
+    fn should_move_passwords_over_to_another_hasher() {
+        let policy = PasswordPolicy { algorithm: Algorithm::Pbkdf2Sha256, rounds: 1_000 };
+        let mut user_service = UsersImpl::default().with_password_policy(policy);
+        user_service.create_user("username".to_owned(), "password".to_owned()).unwrap();
+        let user_uuid = user_service.find_user_uuid("username").unwrap();
+
+        user_service = user_service.with_password_hasher(Arc::new(PlainHasher { version: 1 }));
+        assert_eq!(user_service.outdated_password_hashes(), 1);
+        // The PBKDF2 hash still verifies until it is moved over.
+        assert_eq!(user_service.get_user_uuid("username".to_owned(), "password".to_owned()), Some(user_uuid.clone()));
+        assert_eq!(user_service.upgrade_password_hash(&user_uuid, "password"), Ok(true));
+
+        assert_eq!(user_service.outdated_password_hashes(), 0);
+        assert_eq!(user_service.username_to_user["username"].password, "$plain$v=1$password");
+        assert_eq!(user_service.get_user_uuid("username".to_owned(), "password".to_owned()), Some(user_uuid));
+    }
+
+    #[test]
+    fn should_fail_creating_user_with_empty_or_oversized_input() {
+        let mut user_service = UsersImpl::default();
+
+        assert!(user_service.create_user("".to_owned(), "password".to_owned()).is_err());
+        assert!(user_service.create_user("username".to_owned(), "".to_owned()).is_err());
+        assert!(user_service
+            .create_user("x".repeat(MAX_USERNAME_LEN + 1), "password".to_owned())
+            .is_err());
+    }
+
+    #[test]
+    fn should_keep_the_passkeys_of_a_user() {
+        let mut user_service = UsersImpl::default();
+        user_service.create_user("username".to_owned(), "password".to_owned()).unwrap();
+        let user_uuid = user_service.get_user_uuid("username".to_owned(), "password".to_owned()).unwrap();
+        let credential = WebAuthnCredential { credential_id: b"passkey-1".to_vec(), public_key: vec![4; 65], sign_count: 0 };
+
+        user_service.add_webauthn_credential(&user_uuid, credential.clone()).unwrap();
+        assert_eq!(
+            user_service.add_webauthn_credential(&user_uuid, credential.clone()),
+            Err(StoreError::CredentialAlreadyRegistered)
+        );
+        user_service.set_webauthn_sign_count(b"passkey-1", 7).unwrap();
+
+        let (found_uuid, found) = user_service.find_webauthn_credential(b"passkey-1").unwrap();
+        assert_eq!(found_uuid, user_uuid);
+        assert_eq!(found.sign_count, 7);
+
+        user_service.delete_user(user_uuid.clone());
+        assert!(user_service.find_webauthn_credential(b"passkey-1").is_none());
+        assert_eq!(user_service.set_webauthn_sign_count(b"passkey-1", 8), Err(StoreError::CredentialNotFound));
+    }
+
     #[test]
     fn should_retrieve_user_uuid() {
         let mut user_service = UsersImpl::default();
@@ -144,4 +1099,248 @@ mod tests {
         assert_eq!(user_service.uuid_to_user.len(), 0);
         assert_eq!(user_service.username_to_user.len(), 0);
     }
+
+    #[test]
+    fn should_link_external_user_once() {
+        let mut user_service = UsersImpl::default();
+
+        let user_uuid = user_service.link_external_user("google", "1234").expect("should link user");
+
+        assert_eq!(user_service.link_external_user("google", "1234"), Ok(user_uuid));
+        assert_eq!(user_service.uuid_to_user.len(), 1);
+        assert!(user_service
+            .get_user_uuid("google:1234".to_owned(), "".to_owned())
+            .is_none());
+    }
+
+    #[test]
+    fn should_update_account_status() {
+        let mut user_service = UsersImpl::default();
+        user_service
+            .create_user("username".to_owned(), "password".to_owned())
+            .expect("should create user");
+
+        let user_uuid = user_service.find_user_uuid("username").unwrap();
+
+        assert_eq!(user_service.get_account_status(&user_uuid), Some(AccountStatus::Active));
+
+        user_service
+            .set_account_status(&user_uuid, AccountStatus::Suspended)
+            .expect("should update status");
+
+        assert_eq!(user_service.get_account_status(&user_uuid), Some(AccountStatus::Suspended));
+        assert_eq!(user_service.username_to_user.get("username").unwrap().status, AccountStatus::Suspended);
+        assert!(user_service.set_account_status("unknown", AccountStatus::Active).is_err());
+    }
+
+    #[test]
+    fn should_change_username() {
+        let mut user_service = UsersImpl::default();
+        let user_uuid = user_service.link_external_user("google", "1234").unwrap();
+        let other_uuid = user_service.link_external_user("google", "5678").unwrap();
+
+        assert_eq!(user_service.change_username(&user_uuid, " JDoe "), Ok("google:1234".to_owned()));
+        assert_eq!(user_service.find_user_uuid("JDoe"), Some(user_uuid.clone()));
+        assert_eq!(user_service.find_user_uuid("google:1234"), None);
+
+        // Taken, even with a different case
+        assert!(user_service.change_username(&other_uuid, "jdoe").is_err());
+        // Released
+        assert!(user_service.change_username(&other_uuid, "google:1234").is_ok());
+    }
+
+    #[test]
+    fn should_limit_metadata() {
+        let mut user_service = UsersImpl::default();
+        let user_uuid = user_service.link_external_user("google", "1234").unwrap();
+
+        assert!(user_service.set_metadata(&user_uuid, "locale", "en-GB").is_ok());
+        assert!(user_service.set_metadata(&user_uuid, "theme", &"x".repeat(MAX_METADATA_VALUE_LEN + 1)).is_err());
+        assert!(user_service.set_metadata("unknown", "locale", "en-GB").is_err());
+
+        for i in 1..MAX_METADATA_ENTRIES {
+            user_service.set_metadata(&user_uuid, &format!("key{i}"), "value").unwrap();
+        }
+        assert!(user_service.set_metadata(&user_uuid, "one-too-many", "value").is_err());
+        // Overwriting and removing still work when full.
+        assert!(user_service.set_metadata(&user_uuid, "locale", "fr-FR").is_ok());
+        assert!(user_service.set_metadata(&user_uuid, "key1", "").is_ok());
+
+        let metadata = user_service.get_metadata(&user_uuid).unwrap();
+        assert_eq!(metadata.len(), MAX_METADATA_ENTRIES - 1);
+        assert_eq!(metadata.get("locale").map(String::as_str), Some("fr-FR"));
+    }
+
+    #[test]
+    fn should_check_scopes() {
+        let mut users_service = UsersImpl::default();
+        users_service.create_user("123456".to_owned(), "654321".to_owned()).unwrap();
+        let user_uuid = users_service.find_user_uuid("123456").unwrap();
+
+        assert_eq!(users_service.set_scopes(&user_uuid, &["profile:read".to_owned()]), Ok(()));
+        assert_eq!(users_service.get_scopes(&user_uuid), vec!["profile:read".to_owned()]);
+
+        assert_eq!(users_service.set_scopes(&user_uuid, &["profile read".to_owned()]), Err(StoreError::InvalidScope));
+        assert_eq!(users_service.set_scopes(&user_uuid, &[String::new()]), Err(StoreError::InvalidScope));
+        let too_many: Vec<String> = (0..=MAX_SCOPES).map(|i| format!("scope-{i}")).collect();
+        assert_eq!(users_service.set_scopes(&user_uuid, &too_many), Err(StoreError::TooManyScopes));
+        assert_eq!(users_service.set_scopes("unknown", &[]), Err(StoreError::UserNotFound));
+
+        assert_eq!(users_service.set_scopes(&user_uuid, &[]), Ok(()));
+        assert!(users_service.get_scopes(&user_uuid).is_empty());
+    }
+
+    #[test]
+    fn should_keep_the_tenants_of_a_user() {
+        let mut users_service = UsersImpl::default();
+        users_service.create_user("123456".to_owned(), "654321".to_owned()).unwrap();
+        let user_uuid = users_service.find_user_uuid("123456").unwrap();
+
+        for tenant in ["globex", "acme", "globex"] {
+            assert_eq!(users_service.add_tenant(&user_uuid, tenant), Ok(()));
+        }
+        assert_eq!(users_service.get_tenants(&user_uuid), vec!["acme".to_owned(), "globex".to_owned()]);
+        assert_eq!(users_service.add_tenant("unknown", "acme"), Err(StoreError::UserNotFound));
+
+        let mut imported = UsersImpl::default();
+        imported.import_user(users_service.export_user(&user_uuid).unwrap()).unwrap();
+        assert_eq!(imported.get_tenants(&user_uuid), vec!["acme".to_owned(), "globex".to_owned()]);
+
+        users_service.delete_user(user_uuid.clone());
+        assert!(users_service.get_tenants(&user_uuid).is_empty());
+    }
+
+    #[test]
+    fn should_page_through_users_in_order() {
+        let mut user_service = UsersImpl::default();
+        for username in ["carol", "alice", "dave", "bob", "erin"] {
+            user_service.create_user(username.to_owned(), "password".to_owned()).unwrap();
+        }
+        let carol = user_service.find_user_uuid("carol").unwrap();
+        user_service.set_account_status(&carol, AccountStatus::Suspended).unwrap();
+
+        let list = |query: &UserQuery| {
+            let mut query = query.clone();
+            let mut usernames = vec![];
+            loop {
+                let page = user_service.list_users(&query).unwrap();
+                assert!(page.users.len() <= query.limit);
+                usernames.extend(page.users.into_iter().map(|user| user.username));
+                match page.next_cursor {
+                    Some(cursor) => query.cursor = Some(cursor),
+                    None => return usernames,
+                }
+            }
+        };
+
+        let by_name = UserQuery { order: UserOrder::Username, limit: 2, ..Default::default() };
+        assert_eq!(list(&by_name), ["alice", "bob", "carol", "dave", "erin"]);
+        assert_eq!(list(&UserQuery { descending: true, ..by_name.clone() }), ["erin", "dave", "carol", "bob", "alice"]);
+        assert_eq!(list(&UserQuery { status: Some(AccountStatus::Active), ..by_name.clone() }), ["alice", "bob", "dave", "erin"]);
+
+        let by_creation = UserQuery { limit: 2, ..Default::default() };
+        assert_eq!(list(&by_creation), ["carol", "alice", "dave", "bob", "erin"]);
+        assert_eq!(list(&UserQuery { descending: true, ..by_creation.clone() }), ["erin", "bob", "dave", "alice", "carol"]);
+
+        let created_at = |username: &str| {
+            let user_uuid = user_service.find_user_uuid(username).unwrap();
+            user_service.uuid_to_user[&user_uuid].created_at
+        };
+        let after_alice = UserQuery { created_after: Some(created_at("alice")), ..by_creation.clone() };
+        assert_eq!(list(&after_alice), ["dave", "bob", "erin"]);
+        assert_eq!(list(&UserQuery { descending: true, ..after_alice }), ["erin", "bob", "dave"]);
+
+        // Cursors only fit the order they were made for.
+        let cursor = user_service.list_users(&by_name).unwrap().next_cursor;
+        assert!(user_service.list_users(&UserQuery { cursor, ..by_creation }).is_err());
+    }
+
+    #[test]
+    fn should_rebuild_users_from_journal() {
+        let journal = MemoryJournal::default();
+        let mut user_service = UsersImpl::default().with_journal(Box::new(journal.clone())).unwrap();
+
+        user_service.create_user("username".to_owned(), "password".to_owned()).unwrap();
+        let user_uuid = user_service.find_user_uuid("username").unwrap();
+        user_service.change_username(&user_uuid, "renamed").unwrap();
+        user_service.set_metadata(&user_uuid, "locale", "en-GB").unwrap();
+        user_service.request_deletion(&user_uuid).unwrap();
+        let until = SystemTime::now();
+        let other_uuid = user_service.link_external_user("google", "1234").unwrap();
+
+        // Refused changes are not journaled.
+        assert!(user_service.set_metadata(&user_uuid, "", "value").is_err());
+        assert!(!serde_json::to_string(&journal.entries().unwrap()).unwrap().contains("\"password\""));
+
+        let mut rebuilt = UsersImpl::default().with_journal(Box::new(journal)).unwrap();
+        assert!(rebuilt.get_user_uuid("renamed".to_owned(), "password".to_owned()).is_some());
+        assert_eq!(rebuilt.get_metadata(&user_uuid).unwrap()["locale"], "en-GB");
+        assert_eq!(rebuilt.get_account_status(&user_uuid), Some(AccountStatus::Deleted));
+        assert_eq!(rebuilt.deletion_requested_at(&user_uuid), user_service.deletion_requested_at(&user_uuid));
+        assert_eq!(rebuilt.link_external_user("google", "1234"), Ok(other_uuid.clone()));
+
+        // Back to before the external user was linked.
+        assert_eq!(rebuilt.replay(Some(until)), Ok(5));
+        assert!(rebuilt.get_username(&other_uuid).is_none());
+        assert_eq!(rebuilt.get_username(&user_uuid), Some("renamed".to_owned()));
+
+        assert!(UsersImpl::default().replay(None).is_err());
+    }
+
+    #[test]
+    fn should_keep_the_id_scheme_a_journal_was_started_with() {
+        let journal = MemoryJournal::default();
+        let mut user_service =
+            UsersImpl::default().with_id_scheme(IdScheme::Ulid).with_journal(Box::new(journal.clone())).unwrap();
+        user_service.create_user("username".to_owned(), "password".to_owned()).unwrap();
+        assert_eq!(user_service.find_user_uuid("username").unwrap().len(), 26);
+
+        let mut rebuilt = UsersImpl::default().with_id_scheme(IdScheme::UuidV7).with_journal(Box::new(journal)).unwrap();
+        assert_eq!(rebuilt.id_scheme(), "ulid");
+        assert_eq!(rebuilt.link_external_user("google", "1234").unwrap().len(), 26);
+
+        // Started before there was a choice.
+        let journal = MemoryJournal::default();
+        journal.append(&UserEvent::UserDeleted { user_uuid: String::from("gone") }).unwrap();
+        let older = UsersImpl::default().with_id_scheme(IdScheme::UuidV7).with_journal(Box::new(journal)).unwrap();
+        assert_eq!(older.id_scheme(), "uuid-v4");
+
+        assert_eq!(UsersImpl::default().with_id_scheme(IdScheme::UuidV7).id_scheme(), "uuid-v7");
+    }
+
+    #[test]
+    fn should_undo_deletion_request() {
+        let mut user_service = UsersImpl::default();
+        let user_uuid = user_service.link_external_user("google", "1234").unwrap();
+
+        user_service.request_deletion(&user_uuid).unwrap();
+        assert_eq!(user_service.get_account_status(&user_uuid), Some(AccountStatus::Deleted));
+        assert_eq!(user_service.users_deleted_before(SystemTime::now()), vec![user_uuid.clone()]);
+
+        user_service.set_account_status(&user_uuid, AccountStatus::Active).unwrap();
+        assert!(user_service.deletion_requested_at(&user_uuid).is_none());
+        assert!(user_service.users_deleted_before(SystemTime::now()).is_empty());
+    }
+
+    #[test]
+    fn should_keep_soft_deleted_users_until_restored() {
+        let journal = MemoryJournal::default();
+        let mut user_service = UsersImpl::default().with_journal(Box::new(journal.clone())).unwrap();
+        user_service.create_user("username".to_owned(), "password".to_owned()).unwrap();
+        let user_uuid = user_service.find_user_uuid("username").unwrap();
+
+        user_service.soft_delete(&user_uuid).unwrap();
+        assert!(user_service.soft_delete("unknown").is_err());
+        assert_eq!(user_service.get_account_status(&user_uuid), Some(AccountStatus::SoftDeleted));
+        assert_eq!(user_service.users_soft_deleted_before(SystemTime::now()), vec![user_uuid.clone()]);
+        assert!(user_service.users_deleted_before(SystemTime::now()).is_empty());
+
+        let exported = user_service.export_user(&user_uuid).unwrap();
+        assert!(matches!(exported.last(), Some(UserEvent::SoftDeleted { .. })));
+        let rebuilt = UsersImpl::default().with_journal(Box::new(journal)).unwrap();
+        assert_eq!(rebuilt.users_soft_deleted_before(SystemTime::now()), vec![user_uuid.clone()]);
+
+        user_service.set_account_status(&user_uuid, AccountStatus::Active).unwrap();
+        assert!(user_service.users_soft_deleted_before(SystemTime::now()).is_empty());
+    }
 }
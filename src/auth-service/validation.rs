@@ -0,0 +1,146 @@
+use tonic::Status;
+
+use crate::{
+    auth::authentication::{
+        AcceptTermsRequest, ChangePasswordRequest, ChangeUsernameRequest, ConsumeMagicLinkRequest, CreateDelegationTokenRequest,
+        CreateGuestSessionRequest, DeleteAccountRequest, ExchangeExternalTokenRequest, ExportMyDataRequest,
+        GetServerInfoRequest, GetUserMetadataRequest, IntrospectTokenRequest, ListRevokedSessionsRequest,
+        RequestMagicLinkRequest, SetUserMetadataRequest, SignInRequest, SignOutRequest, SignUpRequest,
+        UpgradeGuestSessionRequest, ValidateSessionRequest, StartWebAuthnRegistrationRequest,
+        FinishWebAuthnRegistrationRequest, StartWebAuthnAssertionRequest, FinishWebAuthnAssertionRequest,
+    },
+    config::Config,
+};
+
+// The longest usernames, passwords and tokens (session, magic link, external id tokens, ...) a
+// request may carry, in bytes. Checked before a handler looks anything up, so a multi-megabyte
+// key never reaches the stores, or the logs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FieldLimits {
+    pub username: usize,
+    pub password: usize,
+    pub token: usize,
+}
+
+impl FieldLimits {
+    pub fn from_config(config: &Config) -> Self {
+        Self { username: config.max_username_len, password: config.max_password_len, token: config.max_token_len }
+    }
+}
+
+// A request whose fields are within `FieldLimits`, and filled in unless they may be empty; answers
+// `INVALID_ARGUMENT` naming the first one that isn't.
+pub trait Validate {
+    fn validate(&self, limits: &FieldLimits) -> Result<(), Status>;
+}
+
+// Strings and bytes (the WebAuthn responses) alike.
+fn check(field: &str, value: impl AsRef<[u8]>, max: usize, may_be_empty: bool) -> Result<(), Status> {
+    let value = value.as_ref();
+    if value.is_empty() && !may_be_empty {
+        return Err(Status::invalid_argument(format!("{field} must not be empty")));
+    }
+    if value.len() > max {
+        return Err(Status::invalid_argument(format!("{field} is longer than {max} bytes")));
+    }
+    Ok(())
+}
+
+// `Request { field: limit, ... }`: which limit each field of the request is held to. Fields are
+// required, unless marked `field: limit or empty`.
+macro_rules! field_limits {
+    (@may_be_empty) => { false };
+    (@may_be_empty empty) => { true };
+    ($($request:ty { $($field:ident: $limit:ident $(or $empty:ident)?),* $(,)? })*) => {
+        $(
+            impl Validate for $request {
+                // Unused by requests without fields to check.
+                #[allow(unused_variables)]
+                fn validate(&self, limits: &FieldLimits) -> Result<(), Status> {
+                    $(check(stringify!($field), &self.$field, limits.$limit, field_limits!(@may_be_empty $($empty)?))?;)*
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+field_limits! {
+    SignUpRequest {
+        username: username,
+        password: password,
+        invite_code: token or empty,
+        accepted_terms_version: token or empty,
+        challenge_response: token or empty,
+    }
+    SignInRequest { username: username, password: password }
+    SignOutRequest { session_token: token }
+    ExchangeExternalTokenRequest { id_token: token, accepted_terms_version: token or empty }
+    IntrospectTokenRequest { token: token }
+    RequestMagicLinkRequest { username: username }
+    ConsumeMagicLinkRequest { magic_link_token: token, accepted_terms_version: token or empty }
+    ValidateSessionRequest { session_token: token }
+    UpgradeGuestSessionRequest { session_token: token, username: username, password: password }
+    CreateDelegationTokenRequest { session_token: token }
+    ChangeUsernameRequest { session_token: token, new_username: username }
+    ChangePasswordRequest { session_token: token, current_password: password, new_password: password }
+    SetUserMetadataRequest { session_token: token }
+    GetUserMetadataRequest { session_token: token }
+    ExportMyDataRequest { session_token: token }
+    DeleteAccountRequest { session_token: token }
+    AcceptTermsRequest { username: username, password: password, version: token }
+    CreateGuestSessionRequest {}
+    ListRevokedSessionsRequest {}
+    GetServerInfoRequest {}
+    StartWebAuthnRegistrationRequest { session_token: token }
+    FinishWebAuthnRegistrationRequest { session_token: token, client_data_json: token, attestation_object: token }
+    StartWebAuthnAssertionRequest {}
+    FinishWebAuthnAssertionRequest {
+        credential_id: token,
+        client_data_json: token,
+        authenticator_data: token,
+        signature: token,
+        accepted_terms_version: token or empty,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    #[test]
+    fn should_name_the_field_over_its_limit() {
+        let limits = FieldLimits { username: 8, password: 16, token: 32 };
+        let sign_in = |username: &str, password: &str| SignInRequest {
+            username: username.to_owned(),
+            password: password.to_owned(),
+            remember_me: false,
+        };
+
+        assert!(sign_in("alice", "secret").validate(&limits).is_ok());
+        assert!(sign_in("12345678", &"x".repeat(16)).validate(&limits).is_ok());
+
+        let status = sign_in("alice", &"x".repeat(17)).validate(&limits).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "password is longer than 16 bytes");
+
+        let status = ValidateSessionRequest { session_token: "x".repeat(1024 * 1024) }.validate(&limits).unwrap_err();
+        assert_eq!(status.message(), "session_token is longer than 32 bytes");
+    }
+
+    #[test]
+    fn should_refuse_empty_fields_unless_optional() {
+        let limits = FieldLimits { username: 8, password: 16, token: 32 };
+
+        let status = SignOutRequest { session_token: String::new() }.validate(&limits).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "session_token must not be empty");
+
+        let sign_up = |username: &str| SignUpRequest { username: username.to_owned(), password: "secret".to_owned(), ..Default::default() };
+        assert_eq!(sign_up("").validate(&limits).unwrap_err().message(), "username must not be empty");
+        // No invite code, terms version or challenge response.
+        assert!(sign_up("alice").validate(&limits).is_ok());
+    }
+}
@@ -0,0 +1,353 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use ciborium::Value;
+use p256::{
+    ecdsa::{signature::Verifier, DerSignature, VerifyingKey},
+    EncodedPoint,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+
+// The flags of authenticator data.
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+// The COSE (RFC 9053) labels of a key, and the values of an ES256 one: EC2, ECDSA with SHA-256,
+// on P-256.
+const COSE_KTY: i128 = 1;
+const COSE_ALG: i128 = 3;
+const COSE_CRV: i128 = -1;
+const COSE_X: i128 = -2;
+const COSE_Y: i128 = -3;
+const COSE_KTY_EC2: i128 = 2;
+const COSE_ALG_ES256: i128 = -7;
+const COSE_CRV_P256: i128 = 1;
+
+// Why a WebAuthn response is refused.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum WebAuthnError {
+    #[error("Error::InvalidClientData: {0}")]
+    InvalidClientData(String),
+    #[error("Error::InvalidAuthenticatorData: {0}")]
+    InvalidAuthenticatorData(String),
+    #[error("Error::UnsupportedCredential: {0}")]
+    UnsupportedCredential(String),
+    #[error("Error::InvalidSignature")]
+    InvalidSignature,
+    // The authenticator's counter didn't go up: the credential may have been cloned.
+    #[error("Error::SignCountRegressed")]
+    SignCountRegressed,
+}
+
+// A passkey of a user: its id, as the authenticator made it up, its public key (an uncompressed
+// SEC1 point on P-256) and the authenticator's signature counter as of the last sign-in.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebAuthnCredential {
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+// What a challenge was handed out for: registering a credential for this user, or signing in
+// with one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WebAuthnCeremony {
+    Registration(String),
+    Assertion,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+fn client_data(client_data_json: &[u8]) -> Result<ClientData, WebAuthnError> {
+    serde_json::from_slice(client_data_json).map_err(|e| WebAuthnError::InvalidClientData(e.to_string()))
+}
+
+// The challenge a response answers, to look up what it was handed out for (see
+// `SessionsOps::consume_webauthn_challenge`) before the rest is checked.
+pub fn challenge_of(client_data_json: &[u8]) -> Result<Vec<u8>, WebAuthnError> {
+    let challenge = client_data(client_data_json)?.challenge;
+    URL_SAFE_NO_PAD.decode(challenge).map_err(|e| WebAuthnError::InvalidClientData(e.to_string()))
+}
+
+struct AuthenticatorData<'a> {
+    flags: u8,
+    sign_count: u32,
+    // Past the fixed part: the attested credential data, if any, and the extensions.
+    rest: &'a [u8],
+}
+
+// Who passkeys are made for (AUTH_WEBAUTHN_RP_ID, a domain), and the origin of the pages the
+// browser runs the ceremonies on (AUTH_WEBAUTHN_ORIGIN). Only ES256 credentials, which platform
+// authenticators and security keys all make, and no attestation: a credential is taken for what
+// the signed-in user registers, whatever made it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelyingParty {
+    pub id: String,
+    pub origin: String,
+}
+
+impl RelyingParty {
+    // None unless AUTH_WEBAUTHN_RP_ID is set; the origin defaults to https on that domain.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let id = config.webauthn_rp_id.clone()?;
+        let origin = config.webauthn_origin.clone().unwrap_or_else(|| format!("https://{id}"));
+        Some(Self { id, origin })
+    }
+
+    // Checks what `navigator.credentials.create` answered to a registration challenge, already
+    // looked up, and returns the new credential.
+    pub fn verify_registration(&self, client_data_json: &[u8], attestation_object: &[u8]) -> Result<WebAuthnCredential, WebAuthnError> {
+        self.check_client_data(client_data_json, "webauthn.create")?;
+
+        let attestation: Value = ciborium::de::from_reader(attestation_object).map_err(|e| invalid(&e.to_string()))?;
+        let auth_data = attestation
+            .as_map()
+            .and_then(|entries| entries.iter().find(|(key, _)| key.as_text() == Some("authData")))
+            .and_then(|(_, value)| value.as_bytes())
+            .ok_or_else(|| invalid("no authData"))?;
+        let auth_data = self.authenticator_data(auth_data)?;
+        if auth_data.flags & ATTESTED_CREDENTIAL_DATA == 0 {
+            return Err(invalid("no attested credential data"));
+        }
+
+        // The AAGUID (16 bytes), the length of the credential id (2), the id, then its COSE key.
+        let id_len = auth_data.rest.get(16..18).ok_or_else(|| invalid("no credential id"))?;
+        let id_len = u16::from_be_bytes([id_len[0], id_len[1]]) as usize;
+        let credential_id = auth_data.rest.get(18..18 + id_len).ok_or_else(|| invalid("credential id cut short"))?;
+        let mut cose_key = &auth_data.rest[18 + id_len..];
+        let cose_key: Value = ciborium::de::from_reader(&mut cose_key).map_err(|e| invalid(&e.to_string()))?;
+
+        Ok(WebAuthnCredential {
+            credential_id: credential_id.to_vec(),
+            public_key: es256_public_key(&cose_key)?,
+            sign_count: auth_data.sign_count,
+        })
+    }
+
+    // Checks what `navigator.credentials.get` answered to a sign-in challenge, already looked up,
+    // with `credential`; returns the authenticator's new signature counter.
+    pub fn verify_assertion(
+        &self,
+        credential: &WebAuthnCredential,
+        client_data_json: &[u8],
+        authenticator_data: &[u8],
+        signature: &[u8],
+    ) -> Result<u32, WebAuthnError> {
+        self.check_client_data(client_data_json, "webauthn.get")?;
+        let auth_data = self.authenticator_data(authenticator_data)?;
+
+        let key = VerifyingKey::from_sec1_bytes(&credential.public_key).map_err(|_| WebAuthnError::InvalidSignature)?;
+        let signature = DerSignature::try_from(signature).map_err(|_| WebAuthnError::InvalidSignature)?;
+        let signed = [authenticator_data, &Sha256::digest(client_data_json)].concat();
+        key.verify(&signed, &signature).map_err(|_| WebAuthnError::InvalidSignature)?;
+
+        // Authenticators keeping no counter (synced passkeys) always say 0.
+        if (auth_data.sign_count != 0 || credential.sign_count != 0) && auth_data.sign_count <= credential.sign_count {
+            return Err(WebAuthnError::SignCountRegressed);
+        }
+        Ok(auth_data.sign_count)
+    }
+
+    fn check_client_data(&self, client_data_json: &[u8], kind: &str) -> Result<(), WebAuthnError> {
+        let client_data = client_data(client_data_json)?;
+        if client_data.kind != kind {
+            return Err(WebAuthnError::InvalidClientData(format!("type {}", client_data.kind)));
+        }
+        if client_data.origin != self.origin {
+            return Err(WebAuthnError::InvalidClientData(format!("origin {}", client_data.origin)));
+        }
+        Ok(())
+    }
+
+    // The fixed part: the SHA-256 of the RP id (32 bytes), the flags (1) and the counter (4).
+    fn authenticator_data<'a>(&self, auth_data: &'a [u8]) -> Result<AuthenticatorData<'a>, WebAuthnError> {
+        if auth_data.len() < 37 {
+            return Err(invalid("cut short"));
+        }
+        if auth_data[..32] != Sha256::digest(self.id.as_bytes())[..] {
+            return Err(invalid("for another relying party"));
+        }
+        // Present, and verified (a PIN, a fingerprint): a passkey stands in for a password.
+        let flags = auth_data[32];
+        if flags & (USER_PRESENT | USER_VERIFIED) != USER_PRESENT | USER_VERIFIED {
+            return Err(invalid("user not verified"));
+        }
+
+        Ok(AuthenticatorData {
+            flags,
+            sign_count: u32::from_be_bytes([auth_data[33], auth_data[34], auth_data[35], auth_data[36]]),
+            rest: &auth_data[37..],
+        })
+    }
+}
+
+fn invalid(reason: &str) -> WebAuthnError {
+    WebAuthnError::InvalidAuthenticatorData(reason.to_owned())
+}
+
+// The point of an ES256 COSE key, checked to be on the curve; other keys are refused.
+fn es256_public_key(cose_key: &Value) -> Result<Vec<u8>, WebAuthnError> {
+    let unsupported = |reason: &str| WebAuthnError::UnsupportedCredential(reason.to_owned());
+    let entries = cose_key.as_map().ok_or_else(|| unsupported("not a COSE key"))?;
+    let entry = |label: i128| {
+        entries
+            .iter()
+            .find(|(key, _)| key.as_integer().map(i128::from) == Some(label))
+            .map(|(_, value)| value)
+    };
+    let integer = |label| entry(label).and_then(Value::as_integer).map(i128::from);
+
+    if integer(COSE_KTY) != Some(COSE_KTY_EC2) || integer(COSE_ALG) != Some(COSE_ALG_ES256) || integer(COSE_CRV) != Some(COSE_CRV_P256) {
+        return Err(unsupported("only ES256 keys are"));
+    }
+    let coordinate = |label| entry(label).and_then(Value::as_bytes).filter(|bytes| bytes.len() == 32);
+    let (x, y) = coordinate(COSE_X).zip(coordinate(COSE_Y)).ok_or_else(|| unsupported("no coordinates"))?;
+
+    let point = EncodedPoint::from_affine_coordinates(x.as_slice().into(), y.as_slice().into(), false);
+    VerifyingKey::from_encoded_point(&point).map_err(|_| unsupported("not a point on P-256"))?;
+    Ok(point.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+    use serde_json::json;
+
+    use super::*;
+
+    // Stands in for an authenticator with one ES256 credential, behind a browser on `origin`.
+    pub struct FakeAuthenticator {
+        pub credential_id: Vec<u8>,
+        rp_id: String,
+        origin: String,
+        signing_key: SigningKey,
+        sign_count: u32,
+    }
+
+    impl FakeAuthenticator {
+        pub fn new(rp: &RelyingParty, credential_id: &[u8]) -> Self {
+            Self {
+                credential_id: credential_id.to_vec(),
+                rp_id: rp.id.clone(),
+                origin: rp.origin.clone(),
+                signing_key: SigningKey::from_slice(&Sha256::digest(credential_id)).unwrap(),
+                sign_count: 0,
+            }
+        }
+
+        pub fn on_origin(mut self, origin: &str) -> Self {
+            self.origin = origin.to_owned();
+            self
+        }
+
+        fn client_data(&self, kind: &str, challenge: &[u8]) -> Vec<u8> {
+            json!({ "type": kind, "challenge": URL_SAFE_NO_PAD.encode(challenge), "origin": self.origin }).to_string().into_bytes()
+        }
+
+        fn authenticator_data(&self, flags: u8, attested: &[u8]) -> Vec<u8> {
+            [&Sha256::digest(self.rp_id.as_bytes())[..], &[flags], &self.sign_count.to_be_bytes(), attested].concat()
+        }
+
+        // The clientDataJSON and attestationObject of a registration answering `challenge`.
+        pub fn register(&self, challenge: &[u8]) -> (Vec<u8>, Vec<u8>) {
+            let point = self.signing_key.verifying_key().to_encoded_point(false);
+            let cose_key = Value::Map(vec![
+                (COSE_KTY.into(), COSE_KTY_EC2.into()),
+                (COSE_ALG.into(), COSE_ALG_ES256.into()),
+                (COSE_CRV.into(), COSE_CRV_P256.into()),
+                (COSE_X.into(), Value::Bytes(point.x().unwrap().to_vec())),
+                (COSE_Y.into(), Value::Bytes(point.y().unwrap().to_vec())),
+            ]);
+            let mut attested = [0u8; 16].to_vec();
+            attested.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            attested.extend_from_slice(&self.credential_id);
+            ciborium::ser::into_writer(&cose_key, &mut attested).unwrap();
+
+            let auth_data = self.authenticator_data(USER_PRESENT | USER_VERIFIED | ATTESTED_CREDENTIAL_DATA, &attested);
+            let attestation = Value::Map(vec![
+                ("fmt".into(), "none".into()),
+                ("attStmt".into(), Value::Map(Vec::new())),
+                ("authData".into(), Value::Bytes(auth_data)),
+            ]);
+            let mut attestation_object = Vec::new();
+            ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+
+            (self.client_data("webauthn.create", challenge), attestation_object)
+        }
+
+        // The clientDataJSON, authenticatorData and signature of an assertion answering
+        // `challenge`; counts one more signature.
+        pub fn assert(&mut self, challenge: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+            self.sign_count += 1;
+            let client_data_json = self.client_data("webauthn.get", challenge);
+            let authenticator_data = self.authenticator_data(USER_PRESENT | USER_VERIFIED, &[]);
+
+            let signed = [&authenticator_data[..], &Sha256::digest(&client_data_json)].concat();
+            let signature: Signature = self.signing_key.sign(&signed);
+
+            (client_data_json, authenticator_data, signature.to_der().as_bytes().to_vec())
+        }
+    }
+
+    fn relying_party() -> RelyingParty {
+        RelyingParty { id: "example.com".to_owned(), origin: "https://example.com".to_owned() }
+    }
+
+    #[test]
+    fn should_take_registrations_and_assertions_of_es256_credentials() {
+        let rp = relying_party();
+        let mut authenticator = FakeAuthenticator::new(&rp, b"credential-1");
+
+        let (client_data_json, attestation_object) = authenticator.register(b"challenge-1");
+        assert_eq!(challenge_of(&client_data_json), Ok(b"challenge-1".to_vec()));
+        let mut credential = rp.verify_registration(&client_data_json, &attestation_object).unwrap();
+        assert_eq!(credential.credential_id, b"credential-1");
+        assert_eq!(credential.sign_count, 0);
+
+        let (client_data_json, authenticator_data, signature) = authenticator.assert(b"challenge-2");
+        assert_eq!(rp.verify_assertion(&credential, &client_data_json, &authenticator_data, &signature), Ok(1));
+        credential.sign_count = 1;
+
+        // Signed again with the same counter, as a clone of the authenticator would.
+        assert_eq!(
+            rp.verify_assertion(&credential, &client_data_json, &authenticator_data, &signature),
+            Err(WebAuthnError::SignCountRegressed)
+        );
+
+        let (client_data_json, authenticator_data, mut signature) = authenticator.assert(b"challenge-3");
+        let last = signature.len() - 1;
+        signature[last] ^= 1;
+        assert_eq!(
+            rp.verify_assertion(&credential, &client_data_json, &authenticator_data, &signature),
+            Err(WebAuthnError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn should_refuse_responses_from_other_origins_and_relying_parties() {
+        let rp = relying_party();
+
+        let (client_data_json, attestation_object) = FakeAuthenticator::new(&rp, b"credential-1").on_origin("https://evil.example").register(b"challenge");
+        assert!(matches!(rp.verify_registration(&client_data_json, &attestation_object), Err(WebAuthnError::InvalidClientData(_))));
+
+        let other_rp = RelyingParty { id: "evil.example".to_owned(), origin: rp.origin.clone() };
+        let (client_data_json, attestation_object) = FakeAuthenticator::new(&other_rp, b"credential-1").register(b"challenge");
+        assert_eq!(
+            rp.verify_registration(&client_data_json, &attestation_object),
+            Err(WebAuthnError::InvalidAuthenticatorData("for another relying party".to_owned()))
+        );
+
+        // An assertion is no registration.
+        let mut authenticator = FakeAuthenticator::new(&rp, b"credential-1");
+        let (client_data_json, authenticator_data, _) = authenticator.assert(b"challenge");
+        assert!(matches!(rp.verify_registration(&client_data_json, &authenticator_data), Err(WebAuthnError::InvalidClientData(_))));
+    }
+}
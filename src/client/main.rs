@@ -1,16 +1,46 @@
 use std::env;
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
 use clap::{Parser, Subcommand};
+use rand_core::{OsRng, RngCore};
+use auth::error::{exit_with, Error};
+use auth::prehash::{PasswordPrehash, PREHASH_ALGORITHM};
 
+use authentication::auth_admin_client::AuthAdminClient;
 use authentication::auth_client::AuthClient;
-use authentication::{SignInRequest, SignOutRequest, SignUpRequest};
-use tonic::codegen::ok;
+use authentication::{
+    ConsumeMagicLinkRequest, ExchangeExternalTokenRequest, IntrospectTokenRequest, RequestMagicLinkRequest,
+    RevokeLongLivedSessionsRequest, SignInRequest, SignOutRequest, SignUpRequest, SuspendUserRequest, UnsuspendUserRequest,
+    SoftDeleteUserRequest, RestoreUserRequest,
+    SetUserScopesRequest, ValidateSessionRequest, CreateGuestSessionRequest, UpgradeGuestSessionRequest, ChangeUsernameRequest, ChangePasswordRequest,
+    SetUserMetadataRequest, GetUserMetadataRequest, ExportMyDataRequest, ExportUserDataRequest,
+    DeleteAccountRequest, CreateInviteRequest, ReserveUsernameRequest, SetHoneypotUsernameRequest, AcceptTermsRequest, SetMaintenanceModeRequest, SetFeatureFlagRequest, CreateDelegationTokenRequest,
+    ListRevokedSessionsRequest, SetLogLevelRequest, ListUsersRequest, UserOrder, ListSessionsRequest, RevokeSessionsRequest, GetStatsRequest, ReplayProjectionRequest,
+    ApproveUserRequest, RejectUserRequest, ListPendingUsersRequest, StreamStatsRequest,
+    ListRecentAuditEventsRequest, ListThrottledSourcesRequest, GetRateLimitStateRequest, get_rate_limit_state_request::Subject, ClearLockoutRequest, SetDebugCaptureRequest, ListDebugCapturesRequest, GetServerInfoRequest, GetEffectiveConfigRequest,
+};
 use tonic::transport::Channel;
 use tonic::{Request, Response};
 
-use crate::authentication::{SignUpResponse, SignInResponse, SignOutResponse};
+use crate::authentication::{
+    ConsumeMagicLinkResponse, ExchangeExternalTokenResponse, IntrospectTokenResponse, RequestMagicLinkResponse,
+    RevokeLongLivedSessionsResponse, SignUpResponse, SignInResponse, SignOutResponse, SuspendUserResponse, UnsuspendUserResponse,
+    SoftDeleteUserResponse, RestoreUserResponse,
+    SetUserScopesResponse, ValidateSessionResponse, CreateGuestSessionResponse, UpgradeGuestSessionResponse, ChangeUsernameResponse, ChangePasswordResponse,
+    SetUserMetadataResponse, GetUserMetadataResponse, ExportMyDataResponse, ExportUserDataResponse,
+    DeleteAccountResponse, CreateInviteResponse, ReserveUsernameResponse, SetHoneypotUsernameResponse, AcceptTermsResponse, SetMaintenanceModeResponse, SetFeatureFlagResponse, CreateDelegationTokenResponse,
+    ListRevokedSessionsResponse, SetLogLevelResponse, ListUsersResponse, ListSessionsResponse, RevokeSessionsResponse, GetStatsResponse, ReplayProjectionResponse,
+    ApproveUserResponse, RejectUserResponse, ListPendingUsersResponse, ListRecentAuditEventsResponse,
+    ListThrottledSourcesResponse, GetRateLimitStateResponse, ClearLockoutResponse, SetDebugCaptureResponse, ListDebugCapturesResponse, GetServerInfoResponse, GetEffectiveConfigResponse,
+};
 
 pub mod authentication {
-    tonic::include_proto!("authentication");
+    pub mod v1 {
+        tonic::include_proto!("authentication.v1");
+    }
+
+    // The version in use
+    pub use v1::*;
 }
 
 #[derive(Parser)]
@@ -21,43 +51,404 @@ struct ClientCommandlineContents {
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::enum_variant_names)]
 enum Commands {
     SignIn {
         #[arg(short, long)]
         username: String,
         #[arg(short, long)]
         password: String,
+        #[arg(short, long)]
+        remember_me: bool,
     },
     SignUp {
         #[arg(short, long)]
         username: String,
         #[arg(short, long)]
         password: String,
+        #[arg(short, long, default_value = "")]
+        invite_code: String,
+        #[arg(short, long, default_value = "")]
+        terms_version: String,
+        #[arg(short, long, default_value = "")]
+        challenge_response: String,
     },
     SignOut {
         #[arg(short, long)]
         session_token: String,
     },
+    ExchangeExternalToken {
+        #[arg(short, long)]
+        provider: String,
+        #[arg(short, long)]
+        id_token: String,
+        #[arg(short, long, default_value = "")]
+        terms_version: String,
+    },
+    IntrospectToken {
+        #[arg(short, long)]
+        token: String,
+        #[arg(short, long)]
+        api_key: String,
+    },
+    RequestMagicLink {
+        #[arg(short, long)]
+        username: String,
+    },
+    ConsumeMagicLink {
+        #[arg(short, long)]
+        magic_link_token: String,
+        #[arg(short, long, default_value = "")]
+        terms_version: String,
+    },
+    ValidateSession {
+        #[arg(short, long)]
+        session_token: String,
+    },
+    CreateGuestSession,
+    UpgradeGuestSession {
+        #[arg(short, long)]
+        session_token: String,
+        #[arg(short, long)]
+        username: String,
+        #[arg(short, long)]
+        password: String,
+    },
+    ChangeUsername {
+        #[arg(short, long)]
+        session_token: String,
+        #[arg(short, long)]
+        new_username: String,
+    },
+    // Signs the user out everywhere; the reply carries a new session.
+    ChangePassword {
+        #[arg(short, long)]
+        session_token: String,
+        #[arg(short, long)]
+        current_password: String,
+        #[arg(short, long)]
+        new_password: String,
+    },
+    SetUserMetadata {
+        #[arg(short, long)]
+        session_token: String,
+        #[arg(short, long)]
+        key: String,
+        #[arg(short, long, default_value = "")]
+        value: String,
+    },
+    GetUserMetadata {
+        #[arg(short, long)]
+        session_token: String,
+    },
+    ExportMyData {
+        #[arg(short, long)]
+        session_token: String,
+    },
+    DeleteAccount {
+        #[arg(short, long)]
+        session_token: String,
+    },
+    AcceptTerms {
+        #[arg(short, long)]
+        username: String,
+        #[arg(short, long)]
+        password: String,
+        #[arg(short, long)]
+        version: String,
+    },
+    CreateDelegationToken {
+        #[arg(short, long)]
+        session_token: String,
+        // May be given more than once.
+        #[arg(long)]
+        scope: Vec<String>,
+        // 0 for the longest allowed.
+        #[arg(short, long, default_value_t = 0)]
+        ttl_secs: u64,
+    },
+    ListRevokedSessions {
+        // The cursor of the last reply.
+        #[arg(short, long, default_value_t = 0)]
+        cursor: u64,
+    },
+    GetServerInfo,
+    SuspendUser {
+        #[arg(short, long)]
+        user_uuid: String,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    UnsuspendUser {
+        #[arg(short, long)]
+        user_uuid: String,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    SoftDeleteUser {
+        #[arg(short, long)]
+        user_uuid: String,
+        // For the audit log.
+        #[arg(short, long, default_value = "")]
+        reason: String,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    RestoreUser {
+        #[arg(short, long)]
+        user_uuid: String,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    ApproveUser {
+        #[arg(short, long)]
+        user_uuid: String,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    RejectUser {
+        #[arg(short, long)]
+        user_uuid: String,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    ListPendingUsers {
+        #[arg(short, long, default_value_t = 0)]
+        page_size: u32,
+        // The next page token printed with the previous page.
+        #[arg(short = 't', long, default_value = "")]
+        page_token: String,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    SetUserScopes {
+        #[arg(short, long)]
+        user_uuid: String,
+        // May be given more than once; none takes all of the user's scopes away.
+        #[arg(short, long)]
+        scope: Vec<String>,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    RevokeLongLivedSessions {
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    RevokeSessions {
+        // May be given more than once.
+        #[arg(short, long)]
+        session_token: Vec<String>,
+        #[arg(short, long, default_value = "")]
+        user_uuid: String,
+        // Seconds since the epoch; 0 for all of the user's sessions.
+        #[arg(short, long, default_value_t = 0)]
+        created_before: i64,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    ExportUserData {
+        #[arg(short, long)]
+        user_uuid: String,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    ListUsers {
+        #[arg(short, long, default_value_t = 0)]
+        page_size: u32,
+        // The next page token printed with the previous page.
+        #[arg(short = 't', long, default_value = "")]
+        page_token: String,
+        #[arg(short, long)]
+        by_username: bool,
+        #[arg(short, long)]
+        descending: bool,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    // The sessions, the oldest first; the filters combine.
+    ListSessions {
+        #[arg(short, long, default_value_t = 0)]
+        page_size: u32,
+        // The next page token printed with the previous page.
+        #[arg(short = 't', long, default_value = "")]
+        page_token: String,
+        #[arg(short, long, default_value = "")]
+        user_uuid: String,
+        // Seconds since the epoch.
+        #[arg(short, long, default_value_t = 0)]
+        created_before: i64,
+        #[arg(short, long, default_value_t = 0)]
+        idle_longer_than_secs: u64,
+        // e.g. "203.0.113.0/24".
+        #[arg(long, default_value = "")]
+        ip_prefix: String,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    CreateInvite {
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    // Keeps a username for later; --release gives it back.
+    ReserveUsername {
+        #[arg(short, long)]
+        username: String,
+        #[arg(short, long, default_value_t = false)]
+        release: bool,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    // Makes a username bait for credential stuffing; --remove stops it.
+    SetHoneypotUsername {
+        #[arg(short, long)]
+        username: String,
+        #[arg(short, long, default_value_t = false)]
+        remove: bool,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    SetMaintenanceMode {
+        #[arg(short, long)]
+        enabled: bool,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    SetFeatureFlag {
+        // Empty to only list the flags.
+        #[arg(short, long, default_value = "")]
+        flag: String,
+        #[arg(short, long)]
+        enabled: bool,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    SetLogLevel {
+        #[arg(short, long)]
+        filter: String,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    GetStats {
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    // Prints the settings the service runs with, one a line.
+    GetEffectiveConfig {
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    // Prints the stats every `interval_secs` until interrupted.
+    StreamStats {
+        #[arg(short, long, default_value_t = 0)]
+        interval_secs: u32,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    ListRecentAuditEvents {
+        #[arg(short, long, default_value_t = 0)]
+        limit: u32,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    ListThrottledSources {
+        #[arg(short, long, default_value_t = 0)]
+        limit: u32,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    // Of an address, or of a username; one of the two.
+    GetRateLimitState {
+        #[arg(short, long, conflicts_with = "username", required_unless_present = "username")]
+        ip: Option<String>,
+        #[arg(short, long)]
+        username: Option<String>,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    ClearLockout {
+        #[arg(short, long)]
+        username: String,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    // Keeps the whole request and reply of this share (0 to 1) of the calls; 0 turns it off.
+    SetDebugCapture {
+        #[arg(short, long)]
+        sample_rate: f64,
+        #[arg(short, long, default_value_t = false)]
+        clear: bool,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    ListDebugCaptures {
+        #[arg(short, long, default_value_t = 0)]
+        limit: u32,
+        // e.g. "SignIn"; all of them when empty.
+        #[arg(short, long, default_value = "")]
+        method: String,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+    ReplayProjection {
+        // Seconds since the epoch; 0 to replay everything.
+        #[arg(short, long, default_value_t = 0)]
+        until: i64,
+        #[arg(short, long)]
+        admin_key: String,
+    },
+}
+
+// Adds a fresh nonce and the time, for the RPCs a deployment can protect from replays
+// (AUTH_REPLAY_PROTECTION).
+fn with_nonce<T>(mut request: Request<T>) -> Result<Request<T>, Error> {
+    let nonce = format!("{:016x}{:016x}", OsRng.next_u64(), OsRng.next_u64());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| Error::Config(format!("Error::ClockBeforeEpoch: {e}")))?.as_secs();
+    request.metadata_mut().insert("x-request-nonce", nonce.parse()?);
+    request.metadata_mut().insert("x-request-timestamp", now.to_string().parse()?);
+    Ok(request)
+}
+
+// What to send for `password`: the password itself, or the key derived from it when the
+// deployment wants passwords pre-hashed (AUTH_PASSWORD_PREHASH_ITERATIONS).
+async fn prehashed(client: &mut AuthClient<Channel>, password: String) -> Result<String, Error> {
+    let info: GetServerInfoResponse = client.get_server_info(GetServerInfoRequest {}).await?.into_inner();
+
+    match info.password_prehash {
+        None => Ok(password),
+        Some(prehash) if prehash.algorithm == PREHASH_ALGORITHM => {
+            Ok(PasswordPrehash { iterations: prehash.iterations, salt: prehash.salt }.derive(&password))
+        }
+        Some(prehash) => Err(Error::Crypto(format!("unsupported password pre-hash: {}", prehash.algorithm))),
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> ExitCode {
+    exit_with(run().await)
+}
+
+async fn run() -> Result<(), Error> {
     // AUTH_SERVICE_IP can be set to your droplet's ip address once your app is deployed
     let auth_ip = env::var("AUTH_SERVICE_IP").unwrap_or("[::0]".to_owned());
-    let mut client: AuthClient<Channel> = 
-                        AuthClient::connect(
-                            format!("http://{}:50051", auth_ip)).await?;
+    let channel: Channel = Channel::from_shared(format!("http://{}:50051", auth_ip))
+        .map_err(|e| Error::Config(format!("Error::InvalidConfig: AUTH_SERVICE_IP={auth_ip}\n{e}")))?
+        .connect()
+        .await?;
+    let mut client: AuthClient<Channel> = AuthClient::new(channel.clone());
+    let mut admin_client: AuthAdminClient<Channel> = AuthAdminClient::new(channel);
 
     let cli = ClientCommandlineContents::parse();
 
     match cli.command {
-        Some(Commands::SignIn { username, password }) => {
+        Some(Commands::SignIn { username, password, remember_me }) => {
+            let password = prehashed(&mut client, password).await?;
 
             // Create a new `SignInRequest`.
             let request: Request<SignInRequest> = 
                     tonic::Request::new(SignInRequest { 
                         username: username.clone(), 
-                        password: password.clone() 
+                        password,
+                        remember_me
                     } ); 
         
             // Make a sign in request. Propagate any errors. Convert Response<SignInResponse> into SignInResponse.
@@ -66,11 +457,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             println!("{:?}", response);
         },
 
-        Some(Commands::SignUp { username, password }) => {
+        Some(Commands::SignUp { username, password, invite_code, terms_version, challenge_response }) => {
+            let password = prehashed(&mut client, password).await?;
+
             // Create a new `SignUpRequest`.
             let request: Request<SignUpRequest> =  tonic::Request::new(SignUpRequest {
                 username: username.clone(), 
-                password: password.clone() 
+                password,
+                invite_code: invite_code.clone(),
+                accepted_terms_version: terms_version.clone(),
+                challenge_response: challenge_response.clone(),
             } );
         
             // Make a sign up request. Propagate any errors.
@@ -90,6 +486,592 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         
             println!("{:?}", response.into_inner());
         },
+
+        Some(Commands::ExchangeExternalToken { provider, id_token, terms_version }) => {
+            // Create a new `ExchangeExternalTokenRequest`.
+            let request: Request<ExchangeExternalTokenRequest> = tonic::Request::new(ExchangeExternalTokenRequest {
+                provider: provider.clone(),
+                id_token: id_token.clone(),
+                accepted_terms_version: terms_version.clone(),
+            } );
+
+            // Make an exchange request. Propagate any errors.
+            let response: Response<ExchangeExternalTokenResponse> = client.exchange_external_token(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::IntrospectToken { token, api_key }) => {
+            // Create a new `IntrospectTokenRequest`, authenticated with our API key.
+            let mut request: Request<IntrospectTokenRequest> = tonic::Request::new(IntrospectTokenRequest {
+                token: token.clone()
+            } );
+            request.metadata_mut().insert("x-api-key", api_key.parse()?);
+
+            // Make an introspection request. Propagate any errors.
+            let response: Response<IntrospectTokenResponse> = client.introspect_token(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::RequestMagicLink { username }) => {
+            // Create a new `RequestMagicLinkRequest`.
+            let request: Request<RequestMagicLinkRequest> = tonic::Request::new(RequestMagicLinkRequest {
+                username: username.clone()
+            } );
+
+            // Ask for a magic link to be mailed. Propagate any errors.
+            let response: Response<RequestMagicLinkResponse> = client.request_magic_link(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ConsumeMagicLink { magic_link_token, terms_version }) => {
+            // Create a new `ConsumeMagicLinkRequest`.
+            let request: Request<ConsumeMagicLinkRequest> = tonic::Request::new(ConsumeMagicLinkRequest {
+                magic_link_token: magic_link_token.clone(),
+                accepted_terms_version: terms_version.clone(),
+            } );
+
+            // Exchange the magic link for a session. Propagate any errors.
+            let response: Response<ConsumeMagicLinkResponse> = client.consume_magic_link(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ValidateSession { session_token }) => {
+            // Create a new `ValidateSessionRequest`.
+            let request: Request<ValidateSessionRequest> = tonic::Request::new(ValidateSessionRequest {
+                session_token: session_token.clone()
+            } );
+
+            // Check (and, with sliding expiry, extend) the session. Propagate any errors.
+            let response: Response<ValidateSessionResponse> = client.validate_session(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::CreateGuestSession) => {
+            // Create a new `CreateGuestSessionRequest`.
+            let request: Request<CreateGuestSessionRequest> = tonic::Request::new(CreateGuestSessionRequest {});
+
+            // Ask for a guest session. Propagate any errors.
+            let response: Response<CreateGuestSessionResponse> = client.create_guest_session(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::UpgradeGuestSession { session_token, username, password }) => {
+            let password = prehashed(&mut client, password).await?;
+
+            // Create a new `UpgradeGuestSessionRequest`.
+            let request: Request<UpgradeGuestSessionRequest> = tonic::Request::new(UpgradeGuestSessionRequest {
+                session_token: session_token.clone(),
+                username: username.clone(),
+                password
+            } );
+
+            // Sign the guest up. Propagate any errors.
+            let response: Response<UpgradeGuestSessionResponse> = client.upgrade_guest_session(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ChangeUsername { session_token, new_username }) => {
+            // Create a new `ChangeUsernameRequest`, with a nonce.
+            let request: Request<ChangeUsernameRequest> = tonic::Request::new(ChangeUsernameRequest {
+                session_token: session_token.clone(),
+                new_username: new_username.clone()
+            } );
+            let request = with_nonce(request)?;
+
+            // Rename the signed in user. Propagate any errors.
+            let response: Response<ChangeUsernameResponse> = client.change_username(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ChangePassword { session_token, current_password, new_password }) => {
+            // Create a new `ChangePasswordRequest`, with a nonce.
+            let request: Request<ChangePasswordRequest> = tonic::Request::new(ChangePasswordRequest {
+                session_token: session_token.clone(),
+                current_password: current_password.clone(),
+                new_password: new_password.clone()
+            } );
+            let request = with_nonce(request)?;
+
+            // Change the signed in user's password. Propagate any errors.
+            let response: Response<ChangePasswordResponse> = client.change_password(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::SetUserMetadata { session_token, key, value }) => {
+            // Create a new `SetUserMetadataRequest`. An empty value removes the key.
+            let request: Request<SetUserMetadataRequest> = tonic::Request::new(SetUserMetadataRequest {
+                session_token: session_token.clone(),
+                key: key.clone(),
+                value: value.clone()
+            } );
+
+            // Store the pair. Propagate any errors.
+            let response: Response<SetUserMetadataResponse> = client.set_user_metadata(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::GetUserMetadata { session_token }) => {
+            // Create a new `GetUserMetadataRequest`.
+            let request: Request<GetUserMetadataRequest> = tonic::Request::new(GetUserMetadataRequest {
+                session_token: session_token.clone()
+            } );
+
+            // Fetch all the pairs. Propagate any errors.
+            let response: Response<GetUserMetadataResponse> = client.get_user_metadata(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ExportMyData { session_token }) => {
+            // Create a new `ExportMyDataRequest`, with a nonce.
+            let request: Request<ExportMyDataRequest> = tonic::Request::new(ExportMyDataRequest {
+                session_token: session_token.clone()
+            } );
+            let request = with_nonce(request)?;
+
+            // Fetch everything kept about the signed in user. Propagate any errors.
+            let response: Response<ExportMyDataResponse> = client.export_my_data(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::DeleteAccount { session_token }) => {
+            // Create a new `DeleteAccountRequest`, with a nonce.
+            let request: Request<DeleteAccountRequest> = tonic::Request::new(DeleteAccountRequest {
+                session_token: session_token.clone()
+            } );
+            let request = with_nonce(request)?;
+
+            // Ask for the signed in user's account to be deleted. Propagate any errors.
+            let response: Response<DeleteAccountResponse> = client.delete_account(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::AcceptTerms { username, password, version }) => {
+            let password = prehashed(&mut client, password).await?;
+
+            // Create a new `AcceptTermsRequest`.
+            let request: Request<AcceptTermsRequest> = tonic::Request::new(AcceptTermsRequest {
+                username: username.clone(),
+                password,
+                version: version.clone()
+            } );
+
+            // Accept the terms of service. Propagate any errors.
+            let response: Response<AcceptTermsResponse> = client.accept_terms(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::CreateDelegationToken { session_token, scope, ttl_secs }) => {
+            // Create a new `CreateDelegationTokenRequest`, with a nonce.
+            let request: Request<CreateDelegationTokenRequest> = tonic::Request::new(CreateDelegationTokenRequest {
+                session_token,
+                scopes: scope,
+                ttl_secs,
+            });
+            let request = with_nonce(request)?;
+
+            // Mint the delegation token. Propagate any errors.
+            let response: Response<CreateDelegationTokenResponse> = client.create_delegation_token(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ListRevokedSessions { cursor }) => {
+            // Create a new `ListRevokedSessionsRequest`.
+            let request: Request<ListRevokedSessionsRequest> = tonic::Request::new(ListRevokedSessionsRequest { cursor });
+
+            // List the revocations since the cursor. Propagate any errors.
+            let response: Response<ListRevokedSessionsResponse> = client.list_revoked_sessions(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::GetServerInfo) => {
+            // Ask for what the deployment expects of clients. Propagate any errors.
+            let response: Response<GetServerInfoResponse> = client.get_server_info(GetServerInfoRequest {}).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::SuspendUser { user_uuid, admin_key }) => {
+            // Create a new `SuspendUserRequest`, authenticated with the admin key.
+            let mut request: Request<SuspendUserRequest> = tonic::Request::new(SuspendUserRequest {
+                user_uuid: user_uuid.clone()
+            } );
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Make a suspend request. Propagate any errors.
+            let response: Response<SuspendUserResponse> = admin_client.suspend_user(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::UnsuspendUser { user_uuid, admin_key }) => {
+            // Create a new `UnsuspendUserRequest`, authenticated with the admin key.
+            let mut request: Request<UnsuspendUserRequest> = tonic::Request::new(UnsuspendUserRequest {
+                user_uuid: user_uuid.clone()
+            } );
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Make an unsuspend request. Propagate any errors.
+            let response: Response<UnsuspendUserResponse> = admin_client.unsuspend_user(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::SoftDeleteUser { user_uuid, reason, admin_key }) => {
+            // Create a new `SoftDeleteUserRequest`, authenticated with the admin key.
+            let mut request: Request<SoftDeleteUserRequest> = tonic::Request::new(SoftDeleteUserRequest { user_uuid, reason });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Take the account down, keeping it restorable. Propagate any errors.
+            let response: Response<SoftDeleteUserResponse> = admin_client.soft_delete_user(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::RestoreUser { user_uuid, admin_key }) => {
+            // Create a new `RestoreUserRequest`, authenticated with the admin key.
+            let mut request: Request<RestoreUserRequest> = tonic::Request::new(RestoreUserRequest { user_uuid });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Bring a soft-deleted account back. Propagate any errors.
+            let response: Response<RestoreUserResponse> = admin_client.restore_user(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ApproveUser { user_uuid, admin_key }) => {
+            // Create a new `ApproveUserRequest`, authenticated with the admin key.
+            let mut request: Request<ApproveUserRequest> = tonic::Request::new(ApproveUserRequest { user_uuid });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Let the user sign in. Propagate any errors.
+            let response: Response<ApproveUserResponse> = admin_client.approve_user(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::RejectUser { user_uuid, admin_key }) => {
+            // Create a new `RejectUserRequest`, authenticated with the admin key.
+            let mut request: Request<RejectUserRequest> = tonic::Request::new(RejectUserRequest { user_uuid });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Turn the user away. Propagate any errors.
+            let response: Response<RejectUserResponse> = admin_client.reject_user(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ListPendingUsers { page_size, page_token, admin_key }) => {
+            // Create a new `ListPendingUsersRequest`, authenticated with the admin key.
+            let mut request: Request<ListPendingUsersRequest> =
+                tonic::Request::new(ListPendingUsersRequest { page_size, page_token });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Get a page of the users waiting for approval. Propagate any errors.
+            let response: Response<ListPendingUsersResponse> = admin_client.list_pending_users(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::SetUserScopes { user_uuid, scope, admin_key }) => {
+            // Create a new `SetUserScopesRequest`, authenticated with the admin key.
+            let mut request: Request<SetUserScopesRequest> = tonic::Request::new(SetUserScopesRequest {
+                user_uuid,
+                scopes: scope,
+            });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Replace the user's scopes. Propagate any errors.
+            let response: Response<SetUserScopesResponse> = admin_client.set_user_scopes(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::RevokeLongLivedSessions { admin_key }) => {
+            // Create a new `RevokeLongLivedSessionsRequest`, authenticated with the admin key.
+            let mut request: Request<RevokeLongLivedSessionsRequest> = tonic::Request::new(RevokeLongLivedSessionsRequest {});
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Make a revoke request. Propagate any errors.
+            let response: Response<RevokeLongLivedSessionsResponse> = admin_client.revoke_long_lived_sessions(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::RevokeSessions { session_token, user_uuid, created_before, admin_key }) => {
+            // Create a new `RevokeSessionsRequest`, authenticated with the admin key.
+            let mut request: Request<RevokeSessionsRequest> = tonic::Request::new(RevokeSessionsRequest {
+                session_tokens: session_token,
+                user_uuid,
+                created_before,
+            });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Revoke the sessions. Propagate any errors.
+            let response: Response<RevokeSessionsResponse> = admin_client.revoke_sessions(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ExportUserData { user_uuid, admin_key }) => {
+            // Create a new `ExportUserDataRequest`, authenticated with the admin key.
+            let mut request: Request<ExportUserDataRequest> = tonic::Request::new(ExportUserDataRequest {
+                user_uuid: user_uuid.clone()
+            } );
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Fetch everything kept about the user. Propagate any errors.
+            let response: Response<ExportUserDataResponse> = admin_client.export_user_data(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ListUsers { page_size, page_token, by_username, descending, admin_key }) => {
+            // Create a new `ListUsersRequest`, authenticated with the admin key.
+            let mut request: Request<ListUsersRequest> = tonic::Request::new(ListUsersRequest {
+                page_size,
+                page_token,
+                order_by: if by_username { UserOrder::Username } else { UserOrder::CreatedAt }.into(),
+                descending,
+                ..ListUsersRequest::default()
+            });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Get a page of users. Propagate any errors.
+            let response: Response<ListUsersResponse> = admin_client.list_users(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ListSessions {
+            page_size,
+            page_token,
+            user_uuid,
+            created_before,
+            idle_longer_than_secs,
+            ip_prefix,
+            admin_key,
+        }) => {
+            // Create a new `ListSessionsRequest`, authenticated with the admin key.
+            let mut request: Request<ListSessionsRequest> = tonic::Request::new(ListSessionsRequest {
+                page_size,
+                page_token,
+                user_uuid,
+                created_before,
+                idle_longer_than_secs,
+                ip_prefix,
+            });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Get a page of sessions. Propagate any errors.
+            let response: Response<ListSessionsResponse> = admin_client.list_sessions(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::CreateInvite { admin_key }) => {
+            // Create a new `CreateInviteRequest`, authenticated with the admin key.
+            let mut request: Request<CreateInviteRequest> = tonic::Request::new(CreateInviteRequest {});
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Make an invite. Propagate any errors.
+            let response: Response<CreateInviteResponse> = admin_client.create_invite(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ReserveUsername { username, release, admin_key }) => {
+            // Create a new `ReserveUsernameRequest`, authenticated with the admin key.
+            let mut request: Request<ReserveUsernameRequest> =
+                tonic::Request::new(ReserveUsernameRequest { username, release });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Reserve or release the username. Propagate any errors.
+            let response: Response<ReserveUsernameResponse> = admin_client.reserve_username(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::SetHoneypotUsername { username, remove, admin_key }) => {
+            // Create a new `SetHoneypotUsernameRequest`, authenticated with the admin key.
+            let mut request: Request<SetHoneypotUsernameRequest> =
+                tonic::Request::new(SetHoneypotUsernameRequest { username, remove });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Set or remove the honeypot. Propagate any errors.
+            let response: Response<SetHoneypotUsernameResponse> = admin_client.set_honeypot_username(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::SetMaintenanceMode { enabled, admin_key }) => {
+            // Create a new `SetMaintenanceModeRequest`, authenticated with the admin key.
+            let mut request: Request<SetMaintenanceModeRequest> = tonic::Request::new(SetMaintenanceModeRequest { enabled });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Turn maintenance mode on or off. Propagate any errors.
+            let response: Response<SetMaintenanceModeResponse> = admin_client.set_maintenance_mode(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::SetFeatureFlag { flag, enabled, admin_key }) => {
+            // Create a new `SetFeatureFlagRequest`, authenticated with the admin key.
+            let mut request: Request<SetFeatureFlagRequest> = tonic::Request::new(SetFeatureFlagRequest { flag, enabled });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Turn the feature flag on or off. Propagate any errors.
+            let response: Response<SetFeatureFlagResponse> = admin_client.set_feature_flag(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::SetLogLevel { filter, admin_key }) => {
+            // Create a new `SetLogLevelRequest`, authenticated with the admin key.
+            let mut request: Request<SetLogLevelRequest> = tonic::Request::new(SetLogLevelRequest { filter });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Replace the log filter. Propagate any errors.
+            let response: Response<SetLogLevelResponse> = admin_client.set_log_level(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::GetStats { admin_key }) => {
+            // Create a new `GetStatsRequest`, authenticated with the admin key.
+            let mut request: Request<GetStatsRequest> = tonic::Request::new(GetStatsRequest {});
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Get the service's stats. Propagate any errors.
+            let response: Response<GetStatsResponse> = admin_client.get_stats(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::GetEffectiveConfig { admin_key }) => {
+            // Create a new `GetEffectiveConfigRequest`, authenticated with the admin key.
+            let mut request: Request<GetEffectiveConfigRequest> = tonic::Request::new(GetEffectiveConfigRequest {});
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Get the service's settings. Propagate any errors.
+            let response: Response<GetEffectiveConfigResponse> = admin_client.get_effective_config(request).await?;
+
+            for setting in response.into_inner().settings {
+                println!("{} = {}", setting.name, setting.value);
+            }
+        },
+
+        Some(Commands::StreamStats { interval_secs, admin_key }) => {
+            // Create a new `StreamStatsRequest`, authenticated with the admin key.
+            let mut request: Request<StreamStatsRequest> = tonic::Request::new(StreamStatsRequest { interval_secs });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Watch the service's stats. Propagate any errors.
+            let mut stats = admin_client.stream_stats(request).await?.into_inner();
+
+            while let Some(stats) = stats.message().await? {
+                println!("{:?}", stats);
+            }
+        },
+
+        Some(Commands::ListRecentAuditEvents { limit, admin_key }) => {
+            // Create a new `ListRecentAuditEventsRequest`, authenticated with the admin key.
+            let mut request: Request<ListRecentAuditEventsRequest> =
+                tonic::Request::new(ListRecentAuditEventsRequest { limit });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Get the latest audit entries. Propagate any errors.
+            let response: Response<ListRecentAuditEventsResponse> = admin_client.list_recent_audit_events(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ListThrottledSources { limit, admin_key }) => {
+            // Create a new `ListThrottledSourcesRequest`, authenticated with the admin key.
+            let mut request: Request<ListThrottledSourcesRequest> =
+                tonic::Request::new(ListThrottledSourcesRequest { limit });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Get the sources the throttle is counting. Propagate any errors.
+            let response: Response<ListThrottledSourcesResponse> = admin_client.list_throttled_sources(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::GetRateLimitState { ip, username, admin_key }) => {
+            // Create a new `GetRateLimitStateRequest`, authenticated with the admin key.
+            let subject = ip.map(Subject::Ip).or(username.map(Subject::Username));
+            let mut request: Request<GetRateLimitStateRequest> =
+                tonic::Request::new(GetRateLimitStateRequest { subject });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Get what the throttle has against it. Propagate any errors.
+            let response: Response<GetRateLimitStateResponse> = admin_client.get_rate_limit_state(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ClearLockout { username, admin_key }) => {
+            // Create a new `ClearLockoutRequest`, authenticated with the admin key.
+            let mut request: Request<ClearLockoutRequest> =
+                tonic::Request::new(ClearLockoutRequest { username: username.clone() });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Clear the lockout. Propagate any errors.
+            let response: Response<ClearLockoutResponse> = admin_client.clear_lockout(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::SetDebugCapture { sample_rate, clear, admin_key }) => {
+            // Create a new `SetDebugCaptureRequest`, authenticated with the admin key.
+            let mut request: Request<SetDebugCaptureRequest> =
+                tonic::Request::new(SetDebugCaptureRequest { sample_rate, clear });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Change what share of the calls is captured. Propagate any errors.
+            let response: Response<SetDebugCaptureResponse> = admin_client.set_debug_capture(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ListDebugCaptures { limit, method, admin_key }) => {
+            // Create a new `ListDebugCapturesRequest`, authenticated with the admin key.
+            let mut request: Request<ListDebugCapturesRequest> =
+                tonic::Request::new(ListDebugCapturesRequest { limit, method });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Get the captured calls. Propagate any errors.
+            let response: Response<ListDebugCapturesResponse> = admin_client.list_debug_captures(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
+
+        Some(Commands::ReplayProjection { until, admin_key }) => {
+            // Create a new `ReplayProjectionRequest`, authenticated with the admin key.
+            let mut request: Request<ReplayProjectionRequest> = tonic::Request::new(ReplayProjectionRequest { until });
+            request.metadata_mut().insert("x-api-key", admin_key.parse()?);
+
+            // Rebuild the stores from their journals. Propagate any errors.
+            let response: Response<ReplayProjectionResponse> = admin_client.replay_projection(request).await?;
+
+            println!("{:?}", response.into_inner());
+        },
         
         None => {}
     }
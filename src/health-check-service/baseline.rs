@@ -0,0 +1,150 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+// Error rates are compared as if they were at least this, so a first failure after runs without
+// any isn't a regression of its own.
+const MIN_ERROR_RATE: f64 = 0.01;
+
+// How a run did, as kept in the baseline file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RunSummary {
+    // What was run, e.g. "Load test"; runs are only compared with runs of the same kind.
+    pub kind: String,
+    // Seconds since the epoch.
+    pub finished_at: u64,
+    pub runs: usize,
+    pub p50_ms: f64,
+    pub p99_ms: f64,
+    pub error_rate: f64,
+}
+
+impl RunSummary {
+    pub fn new(kind: &str, runs: usize, p50_ms: f64, p99_ms: f64, error_rate: f64) -> Self {
+        let finished_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        Self { kind: kind.to_owned(), finished_at, runs, p50_ms, p99_ms, error_rate }
+    }
+}
+
+// The latest runs (--baseline-runs of each kind), kept in a JSON file (--baseline) from one run of
+// the health check to the next. A run is compared with their mean: latency or errors more than
+// --regression-pct over it is a regression.
+pub struct Baseline {
+    path: PathBuf,
+    keep: usize,
+    history: Vec<RunSummary>,
+}
+
+impl Baseline {
+    // Starts out empty when there is no file yet.
+    pub fn load(path: &str, keep: usize) -> Result<Self, String> {
+        let history = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| format!("Failed to read the baseline {path}.\n{e:?}"))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("Failed to read the baseline {path}.\n{e:?}")),
+        };
+        Ok(Self { path: PathBuf::from(path), keep: keep.max(1), history })
+    }
+
+    // The mean of the kept runs of that kind; None before the first.
+    pub fn rolling(&self, kind: &str) -> Option<RunSummary> {
+        let runs: Vec<&RunSummary> = self.history.iter().filter(|run| run.kind == kind).collect();
+        if runs.is_empty() {
+            return None;
+        }
+        let mean = |field: fn(&RunSummary) -> f64| runs.iter().map(|run| field(run)).sum::<f64>() / runs.len() as f64;
+
+        Some(RunSummary {
+            kind: kind.to_owned(),
+            finished_at: runs.iter().map(|run| run.finished_at).max().unwrap_or_default(),
+            runs: runs.iter().map(|run| run.runs).sum::<usize>() / runs.len(),
+            p50_ms: mean(|run| run.p50_ms),
+            p99_ms: mean(|run| run.p99_ms),
+            error_rate: mean(|run| run.error_rate),
+        })
+    }
+
+    // What got worse than the rolling baseline by more than `tolerance_pct` percent, one line each.
+    pub fn regressions(&self, summary: &RunSummary, tolerance_pct: f64) -> Vec<String> {
+        let Some(baseline) = self.rolling(&summary.kind) else { return Vec::new() };
+        let limit = 1.0 + tolerance_pct / 100.0;
+        let over = |now: f64, then: f64| (now - then) / then * 100.0;
+
+        let mut regressions = Vec::new();
+        for (what, now, then) in [("p50 latency", summary.p50_ms, baseline.p50_ms), ("p99 latency", summary.p99_ms, baseline.p99_ms)] {
+            if then > 0.0 && now > then * limit {
+                regressions.push(format!("{what} {now:.1}ms, {:.0}% over the baseline of {then:.1}ms", over(now, then)));
+            }
+        }
+        let then = baseline.error_rate.max(MIN_ERROR_RATE);
+        if summary.error_rate > then * limit {
+            regressions.push(format!(
+                "error rate {:.1}%, {:.0}% over the baseline of {:.1}%",
+                summary.error_rate * 100.0,
+                over(summary.error_rate, then),
+                baseline.error_rate * 100.0,
+            ));
+        }
+        regressions
+    }
+
+    // Adds the run to the baseline, forgetting the oldest of its kind past `keep`, and writes the
+    // file. Written aside first, so a run stopped halfway doesn't leave half a file.
+    pub fn record(&mut self, summary: RunSummary) -> Result<(), String> {
+        let mut forgotten = self.history.iter().filter(|run| run.kind == summary.kind).count().saturating_sub(self.keep - 1);
+        self.history.retain(|run| {
+            let forget = forgotten > 0 && run.kind == summary.kind;
+            forgotten -= forget as usize;
+            !forget
+        });
+        self.history.push(summary);
+
+        let json = serde_json::to_string_pretty(&self.history).map_err(|e| e.to_string())?;
+        let partial = self.path.with_extension("partial");
+        fs::write(&partial, json)
+            .and_then(|_| fs::rename(&partial, &self.path))
+            .map_err(|e| format!("Failed to write the baseline {}.\n{e:?}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(kind: &str, p50_ms: f64, p99_ms: f64, error_rate: f64) -> RunSummary {
+        RunSummary::new(kind, 100, p50_ms, p99_ms, error_rate)
+    }
+
+    #[test]
+    fn should_flag_what_got_worse_than_the_rolling_baseline() {
+        let path = std::env::temp_dir().join(format!("baseline-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut baseline = Baseline::load(path, 2).unwrap();
+        assert!(baseline.regressions(&run("Load test", 500.0, 900.0, 0.5), 20.0).is_empty());
+
+        baseline.record(run("Load test", 5_000.0, 9_000.0, 0.0)).unwrap();
+        baseline.record(run("Load test", 10.0, 20.0, 0.0)).unwrap();
+        baseline.record(run("Load test", 14.0, 30.0, 0.0)).unwrap();
+        baseline.record(run("Soak run", 1.0, 1.0, 0.0)).unwrap();
+
+        // The first run is forgotten, and soak runs count apart.
+        let baseline = Baseline::load(path, 2).unwrap();
+        assert_eq!(baseline.rolling("Load test").unwrap().p50_ms, 12.0);
+        assert!(baseline.regressions(&run("Load test", 14.0, 30.0, 0.01), 20.0).is_empty());
+
+        let regressions = baseline.regressions(&run("Load test", 14.0, 50.0, 0.05), 20.0);
+        assert_eq!(
+            regressions,
+            vec![
+                String::from("p99 latency 50.0ms, 100% over the baseline of 25.0ms"),
+                String::from("error rate 5.0%, 400% over the baseline of 0.0%"),
+            ]
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
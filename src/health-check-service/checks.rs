@@ -0,0 +1,248 @@
+use std::{fmt, time::Duration};
+
+use auth::error::Error;
+use auth_healthcheck::{run_cycle_with, CycleReport, Ids, Outcome, TestAccount};
+use tokio::time::timeout;
+use tonic::{transport::Channel, Code};
+
+use crate::authentication::auth_client::AuthClient;
+use crate::authentication::ValidateSessionRequest;
+use crate::metrics::LatencyMetrics;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Passed,
+    Failed(String),
+    // No answer within the timeout: the service may be hung, rather than failing.
+    TimedOut,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Passed => write!(f, "OK"),
+            Verdict::Failed(reason) => write!(f, "FAILED {reason}"),
+            Verdict::TimedOut => write!(f, "TIMED OUT"),
+        }
+    }
+}
+
+// What the checks of a round share, for one target. Checks run in the order they were registered,
+// so a later one may look at what an earlier one left (e.g. `cycle`).
+pub struct CheckContext<'a> {
+    pub target: &'a str,
+    pub timeout: Duration,
+    pub ids: &'a Ids,
+    pub account: &'a TestAccount,
+    pub metrics: &'a LatencyMetrics,
+    // The round's sign up / sign in / sign out cycle, once `AuthCycleCheck` ran it.
+    pub cycle: Option<CycleReport>,
+}
+
+// One probe of a round. An error means the target couldn't be asked at all; a verdict, that it
+// answered, well or not.
+#[tonic::async_trait]
+pub trait Check: Send + Sync {
+    // As given to --enable-check and --disable-check.
+    fn name(&self) -> &'static str;
+
+    // Whether the check runs unless --disable-check says otherwise; the others need --enable-check.
+    fn enabled_by_default(&self) -> bool {
+        true
+    }
+
+    async fn run(&self, client: &mut AuthClient<Channel>, cx: &mut CheckContext<'_>) -> Result<Verdict, Error>;
+}
+
+// The target answers at all, whatever it thinks of the (made up) session token. Not an empty one,
+// which is refused before the service looks at anything.
+pub struct ConnectCheck;
+
+#[tonic::async_trait]
+impl Check for ConnectCheck {
+    fn name(&self) -> &'static str {
+        "connect"
+    }
+
+    async fn run(&self, client: &mut AuthClient<Channel>, cx: &mut CheckContext<'_>) -> Result<Verdict, Error> {
+        let request = ValidateSessionRequest { session_token: String::from("health-check") };
+        match timeout(cx.timeout, client.validate_session(request)).await {
+            Ok(Ok(_)) => Ok(Verdict::Passed),
+            Ok(Err(status)) if matches!(status.code(), Code::Unavailable | Code::Unknown) => Err(status.into()),
+            Ok(Err(status)) => Ok(Verdict::Failed(format!("{:?}", status.code()))),
+            Err(_) => Ok(Verdict::TimedOut),
+        }
+    }
+}
+
+// One line per step of the cycle, with what it answered.
+pub fn log_steps(report: &CycleReport) {
+    for step in &report.steps {
+        let name = step.name.replace('_', " ").to_uppercase();
+        match step.outcome {
+            Outcome::TimedOut => println!("{} TIMED OUT after {:?}", name, step.latency),
+            Outcome::Answered(_) => println!("{} RESPONSE STATUS: {:?}", name, step.status()),
+        }
+    }
+}
+
+// The health check's cycle (see `run_cycle_with`), logged step by step. A session that isn't valid
+// after sign in, or still is after sign out, is raised as an alert: the service is up, but wrong.
+pub struct AuthCycleCheck;
+
+#[tonic::async_trait]
+impl Check for AuthCycleCheck {
+    fn name(&self) -> &'static str {
+        "auth_cycle"
+    }
+
+    async fn run(&self, client: &mut AuthClient<Channel>, cx: &mut CheckContext<'_>) -> Result<Verdict, Error> {
+        let report = run_cycle_with(client, cx.account, cx.timeout, cx.ids).await?;
+        cx.metrics.record(cx.target, &report);
+        log_steps(&report);
+
+        let violations = report.session_violations();
+        for step in &violations {
+            println!("ALERT: session invariant violated on {}: {} answered {:?}", cx.target, step.name, step.status());
+        }
+
+        let verdict = match report.steps.iter().find(|step| !step.passed()) {
+            None => Verdict::Passed,
+            Some(_) if !violations.is_empty() => {
+                Verdict::Failed(format!("session invariant violated: {}", violations[0].name))
+            }
+            Some(step) if step.timed_out() => Verdict::TimedOut,
+            Some(step) => Verdict::Failed(format!("{} {:?}", step.name, step.status())),
+        };
+        cx.cycle = Some(report);
+        Ok(verdict)
+    }
+}
+
+// The round's cycle took no longer than `max_latency` overall (--latency-slo-ms).
+pub struct LatencySloCheck {
+    pub max_latency: Duration,
+}
+
+#[tonic::async_trait]
+impl Check for LatencySloCheck {
+    fn name(&self) -> &'static str {
+        "latency_slo"
+    }
+
+    async fn run(&self, _client: &mut AuthClient<Channel>, cx: &mut CheckContext<'_>) -> Result<Verdict, Error> {
+        let Some(cycle) = &cx.cycle else {
+            return Ok(Verdict::Failed(String::from("needs the auth_cycle check")));
+        };
+
+        Ok(match cycle.latency() > self.max_latency {
+            true => Verdict::Failed(format!("{:?} over {:?}", cycle.latency(), self.max_latency)),
+            false => Verdict::Passed,
+        })
+    }
+}
+
+// The checks a round runs, in order. New probes only need registering here.
+pub struct CheckRegistry(Vec<Box<dyn Check>>);
+
+impl CheckRegistry {
+    pub fn builtin(latency_slo: Duration) -> Self {
+        Self(vec![
+            Box::new(ConnectCheck),
+            Box::new(AuthCycleCheck),
+            Box::new(LatencySloCheck { max_latency: latency_slo }),
+        ])
+    }
+
+    // Adds a check after the others, e.g. one that only makes sense for some deployments.
+    pub fn with(mut self, check: Box<dyn Check>) -> Self {
+        self.0.push(check);
+        self
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.0.iter().map(|check| check.name()).collect()
+    }
+
+    // Keeps the checks enabled by default, and those in `enable`, unless they are in `disable`.
+    pub fn select(self, enable: &[String], disable: &[String]) -> Result<Self, String> {
+        let names = self.names();
+        if let Some(unknown) = enable.iter().chain(disable).find(|name| !names.contains(&name.as_str())) {
+            return Err(format!("Error::UnknownCheck: {unknown} (known: {})", names.join(", ")));
+        }
+
+        let listed = |names: &[String], check: &dyn Check| names.iter().any(|name| name == check.name());
+        Ok(Self(
+            self.0
+                .into_iter()
+                .filter(|check| (check.enabled_by_default() || listed(enable, check.as_ref())) && !listed(disable, check.as_ref()))
+                .collect(),
+        ))
+    }
+
+    // Runs every check against the target, logging each verdict. Stops at the first one that
+    // couldn't reach it.
+    pub async fn run(
+        &self,
+        client: &mut AuthClient<Channel>,
+        cx: &mut CheckContext<'_>,
+    ) -> Result<Vec<(&'static str, Verdict)>, Error> {
+        let mut verdicts = Vec::new();
+
+        for check in &self.0 {
+            let verdict = check.run(client, cx).await?;
+            println!("CHECK {}: {}", check.name(), verdict);
+            verdicts.push((check.name(), verdict));
+        }
+
+        Ok(verdicts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_select_checks_by_name() {
+        let registry = || CheckRegistry::builtin(Duration::from_secs(1));
+
+        assert_eq!(registry().select(&[], &[]).unwrap().names(), vec!["connect", "auth_cycle", "latency_slo"]);
+
+        let disable = [String::from("latency_slo")];
+        assert_eq!(registry().select(&[], &disable).unwrap().names(), vec!["connect", "auth_cycle"]);
+
+        assert!(registry().select(&[String::from("everything")], &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_judge_latency_by_the_rounds_cycle() {
+        let check = LatencySloCheck { max_latency: Duration::from_millis(100) };
+        let mut client = AuthClient::new(tonic::transport::Endpoint::from_static("http://[::1]:1").connect_lazy());
+        let (ids, metrics) = (Ids::default(), LatencyMetrics::default());
+        let mut cx = CheckContext {
+            target: "auth",
+            timeout: Duration::from_secs(1),
+            ids: &ids,
+            account: &TestAccount::Throwaway,
+            metrics: &metrics,
+            cycle: None,
+        };
+
+        assert!(matches!(check.run(&mut client, &mut cx).await.unwrap(), Verdict::Failed(_)));
+
+        let step = |millis| auth_healthcheck::StepReport {
+            name: "sign_in",
+            outcome: Outcome::Answered(crate::authentication::StatusCode::Success as i32),
+            latency: Duration::from_millis(millis),
+            server_timing: None,
+            exemplar: None,
+            expect_success: true,
+        };
+        cx.cycle = Some(CycleReport { username: String::new(), steps: vec![step(40), step(40)] });
+        assert_eq!(check.run(&mut client, &mut cx).await.unwrap(), Verdict::Passed);
+
+        cx.cycle = Some(CycleReport { username: String::new(), steps: vec![step(40), step(80)] });
+        assert!(matches!(check.run(&mut client, &mut cx).await.unwrap(), Verdict::Failed(_)));
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use tokio::{task::JoinSet, time::sleep_until};
+use yaml_rust::Yaml;
+
+// How many runs a load test starts per second: from `start_rps` up to `peak_rps` over `ramp_up`,
+// held there for `hold`, then back down to `start_rps` over `ramp_down`. Without a ramp up, the
+// test starts right at the peak.
+//
+// In a scenario file:
+//
+//   load: { start_rps: 5, peak_rps: 50, ramp_up_secs: 300, hold_secs: 600, ramp_down_secs: 120 }
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoadProfile {
+    pub start_rps: f64,
+    pub peak_rps: f64,
+    pub ramp_up: Duration,
+    pub hold: Duration,
+    pub ramp_down: Duration,
+}
+
+fn secs_field(load: &Yaml, field: &str, default: Duration) -> Result<Duration, String> {
+    match &load[field] {
+        Yaml::BadValue => Ok(default),
+        value => value
+            .as_f64()
+            .or_else(|| value.as_i64().map(|secs| secs as f64))
+            .filter(|secs| *secs >= 0.0)
+            .map(Duration::from_secs_f64)
+            .ok_or_else(|| format!("Error::InvalidScenario: load {field} needs seconds")),
+    }
+}
+
+fn rps_field(load: &Yaml, field: &str, default: f64) -> Result<f64, String> {
+    match &load[field] {
+        Yaml::BadValue => Ok(default),
+        value => value
+            .as_f64()
+            .or_else(|| value.as_i64().map(|rps| rps as f64))
+            .filter(|rps| *rps >= 0.0)
+            .ok_or_else(|| format!("Error::InvalidScenario: load {field} needs a rate")),
+    }
+}
+
+impl LoadProfile {
+    // A scenario's `load` section; what it leaves out comes from `defaults` (the flags).
+    pub fn from_yaml(load: &Yaml, defaults: LoadProfile) -> Result<Self, String> {
+        if load.as_hash().is_none() {
+            return Err(String::from("Error::InvalidScenario: load needs start_rps, peak_rps, ..."));
+        }
+
+        Ok(Self {
+            start_rps: rps_field(load, "start_rps", defaults.start_rps)?,
+            peak_rps: rps_field(load, "peak_rps", defaults.peak_rps)?,
+            ramp_up: secs_field(load, "ramp_up_secs", defaults.ramp_up)?,
+            hold: secs_field(load, "hold_secs", defaults.hold)?,
+            ramp_down: secs_field(load, "ramp_down_secs", defaults.ramp_down)?,
+        })
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.ramp_up + self.hold + self.ramp_down
+    }
+
+    // The rate `elapsed` into the test; None once it is over.
+    pub fn rate_at(&self, elapsed: Duration) -> Option<f64> {
+        let between = |from: f64, to: f64, done: Duration, of: Duration| {
+            from + (to - from) * done.as_secs_f64() / of.as_secs_f64()
+        };
+
+        if elapsed < self.ramp_up {
+            Some(between(self.start_rps, self.peak_rps, elapsed, self.ramp_up))
+        } else if elapsed < self.ramp_up + self.hold {
+            Some(self.peak_rps)
+        } else if elapsed < self.duration() {
+            Some(between(self.peak_rps, self.start_rps, elapsed - self.ramp_up - self.hold, self.ramp_down))
+        } else {
+            None
+        }
+    }
+}
+
+// The runs that finished since the last report.
+#[derive(Default)]
+struct Window {
+    runs: u32,
+    errors: u32,
+    total_latency: Duration,
+}
+
+impl Window {
+    fn record(&mut self, result: &Result<(), String>, latency: Duration) {
+        self.runs += 1;
+        self.errors += u32::from(result.is_err());
+        self.total_latency += latency;
+    }
+
+    fn report(&self, elapsed: Duration, rate: f64, in_flight: usize) {
+        println!(
+            "LOAD {:?}: rate={:.1}/s finished={} errors={} avg_latency={:?} in_flight={}",
+            elapsed,
+            rate,
+            self.runs,
+            self.errors,
+            self.total_latency.checked_div(self.runs).unwrap_or_default(),
+            in_flight,
+        );
+    }
+}
+
+// How often the rate is looked at again, at the longest, so a ramp starting from (about) zero
+// isn't waited out.
+const MAX_TICK: Duration = Duration::from_millis(100);
+
+// Starts `run(n)` for the nth run at the profile's rate, without waiting for the earlier ones to
+// finish, so a service falling behind sees the load pile up as it would in production. Reports
+// every `report_interval`, and returns how many runs failed once the last one finished.
+pub async fn run_load<F, Fut>(profile: LoadProfile, report_interval: Duration, mut run: F) -> u32
+where
+    F: FnMut(usize) -> Fut,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    let started_at = Instant::now();
+    let mut in_flight = JoinSet::new();
+    let mut window = Window::default();
+    let mut errors = 0;
+    let mut next_report = started_at + report_interval;
+    let mut started = 0;
+
+    // Runs owed so far, started whenever a whole one is: the first one right away.
+    let mut owed = 1.0;
+    let mut tick = started_at;
+    let mut last_tick = started_at;
+
+    while let Some(rate) = profile.rate_at(tick - started_at) {
+        tokio::select! {
+            _ = sleep_until(tick.into()) => {
+                owed += rate * (tick - last_tick).as_secs_f64();
+                last_tick = tick;
+
+                while owed >= 1.0 {
+                    let run = run(started);
+                    in_flight.spawn(async move {
+                        let run_started_at = Instant::now();
+                        let result = run.await;
+                        (result, run_started_at.elapsed())
+                    });
+                    started += 1;
+                    owed -= 1.0;
+                }
+
+                let until_next = if rate > 0.0 { Duration::from_secs_f64((1.0 - owed) / rate) } else { MAX_TICK };
+                tick += until_next.min(MAX_TICK);
+            }
+            Some(Ok((result, latency))) = in_flight.join_next(), if !in_flight.is_empty() => {
+                if let Err(e) = &result {
+                    println!("LOAD RUN FAILED: {}", e);
+                    errors += 1;
+                }
+                window.record(&result, latency);
+            }
+        }
+
+        if Instant::now() >= next_report {
+            window.report(started_at.elapsed(), rate, in_flight.len());
+            window = Window::default();
+            next_report += report_interval;
+        }
+    }
+
+    while let Some(Ok((result, latency))) = in_flight.join_next().await {
+        if let Err(e) = &result {
+            println!("LOAD RUN FAILED: {}", e);
+            errors += 1;
+        }
+        window.record(&result, latency);
+    }
+    window.report(started_at.elapsed(), profile.start_rps, 0);
+    println!("LOAD DONE: {} runs, {} failed", started, errors);
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use yaml_rust::YamlLoader;
+
+    use super::*;
+
+    fn profile() -> LoadProfile {
+        LoadProfile {
+            start_rps: 10.0,
+            peak_rps: 50.0,
+            ramp_up: Duration::from_secs(60),
+            hold: Duration::from_secs(120),
+            ramp_down: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn should_ramp_up_hold_and_ramp_down() {
+        let profile = profile();
+
+        assert_eq!(profile.rate_at(Duration::ZERO), Some(10.0));
+        assert_eq!(profile.rate_at(Duration::from_secs(30)), Some(30.0));
+        assert_eq!(profile.rate_at(Duration::from_secs(60)), Some(50.0));
+        assert_eq!(profile.rate_at(Duration::from_secs(179)), Some(50.0));
+        assert_eq!(profile.rate_at(Duration::from_secs(195)), Some(30.0));
+        assert_eq!(profile.rate_at(Duration::from_secs(210)), None);
+
+        // No ramp: a step to the peak, and off again.
+        let step = LoadProfile { ramp_up: Duration::ZERO, ramp_down: Duration::ZERO, ..profile };
+        assert_eq!(step.rate_at(Duration::ZERO), Some(50.0));
+        assert_eq!(step.rate_at(Duration::from_secs(120)), None);
+    }
+
+    #[test]
+    fn should_take_the_profile_from_a_scenario_over_the_flags() {
+        let docs = YamlLoader::load_from_str("load: { peak_rps: 200, hold_secs: 0.5 }").unwrap();
+        let loaded = LoadProfile::from_yaml(&docs[0]["load"], profile()).unwrap();
+
+        assert_eq!(loaded, LoadProfile { peak_rps: 200.0, hold: Duration::from_millis(500), ..profile() });
+
+        let docs = YamlLoader::load_from_str("load: { peak_rps: -1 }").unwrap();
+        assert!(LoadProfile::from_yaml(&docs[0]["load"], profile()).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_start_runs_at_the_profile_rate() {
+        let profile = LoadProfile {
+            start_rps: 100.0,
+            peak_rps: 100.0,
+            ramp_up: Duration::ZERO,
+            hold: Duration::from_millis(200),
+            ramp_down: Duration::ZERO,
+        };
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let counted = runs.clone();
+        let errors = run_load(profile, Duration::from_secs(60), move |n| {
+            counted.fetch_add(1, Ordering::Relaxed);
+            async move { if n % 2 == 0 { Ok(()) } else { Err(format!("run {n}")) } }
+        })
+        .await;
+
+        // One every 10ms, give or take the last one.
+        let runs = runs.load(Ordering::Relaxed);
+        assert!((19..=21).contains(&runs), "{runs} runs");
+        assert_eq!(errors as usize, runs / 2);
+    }
+}
@@ -1,81 +1,563 @@
-use std::env;
+use std::{env, net::SocketAddr, process::ExitCode, sync::Arc, time::Instant};
 
+mod baseline;
+mod chaos;
+mod checks;
+mod load;
+mod metrics;
+mod pool;
+mod profile;
+mod report;
+mod scenario;
+mod soak;
+mod tls;
+
+use auth::error::{exit_with, Error};
+use auth_healthcheck::{preflight::proto_drift, run_cycle_with, CycleReport, Ids, TestAccount};
+use auth_ids::ids_from_env;
 use authentication::auth_client::AuthClient;
-use authentication::{SignInRequest, SignOutRequest, SignUpRequest};
+use clap::Parser;
+use opentelemetry::{
+    global,
+    sdk::{trace::{self, TracerProvider}, Resource},
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
 use tokio::time::{sleep, Duration};
-use tonic::{Request, Response};
-use uuid::Uuid;
+use tokio::net::UnixStream;
+use tonic::{
+    codec::CompressionEncoding,
+    transport::{Channel, Endpoint, Uri},
+};
+use tower::service_fn;
+
+use crate::baseline::Baseline;
+use crate::chaos::run_chaos;
+use crate::checks::{log_steps, CheckContext, CheckRegistry, Verdict};
+use crate::load::{run_load, LoadProfile};
+use crate::metrics::LatencyMetrics;
+use crate::pool::ChannelPool;
+use crate::profile::Profiler;
+use crate::report::{failure, RunRecorder};
+use crate::scenario::Scenario;
+use crate::soak::{Resources, SoakTracker};
+use crate::tls::{CertExpiryCheck, TargetTls};
+
+// The generated types come with the cycle, so both agree on them.
+pub use auth_healthcheck::authentication;
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct HealthCheckArgs {
+    // Seconds between two rounds
+    #[arg(long, default_value_t = 3)]
+    interval_secs: u64,
+    // Run for `soak_hours`, watching for leaks and degradation, instead of forever
+    #[arg(long)]
+    soak: bool,
+    #[arg(long, default_value_t = 8.0)]
+    soak_hours: f64,
+    // Seconds between two soak samples, or load test reports
+    #[arg(long, default_value_t = 60)]
+    sample_secs: u64,
+    // Seconds a single call may take before it counts as timed out
+    #[arg(long, default_value_t = 10.0)]
+    rpc_timeout_secs: f64,
+    // Send malformed and oversized input once, and fail unless every call got a proper answer
+    #[arg(long)]
+    chaos: bool,
+    // Start rounds at a rate ramping from `start_rps` to `peak_rps` and back (see `LoadProfile`),
+    // without waiting for the earlier ones, and report every `sample_secs`
+    #[arg(long)]
+    load: bool,
+    #[arg(long, default_value_t = 1.0)]
+    start_rps: f64,
+    #[arg(long, default_value_t = 10.0)]
+    peak_rps: f64,
+    #[arg(long, default_value_t = 60)]
+    ramp_up_secs: u64,
+    #[arg(long, default_value_t = 300)]
+    hold_secs: u64,
+    #[arg(long, default_value_t = 60)]
+    ramp_down_secs: u64,
+    // Where to write a report of the load test or soak run once it is over: HTML for a path ending
+    // in .html, Markdown otherwise
+    #[arg(long)]
+    report: Option<String>,
+    // A JSON file keeping the latest load tests and soak runs, to compare each one with: latency
+    // or errors more than `regression_pct` percent over the mean of the last `baseline_runs` of
+    // its kind fail it (see `Baseline`)
+    #[arg(long)]
+    baseline: Option<String>,
+    #[arg(long, default_value_t = 20.0)]
+    regression_pct: f64,
+    #[arg(long, default_value_t = 10)]
+    baseline_runs: usize,
+    // Takes CPU profiles of the auth-service throughout a load test, `profile_secs` each, from its
+    // profiling endpoint (AUTH_SERVICE_PROFILE_URL, with the admin API key of AUTH_ADMIN_API_KEY),
+    // and writes them to `profile_dir` as folded stacks, listed in the report
+    #[arg(long)]
+    profile: bool,
+    #[arg(long, default_value_t = 30)]
+    profile_secs: u64,
+    #[arg(long, default_value_t = String::from("profiles"))]
+    profile_dir: String,
+    // Checks a round runs besides the default ones (see `CheckRegistry`)
+    #[arg(long = "enable-check")]
+    enable_checks: Vec<String>,
+    // Default checks a round skips, e.g. connect
+    #[arg(long = "disable-check")]
+    disable_checks: Vec<String>,
+    // Milliseconds a round's cycle may take, for the latency_slo check
+    #[arg(long, default_value_t = 2000)]
+    latency_slo_ms: u64,
+    // HTTP/2 channels to each target, shared by every run of a load test
+    #[arg(long, default_value_t = 1)]
+    pool_size: usize,
+    // Calls a channel carries at once; runs past that wait for one to free up
+    #[arg(long, default_value_t = 100)]
+    max_in_flight_per_channel: usize,
+    // Days before a target's certificate expires that the cert_expiry check warns, and fails
+    #[arg(long, default_value_t = 30)]
+    cert_warn_days: u64,
+    #[arg(long, default_value_t = 7)]
+    cert_fail_days: u64,
+}
+
+impl HealthCheckArgs {
+    // Writes what the run saw to `report`, if asked to.
+    fn write_report(&self, recorder: &RunRecorder, title: &str) -> Result<(), String> {
+        let Some(path) = &self.report else { return Ok(()) };
+
+        recorder.write(path, title, Duration::from_secs(self.sample_secs))?;
+        println!("REPORT WRITTEN TO {}", path);
+        Ok(())
+    }
+
+    // Compares the run with the baseline, if there is one, then adds it; the regressions found.
+    fn check_baseline(&self, recorder: &RunRecorder, kind: &str) -> Result<usize, String> {
+        let Some(path) = &self.baseline else { return Ok(0) };
+
+        let mut baseline = Baseline::load(path, self.baseline_runs)?;
+        let summary = recorder.summary(kind);
+        let regressions = baseline.regressions(&summary, self.regression_pct);
+        for regression in &regressions {
+            println!("REGRESSION: {}", regression);
+        }
+        baseline.record(summary)?;
+        Ok(regressions.len())
+    }
+
+    // What takes the CPU profiles, if asked to.
+    fn profiler(&self) -> Result<Option<Profiler>, String> {
+        if !self.profile {
+            return Ok(None);
+        }
+        let var = |name: &str| env::var(name).map_err(|_| format!("Error::InvalidConfig: --profile needs {name}"));
 
-use crate::authentication::{StatusCode, SignUpResponse, SignInResponse, SignOutResponse};
+        Profiler::new(&var("AUTH_SERVICE_PROFILE_URL")?, &var("AUTH_ADMIN_API_KEY")?, Duration::from_secs(self.profile_secs), &self.profile_dir)
+            .map(Some)
+    }
 
-pub mod authentication {
-    tonic::include_proto!("authentication");
+    fn load_profile(&self) -> LoadProfile {
+        LoadProfile {
+            start_rps: self.start_rps,
+            peak_rps: self.peak_rps,
+            ramp_up: Duration::from_secs(self.ramp_up_secs),
+            hold: Duration::from_secs(self.hold_secs),
+            ramp_down: Duration::from_secs(self.ramp_down_secs),
+        }
+    }
 }
 
+// Exits with a code telling what went wrong (see `Error::exit_code`): a bad setting, or targets
+// that didn't answer as they should.
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> ExitCode {
+    exit_with(run().await)
+}
+
+async fn run() -> Result<(), Error> {
+    let args = HealthCheckArgs::parse();
+
+    // HEALTH_CHECK_ID_SEED makes the test users' names and passwords the same on every run, in
+    // builds with the deterministic-ids feature.
+    let ids = ids_from_env("HEALTH_CHECK_ID_SEED").map_err(Error::Config)?;
+
+    init_tracing().map_err(|e| Error::Config(e.to_string()))?;
+
+    // HEALTH_CHECK_METRICS_ADDR serves the steps' latencies, with their traces as exemplars.
+    let metrics = LatencyMetrics::default();
+    if let Ok(addr) = env::var("HEALTH_CHECK_METRICS_ADDR") {
+        let addr: SocketAddr = addr.parse().map_err(|e| Error::Config(format!("Error::InvalidConfig: HEALTH_CHECK_METRICS_ADDR={addr}\n{e:?}")))?;
+        let server = metrics.clone().serve(addr)?;
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                println!("METRICS LISTENER FAILED: {:?}", e);
+            }
+        });
+    }
+
     // AUTH_SERVICE_HOST_NAME will be set to 'auth' when running the health check service in Docker
     // ::0 is required for Docker to work: https://stackoverflow.com/questions/59179831/docker-app-server-ip-address-127-0-0-1-difference-of-0-0-0-0-ip
-    let auth_hostname = env::var("AUTH_SERVICE_HOST_NAME").unwrap_or("[::0]".to_owned());
+    // Several replicas can be checked at once by separating their host names with commas; each may
+    // come with its own port (auth-1,auth-2:50052).
+    let auth_hostnames = env::var("AUTH_SERVICE_HOST_NAME").unwrap_or("[::0]".to_owned());
+
+    // AUTH_GRPC_COMPRESSION=gzip compresses requests and asks for compressed responses.
+    let gzip = env::var("AUTH_GRPC_COMPRESSION").as_deref() == Ok("gzip");
+    let client = |channel| match gzip {
+        true => AuthClient::new(channel).send_compressed(CompressionEncoding::Gzip).accept_compressed(CompressionEncoding::Gzip),
+        false => AuthClient::new(channel),
+    };
+    let pool_size = args.pool_size.max(1);
+
+    // AUTH_SERVICE_TLS_CA (a PEM file) reaches the targets over TLS, trusting that CA, and checks
+    // how long their certificates have left every round.
+    let tls = env::var("AUTH_SERVICE_TLS_CA").ok().map(|path| TargetTls::from_file(&path)).transpose().map_err(Error::Crypto)?;
+
+    // Establish connection when auth service. With AUTH_SERVICE_UNIX_SOCKET set, the connection goes
+    // through that socket instead (the host names are then ignored).
+    let targets = match env::var("AUTH_SERVICE_UNIX_SOCKET") {
+        Ok(path) => {
+            let mut clients = Vec::new();
+            for _ in 0..pool_size {
+                let path = path.clone();
+                let channel = Endpoint::try_from("http://[::]:50051")?
+                    .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(path.clone())))
+                    .await?;
+                clients.push(client(channel));
+            }
+            vec![(String::from("unix socket"), Arc::new(ChannelPool::new(clients, args.max_in_flight_per_channel)))]
+        }
+        // Lazily, so a replica that is down shows up in its results instead of stopping the others.
+        Err(_) => auth_hostnames
+            .split(',')
+            .map(str::trim)
+            .filter(|host| !host.is_empty())
+            .map(|host| {
+                let address = if host.ends_with(']') || !host.contains(':') { format!("{host}:50051") } else { host.to_owned() };
+                let endpoint = match &tls {
+                    Some(tls) => Endpoint::try_from(format!("https://{address}"))?.tls_config(tls.client_config(&address))?,
+                    None => Endpoint::try_from(format!("http://{address}"))?,
+                };
+                let clients = (0..pool_size).map(|_| client(endpoint.connect_lazy())).collect();
+                Ok((address, Arc::new(ChannelPool::new(clients, args.max_in_flight_per_channel))))
+            })
+            .collect::<Result<Vec<_>, tonic::transport::Error>>()?,
+    };
+
+    // Only a warning: a target built from another proto may still pass, or fail for good reasons.
+    for (target, pool) in &targets {
+        let timeout = Duration::from_secs_f64(args.rpc_timeout_secs);
+        match tokio::time::timeout(timeout, proto_drift(&mut *pool.get().await)).await {
+            Ok(Ok(Some(drift))) => println!("TARGET {}: WARNING: {}", target, drift),
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => println!("TARGET {}: PREFLIGHT FAILED: {}", target, e.message()),
+            Err(_) => println!("TARGET {}: PREFLIGHT TIMED OUT", target),
+        }
+    }
+
+    // HEALTH_CHECK_SCENARIO points to a YAML file with a custom flow to run instead (see `Scenario`).
+    if let Ok(path) = env::var("HEALTH_CHECK_SCENARIO") {
+        let scenario = Scenario::load(&path).map_err(Error::Config)?;
+
+        // A scenario with a `load` profile is a load test of its own.
+        if let Some(profile) = scenario.load_profile(args.load_profile()).map_err(Error::Config)? {
+            return load_test(&targets, profile, Some(Arc::new(scenario)), &args, &ids, &metrics).await;
+        }
+        if args.load {
+            return load_test(&targets, args.load_profile(), Some(Arc::new(scenario)), &args, &ids, &metrics).await;
+        }
 
-    // Establish connection when auth service
-    let mut client = AuthClient::connect(format!("http://{}:50051", auth_hostname)).await?;
+        loop {
+            for (target, pool) in &targets {
+                println!("TARGET {}", target);
+                scenario.run(&mut *pool.get().await, &ids).await.map_err(|e| Error::Transport(format!("{target}: {e}")))?;
+            }
+
+            if !scenario.repeat() {
+                return Ok(());
+            }
+            println!("--------------------------------------",);
+        }
+    }
+
+    if args.chaos {
+        let mut failed_targets = Vec::new();
+        for (target, pool) in &targets {
+            println!("TARGET {}", target);
+            if let Err(e) = run_chaos(&mut *pool.get().await, &ids).await {
+                println!("TARGET {} FAILED: {}", target, e);
+                failed_targets.push(target.clone());
+            }
+        }
+
+        if !failed_targets.is_empty() {
+            return Err(Error::Transport(format!("chaos checks failed on {}", failed_targets.join(", "))));
+        }
+        return Ok(());
+    }
+
+    if args.soak {
+        return soak(&targets, &args, &ids, &metrics).await;
+    }
+
+    if args.load {
+        return load_test(&targets, args.load_profile(), None, &args, &ids, &metrics).await;
+    }
+
+    let registry = CheckRegistry::builtin(Duration::from_millis(args.latency_slo_ms));
+    let registry = match tls {
+        Some(tls) => registry.with(Box::new(CertExpiryCheck {
+            tls,
+            warn_within: Duration::from_secs(args.cert_warn_days * 24 * 60 * 60),
+            fail_within: Duration::from_secs(args.cert_fail_days * 24 * 60 * 60),
+        })),
+        None => registry,
+    };
+    let registry = registry.select(&args.enable_checks, &args.disable_checks).map_err(Error::Config)?;
 
     loop {
-        let username: String = "User-".to_string() + Uuid::new_v4().to_string().as_str(); // Create random username using new_v4()
-        let password: String = Uuid::new_v4().to_string(); // Create random password using new_v4()
+        round(&targets, &registry, Duration::from_secs_f64(args.rpc_timeout_secs), &ids, &metrics).await?;
 
-         // Create a new `SignUpRequest`.
-        let request: Request<SignUpRequest> = tonic::Request::new(SignUpRequest { 
-                    username: username.clone(), 
-                    password: password.clone() 
-                });
+        println!("--------------------------------------",);
 
-        // Make a sign up request. Propagate any errors.
-        let response: Response<SignUpResponse> = client.sign_up(request).await?; 
+        sleep(Duration::from_secs(args.interval_secs)).await;
+    }
+}
 
-        // Log the response
-        println!(
-            "SIGN UP RESPONSE STATUS: {:?}",
-            StatusCode::from_i32(response.into_inner().status_code)
-        );
+// Runs the health check against every target, reporting each one. Only gives up when none of
+// them could be reached, so one replica being down doesn't hide how the others are doing.
+async fn round(
+    targets: &[(String, Arc<ChannelPool>)],
+    registry: &CheckRegistry,
+    timeout: Duration,
+    ids: &Ids,
+    metrics: &LatencyMetrics,
+) -> Result<(), Error> {
+    let mut errors = Vec::new();
+    let account = test_account();
 
-        // ---------------------------------------------
+    for (target, pool) in targets {
+        let mut cx = CheckContext { target, timeout, ids, account: &account, metrics, cycle: None };
+        let result = registry.run(&mut *pool.get().await, &mut cx).await;
+        let trace_note = cx.cycle.as_ref().map(trace_note).unwrap_or_default();
 
-        // Create a new `SignInRequest`.
+        match result {
+            Ok(verdicts) if verdicts.iter().any(|(_, verdict)| *verdict == Verdict::TimedOut) => {
+                println!("TARGET {}: TIMED OUT{}", target, trace_note)
+            }
+            Ok(verdicts) if verdicts.iter().all(|(_, verdict)| *verdict == Verdict::Passed) => {
+                println!("TARGET {}: OK{}", target, trace_note)
+            }
+            Ok(_) => println!("TARGET {}: FAILED{}", target, trace_note),
+            Err(e) => {
+                println!("TARGET {}: ERROR {:?}", target, e);
+                errors.push(e);
+            }
+        }
+    }
 
-        let request: Request<SignInRequest> = tonic::Request::new(SignInRequest { 
-                        username: username.clone(), 
-                        password: password.clone() 
-                    }); 
+    if errors.len() == targets.len() {
+        if let Some(e) = errors.pop() {
+            return Err(e);
+        }
+    }
 
-        // Make a sign in request. Propagate any errors. Convert Response<SignInResponse> into SignInResponse.
-        let response: SignInResponse = client.sign_in(request).await?.into_inner();
+    Ok(())
+}
 
-        println!(
-            "SIGN IN RESPONSE STATUS: {:?}",
-            // Log response status_code
-            response.status_code 
-        );
+// HEALTH_CHECK_USERNAME and HEALTH_CHECK_PASSWORD name an existing account to sign in as. Without
+// them, every round signs up a new user and deletes it at the end.
+fn test_account() -> TestAccount {
+    match (env::var("HEALTH_CHECK_USERNAME"), env::var("HEALTH_CHECK_PASSWORD")) {
+        (Ok(username), Ok(password)) => TestAccount::Fixed { username, password },
+        _ => TestAccount::Throwaway,
+    }
+}
 
-        // ---------------------------------------------
+// OTEL_EXPORTER_OTLP_ENDPOINT gets the cycles' traces. Without it, they are still made, only not
+// sent anywhere, so the exemplars and the auth-service's logs can still name them.
+fn init_tracing() -> Result<(), TraceError> {
+    let config = trace::config().with_resource(Resource::new([KeyValue::new("service.name", "health-check")]));
 
-        // Create a new `SignOutRequest`.
+    if env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_ok() {
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_env())
+            .with_trace_config(config)
+            .install_batch(opentelemetry::runtime::Tokio)?;
+    } else {
+        global::set_tracer_provider(TracerProvider::builder().with_config(config).build());
+    }
+    Ok(())
+}
 
-        let request: Request<SignOutRequest> = tonic::Request::new(SignOutRequest { 
-                            session_token: response.session_token 
-                        }); 
+// How busy each target's channels were, to size --pool-size and --max-in-flight-per-channel by.
+fn log_pool_stats(targets: &[(String, Arc<ChannelPool>)]) {
+    for (target, pool) in targets {
+        println!("POOL {}: {}", target, pool.stats());
+    }
+}
 
-        let response: Response<SignOutResponse> = client.sign_out(request).await?; // Make a sign out request. Propagate any errors.
+// Which trace to look up when a round was slow or failed.
+fn trace_note(report: &CycleReport) -> String {
+    report.trace_id().map(|trace_id| format!(" (trace {trace_id})")).unwrap_or_default()
+}
 
-        println!(
-            "SIGN OUT RESPONSE STATUS: {:?}",
-            // Log response status_code
-            response.into_inner().status_code 
-        );
+// One cycle of the health check, logged and measured.
+async fn sign_up_in_out(
+    target: &str,
+    client: &mut AuthClient<Channel>,
+    timeout: Duration,
+    ids: &Ids,
+    metrics: &LatencyMetrics,
+) -> Result<CycleReport, Error> {
+    let report = run_cycle_with(client, &test_account(), timeout, ids).await?;
+    metrics.record(target, &report);
+    log_steps(&report);
 
-        println!("--------------------------------------",);
+    Ok(report)
+}
+
+// Runs the health check for hours, sampling latency, errors and resource usage; fails when any
+// of them crept up over the run.
+async fn soak(
+    targets: &[(String, Arc<ChannelPool>)],
+    args: &HealthCheckArgs,
+    ids: &Ids,
+    metrics: &LatencyMetrics,
+) -> Result<(), Error> {
+    let deadline = Instant::now() + Duration::from_secs_f64(args.soak_hours * 60.0 * 60.0);
+    let sample_interval = Duration::from_secs(args.sample_secs);
+
+    // One per target, so a slow replica doesn't blur into the others' numbers.
+    let mut trackers: Vec<SoakTracker> = targets.iter().map(|_| SoakTracker::default()).collect();
+    let mut next_sample = Instant::now() + sample_interval;
+    let mut warnings = 0;
+    let recorder = RunRecorder::default();
+
+    while Instant::now() < deadline {
+        for ((target, pool), tracker) in targets.iter().zip(trackers.iter_mut()) {
+            let started_at = Instant::now();
+            match sign_up_in_out(target, &mut *pool.get().await, Duration::from_secs_f64(args.rpc_timeout_secs), ids, metrics).await {
+                Ok(report) if report.timed_out() => {
+                    tracker.record_timeout(started_at.elapsed());
+                    recorder.record(started_at, started_at.elapsed(), failure(&report));
+                }
+                Ok(report) => {
+                    tracker.record(started_at.elapsed(), !report.passed());
+                    recorder.record(started_at, started_at.elapsed(), failure(&report));
+                }
+                Err(e) => {
+                    println!("HEALTH CHECK ERROR ({}): {:?}", target, e);
+                    tracker.record(started_at.elapsed(), true);
+                    recorder.record(started_at, started_at.elapsed(), Some(String::from("ERROR")));
+                }
+            }
+        }
+
+        if Instant::now() >= next_sample {
+            let resources = Resources::sample();
+            for ((target, _), tracker) in targets.iter().zip(trackers.iter_mut()) {
+                println!("TARGET {}", target);
+                warnings += tracker.sample(resources).len();
+            }
+            next_sample += sample_interval;
+        }
+
+        sleep(Duration::from_secs(args.interval_secs)).await;
+    }
+
+    log_pool_stats(targets);
+    args.write_report(&recorder, "Soak run").map_err(Error::Storage)?;
+    let regressions = args.check_baseline(&recorder, "Soak run").map_err(Error::Storage)?;
+
+    if warnings > 0 {
+        return Err(Error::Transport(format!("soak run ended with {} warnings", warnings)));
+    }
+    if regressions > 0 {
+        return Err(Error::Transport(format!("soak run ended with {} regressions", regressions)));
+    }
 
-        sleep(Duration::from_secs(3)).await;
+    Ok(())
+}
+
+// Runs the health check (or the scenario) at the profile's rate, taking turns between the targets;
+// fails when any of the runs did.
+async fn load_test(
+    targets: &[(String, Arc<ChannelPool>)],
+    profile: LoadProfile,
+    scenario: Option<Arc<Scenario>>,
+    args: &HealthCheckArgs,
+    ids: &Ids,
+    metrics: &LatencyMetrics,
+) -> Result<(), Error> {
+    if targets.is_empty() {
+        return Err(Error::Config(String::from("Error::NoTargets")));
     }
+    println!("LOAD {:?} at {} to {} runs/s", profile.duration(), profile.start_rps, profile.peak_rps);
+
+    let timeout = Duration::from_secs_f64(args.rpc_timeout_secs);
+    let account = test_account();
+    let recorder = RunRecorder::default();
+
+    // Alongside the run, for as long as it lasts.
+    let profiling = args.profiler().map_err(Error::Config)?.map(|profiler| {
+        let (duration, recorder) = (profile.duration(), recorder.clone());
+        tokio::spawn(async move { profiler.capture_for(duration, &recorder).await })
+    });
+
+    let failed = run_load(profile, Duration::from_secs(args.sample_secs), |n| {
+        let (target, pool) = &targets[n % targets.len()];
+        let (target, pool) = (target.clone(), pool.clone());
+        let (scenario, account, ids, metrics, recorder) =
+            (scenario.clone(), account.clone(), ids.clone(), metrics.clone(), recorder.clone());
+
+        async move {
+            // Waiting for a channel counts towards the run's latency, as it would for a client.
+            let started_at = Instant::now();
+            let mut client = pool.get().await;
+
+            if let Some(scenario) = scenario {
+                let result = scenario.run(&mut client, &ids).await.map_err(|e| e.to_string());
+                recorder.record(started_at, started_at.elapsed(), result.clone().err());
+                return result.map_err(|e| format!("{target}: {e}"));
+            }
+
+            let report = match run_cycle_with(&mut client, &account, timeout, &ids).await {
+                Ok(report) => report,
+                Err(status) => {
+                    recorder.record(started_at, started_at.elapsed(), Some(format!("ERROR {:?}", status.code())));
+                    return Err(format!("{target}: {status:?}"));
+                }
+            };
+            metrics.record(&target, &report);
+            recorder.record(started_at, started_at.elapsed(), failure(&report));
+            match failure(&report) {
+                Some(failure) => Err(format!("{target}: {failure}{}", trace_note(&report))),
+                None => Ok(()),
+            }
+        }
+    })
+    .await;
+
+    // Profiles that failed don't fail the load test: they are only there to explain it.
+    if let Some(profiling) = profiling {
+        let _ = profiling.await;
+    }
+    log_pool_stats(targets);
+    args.write_report(&recorder, "Load test").map_err(Error::Storage)?;
+    let regressions = args.check_baseline(&recorder, "Load test").map_err(Error::Storage)?;
+
+    if failed > 0 {
+        return Err(Error::Transport(format!("load test ended with {} failed runs", failed)));
+    }
+    if regressions > 0 {
+        return Err(Error::Transport(format!("load test ended with {} regressions", regressions)));
+    }
+
+    Ok(())
 }
@@ -0,0 +1,190 @@
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use auth_healthcheck::{trace::Exemplar, CycleReport};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+
+// Upper bounds, in seconds, of the latency buckets; slower steps only count towards +Inf.
+const BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    // Not cumulative: the last one is for what no bound holds.
+    counts: [u64; BUCKETS.len() + 1],
+    // The latest observation in each bucket that came with a trace.
+    exemplars: [Option<(Exemplar, f64)>; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64, exemplar: Option<&Exemplar>) {
+        let bucket = BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(BUCKETS.len());
+
+        self.counts[bucket] += 1;
+        if let Some(exemplar) = exemplar {
+            self.exemplars[bucket] = Some((exemplar.clone(), seconds));
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+type Histograms = BTreeMap<(String, &'static str), Histogram>;
+
+// One histogram family of OpenMetrics text, per target and step.
+fn render_family(text: &mut String, family: &str, histograms: &Histograms) {
+    let _ = writeln!(text, "# TYPE {family} histogram");
+    for ((target, step), histogram) in histograms.iter() {
+        let labels = format!("target=\"{target}\",step=\"{step}\"");
+        let bounds = BUCKETS.iter().map(|bound| bound.to_string()).chain([String::from("+Inf")]);
+
+        let mut cumulative = 0;
+        for ((bound, count), exemplar) in bounds.zip(histogram.counts).zip(&histogram.exemplars) {
+            cumulative += count;
+            let _ = write!(text, "{family}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+            if let Some((exemplar, seconds)) = exemplar {
+                let _ = write!(text, " # {{trace_id=\"{}\",span_id=\"{}\"}} {seconds}", exemplar.trace_id, exemplar.span_id);
+            }
+            text.push('\n');
+        }
+        let _ = writeln!(text, "{family}_sum{{{labels}}} {}", histogram.sum);
+        let _ = writeln!(text, "{family}_count{{{labels}}} {}", histogram.count);
+    }
+}
+
+#[derive(Default)]
+struct StepHistograms {
+    // As the health check measured it.
+    latency: Histograms,
+    // As the service says it took (AUTH_SERVER_TIMING); the rest of the latency is the network's.
+    server: Histograms,
+}
+
+// How long every step took, per target, as OpenMetrics histograms served at `GET /metrics`
+// (HEALTH_CHECK_METRICS_ADDR). Buckets carry the trace of their latest step as an exemplar, so a
+// slow check leads straight to what the auth-service did meanwhile.
+#[derive(Clone, Default)]
+pub struct LatencyMetrics(Arc<Mutex<StepHistograms>>);
+
+impl LatencyMetrics {
+    pub fn record(&self, target: &str, report: &CycleReport) {
+        let mut histograms = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+
+        for step in &report.steps {
+            let key = (target.to_owned(), step.name);
+            histograms.latency.entry(key.clone()).or_default().observe(step.latency.as_secs_f64(), step.exemplar.as_ref());
+            if let Some(server_timing) = &step.server_timing {
+                histograms.server.entry(key).or_default().observe(server_timing.handler.as_secs_f64(), step.exemplar.as_ref());
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let histograms = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut text = String::new();
+        render_family(&mut text, "health_check_step_latency_seconds", &histograms.latency);
+        // Only once a target says; most don't.
+        if !histograms.server.is_empty() {
+            render_family(&mut text, "health_check_step_server_seconds", &histograms.server);
+        }
+        text.push_str("# EOF\n");
+        text
+    }
+
+    fn respond(&self, request: &Request<Body>) -> Response<Body> {
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/metrics") => Response::builder()
+                .header("Content-Type", "application/openmetrics-text; version=1.0.0; charset=utf-8")
+                .body(Body::from(self.render()))
+                .unwrap_or_default(),
+            _ => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                response
+            }
+        }
+    }
+
+    // Binds right away, so a taken address is found out on start; serves once awaited.
+    pub fn serve(self, addr: SocketAddr) -> Result<impl Future<Output = Result<(), hyper::Error>>, hyper::Error> {
+        let make_service = make_service_fn(move |_| {
+            let metrics = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = metrics.respond(&request);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        Ok(Server::try_bind(&addr)?.serve(make_service))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use auth_healthcheck::{Outcome, ServerTiming, StepReport};
+
+    use super::*;
+
+    #[test]
+    fn should_render_cumulative_buckets_with_exemplars() {
+        let metrics = LatencyMetrics::default();
+        let step = |millis, exemplar| StepReport {
+            name: "sign_in",
+            outcome: Outcome::Answered(1),
+            latency: Duration::from_millis(millis),
+            server_timing: None,
+            exemplar,
+            expect_success: true,
+        };
+        let exemplar = Exemplar { trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_owned(), span_id: "00f067aa0ba902b7".to_owned() };
+
+        metrics.record("auth:50051", &CycleReport { username: String::new(), steps: vec![step(3, None)] });
+        metrics.record("auth:50051", &CycleReport { username: String::new(), steps: vec![step(2000, Some(exemplar))] });
+
+        let text = metrics.render();
+        let labels = "target=\"auth:50051\",step=\"sign_in\"";
+        assert!(text.contains(&format!("_bucket{{{labels},le=\"0.005\"}} 1\n")));
+        assert!(text.contains(&format!(
+            "_bucket{{{labels},le=\"2.5\"}} 2 # {{trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\",span_id=\"00f067aa0ba902b7\"}} 2\n"
+        )));
+        assert!(text.contains(&format!("_bucket{{{labels},le=\"+Inf\"}} 2\n")));
+        assert!(text.contains(&format!("_count{{{labels}}} 2\n")));
+        assert!(text.ends_with("# EOF\n"));
+        assert!(!text.contains("health_check_step_server_seconds"));
+    }
+
+    #[test]
+    fn should_render_server_timing_apart() {
+        let metrics = LatencyMetrics::default();
+        let step = StepReport {
+            name: "sign_in",
+            outcome: Outcome::Answered(1),
+            latency: Duration::from_millis(40),
+            server_timing: Some(ServerTiming { handler: Duration::from_millis(4), store: Duration::from_millis(1) }),
+            exemplar: None,
+            expect_success: true,
+        };
+
+        metrics.record("auth:50051", &CycleReport { username: String::new(), steps: vec![step] });
+
+        let text = metrics.render();
+        let labels = "target=\"auth:50051\",step=\"sign_in\"";
+        assert!(text.contains(&format!("health_check_step_latency_seconds_bucket{{{labels},le=\"0.005\"}} 0\n")));
+        assert!(text.contains(&format!("health_check_step_server_seconds_bucket{{{labels},le=\"0.005\"}} 1\n")));
+    }
+}
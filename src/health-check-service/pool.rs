@@ -0,0 +1,174 @@
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tonic::transport::Channel;
+
+use crate::authentication::auth_client::AuthClient;
+
+// How one channel of a pool was used over the run.
+#[derive(Default)]
+struct ChannelStats {
+    checkouts: AtomicU64,
+    // Checkouts that found every channel at its limit, and waited for this one.
+    waits: AtomicU64,
+    peak_in_flight: AtomicUsize,
+}
+
+struct PooledChannel {
+    client: AuthClient<Channel>,
+    permits: Arc<Semaphore>,
+    stats: ChannelStats,
+}
+
+// A few HTTP/2 channels to one target, shared by every worker of a load test, each carrying at
+// most `max_in_flight` calls at once. Workers past that wait for a channel to free up, rather than
+// opening more connections than the service would see from a real client.
+pub struct ChannelPool {
+    channels: Vec<PooledChannel>,
+    max_in_flight: usize,
+    next: AtomicUsize,
+}
+
+impl ChannelPool {
+    pub fn new(clients: Vec<AuthClient<Channel>>, max_in_flight: usize) -> Self {
+        let max_in_flight = max_in_flight.max(1);
+        let channels = clients
+            .into_iter()
+            .map(|client| PooledChannel { client, permits: Arc::new(Semaphore::new(max_in_flight)), stats: ChannelStats::default() })
+            .collect();
+
+        Self { channels, max_in_flight, next: AtomicUsize::new(0) }
+    }
+
+    // A channel with room for one more call, taking turns between them; waits for the next one
+    // in turn when they are all full.
+    pub async fn get(&self) -> Lease {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let turns = (0..self.channels.len()).map(|offset| (start + offset) % self.channels.len());
+
+        for index in turns {
+            if let Ok(permit) = self.channels[index].permits.clone().try_acquire_owned() {
+                return self.lease(index, permit);
+            }
+        }
+
+        let index = start % self.channels.len();
+        self.channels[index].stats.waits.fetch_add(1, Ordering::Relaxed);
+        let permit = self.channels[index]
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the pool never closes its semaphores");
+        self.lease(index, permit)
+    }
+
+    fn lease(&self, index: usize, permit: OwnedSemaphorePermit) -> Lease {
+        let channel = &self.channels[index];
+        let in_flight = self.max_in_flight - channel.permits.available_permits();
+
+        channel.stats.checkouts.fetch_add(1, Ordering::Relaxed);
+        channel.stats.peak_in_flight.fetch_max(in_flight, Ordering::Relaxed);
+        Lease { client: channel.client.clone(), _permit: permit }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let load = |stat: fn(&ChannelStats) -> u64| self.channels.iter().map(|channel| stat(&channel.stats)).sum();
+
+        PoolStats {
+            channels: self.channels.len(),
+            max_in_flight: self.max_in_flight,
+            checkouts: load(|stats| stats.checkouts.load(Ordering::Relaxed)),
+            waits: load(|stats| stats.waits.load(Ordering::Relaxed)),
+            peak_in_flight: self.channels.iter().map(|channel| channel.stats.peak_in_flight.load(Ordering::Relaxed)).collect(),
+        }
+    }
+}
+
+// A channel of the pool, for one run; its slot frees up once dropped.
+pub struct Lease {
+    client: AuthClient<Channel>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for Lease {
+    type Target = AuthClient<Channel>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl DerefMut for Lease {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.client
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PoolStats {
+    pub channels: usize,
+    pub max_in_flight: usize,
+    pub checkouts: u64,
+    pub waits: u64,
+    // Per channel: a pool that never fills its channels is bigger than it needs to be.
+    pub peak_in_flight: Vec<usize>,
+}
+
+impl fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "channels={} max_in_flight={} checkouts={} waited={} peak_in_flight={:?}",
+            self.channels, self.max_in_flight, self.checkouts, self.waits, self.peak_in_flight
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+    use tonic::transport::Endpoint;
+
+    use super::*;
+
+    fn pool(channels: usize, max_in_flight: usize) -> ChannelPool {
+        let client = || AuthClient::new(Endpoint::from_static("http://[::1]:1").connect_lazy());
+        ChannelPool::new((0..channels).map(|_| client()).collect(), max_in_flight)
+    }
+
+    #[tokio::test]
+    async fn should_spread_calls_over_the_channels() {
+        let pool = pool(2, 2);
+
+        let leases = vec![pool.get().await, pool.get().await, pool.get().await];
+        assert_eq!(pool.stats().peak_in_flight, vec![2, 1]);
+        drop(leases);
+
+        let _lease = pool.get().await;
+        assert_eq!(pool.stats().checkouts, 4);
+        assert_eq!(pool.stats().waits, 0);
+    }
+
+    #[tokio::test]
+    async fn should_wait_once_every_channel_is_full() {
+        let pool = pool(1, 1);
+
+        let lease = pool.get().await;
+        assert!(timeout(Duration::from_millis(20), pool.get()).await.is_err());
+        drop(lease);
+
+        let _lease = pool.get().await;
+        let stats = pool.stats();
+        assert_eq!((stats.checkouts, stats.waits, stats.peak_in_flight), (2, 1, vec![1]));
+    }
+}
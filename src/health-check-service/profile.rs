@@ -0,0 +1,152 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::report::RunRecorder;
+
+// A CPU profile of the auth-service taken during the run: from when (since the run started), for
+// how long, and the file it was written to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedProfile {
+    pub from: Duration,
+    pub window: Duration,
+    pub path: String,
+}
+
+// Takes CPU profiles of the auth-service, one window after the other, from its profiling endpoint
+// (`GET /debug/pprof/profile?seconds=<window>`, answering once the window is over), and writes
+// them to a directory as folded stacks, one line a stack with its sample count: what flamegraph.pl,
+// inferno or speedscope take.
+pub struct Profiler {
+    client: reqwest::Client,
+    url: String,
+    api_key: String,
+    window: Duration,
+    dir: PathBuf,
+}
+
+impl Profiler {
+    // `url` is the endpoint's base, e.g. `http://auth:6060`; `api_key` an admin API key.
+    pub fn new(url: &str, api_key: &str, window: Duration, dir: &str) -> Result<Self, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {dir}.\n{e:?}"))?;
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_owned(),
+            api_key: api_key.to_owned(),
+            window: window.max(Duration::from_secs(1)),
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    // Profiles the `duration` of the run, window after window, adding each profile to the
+    // recorder; the last window is cut short to end with the run. One that fails is reported and
+    // skipped, so the run goes on; returns how many did.
+    pub async fn capture_for(&self, duration: Duration, recorder: &RunRecorder) -> usize {
+        let mut failed = 0;
+        for (from, window) in windows(duration, self.window) {
+            match self.capture(from, window).await {
+                Ok(path) => {
+                    println!("PROFILE {:?} to {:?} WRITTEN TO {}", from, from + window, path);
+                    recorder.record_profile(CapturedProfile { from, window, path });
+                }
+                Err(e) => {
+                    println!("PROFILE {:?} to {:?} FAILED: {}", from, from + window, e);
+                    failed += 1;
+                }
+            }
+        }
+        failed
+    }
+
+    async fn capture(&self, from: Duration, window: Duration) -> Result<String, String> {
+        let error = |e: reqwest::Error| format!("Error::ProfileFailed: {}: {e}", self.url);
+
+        let response = self
+            .client
+            .get(format!("{}/debug/pprof/profile", self.url))
+            .query(&[("seconds", window.as_secs())])
+            .header("x-api-key", &self.api_key)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(error)?;
+        let folded = response.text().await.map_err(error)?;
+
+        let path = profile_path(&self.dir, from);
+        fs::write(&path, folded).map_err(|e| format!("Failed to write the profile to {}.\n{e:?}", path.display()))?;
+        Ok(path.display().to_string())
+    }
+}
+
+// Where the profile starting `from` into the run goes.
+fn profile_path(dir: &Path, from: Duration) -> PathBuf {
+    dir.join(format!("cpu-{:05}s.folded", from.as_secs()))
+}
+
+// The windows covering `duration`, whole seconds each, the last one cut short.
+fn windows(duration: Duration, window: Duration) -> Vec<(Duration, Duration)> {
+    let (duration, window) = (duration.as_secs(), window.as_secs().max(1));
+    (0..duration)
+        .step_by(window as usize)
+        .map(|from| (Duration::from_secs(from), Duration::from_secs(window.min(duration - from))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::SocketAddr};
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+
+    use super::*;
+
+    #[test]
+    fn should_cover_the_run_with_windows() {
+        let secs = Duration::from_secs;
+
+        assert_eq!(windows(secs(70), secs(30)), vec![(secs(0), secs(30)), (secs(30), secs(30)), (secs(60), secs(10))]);
+        assert_eq!(windows(secs(2), secs(0)), vec![(secs(0), secs(1)), (secs(1), secs(1))]);
+        assert!(windows(secs(0), secs(30)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_write_the_profiles_of_the_run() {
+        // Answers like the auth-service would, straight away rather than after the window.
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let authorized = request.headers().get("x-api-key").is_some_and(|key| key == "admin-key");
+                let mut response = Response::new(Body::from(format!("main;{} 3\n", request.uri().query().unwrap_or_default())));
+                if !authorized {
+                    *response.status_mut() = StatusCode::UNAUTHORIZED;
+                }
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let dir = std::env::temp_dir().join(format!("profiles-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let recorder = RunRecorder::default();
+
+        let profiler = Profiler::new(&url, "admin-key", Duration::from_secs(2), dir).unwrap();
+        assert_eq!(profiler.capture_for(Duration::from_secs(3), &recorder).await, 0);
+
+        let profiles = recorder.profiles();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!((profiles[1].from, profiles[1].window), (Duration::from_secs(2), Duration::from_secs(1)));
+        assert_eq!(fs::read_to_string(&profiles[1].path).unwrap(), "main;seconds=1 3\n");
+
+        let unauthorized = Profiler::new(&url, "other-key", Duration::from_secs(2), dir).unwrap();
+        assert_eq!(unauthorized.capture_for(Duration::from_secs(1), &recorder).await, 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
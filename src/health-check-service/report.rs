@@ -0,0 +1,348 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use auth_healthcheck::{CycleReport, Outcome};
+
+use crate::authentication::StatusCode;
+use crate::baseline::RunSummary;
+use crate::profile::CapturedProfile;
+
+// One round (or run of a scenario): when it started, how long it took, and what went wrong, if
+// anything.
+#[derive(Clone, Debug)]
+struct Run {
+    at: Duration,
+    latency: Duration,
+    failure: Option<String>,
+}
+
+// What a round failed with: its first step that didn't succeed, and how.
+pub fn failure(report: &CycleReport) -> Option<String> {
+    let step = report.steps.iter().find(|step| !step.passed())?;
+    Some(match step.outcome {
+        Outcome::TimedOut => format!("{} TIMED OUT", step.name),
+        Outcome::Answered(status_code) => match StatusCode::from_i32(status_code) {
+            Some(status_code) => format!("{} {}", step.name, status_code.as_str_name()),
+            None => format!("{} status {}", step.name, status_code),
+        },
+    })
+}
+
+// The nearest-rank percentile of sorted latencies.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.1}", duration.as_secs_f64() * 1000.0)
+}
+
+// The runs that started within one window of the run.
+struct Window {
+    from: Duration,
+    runs: usize,
+    failures: usize,
+    throughput: f64,
+    p50: Duration,
+    p90: Duration,
+    p99: Duration,
+}
+
+// Every run of a load test or soak run, for a report to share afterwards (incident reviews,
+// capacity docs): latency percentiles over time, failures by step and status, and throughput; and
+// the CPU profiles of the auth-service taken meanwhile (see `Profiler`).
+#[derive(Clone)]
+pub struct RunRecorder {
+    started_at: Instant,
+    runs: Arc<Mutex<Vec<Run>>>,
+    profiles: Arc<Mutex<Vec<CapturedProfile>>>,
+}
+
+impl Default for RunRecorder {
+    fn default() -> Self {
+        Self { started_at: Instant::now(), runs: Arc::default(), profiles: Arc::default() }
+    }
+}
+
+impl RunRecorder {
+    pub fn record(&self, started_at: Instant, latency: Duration, failure: Option<String>) {
+        let run = Run { at: started_at.saturating_duration_since(self.started_at), latency, failure };
+        self.runs.lock().unwrap_or_else(PoisonError::into_inner).push(run);
+    }
+
+    pub fn record_profile(&self, profile: CapturedProfile) {
+        self.profiles.lock().unwrap_or_else(PoisonError::into_inner).push(profile);
+    }
+
+    pub fn profiles(&self) -> Vec<CapturedProfile> {
+        self.profiles.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    fn windows(runs: &[Run], window: Duration) -> Vec<Window> {
+        let mut by_window: BTreeMap<u64, Vec<&Run>> = BTreeMap::new();
+        for run in runs {
+            let index = (run.at.as_secs_f64() / window.as_secs_f64()) as u64;
+            by_window.entry(index).or_default().push(run);
+        }
+
+        by_window
+            .into_iter()
+            .map(|(index, runs)| {
+                let mut latencies: Vec<Duration> = runs.iter().map(|run| run.latency).collect();
+                latencies.sort();
+
+                Window {
+                    from: window * index as u32,
+                    runs: runs.len(),
+                    failures: runs.iter().filter(|run| run.failure.is_some()).count(),
+                    throughput: runs.len() as f64 / window.as_secs_f64(),
+                    p50: percentile(&latencies, 50.0),
+                    p90: percentile(&latencies, 90.0),
+                    p99: percentile(&latencies, 99.0),
+                }
+            })
+            .collect()
+    }
+
+    fn failures(runs: &[Run]) -> BTreeMap<&str, usize> {
+        let mut failures = BTreeMap::new();
+        for failure in runs.iter().filter_map(|run| run.failure.as_deref()) {
+            *failures.entry(failure).or_default() += 1;
+        }
+        failures
+    }
+
+    pub fn render_markdown(&self, title: &str, window: Duration) -> String {
+        let runs = self.runs.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut latencies: Vec<Duration> = runs.iter().map(|run| run.latency).collect();
+        latencies.sort();
+        let failed = runs.iter().filter(|run| run.failure.is_some()).count();
+
+        let mut text = format!("# {title}\n\n");
+        let _ = writeln!(text, "- Duration: {:?}", self.started_at.elapsed());
+        let _ = writeln!(text, "- Runs: {} ({} failed)", runs.len(), failed);
+        let _ = writeln!(
+            text,
+            "- Latency (ms): p50 {}, p90 {}, p99 {}, max {}",
+            millis(percentile(&latencies, 50.0)),
+            millis(percentile(&latencies, 90.0)),
+            millis(percentile(&latencies, 99.0)),
+            millis(latencies.last().copied().unwrap_or_default()),
+        );
+
+        let _ = writeln!(text, "\n## Over time\n");
+        text.push_str("| From (s) | Runs | Runs/s | Failed | p50 (ms) | p90 (ms) | p99 (ms) |\n");
+        text.push_str("|---:|---:|---:|---:|---:|---:|---:|\n");
+        for window in Self::windows(&runs, window) {
+            let _ = writeln!(
+                text,
+                "| {} | {} | {:.2} | {} | {} | {} | {} |",
+                window.from.as_secs(),
+                window.runs,
+                window.throughput,
+                window.failures,
+                millis(window.p50),
+                millis(window.p90),
+                millis(window.p99),
+            );
+        }
+
+        let _ = writeln!(text, "\n## Failures\n");
+        let failures = Self::failures(&runs);
+        if failures.is_empty() {
+            text.push_str("None.\n");
+        } else {
+            text.push_str("| Failure | Runs |\n|---|---:|\n");
+            for (failure, count) in failures {
+                let _ = writeln!(text, "| {failure} | {count} |");
+            }
+        }
+
+        let profiles = self.profiles();
+        if !profiles.is_empty() {
+            let _ = writeln!(text, "\n## CPU profiles\n");
+            text.push_str("| From (s) | Seconds | Folded stacks |\n|---:|---:|---|\n");
+            for profile in profiles {
+                let _ = writeln!(text, "| {} | {} | {} |", profile.from.as_secs(), profile.window.as_secs(), profile.path);
+            }
+        }
+
+        text
+    }
+
+    pub fn render_html(&self, title: &str, window: Duration) -> String {
+        let runs = self.runs.lock().unwrap_or_else(PoisonError::into_inner);
+        let windows = Self::windows(&runs, window);
+        let failed = runs.iter().filter(|run| run.failure.is_some()).count();
+        let escape = |text: &str| text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+
+        let mut html = format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n", escape(title));
+        let _ = writeln!(html, "<p>Duration: {:?}. Runs: {} ({} failed).</p>", self.started_at.elapsed(), runs.len(), failed);
+
+        // Throughput (bars) and p99 latency (line), each scaled to its own maximum.
+        let (width, height) = (800.0, 200.0);
+        let max_throughput = windows.iter().map(|window| window.throughput).fold(0.0, f64::max).max(f64::EPSILON);
+        let max_p99 = windows.iter().map(|window| window.p99.as_secs_f64()).fold(0.0, f64::max).max(f64::EPSILON);
+        let step = width / windows.len().max(1) as f64;
+        let _ = writeln!(html, "<h2>Throughput and p99 latency</h2>\n<svg width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\">");
+        for (index, window) in windows.iter().enumerate() {
+            let bar = height * window.throughput / max_throughput;
+            let _ = writeln!(
+                html,
+                "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"#9ecae1\"><title>{:.2} runs/s</title></rect>",
+                index as f64 * step,
+                height - bar,
+                (step - 1.0).max(1.0),
+                bar,
+                window.throughput,
+            );
+        }
+        let points: Vec<String> = windows
+            .iter()
+            .enumerate()
+            .map(|(index, window)| {
+                format!("{:.1},{:.1}", (index as f64 + 0.5) * step, height - height * window.p99.as_secs_f64() / max_p99)
+            })
+            .collect();
+        let _ = writeln!(html, "<polyline points=\"{}\" fill=\"none\" stroke=\"#de2d26\" stroke-width=\"2\"/>\n</svg>", points.join(" "));
+
+        html.push_str("<h2>Over time</h2>\n<table>\n<tr><th>From (s)</th><th>Runs</th><th>Runs/s</th><th>Failed</th><th>p50 (ms)</th><th>p90 (ms)</th><th>p99 (ms)</th></tr>\n");
+        for window in &windows {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{:.2}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                window.from.as_secs(),
+                window.runs,
+                window.throughput,
+                window.failures,
+                millis(window.p50),
+                millis(window.p90),
+                millis(window.p99),
+            );
+        }
+        html.push_str("</table>\n<h2>Failures</h2>\n<table>\n<tr><th>Failure</th><th>Runs</th></tr>\n");
+        for (failure, count) in Self::failures(&runs) {
+            let _ = writeln!(html, "<tr><td>{}</td><td>{}</td></tr>", escape(failure), count);
+        }
+        html.push_str("</table>\n");
+        let profiles = self.profiles();
+        if !profiles.is_empty() {
+            html.push_str("<h2>CPU profiles</h2>\n<table>\n<tr><th>From (s)</th><th>Seconds</th><th>Folded stacks</th></tr>\n");
+            for profile in profiles {
+                let _ = writeln!(
+                    html,
+                    "<tr><td>{}</td><td>{}</td><td><a href=\"{path}\">{path}</a></td></tr>",
+                    profile.from.as_secs(),
+                    profile.window.as_secs(),
+                    path = escape(&profile.path),
+                );
+            }
+            html.push_str("</table>\n");
+        }
+        html.push_str("</body>\n</html>\n");
+
+        html
+    }
+
+    // How the runs did overall, to compare with the baseline.
+    pub fn summary(&self, kind: &str) -> RunSummary {
+        let runs = self.runs.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut latencies: Vec<Duration> = runs.iter().map(|run| run.latency).collect();
+        latencies.sort();
+        let failed = runs.iter().filter(|run| run.failure.is_some()).count();
+        let error_rate = if runs.is_empty() { 0.0 } else { failed as f64 / runs.len() as f64 };
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+
+        RunSummary::new(kind, runs.len(), ms(percentile(&latencies, 50.0)), ms(percentile(&latencies, 99.0)), error_rate)
+    }
+
+    // HTML for a path ending in .html, Markdown otherwise.
+    pub fn write(&self, path: &str, title: &str, window: Duration) -> Result<(), String> {
+        let report = match path.ends_with(".html") {
+            true => self.render_html(title, window),
+            false => self.render_markdown(title, window),
+        };
+        fs::write(path, report).map_err(|e| format!("Failed to write the report to {path}.\n{e:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use auth_healthcheck::StepReport;
+
+    use super::*;
+
+    fn recorder() -> RunRecorder {
+        let recorder = RunRecorder::default();
+        let at = |secs| recorder.started_at + Duration::from_secs(secs);
+
+        for millis in 1..=10 {
+            recorder.record(at(1), Duration::from_millis(millis), None);
+        }
+        recorder.record(at(12), Duration::from_millis(500), Some(String::from("sign_in TIMED OUT")));
+        recorder.record(at(13), Duration::from_millis(20), Some(String::from("sign_up FAILURE")));
+        recorder.record(at(14), Duration::from_millis(30), Some(String::from("sign_up FAILURE")));
+        recorder
+    }
+
+    #[test]
+    fn should_sum_up_the_runs_for_the_baseline() {
+        let summary = recorder().summary("Load test");
+
+        assert_eq!((summary.kind.as_str(), summary.runs), ("Load test", 13));
+        assert_eq!((summary.p50_ms, summary.p99_ms), (7.0, 500.0));
+        assert_eq!(summary.error_rate, 3.0 / 13.0);
+    }
+
+    #[test]
+    fn should_report_percentiles_per_window_and_failures() {
+        let markdown = recorder().render_markdown("Load test", Duration::from_secs(10));
+
+        assert!(markdown.contains("- Runs: 13 (3 failed)"));
+        assert!(markdown.contains("| 0 | 10 | 1.00 | 0 | 5.0 | 9.0 | 10.0 |"));
+        assert!(markdown.contains("| 10 | 3 | 0.30 | 3 | 30.0 | 500.0 | 500.0 |"));
+        assert!(markdown.contains("| sign_up FAILURE | 2 |"));
+        assert!(markdown.contains("| sign_in TIMED OUT | 1 |"));
+
+        let html = recorder().render_html("Load test", Duration::from_secs(10));
+        assert!(html.contains("<td>sign_up FAILURE</td><td>2</td>"));
+        assert_eq!(html.matches("<rect").count(), 2);
+        assert!(!markdown.contains("CPU profiles") && !html.contains("CPU profiles"));
+    }
+
+    #[test]
+    fn should_list_the_profiles_taken_during_the_run() {
+        let recorder = recorder();
+        let path = String::from("profiles/cpu-00030s.folded");
+        recorder.record_profile(CapturedProfile { from: Duration::from_secs(30), window: Duration::from_secs(30), path });
+
+        let markdown = recorder.render_markdown("Load test", Duration::from_secs(10));
+        assert!(markdown.contains("| 30 | 30 | profiles/cpu-00030s.folded |"));
+
+        let html = recorder.render_html("Load test", Duration::from_secs(10));
+        assert!(html.contains("<a href=\"profiles/cpu-00030s.folded\">"));
+    }
+
+    #[test]
+    fn should_name_the_first_failed_step() {
+        let step = |name, outcome| StepReport { name, outcome, latency: Duration::ZERO, server_timing: None, exemplar: None, expect_success: true };
+        let mut report = CycleReport {
+            username: String::new(),
+            steps: vec![step("sign_up", Outcome::Answered(StatusCode::Success as i32))],
+        };
+        assert_eq!(failure(&report), None);
+
+        report.steps.push(step("sign_in", Outcome::Answered(StatusCode::AccountSuspended as i32)));
+        report.steps.push(step("sign_out", Outcome::TimedOut));
+        assert_eq!(failure(&report).as_deref(), Some("sign_in ACCOUNT_SUSPENDED"));
+    }
+}
@@ -0,0 +1,277 @@
+use std::{collections::HashMap, fs, time::Duration};
+
+use auth::error::Error;
+use auth_healthcheck::Ids;
+use tokio::time::sleep;
+use tonic::transport::Channel;
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::authentication::auth_client::AuthClient;
+use crate::load::LoadProfile;
+use crate::authentication::{
+    SignInRequest, SignOutRequest, SignUpRequest, StatusCode, ValidateSessionRequest,
+};
+
+// A flow to check instead of the built-in sign up / sign in / sign out loop, e.g.
+//
+//   repeat: true
+//   variables:
+//     username: "smoke-${uuid}"
+//     password: "${uuid}"
+//   steps:
+//     - sign_up: { username: "${username}", password: "${password}" }
+//     - sign_in: { username: "${username}", password: "wrong" }
+//       expect: FAILURE
+//     - sign_in: { username: "${username}", password: "${password}" }
+//     - validate: { session_token: "${session_token}" }
+//     - sign_out: { session_token: "${session_token}" }
+//     - sleep: 3
+//
+// With a `load` section (see `LoadProfile`), the scenario is run as a load test instead.
+//
+// "${name}" is replaced by a variable. `uuid` is new for every run, and successful steps set
+// `session_token` and `user_uuid` from their responses. A step expects SUCCESS unless told
+// otherwise.
+pub struct Scenario {
+    repeat: bool,
+    load: Option<Yaml>,
+    variables: Vec<(String, String)>,
+    steps: Vec<Step>,
+}
+
+struct Step {
+    action: Action,
+    expect: StatusCode,
+}
+
+// Field values are templates, filled in when the step runs.
+enum Action {
+    SignUp { username: String, password: String },
+    SignIn { username: String, password: String },
+    Validate { session_token: String },
+    SignOut { session_token: String },
+    Sleep(Duration),
+}
+
+fn substitute(template: &str, variables: &HashMap<String, String>) -> String {
+    variables
+        .iter()
+        .fold(template.to_owned(), |text, (name, value)| text.replace(&format!("${{{name}}}"), value))
+}
+
+fn string_field(step: &Yaml, action: &str, field: &str) -> Result<String, String> {
+    step[action][field]
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| format!("Error::InvalidScenario: {action} needs a {field}"))
+}
+
+impl Step {
+    fn from_yaml(step: &Yaml) -> Result<Self, String> {
+        let action_name = step
+            .as_hash()
+            .and_then(|hash| hash.keys().filter_map(Yaml::as_str).find(|key| *key != "expect"))
+            .ok_or_else(|| String::from("Error::InvalidScenario: step without an action"))?;
+
+        let action = match action_name {
+            "sign_up" => Action::SignUp {
+                username: string_field(step, action_name, "username")?,
+                password: string_field(step, action_name, "password")?,
+            },
+            "sign_in" => Action::SignIn {
+                username: string_field(step, action_name, "username")?,
+                password: string_field(step, action_name, "password")?,
+            },
+            "validate" => Action::Validate { session_token: string_field(step, action_name, "session_token")? },
+            "sign_out" => Action::SignOut { session_token: string_field(step, action_name, "session_token")? },
+            "sleep" => Action::Sleep(Duration::from_secs_f64(
+                step["sleep"]
+                    .as_f64()
+                    .or_else(|| step["sleep"].as_i64().map(|secs| secs as f64))
+                    .ok_or_else(|| String::from("Error::InvalidScenario: sleep needs seconds"))?,
+            )),
+            _ => return Err(format!("Error::InvalidScenario: unknown action {action_name}")),
+        };
+
+        let expect = match step["expect"].as_str() {
+            Some(expect) => StatusCode::from_str_name(expect)
+                .ok_or_else(|| format!("Error::InvalidScenario: unknown status {expect}"))?,
+            None => StatusCode::Success,
+        };
+
+        Ok(Self { action, expect })
+    }
+}
+
+impl Scenario {
+    pub fn from_yaml(yaml: &str) -> Result<Self, String> {
+        let docs = YamlLoader::load_from_str(yaml).map_err(|e| format!("Error::InvalidScenario: {e}"))?;
+        let doc = docs.first().ok_or_else(|| String::from("Error::InvalidScenario: empty"))?;
+
+        let variables = doc["variables"]
+            .as_hash()
+            .map(|variables| {
+                variables
+                    .iter()
+                    .filter_map(|(name, value)| Some((name.as_str()?.to_owned(), value.as_str()?.to_owned())))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let steps = doc["steps"]
+            .as_vec()
+            .ok_or_else(|| String::from("Error::InvalidScenario: no steps"))?
+            .iter()
+            .map(Step::from_yaml)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            repeat: doc["repeat"].as_bool().unwrap_or(false),
+            load: Some(doc["load"].clone()).filter(|load| !load.is_badvalue()),
+            variables,
+            steps,
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        Self::from_yaml(&fs::read_to_string(path).map_err(|e| format!("Failed to read {path}.\n{e:?}"))?)
+    }
+
+    pub fn repeat(&self) -> bool {
+        self.repeat
+    }
+
+    // The `load` section, with what it leaves out taken from `defaults`.
+    pub fn load_profile(&self, defaults: LoadProfile) -> Result<Option<LoadProfile>, String> {
+        self.load.as_ref().map(|load| LoadProfile::from_yaml(load, defaults)).transpose()
+    }
+
+    fn initial_variables(&self, ids: &Ids) -> HashMap<String, String> {
+        let mut variables = HashMap::from([("uuid".to_owned(), ids.new_id().to_string())]);
+
+        // In order, so a variable can use the ones before it.
+        for (name, template) in &self.variables {
+            let value = substitute(template, &variables);
+            variables.insert(name.clone(), value);
+        }
+
+        variables
+    }
+
+    // Stops at the first step whose status isn't the expected one.
+    pub async fn run(&self, client: &mut AuthClient<Channel>, ids: &Ids) -> Result<(), Error> {
+        let mut variables = self.initial_variables(ids);
+
+        for (index, step) in self.steps.iter().enumerate() {
+            let fill = |template: &String| substitute(template, &variables);
+
+            let (name, status_code, outputs) = match &step.action {
+                Action::SignUp { username, password } => {
+                    let response = client
+                        .sign_up(SignUpRequest {
+                            username: fill(username),
+                            password: fill(password),
+                            ..SignUpRequest::default()
+                        })
+                        .await?
+                        .into_inner();
+                    ("sign_up", response.status_code, vec![])
+                }
+                Action::SignIn { username, password } => {
+                    let response = client
+                        .sign_in(SignInRequest {
+                            username: fill(username),
+                            password: fill(password),
+                            remember_me: false,
+                        })
+                        .await?
+                        .into_inner();
+                    let outputs = vec![("session_token", response.session_token), ("user_uuid", response.user_uuid)];
+                    ("sign_in", response.status_code, outputs)
+                }
+                Action::Validate { session_token } => {
+                    let response = client
+                        .validate_session(ValidateSessionRequest { session_token: fill(session_token) })
+                        .await?
+                        .into_inner();
+                    ("validate", response.status_code, vec![("user_uuid", response.user_uuid)])
+                }
+                Action::SignOut { session_token } => {
+                    let response = client
+                        .sign_out(SignOutRequest { session_token: fill(session_token) })
+                        .await?
+                        .into_inner();
+                    ("sign_out", response.status_code, vec![])
+                }
+                Action::Sleep(duration) => {
+                    sleep(*duration).await;
+                    continue;
+                }
+            };
+
+            println!("STEP {} {} RESPONSE STATUS: {:?}", index + 1, name, StatusCode::from_i32(status_code));
+
+            if status_code != step.expect as i32 {
+                return Err(Error::Transport(format!("step {} ({}) expected {:?}", index + 1, name, step.expect)));
+            }
+
+            if status_code == StatusCode::Success as i32 {
+                for (variable, value) in outputs {
+                    variables.insert(variable.to_owned(), value);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_scenario() {
+        let scenario = Scenario::from_yaml(
+            r#"
+variables:
+  username: "smoke-${uuid}"
+steps:
+  - sign_in: { username: "${username}", password: "wrong" }
+    expect: FAILURE
+  - sleep: 0.5
+"#,
+        )
+        .unwrap();
+
+        assert!(!scenario.repeat());
+        assert_eq!(scenario.steps.len(), 2);
+        assert_eq!(scenario.steps[0].expect, StatusCode::Failure);
+
+        let variables = scenario.initial_variables(&Ids::default());
+        assert_eq!(variables["username"], format!("smoke-{}", variables["uuid"]));
+    }
+
+    #[test]
+    fn should_parse_load_profile() {
+        let defaults = LoadProfile {
+            start_rps: 1.0,
+            peak_rps: 10.0,
+            ramp_up: Duration::from_secs(60),
+            hold: Duration::from_secs(300),
+            ramp_down: Duration::from_secs(60),
+        };
+
+        let scenario = Scenario::from_yaml("load: { peak_rps: 40 }\nsteps:\n  - sleep: 1\n").unwrap();
+        assert_eq!(scenario.load_profile(defaults), Ok(Some(LoadProfile { peak_rps: 40.0, ..defaults })));
+
+        let scenario = Scenario::from_yaml("steps:\n  - sleep: 1\n").unwrap();
+        assert_eq!(scenario.load_profile(defaults), Ok(None));
+    }
+
+    #[test]
+    fn should_reject_unknown_action_or_status() {
+        assert!(Scenario::from_yaml("steps:\n  - delete_everything: {}\n").is_err());
+        assert!(Scenario::from_yaml("steps:\n  - sleep: 1\n    expect: MAYBE\n").is_err());
+    }
+}
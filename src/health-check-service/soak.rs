@@ -0,0 +1,152 @@
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+// Latency and errors of the runs since the last sample.
+#[derive(Clone, Copy, Debug, Default)]
+struct Window {
+    runs: u32,
+    errors: u32,
+    // Also counted in `errors`.
+    timeouts: u32,
+    total_latency: Duration,
+}
+
+impl Window {
+    fn average_latency(&self) -> Duration {
+        self.total_latency.checked_div(self.runs).unwrap_or_default()
+    }
+
+    fn error_rate(&self) -> f64 {
+        if self.runs == 0 { 0.0 } else { f64::from(self.errors) / f64::from(self.runs) }
+    }
+}
+
+// What the process holds on to, from /proc (Linux only; zero elsewhere).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Resources {
+    pub rss_kib: u64,
+    pub open_fds: usize,
+}
+
+impl Resources {
+    pub fn sample() -> Self {
+        let rss_kib = fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                status
+                    .lines()
+                    .find_map(|line| line.strip_prefix("VmRSS:"))
+                    .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            })
+            .unwrap_or_default();
+
+        let open_fds = fs::read_dir("/proc/self/fd").map(|fds| fds.count()).unwrap_or_default();
+
+        Self { rss_kib, open_fds }
+    }
+}
+
+// Compares every sampling window with the first one, and complains when latency, error rate or
+// resource usage creep up over the hours.
+pub struct SoakTracker {
+    started_at: Instant,
+    baseline: Option<(Window, Resources)>,
+    window: Window,
+}
+
+impl Default for SoakTracker {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            baseline: None,
+            window: Window::default(),
+        }
+    }
+}
+
+impl SoakTracker {
+    pub fn record(&mut self, latency: Duration, failed: bool) {
+        self.window.runs += 1;
+        self.window.errors += u32::from(failed);
+        self.window.total_latency += latency;
+    }
+
+    // A run that got stuck on a call that never answered.
+    pub fn record_timeout(&mut self, latency: Duration) {
+        self.record(latency, true);
+        self.window.timeouts += 1;
+    }
+
+    // Logs the window that just ended, and returns the warnings about it.
+    pub fn sample(&mut self, resources: Resources) -> Vec<String> {
+        let window = std::mem::take(&mut self.window);
+
+        println!(
+            "SOAK {:?}: runs={} errors={} timeouts={} avg_latency={:?} rss={}KiB fds={}",
+            self.started_at.elapsed(),
+            window.runs,
+            window.errors,
+            window.timeouts,
+            window.average_latency(),
+            resources.rss_kib,
+            resources.open_fds,
+        );
+
+        let Some((baseline, baseline_resources)) = self.baseline else {
+            self.baseline = Some((window, resources));
+            return Vec::new();
+        };
+
+        let mut warnings = Vec::new();
+
+        if window.average_latency() > baseline.average_latency() * 2 {
+            warnings.push(format!(
+                "latency drift: {:?} (started at {:?})",
+                window.average_latency(),
+                baseline.average_latency()
+            ));
+        }
+        if window.error_rate() > baseline.error_rate() + 0.05 {
+            warnings.push(format!(
+                "error rate creep: {:.1}% (started at {:.1}%)",
+                window.error_rate() * 100.0,
+                baseline.error_rate() * 100.0
+            ));
+        }
+        if resources.rss_kib > baseline_resources.rss_kib * 2 {
+            warnings.push(format!("rss growth: {}KiB (started at {}KiB)", resources.rss_kib, baseline_resources.rss_kib));
+        }
+        if resources.open_fds > baseline_resources.open_fds + 16 {
+            warnings.push(format!("fd growth: {} (started at {})", resources.open_fds, baseline_resources.open_fds));
+        }
+
+        for warning in &warnings {
+            println!("SOAK WARNING: {}", warning);
+        }
+
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_warn_about_drift_against_first_window() {
+        let mut tracker = SoakTracker::default();
+        let resources = Resources { rss_kib: 1000, open_fds: 10 };
+
+        tracker.record(Duration::from_millis(10), false);
+        assert!(tracker.sample(resources).is_empty());
+
+        tracker.record(Duration::from_millis(12), false);
+        assert!(tracker.sample(resources).is_empty());
+
+        tracker.record(Duration::from_millis(50), true);
+        let warnings = tracker.sample(Resources { rss_kib: 3000, open_fds: 40 });
+        assert_eq!(warnings.len(), 4);
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    fs,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use auth::error::Error;
+use simple_asn1::{from_der, ASN1Block};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_rustls::{
+    rustls::{Certificate, ClientConfig, RootCertStore, ServerName},
+    TlsConnector,
+};
+use tonic::transport::{Certificate as TonicCertificate, Channel, ClientTlsConfig};
+
+use crate::authentication::auth_client::AuthClient;
+use crate::checks::{Check, CheckContext, Verdict};
+
+// The CA the targets' certificates are checked against, when they are reached over TLS
+// (AUTH_SERVICE_TLS_CA, a PEM file).
+#[derive(Clone)]
+pub struct TargetTls {
+    ca_pem: Vec<u8>,
+}
+
+impl TargetTls {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let ca_pem = fs::read(path).map_err(|e| format!("Failed to read {path}.\n{e:?}"))?;
+        Ok(Self { ca_pem })
+    }
+
+    // For the channels to `address` (`host:port`).
+    pub fn client_config(&self, address: &str) -> ClientTlsConfig {
+        ClientTlsConfig::new().ca_certificate(TonicCertificate::from_pem(&self.ca_pem)).domain_name(host(address))
+    }
+
+    // The certificates `address` presents, the leaf first, once they check out against the CA.
+    pub async fn peer_certificates(&self, address: &str) -> Result<Vec<Certificate>, Error> {
+        let mut roots = RootCertStore::empty();
+        for der in rustls_pemfile::certs(&mut self.ca_pem.as_slice()).map_err(|e| Error::Crypto(e.to_string()))? {
+            roots.add(&Certificate(der)).map_err(|e| Error::Crypto(e.to_string()))?;
+        }
+        let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+
+        let server_name = ServerName::try_from(host(address)).map_err(|e| Error::Crypto(e.to_string()))?;
+        let stream = TcpStream::connect(address).await?;
+        let stream = TlsConnector::from(Arc::new(config)).connect(server_name, stream).await?;
+        Ok(stream.get_ref().1.peer_certificates().unwrap_or_default().to_vec())
+    }
+}
+
+// `auth:50051` and `[::1]:50051` as `auth` and `::1`.
+fn host(address: &str) -> &str {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+// When a DER certificate stops being valid (its notAfter).
+pub fn not_after(der: &[u8]) -> Result<SystemTime, String> {
+    let invalid = || String::from("Error::InvalidCertificate");
+
+    // Certificate ::= SEQUENCE { tbsCertificate SEQUENCE { ..., validity SEQUENCE { notBefore, notAfter }, ... }, ... }
+    let blocks = from_der(der).map_err(|_| invalid())?;
+    let Some(ASN1Block::Sequence(_, certificate)) = blocks.first() else { return Err(invalid()) };
+    let Some(ASN1Block::Sequence(_, tbs_certificate)) = certificate.first() else { return Err(invalid()) };
+
+    let is_time = |block: &ASN1Block| matches!(block, ASN1Block::UTCTime(..) | ASN1Block::GeneralizedTime(..));
+    let not_after = tbs_certificate.iter().find_map(|block| match block {
+        ASN1Block::Sequence(_, validity) => match validity.as_slice() {
+            [not_before, ASN1Block::UTCTime(_, time) | ASN1Block::GeneralizedTime(_, time)] if is_time(not_before) => {
+                Some(time.assume_utc().unix_timestamp())
+            }
+            _ => None,
+        },
+        _ => None,
+    });
+
+    let secs = u64::try_from(not_after.ok_or_else(invalid)?).map_err(|_| invalid())?;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// The certificates of the target's chain are good for longer than `fail_within`, or it fails, and
+// longer than `warn_within`, or it warns (--cert-fail-days, --cert-warn-days). A chain that doesn't
+// check out against the CA, expired ones included, fails as well. Registered when the targets are
+// reached over TLS.
+pub struct CertExpiryCheck {
+    pub tls: TargetTls,
+    pub warn_within: Duration,
+    pub fail_within: Duration,
+}
+
+impl CertExpiryCheck {
+    fn judge(&self, target: &str, expires_in: Duration) -> Verdict {
+        let days = expires_in.as_secs() / (24 * 60 * 60);
+        if expires_in <= self.fail_within {
+            return Verdict::Failed(format!("certificate expires in {days} days"));
+        }
+        if expires_in <= self.warn_within {
+            println!("WARNING: a certificate of {} expires in {} days", target, days);
+        }
+        Verdict::Passed
+    }
+}
+
+#[tonic::async_trait]
+impl Check for CertExpiryCheck {
+    fn name(&self) -> &'static str {
+        "cert_expiry"
+    }
+
+    async fn run(&self, _client: &mut AuthClient<Channel>, cx: &mut CheckContext<'_>) -> Result<Verdict, Error> {
+        let certificates = match timeout(cx.timeout, self.tls.peer_certificates(cx.target)).await {
+            Ok(Ok(certificates)) => certificates,
+            Ok(Err(e)) => return Ok(Verdict::Failed(e.to_string())),
+            Err(_) => return Ok(Verdict::TimedOut),
+        };
+
+        // The one expiring first is the one that matters.
+        let mut expires_at = None;
+        for certificate in &certificates {
+            let not_after = not_after(&certificate.0).map_err(Error::Crypto)?;
+            expires_at = Some(expires_at.map_or(not_after, |expires_at: SystemTime| expires_at.min(not_after)));
+        }
+
+        Ok(match expires_at {
+            Some(expires_at) => self.judge(cx.target, expires_at.duration_since(SystemTime::now()).unwrap_or_default()),
+            None => Verdict::Failed(String::from("no certificate")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -subj /CN=auth -days 3650`,
+    // valid until 2036-10-14T05:06:58Z.
+    const CERTIFICATE: &str = concat!(
+        "-----BEGIN CERTIFICATE-----\n",
+        "MIIBczCCARmgAwIBAgIUJ3LpXmYBwJWQ8ijDKRYoJZNb75kwCgYIKoZIzj0EAwIw\n",
+        "DzENMAsGA1UEAwwEYXV0aDAeFw0yNjEwMTcwNTA2NThaFw0zNjEwMTQwNTA2NTha\n",
+        "MA8xDTALBgNVBAMMBGF1dGgwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAS3Lyjo\n",
+        "o5+amRx9UF9vQG678hT6YvFYEoaQice8FiA1RYiiLiCSzeyh/vPIEor/rHyTYHdf\n",
+        "L2mTQ3CA9J8OUmLPo1MwUTAdBgNVHQ4EFgQUFcYg0+FaZ7jw1zSd/Q1AMDuuissw\n",
+        "HwYDVR0jBBgwFoAUFcYg0+FaZ7jw1zSd/Q1AMDuuisswDwYDVR0TAQH/BAUwAwEB\n",
+        "/zAKBggqhkjOPQQDAgNIADBFAiASeKMjNjuoS2WbSi5zqVknF6VwmfN7sj0jb7zL\n",
+        "ptb/gQIhAN9u4JBTj3eAkqJWv0z/AdcuEl3tuXcXNS9RIFjX5hCl\n",
+        "-----END CERTIFICATE-----\n",
+    );
+
+    #[test]
+    fn should_read_when_a_certificate_expires() {
+        let der = rustls_pemfile::certs(&mut CERTIFICATE.as_bytes()).unwrap().remove(0);
+
+        assert_eq!(not_after(&der), Ok(UNIX_EPOCH + Duration::from_secs(2_107_573_618)));
+        assert!(not_after(&der[..der.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn should_fail_and_warn_ahead_of_expiry() {
+        let check = CertExpiryCheck {
+            tls: TargetTls { ca_pem: CERTIFICATE.as_bytes().to_vec() },
+            warn_within: Duration::from_secs(30 * 24 * 60 * 60),
+            fail_within: Duration::from_secs(7 * 24 * 60 * 60),
+        };
+        let days = |days: u64| Duration::from_secs(days * 24 * 60 * 60);
+
+        assert_eq!(check.judge("auth:50051", days(90)), Verdict::Passed);
+        assert_eq!(check.judge("auth:50051", days(10)), Verdict::Passed);
+        assert_eq!(check.judge("auth:50051", days(3)), Verdict::Failed(String::from("certificate expires in 3 days")));
+        assert_eq!(check.judge("auth:50051", Duration::ZERO), Verdict::Failed(String::from("certificate expires in 0 days")));
+    }
+
+    #[test]
+    fn should_name_the_host_of_an_address() {
+        assert_eq!(host("auth:50051"), "auth");
+        assert_eq!(host("[::1]:50051"), "::1");
+        assert_eq!(host("auth"), "auth");
+    }
+}